@@ -0,0 +1,294 @@
+//! Selection of the tiles that intersect polygons given as `GeoJSON`, e.g. to generate
+//! the tiles of a country without the tiles of the surrounding ocean.
+
+use std::collections::HashMap;
+
+use std::f64::consts::PI;
+
+use serde_json::Value;
+
+use crate::TileRect;
+
+/// Maximum latitude that can be shown in Web Mercator
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Ring of a polygon, as WGS84 longitude and latitude pairs
+type Ring = Vec<[f64; 2]>;
+
+/// Polygons of a `GeoJSON` document, each with its outer ring followed by its holes
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileArea {
+    polygons: Vec<Vec<Ring>>,
+}
+
+impl TileArea {
+    /// Read all polygons of a `GeoJSON` geometry, feature, or feature collection.
+    /// Any other geometry type is an error.
+    pub fn from_geojson(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let mut polygons = Vec::new();
+        collect_polygons(&value, &mut polygons)?;
+        if polygons.is_empty() {
+            return Err("no polygons found".to_string());
+        }
+        Ok(Self { polygons })
+    }
+
+    /// Bounding box of all polygons as `[left, bottom, right, top]` longitudes and latitudes
+    #[must_use]
+    pub fn bounds(&self) -> [f64; 4] {
+        self.polygons.iter().flatten().flatten().fold(
+            [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+            |[left, bottom, right, top], &[lon, lat]| {
+                [left.min(lon), bottom.min(lat), right.max(lon), top.max(lat)]
+            },
+        )
+    }
+
+    /// Non-overlapping tile ranges of a zoom level that cover all tiles intersecting the polygons.
+    /// Rows with the same columns as the row above are merged into a single range.
+    #[must_use]
+    pub fn tile_ranges(&self, zoom: u8) -> Vec<TileRect> {
+        let size = f64::from(1_u32 << zoom);
+        let max_index = (1_u32 << zoom) - 1;
+        // Web Mercator coordinates in tile units, so that tile (x, y) covers x..x+1 and y..y+1
+        let polygons: Vec<Vec<Ring>> = self
+            .polygons
+            .iter()
+            .map(|rings| {
+                let ring = |ring: &Ring| {
+                    let points = ring.iter().map(|&[lon, lat]| {
+                        let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+                        let y = ((90.0 + lat) * PI / 360.0).tan().ln() / PI;
+                        [(lon + 180.0) / 360.0 * size, (1.0 - y) / 2.0 * size]
+                    });
+                    points.collect()
+                };
+                rings.iter().map(ring).collect()
+            })
+            .collect();
+        let (min_y, max_y) = polygons
+            .iter()
+            .flatten()
+            .flatten()
+            .fold((f64::MAX, f64::MIN), |(lo, hi), p| {
+                (lo.min(p[1]), hi.max(p[1]))
+            });
+        let (Some(min_y), Some(max_y)) = (to_index(min_y, max_index), to_index(max_y, max_index))
+        else {
+            return Vec::new();
+        };
+
+        let mut ranges = Vec::new();
+        // ranges of the previous row, by their columns, to extend them downwards
+        let mut open: HashMap<(u32, u32), usize> = HashMap::new();
+        for row in min_y..=max_y {
+            let runs = row_runs(&polygons, f64::from(row), max_index);
+            let mut next_open = HashMap::with_capacity(runs.len());
+            for run in runs {
+                let idx = if let Some(&idx) = open.get(&run) {
+                    let rect: &mut TileRect = &mut ranges[idx];
+                    rect.max_y = row;
+                    idx
+                } else {
+                    ranges.push(TileRect::new(zoom, run.0, row, run.1, row));
+                    ranges.len() - 1
+                };
+                next_open.insert(run, idx);
+            }
+            open = next_open;
+        }
+        ranges
+    }
+}
+
+/// Index of the tile containing a coordinate in tile units, clamped to the zoom level
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_index(value: f64, max_index: u32) -> Option<u32> {
+    if value.is_finite() {
+        Some((value.floor().max(0.0) as u32).min(max_index))
+    } else {
+        None
+    }
+}
+
+/// Sorted and merged column ranges of a tile row intersecting the polygons. A tile intersects
+/// a polygon if one of the polygon edges passes through it, or if its center is inside.
+fn row_runs(polygons: &[Vec<Ring>], row: f64, max_index: u32) -> Vec<(u32, u32)> {
+    let (top, bottom, center) = (row, row + 1.0, row + 0.5);
+    let mut cols = Vec::new();
+    for rings in polygons {
+        let mut crossings = Vec::new();
+        for ring in rings {
+            for edge in ring.windows(2) {
+                let ([x1, y1], [x2, y2]) = (edge[0], edge[1]);
+                // the part of the edge within the row
+                if y1.max(y2) >= top && y1.min(y2) <= bottom {
+                    let (xa, xb) = if (y2 - y1).abs() < f64::EPSILON {
+                        (x1, x2)
+                    } else {
+                        let x_at = |y: f64| x1 + (x2 - x1) * (y - y1) / (y2 - y1);
+                        (x_at(y1.min(y2).max(top)), x_at(y1.max(y2).min(bottom)))
+                    };
+                    if let (Some(a), Some(b)) = (
+                        to_index(xa.min(xb), max_index),
+                        to_index(xa.max(xb), max_index),
+                    ) {
+                        cols.push((a, b));
+                    }
+                }
+                if (y1 <= center) != (y2 <= center) {
+                    crossings.push(x1 + (x2 - x1) * (center - y1) / (y2 - y1));
+                }
+            }
+        }
+        // the tile centers between each pair of crossings are inside the polygon
+        crossings.sort_by(f64::total_cmp);
+        for pair in crossings.chunks_exact(2) {
+            let (first, last) = ((pair[0] - 0.5).ceil(), (pair[1] - 0.5).floor());
+            if first <= last && last >= 0.0 {
+                if let (Some(a), Some(b)) = (to_index(first, max_index), to_index(last, max_index))
+                {
+                    cols.push((a, b));
+                }
+            }
+        }
+    }
+
+    cols.sort_unstable();
+    let mut runs: Vec<(u32, u32)> = Vec::with_capacity(cols.len());
+    for (a, b) in cols {
+        match runs.last_mut() {
+            Some(last) if a <= last.1 + 1 => last.1 = last.1.max(b),
+            _ => runs.push((a, b)),
+        }
+    }
+    runs
+}
+
+fn collect_polygons(value: &Value, polygons: &mut Vec<Vec<Ring>>) -> Result<(), String> {
+    let coords = || value.get("coordinates").ok_or("missing coordinates");
+    match value.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => {
+            let features = value.get("features").and_then(Value::as_array);
+            for feature in features.ok_or("missing features")? {
+                collect_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => {
+            // features without a geometry are allowed by the spec, but do not cover anything
+            match value.get("geometry") {
+                Some(Value::Null) | None => {}
+                Some(geometry) => collect_polygons(geometry, polygons)?,
+            }
+        }
+        Some("GeometryCollection") => {
+            let geometries = value.get("geometries").and_then(Value::as_array);
+            for geometry in geometries.ok_or("missing geometries")? {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        Some("Polygon") => polygons.push(parse_polygon(coords()?)?),
+        Some("MultiPolygon") => {
+            for polygon in coords()?.as_array().ok_or("invalid coordinates")? {
+                polygons.push(parse_polygon(polygon)?);
+            }
+        }
+        Some(other) => return Err(format!("{other} geometries are not supported")),
+        None => return Err("missing type".to_string()),
+    }
+    Ok(())
+}
+
+fn parse_polygon(value: &Value) -> Result<Vec<Ring>, String> {
+    let rings = value.as_array().ok_or("invalid polygon coordinates")?;
+    rings
+        .iter()
+        .map(|ring| {
+            let mut ring = ring
+                .as_array()
+                .ok_or("invalid ring coordinates")?
+                .iter()
+                .map(|point| match point.as_array().map(Vec::as_slice) {
+                    Some([lon, lat, ..]) => match (lon.as_f64(), lat.as_f64()) {
+                        (Some(lon), Some(lat)) => Ok([lon, lat]),
+                        _ => Err("invalid point coordinates"),
+                    },
+                    _ => Err("invalid point coordinates"),
+                })
+                .collect::<Result<Ring, _>>()?;
+            // rings must be closed, but tolerate the files that do not repeat the first point
+            if let (Some(&first), Some(&last)) = (ring.first(), ring.last()) {
+                #[allow(clippy::float_cmp)]
+                if first != last {
+                    ring.push(first);
+                }
+            }
+            Ok(ring)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_area() {
+        let area = TileArea::from_geojson(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 0]]]}},
+                {"type": "Feature", "properties": {}, "geometry": {"type": "MultiPolygon", "coordinates": [[[[0, 0], [1, 0], [1, 1]]]]}},
+                {"type": "Feature", "properties": {}, "geometry": null}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(area.polygons.len(), 2);
+        // unclosed rings are closed
+        assert_eq!(area.polygons[1][0].len(), 4);
+        assert_eq!(area.bounds(), [0.0, 0.0, 10.0, 10.0]);
+
+        let err = TileArea::from_geojson(r#"{"type": "Point", "coordinates": [0, 0]}"#);
+        assert_eq!(err.unwrap_err(), "Point geometries are not supported");
+        assert!(
+            TileArea::from_geojson(r#"{"type": "FeatureCollection", "features": []}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn area_tile_ranges() {
+        // a triangle in the north-east quarter, with the right angle at the north-west corner
+        let area = TileArea::from_geojson(
+            r#"{"type": "Polygon", "coordinates": [[[1, 80], [179, 80], [1, 1], [1, 80]]]}"#,
+        )
+        .unwrap();
+        assert_eq!(area.tile_ranges(0), vec![TileRect::new(0, 0, 0, 0, 0)]);
+        assert_eq!(area.tile_ranges(1), vec![TileRect::new(1, 1, 0, 1, 0)]);
+        assert_eq!(area.tile_ranges(2), vec![TileRect::new(2, 2, 0, 3, 1)]);
+        // the tiles in the south-east corner of the quarter are not covered
+        let ranges = area.tile_ranges(3);
+        let covers = |x, y| {
+            ranges
+                .iter()
+                .any(|r| r.is_overlapping(&TileRect::new(3, x, y, x, y)))
+        };
+        assert!(covers(4, 0) && covers(7, 0) && covers(4, 3) && covers(5, 3));
+        assert!(!covers(6, 3) && !covers(7, 3) && !covers(3, 0));
+
+        // a polygon with a hole, covering a ring of tiles
+        let area = TileArea::from_geojson(
+            r#"{"type": "Polygon", "coordinates": [
+                [[-179, -84], [179, -84], [179, 84], [-179, 84], [-179, -84]],
+                [[-80, -60], [80, -60], [80, 60], [-80, 60], [-80, -60]]
+            ]}"#,
+        )
+        .unwrap();
+        let ranges = area.tile_ranges(3);
+        let total: u64 = ranges.iter().map(TileRect::size).sum();
+        // the 2x2 tiles in the middle are inside the hole
+        assert_eq!(total, 64 - 4);
+        assert!(ranges
+            .iter()
+            .all(|r| !r.is_overlapping(&TileRect::new(3, 3, 3, 4, 4))));
+    }
+}
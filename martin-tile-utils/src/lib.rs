@@ -6,6 +6,12 @@
 use std::f64::consts::PI;
 use std::fmt::Display;
 
+mod area;
+pub use area::TileArea;
+
+mod rectangle;
+pub use rectangle::{append_rect, TileRect};
+
 pub const EARTH_CIRCUMFERENCE: f64 = 40_075_016.7;
 pub const EARTH_RADIUS: f64 = EARTH_CIRCUMFERENCE / 2.0 / PI;
 
@@ -16,6 +22,7 @@ pub enum Format {
     Json,
     Mvt,
     Png,
+    Tiff,
     Webp,
 }
 
@@ -28,6 +35,7 @@ impl Format {
             "json" => Self::Json,
             "pbf" | "mvt" => Self::Mvt,
             "png" => Self::Png,
+            "tif" | "tiff" => Self::Tiff,
             "webp" => Self::Webp,
             _ => None?,
         })
@@ -41,6 +49,7 @@ impl Format {
             Self::Json => "application/json",
             Self::Mvt => "application/x-protobuf",
             Self::Png => "image/png",
+            Self::Tiff => "image/tiff",
             Self::Webp => "image/webp",
         }
     }
@@ -48,7 +57,7 @@ impl Format {
     #[must_use]
     pub fn is_detectable(&self) -> bool {
         match *self {
-            Self::Png | Self::Jpeg | Self::Gif | Self::Webp => true,
+            Self::Png | Self::Jpeg | Self::Gif | Self::Tiff | Self::Webp => true,
             // TODO: Json can be detected, but currently we only detect it
             //       when it's not compressed, so to avoid a warning, keeping it as false for now.
             //       Once we can detect it inside a compressed data, change it to true.
@@ -65,12 +74,13 @@ impl Display for Format {
             Self::Json => write!(f, "json"),
             Self::Mvt => write!(f, "mvt"),
             Self::Png => write!(f, "png"),
+            Self::Tiff => write!(f, "tiff"),
             Self::Webp => write!(f, "webp"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Encoding {
     /// Data is not compressed, but it can be
     Uncompressed = 0b0000_0000,
@@ -143,9 +153,11 @@ impl TileInfo {
             // Compressed prefixes assume MVT content
             v if v.starts_with(b"\x1f\x8b") => Self::new(Mvt, Gzip),
             v if v.starts_with(b"\x78\x9c") => Self::new(Mvt, Zlib),
+            v if v.starts_with(b"\x28\xB5\x2F\xFD") => Self::new(Mvt, Zstd),
             v if v.starts_with(b"\x89\x50\x4E\x47\x0D\x0A\x1A\x0A") => Self::new(Png, Internal),
             v if v.starts_with(b"\x47\x49\x46\x38\x39\x61") => Self::new(Gif, Internal),
             v if v.starts_with(b"\xFF\xD8\xFF") => Self::new(Jpeg, Internal),
+            v if v.starts_with(b"II*\0") || v.starts_with(b"MM\0*") => Self::new(Tiff, Internal),
             v if v.starts_with(b"RIFF") && v.len() > 8 && v[8..].starts_with(b"WEBP") => {
                 Self::new(Webp, Internal)
             }
@@ -165,7 +177,9 @@ impl From<Format> for TileInfo {
         Self::new(
             format,
             match format {
-                Format::Png | Format::Jpeg | Format::Webp | Format::Gif => Encoding::Internal,
+                Format::Png | Format::Jpeg | Format::Webp | Format::Gif | Format::Tiff => {
+                    Encoding::Internal
+                }
                 Format::Mvt | Format::Json => Encoding::Uncompressed,
             },
         )
@@ -184,12 +198,24 @@ impl Display for TileInfo {
     }
 }
 
+/// Convert longitude and latitude to the index of the tile containing them at the given zoom level
+#[must_use]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn tile_index(lon: f64, lat: f64, zoom: u8) -> (u32, u32) {
+    let n = f64::from(1_u32 << zoom);
+    let x = ((lon + 180.0) / 360.0 * n).floor() as u32;
+    let y = ((1.0 - (lat.to_radians().tan() + 1.0 / lat.to_radians().cos()).ln() / PI) / 2.0 * n)
+        .floor() as u32;
+    let max_value = (1_u32 << zoom) - 1;
+    (x.min(max_value), y.min(max_value))
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::read;
 
-    use Encoding::{Internal, Uncompressed};
-    use Format::{Jpeg, Json, Png, Webp};
+    use Encoding::{Internal, Uncompressed, Zstd};
+    use Format::{Jpeg, Json, Mvt, Png, Tiff, Webp};
 
     use super::*;
 
@@ -218,6 +244,17 @@ mod tests {
         assert_eq!(TileInfo::detect(br"RIFF"), None);
     }
 
+    #[test]
+    fn test_data_format_tiff() {
+        assert_eq!(TileInfo::detect(b"II*\0\x08\0\0\0"), info(Tiff, Internal));
+        assert_eq!(TileInfo::detect(b"MM\0*\0\0\0\x08"), info(Tiff, Internal));
+    }
+
+    #[test]
+    fn test_data_format_zstd() {
+        assert_eq!(TileInfo::detect(b"\x28\xB5\x2F\xFD\x00"), info(Mvt, Zstd));
+    }
+
     #[test]
     fn test_data_format_json() {
         assert_eq!(
@@ -225,4 +262,9 @@ mod tests {
             info(Json, Uncompressed)
         );
     }
+
+    #[test]
+    fn test_tile_index() {
+        assert_eq!((0, 0), tile_index(-180.0, 85.0511, 0));
+    }
 }
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use criterion::async_executor::FuturesExecutor;
 use criterion::{criterion_group, criterion_main, Criterion};
-use martin::srv::get_tile_response;
+use martin::srv::{get_tile_response, TileOptions};
 use martin::{
     CatalogSourceEntry, MartinResult, Source, TileCoord, TileData, TileSources, UrlQuery,
 };
@@ -35,6 +35,10 @@ impl Source for NullSource {
         TileInfo::new(Format::Png, Encoding::Internal)
     }
 
+    fn get_source_type(&self) -> &'static str {
+        "null"
+    }
+
     fn clone_source(&self) -> Box<dyn Source> {
         Box::new(self.clone())
     }
@@ -57,9 +61,17 @@ impl Source for NullSource {
 }
 
 async fn process_tile(sources: &TileSources) {
-    get_tile_response(sources, TileCoord { z: 0, x: 0, y: 0 }, "null", "", None)
-        .await
-        .unwrap();
+    get_tile_response(
+        sources,
+        &TileOptions::default(),
+        TileCoord { z: 0, x: 0, y: 0 },
+        "null",
+        "",
+        None,
+        None,
+    )
+    .await
+    .unwrap();
 }
 
 fn bench_null_source(c: &mut Criterion) {
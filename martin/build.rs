@@ -0,0 +1,51 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    compile_grpc();
+}
+
+/// Generate the gRPC service of `proto/martin.proto`. The messages are defined in `src/srv/grpc.rs`,
+/// so that no `protoc` is needed to build Martin.
+#[cfg(feature = "grpc")]
+fn compile_grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route_name: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::srv::grpc::{input}"))
+            .output_type(format!("crate::srv::grpc::{output}"))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Tiles")
+        .package("martin.v1")
+        .method(
+            method(
+                "list_sources",
+                "ListSources",
+                "ListSourcesRequest",
+                "ListSourcesResponse",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "get_tile_json",
+                "GetTileJSON",
+                "GetTileJsonRequest",
+                "GetTileJsonResponse",
+            )
+            .build(),
+        )
+        .method(method("get_tile", "GetTile", "GetTileRequest", "GetTileResponse").build())
+        .method(
+            method("get_tiles", "GetTiles", "GetTileRequest", "GetTileResponse")
+                .client_streaming()
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::info;
+use martin_tile_utils::TileInfo;
+use tilejson::TileJSON;
+
+use crate::source::{PoolStatus, Source, TileData, TileHeaders, TileInfoSources, UrlQuery};
+use crate::utils::IdResolver;
+use crate::{MartinResult, TileCoord, TileMatrixSet};
+
+pub type AliasResult<T> = Result<T, AliasError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AliasError {
+    #[error("An alias is configured for source {0}, but there is no such source")]
+    UnknownSource(String),
+
+    #[error("Source {1} cannot be renamed to {0}: the ID is reserved, used by another source, or has invalid characters")]
+    InvalidId(String, String),
+}
+
+/// New IDs of the sources, keyed by the IDs they were created with, e.g. the auto-discovered ones
+pub type SourceAliases = BTreeMap<String, String>;
+
+/// Apply the settings of the renamed sources, keyed by the IDs they were created with, to their new IDs as well.
+/// The settings configured for the new IDs are kept.
+pub fn rename_keys<T: Clone>(settings: &mut BTreeMap<String, T>, aliases: &SourceAliases) {
+    for (old_id, new_id) in aliases {
+        if let Some(value) = settings.get(old_id).cloned() {
+            settings.entry(new_id.clone()).or_insert(value);
+        }
+    }
+}
+
+/// Replace the IDs the renamed sources were created with by their new IDs in a list of source IDs
+pub fn rename_ids(ids: &mut [String], aliases: &SourceAliases) {
+    for id in ids {
+        if let Some(new_id) = aliases.get(id.as_str()) {
+            new_id.clone_into(id);
+        }
+    }
+}
+
+/// Serve the sources under their configured IDs. The new IDs are claimed with the resolver,
+/// so that they cannot be reserved keywords or be used by other sources.
+pub fn apply_aliases(
+    sources: Vec<TileInfoSources>,
+    aliases: &SourceAliases,
+    idr: &IdResolver,
+) -> MartinResult<Vec<TileInfoSources>> {
+    if let Some(id) = aliases.keys().find(|id| {
+        !sources
+            .iter()
+            .flatten()
+            .any(|src| src.get_id() == id.as_str())
+    }) {
+        return Err(AliasError::UnknownSource(id.clone()).into());
+    }
+    for (old_id, new_id) in aliases {
+        if idr.resolve(new_id, format!("alias:{new_id}")) != *new_id {
+            return Err(AliasError::InvalidId(new_id.clone(), old_id.clone()).into());
+        }
+    }
+
+    let mut result = Vec::with_capacity(sources.len());
+    for group in sources {
+        let mut renamed = TileInfoSources::default();
+        for src in group {
+            match aliases.get(src.get_id()) {
+                Some(id) => {
+                    info!("Serving source {} as {id}", src.get_id());
+                    renamed.push(Box::new(AliasSource::new(src, id.clone())));
+                }
+                None => renamed.push(src),
+            }
+        }
+        result.push(renamed);
+    }
+    Ok(result)
+}
+
+/// A source served under another ID than the one it was created with
+#[derive(Debug)]
+pub struct AliasSource {
+    source: Box<dyn Source>,
+    id: String,
+}
+
+impl Clone for AliasSource {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_source(),
+            id: self.id.clone(),
+        }
+    }
+}
+
+impl AliasSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, id: String) -> Self {
+        Self { source, id }
+    }
+}
+
+#[async_trait]
+impl Source for AliasSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn support_utfgrid(&self) -> bool {
+        self.source.support_utfgrid()
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.source.is_cacheable()
+    }
+
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.source.get_tile_matrix_set()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        self.source.get_tile(xyz, url_query).await
+    }
+
+    async fn get_tile_with_headers(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<(TileData, Option<TileHeaders>)> {
+        self.source.get_tile_with_headers(xyz, url_query).await
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        self.source.get_pool_status()
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TableSource {
+        id: &'static str,
+        tj: TileJSON,
+    }
+
+    #[async_trait]
+    impl Source for TableSource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            Ok(vec![1, 2, 3])
+        }
+    }
+
+    fn sources() -> Vec<TileInfoSources> {
+        let src = |id| -> Box<dyn Source> {
+            Box::new(TableSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+            })
+        };
+        vec![vec![src("public.roads_v2"), src("water")]]
+    }
+
+    fn resolver() -> IdResolver {
+        let idr = IdResolver::new(&["catalog"]);
+        for id in ["public.roads_v2", "water"] {
+            let _ = idr.resolve(id, id.to_string());
+        }
+        idr
+    }
+
+    #[actix_rt::test]
+    async fn rename_sources() {
+        let aliases = SourceAliases::from([("public.roads_v2".to_string(), "roads".to_string())]);
+        let sources = apply_aliases(sources(), &aliases, &resolver()).unwrap();
+        let ids: Vec<_> = sources.iter().flatten().map(|src| src.get_id()).collect();
+        assert_eq!(ids, ["roads", "water"]);
+        let tile = TileCoord { z: 0, x: 0, y: 0 };
+        let data = sources[0][0].get_tile(&tile, &None).await.unwrap();
+        assert_eq!(data, vec![1, 2, 3]);
+        assert_eq!(sources[0][0].clone_source().get_id(), "roads");
+    }
+
+    #[test]
+    fn invalid_aliases() {
+        let apply = |from: &str, to: &str| {
+            let aliases = SourceAliases::from([(from.to_string(), to.to_string())]);
+            apply_aliases(sources(), &aliases, &resolver())
+        };
+        assert!(apply("missing", "roads").is_err());
+        assert!(apply("public.roads_v2", "water").is_err());
+        assert!(apply("public.roads_v2", "catalog").is_err());
+        assert!(apply("public.roads_v2", "my roads").is_err());
+    }
+}
@@ -8,7 +8,9 @@ mod pg;
 pub use pg::{BoundsCalcType, PgArgs, DEFAULT_BOUNDS_TIMEOUT};
 
 mod root;
-pub use root::{Args, ExtraArgs, MetaArgs};
+pub use root::{
+    Args, BenchArgs, DemoArgs, DumpFormat, ExtraArgs, InitArgs, MartinCommand, MetaArgs,
+};
 
 mod srv;
 pub use srv::SrvArgs;
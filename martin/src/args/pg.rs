@@ -16,11 +16,17 @@ pub const DEFAULT_BOUNDS_TIMEOUT: Duration = Duration::from_secs(5);
 #[derive(PartialEq, Eq, Default, Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum BoundsCalcType {
-    /// Compute table geometry bounds, but abort if it takes longer than 5 seconds.
+    /// Compute table geometry bounds, but abort if it takes longer than the bounds timeout (5 seconds by default).
     #[default]
     Quick,
     /// Compute table geometry bounds. The startup time may be significant. Make sure all GEO columns have indexes.
+    #[serde(alias = "exact")]
+    #[value(alias = "exact")]
     Calc,
+    /// Use the bounds estimated from the table statistics. Run ANALYZE on the tables to keep them accurate.
+    Estimate,
+    /// Serve the tables right away, and add their bounds once they have been computed.
+    Background,
     /// Skip bounds calculation. The bounds will be set to the whole world.
     Skip,
 }
@@ -31,6 +37,9 @@ pub struct PgArgs {
     /// Specify how bounds should be computed for the spatial PG tables. [DEFAULT: quick]
     #[arg(short = 'b', long)]
     pub auto_bounds: Option<BoundsCalcType>,
+    /// Maximum time in seconds to compute the bounds of a spatial PG table in the quick and background modes. [DEFAULT: 5 for quick, unlimited for background]
+    #[arg(long)]
+    pub bounds_timeout: Option<u64>,
     /// Loads trusted root certificates from a file. The file should contain a sequence of PEM-formatted CA certificates.
     #[arg(long)]
     pub ca_root_file: Option<std::path::PathBuf>,
@@ -42,6 +51,9 @@ pub struct PgArgs {
     /// Limit the number of features in a tile from a PG table source.
     #[arg(short, long)]
     pub max_feature_count: Option<usize>,
+    /// Sort features of PG table sources so that the same data always produces byte-identical tiles.
+    #[arg(long)]
+    pub deterministic: bool,
 }
 
 impl PgArgs {
@@ -58,14 +70,30 @@ impl PgArgs {
             .into_iter()
             .map(|s| PgConfig {
                 connection_string: Some(s),
+                replicas: None,
                 ssl_certificates: certs.clone(),
                 default_srid,
                 auto_bounds: self.auto_bounds,
+                bounds_timeout: self.bounds_timeout,
                 max_feature_count: self.max_feature_count,
+                deterministic: self.deterministic.then_some(true),
+                extent: None,
+                buffer: None,
+                clip_geom: None,
                 pool_size: self.pool_size,
+                idle_timeout_secs: None,
+                statement_cache_size: None,
+                transaction_pooling: None,
+                slow_query_ms: None,
+                statement_timeout_ms: None,
+                application_name: None,
+                explain_slow_queries: None,
+                notify_channel: None,
+                rediscover_interval_secs: None,
                 auto_publish: OptBoolObj::NoValue,
                 tables: None,
                 functions: None,
+                rasters: None,
             })
             .collect();
 
@@ -95,6 +123,18 @@ impl PgArgs {
                 c.max_feature_count = self.max_feature_count;
             });
         }
+        if self.bounds_timeout.is_some() {
+            info!("Overriding bounds timeout to {}s on all Postgres connections because of a CLI parameter", self.bounds_timeout.unwrap());
+            pg_config.iter_mut().for_each(|c| {
+                c.bounds_timeout = self.bounds_timeout;
+            });
+        }
+        if self.deterministic {
+            info!("Enabling deterministic tile generation on all Postgres connections because of a CLI parameter");
+            pg_config.iter_mut().for_each(|c| {
+                c.deterministic = Some(true);
+            });
+        }
 
         if self.ca_root_file.is_some() {
             info!("Overriding root certificate file to {} on all Postgres connections because of a CLI parameter",
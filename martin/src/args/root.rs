@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use log::warn;
 
 use crate::args::connections::Arguments;
@@ -9,7 +9,7 @@ use crate::args::pg::PgArgs;
 use crate::args::srv::SrvArgs;
 use crate::args::State::{Ignore, Share, Take};
 use crate::config::Config;
-use crate::file_config::FileConfigEnum;
+use crate::file_config::{has_extension, FileConfigEnum};
 use crate::MartinError::ConfigAndConnectionsError;
 use crate::{MartinResult, OptOneMany};
 
@@ -24,6 +24,74 @@ pub struct Args {
     pub srv: SrvArgs,
     #[command(flatten)]
     pub pg: Option<PgArgs>,
+    #[command(subcommand)]
+    pub command: Option<MartinCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum MartinCommand {
+    /// Serve a small bundled dataset with sprites, fonts, and a map at the root path, without any configuration.
+    Demo(DemoArgs),
+    /// Create a config file by answering a few questions about the data to publish.
+    Init(InitArgs),
+    /// Replay random or recorded tile requests, and report the throughput and the latency of each zoom.
+    Bench(BenchArgs),
+    /// Validate the config, check that all files exist, connect to each Postgres database, and load the
+    /// sprites, fonts, and styles. Reports all problems, and exits with an error if there are any.
+    Check,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct DemoArgs {
+    /// Directory to write the demo data to [default: martin-demo in the system temp directory]
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct InitArgs {
+    /// Config file to create
+    #[arg(short, long, default_value = "config.yaml")]
+    pub output: PathBuf,
+    /// Overwrite the config file if it already exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq, Default)]
+pub struct BenchArgs {
+    /// Tile sources to request, or comma-separated composite sources [default: all tile sources]
+    #[arg(long)]
+    pub source: Vec<String>,
+    /// File with the recorded requests to replay instead of the random ones, e.g. exported by `/_/traffic`
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+    /// Number of random requests
+    #[arg(long, default_value = "1000")]
+    pub requests: usize,
+    /// Number of requests sent at the same time
+    #[arg(long, default_value = "8")]
+    pub concurrency: usize,
+    /// Minimum zoom level of the random requests
+    #[arg(long)]
+    pub min_zoom: Option<u8>,
+    /// Maximum zoom level of the random requests [default: maxzoom of each source, or 14]
+    #[arg(long)]
+    pub max_zoom: Option<u8>,
+    /// Seed of the random requests, so that the runs with the same seed request the same tiles
+    #[arg(long, default_value = "1")]
+    pub seed: u64,
+    /// Send the requests to a running server, e.g. `http://localhost:3000`, instead of calling the sources in-process
+    #[arg(long)]
+    pub url: Option<String>,
+}
+
+/// Output format of `--dump-catalog`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    #[default]
+    Json,
+    Yaml,
 }
 
 // None of these params will be transferred to the config
@@ -39,6 +107,21 @@ pub struct MetaArgs {
     /// By default, only print if sources are auto-detected.
     #[arg(long)]
     pub save_config: Option<PathBuf>,
+    /// Save the resulting config with all discovered sources listed explicitly and auto-discovery disabled,
+    /// or use "-" to print to stdout. Martin exits without serving tiles. Use it to move sources to another instance.
+    #[arg(long)]
+    pub export_config: Option<PathBuf>,
+    /// Check that all sources listed in the config are available with the same IDs, and exit without serving tiles.
+    #[arg(long)]
+    pub validate_config: bool,
+    /// Print the effective config, with the CLI arguments, the environment, and all discovered sources applied,
+    /// and exit without serving tiles.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Discover all sources, print the catalog with the `TileJSON` of each tile source to stdout, and exit
+    /// without serving tiles. Use it to compare the published sources of two releases.
+    #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "json")]
+    pub dump_catalog: Option<DumpFormat>,
     /// **Deprecated** Scan for new sources on sources list requests
     #[arg(short, long, hide = true)]
     pub watch: bool,
@@ -55,6 +138,9 @@ pub struct ExtraArgs {
     /// Export a font file or a directory with font files as a font source (recursive). Can be specified multiple times.
     #[arg(short, long)]
     pub font: Vec<PathBuf>,
+    /// Export a style JSON file, or a directory with style JSON files, as a style. Can be specified multiple times.
+    #[arg(long)]
+    pub style: Vec<PathBuf>,
 }
 
 impl Args {
@@ -92,6 +178,30 @@ impl Args {
             config.mbtiles = parse_file_args(&mut cli_strings, "mbtiles");
         }
 
+        if !cli_strings.is_empty() {
+            config.cog = parse_file_args(&mut cli_strings, "tif");
+        }
+
+        if !cli_strings.is_empty() {
+            config.gpkg = parse_file_args(&mut cli_strings, "gpkg");
+        }
+
+        if !cli_strings.is_empty() {
+            config.geoparquet = parse_file_args(&mut cli_strings, "parquet");
+        }
+
+        if !cli_strings.is_empty() {
+            config.osm = parse_file_args(&mut cli_strings, "osm.pbf");
+        }
+
+        if !cli_strings.is_empty() {
+            config.shapefile = parse_file_args(&mut cli_strings, "shp");
+        }
+
+        if !cli_strings.is_empty() {
+            config.sqlite = parse_file_args(&mut cli_strings, "sqlite");
+        }
+
         if !self.extras.sprite.is_empty() {
             config.sprites = FileConfigEnum::new(self.extras.sprite);
         }
@@ -100,6 +210,10 @@ impl Args {
             config.fonts = OptOneMany::new(self.extras.font);
         }
 
+        if !self.extras.style.is_empty() {
+            config.styles = FileConfigEnum::new(self.extras.style);
+        }
+
         cli_strings.check()
     }
 }
@@ -109,7 +223,7 @@ pub fn parse_file_args(cli_strings: &mut Arguments, extension: &str) -> FileConf
         Ok(v) => {
             if v.is_dir() {
                 Share(v)
-            } else if v.is_file() && v.extension().map_or(false, |e| e == extension) {
+            } else if v.is_file() && has_extension(&v, extension) {
                 Take(v)
             } else {
                 Ignore
@@ -161,6 +275,47 @@ mod tests {
         };
         assert_eq!(args, (Config::default(), meta));
 
+        let args = parse(&[
+            "martin",
+            "-c",
+            "c.toml",
+            "--export-config",
+            "-",
+            "--validate-config",
+        ])
+        .unwrap();
+        let meta = MetaArgs {
+            config: Some(PathBuf::from("c.toml")),
+            export_config: Some(PathBuf::from("-")),
+            validate_config: true,
+            ..Default::default()
+        };
+        assert_eq!(args, (Config::default(), meta));
+
+        let args = parse(&["martin", "-c", "c.toml", "--print-config"]).unwrap();
+        let meta = MetaArgs {
+            config: Some(PathBuf::from("c.toml")),
+            print_config: true,
+            ..Default::default()
+        };
+        assert_eq!(args, (Config::default(), meta));
+
+        let args = parse(&["martin", "-c", "c.toml", "--dump-catalog"]).unwrap();
+        let meta = MetaArgs {
+            config: Some(PathBuf::from("c.toml")),
+            dump_catalog: Some(DumpFormat::Json),
+            ..Default::default()
+        };
+        assert_eq!(args, (Config::default(), meta));
+
+        let args = parse(&["martin", "--dump-catalog", "yaml", "-c", "c.toml"]).unwrap();
+        let meta = MetaArgs {
+            config: Some(PathBuf::from("c.toml")),
+            dump_catalog: Some(DumpFormat::Yaml),
+            ..Default::default()
+        };
+        assert_eq!(args, (Config::default(), meta));
+
         let args = parse(&["martin", "postgres://connection"]).unwrap();
         let cfg = Config {
             postgres: OptOneMany::One(PgConfig {
@@ -197,6 +352,26 @@ mod tests {
         assert!(matches!(err, ConfigAndConnectionsError(..)));
     }
 
+    #[test]
+    fn cli_demo() {
+        let args = Args::parse_from(["martin", "demo"]);
+        assert_eq!(args.command, Some(MartinCommand::Demo(DemoArgs::default())));
+        let args = Args::parse_from(["martin", "--listen-addresses", "[::]:3001", "demo"]);
+        assert_eq!(args.srv.listen_addresses, vec!["[::]:3001".to_string()]);
+        assert!(Args::try_parse_from(["martin", "demo", "postgres://a"]).is_err());
+
+        let args = Args::parse_from(["martin", "init", "-o", "m.yaml"]);
+        let Some(MartinCommand::Init(init)) = args.command else {
+            panic!("Expected init command");
+        };
+        assert_eq!(init.output, PathBuf::from("m.yaml"));
+        assert!(!init.force);
+
+        let args = Args::parse_from(["martin", "-c", "m.yaml", "check"]);
+        assert_eq!(args.command, Some(MartinCommand::Check));
+        assert_eq!(args.meta.config, Some(PathBuf::from("m.yaml")));
+    }
+
     #[test]
     fn cli_unknown_con_str() {
         let args = Args::parse_from(["martin", "foobar"]);
@@ -1,12 +1,13 @@
 use crate::srv::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+use crate::utils::OptOneMany;
 
 #[derive(clap::Args, Debug, PartialEq, Default)]
 #[command(about, version)]
 pub struct SrvArgs {
     #[arg(help = format!("Connection keep alive timeout. [DEFAULT: {}]", KEEP_ALIVE_DEFAULT), short, long)]
     pub keep_alive: Option<u64>,
-    #[arg(help = format!("The socket address to bind. [DEFAULT: {}]", LISTEN_ADDRESSES_DEFAULT), short, long)]
-    pub listen_addresses: Option<String>,
+    #[arg(help = format!("The socket address to bind, can be repeated. [DEFAULT: {}]", LISTEN_ADDRESSES_DEFAULT), short, long)]
+    pub listen_addresses: Vec<String>,
     /// Number of web server workers
     #[arg(short = 'W', long)]
     pub workers: Option<usize>,
@@ -18,8 +19,8 @@ impl SrvArgs {
         if self.keep_alive.is_some() {
             srv_config.keep_alive = self.keep_alive;
         }
-        if self.listen_addresses.is_some() {
-            srv_config.listen_addresses = self.listen_addresses;
+        if !self.listen_addresses.is_empty() {
+            srv_config.listen_addresses = OptOneMany::new(self.listen_addresses);
         }
         if self.workers.is_some() {
             srv_config.worker_processes = self.workers;
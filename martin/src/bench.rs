@@ -0,0 +1,462 @@
+//! `martin bench`: replay random or recorded tile requests against the configured sources,
+//! and report the throughput and the latency percentiles of each zoom level.
+
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use actix_web::http::Uri;
+use futures::{stream, StreamExt as _};
+use log::{info, warn};
+use tilejson::{Bounds, TileJSON};
+
+use crate::args::BenchArgs;
+use crate::config::ServerState;
+use crate::srv::{get_tile_response, SrvConfig, TileOptions};
+use crate::MartinError::{BenchReadError, InvalidBench};
+use crate::{MartinResult, TileCoord};
+
+/// Zoom levels requested from the sources without a `maxzoom` in their `TileJSON`
+const BENCH_MAX_ZOOM_DEFAULT: u8 = 14;
+/// Percentiles of the latencies shown in the report
+const PERCENTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// A tile request to replay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchRequest {
+    /// One or more comma-separated source IDs
+    pub source_ids: String,
+    pub xyz: TileCoord,
+    /// URL query without the leading `?`, may be empty
+    pub query: String,
+}
+
+impl BenchRequest {
+    /// Parse a tile URL or path, e.g. `/roads/3/2/1?lang=en`, ignoring any path prefix before the source IDs
+    #[must_use]
+    pub fn parse(url: &str) -> Option<Self> {
+        let uri = url.parse::<Uri>().ok()?;
+        let mut segments = uri.path().trim_matches('/').rsplit('/');
+        let y = segments.next()?.parse().ok()?;
+        let x = segments.next()?.parse().ok()?;
+        let z = segments.next()?.parse().ok()?;
+        let source_ids = segments.next().filter(|v| !v.is_empty())?.to_string();
+        let xyz = TileCoord { z, x, y };
+        xyz.is_valid().then(|| Self {
+            source_ids,
+            xyz,
+            query: uri.query().unwrap_or_default().to_string(),
+        })
+    }
+
+    fn path(&self) -> String {
+        let TileCoord { z, x, y } = self.xyz;
+        let mut path = format!("/{}/{z}/{x}/{y}", self.source_ids);
+        if !self.query.is_empty() {
+            path.push('?');
+            path.push_str(&self.query);
+        }
+        path
+    }
+}
+
+/// Read the recorded requests exported by the `/_/traffic` endpoint, either as a k6 JSON array
+/// of URLs, or as `GET <url>` vegeta targets. Plain URLs or paths, one per line, are also accepted.
+/// The requests of anything but the tiles, e.g. `GeoJSON` tiles, are skipped.
+pub fn parse_recorded_requests(text: &str) -> Result<Vec<BenchRequest>, String> {
+    let urls: Vec<String> = if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).map_err(|e| e.to_string())?
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|v| !v.is_empty() && !v.starts_with('#'))
+            .map(|v| v.strip_prefix("GET ").unwrap_or(v).trim().to_string())
+            .collect()
+    };
+    let requests: Vec<_> = urls.iter().filter_map(|v| BenchRequest::parse(v)).collect();
+    let skipped = urls.len() - requests.len();
+    if skipped > 0 {
+        warn!("Skipped {skipped} recorded requests that are not tile requests");
+    }
+    Ok(requests)
+}
+
+/// Small deterministic random generator, so that the runs with the same seed request the same tiles
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in the inclusive range
+    fn between(&mut self, min: u32, max: u32) -> u32 {
+        let count = u64::from(max - min) + 1;
+        // the remainder is always below `count`, so it fits into u32
+        min + u32::try_from(self.next_u64() % count).unwrap_or_default()
+    }
+}
+
+/// Convert longitude and latitude to tile index
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn tile_index(lon: f64, lat: f64, zoom: u8) -> (u32, u32) {
+    let n = f64::from(1_u32 << zoom);
+    let lat = lat.clamp(-85.051_128_779_806_59, 85.051_128_779_806_59);
+    let x = ((lon + 180.0) / 360.0 * n).floor().max(0.0) as u32;
+    let y = ((1.0 - (lat.to_radians().tan() + 1.0 / lat.to_radians().cos()).ln() / PI) / 2.0 * n)
+        .floor()
+        .max(0.0) as u32;
+    let max_value = (1_u32 << zoom) - 1;
+    (x.min(max_value), y.min(max_value))
+}
+
+/// Generate random tile requests within the zoom levels and the bounds of each source,
+/// limited to the zoom levels of the arguments
+pub fn random_requests(
+    sources: &[(String, TileJSON)],
+    args: &BenchArgs,
+) -> Result<Vec<BenchRequest>, String> {
+    let mut targets = Vec::new();
+    for (id, tj) in sources {
+        let min_zoom = tj.minzoom.unwrap_or(0).max(args.min_zoom.unwrap_or(0));
+        let max_zoom = tj
+            .maxzoom
+            .unwrap_or(BENCH_MAX_ZOOM_DEFAULT)
+            .min(args.max_zoom.unwrap_or(u8::MAX));
+        if min_zoom > max_zoom {
+            return Err(format!(
+                "source {id} has no tiles between zoom {min_zoom} and {max_zoom}"
+            ));
+        }
+        targets.push((
+            id,
+            min_zoom,
+            max_zoom,
+            tj.bounds.unwrap_or(Bounds::MAX_TILED),
+        ));
+    }
+    if targets.is_empty() {
+        return Err("there are no tile sources to request".to_string());
+    }
+
+    let mut rng = SplitMix64(args.seed);
+    let last = u32::try_from(targets.len() - 1).unwrap_or(u32::MAX);
+    let requests = (0..args.requests)
+        .map(|_| {
+            let (id, min_zoom, max_zoom, bounds) = targets[rng.between(0, last) as usize];
+            let z = u8::try_from(rng.between(min_zoom.into(), max_zoom.into())).unwrap_or_default();
+            let (min_x, min_y) = tile_index(bounds.left, bounds.top, z);
+            let (max_x, max_y) = tile_index(bounds.right, bounds.bottom, z);
+            BenchRequest {
+                source_ids: id.clone(),
+                xyz: TileCoord {
+                    z,
+                    x: rng.between(min_x, max_x),
+                    y: rng.between(min_y, max_y),
+                },
+                query: String::new(),
+            }
+        })
+        .collect();
+    Ok(requests)
+}
+
+/// Latencies of the requests of one zoom level
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZoomStats {
+    pub latencies: Vec<Duration>,
+    pub errors: usize,
+}
+
+impl ZoomStats {
+    /// Latency that the given share of the requests did not exceed, using the nearest rank
+    #[must_use]
+    pub fn percentile(&self, share: f64) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let rank = (share * sorted.len() as f64).ceil() as usize;
+        sorted
+            .get(rank.saturating_sub(1).min(sorted.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Result of a benchmark run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchReport {
+    pub elapsed: Duration,
+    pub zooms: BTreeMap<u8, ZoomStats>,
+}
+
+impl BenchReport {
+    fn add(&mut self, zoom: u8, latency: Duration, success: bool) {
+        let stats = self.zooms.entry(zoom).or_default();
+        stats.latencies.push(latency);
+        if !success {
+            stats.errors += 1;
+        }
+    }
+
+    /// Statistics of all zoom levels combined
+    #[must_use]
+    pub fn total(&self) -> ZoomStats {
+        ZoomStats {
+            latencies: self
+                .zooms
+                .values()
+                .flat_map(|v| v.latencies.iter().copied())
+                .collect(),
+            errors: self.zooms.values().map(|v| v.errors).sum(),
+        }
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = |v: Duration| v.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            "{:>5} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9}",
+            "zoom", "requests", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        )?;
+        let total = self.total();
+        let rows = self
+            .zooms
+            .iter()
+            .map(|(z, v)| (z.to_string(), v))
+            .chain([("all".to_string(), &total)]);
+        for (zoom, stats) in rows {
+            let [p50, p90, p99] = PERCENTILES.map(|p| ms(stats.percentile(p)));
+            let max = ms(stats.latencies.iter().max().copied().unwrap_or_default());
+            writeln!(
+                f,
+                "{zoom:>5} {:>9} {:>7} {p50:>9.2} {p90:>9.2} {p99:>9.2} {max:>9.2}",
+                stats.latencies.len(),
+                stats.errors,
+            )?;
+        }
+        let secs = self.elapsed.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let throughput = if secs > 0.0 {
+            total.latencies.len() as f64 / secs
+        } else {
+            0.0
+        };
+        write!(f, "Throughput: {throughput:.1} requests/s in {secs:.2} s")
+    }
+}
+
+/// Run the benchmark and print its report
+pub async fn run_bench(args: &BenchArgs, srv: &SrvConfig, state: &ServerState) -> MartinResult<()> {
+    let requests = if let Some(path) = &args.replay {
+        let text = std::fs::read_to_string(path).map_err(|e| BenchReadError(e, path.clone()))?;
+        parse_recorded_requests(&text).map_err(InvalidBench)?
+    } else {
+        let ids: Vec<String> = if args.source.is_empty() {
+            state.tiles.get_catalog().into_keys().collect()
+        } else {
+            args.source.clone()
+        };
+        let mut sources = Vec::new();
+        for id in ids {
+            let (srcs, _, _) = state
+                .tiles
+                .get_sources(&id, None)
+                .map_err(|e| InvalidBench(e.to_string()))?;
+            let tilejson = srcs.first().map(|src| src.get_tilejson().clone());
+            sources.extend(tilejson.map(|tj| (id, tj)));
+        }
+        random_requests(&sources, args).map_err(InvalidBench)?
+    };
+    if requests.is_empty() {
+        return Err(InvalidBench("there are no requests to replay".to_string()));
+    }
+
+    let target = args.url.as_deref().unwrap_or("the sources in-process");
+    info!(
+        "Sending {} tile requests to {target}, {} at a time",
+        requests.len(),
+        args.concurrency
+    );
+    let report = if let Some(url) = &args.url {
+        bench_http(&requests, args.concurrency, url.trim_end_matches('/')).await
+    } else {
+        let options = TileOptions::new(srv, state);
+        bench_in_process(&requests, args.concurrency, state, &options).await
+    };
+    println!("{report}");
+    Ok(())
+}
+
+/// Call the tile handler directly, bypassing the HTTP layer
+async fn bench_in_process(
+    requests: &[BenchRequest],
+    concurrency: usize,
+    state: &ServerState,
+    options: &TileOptions,
+) -> BenchReport {
+    run_requests(requests, concurrency, |req| async move {
+        let response = get_tile_response(
+            &state.tiles,
+            options,
+            req.xyz,
+            &req.source_ids,
+            &req.query,
+            None,
+            None,
+        )
+        .await;
+        response.map_or(false, |v| v.status().is_success())
+    })
+    .await
+}
+
+/// Send the requests to a running server, reading each response to the end
+async fn bench_http(requests: &[BenchRequest], concurrency: usize, url: &str) -> BenchReport {
+    let client = reqwest::Client::new();
+    let client = &client;
+    run_requests(requests, concurrency, |req| async move {
+        match client.get(format!("{url}{}", req.path())).send().await {
+            Ok(response) if response.status().is_success() => response.bytes().await.is_ok(),
+            _ => false,
+        }
+    })
+    .await
+}
+
+async fn run_requests<'a, F, Fut>(
+    requests: &'a [BenchRequest],
+    concurrency: usize,
+    send: F,
+) -> BenchReport
+where
+    F: Fn(&'a BenchRequest) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let start = Instant::now();
+    let results: Vec<_> = stream::iter(requests)
+        .map(|req| {
+            let response = send(req);
+            async move {
+                let started = Instant::now();
+                let success = response.await;
+                (req.xyz.z, started.elapsed(), success)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    let mut report = BenchReport {
+        elapsed: start.elapsed(),
+        ..Default::default()
+    };
+    for (zoom, latency, success) in results {
+        report.add(zoom, latency, success);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::tilejson;
+
+    use super::*;
+
+    fn request(source_ids: &str, z: u8, x: u32, y: u32, query: &str) -> BenchRequest {
+        BenchRequest {
+            source_ids: source_ids.to_string(),
+            xyz: TileCoord { z, x, y },
+            query: query.to_string(),
+        }
+    }
+
+    #[test]
+    fn recorded_requests() {
+        let vegeta = "GET https://staging.example.com/roads/3/2/1?lang=en\n\nGET https://staging.example.com/roads,rail/0/0/0\n# comment\nGET https://staging.example.com/roads/3/2/1.geojson\n";
+        assert_eq!(
+            parse_recorded_requests(vegeta).unwrap(),
+            vec![
+                request("roads", 3, 2, 1, "lang=en"),
+                request("roads,rail", 0, 0, 0, ""),
+            ]
+        );
+        let k6 = r#"["http://localhost:3000/tenant/roads/3/2/1", "/roads/1/5/0"]"#;
+        assert_eq!(
+            parse_recorded_requests(k6).unwrap(),
+            vec![request("roads", 3, 2, 1, "")]
+        );
+        assert_eq!(
+            request("roads", 3, 2, 1, "lang=en").path(),
+            "/roads/3/2/1?lang=en"
+        );
+    }
+
+    #[test]
+    fn random_tiles() {
+        let mut europe = tilejson! { tiles: vec![] };
+        europe.minzoom = Some(4);
+        europe.maxzoom = Some(6);
+        europe.bounds = Some(Bounds::new(-10.0, 35.0, 30.0, 60.0));
+        let world = tilejson! { tiles: vec![] };
+        let args = BenchArgs {
+            requests: 500,
+            max_zoom: Some(5),
+            seed: 7,
+            ..Default::default()
+        };
+        let sources = [("europe".to_string(), europe), ("world".to_string(), world)];
+        let requests = random_requests(&sources, &args).unwrap();
+        assert_eq!(requests.len(), 500);
+        assert_eq!(requests, random_requests(&sources, &args).unwrap());
+        for req in &requests {
+            assert!(req.xyz.is_valid());
+            assert!(req.xyz.z <= 5);
+            if req.source_ids == "europe" {
+                let (min_x, min_y) = tile_index(-10.0, 60.0, req.xyz.z);
+                let (max_x, max_y) = tile_index(30.0, 35.0, req.xyz.z);
+                assert!(req.xyz.z >= 4);
+                assert!((min_x..=max_x).contains(&req.xyz.x));
+                assert!((min_y..=max_y).contains(&req.xyz.y));
+            }
+        }
+        assert!(requests.iter().any(|v| v.source_ids == "world"));
+
+        let args = BenchArgs {
+            max_zoom: Some(2),
+            ..args
+        };
+        assert!(random_requests(&sources, &args).is_err());
+    }
+
+    #[test]
+    fn report_percentiles() {
+        let stats = ZoomStats {
+            latencies: (1..=100).rev().map(Duration::from_millis).collect(),
+            errors: 0,
+        };
+        assert_eq!(stats.percentile(0.5), Duration::from_millis(50));
+        assert_eq!(stats.percentile(0.99), Duration::from_millis(99));
+        assert_eq!(ZoomStats::default().percentile(0.5), Duration::ZERO);
+
+        let mut report = BenchReport::default();
+        report.add(3, Duration::from_millis(10), true);
+        report.add(3, Duration::from_millis(30), false);
+        report.add(0, Duration::from_millis(20), true);
+        assert_eq!(report.total().latencies.len(), 3);
+        assert_eq!(report.total().errors, 1);
+        let text = report.to_string();
+        assert!(text.contains("    3         2       1"), "{text}");
+        assert!(text.contains("  all         3       1"), "{text}");
+    }
+}
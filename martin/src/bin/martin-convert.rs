@@ -0,0 +1,87 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use log::{error, info, log_enabled};
+use martin::pmtiles::{mbtiles_to_pmtiles, pmtiles_to_mbtiles};
+use martin::MartinError;
+use mbtiles::{MbtType, MbtTypeCli};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Parser, Debug, PartialEq)]
+#[command(
+    about = "A tool to convert an mbtiles file into a pmtiles file, or a pmtiles file into an mbtiles file",
+    version
+)]
+pub struct ConvertArgs {
+    /// File to convert, with the `.mbtiles` or `.pmtiles` extension
+    pub input_file: PathBuf,
+    /// File to create, with the other extension. The file must not exist yet.
+    pub output_file: PathBuf,
+    /// Schema of the output mbtiles file. Defaults to 'normalized', which stores identical tiles once.
+    #[arg(long = "mbtiles-type", value_name = "SCHEMA", value_enum)]
+    pub mbt_type: Option<MbtTypeCli>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum MartinConvertError {
+    #[error(transparent)]
+    Martin(#[from] MartinError),
+    #[error("Output file {} already exists", .0.display())]
+    OutputExists(PathBuf),
+    #[error("Unable to convert {} into {}, the files must have the .mbtiles and .pmtiles extensions", .0.display(), .1.display())]
+    UnsupportedConversion(PathBuf, PathBuf),
+}
+
+async fn start(args: ConvertArgs) -> Result<(), MartinConvertError> {
+    info!("Martin-Convert tile converter v{VERSION}");
+    let (input, output) = (&args.input_file, &args.output_file);
+    if output.exists() {
+        return Err(MartinConvertError::OutputExists(output.clone()));
+    }
+    let count = match (extension(input).as_deref(), extension(output).as_deref()) {
+        (Some("mbtiles"), Some("pmtiles")) => mbtiles_to_pmtiles(input, output.clone()).await?,
+        (Some("pmtiles"), Some("mbtiles")) => {
+            let mbt_type = match args.mbt_type.unwrap_or(MbtTypeCli::Normalized) {
+                MbtTypeCli::Flat => MbtType::Flat,
+                MbtTypeCli::FlatWithHash => MbtType::FlatWithHash,
+                MbtTypeCli::Normalized => MbtType::Normalized { hash_view: true },
+            };
+            pmtiles_to_mbtiles(input, output, mbt_type).await?
+        }
+        _ => {
+            return Err(MartinConvertError::UnsupportedConversion(
+                input.clone(),
+                output.clone(),
+            ))
+        }
+    };
+    info!("Converted {count} tiles into {}", output.display());
+    Ok(())
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+#[actix_web::main]
+async fn main() {
+    let env = env_logger::Env::default().default_filter_or("martin_convert=info");
+    env_logger::Builder::from_env(env).init();
+
+    start(ConvertArgs::parse())
+        .await
+        .unwrap_or_else(|e| on_error(e));
+}
+
+fn on_error<E: Display>(e: E) -> ! {
+    // Ensure the message is printed, even if the logging is disabled
+    if log_enabled!(log::Level::Error) {
+        error!("{e}");
+    } else {
+        eprintln!("{e}");
+    }
+    std::process::exit(1);
+}
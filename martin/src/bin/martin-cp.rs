@@ -1,28 +1,32 @@
-use std::f64::consts::PI;
+use std::collections::BTreeSet;
 use std::fmt::{Debug, Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
 use actix_http::error::ParseError;
 use actix_http::test::TestRequest;
-use actix_web::http::header::{AcceptEncoding, Header as _, ACCEPT_ENCODING};
+use actix_web::http::header::{AcceptEncoding, Header as _, HeaderMap, ACCEPT_ENCODING};
 use clap::Parser;
 use futures::stream::{self, StreamExt};
 use futures::TryStreamExt;
-use log::{debug, error, info, log_enabled};
+use log::{debug, error, info, log_enabled, warn};
 use martin::args::{Args, ExtraArgs, MetaArgs, OsEnv, PgArgs, SrvArgs};
-use martin::srv::{get_tile_content, merge_tilejson, RESERVED_KEYWORDS};
+use martin::pmtiles::PmtWriter;
+use martin::srv::{
+    get_tile_content, merge_tilejson, CompositeTileJsonConfig, TileOptions, RESERVED_KEYWORDS,
+};
 use martin::{
     append_rect, read_config, Config, IdResolver, MartinError, MartinResult, ServerState, Source,
-    TileCoord, TileData, TileRect,
+    TileArea, TileCoord, TileData, TileRect,
 };
-use martin_tile_utils::TileInfo;
-use mbtiles::sqlx::SqliteConnection;
+use martin_tile_utils::{tile_index, TileInfo};
+use mbtiles::sqlx::{Connection as _, SqliteConnection};
 use mbtiles::{
     init_mbtiles_schema, is_empty_database, CopyDuplicateMode, MbtType, MbtTypeCli, Mbtiles,
 };
-use tilejson::Bounds;
+use serde_json::json;
+use tilejson::{Bounds, TileJSON};
 use tokio::sync::mpsc::channel;
 use tokio::time::Instant;
 use tokio::try_join;
@@ -32,10 +36,12 @@ const SAVE_EVERY: Duration = Duration::from_secs(60);
 const PROGRESS_REPORT_AFTER: u64 = 100;
 const PROGRESS_REPORT_EVERY: Duration = Duration::from_secs(2);
 const BATCH_SIZE: usize = 1000;
+/// Metadata key with the progress of an unfinished copy, removed once the copy is done
+const PROGRESS_KEY: &str = "martin-cp.progress";
 
 #[derive(Parser, Debug, PartialEq, Default)]
 #[command(
-    about = "A tool to bulk copy tiles from any Martin-supported sources into an mbtiles file",
+    about = "A tool to bulk copy tiles from any Martin-supported sources into an mbtiles or pmtiles file",
     version
 )]
 pub struct CopierArgs {
@@ -53,10 +59,11 @@ pub struct CopyArgs {
     /// Name of the source to copy from.
     #[arg(short, long)]
     pub source: String,
-    /// Path to the mbtiles file to copy to.
+    /// Path to the mbtiles file to copy to. With the `.pmtiles` extension, a new clustered `PMTiles` archive is written instead,
+    /// and the file must not exist yet.
     #[arg(short, long)]
     pub output_file: PathBuf,
-    /// Output format of the new destination file. Ignored if the file exists. Defaults to 'normalized'.
+    /// Output format of the new destination mbtiles file. Ignored if the file exists. Defaults to 'normalized'.
     #[arg(
         long = "mbtiles-type",
         alias = "dst-type",
@@ -82,6 +89,9 @@ pub struct CopyArgs {
     /// Bounds to copy. Can be specified multiple times. Overlapping regions will be handled correctly.
     #[arg(long)]
     pub bbox: Vec<Bounds>,
+    /// `GeoJSON` file with the polygons to copy. Only the tiles intersecting the polygons are copied.
+    #[arg(long, value_name = "GEOJSON", conflicts_with("bbox"))]
+    pub area: Option<PathBuf>,
     /// Minimum zoom level to copy
     #[arg(long, alias = "minzoom", conflicts_with("zoom_levels"))]
     pub min_zoom: Option<u8>,
@@ -96,6 +106,10 @@ pub struct CopyArgs {
     /// List of zoom levels to copy
     #[arg(short, long, alias = "zooms", value_delimiter = ',')]
     pub zoom_levels: Vec<u8>,
+    /// Continue an interrupted copy into the same mbtiles file, skipping the tiles it has already saved.
+    /// The other arguments must be the same as those of the interrupted copy.
+    #[arg(long)]
+    pub resume: bool,
 }
 
 async fn start(copy_args: CopierArgs) -> MartinCpResult<()> {
@@ -116,6 +130,7 @@ async fn start(copy_args: CopierArgs) -> MartinCpResult<()> {
         extras: ExtraArgs::default(),
         srv: SrvArgs::default(),
         pg: copy_args.pg,
+        command: None,
     };
 
     args.merge_into_config(&mut config, &env)?;
@@ -131,18 +146,7 @@ async fn start(copy_args: CopierArgs) -> MartinCpResult<()> {
     run_tile_copy(copy_args.copy, sources).await
 }
 
-/// Convert longitude and latitude to tile index
-#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-fn tile_index(lon: f64, lat: f64, zoom: u8) -> (u32, u32) {
-    let n = f64::from(1_u32 << zoom);
-    let x = ((lon + 180.0) / 360.0 * n).floor() as u32;
-    let y = ((1.0 - (lat.to_radians().tan() + 1.0 / lat.to_radians().cos()).ln() / PI) / 2.0 * n)
-        .floor() as u32;
-    let max_value = (1_u32 << zoom) - 1;
-    (x.min(max_value), y.min(max_value))
-}
-
-fn compute_tile_ranges(args: &CopyArgs) -> Vec<TileRect> {
+fn compute_tile_ranges(args: &CopyArgs, area: Option<&TileArea>) -> Vec<TileRect> {
     let mut ranges = Vec::new();
     let mut zooms_vec = Vec::new();
     let zooms = if let Some(max_zoom) = args.max_zoom {
@@ -158,6 +162,11 @@ fn compute_tile_ranges(args: &CopyArgs) -> Vec<TileRect> {
         args.bbox.clone()
     };
     for zoom in zooms {
+        if let Some(area) = area {
+            // the ranges of the same zoom level do not overlap
+            ranges.extend(area.tile_ranges(*zoom));
+            continue;
+        }
         for bbox in &boxes {
             let (min_x, min_y) = tile_index(bbox.left, bbox.top, *zoom);
             let (max_x, max_y) = tile_index(bbox.right, bbox.bottom, *zoom);
@@ -171,8 +180,11 @@ fn compute_tile_ranges(args: &CopyArgs) -> Vec<TileRect> {
 }
 
 struct TileXyz {
+    /// Position of the tile in the order of generation
+    index: u64,
     xyz: TileCoord,
     data: TileData,
+    info: TileInfo,
 }
 
 impl Debug for TileXyz {
@@ -185,16 +197,19 @@ struct Progress {
     // needed to compute elapsed time
     start_time: Instant,
     total: u64,
+    /// Tiles copied before an interrupted copy was resumed
+    skipped: u64,
     empty: AtomicU64,
     non_empty: AtomicU64,
 }
 
 impl Progress {
-    pub fn new(tiles: &[TileRect]) -> Self {
+    pub fn new(tiles: &[TileRect], skipped: u64) -> Self {
         let total = tiles.iter().map(TileRect::size).sum();
         Progress {
             start_time: Instant::now(),
             total,
+            skipped,
             empty: AtomicU64::default(),
             non_empty: AtomicU64::default(),
         }
@@ -213,6 +228,16 @@ enum MartinCpError {
     Actix(#[from] actix_web::Error),
     #[error(transparent)]
     Mbt(#[from] mbtiles::MbtError),
+    #[error("PMTiles output file {} already exists", .0.display())]
+    PmtOutputExists(PathBuf),
+    #[error("Unable to read area file {}: {0}", .1.display())]
+    AreaRead(std::io::Error, PathBuf),
+    #[error("Area file {} is not valid: {0}", .1.display())]
+    InvalidArea(String, PathBuf),
+    #[error("Copies into PMTiles files cannot be resumed")]
+    PmtResume,
+    #[error("Unable to resume the copy into {}, because it was started with different arguments", .0.display())]
+    ResumeMismatch(PathBuf),
 }
 
 impl Display for Progress {
@@ -222,10 +247,11 @@ impl Display for Progress {
         let elapsed_s = elapsed.as_secs_f32();
         let non_empty = self.non_empty.load(Ordering::Relaxed);
         let empty = self.empty.load(Ordering::Relaxed);
-        let done = non_empty + empty;
+        let generated = non_empty + empty;
+        let done = generated + self.skipped;
         let percent = done * 100 / self.total;
         let speed = if elapsed_s > 0.0 {
-            done as f32 / elapsed_s
+            generated as f32 / elapsed_s
         } else {
             0.0
         };
@@ -237,10 +263,10 @@ impl Display for Progress {
         let left = self.total - done;
         if left == 0 {
             write!(f, " | done")
-        } else if done == 0 {
+        } else if generated == 0 {
             write!(f, " | ??? left")
         } else {
-            let left = Duration::from_secs_f32(elapsed_s * left as f32 / done as f32);
+            let left = Duration::from_secs_f32(elapsed_s * left as f32 / generated as f32);
             write!(f, " | {left:.0?} left")
         }
     }
@@ -255,25 +281,196 @@ fn iterate_tiles(tiles: Vec<TileRect>) -> impl Iterator<Item = TileCoord> {
     })
 }
 
+/// Destination of the copied tiles
+enum TileWriter {
+    Mbtiles {
+        mbt: Mbtiles,
+        conn: SqliteConnection,
+        mbt_type: MbtType,
+        on_duplicate: CopyDuplicateMode,
+        batch: Vec<(u8, u32, u32, Vec<u8>)>,
+        last_saved: Instant,
+        /// Arguments of the copy, saved with its progress to check that a resumed copy matches them
+        copy_args: serde_json::Value,
+    },
+    /// Archive written once all tiles are generated, with the tile info of the generated tiles
+    Pmtiles(PmtWriter, TileInfo),
+}
+
+impl TileWriter {
+    /// Open the destination, returning it with the number of tiles saved by the interrupted copy
+    /// if the copy is resumed
+    async fn new(
+        args: &CopyArgs,
+        sources: &[&dyn Source],
+        tile_info: TileInfo,
+        tiles: &[TileRect],
+    ) -> MartinCpResult<(Self, u64)> {
+        let output_file = &args.output_file;
+        if is_pmtiles(output_file) {
+            if args.resume {
+                return Err(MartinCpError::PmtResume);
+            }
+            if output_file.exists() {
+                return Err(MartinCpError::PmtOutputExists(output_file.clone()));
+            }
+            let writer = PmtWriter::new(output_file.clone()).map_err(MartinError::from)?;
+            return Ok((Self::Pmtiles(writer, tile_info), 0));
+        }
+        let mbt = Mbtiles::new(output_file)?;
+        let mut conn = mbt.open_or_new().await?;
+        let mbt_type = init_schema(&mbt, &mut conn, sources, tile_info, args.mbt_type).await?;
+        let copy_args = json!({
+            "source": args.source,
+            "url_query": args.url_query,
+            "encoding": args.encoding,
+            "tiles": tiles,
+        });
+
+        let progress = mbt.get_metadata_value(&mut conn, PROGRESS_KEY).await?;
+        let done = match progress {
+            Some(progress) if args.resume => {
+                let progress: serde_json::Value =
+                    serde_json::from_str(&progress).unwrap_or_default();
+                if progress.get("args") != Some(&copy_args) {
+                    return Err(MartinCpError::ResumeMismatch(output_file.clone()));
+                }
+                progress["done"].as_u64().unwrap_or_default()
+            }
+            None if args.resume => {
+                warn!(
+                    "{} has no unfinished copy to resume, copying all tiles",
+                    output_file.display()
+                );
+                0
+            }
+            Some(_) => {
+                info!(
+                    "Restarting the unfinished copy into {}, use --resume to continue it instead",
+                    output_file.display()
+                );
+                0
+            }
+            None => 0,
+        };
+
+        let writer = Self::Mbtiles {
+            mbt,
+            conn,
+            mbt_type,
+            on_duplicate: args.on_duplicate,
+            batch: Vec::with_capacity(BATCH_SIZE),
+            last_saved: Instant::now(),
+            copy_args,
+        };
+        Ok((writer, done))
+    }
+
+    fn write(&mut self, tile: TileXyz) -> MartinResult<()> {
+        match self {
+            Self::Mbtiles { batch, .. } => {
+                batch.push((tile.xyz.z, tile.xyz.x, tile.xyz.y, tile.data));
+            }
+            Self::Pmtiles(writer, info) => {
+                // the tiles may have been re-encoded for the requested encoding
+                *info = tile.info;
+                writer.add_tile(tile.xyz, &tile.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the written tiles if the batch is full or was not saved for a while.
+    /// The progress is saved with them, `done` being the number of tiles generated
+    /// in the order of generation, without any gaps.
+    async fn save_if_due(&mut self, done: u64) -> MartinResult<()> {
+        if let Self::Mbtiles {
+            mbt,
+            conn,
+            mbt_type,
+            on_duplicate,
+            batch,
+            last_saved,
+            copy_args,
+        } = self
+        {
+            if batch.len() >= BATCH_SIZE || last_saved.elapsed() > SAVE_EVERY {
+                let progress = json!({ "args": copy_args, "done": done });
+                let mut tx = conn.begin().await.map_err(mbtiles::MbtError::from)?;
+                mbt.insert_tiles(&mut tx, *mbt_type, *on_duplicate, batch)
+                    .await?;
+                mbt.set_metadata_value(&mut *tx, PROGRESS_KEY, progress)
+                    .await?;
+                tx.commit().await.map_err(mbtiles::MbtError::from)?;
+                batch.clear();
+                *last_saved = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(self, sources: &[&dyn Source]) -> MartinCpResult<()> {
+        match self {
+            Self::Mbtiles {
+                mbt,
+                mut conn,
+                mbt_type,
+                on_duplicate,
+                batch,
+                ..
+            } => {
+                let mut tx = conn.begin().await.map_err(mbtiles::MbtError::from)?;
+                if !batch.is_empty() {
+                    mbt.insert_tiles(&mut tx, mbt_type, on_duplicate, &batch)
+                        .await?;
+                }
+                mbt.delete_metadata_value(&mut *tx, PROGRESS_KEY).await?;
+                tx.commit().await.map_err(mbtiles::MbtError::from)?;
+            }
+            Self::Pmtiles(writer, info) => {
+                info!("Writing {}", writer.path().display());
+                let tj = copy_tilejson(sources, info);
+                writer.finish(info, &tj).map_err(MartinError::from)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_area(path: &Path) -> MartinCpResult<TileArea> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| MartinCpError::AreaRead(e, path.to_path_buf()))?;
+    TileArea::from_geojson(&json).map_err(|e| MartinCpError::InvalidArea(e, path.to_path_buf()))
+}
+
+fn is_pmtiles(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("pmtiles"))
+}
+
 async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()> {
-    let output_file = &args.output_file;
     let concurrency = args.concurrency.unwrap_or(1);
-    let (sources, _use_url_query, info) = state.tiles.get_sources(args.source.as_str(), None)?;
+    // without request headers, the variant is selected by the URL query, or the default one is used
+    let url_query = args.url_query.as_deref().unwrap_or_default();
+    let (source_ids, _) = state
+        .tiles
+        .resolve_variants(&args.source, url_query, &HeaderMap::new());
+    let (sources, _use_url_query, info) = state.tiles.get_sources(&source_ids, None)?;
     let sources = sources.as_slice();
     let tile_info = sources.first().unwrap().get_tile_info();
     let (tx, mut rx) = channel::<TileXyz>(500);
-    let tiles = compute_tile_ranges(&args);
-    let mbt = Mbtiles::new(output_file)?;
-    let mut conn = mbt.open_or_new().await?;
-    let mbt_type = init_schema(&mbt, &mut conn, sources, tile_info, args.mbt_type).await?;
+    let area = args.area.as_deref().map(read_area).transpose()?;
+    let tiles = compute_tile_ranges(&args, area.as_ref());
+    let (mut writer, skipped) = TileWriter::new(&args, sources, tile_info, &tiles).await?;
     let query = args.url_query.as_deref();
     let req = TestRequest::default()
         .insert_header((ACCEPT_ENCODING, args.encoding.as_str()))
         .finish();
     let accept_encoding = AcceptEncoding::parse(&req)?;
     let encodings = Some(&accept_encoding);
+    let options = &TileOptions::default();
 
-    let progress = Progress::new(&tiles);
+    let progress = Progress::new(&tiles, skipped);
     info!(
         "Copying {} {} tiles from {} to {}",
         progress.total,
@@ -281,42 +478,54 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
         args.source,
         args.output_file.display()
     );
+    if skipped > 0 {
+        info!("Resuming the copy after {skipped} tiles");
+    }
 
     try_join!(
         async move {
-            stream::iter(iterate_tiles(tiles))
+            let tiles = iterate_tiles(tiles).zip(0..);
+            stream::iter(tiles.skip(usize::try_from(skipped).unwrap_or(usize::MAX)))
                 .map(MartinResult::Ok)
-                .try_for_each_concurrent(concurrency, |xyz| {
+                .try_for_each_concurrent(concurrency, |(xyz, index)| {
                     let tx = tx.clone();
                     async move {
-                        let tile = get_tile_content(sources, info, &xyz, query, encodings).await?;
+                        let tile =
+                            get_tile_content(sources, options, info, &xyz, query, None, encodings)
+                                .await?;
                         let data = tile.data;
-                        tx.send(TileXyz { xyz, data })
-                            .await
-                            .map_err(|e| MartinError::InternalError(e.into()))?;
+                        let info = tile.info;
+                        tx.send(TileXyz {
+                            index,
+                            xyz,
+                            data,
+                            info,
+                        })
+                        .await
+                        .map_err(|e| MartinError::InternalError(e.into()))?;
                         Ok(())
                     }
                 })
                 .await
         },
         async {
-            let mut last_saved = Instant::now();
             let mut last_reported = Instant::now();
-            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            // the tiles are generated concurrently, so they may arrive out of order
+            let mut arrived = BTreeSet::new();
+            let mut received_until = skipped;
             while let Some(tile) = rx.recv().await {
                 debug!("Generated tile {tile:?}");
+                arrived.insert(tile.index);
+                while arrived.remove(&received_until) {
+                    received_until += 1;
+                }
                 let done = if tile.data.is_empty() {
                     progress.empty.fetch_add(1, Ordering::Relaxed)
                 } else {
-                    batch.push((tile.xyz.z, tile.xyz.x, tile.xyz.y, tile.data));
-                    if batch.len() >= BATCH_SIZE || last_saved.elapsed() > SAVE_EVERY {
-                        mbt.insert_tiles(&mut conn, mbt_type, args.on_duplicate, &batch)
-                            .await?;
-                        batch.clear();
-                        last_saved = Instant::now();
-                    }
+                    writer.write(tile)?;
                     progress.non_empty.fetch_add(1, Ordering::Relaxed)
                 };
+                writer.save_if_due(received_until).await?;
                 if done % PROGRESS_REPORT_AFTER == (PROGRESS_REPORT_AFTER - 1)
                     && last_reported.elapsed() > PROGRESS_REPORT_EVERY
                 {
@@ -324,18 +533,29 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
                     last_reported = Instant::now();
                 }
             }
-            if !batch.is_empty() {
-                mbt.insert_tiles(&mut conn, mbt_type, args.on_duplicate, &batch)
-                    .await?;
-            }
             Ok(())
         }
     )?;
 
+    writer.finish(sources).await?;
     info!("{progress}");
     Ok(())
 }
 
+/// Metadata of the copied tiles
+fn copy_tilejson(sources: &[&dyn Source], tile_info: TileInfo) -> TileJSON {
+    let mut tj = merge_tilejson(sources, String::new(), CompositeTileJsonConfig::default());
+    tj.other.insert(
+        "format".to_string(),
+        serde_json::Value::String(tile_info.format.to_string()),
+    );
+    tj.other.insert(
+        "generator".to_string(),
+        serde_json::Value::String(format!("martin-cp v{VERSION}")),
+    );
+    tj
+}
+
 async fn init_schema(
     mbt: &Mbtiles,
     conn: &mut SqliteConnection,
@@ -350,15 +570,7 @@ async fn init_schema(
             MbtTypeCli::Normalized => MbtType::Normalized { hash_view: true },
         };
         init_mbtiles_schema(&mut *conn, mbt_type).await?;
-        let mut tj = merge_tilejson(sources, String::new());
-        tj.other.insert(
-            "format".to_string(),
-            serde_json::Value::String(tile_info.format.to_string()),
-        );
-        tj.other.insert(
-            "generator".to_string(),
-            serde_json::Value::String(format!("martin-cp v{VERSION}")),
-        );
+        let tj = copy_tilejson(sources, tile_info);
         mbt.insert_metadata(&mut *conn, &tj).await?;
         mbt_type
     } else {
@@ -394,11 +606,6 @@ mod tests {
 
     use super::*;
 
-    #[test]
-    fn test_tile_index() {
-        assert_eq!((0, 0), tile_index(-180.0, 85.0511, 0));
-    }
-
     #[test]
     fn test_compute_tile_ranges() {
         let world = Bounds::MAX_TILED;
@@ -407,40 +614,40 @@ mod tests {
         let bbox_mi = Bounds::from_str("-86.6271,41.6811,-82.3095,45.8058").unwrap();
         let bbox_usa = Bounds::from_str("-124.8489,24.3963,-66.8854,49.3843").unwrap();
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[0])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[0]), None), @r###"
         ---
         - "0: (0,0) - (0,0)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[3,7])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[3,7]), None), @r###"
         ---
         - "3: (0,0) - (7,7)"
         - "7: (0,0) - (127,127)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&arg_minmax(&[world], 2, 4)), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&arg_minmax(&[world], 2, 4), None), @r###"
         ---
         - "2: (0,0) - (3,3)"
         - "3: (0,0) - (7,7)"
         - "4: (0,0) - (15,15)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[14])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[world], &[14]), None), @r###"
         ---
         - "14: (0,0) - (16383,16383)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa], &[14])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa], &[14]), None), @r###"
         ---
         - "14: (2509,5599) - (5147,7046)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa, bbox_mi, bbox_ca], &[14])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_usa, bbox_mi, bbox_ca], &[14]), None), @r###"
         ---
         - "14: (2509,5599) - (5147,7046)"
         "###);
 
-        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_ca_south, bbox_mi, bbox_ca], &[14])), @r###"
+        assert_yaml_snapshot!(compute_tile_ranges(&args(&[bbox_ca_south, bbox_mi, bbox_ca], &[14]), None), @r###"
         ---
         - "14: (2791,6499) - (2997,6624)"
         - "14: (4249,5841) - (4446,6101)"
@@ -1,20 +1,44 @@
 use std::fmt::Display;
+use std::io::Write as _;
+use std::path::PathBuf;
 
 use actix_web::dev::Server;
 use clap::Parser;
 use log::{error, info, log_enabled};
-use martin::args::{Args, OsEnv};
-use martin::srv::{new_server, RESERVED_KEYWORDS};
+use martin::args::{Args, DumpFormat, MartinCommand, OsEnv};
+use martin::bench::run_bench;
+use martin::check::run_check;
+use martin::demo::{create_demo_config, default_demo_dir};
+use martin::init::run_init;
+use martin::srv::{
+    current_request_id, init_telemetry, new_server, shutdown_telemetry, CatalogDump,
+    RESERVED_KEYWORDS,
+};
+use martin::MartinError::MissingSources;
 use martin::{read_config, Config, IdResolver, MartinResult};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-async fn start(args: Args) -> MartinResult<Server> {
+async fn start(args: Args) -> MartinResult<Option<Server>> {
     info!("Starting Martin v{VERSION}");
 
     let env = OsEnv::default();
+    init_telemetry(&env)?;
+    if let Some(MartinCommand::Init(init)) = &args.command {
+        run_init(init, &env).await?;
+        return Ok(None);
+    }
+    if let Some(MartinCommand::Check) = &args.command {
+        run_check(args, &env).await?;
+        return Ok(None);
+    }
     let save_config = args.meta.save_config.clone();
-    let mut config = if let Some(ref cfg_filename) = args.meta.config {
+    let mut config = if let Some(MartinCommand::Demo(demo)) = &args.command {
+        let dir = demo.data_dir.clone().unwrap_or_else(default_demo_dir);
+        let mut config = create_demo_config(&dir)?;
+        config.srv.demo = true;
+        config
+    } else if let Some(ref cfg_filename) = args.meta.config {
         info!("Using {}", cfg_filename.display());
         read_config(cfg_filename, &env)?
     } else {
@@ -22,33 +46,122 @@ async fn start(args: Args) -> MartinResult<Server> {
         Config::default()
     };
 
+    let bench = match &args.command {
+        Some(MartinCommand::Bench(bench)) => Some(bench.clone()),
+        _ => None,
+    };
+    let export_config = args.meta.export_config.clone();
+    let validate_config = args.meta.validate_config;
+    let print_config = args.meta.print_config;
+    let dump_catalog = args.meta.dump_catalog;
     args.merge_into_config(&mut config, &env)?;
     config.finalize()?;
+    let configured_ids = config.get_source_ids();
     let sources = config.resolve(IdResolver::new(RESERVED_KEYWORDS)).await?;
 
+    if let Some(bench) = bench {
+        run_bench(&bench, &config.srv, &sources).await?;
+        return Ok(None);
+    }
+
     if let Some(file_name) = save_config {
         config.save_to_file(file_name)?;
-    } else {
+    } else if export_config.is_none() && !validate_config && !print_config && dump_catalog.is_none()
+    {
         info!("Use --save-config to save or print Martin configuration.");
     }
 
+    if validate_config {
+        let missing: Vec<_> = configured_ids
+            .into_iter()
+            .filter(|id| !sources.tiles.contains(id))
+            .collect();
+        if !missing.is_empty() {
+            return Err(MissingSources(missing));
+        }
+        info!(
+            "Config is valid, found {} tile sources.",
+            sources.tiles.get_catalog().len()
+        );
+    }
+
+    if print_config {
+        config.save_to_file(PathBuf::from("-"))?;
+    }
+
+    if let Some(format) = dump_catalog {
+        let limits = config.srv.composite_tilejson.unwrap_or_default();
+        let dump = CatalogDump::new(&sources, limits)?;
+        let dump = match format {
+            DumpFormat::Json => {
+                serde_json::to_string_pretty(&dump).expect("Unable to serialize catalog")
+            }
+            DumpFormat::Yaml => serde_yaml::to_string(&dump).expect("Unable to serialize catalog"),
+        };
+        println!("{dump}");
+        return Ok(None);
+    }
+
+    if let Some(file_name) = export_config {
+        config.pin_sources();
+        config.save_to_file(file_name)?;
+        return Ok(None);
+    }
+    if validate_config || print_config {
+        return Ok(None);
+    }
+
+    let demo = config.srv.demo;
+    let internal_addresses: Vec<_> = config
+        .srv
+        .internal_listen_addresses
+        .iter()
+        .cloned()
+        .collect();
     let (server, listen_addresses) = new_server(config.srv, sources)?;
-    info!("Martin has been started on {listen_addresses}.");
-    info!("Use http://{listen_addresses}/catalog to get the list of available sources.");
+    info!(
+        "Martin has been started on {}.",
+        listen_addresses.join(", ")
+    );
+    if !internal_addresses.is_empty() {
+        let addrs = internal_addresses.join(", ");
+        info!("The admin endpoints and the metrics are served only on {addrs}.");
+    }
+    let listen_address = &listen_addresses[0];
+    if demo {
+        let port = listen_address.rsplit(':').next().unwrap_or_default();
+        info!("Open http://localhost:{port}/ in a browser to see the demo map.");
+    }
+    info!("Use http://{listen_address}/catalog to get the list of available sources.");
 
-    Ok(server)
+    Ok(Some(server))
 }
 
 #[actix_web::main]
 async fn main() {
     let env = env_logger::Env::default().default_filter_or("martin=info");
-    env_logger::Builder::from_env(env).init();
+    env_logger::Builder::from_env(env)
+        .format(|buf, record| {
+            // the lines logged while serving a request include its ID
+            let request = current_request_id()
+                .map(|id| format!(" request_id={id}"))
+                .unwrap_or_default();
+            let level = buf.default_styled_level(record.level());
+            writeln!(
+                buf,
+                "[{} {level:<5} {}{request}] {}",
+                buf.timestamp(),
+                record.target(),
+                record.args()
+            )
+        })
+        .init();
 
-    start(Args::parse())
-        .await
-        .unwrap_or_else(|e| on_error(e))
-        .await
-        .unwrap_or_else(|e| on_error(e));
+    let server = start(Args::parse()).await.unwrap_or_else(|e| on_error(e));
+    if let Some(server) = server {
+        server.await.unwrap_or_else(|e| on_error(e));
+    }
+    shutdown_telemetry();
 }
 
 fn on_error<E: Display>(e: E) -> ! {
@@ -0,0 +1,528 @@
+use std::mem;
+use std::path::PathBuf;
+
+use actix_web::dev::Server;
+
+use crate::config::ServerState;
+use crate::source::{Source, TileInfoSources};
+use crate::srv::{new_server, ServerData, SrvConfig, RESERVED_KEYWORDS};
+use crate::MartinError::NoSources;
+use crate::{Config, IdResolver, MartinResult, OptOneMany, SourceFactory};
+
+/// Set up Martin from a Rust application, without a config file.
+///
+/// ```no_run
+/// # async fn run(my_source: Box<dyn martin::Source>) -> martin::MartinResult<()> {
+/// use actix_web::{web, App, HttpServer};
+///
+/// let martin = martin::Builder::new()
+///     .source(my_source)
+///     .sprites("./sprites")
+///     .fonts("./fonts")
+///     .build()
+///     .await?;
+///
+/// // mount the Martin routes into an existing application
+/// let data = martin.into_data()?;
+/// HttpServer::new(move || {
+///     let data = data.clone();
+///     App::new().service(web::scope("/tiles").configure(move |cfg| data.configure(cfg)))
+/// })
+/// .bind("127.0.0.1:8080")
+/// .unwrap()
+/// .run()
+/// .await
+/// .unwrap();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    config: Config,
+    sources: TileInfoSources,
+}
+
+impl Builder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from a config, e.g. one read with [`read_config`](crate::read_config).
+    /// The sources given to the builder are added to the configured ones.
+    #[must_use]
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            sources: Vec::new(),
+        }
+    }
+
+    /// Set the web server configuration, e.g. the listening address or the admin endpoints
+    #[must_use]
+    pub fn srv_config(mut self, srv: SrvConfig) -> Self {
+        self.config.srv = srv;
+        self
+    }
+
+    /// Serve a tile source implemented by the application. Its ID must be unique and not reserved.
+    #[must_use]
+    pub fn source(mut self, source: Box<dyn Source>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Create the sources of a custom config section, e.g. one read with
+    /// [`read_config`](crate::read_config), with the given factory
+    #[must_use]
+    pub fn source_factory<T: SourceFactory + 'static>(mut self, factory: T) -> Self {
+        self.config.factories.register(factory);
+        self
+    }
+
+    /// Serve the sprites of an SVG directory
+    #[must_use]
+    pub fn sprites(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.sprites.add_path(path.into());
+        self
+    }
+
+    /// Serve the fonts of a file or a directory
+    #[must_use]
+    pub fn fonts(mut self, path: impl Into<PathBuf>) -> Self {
+        let fonts = mem::take(&mut self.config.fonts);
+        self.config.fonts = OptOneMany::new(fonts.into_iter().chain([path.into()]));
+        self
+    }
+
+    /// Serve the style files of a file or a directory
+    #[must_use]
+    pub fn styles(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.styles.add_path(path.into());
+        self
+    }
+
+    /// Validate the configuration, and resolve all sources
+    pub async fn build(mut self) -> MartinResult<Martin> {
+        match self.config.finalize() {
+            // the sources of the application are not part of the config
+            Err(NoSources) if !self.sources.is_empty() => {}
+            res => {
+                res?;
+            }
+        }
+        let idr = IdResolver::new(RESERVED_KEYWORDS);
+        let state = self.config.resolve_with_sources(idr, self.sources).await?;
+        Ok(Martin {
+            config: self.config.srv,
+            state,
+        })
+    }
+}
+
+/// Martin with all sources resolved, ready to be served
+pub struct Martin {
+    pub config: SrvConfig,
+    pub state: ServerState,
+}
+
+impl Martin {
+    /// Start a standalone server, returning it together with its public listening addresses
+    pub fn into_server(self) -> MartinResult<(Server, Vec<String>)> {
+        new_server(self.config, self.state)
+    }
+
+    /// Get the data and the routes to mount into an existing actix-web application.
+    /// Must be called from within an actix runtime.
+    pub fn into_data(self) -> MartinResult<ServerData> {
+        ServerData::new(&self.config, self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Write as _};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use async_trait::async_trait;
+    use indoc::indoc;
+    use martin_tile_utils::{Encoding, Format, TileInfo};
+    use tilejson::{tilejson, TileJSON};
+
+    use super::*;
+    use crate::{TileCoord, TileData, UrlQuery};
+
+    #[derive(Debug, Clone)]
+    struct AppSource(TileJSON);
+
+    #[async_trait]
+    impl Source for AppSource {
+        fn get_id(&self) -> &str {
+            "app"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.0
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "app"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            Ok(xyz.to_string().into_bytes())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn embedded_routes() {
+        let src = AppSource(tilejson! { tiles: vec![] });
+        let martin = Builder::new()
+            .source(Box::new(src.clone()))
+            .styles("../tests/fixtures/styles")
+            .build()
+            .await
+            .unwrap();
+        assert!(martin.state.tiles.is_embedded("app"));
+        let data = martin.into_data().unwrap();
+        let app = init_service(
+            App::new().service(web::scope("/tiles").configure(|cfg| data.configure(cfg))),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/tiles/catalog").to_request();
+        let body = read_body(call_service(&app, req).await).await;
+        let catalog: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(catalog["tiles"]["app"].is_object());
+        assert!(catalog["styles"]["maplibre_demo"].is_object());
+
+        let req = TestRequest::get().uri("/tiles/app/2/1/3").to_request();
+        let body = read_body(call_service(&app, req).await).await;
+        assert_eq!(body, "2,1,3");
+
+        let result = Builder::new()
+            .source(Box::new(src.clone()))
+            .source(Box::new(src))
+            .build()
+            .await;
+        assert!(result.is_err(), "duplicate IDs must be rejected");
+    }
+
+    #[actix_rt::test]
+    async fn tenant_routes() {
+        let srv: SrvConfig = serde_yaml::from_str(indoc! {"
+            tenants:
+              acme:
+                sources: [app]
+                token: secret
+        "})
+        .unwrap();
+        let martin = Builder::new()
+            .srv_config(srv)
+            .source(Box::new(AppSource(tilejson! { tiles: vec![] })))
+            .build()
+            .await
+            .unwrap();
+        let data = martin.into_data().unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(data.tenants())
+                .configure(|cfg| data.configure(cfg)),
+        )
+        .await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let resp = call_service(&app, get("/acme/app/2/1/3?token=secret")).await;
+        assert_eq!(read_body(resp).await, "2,1,3");
+        let resp = call_service(&app, get("/acme/app/2/1/3")).await;
+        assert_eq!(resp.status(), 401);
+        let resp = call_service(&app, get("/app/2/1/3")).await;
+        assert_eq!(resp.status(), 404);
+
+        let body = read_body(call_service(&app, get("/acme/catalog?token=secret")).await).await;
+        let catalog: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(catalog["tiles"]["app"].is_object());
+        let body = read_body(call_service(&app, get("/catalog")).await).await;
+        let catalog: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(catalog["tiles"]["app"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn tenant_members() {
+        let config = crate::config::tests::parse_cfg(indoc! {"
+            tenants:
+              acme:
+                sources: [private, own_mix]
+                token: secret
+            composites:
+              mixed: [public, private]
+              own_mix: [public, private]
+            variants:
+              cities:
+                default: public
+                sources:
+                  public: public
+                  private: private
+            mbtiles:
+              sources:
+                public: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                private: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        "});
+        let data = Builder::from_config(config)
+            .build()
+            .await
+            .unwrap()
+            .into_data()
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(data.tenants())
+                .configure(|cfg| data.configure(cfg)),
+        )
+        .await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        // the composites and the variants do not serve the tenant sources outside of the tenant
+        for uri in [
+            "/mixed",
+            "/mixed/0/0/0",
+            "/mixed/style.json",
+            "/cities/0/0/0?variant=private",
+            "/own_mix/0/0/0",
+        ] {
+            assert_eq!(call_service(&app, get(uri)).await.status(), 404, "{uri}");
+        }
+        let resp = call_service(&app, get("/cities/0/0/0")).await;
+        assert!(resp.status().is_success());
+        let resp = call_service(&app, get("/acme/own_mix/0/0/0?token=secret")).await;
+        assert!(resp.status().is_success());
+        let resp = call_service(&app, get("/acme/mixed/0/0/0?token=secret")).await;
+        assert_eq!(resp.status(), 404);
+
+        // and the catalogs only list the sources that are served
+        let catalog = |uri| {
+            let app = &app;
+            async move {
+                let catalog: serde_json::Value =
+                    read_body_json(call_service(app, get(uri)).await).await;
+                let tiles = catalog["tiles"].as_object().unwrap();
+                tiles.keys().cloned().collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(catalog("/catalog").await, ["cities", "public"]);
+        assert_eq!(
+            catalog("/acme/catalog?token=secret").await,
+            ["own_mix", "private"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn tenant_ip_rules() {
+        let srv: SrvConfig = serde_yaml::from_str(indoc! {"
+            tenants:
+              acme:
+                sources: [app]
+            ip_filter:
+              sources:
+                app:
+                  allow: [10.0.0.0/8]
+        "})
+        .unwrap();
+        let martin = Builder::new()
+            .srv_config(srv)
+            .source(Box::new(AppSource(tilejson! { tiles: vec![] })))
+            .build()
+            .await
+            .unwrap();
+        let data = martin.into_data().unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(data.ip_filter())
+                .wrap(data.tenants())
+                .configure(|cfg| data.configure(cfg)),
+        )
+        .await;
+        let get = |uri: &str, ip: &str| {
+            TestRequest::get()
+                .uri(uri)
+                .peer_addr(format!("{ip}:1234").parse().unwrap())
+                .to_request()
+        };
+
+        for uri in ["/acme/app", "/acme/app/2/1/3", "/acme/app/quadkey/00"] {
+            let resp = call_service(&app, get(uri, "8.8.8.8")).await;
+            assert_eq!(resp.status(), 403, "{uri}");
+            let resp = call_service(&app, get(uri, "10.1.1.1")).await;
+            assert!(resp.status().is_success(), "{uri}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn tile_quotas() {
+        let srv: SrvConfig = serde_yaml::from_str(indoc! {"
+            tenants:
+              acme:
+                sources: [app]
+            throttle:
+              anonymous_max_zoom: 3
+        "})
+        .unwrap();
+        let martin = Builder::new()
+            .srv_config(srv)
+            .source(Box::new(AppSource(tilejson! { tiles: vec![] })))
+            .build()
+            .await
+            .unwrap();
+        let data = martin.into_data().unwrap();
+        let throttle = data.throttle();
+        let app = init_service(
+            App::new()
+                .wrap(data.throttle())
+                .wrap(data.tenants())
+                .configure(|cfg| data.configure(cfg)),
+        )
+        .await;
+        let batch = |tiles: &[&str]| {
+            TestRequest::post()
+                .uri("/acme/app/tiles")
+                .set_json(serde_json::json!({ "tiles": tiles }))
+        };
+
+        // every tile route, with the tiles above the maximum zoom
+        for req in [
+            TestRequest::get().uri("/acme/app/4/0/0"),
+            TestRequest::get().uri("/acme/app/quadkey/0000"),
+            TestRequest::get().uri("/acme/app/4/0/0.geojson"),
+            TestRequest::get().uri("/acme/app/4/0/0.grid.json"),
+            TestRequest::get().uri("/acme/app/3/0/0@2x.png"),
+        ] {
+            let req = req.to_request();
+            let path = req.path().to_string();
+            assert_eq!(call_service(&app, req).await.status(), 403, "{path}");
+        }
+        let resp = call_service(&app, batch(&["3/0/0", "4/0/0"]).to_request()).await;
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("X-Tile-Status: 200"));
+        assert!(body.contains("X-Tile-Status: 403"));
+        assert_eq!(throttle.report().anonymous.used, 1);
+
+        // the tiles are counted even if they cannot be converted
+        for uri in [
+            "/acme/app/3/0/0",
+            "/acme/app/quadkey/000",
+            "/acme/app/3/0/0.geojson",
+            "/acme/app/3/0/0.grid.json",
+            "/acme/app/2/0/0@2x.png",
+        ] {
+            call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        }
+        call_service(&app, batch(&["0/0/0", "1/0/0"]).to_request()).await;
+        assert_eq!(throttle.report().anonymous.used, 8);
+    }
+
+    /// Send the start of a connection to a local server, and read the start of its answer
+    fn exchange(port: u16, request: &[u8], len: usize) -> Vec<u8> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = vec![0; len];
+        stream.read_exact(&mut response).unwrap();
+        response
+    }
+
+    #[actix_rt::test]
+    async fn h2c_listener() {
+        // HTTP/2 connection preface, followed by an empty SETTINGS frame
+        const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0";
+        const HTTP1: &[u8] =
+            b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        for h2c in [true, false] {
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let srv: SrvConfig = serde_yaml::from_str(&format!(
+                "{{ listen_addresses: '127.0.0.1:{port}', worker_processes: 1, h2c: {h2c} }}"
+            ))
+            .unwrap();
+            let martin = Builder::new()
+                .srv_config(srv)
+                .source(Box::new(AppSource(tilejson! { tiles: vec![] })))
+                .build()
+                .await
+                .unwrap();
+            let (server, _) = martin.into_server().unwrap();
+            let handle = server.handle();
+            actix_rt::spawn(server);
+
+            let (h2, h1) = actix_rt::task::spawn_blocking(move || {
+                (exchange(port, PREFACE, 9), exchange(port, HTTP1, 12))
+            })
+            .await
+            .unwrap();
+            if h2c {
+                // the server answers the preface with its own SETTINGS frame
+                assert_eq!(h2[3], 0x04, "{h2:?}");
+            } else {
+                // without h2c, the preface is rejected as an invalid HTTP/1.1 request
+                assert!(h2.starts_with(b"HTTP/1.1 "), "{h2:?}");
+            }
+            // HTTP/1.1 clients are served on the same port
+            assert_eq!(h1, b"HTTP/1.1 200");
+            handle.stop(false).await;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn static_files_routes() {
+        let static_files = |path: &str| {
+            let srv: SrvConfig = serde_yaml::from_str(&format!(
+                "static_files: {{ path: {path}, dir: ../tests/fixtures/static }}"
+            ))
+            .unwrap();
+            Builder::new()
+                .srv_config(srv)
+                .source(Box::new(AppSource(tilejson! { tiles: vec![] })))
+        };
+        let martin = static_files("/web").build().await.unwrap();
+        let data = martin.into_data().unwrap();
+        let app = init_service(App::new().configure(|cfg| data.configure(cfg))).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let index = std::fs::read("../tests/fixtures/static/index.html").unwrap();
+        let body = read_body(call_service(&app, get("/web/")).await).await;
+        assert_eq!(body, index);
+        let body = read_body(call_service(&app, get("/web/app.js")).await).await;
+        assert_eq!(body, "console.log(\"martin\");\n");
+        // the routes of the frontend get the index file
+        let body = read_body(call_service(&app, get("/web/maps/roads")).await).await;
+        assert_eq!(body, index);
+        // the tiles and the endpoints are not shadowed
+        let body = read_body(call_service(&app, get("/app/2/1/3")).await).await;
+        assert_eq!(body, "2,1,3");
+        let resp = call_service(&app, get("/catalog")).await;
+        assert!(resp.status().is_success());
+
+        let martin = static_files("/app").build().await.unwrap();
+        assert!(martin.into_data().is_err(), "the source must not be hidden");
+    }
+}
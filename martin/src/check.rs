@@ -0,0 +1,227 @@
+//! `martin check`: validate the configuration without starting the server. All files and directories
+//! must exist, each `PostgreSQL` connection must work, and the sprites, fonts, and styles must load.
+//! Every problem is reported instead of stopping at the first one.
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use crate::args::{Args, Env};
+use crate::config::{read_config, Config};
+use crate::file_config::is_url;
+use crate::fonts::FontSources;
+use crate::pg::PgPool;
+use crate::sprites::SpriteSources;
+use crate::styles::StyleSources;
+use crate::MartinError::CheckFailed;
+use crate::MartinResult;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    /// What was checked, e.g. `mbtiles /data/world.mbtiles`
+    pub name: String,
+    /// Why the check failed, or `None` if it passed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    fn add<E: Display>(&mut self, name: impl Into<String>, result: Result<(), E>) {
+        self.results.push(CheckResult {
+            name: name.into(),
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+
+    /// Number of the failed checks
+    #[must_use]
+    pub fn failures(&self) -> usize {
+        self.results.iter().filter(|v| v.error.is_some()).count()
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for result in &self.results {
+            match &result.error {
+                None => writeln!(f, "OK    {}", result.name)?,
+                Some(e) => writeln!(f, "FAIL  {}: {e}", result.name)?,
+            }
+        }
+        match self.failures() {
+            0 => write!(f, "All {} checks passed", self.results.len()),
+            n => write!(f, "{n} of {} checks failed", self.results.len()),
+        }
+    }
+}
+
+/// Load the config like the server does, check it, and print the report.
+/// Returns an error if any of the checks failed.
+pub async fn run_check<'a, M>(args: Args, env: &'a M) -> MartinResult<()>
+where
+    M: Env<'a>,
+    M::Value: AsRef<str>,
+{
+    let report = check_args(args, env).await;
+    println!("{report}");
+    match report.failures() {
+        0 => Ok(()),
+        n => Err(CheckFailed(n)),
+    }
+}
+
+async fn check_args<'a, M>(args: Args, env: &'a M) -> CheckReport
+where
+    M: Env<'a>,
+    M::Value: AsRef<str>,
+{
+    let mut report = CheckReport::default();
+    let mut config = if let Some(file_name) = &args.meta.config {
+        let config = read_config(file_name, env);
+        let name = format!("config file {}", file_name.display());
+        match config {
+            Ok(config) => {
+                report.add::<String>(name, Ok(()));
+                config
+            }
+            Err(e) => {
+                report.add(name, Err(e));
+                return report;
+            }
+        }
+    } else {
+        Config::default()
+    };
+    let result = args.merge_into_config(&mut config, env);
+    if result.is_err() {
+        report.add("command line arguments", result);
+        return report;
+    }
+    check_config(&mut report, &mut config).await;
+    report
+}
+
+/// Check a loaded config, adding the results to the report
+pub async fn check_config(report: &mut CheckReport, config: &mut Config) {
+    let unrecognized = match config.finalize() {
+        Ok(v) => v,
+        Err(e) => {
+            report.add("config", Err(e));
+            return;
+        }
+    };
+    for key in unrecognized.keys() {
+        report.add(
+            format!("config key {key}"),
+            Err("unknown key, ignored by the server"),
+        );
+    }
+
+    let files = [
+        ("pmtiles", &config.pmtiles),
+        ("mbtiles", &config.mbtiles),
+        ("cog", &config.cog),
+        ("gpkg", &config.gpkg),
+        ("geoparquet", &config.geoparquet),
+        ("osm", &config.osm),
+        ("shapefile", &config.shapefile),
+        ("sqlite", &config.sqlite),
+        ("sprites", &config.sprites),
+        ("styles", &config.styles),
+    ];
+    let mut missing = Vec::new();
+    for (kind, cfg) in files {
+        for path in cfg.get_paths() {
+            if !check_path(report, kind, &path) {
+                missing.push(kind);
+            }
+        }
+    }
+    let mut missing_fonts = false;
+    for path in config.fonts.iter() {
+        missing_fonts |= !check_path(report, "fonts", path);
+    }
+
+    for (idx, pg) in config.postgres.iter().enumerate() {
+        let result = PgPool::new(pg).await.map(|_| ());
+        report.add(format!("postgres[{idx}] connection"), result);
+    }
+
+    // the missing paths are already reported, so the sources using them are not loaded
+    if !config.sprites.is_empty() && !missing.contains(&"sprites") {
+        let result = SpriteSources::resolve(&mut config.sprites)
+            .await
+            .map(|_| ());
+        report.add("sprites", result);
+    }
+    if !config.fonts.is_empty() && !missing_fonts {
+        let fallbacks = config.font_fallbacks.as_ref();
+        let result = FontSources::resolve(&mut config.fonts, fallbacks).await;
+        report.add("fonts", result.map(|_| ()));
+    }
+    if !config.styles.is_empty() && !missing.contains(&"styles") {
+        let result = StyleSources::resolve(&mut config.styles).map(|_| ());
+        report.add("styles", result);
+    }
+}
+
+/// Check that a local file or directory exists, returning false if it does not. URLs are not checked.
+fn check_path(report: &mut CheckReport, kind: &str, path: &Path) -> bool {
+    if is_url(path) {
+        return true;
+    }
+    let exists = path.exists();
+    let result = if exists {
+        Ok(())
+    } else {
+        Err("the file or directory does not exist")
+    };
+    report.add(format!("{kind} {}", path.display()), result);
+    exists
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_config::FileConfigEnum;
+    use crate::OptOneMany;
+
+    #[actix_rt::test]
+    async fn check_files() {
+        let fixtures = Path::new("../tests/fixtures");
+        let mut config = Config {
+            mbtiles: FileConfigEnum::Paths(vec![
+                fixtures.join("mbtiles/json.mbtiles"),
+                fixtures.join("mbtiles/missing.mbtiles"),
+            ]),
+            sprites: FileConfigEnum::Path(fixtures.join("sprites/src1")),
+            fonts: OptOneMany::One(fixtures.join("fonts")),
+            ..Config::default()
+        };
+        config
+            .unrecognized
+            .insert("pmtile".to_string(), serde_yaml::Value::Null);
+        let mut report = CheckReport::default();
+        check_config(&mut report, &mut config).await;
+
+        let failed: Vec<_> = report
+            .results
+            .iter()
+            .filter(|v| v.error.is_some())
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                "config key pmtile",
+                "mbtiles ../tests/fixtures/mbtiles/missing.mbtiles"
+            ]
+        );
+        assert_eq!(report.results.len(), 7);
+        assert!(report.to_string().ends_with("2 of 7 checks failed"));
+    }
+}
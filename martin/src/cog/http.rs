@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use log::trace;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+
+use crate::cog::CogError::{HttpError, RangeNotSupported};
+use crate::cog::CogResult;
+
+/// Remote files are read in blocks of this size
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Number of blocks at the start of the file that are kept in memory.
+/// Cloud optimized `GeoTIFF` files store all image headers at the start of the file,
+/// so this avoids re-reading them for every tile.
+const HEADER_BLOCKS: u64 = 16;
+
+type Block = Arc<Vec<u8>>;
+
+/// A remote file that supports HTTP range requests. Must only be used from blocking threads.
+#[derive(Clone, Debug)]
+pub struct HttpFile {
+    client: Client,
+    url: String,
+    len: u64,
+    header: Arc<Mutex<HashMap<u64, Block>>>,
+}
+
+impl HttpFile {
+    pub fn open(url: &str) -> CogResult<Self> {
+        let client = Client::new();
+        let first = fetch_range(&client, url, 0, BLOCK_SIZE)?;
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            len: first.total,
+            header: Arc::new(Mutex::new(HashMap::from([(0, Arc::new(first.data))]))),
+        })
+    }
+
+    #[must_use]
+    pub fn reader(&self) -> HttpReader {
+        HttpReader {
+            file: self.clone(),
+            pos: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    fn fetch_block(&self, block: u64) -> io::Result<Block> {
+        let start = block * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(self.len);
+        trace!("Reading bytes {start}..{end} of {}", self.url);
+        fetch_range(&self.client, &self.url, start, end - start)
+            .map(|v| Arc::new(v.data))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+struct RangeResponse {
+    data: Vec<u8>,
+    total: u64,
+}
+
+fn fetch_range(client: &Client, url: &str, start: u64, len: u64) -> CogResult<RangeResponse> {
+    let end = start + len - 1;
+    let response = client
+        .get(url)
+        .header(RANGE, format!("bytes={start}-{end}"))
+        .send()
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .map_err(|e| HttpError(e, url.to_string()))?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(RangeNotSupported(url.to_string()));
+    }
+    // Content-Range: bytes 0-65535/123456
+    let total = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit('/').next())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| RangeNotSupported(url.to_string()))?;
+    let data = response
+        .bytes()
+        .map_err(|e| HttpError(e, url.to_string()))?
+        .to_vec();
+    Ok(RangeResponse { data, total })
+}
+
+/// Reads a remote file with [`Read`] and [`Seek`], keeping the fetched blocks until dropped.
+pub struct HttpReader {
+    file: HttpFile,
+    pos: u64,
+    blocks: HashMap<u64, Block>,
+}
+
+impl HttpReader {
+    fn get_block(&mut self, block: u64) -> io::Result<Block> {
+        if block < HEADER_BLOCKS {
+            if let Some(data) = self.file.header.lock().unwrap().get(&block) {
+                return Ok(data.clone());
+            }
+            let data = self.file.fetch_block(block)?;
+            self.file.header.lock().unwrap().insert(block, data.clone());
+            return Ok(data);
+        }
+        if let Some(data) = self.blocks.get(&block) {
+            return Ok(data.clone());
+        }
+        let data = self.file.fetch_block(block)?;
+        self.blocks.insert(block, data.clone());
+        Ok(data)
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.file.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.get_block(self.pos / BLOCK_SIZE)?;
+        // Offset within a block is always smaller than the block size
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = (self.pos % BLOCK_SIZE) as usize;
+        let available = data.get(offset..).unwrap_or_default();
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => add_offset(self.file.len, v),
+            SeekFrom::Current(v) => add_offset(self.pos, v),
+        };
+        self.pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+        Ok(self.pos)
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    i64::try_from(base)
+        .ok()
+        .and_then(|v| v.checked_add(offset))
+        .and_then(|v| u64::try_from(v).ok())
+}
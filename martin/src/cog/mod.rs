@@ -0,0 +1,539 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{trace, warn};
+use martin_tile_utils::{Format, TileInfo};
+use png::{BitDepth, ColorType as PngColorType, Encoder};
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+use tilejson::{tilejson, Bounds, TileJSON};
+
+use crate::cog::http::{HttpFile, HttpReader};
+use crate::file_config::{is_url, FileError, FileResult};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::utils::saturation::spawn_blocking;
+use crate::{MartinError, MartinResult, TileCoord};
+
+mod http;
+
+pub type CogResult<T> = Result<T, CogError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CogError {
+    #[error("IO error {0}: {}", .1.display())]
+    IoError(io::Error, PathBuf),
+
+    #[error("Unable to read TIFF file {}: {0}", .1.display())]
+    TiffError(tiff::TiffError, PathBuf),
+
+    #[error("File {} is not a tiled TIFF, convert it to a cloud optimized GeoTIFF first", .0.display())]
+    NotTiled(PathBuf),
+
+    #[error("Color type {0:?} of file {} is not supported, only 8-bit gray, gray with alpha, RGB and RGBA images can be used", .1.display())]
+    UnsupportedColorType(ColorType, PathBuf),
+
+    #[error("Unable to fetch {1}: {0}")]
+    HttpError(reqwest::Error, String),
+
+    #[error("Server does not support range requests for {0}")]
+    RangeNotSupported(String),
+
+    #[error("Unable to encode tile {1} of source {2} as PNG: {0}")]
+    PngError(png::EncodingError, TileCoord, String),
+
+    #[error("Unable to read tile {1} of source {2}: {0}")]
+    TileError(tiff::TiffError, TileCoord, String),
+}
+
+/// Web Mercator circumference at the equator in meters
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.685_578_5;
+
+/// Where the file is stored
+#[derive(Clone, Debug)]
+enum Location {
+    Local(PathBuf),
+    Remote(HttpFile),
+}
+
+enum CogReader {
+    Local(BufReader<File>),
+    Remote(HttpReader),
+}
+
+impl Read for CogReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Local(r) => r.read(buf),
+            Self::Remote(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for CogReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Local(r) => r.seek(pos),
+            Self::Remote(r) => r.seek(pos),
+        }
+    }
+}
+
+/// One of the images in the file, either the full resolution image or an overview
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CogLevel {
+    /// Index of the image in the file
+    ifd: usize,
+    tile_width: u32,
+    tile_height: u32,
+    tiles_across: u32,
+    tiles_down: u32,
+    /// Position of the first internal tile in the Web Mercator tile grid
+    x_offset: u32,
+    y_offset: u32,
+}
+
+#[derive(Clone, Debug)]
+struct CogMeta {
+    levels: BTreeMap<u8, CogLevel>,
+    color_type: ColorType,
+    nodata: Option<u8>,
+    bounds: Option<Bounds>,
+}
+
+/// Serves the internal tiles of a cloud optimized `GeoTIFF` file as PNG images.
+/// The full resolution image is used for the highest zoom level, and each overview
+/// for one zoom level lower. Files in the Web Mercator tile grid (e.g. created with
+/// `gdal_translate -of COG -co TILING_SCHEME=GoogleMapsCompatible`) are placed
+/// at their geographic location, other files are served starting at zoom 0.
+#[derive(Clone)]
+pub struct CogSource {
+    id: String,
+    path: PathBuf,
+    location: Location,
+    meta: Arc<CogMeta>,
+    tilejson: TileJSON,
+}
+
+impl Debug for CogSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CogSource {{ id: {}, path: {:?} }}", self.id, self.path)
+    }
+}
+
+impl CogSource {
+    pub async fn new_box(id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
+        Ok(Box::new(CogSource::new(id, path).await?))
+    }
+
+    async fn new(id: String, path: PathBuf) -> FileResult<Self> {
+        let src_path = path.clone();
+        let (location, meta) = spawn_blocking(move || {
+            let location = match path.to_str() {
+                Some(url) if is_url(&path) => Location::Remote(HttpFile::open(url)?),
+                _ => Location::Local(path.clone()),
+            };
+            let meta = read_meta(open(&location)?, &path)?;
+            Ok::<_, CogError>((location, meta))
+        })
+        .await
+        .map_err(|e| FileError::AquireConnError(e.to_string()))??;
+
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            minzoom: *meta.levels.keys().next().unwrap_or(&0),
+            maxzoom: *meta.levels.keys().next_back().unwrap_or(&0),
+        };
+        tilejson.bounds = meta.bounds;
+
+        Ok(Self {
+            id,
+            path: src_path,
+            location,
+            meta: Arc::new(meta),
+            tilejson,
+        })
+    }
+
+    fn read_tile(&self, xyz: TileCoord) -> CogResult<TileData> {
+        let Some(level) = self.meta.levels.get(&xyz.z) else {
+            return Ok(Vec::new());
+        };
+        let (Some(col), Some(row)) = (
+            xyz.x.checked_sub(level.x_offset),
+            xyz.y.checked_sub(level.y_offset),
+        ) else {
+            return Ok(Vec::new());
+        };
+        if col >= level.tiles_across || row >= level.tiles_down {
+            return Ok(Vec::new());
+        }
+
+        let tile_err = |e| CogError::TileError(e, xyz, self.id.clone());
+        let mut decoder = Decoder::new(open(&self.location)?).map_err(tile_err)?;
+        decoder.seek_to_image(level.ifd).map_err(tile_err)?;
+        let index = row * level.tiles_across + col;
+        let (width, height) = decoder.chunk_data_dimensions(index);
+        let DecodingResult::U8(data) = decoder.read_chunk(index).map_err(tile_err)? else {
+            return Err(CogError::UnsupportedColorType(
+                self.meta.color_type,
+                self.path.clone(),
+            ));
+        };
+
+        let rgba = to_rgba(
+            &data,
+            (width, height),
+            (level.tile_width, level.tile_height),
+            self.meta.color_type,
+            self.meta.nodata,
+        );
+        let png_err = |e| CogError::PngError(e, xyz, self.id.clone());
+        let mut result = Vec::new();
+        let mut encoder = Encoder::new(&mut result, level.tile_width, level.tile_height);
+        encoder.set_color(PngColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(png_err)?;
+        writer.write_image_data(&rgba).map_err(png_err)?;
+        writer.finish().map_err(png_err)?;
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl Source for CogSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        Format::Png.into()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "cog"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let src = self.clone();
+        let xyz = *xyz;
+        let tile = spawn_blocking(move || src.read_tile(xyz))
+            .await
+            .map_err(|e| MartinError::InternalError(e.into()))?
+            .map_err(FileError::from)?;
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        if let Location::Local(path) = &self.location {
+            tokio::fs::metadata(path)
+                .await
+                .map_err(|e| FileError::IoError(e, path.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+fn open(location: &Location) -> CogResult<CogReader> {
+    Ok(match location {
+        Location::Local(path) => CogReader::Local(BufReader::new(
+            File::open(path).map_err(|e| CogError::IoError(e, path.clone()))?,
+        )),
+        Location::Remote(file) => CogReader::Remote(file.reader()),
+    })
+}
+
+/// Image properties needed to place it in the tile grid
+struct ImageInfo {
+    ifd: usize,
+    width: u32,
+    tile_width: u32,
+    tile_height: u32,
+    tiles_across: u32,
+    tiles_down: u32,
+}
+
+fn read_meta(reader: CogReader, path: &Path) -> CogResult<CogMeta> {
+    let err = |e| CogError::TiffError(e, path.to_path_buf());
+    let mut decoder = Decoder::new(reader).map_err(err)?;
+
+    let color_type = decoder.colortype().map_err(err)?;
+    if !matches!(
+        color_type,
+        ColorType::Gray(8) | ColorType::GrayA(8) | ColorType::RGB(8) | ColorType::RGBA(8)
+    ) {
+        return Err(CogError::UnsupportedColorType(
+            color_type,
+            path.to_path_buf(),
+        ));
+    }
+    let nodata = decoder
+        .find_tag(Tag::GdalNodata)
+        .map_err(err)?
+        .and_then(|v| v.into_string().ok())
+        .and_then(|v| v.trim_end_matches('\0').trim().parse::<f64>().ok())
+        .filter(|v| (0.0..=255.0).contains(v) && v.fract() == 0.0)
+        // The value was checked to be a valid u8
+        .map(|v| {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let v = v as u8;
+            v
+        });
+    let pixel_scale = decoder
+        .find_tag(Tag::ModelPixelScaleTag)
+        .map_err(err)?
+        .and_then(|v| v.into_f64_vec().ok());
+    let tie_point = decoder
+        .find_tag(Tag::ModelTiepointTag)
+        .map_err(err)?
+        .and_then(|v| v.into_f64_vec().ok());
+
+    let mut images = Vec::new();
+    let mut ifd = 0;
+    loop {
+        // Skip transparency masks, they are stored as separate images
+        let subfile_type = decoder
+            .find_tag_unsigned::<u32>(Tag::NewSubfileType)
+            .map_err(err)?
+            .unwrap_or(0);
+        if subfile_type & 4 == 0 {
+            if decoder.get_chunk_type() != ChunkType::Tile {
+                return Err(CogError::NotTiled(path.to_path_buf()));
+            }
+            let (width, height) = decoder.dimensions().map_err(err)?;
+            let (tile_width, tile_height) = decoder.chunk_dimensions();
+            images.push(ImageInfo {
+                ifd,
+                width,
+                tile_width,
+                tile_height,
+                tiles_across: (width + tile_width - 1) / tile_width,
+                tiles_down: (height + tile_height - 1) / tile_height,
+            });
+        }
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(err)?;
+        ifd += 1;
+    }
+
+    let georef = match (pixel_scale, tie_point) {
+        (Some(scale), Some(tie)) if scale.len() >= 2 && tie.len() >= 6 => {
+            // Tie point maps a raster position (I, J) to model coordinates (X, Y)
+            let origin_x = tie[3] - tie[0] * scale[0];
+            let origin_y = tie[4] + tie[1] * scale[1];
+            Some((scale[0], origin_x, origin_y))
+        }
+        _ => None,
+    };
+    let levels = georef
+        .and_then(|(scale, x, y)| get_mercator_levels(&images, scale, x, y))
+        .unwrap_or_else(|| {
+            if georef.is_some() {
+                warn!(
+                    "File {} does not use the Web Mercator tile grid, serving its tiles starting at zoom 0",
+                    path.display()
+                );
+            }
+            get_levels(&images)
+        });
+    let bounds = georef.map(|(scale, x, y)| {
+        let full = &images[0];
+        let width = f64::from(full.width) * scale;
+        let height = f64::from(full.tiles_down * full.tile_height) * scale;
+        let (west, north) = mercator_to_lon_lat(x, y);
+        let (east, south) = mercator_to_lon_lat(x + width, y - height);
+        Bounds::new(west, south.max(-85.051_129), east, north.min(85.051_129))
+    });
+
+    Ok(CogMeta {
+        levels,
+        color_type,
+        nodata,
+        bounds,
+    })
+}
+
+/// Assign zoom levels without georeferencing: the smallest overview is zoom 0
+fn get_levels(images: &[ImageInfo]) -> BTreeMap<u8, CogLevel> {
+    images
+        .iter()
+        .rev()
+        .zip(0_u8..=30)
+        .map(|(img, zoom)| (zoom, new_level(img, 0, 0)))
+        .collect()
+}
+
+/// Find the Web Mercator zoom level and tile position of every image.
+/// Returns `None` if any image is not aligned with the tile grid.
+fn get_mercator_levels(
+    images: &[ImageInfo],
+    scale: f64,
+    origin_x: f64,
+    origin_y: f64,
+) -> Option<BTreeMap<u8, CogLevel>> {
+    let full_width = f64::from(images.first()?.width);
+    let half = EARTH_CIRCUMFERENCE / 2.0;
+    let mut levels = BTreeMap::new();
+    for img in images {
+        // overviews cover the same area with fewer pixels
+        let tile_size = scale * full_width / f64::from(img.width) * f64::from(img.tile_width);
+        let tile_count = to_whole(EARTH_CIRCUMFERENCE / tile_size)?;
+        let zoom = tile_count.trailing_zeros();
+        if !tile_count.is_power_of_two() || zoom > 30 {
+            return None;
+        }
+        let x = to_whole((origin_x + half) / tile_size)?;
+        let y = to_whole((half - origin_y) / tile_size)?;
+        // Zoom was checked to be at most 30, so tile positions fit into u32
+        #[allow(clippy::cast_possible_truncation)]
+        levels.insert(zoom as u8, new_level(img, x as u32, y as u32));
+    }
+    Some(levels)
+}
+
+fn new_level(img: &ImageInfo, x_offset: u32, y_offset: u32) -> CogLevel {
+    CogLevel {
+        ifd: img.ifd,
+        tile_width: img.tile_width,
+        tile_height: img.tile_height,
+        tiles_across: img.tiles_across,
+        tiles_down: img.tiles_down,
+        x_offset,
+        y_offset,
+    }
+}
+
+/// Round the value if it is very close to a non-negative whole number
+fn to_whole(value: f64) -> Option<u64> {
+    let rounded = value.round();
+    if rounded >= 0.0 && (value - rounded).abs() < 1e-3 {
+        // The value was checked to be a non-negative whole number
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Some(rounded as u64)
+    } else {
+        None
+    }
+}
+
+fn mercator_to_lon_lat(x: f64, y: f64) -> (f64, f64) {
+    let radius = EARTH_CIRCUMFERENCE / (2.0 * std::f64::consts::PI);
+    let lon = (x / radius).to_degrees();
+    let lat = (2.0 * (y / radius).exp().atan() - std::f64::consts::FRAC_PI_2).to_degrees();
+    (lon, lat)
+}
+
+/// Convert the pixels of an internal tile into a full-size RGBA image.
+/// Edge tiles may be smaller than the tile size, the rest of the image is transparent.
+fn to_rgba(
+    data: &[u8],
+    (width, height): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+    color_type: ColorType,
+    nodata: Option<u8>,
+) -> Vec<u8> {
+    let samples = match color_type {
+        ColorType::Gray(_) => 1,
+        ColorType::GrayA(_) => 2,
+        ColorType::RGB(_) => 3,
+        _ => 4,
+    };
+    let (width, height) = (width as usize, height as usize);
+    let tile_width = tile_width as usize;
+    let mut result = vec![0; tile_width * tile_height as usize * 4];
+    for (y, row) in data.chunks_exact(width * samples).take(height).enumerate() {
+        for (x, px) in row.chunks_exact(samples).enumerate() {
+            let is_nodata = nodata.map_or(false, |v| px.iter().all(|p| *p == v));
+            let rgba = match (px, is_nodata) {
+                (_, true) => [0, 0, 0, 0],
+                ([g], _) => [*g, *g, *g, 255],
+                ([g, a], _) => [*g, *g, *g, *a],
+                ([r, g, b], _) => [*r, *g, *b, 255],
+                ([r, g, b, a], _) => [*r, *g, *b, *a],
+                _ => unreachable!(),
+            };
+            let pos = (y * tile_width + x) * 4;
+            result[pos..pos + 4].copy_from_slice(&rgba);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(ifd: usize, width: u32) -> ImageInfo {
+        ImageInfo {
+            ifd,
+            width,
+            tile_width: 256,
+            tile_height: 256,
+            tiles_across: (width + 255) / 256,
+            tiles_down: (width + 255) / 256,
+        }
+    }
+
+    #[test]
+    fn mercator_levels() {
+        let half = EARTH_CIRCUMFERENCE / 2.0;
+        let images = [image(0, 1024), image(1, 512), image(2, 256)];
+
+        // whole world at zoom 2
+        let scale = EARTH_CIRCUMFERENCE / 1024.0;
+        let levels = get_mercator_levels(&images, scale, -half, half).unwrap();
+        assert_eq!(levels.keys().copied().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(levels[&2].ifd, 0);
+        assert_eq!(levels[&0].ifd, 2);
+        assert_eq!((levels[&2].x_offset, levels[&2].y_offset), (0, 0));
+
+        // south-east quarter of the world, 4 tiles at zoom 3
+        let scale = EARTH_CIRCUMFERENCE / 2048.0;
+        let levels = get_mercator_levels(&images, scale, 0.0, 0.0).unwrap();
+        assert_eq!((levels[&3].x_offset, levels[&3].y_offset), (4, 4));
+        assert_eq!((levels[&1].x_offset, levels[&1].y_offset), (1, 1));
+
+        // not aligned with the tile grid
+        assert!(get_mercator_levels(&images, scale, scale * 128.0, 0.0).is_none());
+        assert!(get_mercator_levels(&images, 10.0, -half, half).is_none());
+    }
+
+    #[test]
+    fn plain_levels() {
+        let levels = get_levels(&[image(0, 1024), image(2, 512)]);
+        assert_eq!(levels[&0].ifd, 2);
+        assert_eq!(levels[&1].ifd, 0);
+        assert_eq!(levels[&1].tiles_across, 4);
+    }
+
+    #[test]
+    fn rgba_padding() {
+        let rgb = [1, 2, 3, 0, 0, 0];
+        let result = to_rgba(&rgb, (2, 1), (2, 2), ColorType::RGB(8), Some(0));
+        assert_eq!(result, [1, 2, 3, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let gray = [7];
+        let result = to_rgba(&gray, (1, 1), (1, 1), ColorType::Gray(8), None);
+        assert_eq!(result, [7, 7, 7, 255]);
+    }
+}
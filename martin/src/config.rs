@@ -1,37 +1,80 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::future::Future;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::OnceLock;
 
 use futures::future::try_join_all;
-use log::info;
+use log::{info, warn};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use subst::VariableMap;
 
-use crate::file_config::{resolve_files, FileConfigEnum};
-use crate::fonts::FontSources;
+use crate::aliases::{apply_aliases, rename_keys, SourceAliases};
+use crate::cog::CogSource;
+use crate::factory::SourceFactories;
+use crate::file_config::{resolve_files, FileConfigEnum, FileConfigSource};
+use crate::fonts::{FontFallbacks, FontSources};
+use crate::geoparquet::GeoParquetSource;
+use crate::gpkg::GpkgSource;
 use crate::mbtiles::MbtSource;
+use crate::osm::OsmSource;
+use crate::overrides::{apply_overrides, SourceOverrides};
+use crate::overzoom::{apply_overzoom, OverzoomConfigs};
 use crate::pg::PgConfig;
 use crate::pmtiles::PmtSource;
+use crate::proxy::{resolve_proxies, ProxyConfigs};
+use crate::shapefile::ShapefileSource;
 use crate::source::{TileInfoSources, TileSources};
 use crate::sprites::SpriteSources;
-use crate::srv::SrvConfig;
-use crate::MartinError::{ConfigLoadError, ConfigParseError, ConfigWriteError, NoSources};
+use crate::sqlite::SqliteSource;
+use crate::srv::{ScheduledTask, SrvConfig, TaskConfig};
+use crate::styles::StyleSources;
+use crate::terrain::{apply_terrain, TerrainConfigs};
+use crate::utils::{new_main_cache, OptBoolObj, OptMainCache, TileExpiration};
+use crate::variants::VariantConfigs;
+use crate::watermark::{apply_watermarks, WatermarkConfigs};
+use crate::MartinError::{
+    ConfigIncludeCycle, ConfigLoadError, ConfigParseError, ConfigSubstError, ConfigValueError,
+    ConfigWriteError, InvalidSource, NoSources,
+};
 use crate::{IdResolver, MartinResult, OptOneMany};
 
-pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
+pub const CACHE_SIZE_MB_DEFAULT: u64 = 512;
+
+pub type UnrecognizedValues = HashMap<String, Value>;
 
 pub struct ServerState {
+    /// Configuration before the sources were resolved, used to discover them again
+    pub discovery: Config,
+    pub cache: OptMainCache,
+    pub tile_expiration: TileExpiration,
     pub tiles: TileSources,
     pub sprites: SpriteSources,
     pub fonts: FontSources,
+    pub styles: StyleSources,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Config {
+    /// Maximum size of the in-memory tile cache in megabytes, 0 to disable
+    pub cache_size_mb: Option<u64>,
+
+    /// Tile `max-age` in seconds per zoom range, keyed by the first zoom of each range
+    pub tile_max_age: Option<BTreeMap<u8, u64>>,
+
+    /// Seconds to keep serving the cached tiles of each source after their `max-age`, while they
+    /// are fetched again in the background, keyed by source ID
+    pub stale_while_revalidate: Option<BTreeMap<String, u64>>,
+
+    /// Empty tile `max-age` in seconds, replacing the `tile_max_age` of their zoom
+    pub empty_tile_max_age: Option<u64>,
+
     #[serde(flatten)]
     pub srv: SrvConfig,
 
@@ -44,14 +87,71 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
     pub mbtiles: FileConfigEnum,
 
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub cog: FileConfigEnum,
+
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub gpkg: FileConfigEnum,
+
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub geoparquet: FileConfigEnum,
+
+    /// `OpenStreetMap` extracts in the PBF format, generating vector tiles from their nodes and ways
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub osm: FileConfigEnum,
+
+    /// ESRI Shapefiles, or zip archives of them, generating vector tiles from their features
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub shapefile: FileConfigEnum,
+
+    /// `SQLite` tile archives with a custom tile table, e.g. the ones made by legacy tools
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub sqlite: FileConfigEnum,
+
+    /// Upstream tile servers proxied as tile sources
+    pub proxy: Option<ProxyConfigs>,
+
     #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
     pub sprites: FileConfigEnum,
 
     #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
     pub fonts: OptOneMany<PathBuf>,
 
+    /// Fonts used for the glyphs missing from a requested font, or instead of a missing font
+    pub font_fallbacks: Option<FontFallbacks>,
+
+    /// `MapLibre` style files, served with their URLs pointing to this server
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub styles: FileConfigEnum,
+
+    /// Named composite sources, each combining several tile sources into one
+    pub composites: Option<BTreeMap<String, Vec<String>>>,
+
+    /// Sources served by one of several other sources, selected by a request header or query parameter
+    pub variants: Option<VariantConfigs>,
+
+    /// Tiles above the maxzoom of a source, generated from its maxzoom tiles, keyed by source ID
+    pub overzoom: Option<OverzoomConfigs>,
+
+    /// Watermarks stamped onto the tiles of raster sources, keyed by source ID
+    pub watermarks: Option<WatermarkConfigs>,
+
+    /// Terrain encodings of the raster DEM sources, and of their served tiles, keyed by source ID
+    pub terrain: Option<TerrainConfigs>,
+
+    /// New IDs of the sources, keyed by their discovered or configured IDs.
+    /// All other settings and the URLs use the new IDs.
+    pub aliases: Option<SourceAliases>,
+
+    /// Zoom levels and visibility of the sources, replacing the discovered ones, keyed by source ID
+    pub overrides: Option<SourceOverrides>,
+
     #[serde(flatten)]
     pub unrecognized: UnrecognizedValues,
+
+    /// Factories of the custom sources, configured by some of the unrecognized sections
+    #[serde(skip)]
+    pub factories: SourceFactories,
 }
 
 impl Config {
@@ -59,6 +159,15 @@ impl Config {
     pub fn finalize(&mut self) -> MartinResult<UnrecognizedValues> {
         let mut res = UnrecognizedValues::new();
         copy_unrecognized_config(&mut res, "", &self.unrecognized);
+        res.retain(|key, _| !self.factories.contains(key));
+
+        self.srv.finalize()?;
+        if let Some(aliases) = &self.aliases {
+            self.srv.rename_sources(aliases);
+            if let Some(stale) = &mut self.stale_while_revalidate {
+                rename_keys(stale, aliases);
+            }
+        }
 
         for pg in self.postgres.iter_mut() {
             res.extend(pg.finalize()?);
@@ -66,7 +175,14 @@ impl Config {
 
         res.extend(self.pmtiles.finalize("pmtiles.")?);
         res.extend(self.mbtiles.finalize("mbtiles.")?);
+        res.extend(self.cog.finalize("cog.")?);
+        res.extend(self.gpkg.finalize("gpkg.")?);
+        res.extend(self.geoparquet.finalize("geoparquet.")?);
+        res.extend(self.osm.finalize("osm.")?);
+        res.extend(self.shapefile.finalize("shapefile.")?);
+        res.extend(self.sqlite.finalize("sqlite.")?);
         res.extend(self.sprites.finalize("sprites.")?);
+        res.extend(self.styles.finalize("styles.")?);
 
         // TODO: support for unrecognized fonts?
         // res.extend(self.fonts.finalize("fonts.")?);
@@ -74,8 +190,17 @@ impl Config {
         if self.postgres.is_empty()
             && self.pmtiles.is_empty()
             && self.mbtiles.is_empty()
+            && self.cog.is_empty()
+            && self.gpkg.is_empty()
+            && self.geoparquet.is_empty()
+            && self.osm.is_empty()
+            && self.shapefile.is_empty()
+            && self.sqlite.is_empty()
+            && self.proxy.as_ref().map_or(true, ProxyConfigs::is_empty)
             && self.sprites.is_empty()
             && self.fonts.is_empty()
+            && self.styles.is_empty()
+            && !self.unrecognized.keys().any(|v| self.factories.contains(v))
         {
             Err(NoSources)
         } else {
@@ -84,16 +209,62 @@ impl Config {
     }
 
     pub async fn resolve(&mut self, idr: IdResolver) -> MartinResult<ServerState> {
+        self.resolve_with_sources(idr, Vec::new()).await
+    }
+
+    /// Resolve the configured sources together with the tile sources created by an application
+    /// embedding Martin, so that composite and variant sources can use them as well
+    pub async fn resolve_with_sources(
+        &mut self,
+        idr: IdResolver,
+        embedded: TileInfoSources,
+    ) -> MartinResult<ServerState> {
+        let discovery = self.clone();
+        let tile_expiration = TileExpiration::new(self.tile_max_age.as_ref())
+            .with_stale_windows(self.stale_while_revalidate.as_ref())
+            .with_empty_max_age(self.empty_tile_max_age);
+        let cache_size = self.cache_size_mb.unwrap_or(CACHE_SIZE_MB_DEFAULT);
         Ok(ServerState {
-            tiles: self.resolve_tile_sources(idr).await?,
-            sprites: SpriteSources::resolve(&mut self.sprites)?,
-            fonts: FontSources::resolve(&mut self.fonts)?,
+            discovery,
+            cache: new_main_cache(cache_size, tile_expiration.clone()),
+            tile_expiration,
+            tiles: self.resolve_tile_sources_with(idr, embedded).await?,
+            sprites: SpriteSources::resolve(&mut self.sprites).await?,
+            fonts: FontSources::resolve(&mut self.fonts, self.font_fallbacks.as_ref()).await?,
+            styles: StyleSources::resolve(&mut self.styles)?,
         })
     }
 
-    async fn resolve_tile_sources(&mut self, idr: IdResolver) -> MartinResult<TileSources> {
-        let new_pmt_src = &mut PmtSource::new_box;
-        let new_mbt_src = &mut MbtSource::new_box;
+    pub async fn resolve_tile_sources(&mut self, idr: IdResolver) -> MartinResult<TileSources> {
+        self.resolve_tile_sources_with(idr, Vec::new()).await
+    }
+
+    async fn resolve_tile_sources_with(
+        &mut self,
+        idr: IdResolver,
+        embedded: TileInfoSources,
+    ) -> MartinResult<TileSources> {
+        // claim the IDs of the embedded sources first, so that the discovered ones are renamed instead
+        for src in &embedded {
+            let id = src.get_id();
+            if idr.resolve(id, format!("embedded:{id}")) != id {
+                let msg = "the ID is reserved, used twice, or has invalid characters".to_string();
+                return Err(InvalidSource(id.to_string(), msg));
+            }
+        }
+        let new_pmt_src = &mut |id, cfg: FileConfigSource| PmtSource::new_box(id, cfg.path);
+        let new_mbt_src = &mut |id, cfg: FileConfigSource| MbtSource::new_box(id, cfg.path);
+        let new_cog_src = &mut |id, cfg: FileConfigSource| CogSource::new_box(id, cfg.path);
+        let new_gpkg_src =
+            &mut |id, cfg: FileConfigSource| GpkgSource::new_box(id, cfg.path, cfg.antimeridian);
+        let new_parquet_src =
+            &mut |id, cfg: FileConfigSource| GeoParquetSource::new_box(id, cfg.path);
+        let new_osm_src =
+            &mut |id, cfg: FileConfigSource| OsmSource::new_box(id, cfg.path, cfg.layers);
+        let new_shapefile_src =
+            &mut |id, cfg: FileConfigSource| ShapefileSource::new_box(id, cfg.path);
+        let new_sqlite_src =
+            &mut |id, cfg: FileConfigSource| SqliteSource::new_box(id, cfg.path, cfg.tile_table);
         let mut sources: Vec<Pin<Box<dyn Future<Output = MartinResult<TileInfoSources>>>>> =
             Vec::new();
 
@@ -111,7 +282,220 @@ impl Config {
             sources.push(Box::pin(val));
         }
 
-        Ok(TileSources::new(try_join_all(sources).await?))
+        if !self.cog.is_empty() {
+            let val = resolve_files(&mut self.cog, idr.clone(), "tif", new_cog_src);
+            sources.push(Box::pin(val));
+        }
+
+        if !self.gpkg.is_empty() {
+            let val = resolve_files(&mut self.gpkg, idr.clone(), "gpkg", new_gpkg_src);
+            sources.push(Box::pin(val));
+        }
+
+        if !self.geoparquet.is_empty() {
+            let val = resolve_files(
+                &mut self.geoparquet,
+                idr.clone(),
+                "parquet",
+                new_parquet_src,
+            );
+            sources.push(Box::pin(val));
+        }
+
+        if !self.osm.is_empty() {
+            let val = resolve_files(&mut self.osm, idr.clone(), "osm.pbf", new_osm_src);
+            sources.push(Box::pin(val));
+        }
+
+        if !self.shapefile.is_empty() {
+            let val = resolve_files(&mut self.shapefile, idr.clone(), "shp", new_shapefile_src);
+            sources.push(Box::pin(val));
+        }
+
+        if !self.sqlite.is_empty() {
+            let val = resolve_files(&mut self.sqlite, idr.clone(), "sqlite", new_sqlite_src);
+            sources.push(Box::pin(val));
+        }
+
+        if let Some(proxy) = &mut self.proxy {
+            sources.push(Box::pin(resolve_proxies(proxy, idr.clone())));
+        }
+
+        let custom = self
+            .factories
+            .create_sources(&self.unrecognized, idr.clone());
+        sources.push(Box::pin(custom));
+
+        let mut sources = try_join_all(sources).await?;
+        // renamed first, so that the settings below are keyed by the served IDs
+        if let Some(aliases) = &self.aliases {
+            sources = apply_aliases(sources, aliases, &idr)?;
+        }
+        // the watermarks are stamped onto the overzoomed tiles, so that they are not scaled
+        if let Some(overzoom) = &self.overzoom {
+            sources = apply_overzoom(sources, overzoom)?;
+        }
+        if let Some(watermarks) = &self.watermarks {
+            sources = apply_watermarks(sources, watermarks)?;
+        }
+        if let Some(terrain) = &self.terrain {
+            sources = apply_terrain(sources, terrain)?;
+        }
+        // the configured zoom levels replace the ones extended by overzoom
+        if let Some(overrides) = &self.overrides {
+            sources = apply_overrides(sources, overrides)?;
+        }
+        let mut sources = TileSources::new(sources);
+        for src in embedded {
+            sources.add_embedded(src)?;
+        }
+        if let Some(composites) = &self.composites {
+            sources.set_composites(composites)?;
+        }
+        if let Some(variants) = &self.variants {
+            sources.set_variants(variants)?;
+        }
+        sources.set_hidden(self.get_hidden_source_ids());
+        Ok(sources)
+    }
+
+    /// Get the IDs of all tile sources that are explicitly configured, i.e. not auto-discovered
+    #[must_use]
+    pub fn get_source_ids(&self) -> Vec<String> {
+        let mut ids = Vec::new();
+        for pg in self.postgres.iter() {
+            ids.extend(pg.tables.iter().flat_map(|v| v.keys().cloned()));
+            ids.extend(pg.functions.iter().flat_map(|v| v.keys().cloned()));
+        }
+        for cfg in [
+            &self.pmtiles,
+            &self.mbtiles,
+            &self.cog,
+            &self.gpkg,
+            &self.geoparquet,
+            &self.osm,
+            &self.shapefile,
+            &self.sqlite,
+        ] {
+            ids.extend(cfg.get_source_ids());
+        }
+        ids.extend(self.proxy.iter().flat_map(|v| v.keys().cloned()));
+        ids
+    }
+
+    /// Get the IDs of the resolved sources that are served, but not listed in the catalog
+    #[must_use]
+    pub fn get_hidden_source_ids(&self) -> Vec<String> {
+        fn hidden<T>(
+            infos: Option<&BTreeMap<String, T>>,
+            f: fn(&T) -> Option<bool>,
+        ) -> Vec<String> {
+            infos
+                .into_iter()
+                .flatten()
+                .filter(|(_, v)| f(v) == Some(true))
+                .map(|(id, _)| id.clone())
+                .collect()
+        }
+        let mut ids = Vec::new();
+        for pg in self.postgres.iter() {
+            ids.extend(hidden(pg.tables.as_ref(), |v| v.hidden));
+            ids.extend(hidden(pg.functions.as_ref(), |v| v.hidden));
+            ids.extend(hidden(pg.rasters.as_ref(), |v| v.hidden));
+        }
+        for cfg in [
+            &self.pmtiles,
+            &self.mbtiles,
+            &self.cog,
+            &self.gpkg,
+            &self.geoparquet,
+            &self.osm,
+            &self.shapefile,
+            &self.sqlite,
+        ] {
+            ids.extend(cfg.get_hidden_source_ids());
+        }
+        ids.extend(hidden(self.proxy.as_ref(), |v| v.hidden));
+        if let Some(aliases) = &self.aliases {
+            for id in &mut ids {
+                if let Some(new_id) = aliases.get(id) {
+                    new_id.clone_into(id);
+                }
+            }
+        }
+        ids.extend(hidden(self.overrides.as_ref(), |v| v.hidden));
+        ids
+    }
+
+    /// Get the local directories that are scanned for the file sources
+    #[must_use]
+    pub fn get_file_directories(&self) -> Vec<PathBuf> {
+        [
+            &self.pmtiles,
+            &self.mbtiles,
+            &self.cog,
+            &self.gpkg,
+            &self.geoparquet,
+            &self.osm,
+            &self.shapefile,
+            &self.sqlite,
+        ]
+        .into_iter()
+        .flat_map(FileConfigEnum::get_directories)
+        .collect()
+    }
+
+    /// Get the IDs of the resolved file sources that are read from any of the given absolute file paths
+    #[must_use]
+    pub fn find_file_sources(&self, files: &[PathBuf]) -> Vec<String> {
+        [
+            &self.pmtiles,
+            &self.mbtiles,
+            &self.cog,
+            &self.gpkg,
+            &self.geoparquet,
+            &self.osm,
+            &self.shapefile,
+            &self.sqlite,
+        ]
+        .into_iter()
+        .flat_map(|cfg| cfg.find_sources(files))
+        .collect()
+    }
+
+    /// The `rediscover` task requested by the `rediscover_interval_secs` of the `PostgreSQL` connections,
+    /// using the shortest interval if several connections set it
+    #[must_use]
+    pub fn pg_rediscover_task(&self) -> Option<ScheduledTask> {
+        let interval_secs = self
+            .postgres
+            .iter()
+            .filter_map(|pg| pg.rediscover_interval_secs)
+            .min()?;
+        Some(ScheduledTask {
+            interval_secs,
+            task: TaskConfig::Rediscover,
+        })
+    }
+
+    /// Disable auto-discovery, so that a resolved config only publishes the sources it lists.
+    /// Such config can be moved to another instance, and will publish the same sources with the same IDs.
+    pub fn pin_sources(&mut self) {
+        for pg in self.postgres.iter_mut() {
+            pg.auto_publish = OptBoolObj::Bool(false);
+        }
+        for cfg in [
+            &mut self.pmtiles,
+            &mut self.mbtiles,
+            &mut self.cog,
+            &mut self.gpkg,
+            &mut self.geoparquet,
+            &mut self.osm,
+            &mut self.shapefile,
+            &mut self.sqlite,
+        ] {
+            cfg.pin_sources();
+        }
     }
 
     pub fn save_to_file(&self, file_name: PathBuf) -> MartinResult<()> {
@@ -147,7 +531,7 @@ pub fn copy_unrecognized_config(
     );
 }
 
-/// Read config from a file
+/// Read config from a file, including the files listed in its `include` value
 pub fn read_config<'a, M>(file_name: &Path, env: &'a M) -> MartinResult<Config>
 where
     M: VariableMap<'a>,
@@ -160,16 +544,302 @@ where
     parse_config(&contents, env, file_name)
 }
 
+/// Parse the config, replacing `${VAR}`, `${VAR:default}`, and `${VAR:-default}` in all string values
+/// with the environment variables. A value that is just a variable, e.g. `${WORKERS:4}`, is also accepted
+/// where a number or a boolean is expected. The `include` files are relative to the directory of `file_name`.
 pub fn parse_config<'a, M>(contents: &str, env: &'a M, file_name: &Path) -> MartinResult<Config>
 where
     M: VariableMap<'a>,
     M::Value: AsRef<str>,
 {
-    subst::yaml::from_str(contents, env).map_err(|e| ConfigParseError(e, file_name.into()))
+    let mut parents = vec![canonical_path(file_name)];
+    let value = parse_config_value(contents, env, file_name, &mut parents)?;
+    serde_yaml::from_value(value)
+        .map_err(|e| ConfigParseError(subst::yaml::Error::Yaml(e), file_name.into()))
+}
+
+fn canonical_path(file_name: &Path) -> PathBuf {
+    file_name
+        .canonicalize()
+        .unwrap_or_else(|_| file_name.to_path_buf())
+}
+
+/// Read an included config file as a YAML value, keeping the chain of the including files to detect include cycles
+fn read_config_value<'a, M>(
+    file_name: &Path,
+    env: &'a M,
+    parents: &mut Vec<PathBuf>,
+) -> MartinResult<Value>
+where
+    M: VariableMap<'a>,
+    M::Value: AsRef<str>,
+{
+    let mut file = File::open(file_name).map_err(|e| ConfigLoadError(e, file_name.into()))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| ConfigLoadError(e, file_name.into()))?;
+
+    let path = canonical_path(file_name);
+    if parents.contains(&path) {
+        return Err(ConfigIncludeCycle(file_name.into()));
+    }
+    parents.push(path);
+    let value = parse_config_value(&contents, env, file_name, parents);
+    parents.pop();
+    value
+}
+
+fn parse_config_value<'a, M>(
+    contents: &str,
+    env: &'a M,
+    file_name: &Path,
+    parents: &mut Vec<PathBuf>,
+) -> MartinResult<Value>
+where
+    M: VariableMap<'a>,
+    M::Value: AsRef<str>,
+{
+    let parse_err = |e| ConfigParseError(subst::yaml::Error::Yaml(e), file_name.into());
+    let mut value: Value = serde_yaml::from_str(contents).map_err(parse_err)?;
+    let mut single_vars = HashSet::new();
+    substitute_env_vars(&mut value, env, "", &mut single_vars)
+        .map_err(|(key, e)| ConfigSubstError(key, e, file_name.into()))?;
+    loop {
+        let Err(err) = serde_yaml::from_value::<Config>(value.clone()) else {
+            break;
+        };
+        // Variables are substituted as strings, so that e.g. a numeric password stays a string.
+        // Only the values rejected as strings are converted to numbers and booleans.
+        if !retype_env_vars(&mut value, "", &single_vars, &err.to_string()) {
+            return Err(match locate_parse_error(&value, &err.to_string()) {
+                Some(key) => ConfigValueError(key, err, file_name.into()),
+                None => parse_err(err),
+            });
+        }
+    }
+
+    let Some(include_value) = value.as_mapping_mut().and_then(|v| v.remove("include")) else {
+        return Ok(value);
+    };
+    let mut merged = Value::Mapping(serde_yaml::Mapping::new());
+    for path in get_include_files(include_value, file_name)? {
+        let included = read_config_value(&path, env, parents)?;
+        merge_config_values(&mut merged, included, "", &path);
+    }
+    merge_config_values(&mut merged, value, "", file_name);
+    Ok(merged)
+}
+
+/// Get the files of the `include` value, which is a path or a list of paths relative to the including file.
+/// A directory includes all of its `.yaml` and `.yml` files, sorted by name.
+fn get_include_files(includes: Value, file_name: &Path) -> MartinResult<Vec<PathBuf>> {
+    let parse_err = |e| ConfigParseError(subst::yaml::Error::Yaml(e), file_name.into());
+    let includes: OptOneMany<PathBuf> = serde_yaml::from_value(includes).map_err(parse_err)?;
+    let base_dir = file_name.parent().unwrap_or_else(|| Path::new(""));
+    let mut files = Vec::new();
+    for path in includes.iter() {
+        let path = base_dir.join(path);
+        if path.is_dir() {
+            let entries = path
+                .read_dir()
+                .map_err(|e| ConfigLoadError(e, path.clone()))?;
+            let mut dir_files: Vec<_> = entries
+                .flatten()
+                .map(|v| v.path())
+                .filter(|v| {
+                    v.is_file()
+                        && matches!(v.extension().and_then(OsStr::to_str), Some("yaml" | "yml"))
+                })
+                .collect();
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Merge a config value into the values merged so far. Mappings are merged key by key,
+/// lists are concatenated without duplicates, and any other non-empty value replaces the previous one.
+fn merge_config_values(target: &mut Value, value: Value, key: &str, file_name: &Path) {
+    match (target, value) {
+        (_, Value::Null) => {}
+        (Value::Mapping(target), Value::Mapping(map)) => {
+            for (name, value) in map {
+                let key = child_key(key, &name);
+                if let Some(target) = target.get_mut(&name) {
+                    merge_config_values(target, value, &key, file_name);
+                } else {
+                    target.insert(name, value);
+                }
+            }
+        }
+        (Value::Sequence(target), Value::Sequence(items)) => {
+            for item in items {
+                if !target.contains(&item) {
+                    target.push(item);
+                }
+            }
+        }
+        (target, value) => {
+            if *target != value {
+                warn!(
+                    "Config value `{key}` from {} replaces the value of a previously included file",
+                    file_name.display()
+                );
+                *target = value;
+            }
+        }
+    }
+}
+
+/// Substitute the variables of all string values, keeping the key of the failed value for the error message.
+/// The keys of the values that consist of a single variable are added to `single_vars`.
+fn substitute_env_vars<'a, M>(
+    value: &mut Value,
+    env: &'a M,
+    key: &str,
+    single_vars: &mut HashSet<String>,
+) -> Result<(), (String, subst::Error)>
+where
+    M: VariableMap<'a>,
+    M::Value: AsRef<str>,
+{
+    static RE_DEFAULT: OnceLock<Regex> = OnceLock::new();
+    static RE_SINGLE_VAR: OnceLock<Regex> = OnceLock::new();
+
+    match value {
+        Value::String(text) => {
+            // Accept the shell syntax for the default values, e.g. `${PORT:-3000}`
+            let text = RE_DEFAULT
+                .get_or_init(|| Regex::new(r"\$\{(\w+):-").unwrap())
+                .replace_all(text, "$${${1}:");
+            if RE_SINGLE_VAR
+                .get_or_init(|| Regex::new(r"^\$\{\w+(:[^}]*)?\}$").unwrap())
+                .is_match(&text)
+            {
+                single_vars.insert(key.to_string());
+            }
+            *value =
+                Value::String(subst::substitute(&text, env).map_err(|e| (key.to_string(), e))?);
+        }
+        Value::Sequence(items) => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                substitute_env_vars(item, env, &format!("{key}[{idx}]"), single_vars)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (name, item) in map.iter_mut() {
+                substitute_env_vars(item, env, &child_key(key, name), single_vars)?;
+            }
+        }
+        Value::Tagged(tagged) => substitute_env_vars(&mut tagged.value, env, key, single_vars)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Convert the single variable values mentioned by the deserialization error to numbers or booleans.
+/// Returns false if nothing was converted.
+fn retype_env_vars(value: &mut Value, key: &str, single_vars: &HashSet<String>, err: &str) -> bool {
+    match value {
+        Value::String(text) if single_vars.contains(key) => {
+            if !err.contains(&format!("invalid type: string {text:?}")) {
+                return false;
+            }
+            match serde_yaml::from_str::<Value>(text) {
+                Ok(v @ (Value::Number(_) | Value::Bool(_))) => {
+                    *value = v;
+                    true
+                }
+                _ => false,
+            }
+        }
+        Value::Sequence(items) => items
+            .iter_mut()
+            .enumerate()
+            .fold(false, |res, (idx, item)| {
+                retype_env_vars(item, &format!("{key}[{idx}]"), single_vars, err) || res
+            }),
+        Value::Mapping(map) => map.iter_mut().fold(false, |res, (name, item)| {
+            retype_env_vars(item, &child_key(key, name), single_vars, err) || res
+        }),
+        Value::Tagged(tagged) => retype_env_vars(&mut tagged.value, key, single_vars, err),
+        _ => false,
+    }
+}
+
+/// A step from a YAML value to one of its children
+enum ValueStep {
+    Key(Value),
+    Index(usize),
+}
+
+fn get_child_mut<'a>(value: &'a mut Value, path: &[ValueStep]) -> Option<&'a mut Value> {
+    path.iter()
+        .try_fold(value, |value, step| match (value, step) {
+            (Value::Mapping(map), ValueStep::Key(name)) => map.get_mut(name),
+            (Value::Sequence(items), ValueStep::Index(idx)) => items.get_mut(*idx),
+            _ => None,
+        })
+}
+
+/// Find the key of the value rejected by the deserialization, e.g. `postgres[0].pool_size`.
+/// The values lose their location in the file once the variables are substituted, so the children
+/// are removed one at a time, following the one whose removal makes the error go away.
+fn locate_parse_error(root: &Value, err: &str) -> Option<String> {
+    let mut path = Vec::new();
+    let mut key = String::new();
+    loop {
+        let mut node = root.clone();
+        let steps: Vec<_> = match get_child_mut(&mut node, &path)? {
+            Value::Mapping(map) => map.keys().cloned().map(ValueStep::Key).collect(),
+            Value::Sequence(items) => (0..items.len()).map(ValueStep::Index).collect(),
+            _ => Vec::new(),
+        };
+        let culprit = steps.into_iter().find(|step| {
+            let mut pruned = root.clone();
+            match (get_child_mut(&mut pruned, &path), step) {
+                (Some(Value::Mapping(map)), ValueStep::Key(name)) => {
+                    map.remove(name);
+                }
+                (Some(Value::Sequence(items)), ValueStep::Index(idx)) => {
+                    items.remove(*idx);
+                }
+                _ => return false,
+            }
+            !matches!(serde_yaml::from_value::<Config>(pruned), Err(e) if e.to_string() == err)
+        });
+        let Some(step) = culprit else { break };
+        key = match &step {
+            ValueStep::Key(name) => child_key(&key, name),
+            ValueStep::Index(idx) => format!("{key}[{idx}]"),
+        };
+        path.push(step);
+    }
+    (!key.is_empty()).then_some(key)
+}
+
+fn child_key(key: &str, name: &Value) -> String {
+    let name = match name {
+        Value::String(v) => v.clone(),
+        v => serde_yaml::to_string(v)
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    };
+    if key.is_empty() {
+        name
+    } else {
+        format!("{key}.{name}")
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use indoc::indoc;
+
     use super::*;
     use crate::config::Config;
     use crate::test_utils::FauxEnv;
@@ -184,4 +854,183 @@ pub mod tests {
         assert!(res.is_empty(), "unrecognized config: {res:?}");
         assert_eq!(&config, expected);
     }
+
+    #[test]
+    fn config_includes() {
+        let dir = std::env::temp_dir().join(format!("martin-config-{}", std::process::id()));
+        let teams = dir.join("teams");
+        std::fs::create_dir_all(&teams).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write(
+            "base.yaml",
+            "keep_alive: 10\nworker_processes: 2\npmtiles:\n  paths: [/data/base]\n",
+        );
+        write(
+            "teams/a.yaml",
+            "pmtiles:\n  paths: [/data/a]\n  sources:\n    roads: /data/a/roads.pmtiles\n",
+        );
+        write(
+            "teams/b.yml",
+            "pmtiles:\n  sources:\n    roads: /data/b/roads.pmtiles\n    water: /data/b/water.pmtiles\n",
+        );
+        write("teams/notes.txt", "not a config");
+        write(
+            "main.yaml",
+            "include: [base.yaml, teams]\nworker_processes: 4\n",
+        );
+
+        let config = read_config(&dir.join("main.yaml"), &FauxEnv::default()).unwrap();
+        // the including file wins over the included ones, and the later includes over the earlier ones
+        assert_eq!(config.srv.keep_alive, Some(10));
+        assert_eq!(config.srv.worker_processes, Some(4));
+        let FileConfigEnum::Config(pmtiles) = config.pmtiles else {
+            panic!()
+        };
+        assert_eq!(
+            pmtiles.paths,
+            OptOneMany::Many(vec![PathBuf::from("/data/base"), PathBuf::from("/data/a")])
+        );
+        let sources = pmtiles.sources.unwrap();
+        assert_eq!(sources.keys().collect::<Vec<_>>(), vec!["roads", "water"]);
+        assert_eq!(
+            sources["roads"].get_path(),
+            &PathBuf::from("/data/b/roads.pmtiles")
+        );
+
+        write("base.yaml", "include: main.yaml\n");
+        let err = read_config(&dir.join("main.yaml"), &FauxEnv::default()).unwrap_err();
+        assert!(matches!(err, ConfigIncludeCycle(_)), "{err}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_error_key() {
+        let parse_err = |yaml: &str| {
+            let err = parse_config(yaml, &FauxEnv::default(), Path::new("<test>")).unwrap_err();
+            match err {
+                ConfigValueError(key, ..) => key,
+                err => panic!("{err}"),
+            }
+        };
+        assert_eq!(parse_err("keep_alive: abc\n"), "keep_alive");
+        let yaml = indoc! {"
+            postgres:
+              - connection_string: postgres://localhost/a
+              - connection_string: postgres://localhost/b
+                pool_size: many
+        "};
+        assert_eq!(parse_err(yaml), "postgres[1].pool_size");
+        assert_eq!(parse_err("mbtiles: 5\n"), "mbtiles");
+    }
+
+    #[test]
+    fn env_var_substitution() {
+        let env = FauxEnv(
+            vec![
+                ("DB_URL", "postgresql://postgres@localhost/db".into()),
+                ("WORKERS", "8".into()),
+                ("PASSWORD", "1234".into()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let config = parse_config(
+            indoc! {"
+                listen_addresses: '0.0.0.0:${PORT:-3001}'
+                worker_processes: ${WORKERS}
+                keep_alive: ${KEEP_ALIVE:30}
+                postgres:
+                  connection_string: ${DB_URL}
+                admin:
+                  token: ${PASSWORD}
+            "},
+            &env,
+            Path::new("<test>"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.srv.listen_addresses,
+            OptOneMany::One("0.0.0.0:3001".to_string())
+        );
+        assert_eq!(config.srv.worker_processes, Some(8));
+        assert_eq!(config.srv.keep_alive, Some(30));
+        let OptOneMany::One(pg) = &config.postgres else {
+            panic!()
+        };
+        assert_eq!(
+            pg.connection_string.as_deref(),
+            Some("postgresql://postgres@localhost/db")
+        );
+        assert_eq!(config.srv.admin.unwrap().token, "1234");
+
+        let err = parse_config(
+            "postgres:\n  - connection_string: ${MISSING_URL}\n",
+            &env,
+            Path::new("<test>"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unable to substitute the environment variables of `postgres[0].connection_string` in config file <test>: No such variable: $MISSING_URL"
+        );
+    }
+
+    #[test]
+    fn pin_sources() {
+        let mut config = parse_cfg(indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              functions:
+                fnc:
+                  schema: public
+                  function: function_zxy_query
+            pmtiles:
+              paths: /dir-path
+              sources:
+                pm-src: /tmp/file.pmtiles
+            mbtiles: /dir-path
+        "});
+        config.finalize().unwrap();
+        assert_eq!(config.get_source_ids(), vec!["fnc", "pm-src"]);
+
+        config.pin_sources();
+        let OptOneMany::One(pg) = &config.postgres else {
+            panic!()
+        };
+        assert_eq!(pg.auto_publish, OptBoolObj::Bool(false));
+        assert!(config.mbtiles.is_none());
+        assert_eq!(
+            serde_yaml::to_string(&config.pmtiles).unwrap(),
+            "sources:\n  pm-src: /tmp/file.pmtiles\n"
+        );
+    }
+
+    #[test]
+    fn pg_rediscover_task() {
+        let mut config = parse_cfg(indoc! {"
+            postgres:
+              - connection_string: 'postgresql://postgres@localhost/db1'
+                rediscover_interval_secs: 600
+              - connection_string: 'postgresql://postgres@localhost/db2'
+                rediscover_interval_secs: 300
+              - connection_string: 'postgresql://postgres@localhost/db3'
+        "});
+        config.finalize().unwrap();
+        assert_eq!(
+            config.pg_rediscover_task(),
+            Some(ScheduledTask {
+                interval_secs: 300,
+                task: TaskConfig::Rediscover,
+            })
+        );
+
+        let mut config = parse_cfg(indoc! {"
+            postgres:
+              connection_string: 'postgresql://postgres@localhost/db'
+              rediscover_interval_secs: 0
+        "});
+        assert!(config.finalize().is_err());
+        assert_eq!(parse_cfg("").pg_rediscover_task(), None);
+    }
 }
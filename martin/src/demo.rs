@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use actix_web::web::Data;
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use log::info;
+
+use crate::config::{Config, UnrecognizedValues};
+use crate::file_config::{FileConfigEnum, FileConfigSrc};
+use crate::srv::get_public_url;
+use crate::MartinError::DemoWriteError;
+use crate::{MartinResult, OptOneMany};
+
+/// Files bundled into the binary for `martin demo`, written to the demo directory on startup.
+/// The basemap is the Stamen Toner extract up to zoom 3 (CC-BY and `ODbL` licenses), the cities are from Natural Earth.
+const DEMO_FILES: &[(&str, &[u8])] = &[
+    ("basemap.pmtiles", include_bytes!("../demo/basemap.pmtiles")),
+    ("cities.mbtiles", include_bytes!("../demo/cities.mbtiles")),
+    (
+        "sprites/circle.svg",
+        include_bytes!("../demo/sprites/circle.svg"),
+    ),
+    (
+        "sprites/bicycle.svg",
+        include_bytes!("../demo/sprites/bicycle.svg"),
+    ),
+    (
+        "fonts/overpass-mono-regular.ttf",
+        include_bytes!("../demo/fonts/overpass-mono-regular.ttf"),
+    ),
+];
+
+const DEMO_INDEX: &str = include_str!("../demo/index.html");
+const DEMO_STYLE: &str = include_str!("../demo/style.json");
+
+/// Enables the demo map page. Only added to the app by `martin demo`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DemoMode;
+
+/// Write the bundled demo data into `dir`, and create a config that serves it
+pub fn create_demo_config(dir: &Path) -> MartinResult<Config> {
+    for (name, data) in DEMO_FILES {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| DemoWriteError(e, parent.to_path_buf()))?;
+        }
+        fs::write(&path, data).map_err(|e| DemoWriteError(e, path.clone()))?;
+    }
+    info!("Demo data is in {}", dir.display());
+
+    let file_source = |id: &str, name: &str| {
+        let sources = BTreeMap::from([(id.to_string(), FileConfigSrc::Path(dir.join(name)))]);
+        FileConfigEnum::new_extended(Vec::new(), sources, UnrecognizedValues::new())
+    };
+    Ok(Config {
+        pmtiles: file_source("basemap", "basemap.pmtiles"),
+        mbtiles: file_source("cities", "cities.mbtiles"),
+        sprites: file_source("demo", "sprites"),
+        fonts: OptOneMany::One(dir.join("fonts")),
+        ..Config::default()
+    })
+}
+
+/// Default location of the demo data
+#[must_use]
+pub fn default_demo_dir() -> PathBuf {
+    std::env::temp_dir().join("martin-demo")
+}
+
+/// The map page shown at the root path in demo mode
+#[must_use]
+pub fn demo_index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DEMO_INDEX)
+}
+
+/// `MapLibre` style of the demo map, with absolute URLs of this server
+#[route("/_/demo/style.json", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_demo_style(
+    req: HttpRequest,
+    demo: Option<Data<DemoMode>>,
+) -> ActixResult<HttpResponse> {
+    if demo.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let base_url = get_public_url(&req).base_url();
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DEMO_STYLE.replace("{base_url}", &base_url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demo_style_is_valid() {
+        let style: serde_json::Value =
+            serde_json::from_str(&DEMO_STYLE.replace("{base_url}", "http://localhost:3000"))
+                .unwrap();
+        assert_eq!(
+            style["sources"]["basemap"]["url"],
+            "http://localhost:3000/basemap"
+        );
+        assert_eq!(
+            style["glyphs"],
+            "http://localhost:3000/font/{fontstack}/{range}"
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+
+use crate::config::UnrecognizedValues;
+use crate::source::TileInfoSources;
+use crate::MartinError::InvalidSourceConfig;
+use crate::{IdResolver, MartinResult};
+
+/// Creates the tile sources of a custom top-level config section, e.g. to serve
+/// a tile store that Martin does not support out of the box.
+#[async_trait]
+pub trait SourceFactory: Send + Sync {
+    /// Content of the config section
+    type Config: DeserializeOwned + Send;
+
+    /// Top-level key of the config section. It must not be one of the keys used by Martin.
+    fn config_key(&self) -> &str;
+
+    /// Create the sources of the config section. The sources are created again
+    /// whenever the sources are rediscovered. Use the ID resolver to make the source IDs unique.
+    async fn create_sources(
+        &self,
+        config: Self::Config,
+        idr: IdResolver,
+    ) -> MartinResult<TileInfoSources>;
+}
+
+/// Object-safe version of [`SourceFactory`], parsing the config section first
+#[async_trait]
+trait DynSourceFactory: Send + Sync {
+    async fn create_sources(
+        &self,
+        config: &Value,
+        idr: IdResolver,
+    ) -> MartinResult<TileInfoSources>;
+}
+
+#[async_trait]
+impl<T: SourceFactory> DynSourceFactory for T {
+    async fn create_sources(
+        &self,
+        config: &Value,
+        idr: IdResolver,
+    ) -> MartinResult<TileInfoSources> {
+        let key = self.config_key();
+        let config = serde_yaml::from_value(config.clone())
+            .map_err(|e| InvalidSourceConfig(key.to_string(), e))?;
+        SourceFactory::create_sources(self, config, idr).await
+    }
+}
+
+/// Registered source factories, keyed by their config section
+#[derive(Clone, Default)]
+pub struct SourceFactories(BTreeMap<String, Arc<dyn DynSourceFactory>>);
+
+impl SourceFactories {
+    /// Register a factory, replacing any other factory of the same config section
+    pub fn register<T: SourceFactory + 'static>(&mut self, factory: T) {
+        self.0
+            .insert(factory.config_key().to_string(), Arc::new(factory));
+    }
+
+    /// Check if a factory handles the given config section
+    #[must_use]
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Create the sources of each config section that has a registered factory
+    pub(crate) async fn create_sources(
+        &self,
+        sections: &UnrecognizedValues,
+        idr: IdResolver,
+    ) -> MartinResult<TileInfoSources> {
+        let mut sources = TileInfoSources::new();
+        for (key, factory) in &self.0 {
+            if let Some(config) = sections.get(key) {
+                sources.extend(factory.create_sources(config, idr.clone()).await?);
+            }
+        }
+        Ok(sources)
+    }
+}
+
+impl Debug for SourceFactories {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Factories are compared by their config sections, because the factories themselves cannot be compared
+impl PartialEq for SourceFactories {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.keys().eq(other.0.keys())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use martin_tile_utils::{Encoding, Format, TileInfo};
+    use serde::Deserialize;
+    use tilejson::{tilejson, TileJSON};
+
+    use super::*;
+    use crate::config::tests::parse_cfg;
+    use crate::{Source, TileCoord, TileData, UrlQuery};
+
+    #[derive(Debug, Clone)]
+    struct StoreSource {
+        id: String,
+        tj: TileJSON,
+    }
+
+    #[async_trait]
+    impl Source for StoreSource {
+        fn get_id(&self) -> &str {
+            &self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Png, Encoding::Internal)
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "store"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct StoreConfig {
+        layers: Vec<String>,
+    }
+
+    struct StoreFactory;
+
+    #[async_trait]
+    impl SourceFactory for StoreFactory {
+        type Config = StoreConfig;
+
+        fn config_key(&self) -> &str {
+            "tile_store"
+        }
+
+        async fn create_sources(
+            &self,
+            config: StoreConfig,
+            idr: IdResolver,
+        ) -> MartinResult<TileInfoSources> {
+            Ok(config
+                .layers
+                .into_iter()
+                .map(|layer| {
+                    let id = idr.resolve(&layer, format!("store:{layer}"));
+                    let tj = tilejson! { tiles: vec![] };
+                    Box::new(StoreSource { id, tj }) as Box<dyn Source>
+                })
+                .collect())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn custom_sources() {
+        let mut config = parse_cfg(indoc! {"
+            tile_store:
+              layers: [roads, catalog]
+        "});
+        config.factories.register(StoreFactory);
+        assert!(config.finalize().unwrap().is_empty());
+        let tiles = config
+            .resolve_tile_sources(IdResolver::new(&["catalog"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            tiles.source_ids().into_iter().collect::<Vec<_>>(),
+            vec!["catalog.1", "roads"]
+        );
+
+        let mut config = parse_cfg("tile_store: [roads]");
+        config.factories.register(StoreFactory);
+        let err = config.resolve_tile_sources(IdResolver::new(&[])).await;
+        assert!(matches!(err, Err(InvalidSourceConfig(key, _)) if key == "tile_store"));
+    }
+}
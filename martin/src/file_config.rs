@@ -1,18 +1,35 @@
 use std::collections::{BTreeMap, HashSet};
 use std::future::Future;
 use std::mem;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use async_trait::async_trait;
 use futures::TryFutureExt;
-use log::{info, warn};
+use log::{debug, info, warn};
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
 
+use crate::cog::CogError;
 use crate::config::{copy_unrecognized_config, UnrecognizedValues};
 use crate::file_config::FileError::{InvalidFilePath, InvalidSourceFilePath, IoError};
-use crate::source::{Source, TileInfoSources};
-use crate::utils::{IdResolver, OptOneMany};
-use crate::MartinResult;
+use crate::geoparquet::GeoParquetError;
+use crate::gpkg::GpkgError;
+use crate::osm::{OsmError, OsmLayerConfig};
+use crate::shapefile::ShapefileError;
+use crate::source::{Source, TileData, TileInfoSources, UrlQuery};
+use crate::sqlite::{SqliteError, TileTableConfig};
+use crate::utils::antimeridian::Antimeridian;
+use crate::utils::mvt::make_valid;
+use crate::utils::s3::{S3Client, S3Error, S3Location};
+use crate::utils::{
+    decode_brotli, decode_gzip, decode_zstd, encode_brotli, encode_gzip, encode_zstd, IdResolver,
+    OptOneMany,
+};
 use crate::OptOneMany::{Many, One};
+use crate::{MartinResult, TileCoord};
 
 pub type FileResult<T> = Result<T, FileError>;
 
@@ -32,6 +49,30 @@ pub enum FileError {
 
     #[error(r#"Unable to aquire connection to file: {0}"#)]
     AquireConnError(String),
+
+    #[error(transparent)]
+    CogError(#[from] CogError),
+
+    #[error(transparent)]
+    GpkgError(#[from] GpkgError),
+
+    #[error(transparent)]
+    GeoParquetError(#[from] GeoParquetError),
+
+    #[error(transparent)]
+    OsmError(#[from] OsmError),
+
+    #[error(transparent)]
+    ShapefileError(#[from] ShapefileError),
+
+    #[error(transparent)]
+    SqliteError(#[from] SqliteError),
+
+    #[error(transparent)]
+    S3Error(#[from] S3Error),
+
+    #[error("Unable to repair tile {1} of source {0}: {2}")]
+    TileRepairError(String, TileCoord, String),
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -90,6 +131,14 @@ impl FileConfigEnum {
         }
     }
 
+    /// Add a file or a directory to the configured paths
+    pub fn add_path(&mut self, path: PathBuf) {
+        let cfg = self.extract_file_config().unwrap_or_default();
+        let mut paths: Vec<_> = cfg.paths.into_iter().collect();
+        paths.push(path);
+        *self = Self::new_extended(paths, cfg.sources.unwrap_or_default(), cfg.unrecognized);
+    }
+
     pub fn extract_file_config(&mut self) -> Option<FileConfig> {
         match self {
             FileConfigEnum::None => None,
@@ -112,6 +161,95 @@ impl FileConfigEnum {
         }
         Ok(res)
     }
+
+    /// Get the IDs of the sources listed explicitly, without the ones that are found in the directories
+    #[must_use]
+    pub fn get_source_ids(&self) -> Vec<String> {
+        match self {
+            Self::Config(FileConfig {
+                sources: Some(sources),
+                ..
+            }) => sources.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get the IDs of the configured sources that are not listed in the catalog
+    #[must_use]
+    pub fn get_hidden_source_ids(&self) -> Vec<String> {
+        match self {
+            Self::Config(FileConfig {
+                sources: Some(sources),
+                ..
+            }) => sources
+                .iter()
+                .filter(|(_, src)| matches!(src, FileConfigSrc::Obj(v) if v.hidden == Some(true)))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get all configured paths: the files and the directories, and the paths of the sources listed by ID
+    #[must_use]
+    pub fn get_paths(&self) -> Vec<PathBuf> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Path(path) => vec![path.clone()],
+            Self::Paths(paths) => paths.clone(),
+            Self::Config(cfg) => cfg
+                .paths
+                .iter()
+                .chain(
+                    cfg.sources
+                        .iter()
+                        .flat_map(|v| v.values().map(FileConfigSrc::get_path)),
+                )
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Get the local directories that are scanned for new files
+    #[must_use]
+    pub fn get_directories(&self) -> Vec<PathBuf> {
+        let paths = match self {
+            Self::None => Vec::new(),
+            Self::Path(path) => vec![path.clone()],
+            Self::Paths(paths) => paths.clone(),
+            Self::Config(cfg) => cfg.paths.iter().cloned().collect(),
+        };
+        paths
+            .into_iter()
+            .filter(|p| !is_url(p) && p.is_dir())
+            .collect()
+    }
+
+    /// Get the IDs of the resolved sources that are read from any of the given absolute file paths
+    #[must_use]
+    pub fn find_sources(&self, files: &[PathBuf]) -> Vec<String> {
+        let Self::Config(FileConfig {
+            sources: Some(sources),
+            ..
+        }) = self
+        else {
+            return Vec::new();
+        };
+        sources
+            .iter()
+            .filter(|(_, src)| src.abs_path().map_or(false, |p| files.contains(&p)))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Remove all directories and single paths, keeping only the sources listed by ID.
+    /// Once resolved, this prevents new files in the directories from being published.
+    pub fn pin_sources(&mut self) {
+        if let Some(cfg) = self.extract_file_config() {
+            let sources = cfg.sources.unwrap_or_default();
+            *self = Self::new_extended(Vec::new(), sources, cfg.unrecognized);
+        }
+    }
 }
 
 #[serde_with::skip_serializing_none]
@@ -164,16 +302,39 @@ impl FileConfigSrc {
     }
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct FileConfigSource {
     pub path: PathBuf,
+    /// Repair the invalid geometries of the vector tiles before serving them
+    pub make_valid: Option<bool>,
+    /// Handling of the features that cross the antimeridian, only used by the generated vector tiles
+    pub antimeridian: Option<Antimeridian>,
+    /// Serve the source by its URL, but do not list it in the catalog
+    pub hidden: Option<bool>,
+    /// Tile table of the `SQLite` tile archives, only used by the `sqlite` sources
+    #[serde(flatten)]
+    pub tile_table: TileTableConfig,
+    /// Vector tile layers and the elements they include, only used by the `osm` sources
+    /// [default: `roads`, `buildings`, and `pois`]
+    pub layers: Option<BTreeMap<String, OsmLayerConfig>>,
+}
+
+impl FileConfigSource {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            ..Self::default()
+        }
+    }
 }
 
 pub async fn resolve_files<Fut>(
     config: &mut FileConfigEnum,
     idr: IdResolver,
     extension: &str,
-    new_source: &mut impl FnMut(String, PathBuf) -> Fut,
+    new_source: &mut impl FnMut(String, FileConfigSource) -> Fut,
 ) -> MartinResult<TileInfoSources>
 where
     Fut: Future<Output = Result<Box<dyn Source>, FileError>>,
@@ -187,7 +348,7 @@ async fn resolve_int<Fut>(
     config: &mut FileConfigEnum,
     idr: IdResolver,
     extension: &str,
-    new_source: &mut impl FnMut(String, PathBuf) -> Fut,
+    new_source: &mut impl FnMut(String, FileConfigSource) -> Fut,
 ) -> FileResult<TileInfoSources>
 where
     Fut: Future<Output = Result<Box<dyn Source>, FileError>>,
@@ -203,11 +364,16 @@ where
 
     if let Some(sources) = cfg.sources {
         for (id, source) in sources {
-            let can = source.abs_path()?;
-            if !can.is_file() {
-                // todo: maybe warn instead?
-                return Err(InvalidSourceFilePath(id.to_string(), can));
-            }
+            let can = if is_url(source.get_path()) {
+                source.get_path().clone()
+            } else {
+                let can = source.abs_path()?;
+                if !can.is_file() {
+                    // todo: maybe warn instead?
+                    return Err(InvalidSourceFilePath(id.to_string(), can));
+                }
+                can
+            };
 
             let dup = !files.insert(can.clone());
             let dup = if dup { "duplicate " } else { "" };
@@ -215,25 +381,39 @@ where
             info!("Configured {dup}source {id} from {}", can.display());
             configs.insert(id.clone(), source.clone());
 
-            let path = match source {
-                FileConfigSrc::Obj(pmt) => pmt.path,
-                FileConfigSrc::Path(path) => path,
+            let cfg = match source {
+                FileConfigSrc::Obj(cfg) => cfg,
+                FileConfigSrc::Path(path) => FileConfigSource::new(path),
             };
-            results.push(new_source(id, path).await?);
+            let repair = cfg.make_valid.unwrap_or_default();
+            let source = new_source(id, cfg).await?;
+            results.push(if repair {
+                Box::new(ValidSource::new(source))
+            } else {
+                source
+            });
         }
     }
 
     for path in cfg.paths {
-        let is_dir = path.is_dir();
-        let dir_files = if is_dir {
+        let remote_dir = path
+            .to_str()
+            .and_then(S3Location::parse)
+            .filter(S3Location::is_dir);
+        let is_dir = remote_dir.is_some() || path.is_dir();
+        let dir_files = if let Some(location) = remote_dir {
+            // remote directories are listed again on each rediscovery, just like the local ones
+            directories.push(path.clone());
+            list_remote_dir(&location, extension).await?
+        } else if is_url(&path) {
+            vec![path]
+        } else if is_dir {
             // directories will be kept in the config just in case there are new files
             directories.push(path.clone());
             path.read_dir()
                 .map_err(|e| IoError(e, path.clone()))?
                 .filter_map(Result::ok)
-                .filter(|f| {
-                    f.path().extension().filter(|e| *e == extension).is_some() && f.path().is_file()
-                })
+                .filter(|f| has_extension(&f.path(), extension) && f.path().is_file())
                 .map(|f| f.path())
                 .collect()
         } else if path.is_file() {
@@ -242,28 +422,25 @@ where
             return Err(InvalidFilePath(path.canonicalize().unwrap_or(path)));
         };
         for path in dir_files {
-            let can = path.canonicalize().map_err(|e| IoError(e, path.clone()))?;
+            let can = if is_url(&path) {
+                path.clone()
+            } else {
+                path.canonicalize().map_err(|e| IoError(e, path.clone()))?
+            };
             if files.contains(&can) {
                 if !is_dir {
                     warn!("Ignoring duplicate MBTiles path: {}", can.display());
                 }
                 continue;
             }
-            let id = path.file_stem().map_or_else(
-                || "_unknown".to_string(),
-                |s| s.to_string_lossy().to_string(),
-            );
+            let id = source_id(&path, extension);
             let source = FileConfigSrc::Path(path);
             let id = idr.resolve(&id, can.to_string_lossy().to_string());
             info!("Configured source {id} from {}", can.display());
             files.insert(can);
             configs.insert(id.clone(), source.clone());
 
-            let path = match source {
-                FileConfigSrc::Obj(pmt) => pmt.path,
-                FileConfigSrc::Path(path) => path,
-            };
-            results.push(new_source(id, path).await?);
+            results.push(new_source(id, FileConfigSource::new(source.into_path())).await?);
         }
     }
 
@@ -272,6 +449,151 @@ where
     Ok(results)
 }
 
+/// A vector tile source wrapper that repairs the tile geometries before they are served,
+/// because the invalid polygons may render with artifacts or crash the strict clients.
+/// Other tile formats are returned as is.
+#[derive(Clone, Debug)]
+struct ValidSource {
+    source: Box<dyn Source>,
+    /// Total number of repaired features, to report the first repair of the source only once
+    repaired: Arc<AtomicUsize>,
+}
+
+impl ValidSource {
+    fn new(source: Box<dyn Source>) -> Self {
+        Self {
+            source,
+            repaired: Arc::default(),
+        }
+    }
+
+    fn repair(&self, data: TileData, info: TileInfo, xyz: &TileCoord) -> FileResult<TileData> {
+        let id = self.get_id();
+        let err = |e: &dyn std::fmt::Display| {
+            FileError::TileRepairError(id.to_string(), *xyz, e.to_string())
+        };
+        let decoded = match info.encoding {
+            Encoding::Uncompressed => None,
+            Encoding::Gzip => Some(decode_gzip(&data).map_err(|e| err(&e))?),
+            Encoding::Brotli => Some(decode_brotli(&data).map_err(|e| err(&e))?),
+            Encoding::Zstd => Some(decode_zstd(&data).map_err(|e| err(&e))?),
+            _ => return Ok(data),
+        };
+        let tile = decoded.as_deref().unwrap_or(&data);
+        let Some((tile, count)) = make_valid(tile).map_err(|e| err(&e))? else {
+            return Ok(data);
+        };
+        let total = self.repaired.fetch_add(count, Ordering::Relaxed) + count;
+        if total == count {
+            warn!("Repaired {count} invalid features in tile {xyz} of source {id}, further repairs are logged at debug level");
+        } else {
+            debug!(
+                "Repaired {count} invalid features in tile {xyz} of source {id}, {total} in total"
+            );
+        }
+        Ok(match info.encoding {
+            Encoding::Gzip => encode_gzip(&tile).map_err(|e| err(&e))?,
+            Encoding::Brotli => encode_brotli(&tile).map_err(|e| err(&e))?,
+            Encoding::Zstd => encode_zstd(&tile).map_err(|e| err(&e))?,
+            _ => tile,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for ValidSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData> {
+        let data = self.source.get_tile(xyz, query).await?;
+        let info = self.get_tile_info();
+        if data.is_empty() || info.format != Format::Mvt {
+            return Ok(data);
+        }
+        Ok(self.repair(data, info, xyz)?)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+}
+
+/// Get the URLs of all objects with the given extension in an S3 "directory"
+async fn list_remote_dir(location: &S3Location, extension: &str) -> FileResult<Vec<PathBuf>> {
+    let client = S3Client::from_env(reqwest::Client::new());
+    let suffix = format!(".{extension}");
+    Ok(client
+        .list(location)
+        .await?
+        .into_iter()
+        .filter(|key| key.ends_with(&suffix))
+        .map(|key| PathBuf::from(format!("s3://{}/{key}", location.bucket)))
+        .collect())
+}
+
+/// Check if the file name ends with the extension, which may have several parts like `osm.pbf`
+#[must_use]
+pub fn has_extension(path: &Path, extension: &str) -> bool {
+    path.file_name()
+        .and_then(|v| v.to_str())
+        .and_then(|v| v.strip_suffix(extension))
+        .and_then(|v| v.strip_suffix('.'))
+        .map_or(false, |stem| !stem.is_empty())
+}
+
+/// The file name without the extension, used as the ID of the discovered sources
+fn source_id(path: &Path, extension: &str) -> String {
+    let name = path.file_name().map(|v| v.to_string_lossy().to_string());
+    match name {
+        Some(name) if has_extension(path, extension) => {
+            name[..name.len() - extension.len() - 1].to_string()
+        }
+        _ => path.file_stem().map_or_else(
+            || "_unknown".to_string(),
+            |s| s.to_string_lossy().to_string(),
+        ),
+    }
+}
+
+/// Remote files are given as `http://`, `https://`, or `s3://` URLs instead of paths.
+/// Only some source types support them.
+#[must_use]
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().map_or(false, |p| {
+        p.starts_with("http://") || p.starts_with("https://") || p.starts_with("s3://")
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
@@ -279,7 +601,11 @@ mod tests {
 
     use indoc::indoc;
 
-    use crate::file_config::{FileConfigEnum, FileConfigSource, FileConfigSrc};
+    use crate::config::UnrecognizedValues;
+    use crate::file_config::{
+        has_extension, source_id, FileConfigEnum, FileConfigSource, FileConfigSrc,
+    };
+    use crate::sqlite::TileTableConfig;
 
     #[test]
     fn parse() {
@@ -291,6 +617,9 @@ mod tests {
                 pm-src1: /tmp/file.ext
                 pm-src2:
                   path: /tmp/file.ext
+                pm-src3:
+                  path: /tmp/file.ext
+                  make_valid: true
         "})
         .unwrap();
         let res = cfg.finalize("").unwrap();
@@ -317,9 +646,68 @@ mod tests {
                     "pm-src2".to_string(),
                     FileConfigSrc::Obj(FileConfigSource {
                         path: PathBuf::from("/tmp/file.ext"),
+                        make_valid: None,
+                        antimeridian: None,
+                        hidden: None,
+                        tile_table: TileTableConfig::default(),
+                        layers: None,
+                    })
+                ),
+                (
+                    "pm-src3".to_string(),
+                    FileConfigSrc::Obj(FileConfigSource {
+                        path: PathBuf::from("/tmp/file.ext"),
+                        make_valid: Some(true),
+                        antimeridian: None,
+                        hidden: None,
+                        tile_table: TileTableConfig::default(),
+                        layers: None,
                     })
                 )
             ]))
         );
     }
+
+    #[test]
+    fn pin_sources() {
+        let mut cfg = serde_yaml::from_str::<FileConfigEnum>(indoc! {"
+            paths: /dir-path
+            sources:
+                pm-src1: /tmp/file.ext
+        "})
+        .unwrap();
+        assert_eq!(cfg.get_source_ids(), vec!["pm-src1".to_string()]);
+        cfg.pin_sources();
+        assert_eq!(
+            cfg,
+            FileConfigEnum::new_extended(
+                vec![],
+                BTreeMap::from([(
+                    "pm-src1".to_string(),
+                    FileConfigSrc::Path(PathBuf::from("/tmp/file.ext"))
+                )]),
+                UnrecognizedValues::new()
+            )
+        );
+
+        let mut cfg = FileConfigEnum::new(vec![PathBuf::from("/dir-path")]);
+        assert!(cfg.get_source_ids().is_empty());
+        cfg.pin_sources();
+        assert!(cfg.is_none());
+    }
+
+    #[test]
+    fn extensions() {
+        let path = PathBuf::from("/data/monaco.osm.pbf");
+        assert!(has_extension(&path, "osm.pbf"));
+        assert!(has_extension(&path, "pbf"));
+        assert!(!has_extension(&path, "mbtiles"));
+        assert!(!has_extension(&PathBuf::from("/data/.pbf"), "pbf"));
+        assert_eq!(source_id(&path, "osm.pbf"), "monaco");
+        assert_eq!(source_id(&path, "pbf"), "monaco.osm");
+        assert_eq!(
+            source_id(&PathBuf::from("/data/world.mbtiles"), "mbtiles"),
+            "world"
+        );
+    }
 }
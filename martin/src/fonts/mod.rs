@@ -1,19 +1,23 @@
 use std::collections::hash_map::Entry;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::OsStr;
-use std::fmt::Debug;
+use std::fmt::{Debug, Formatter};
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::OnceLock;
 
 use bit_set::BitSet;
 use itertools::Itertools;
 use log::{debug, info, warn};
+use moka::sync::Cache;
 use pbf_font_tools::freetype::{Face, Library};
 use pbf_font_tools::protobuf::Message;
 use pbf_font_tools::{render_sdf_glyph, Fontstack, Glyphs, PbfFontError};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::remote::{download, download_dir, is_remote, remote_id, RemoteError};
+use crate::utils::saturation::{GLYPH_CACHE_HITS, GLYPH_CACHE_MISSES};
 use crate::OptOneMany;
 
 const MAX_UNICODE_CP: usize = 0xFFFF;
@@ -25,9 +29,15 @@ const BUFFER_SIZE: usize = 3;
 const RADIUS: usize = 8;
 const CUTOFF: f64 = 0.25_f64;
 
+/// Maximum size of the rendered glyph ranges kept in memory
+const GLYPH_CACHE_SIZE_MB: u64 = 64;
+
 /// Each range is 256 codepoints long, so the highest range ID is 0xFFFF / 256 = 255.
 const MAX_UNICODE_CP_RANGE_ID: usize = MAX_UNICODE_CP / CP_RANGE_SIZE;
 
+/// Extensions of the font files, also the only files kept when downloading the remote font sources
+const FONT_EXTENSIONS: &[&str] = &["otf", "ttf", "ttc"];
+
 pub type FontResult<T> = Result<T, FontError>;
 
 #[derive(thiserror::Error, Debug)]
@@ -61,12 +71,18 @@ pub enum FontError {
     #[error("No font files found in {}", .0.display())]
     NoFontFilesFound(PathBuf),
 
+    #[error("Fallback font {1} of font {0} not found")]
+    FallbackNotFound(String, String),
+
     #[error("Font {0} is missing a family name")]
     MissingFamilyName(PathBuf),
 
     #[error(transparent)]
     PbfFontError(#[from] PbfFontError),
 
+    #[error(transparent)]
+    RemoteError(#[from] RemoteError),
+
     #[error(transparent)]
     ErrorSerializingProtobuf(#[from] pbf_font_tools::protobuf::Error),
 }
@@ -105,8 +121,39 @@ fn get_available_codepoints(face: &mut Face) -> Option<GetGlyphInfo> {
 pub struct FontSources {
     fonts: HashMap<String, FontSource>,
     masks: Vec<BitSet>,
+    fallbacks: FontFallbacks,
+    /// Local font files and directories, including the downloaded remote fonts
+    paths: Vec<PathBuf>,
+    cache: Option<GlyphCache>,
+}
+
+/// Rendered glyph ranges, keyed by the requested font stack and the start of the range
+#[derive(Clone)]
+struct GlyphCache(Cache<(String, u32), Vec<u8>>);
+
+impl GlyphCache {
+    fn new(size_mb: u64) -> Self {
+        Self(
+            Cache::builder()
+                .weigher(|key: &(String, u32), value: &Vec<u8>| -> u32 {
+                    (key.0.len() + value.len()).try_into().unwrap_or(u32::MAX)
+                })
+                .max_capacity(size_mb * 1024 * 1024)
+                .build(),
+        )
+    }
+}
+
+impl Debug for GlyphCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GlyphCache({} entries)", self.0.entry_count())
+    }
 }
 
+/// Fonts to use, in order, for the glyphs that a requested font does not have, keyed by font name.
+/// The requested font does not need to exist.
+pub type FontFallbacks = BTreeMap<String, Vec<String>>;
+
 pub type FontCatalog = BTreeMap<String, CatalogFontEntry>;
 
 #[serde_with::skip_serializing_none]
@@ -119,16 +166,68 @@ pub struct CatalogFontEntry {
     pub end: usize,
 }
 
+/// Glyph coverage of every font, keyed by the font name
+pub type FontCoverage = BTreeMap<String, FontCoverageEntry>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FontCoverageEntry {
+    #[serde(flatten)]
+    pub font: CatalogFontEntry,
+    /// Glyph ranges that contain at least one glyph of the font
+    pub ranges: Vec<FontRangeCoverage>,
+}
+
+/// Number of glyphs in one of the `start-end` ranges requested from `/font/{name}/{start}-{end}`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FontRangeCoverage {
+    pub start: usize,
+    pub end: usize,
+    pub glyphs: usize,
+}
+
 impl FontSources {
-    pub fn resolve(config: &mut OptOneMany<PathBuf>) -> FontResult<Self> {
+    /// Resolve the configured font files and directories. Remote fonts, given as `http(s)://` URLs
+    /// of font files, archives, or directory listings, are downloaded once at startup.
+    pub async fn resolve(
+        config: &mut OptOneMany<PathBuf>,
+        fallbacks: Option<&FontFallbacks>,
+    ) -> FontResult<Self> {
         if config.is_empty() {
             return Ok(Self::default());
         }
 
+        let mut paths = Vec::new();
+        for (idx, path) in config.iter().enumerate() {
+            if is_remote(path) {
+                let url = path.to_string_lossy().to_string();
+                let name = remote_id(&url).unwrap_or_default();
+                let dir = download_dir("fonts", &format!("{idx}-{name}"));
+                paths.push(download(&url, &dir, FONT_EXTENSIONS).await?);
+            } else {
+                paths.push(path.clone());
+            }
+        }
+
+        Self::load(paths, fallbacks.cloned().unwrap_or_default())
+    }
+
+    /// Scan the font files and directories again, e.g. after fonts were added to or removed
+    /// from a font directory. The remote fonts are not downloaded again.
+    pub fn reload(&self) -> FontResult<Self> {
+        Self::load(self.paths.clone(), self.fallbacks.clone())
+    }
+
+    /// Local font directories, including the ones of the downloaded remote fonts
+    #[must_use]
+    pub fn get_directories(&self) -> Vec<PathBuf> {
+        self.paths.iter().filter(|v| v.is_dir()).cloned().collect()
+    }
+
+    fn load(paths: Vec<PathBuf>, fallbacks: FontFallbacks) -> FontResult<Self> {
         let mut fonts = HashMap::new();
         let lib = Library::init()?;
 
-        for path in config.iter() {
+        for path in &paths {
             recurse_dirs(&lib, path.clone(), &mut fonts, true)?;
         }
 
@@ -143,7 +242,20 @@ impl FontSources {
             }
         }
 
-        Ok(Self { fonts, masks })
+        for (id, ids) in &fallbacks {
+            if let Some(missing) = ids.iter().find(|v| !fonts.contains_key(*v)) {
+                return Err(FontError::FallbackNotFound(id.clone(), missing.clone()));
+            }
+            info!("Configured font fallbacks {} for {id}", ids.join(", "));
+        }
+
+        Ok(Self {
+            fonts,
+            masks,
+            fallbacks,
+            paths,
+            cache: Some(GlyphCache::new(GLYPH_CACHE_SIZE_MB)),
+        })
     }
 
     #[must_use]
@@ -155,7 +267,33 @@ impl FontSources {
             .collect()
     }
 
+    /// Names of all fonts that can be requested, including the ones only served by their fallbacks
+    #[must_use]
+    pub fn get_font_names(&self) -> BTreeSet<String> {
+        self.fonts
+            .keys()
+            .chain(self.fallbacks.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// List the glyph ranges covered by each font, with the number of glyphs in each range
+    #[must_use]
+    pub fn get_coverage(&self) -> FontCoverage {
+        self.fonts
+            .iter()
+            .map(|(k, v)| {
+                let entry = FontCoverageEntry {
+                    font: v.catalog_entry.clone(),
+                    ranges: get_range_coverage(&v.codepoints),
+                };
+                (k.clone(), entry)
+            })
+            .collect()
+    }
+
     /// Given a list of IDs in a format "id1,id2,id3", return a combined font.
+    /// Rendered ranges are cached, so that the expensive ones, e.g. CJK ranges, are only rendered once.
     #[allow(clippy::cast_possible_truncation)]
     pub fn get_font_range(&self, ids: &str, start: u32, end: u32) -> FontResult<Vec<u8>> {
         if start > end {
@@ -171,9 +309,26 @@ impl FontSources {
             return Err(FontError::InvalidFontRange(start, end));
         }
 
+        let Some(cache) = &self.cache else {
+            return self.render_font_range(ids, start, end);
+        };
+        let key = (ids.to_string(), start);
+        if let Some(data) = cache.0.get(&key) {
+            GLYPH_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+        GLYPH_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+        let data = self.render_font_range(ids, start, end)?;
+        cache.0.insert(key, data.clone());
+        Ok(data)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn render_font_range(&self, ids: &str, start: u32, end: u32) -> FontResult<Vec<u8>> {
         let mut needed = self.masks[(start as usize) / CP_RANGE_SIZE].clone();
-        let fonts = ids
-            .split(',')
+        let fonts = self
+            .with_fallbacks(ids)?
+            .into_iter()
             .filter_map(|id| match self.fonts.get(id) {
                 None => Some(Err(FontError::FontNotFound(id.to_string()))),
                 Some(v) => {
@@ -229,6 +384,44 @@ impl FontSources {
         glyphs.write_to_vec(&mut result)?;
         Ok(result)
     }
+
+    /// List the requested fonts that exist, followed by the fallbacks of all requested fonts.
+    /// A requested font must either exist or have fallbacks.
+    fn with_fallbacks<'a>(&'a self, ids: &'a str) -> FontResult<Vec<&'a str>> {
+        let requested: Vec<&str> = ids.split(',').collect();
+        let mut result = Vec::with_capacity(requested.len());
+        for id in &requested {
+            if self.fonts.contains_key(*id) {
+                result.push(*id);
+            } else if !self.fallbacks.contains_key(*id) {
+                return Err(FontError::FontNotFound((*id).to_string()));
+            }
+        }
+        for fallback in requested.iter().filter_map(|id| self.fallbacks.get(*id)) {
+            for id in fallback {
+                if !result.contains(&id.as_str()) {
+                    result.push(id);
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn get_range_coverage(codepoints: &BitSet) -> Vec<FontRangeCoverage> {
+    let mut ranges: Vec<FontRangeCoverage> = Vec::new();
+    for cp in codepoints {
+        let start = cp - cp % CP_RANGE_SIZE;
+        match ranges.last_mut() {
+            Some(range) if range.start == start => range.glyphs += 1,
+            _ => ranges.push(FontRangeCoverage {
+                start,
+                end: start + CP_RANGE_SIZE - 1,
+                glyphs: 1,
+            }),
+        }
+    }
+    ranges
 }
 
 #[derive(Clone, Debug)]
@@ -261,7 +454,7 @@ fn recurse_dirs(
         if path
             .extension()
             .and_then(OsStr::to_str)
-            .is_some_and(|e| ["otf", "ttf", "ttc"].contains(&e))
+            .map_or(false, |e| FONT_EXTENSIONS.contains(&e))
         {
             parse_font(lib, fonts, path.clone())?;
         }
@@ -273,12 +466,18 @@ fn recurse_dirs(
     Ok(())
 }
 
+/// Number of the named instances of a variable font, or 0 if the face is not a variable font
+fn get_named_instances(face: &Face) -> isize {
+    isize::try_from((face.raw().style_flags >> 16) & 0x7FFF).unwrap_or_default()
+}
+
 fn parse_font(
     lib: &Library,
     fonts: &mut HashMap<String, FontSource>,
     path: PathBuf,
 ) -> FontResult<()> {
     static RE_SPACES: OnceLock<Regex> = OnceLock::new();
+    let re_spaces = RE_SPACES.get_or_init(|| Regex::new(r"(\s|/|,)+").unwrap());
 
     let mut face = lib.new_face(&path, 0)?;
     let num_faces = face.num_faces() as isize;
@@ -286,74 +485,256 @@ fn parse_font(
         if face_index > 0 {
             face = lib.new_face(&path, face_index)?;
         }
-        let Some(family) = face.family_name() else {
-            return Err(FontError::MissingFamilyName(path));
-        };
-        let mut name = family.clone();
-        let style = face.style_name();
-        if let Some(style) = &style {
-            name.push(' ');
-            name.push_str(style);
-        }
-        // Make sure font name has no slashes or commas, replacing them with spaces and de-duplicating spaces
-        name = RE_SPACES
-            .get_or_init(|| Regex::new(r"(\s|/|,)+").unwrap())
-            .replace_all(name.as_str(), " ")
-            .to_string();
-
-        match fonts.entry(name) {
-            Entry::Occupied(v) => {
-                warn!(
-                    "Ignoring duplicate font {} from {} because it was already configured from {}",
-                    v.key(),
-                    path.display(),
-                    v.get().path.display()
-                );
+        // Each named instance of a variable font, e.g. each of its weights, is a separate font.
+        // The default instance is skipped, as FreeType adds it to the named instances
+        // unless one of them already has the default coordinates.
+        let instances = get_named_instances(&face);
+        let instances = if instances == 0 { 0..=0 } else { 1..=instances };
+        for instance in instances {
+            // FreeType selects the named instance with the upper 16 bits of the face index
+            let index = face_index | (instance << 16);
+            if instance > 0 {
+                face = lib.new_face(&path, index)?;
+            }
+            let Some(family) = face.family_name() else {
+                return Err(FontError::MissingFamilyName(path));
+            };
+            let mut name = family.clone();
+            let style = face.style_name();
+            if let Some(style) = &style {
+                name.push(' ');
+                name.push_str(style);
             }
-            Entry::Vacant(v) => {
-                let key = v.key();
-                let Some((codepoints, glyphs, ranges, start, end)) =
-                    get_available_codepoints(&mut face)
-                else {
+            // Make sure font name has no slashes or commas, replacing them with spaces and de-duplicating spaces
+            name = re_spaces.replace_all(name.as_str(), " ").to_string();
+
+            match fonts.entry(name) {
+                Entry::Occupied(v) => {
                     warn!(
-                        "Ignoring font {key} from {} because it has no available glyphs",
+                        "Ignoring duplicate font {} from {} because it was already configured from {}",
+                        v.key(),
+                        path.display(),
+                        v.get().path.display()
+                    );
+                }
+                Entry::Vacant(v) => {
+                    let key = v.key();
+                    let Some((codepoints, glyphs, ranges, start, end)) =
+                        get_available_codepoints(&mut face)
+                    else {
+                        warn!(
+                            "Ignoring font {key} from {} because it has no available glyphs",
+                            path.display()
+                        );
+                        continue;
+                    };
+
+                    info!(
+                        "Configured font {key} with {glyphs} glyphs ({start:04X}-{end:04X}) from {}",
                         path.display()
                     );
-                    continue;
-                };
+                    debug!(
+                        "Available font ranges: {}",
+                        ranges
+                            .iter()
+                            .map(|(s, e)| if s == e {
+                                format!("{s:02X}")
+                            } else {
+                                format!("{s:02X}-{e:02X}")
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    );
 
-                info!(
-                    "Configured font {key} with {glyphs} glyphs ({start:04X}-{end:04X}) from {}",
-                    path.display()
-                );
-                debug!(
-                    "Available font ranges: {}",
-                    ranges
-                        .iter()
-                        .map(|(s, e)| if s == e {
-                            format!("{s:02X}")
-                        } else {
-                            format!("{s:02X}-{e:02X}")
-                        })
-                        .collect::<Vec<_>>()
-                        .join(", "),
-                );
-
-                v.insert(FontSource {
-                    path: path.clone(),
-                    face_index,
-                    codepoints,
-                    catalog_entry: CatalogFontEntry {
-                        family,
-                        style,
-                        glyphs,
-                        start,
-                        end,
-                    },
-                });
+                    v.insert(FontSource {
+                        path: path.clone(),
+                        face_index: index,
+                        codepoints,
+                        catalog_entry: CatalogFontEntry {
+                            family,
+                            style,
+                            glyphs,
+                            start,
+                            end,
+                        },
+                    });
+                }
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_coverage() {
+        let codepoints = BitSet::from_iter([0x20, 0x41, 0xFF, 0x100, 0x4E00, 0x4E01]);
+        let range = |start, end, glyphs| FontRangeCoverage { start, end, glyphs };
+        assert_eq!(
+            get_range_coverage(&codepoints),
+            vec![
+                range(0, 255, 3),
+                range(256, 511, 1),
+                range(0x4E00, 0x4EFF, 2),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn font_coverage() {
+        let mut config = OptOneMany::One(PathBuf::from(
+            "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+        ));
+        let coverage = FontSources::resolve(&mut config, None)
+            .await
+            .unwrap()
+            .get_coverage();
+        let font = &coverage["Overpass Mono Regular"];
+        assert_eq!(
+            font.ranges.iter().map(|v| v.glyphs).sum::<usize>(),
+            font.font.glyphs
+        );
+        assert_eq!(font.ranges[0].start, 0);
+    }
+
+    #[actix_rt::test]
+    async fn cached_font_range() {
+        let mut config = OptOneMany::One(PathBuf::from(
+            "../tests/fixtures/fonts/overpass-mono-regular.ttf",
+        ));
+        let fonts = FontSources::resolve(&mut config, None).await.unwrap();
+        let data = fonts
+            .get_font_range("Overpass Mono Regular", 0, 255)
+            .unwrap();
+        let hits = GLYPH_CACHE_HITS.load(Ordering::Relaxed);
+        let cached = fonts
+            .get_font_range("Overpass Mono Regular", 0, 255)
+            .unwrap();
+        assert_eq!(data, cached);
+        assert!(GLYPH_CACHE_HITS.load(Ordering::Relaxed) > hits);
+        assert_eq!(
+            fonts
+                .cache
+                .as_ref()
+                .map(|v| v.0.contains_key(&("Overpass Mono Regular".to_string(), 0))),
+            Some(true)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn font_fallbacks() {
+        let mut config = OptOneMany::One(PathBuf::from("../tests/fixtures/fonts"));
+        let fallbacks = FontFallbacks::from([
+            (
+                "Overpass Mono Light".to_string(),
+                vec!["Overpass Mono Regular".to_string()],
+            ),
+            (
+                "Open Sans Regular".to_string(),
+                vec![
+                    "Overpass Mono Regular".to_string(),
+                    "Overpass Mono Light".to_string(),
+                ],
+            ),
+        ]);
+        let fonts = FontSources::resolve(&mut config, Some(&fallbacks))
+            .await
+            .unwrap();
+        let ids = |v| fonts.with_fallbacks(v).unwrap();
+        assert_eq!(
+            ids("Overpass Mono Light"),
+            ["Overpass Mono Light", "Overpass Mono Regular"]
+        );
+        assert_eq!(
+            ids("Overpass Mono Regular,Overpass Mono Light"),
+            ["Overpass Mono Regular", "Overpass Mono Light"]
+        );
+        assert_eq!(
+            ids("Open Sans Regular"),
+            ["Overpass Mono Regular", "Overpass Mono Light"]
+        );
+        assert!(matches!(
+            fonts.with_fallbacks("Open Sans Bold"),
+            Err(FontError::FontNotFound(_))
+        ));
+        assert!(!fonts
+            .get_font_range("Open Sans Regular", 0, 255)
+            .unwrap()
+            .is_empty());
+
+        let fallbacks = FontFallbacks::from([(
+            "Open Sans Regular".to_string(),
+            vec!["Open Sans Bold".to_string()],
+        )]);
+        assert!(matches!(
+            FontSources::resolve(&mut config, Some(&fallbacks)).await,
+            Err(FontError::FallbackNotFound(..))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn reload_fonts() {
+        let dir = std::env::temp_dir().join(format!("martin-fonts-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixtures = PathBuf::from("../tests/fixtures/fonts");
+        std::fs::copy(
+            fixtures.join("overpass-mono-regular.ttf"),
+            dir.join("regular.ttf"),
+        )
+        .unwrap();
+
+        let mut config = OptOneMany::One(dir.clone());
+        let fonts = FontSources::resolve(&mut config, None).await.unwrap();
+        assert_eq!(fonts.get_directories(), [dir.clone()]);
+        assert_eq!(fonts.fonts.len(), 1);
+
+        std::fs::copy(
+            fixtures.join("sub_dir/overpass-mono-light.otf"),
+            dir.join("light.otf"),
+        )
+        .unwrap();
+        let reloaded = fonts.reload().unwrap();
+        assert!(reloaded.fonts.contains_key("Overpass Mono Light"));
+        assert!(reloaded.fonts.contains_key("Overpass Mono Regular"));
+
+        // a directory without any fonts left cannot be reloaded, the previous fonts are kept
+        std::fs::remove_file(dir.join("regular.ttf")).unwrap();
+        std::fs::remove_file(dir.join("light.otf")).unwrap();
+        assert!(matches!(
+            fonts.reload(),
+            Err(FontError::NoFontFilesFound(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn variable_font_instances() {
+        // a variable font with the named instances Light and Bold. FreeType adds the default
+        // instance as Regular, because none of the named instances matches it.
+        let mut config = OptOneMany::One(PathBuf::from(
+            "../tests/fixtures/variable_fonts/overpass-mono-variable.ttf",
+        ));
+        let fonts = FontSources::resolve(&mut config, None).await.unwrap();
+        let mut names: Vec<_> = fonts.fonts.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        let expected = [
+            "Overpass Mono Bold",
+            "Overpass Mono Light",
+            "Overpass Mono Regular",
+        ];
+        assert_eq!(names, expected);
+        let mut instances = Vec::new();
+        for name in expected {
+            let font = &fonts.fonts[name];
+            instances.push(font.face_index >> 16);
+            assert_eq!(font.catalog_entry.family, "Overpass Mono");
+            assert!(!fonts.get_font_range(name, 0, 255).unwrap().is_empty());
+        }
+        instances.sort_unstable();
+        assert_eq!(instances, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{info, trace};
+use martin_tile_utils::{Format, TileInfo};
+use parquet::basic::{ConvertedType, LogicalType, Type as PhysicalType};
+use parquet::errors::ParquetError;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::statistics::Statistics;
+use parquet::record::{Field, Row};
+use parquet::schema::types::Type;
+use serde::Deserialize;
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
+
+use crate::file_config::{FileError, FileResult};
+use crate::geoparquet::GeoParquetError::{
+    InvalidMetadata, NotGeoParquet, ReadError, UnsupportedCrs, UnsupportedEncoding,
+};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::utils::mvt::{
+    encode_tile, intersects, LayerBuilder, PropValue, Srs, TileTransform, DEFAULT_MVT_EXTENT,
+};
+use crate::utils::saturation::spawn_blocking;
+use crate::utils::wkb::{parse_wkb, Geometry};
+use crate::{MartinError, MartinResult, TileCoord};
+
+pub type GeoParquetResult<T> = Result<T, GeoParquetError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum GeoParquetError {
+    #[error("Unable to read Parquet file {}: {0}", .1.display())]
+    ReadError(ParquetError, PathBuf),
+
+    #[error("File {} has no GeoParquet metadata", .0.display())]
+    NotGeoParquet(PathBuf),
+
+    #[error("Unable to parse GeoParquet metadata of {}: {0}", .1.display())]
+    InvalidMetadata(serde_json::Error, PathBuf),
+
+    #[error("Geometry encoding {0} of {} is not supported, only WKB can be used", .1.display())]
+    UnsupportedEncoding(String, PathBuf),
+
+    #[error("CRS of {} is not supported, only EPSG:4326 and EPSG:3857 can be used", .0.display())]
+    UnsupportedCrs(PathBuf),
+
+    #[error("Unable to read tile {1} of source {2}: {0}")]
+    TileError(ParquetError, TileCoord, String),
+}
+
+/// The `geo` key of the Parquet file metadata, see <https://geoparquet.org/releases/v1.1.0/>
+#[derive(Deserialize, Debug)]
+struct GeoMetadata {
+    primary_column: String,
+    columns: BTreeMap<String, GeoColumn>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeoColumn {
+    encoding: String,
+    /// PROJJSON of the coordinate reference system, `OGC:CRS84` if missing
+    #[serde(default)]
+    crs: Option<serde_json::Value>,
+    #[serde(default)]
+    covering: Option<Covering>,
+}
+
+/// Columns with the bounding box of each geometry, used to skip the row groups
+/// that do not intersect a tile without reading them
+#[derive(Deserialize, Debug)]
+struct Covering {
+    bbox: BboxCovering,
+}
+
+#[derive(Deserialize, Debug)]
+struct BboxCovering {
+    xmin: Vec<String>,
+    ymin: Vec<String>,
+    xmax: Vec<String>,
+    ymax: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ParquetContent {
+    geometry_column: String,
+    srs: Srs,
+    /// Columns encoded as feature properties
+    columns: Vec<String>,
+    /// Bounding box of each row group, if it has any geometries
+    row_groups: Vec<Option<[f64; 4]>>,
+}
+
+/// Serves a `GeoParquet` file as vector tiles generated on the fly. The row groups are filtered
+/// by their bounding box, so files sorted spatially and split into small row groups are faster.
+/// Only WKB geometries in EPSG:4326 or EPSG:3857 are supported.
+#[derive(Clone)]
+pub struct GeoParquetSource {
+    id: String,
+    path: PathBuf,
+    reader: Arc<SerializedFileReader<File>>,
+    content: Arc<ParquetContent>,
+    tilejson: TileJSON,
+}
+
+impl Debug for GeoParquetSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GeoParquetSource {{ id: {}, path: {:?} }}",
+            self.id, self.path
+        )
+    }
+}
+
+impl GeoParquetSource {
+    pub async fn new_box(id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
+        let source = spawn_blocking(move || Self::new(id, path))
+            .await
+            .map_err(|e| FileError::AquireConnError(e.to_string()))??;
+        Ok(Box::new(source))
+    }
+
+    fn new(id: String, path: PathBuf) -> GeoParquetResult<Self> {
+        let err = |e| ReadError(e, path.clone());
+        let file = File::open(&path).map_err(|e| err(e.into()))?;
+        let reader = SerializedFileReader::new(file).map_err(err)?;
+        let metadata = reader.metadata();
+
+        let geo = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|kv| kv.iter().find(|v| v.key == "geo"))
+            .and_then(|v| v.value.as_ref())
+            .ok_or_else(|| NotGeoParquet(path.clone()))?;
+        let geo: GeoMetadata =
+            serde_json::from_str(geo).map_err(|e| InvalidMetadata(e, path.clone()))?;
+        let Some(column) = geo.columns.get(&geo.primary_column) else {
+            return Err(NotGeoParquet(path));
+        };
+        if !column.encoding.eq_ignore_ascii_case("wkb") {
+            return Err(UnsupportedEncoding(column.encoding.clone(), path));
+        }
+        let srs = get_srs(column.crs.as_ref()).ok_or_else(|| UnsupportedCrs(path.clone()))?;
+
+        let schema = metadata.file_metadata().schema_descr().root_schema();
+        let mut fields = BTreeMap::new();
+        for field in schema.get_fields() {
+            if !geo.columns.contains_key(field.name()) {
+                if let Some(field_type) = field_type(field) {
+                    fields.insert(field.name().to_string(), field_type.to_string());
+                }
+            }
+        }
+
+        let row_groups: Vec<_> = match &column.covering {
+            Some(covering) => (0..metadata.num_row_groups())
+                .map(|idx| covering_bbox(&reader, idx, &covering.bbox))
+                .collect(),
+            None => (0..metadata.num_row_groups())
+                .map(|idx| scan_bbox(&reader, idx, &geo.primary_column))
+                .collect::<Result<_, _>>()
+                .map_err(err)?,
+        };
+
+        let bounds = row_groups
+            .iter()
+            .flatten()
+            .map(|bbox| srs.to_bounds(*bbox))
+            .reduce(|a, b| a + b);
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            vector_layers: vec![VectorLayer {
+                fields: fields.clone(),
+                ..VectorLayer::new(id.clone(), BTreeMap::new())
+            }],
+        };
+        tilejson.bounds = bounds.or(Some(Bounds::MAX));
+
+        info!(
+            "Serving {} row groups of GeoParquet file {}",
+            row_groups.len(),
+            path.display()
+        );
+        let content = ParquetContent {
+            geometry_column: geo.primary_column,
+            srs,
+            columns: fields.into_keys().collect(),
+            row_groups,
+        };
+        Ok(Self {
+            id,
+            path,
+            reader: Arc::new(reader),
+            content: Arc::new(content),
+            tilejson,
+        })
+    }
+
+    fn read_tile(&self, xyz: TileCoord) -> GeoParquetResult<TileData> {
+        let content = &self.content;
+        let transform = TileTransform::new(xyz, content.srs);
+        let search = transform.search_bbox();
+        let err = |e| GeoParquetError::TileError(e, xyz, self.id.clone());
+
+        let mut layer = LayerBuilder::new(&self.id, DEFAULT_MVT_EXTENT);
+        for (idx, bbox) in content.row_groups.iter().enumerate() {
+            if !intersects(*bbox, search) {
+                continue;
+            }
+            let row_group = self.reader.get_row_group(idx).map_err(err)?;
+            for row in row_group.get_row_iter(None).map_err(err)? {
+                let row = row.map_err(err)?;
+                let Some(geom) = get_geometry(&row, &content.geometry_column) else {
+                    continue;
+                };
+                if !intersects(geom.bbox(), search) {
+                    continue;
+                }
+                let props = get_properties(&row, &content.columns);
+                for part in geom.to_tile(&|c| transform.apply(c)) {
+                    layer.add_feature(None, &part, &props);
+                }
+            }
+        }
+        Ok(encode_tile(vec![layer.build()]))
+    }
+}
+
+#[async_trait]
+impl Source for GeoParquetSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        Format::Mvt.into()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "geoparquet"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let src = self.clone();
+        let xyz = *xyz;
+        let tile = spawn_blocking(move || src.read_tile(xyz))
+            .await
+            .map_err(|e| MartinError::InternalError(e.into()))?
+            .map_err(FileError::from)?;
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| FileError::IoError(e, self.path.clone()))?;
+        Ok(())
+    }
+}
+
+/// Get the SRS from the PROJJSON of a geometry column, or from its `authority:code` string
+fn get_srs(crs: Option<&serde_json::Value>) -> Option<Srs> {
+    let Some(crs) = crs.filter(|v| !v.is_null()) else {
+        return Some(Srs::Wgs84);
+    };
+    let (authority, code) = if let Some(name) = crs.as_str() {
+        let (authority, code) = name.split_once(':')?;
+        (authority.to_string(), code.to_string())
+    } else {
+        let id = crs.get("id")?;
+        let code = match id.get("code")? {
+            serde_json::Value::String(v) => v.clone(),
+            v => v.to_string(),
+        };
+        (id.get("authority")?.as_str()?.to_string(), code)
+    };
+    if authority.eq_ignore_ascii_case("ogc") && code.eq_ignore_ascii_case("crs84") {
+        Some(Srs::Wgs84)
+    } else {
+        Srs::from_epsg(Some(&authority), code.parse().ok())
+    }
+}
+
+/// Map a top-level Parquet column to a `TileJSON` field type, or `None` if it cannot be a property
+fn field_type(field: &Type) -> Option<&'static str> {
+    if !field.is_primitive() {
+        return None;
+    }
+    let info = field.get_basic_info();
+    match field.get_physical_type() {
+        PhysicalType::BOOLEAN => Some("Boolean"),
+        PhysicalType::INT32 | PhysicalType::INT64 | PhysicalType::FLOAT | PhysicalType::DOUBLE => {
+            match info.converted_type() {
+                ConvertedType::NONE
+                | ConvertedType::INT_8
+                | ConvertedType::INT_16
+                | ConvertedType::INT_32
+                | ConvertedType::INT_64
+                | ConvertedType::UINT_8
+                | ConvertedType::UINT_16
+                | ConvertedType::UINT_32
+                | ConvertedType::UINT_64 => Some("Number"),
+                _ => None,
+            }
+        }
+        PhysicalType::BYTE_ARRAY => match (info.logical_type(), info.converted_type()) {
+            (Some(LogicalType::String | LogicalType::Enum), _)
+            | (_, ConvertedType::UTF8 | ConvertedType::ENUM) => Some("String"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Get the bounding box of a row group from the statistics of its bounding box columns
+fn covering_bbox(
+    reader: &SerializedFileReader<File>,
+    idx: usize,
+    covering: &BboxCovering,
+) -> Option<[f64; 4]> {
+    let row_group = reader.metadata().row_group(idx);
+    let stat = |path: &[String], use_min: bool| -> Option<f64> {
+        let column = row_group
+            .columns()
+            .iter()
+            .find(|c| c.column_path().parts() == path)?;
+        match column.statistics()? {
+            Statistics::Double(v) if use_min => v.min_opt().copied(),
+            Statistics::Double(v) => v.max_opt().copied(),
+            Statistics::Float(v) if use_min => v.min_opt().map(|v| f64::from(*v)),
+            Statistics::Float(v) => v.max_opt().map(|v| f64::from(*v)),
+            _ => None,
+        }
+    };
+    Some([
+        stat(&covering.xmin, true)?,
+        stat(&covering.ymin, true)?,
+        stat(&covering.xmax, false)?,
+        stat(&covering.ymax, false)?,
+    ])
+}
+
+/// Get the bounding box of a row group by reading all of its geometries
+fn scan_bbox(
+    reader: &SerializedFileReader<File>,
+    idx: usize,
+    geometry_column: &str,
+) -> Result<Option<[f64; 4]>, ParquetError> {
+    let schema = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .root_schema();
+    let fields = schema
+        .get_fields()
+        .iter()
+        .filter(|f| f.name() == geometry_column)
+        .cloned()
+        .collect();
+    let projection = Type::group_type_builder(schema.name())
+        .with_fields(fields)
+        .build()?;
+    let mut result: Option<[f64; 4]> = None;
+    let row_group = reader.get_row_group(idx)?;
+    for row in row_group.get_row_iter(Some(projection))? {
+        let Some(bbox) = get_geometry(&row?, geometry_column).and_then(|g| g.bbox()) else {
+            continue;
+        };
+        result = Some(result.map_or(bbox, |r| {
+            [
+                r[0].min(bbox[0]),
+                r[1].min(bbox[1]),
+                r[2].max(bbox[2]),
+                r[3].max(bbox[3]),
+            ]
+        }));
+    }
+    Ok(result)
+}
+
+fn get_geometry(row: &Row, geometry_column: &str) -> Option<Geometry> {
+    row.get_column_iter().find_map(|(name, field)| match field {
+        Field::Bytes(v) if name == geometry_column => parse_wkb(v.data()),
+        _ => None,
+    })
+}
+
+fn get_properties(row: &Row, columns: &[String]) -> Vec<(String, PropValue)> {
+    row.get_column_iter()
+        .filter(|(name, _)| columns.contains(name))
+        .filter_map(|(name, field)| {
+            let value = match field {
+                Field::Bool(v) => PropValue::Bool(*v),
+                Field::Byte(v) => PropValue::Int(i64::from(*v)),
+                Field::Short(v) => PropValue::Int(i64::from(*v)),
+                Field::Int(v) => PropValue::Int(i64::from(*v)),
+                Field::Long(v) => PropValue::Int(*v),
+                Field::UByte(v) => PropValue::Int(i64::from(*v)),
+                Field::UShort(v) => PropValue::Int(i64::from(*v)),
+                Field::UInt(v) => PropValue::Int(i64::from(*v)),
+                Field::ULong(v) => PropValue::Int(i64::try_from(*v).ok()?),
+                Field::Float(v) => PropValue::Double(f64::from(*v)),
+                Field::Double(v) => PropValue::Double(*v),
+                Field::Str(v) => PropValue::String(v.clone()),
+                _ => None?,
+            };
+            Some((name.clone(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::format::KeyValue;
+    use parquet::schema::parser::parse_message_type;
+    use prost::Message as _;
+
+    use super::*;
+    use crate::utils::mvt::Tile;
+
+    /// WKB of a point
+    fn point(x: f64, y: f64) -> ByteArray {
+        let mut wkb = vec![1, 1, 0, 0, 0];
+        wkb.extend(x.to_le_bytes());
+        wkb.extend(y.to_le_bytes());
+        wkb.into()
+    }
+
+    /// Write a file with one point per row group
+    fn write_file(path: &std::path::Path, points: &[(f64, f64, &str, i64)]) {
+        let schema = Arc::new(
+            parse_message_type(
+                "message schema {
+                    REQUIRED BYTE_ARRAY geometry;
+                    REQUIRED BYTE_ARRAY name (UTF8);
+                    REQUIRED INT64 pop;
+                }",
+            )
+            .unwrap(),
+        );
+        let geo = r#"{"version":"1.1.0","primary_column":"geometry","columns":{"geometry":{"encoding":"WKB","geometry_types":["Point"]}}}"#;
+        let props = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "geo".to_string(),
+                geo.to_string(),
+            )]))
+            .build();
+        let file = File::create(path).unwrap();
+        let mut writer = SerializedFileWriter::new(file, schema, Arc::new(props)).unwrap();
+        for (x, y, name, pop) in points {
+            let mut row_group = writer.next_row_group().unwrap();
+            let mut col = row_group.next_column().unwrap().unwrap();
+            col.typed::<ByteArrayType>()
+                .write_batch(&[point(*x, *y)], None, None)
+                .unwrap();
+            col.close().unwrap();
+            let mut col = row_group.next_column().unwrap().unwrap();
+            col.typed::<ByteArrayType>()
+                .write_batch(&[ByteArray::from(*name)], None, None)
+                .unwrap();
+            col.close().unwrap();
+            let mut col = row_group.next_column().unwrap().unwrap();
+            col.typed::<Int64Type>()
+                .write_batch(&[*pop], None, None)
+                .unwrap();
+            col.close().unwrap();
+            row_group.close().unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn geoparquet_tiles() {
+        let path = std::env::temp_dir().join(format!("martin-{}.parquet", std::process::id()));
+        write_file(&path, &[(10.0, 10.0, "east", 5), (-100.0, 40.0, "west", 7)]);
+        let src = GeoParquetSource::new("cities".to_string(), path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(src.content.row_groups.len(), 2);
+        assert_eq!(src.content.columns, vec!["name", "pop"]);
+        let tj = src.get_tilejson();
+        assert_eq!(tj.bounds, Some(Bounds::new(-100.0, 10.0, 10.0, 40.0)));
+        let fields = &tj.vector_layers.as_ref().unwrap()[0].fields;
+        assert_eq!(fields["name"], "String");
+        assert_eq!(fields["pop"], "Number");
+
+        let features = |xyz| {
+            let tile = Tile::decode(src.read_tile(xyz).unwrap().as_slice()).unwrap();
+            tile.layers.iter().map(|l| l.features.len()).sum::<usize>()
+        };
+        assert_eq!(features(TileCoord { z: 0, x: 0, y: 0 }), 2);
+        assert_eq!(features(TileCoord { z: 1, x: 1, y: 0 }), 1);
+        assert_eq!(features(TileCoord { z: 1, x: 0, y: 1 }), 0);
+    }
+
+    #[test]
+    fn geoparquet_crs() {
+        assert_eq!(get_srs(None), Some(Srs::Wgs84));
+        let crs = serde_json::json!({"id": {"authority": "EPSG", "code": 3857}});
+        assert_eq!(get_srs(Some(&crs)), Some(Srs::WebMercator));
+        let crs = serde_json::json!({"id": {"authority": "OGC", "code": "CRS84"}});
+        assert_eq!(get_srs(Some(&crs)), Some(Srs::Wgs84));
+        let crs = serde_json::json!({"id": {"authority": "EPSG", "code": 2056}});
+        assert_eq!(get_srs(Some(&crs)), None);
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{info, trace, warn};
+use martin_tile_utils::{Format, TileInfo};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::{Column, Row, TypeInfo, ValueRef};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
+
+use crate::file_config::{FileError, FileResult};
+use crate::gpkg::GpkgError::{NoTables, QueryError, SqlError};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::utils::antimeridian::{fix_antimeridian, Antimeridian};
+use crate::utils::mvt::{
+    encode_tile, intersects, LayerBuilder, PropValue, Srs, TileTransform, DEFAULT_MVT_EXTENT,
+    MERCATOR_MAX,
+};
+use crate::utils::wkb::{parse_wkb, Geometry};
+use crate::{MartinResult, TileCoord};
+
+pub type GpkgResult<T> = Result<T, GpkgError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum GpkgError {
+    #[error("Unable to read GeoPackage {}: {0}", .1.display())]
+    SqlError(sqlx::Error, PathBuf),
+
+    #[error("GeoPackage {} has no feature tables or tile pyramids in EPSG:4326 or EPSG:3857", .0.display())]
+    NoTables(PathBuf),
+
+    #[error("Unable to query table {1} of source {2}: {0}")]
+    QueryError(sqlx::Error, String, String),
+}
+
+/// Allowed difference between a zoom level or a tile offset and the closest whole number
+const GRID_TOLERANCE: f64 = 0.01;
+
+/// A table with features, served as one layer of the generated vector tiles
+#[derive(Clone, Debug)]
+struct FeatureTable {
+    table: String,
+    geometry_column: String,
+    id_column: Option<String>,
+    columns: Vec<String>,
+    srs: Srs,
+    /// Spatial index table, if the file has one
+    rtree: Option<String>,
+}
+
+/// Location of a Web Mercator zoom level in a tile pyramid table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TileLevel {
+    zoom_level: i64,
+    col_offset: i64,
+    row_offset: i64,
+}
+
+#[derive(Clone, Debug)]
+enum Content {
+    Features(Vec<FeatureTable>),
+    Tiles {
+        table: String,
+        levels: BTreeMap<u8, TileLevel>,
+    },
+}
+
+/// Serves a `GeoPackage` file. If the file has feature tables, each of them becomes a layer
+/// of vector tiles generated on the fly using the R-tree spatial indexes. Otherwise,
+/// the first tile pyramid table is served as is. Only EPSG:4326 and EPSG:3857 data is supported,
+/// and tile pyramids must be aligned with the Web Mercator tile grid.
+#[derive(Clone)]
+pub struct GpkgSource {
+    id: String,
+    path: PathBuf,
+    pool: SqlitePool,
+    content: Arc<Content>,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+    /// Handling of the EPSG:4326 features that cross the antimeridian
+    antimeridian: Option<Antimeridian>,
+}
+
+impl Debug for GpkgSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GpkgSource {{ id: {}, path: {:?} }}", self.id, self.path)
+    }
+}
+
+impl GpkgSource {
+    pub async fn new_box(
+        id: String,
+        path: PathBuf,
+        antimeridian: Option<Antimeridian>,
+    ) -> FileResult<Box<dyn Source>> {
+        Ok(Box::new(GpkgSource::new(id, path, antimeridian).await?))
+    }
+
+    async fn new(
+        id: String,
+        path: PathBuf,
+        antimeridian: Option<Antimeridian>,
+    ) -> GpkgResult<Self> {
+        let opt = SqliteConnectOptions::new().filename(&path).read_only(true);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(opt)
+            .await
+            .map_err(|e| SqlError(e, path.clone()))?;
+
+        let tables = get_feature_tables(&pool)
+            .await
+            .map_err(|e| SqlError(e, path.clone()))?;
+        let (content, tilejson, tile_info) = if tables.is_empty() {
+            let Some((table, levels, tilejson, info)) = get_tile_pyramid(&pool)
+                .await
+                .map_err(|e| SqlError(e, path.clone()))?
+            else {
+                return Err(NoTables(path));
+            };
+            info!("Serving tile table {table} of {}", path.display());
+            (Content::Tiles { table, levels }, tilejson, info)
+        } else {
+            let tilejson = get_features_tilejson(&pool, &tables)
+                .await
+                .map_err(|e| SqlError(e, path.clone()))?;
+            let info = TileInfo::from(Format::Mvt);
+            (Content::Features(tables), tilejson, info)
+        };
+
+        Ok(Self {
+            id,
+            path,
+            pool,
+            content: Arc::new(content),
+            tilejson,
+            tile_info,
+            antimeridian,
+        })
+    }
+
+    async fn get_pyramid_tile(
+        &self,
+        table: &str,
+        levels: &BTreeMap<u8, TileLevel>,
+        xyz: &TileCoord,
+    ) -> GpkgResult<TileData> {
+        let Some(level) = levels.get(&xyz.z) else {
+            return Ok(Vec::new());
+        };
+        let sql = format!(
+            "SELECT tile_data FROM {} WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+            escape_identifier(table)
+        );
+        let tile = sqlx::query(&sql)
+            .bind(level.zoom_level)
+            .bind(i64::from(xyz.x) - level.col_offset)
+            .bind(i64::from(xyz.y) - level.row_offset)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| QueryError(e, table.to_string(), self.id.clone()))?;
+        Ok(tile.map(|row| row.get(0)).unwrap_or_default())
+    }
+
+    async fn get_features_tile(
+        &self,
+        tables: &[FeatureTable],
+        xyz: &TileCoord,
+    ) -> GpkgResult<TileData> {
+        let mut layers = Vec::with_capacity(tables.len());
+        for table in tables {
+            let transform = TileTransform::new(*xyz, table.srs);
+            let [min_x, min_y, max_x, max_y] = transform.search_bbox();
+            let antimeridian = self.antimeridian.filter(|_| table.srs == Srs::Wgs84);
+            let mut search = vec![[min_x, min_y, max_x, max_y]];
+            if antimeridian == Some(Antimeridian::Wrap) {
+                // features beyond the antimeridian are moved by a whole world width
+                search.push([min_x + 360.0, min_y, max_x + 360.0, max_y]);
+                search.push([min_x - 360.0, min_y, max_x - 360.0, max_y]);
+            }
+            let rows = query_features(&self.pool, table, &search)
+                .await
+                .map_err(|e| QueryError(e, table.table.clone(), self.id.clone()))?;
+
+            let mut layer = LayerBuilder::new(&table.table, DEFAULT_MVT_EXTENT);
+            for row in rows {
+                let Some(geom) = row
+                    .try_get::<Option<Vec<u8>>, _>(1)
+                    .ok()
+                    .flatten()
+                    .and_then(|v| parse_gpkg_geometry(&v))
+                else {
+                    continue;
+                };
+                if table.rtree.is_none() && !search.iter().any(|b| intersects(geom.bbox(), *b)) {
+                    continue;
+                }
+                let geom = match antimeridian {
+                    Some(mode) => match fix_antimeridian(&geom, mode) {
+                        Some(geom) => geom,
+                        None => continue,
+                    },
+                    None => geom,
+                };
+                let id = row
+                    .try_get::<Option<i64>, _>(0)
+                    .ok()
+                    .flatten()
+                    .and_then(|v| u64::try_from(v).ok());
+                let props = get_properties(&row, &table.columns);
+                for part in geom.to_tile(&|c| transform.apply(c)) {
+                    layer.add_feature(id, &part, &props);
+                }
+            }
+            layers.push(layer.build());
+        }
+        Ok(encode_tile(layers))
+    }
+}
+
+#[async_trait]
+impl Source for GpkgSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "gpkg"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let tile = match self.content.as_ref() {
+            Content::Tiles { table, levels } => self.get_pyramid_tile(table, levels, xyz).await,
+            Content::Features(tables) => self.get_features_tile(tables, xyz).await,
+        }
+        .map_err(FileError::from)?;
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz:#} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| FileError::from(SqlError(e, self.path.clone())))?;
+        Ok(())
+    }
+}
+
+fn escape_identifier(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Strip the `GeoPackage` binary header, and parse the remaining WKB geometry
+fn parse_gpkg_geometry(data: &[u8]) -> Option<Geometry> {
+    if data.len() < 8 || &data[..2] != b"GP" {
+        return None;
+    }
+    let flags = data[3];
+    if flags & 0b1_0000 != 0 {
+        // empty geometry
+        return None;
+    }
+    let envelope = match (flags >> 1) & 0b111 {
+        0 => 0,
+        1 => 32,
+        2 | 3 => 48,
+        4 => 64,
+        _ => None?,
+    };
+    parse_wkb(data.get(8 + envelope..)?)
+}
+
+fn get_properties(row: &SqliteRow, columns: &[String]) -> Vec<(String, PropValue)> {
+    let mut props = Vec::with_capacity(columns.len());
+    for (idx, column) in columns.iter().enumerate() {
+        // the first two columns are the ID and the geometry
+        let idx = idx + 2;
+        let Ok(raw) = row.try_get_raw(idx) else {
+            continue;
+        };
+        if raw.is_null() {
+            continue;
+        }
+        // SQLite stores booleans as integers, so the declared column type has to be checked
+        let declared = row.column(idx).type_info().name();
+        let value = match raw.type_info().name() {
+            "INTEGER" if declared == "BOOLEAN" => row.try_get(idx).map(PropValue::Bool),
+            "INTEGER" => row.try_get(idx).map(PropValue::Int),
+            "REAL" => row.try_get(idx).map(PropValue::Double),
+            "TEXT" => row.try_get(idx).map(PropValue::String),
+            _ => continue,
+        };
+        if let Ok(value) = value {
+            props.push((column.clone(), value));
+        }
+    }
+    props
+}
+
+/// Get the features that intersect any of the bounding boxes
+async fn query_features(
+    pool: &SqlitePool,
+    table: &FeatureTable,
+    bboxes: &[[f64; 4]],
+) -> Result<Vec<SqliteRow>, sqlx::Error> {
+    let id = table
+        .id_column
+        .as_deref()
+        .map_or_else(|| "rowid".to_string(), escape_identifier);
+    let mut columns = format!("{id}, {}", escape_identifier(&table.geometry_column));
+    for column in &table.columns {
+        columns.push_str(", ");
+        columns.push_str(&escape_identifier(column));
+    }
+    let name = escape_identifier(&table.table);
+    if let Some(rtree) = &table.rtree {
+        let condition = vec!["(minx <= ? AND maxx >= ? AND miny <= ? AND maxy >= ?)"; bboxes.len()];
+        let sql = format!(
+            "SELECT {columns} FROM {name} WHERE rowid IN (SELECT id FROM {} WHERE {})",
+            escape_identifier(rtree),
+            condition.join(" OR ")
+        );
+        let mut query = sqlx::query(&sql);
+        for bbox in bboxes {
+            query = query
+                .bind(bbox[2])
+                .bind(bbox[0])
+                .bind(bbox[3])
+                .bind(bbox[1]);
+        }
+        query.fetch_all(pool).await
+    } else {
+        sqlx::query(&format!("SELECT {columns} FROM {name}"))
+            .fetch_all(pool)
+            .await
+    }
+}
+
+async fn has_table(pool: &SqlitePool, name: &str) -> Result<bool, sqlx::Error> {
+    Ok(
+        sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+            .bind(name)
+            .fetch_optional(pool)
+            .await?
+            .is_some(),
+    )
+}
+
+async fn get_feature_tables(pool: &SqlitePool) -> Result<Vec<FeatureTable>, sqlx::Error> {
+    // Files with only tiles are not required to have this table
+    if !has_table(pool, "gpkg_geometry_columns").await? {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query(
+        "SELECT g.table_name, g.column_name, s.organization, s.organization_coordsys_id
+         FROM gpkg_geometry_columns g
+         JOIN gpkg_contents c ON c.table_name = g.table_name
+         LEFT JOIN gpkg_spatial_ref_sys s ON s.srs_id = g.srs_id
+         WHERE c.data_type = 'features'
+         ORDER BY g.table_name",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tables = Vec::new();
+    for row in rows {
+        let table: String = row.get(0);
+        let geometry_column: String = row.get(1);
+        let Some(srs) = Srs::from_epsg(row.get(2), row.get(3)) else {
+            warn!("Skipping GeoPackage table {table} because only EPSG:4326 and EPSG:3857 are supported");
+            continue;
+        };
+
+        let mut id_column = None;
+        let mut columns = Vec::new();
+        for col in sqlx::query("SELECT name, type, pk FROM pragma_table_info(?)")
+            .bind(&table)
+            .fetch_all(pool)
+            .await?
+        {
+            let name: String = col.get(0);
+            let col_type: String = col.get(1);
+            let pk: i64 = col.get(2);
+            if pk == 1 && col_type.eq_ignore_ascii_case("integer") {
+                id_column = Some(name);
+            } else if name != geometry_column {
+                columns.push(name);
+            }
+        }
+
+        let rtree = format!("rtree_{table}_{geometry_column}");
+        let has_rtree = has_table(pool, &rtree).await?;
+        if !has_rtree {
+            warn!("GeoPackage table {table} has no spatial index, all of its features will be read for each tile");
+        }
+
+        tables.push(FeatureTable {
+            table,
+            geometry_column,
+            id_column,
+            columns,
+            srs,
+            rtree: has_rtree.then_some(rtree),
+        });
+    }
+    Ok(tables)
+}
+
+async fn get_features_tilejson(
+    pool: &SqlitePool,
+    tables: &[FeatureTable],
+) -> Result<TileJSON, sqlx::Error> {
+    let mut vector_layers = Vec::with_capacity(tables.len());
+    let mut bounds: Option<Bounds> = None;
+    for table in tables {
+        let mut fields = BTreeMap::new();
+        for col in sqlx::query("SELECT name, type FROM pragma_table_info(?)")
+            .bind(&table.table)
+            .fetch_all(pool)
+            .await?
+        {
+            let name: String = col.get(0);
+            if table.columns.contains(&name) {
+                fields.insert(name, field_type(col.get(1)).to_string());
+            }
+        }
+        vector_layers.push(VectorLayer {
+            fields,
+            ..VectorLayer::new(table.table.clone(), BTreeMap::new())
+        });
+
+        let row = sqlx::query(
+            "SELECT min_x, min_y, max_x, max_y FROM gpkg_contents WHERE table_name = ?",
+        )
+        .bind(&table.table)
+        .fetch_one(pool)
+        .await?;
+        if let (Some(a), Some(b), Some(c), Some(d)) =
+            (row.get(0), row.get(1), row.get(2), row.get(3))
+        {
+            let table_bounds = table.srs.to_bounds([a, b, c, d]);
+            bounds = Some(bounds.map_or(table_bounds, |v| v + table_bounds));
+        }
+    }
+
+    let mut tilejson = tilejson! {
+        tiles: vec![],
+        vector_layers: vector_layers,
+    };
+    tilejson.bounds = bounds;
+    Ok(tilejson)
+}
+
+/// Map an `SQLite` column type to a `TileJSON` field type
+fn field_type(sql_type: &str) -> &'static str {
+    let sql_type = sql_type.to_ascii_uppercase();
+    if sql_type == "BOOLEAN" {
+        "Boolean"
+    } else if sql_type.contains("INT")
+        || sql_type.contains("REAL")
+        || sql_type.contains("FLOAT")
+        || sql_type.contains("DOUBLE")
+    {
+        "Number"
+    } else {
+        "String"
+    }
+}
+
+/// Find the first tile pyramid table aligned with the Web Mercator grid
+async fn get_tile_pyramid(
+    pool: &SqlitePool,
+) -> Result<Option<(String, BTreeMap<u8, TileLevel>, TileJSON, TileInfo)>, sqlx::Error> {
+    if !has_table(pool, "gpkg_tile_matrix_set").await? {
+        return Ok(None);
+    }
+    let rows = sqlx::query(
+        "SELECT m.table_name, s.organization, s.organization_coordsys_id,
+                m.min_x, m.min_y, m.max_x, m.max_y, c.description
+         FROM gpkg_tile_matrix_set m
+         JOIN gpkg_contents c ON c.table_name = m.table_name
+         LEFT JOIN gpkg_spatial_ref_sys s ON s.srs_id = m.srs_id
+         WHERE c.data_type IN ('tiles', 'vector-tiles')
+         ORDER BY m.table_name",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+        let table: String = row.get(0);
+        if Srs::from_epsg(row.get(1), row.get(2)) != Some(Srs::WebMercator) {
+            warn!(
+                "Skipping GeoPackage tile table {table} because only EPSG:3857 tiles are supported"
+            );
+            continue;
+        }
+        let bbox: [f64; 4] = [row.get(3), row.get(4), row.get(5), row.get(6)];
+        let matrices = sqlx::query(
+            "SELECT zoom_level, tile_width, pixel_x_size FROM gpkg_tile_matrix WHERE table_name = ?",
+        )
+        .bind(&table)
+        .fetch_all(pool)
+        .await?;
+        let levels: Option<BTreeMap<_, _>> = matrices
+            .iter()
+            .map(|m| get_tile_level(m.get(0), m.get(1), m.get(2), bbox))
+            .collect();
+        let Some(levels) = levels.filter(|v| !v.is_empty()) else {
+            warn!("Skipping GeoPackage tile table {table} because it is not aligned with the Web Mercator tile grid");
+            continue;
+        };
+
+        let sample = format!(
+            "SELECT tile_data FROM {} LIMIT 1",
+            escape_identifier(&table)
+        );
+        let info = sqlx::query(&sample)
+            .fetch_optional(pool)
+            .await?
+            .and_then(|row| TileInfo::detect(&row.get::<Vec<u8>, _>(0)));
+        let Some(info) = info else {
+            warn!("Skipping GeoPackage tile table {table} because its tile format is unknown");
+            continue;
+        };
+
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            bounds: Srs::WebMercator.to_bounds(bbox),
+        };
+        tilejson.minzoom = levels.keys().next().copied();
+        tilejson.maxzoom = levels.keys().next_back().copied();
+        tilejson.description = row.get(7);
+        return Ok(Some((table, levels, tilejson, info)));
+    }
+    Ok(None)
+}
+
+/// Find the Web Mercator zoom level of a tile matrix, and the position of its first tile
+fn get_tile_level(
+    zoom_level: i64,
+    tile_width: i64,
+    pixel_size: f64,
+    bbox: [f64; 4],
+) -> Option<(u8, TileLevel)> {
+    // Precision loss is irrelevant for the tile sizes
+    #[allow(clippy::cast_precision_loss)]
+    let span = tile_width as f64 * pixel_size;
+    let to_whole = |v: f64| {
+        let rounded = v.round();
+        // Only values that are already whole numbers are accepted
+        #[allow(clippy::cast_possible_truncation)]
+        ((v - rounded).abs() < GRID_TOLERANCE).then_some(rounded as i64)
+    };
+    let zoom = to_whole((2.0 * MERCATOR_MAX / span).log2())?;
+    let zoom = u8::try_from(zoom).ok().filter(|z| *z <= 30)?;
+    let level = TileLevel {
+        zoom_level,
+        col_offset: to_whole((bbox[0] + MERCATOR_MAX) / span)?,
+        row_offset: to_whole((MERCATOR_MAX - bbox[3]) / span)?,
+    };
+    Some((zoom, level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_levels() {
+        let world = [-MERCATOR_MAX, -MERCATOR_MAX, MERCATOR_MAX, MERCATOR_MAX];
+        let pixel = 2.0 * MERCATOR_MAX / 256.0;
+        assert_eq!(
+            get_tile_level(0, 256, pixel, world),
+            Some((
+                0,
+                TileLevel {
+                    zoom_level: 0,
+                    col_offset: 0,
+                    row_offset: 0,
+                }
+            ))
+        );
+        // zoom 3 of a matrix set that starts with the north-east quarter of the world
+        let quarter = [0.0, 0.0, MERCATOR_MAX, MERCATOR_MAX];
+        assert_eq!(
+            get_tile_level(2, 256, pixel / 8.0, quarter),
+            Some((
+                3,
+                TileLevel {
+                    zoom_level: 2,
+                    col_offset: 4,
+                    row_offset: 0,
+                }
+            ))
+        );
+        // not aligned with the grid
+        assert_eq!(get_tile_level(0, 256, pixel / 3.0, world), None);
+    }
+
+    #[test]
+    fn gpkg_geometry() {
+        // POINT(1 2) with an xy envelope
+        let mut data = vec![b'G', b'P', 0, 0b0000_0011, 0, 0, 0x10, 0xE6];
+        data.extend([0; 32]);
+        data.extend([1, 1, 0, 0, 0]);
+        data.extend(1.0_f64.to_le_bytes());
+        data.extend(2.0_f64.to_le_bytes());
+        assert_eq!(
+            parse_gpkg_geometry(&data),
+            Some(Geometry::Points(vec![[1.0, 2.0]]))
+        );
+        assert_eq!(parse_gpkg_geometry(b"XX"), None);
+    }
+}
@@ -1,26 +1,54 @@
 #![doc = include_str!("../README.md")]
 #![forbid(unsafe_code)]
 
+mod builder;
+pub use builder::{Builder, Martin};
+
 mod config;
 pub use config::{read_config, Config, ServerState};
 
+mod factory;
+pub use factory::{SourceFactories, SourceFactory};
+
 mod source;
-pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, UrlQuery};
+pub use source::{
+    CatalogSourceEntry, PoolStatus, Source, Tile, TileData, TileHeaders, TileSources, UrlQuery,
+};
 
 mod utils;
+pub use martin_tile_utils::{append_rect, TileArea, TileRect};
 pub use utils::{
-    append_rect, decode_brotli, decode_gzip, IdResolver, MartinError, MartinResult, OptBoolObj,
-    OptOneMany, TileCoord, TileRect,
+    decode_brotli, decode_gzip, decode_zstd, new_main_cache, CacheKey, CacheValue, IdResolver,
+    MainCache, MartinError, MartinResult, OptBoolObj, OptMainCache, OptOneMany, TileCoord,
+    TileExpiration, TileMatrixSet, NO_MAIN_CACHE,
 };
 
+pub mod aliases;
 pub mod args;
+pub mod bench;
+pub mod check;
+pub mod cog;
+pub mod demo;
 pub mod file_config;
 pub mod fonts;
+pub mod geoparquet;
+pub mod gpkg;
+pub mod init;
 pub mod mbtiles;
+pub mod osm;
+pub mod overrides;
+pub mod overzoom;
 pub mod pg;
 pub mod pmtiles;
+pub mod proxy;
+pub mod shapefile;
 pub mod sprites;
+pub mod sqlite;
 pub mod srv;
+pub mod styles;
+pub mod terrain;
+pub mod variants;
+pub mod watermark;
 
 #[cfg(test)]
 #[path = "utils/test_utils.rs"]
@@ -17,6 +17,7 @@ use crate::{MartinResult, Source, TileCoord};
 #[derive(Clone)]
 pub struct MbtSource {
     id: String,
+    path: PathBuf,
     mbtiles: Arc<MbtilesPool>,
     tilejson: TileJSON,
     tile_info: TileInfo,
@@ -52,10 +53,11 @@ impl MbtSource {
         let meta = mbt
             .get_metadata()
             .await
-            .map_err(|e| InvalidMetadata(e.to_string(), path))?;
+            .map_err(|e| InvalidMetadata(e.to_string(), path.clone()))?;
 
         Ok(Self {
             id,
+            path,
             mbtiles: Arc::new(mbt),
             tilejson: meta.tilejson,
             tile_info: meta.tile_info,
@@ -77,6 +79,10 @@ impl Source for MbtSource {
         self.tile_info
     }
 
+    fn get_source_type(&self) -> &'static str {
+        "mbtiles"
+    }
+
     fn clone_source(&self) -> Box<dyn Source> {
         Box::new(self.clone())
     }
@@ -104,4 +110,11 @@ impl Source for MbtSource {
             Ok(Vec::new())
         }
     }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| IoError(e, self.path.clone()))?;
+        Ok(())
+    }
 }
@@ -0,0 +1,797 @@
+//! Vector tiles generated from an `OpenStreetMap` extract in the [PBF format](https://wiki.openstreetmap.org/wiki/PBF_Format).
+//! The whole file is read once at startup, and the features of the configured layers are kept in memory,
+//! so this is meant for small regional extracts, not for the whole planet.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter};
+use std::io::Read as _;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use flate2::read::ZlibDecoder;
+use log::{info, trace};
+use martin_tile_utils::{Format, TileInfo};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
+
+use crate::file_config::{FileError, FileResult};
+use crate::osm::OsmError::{InvalidData, IoError, UnsupportedFeature};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::utils::decode_zstd;
+use crate::utils::mvt::{
+    encode_tile, intersects, LayerBuilder, PropValue, Srs, TileTransform, DEFAULT_MVT_EXTENT,
+};
+use crate::utils::saturation::spawn_blocking;
+use crate::utils::wkb::{Coord, Geometry};
+use crate::{MartinError, MartinResult, TileCoord};
+
+pub type OsmResult<T> = Result<T, OsmError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OsmError {
+    #[error("Unable to read OSM file {}: {0}", .1.display())]
+    IoError(std::io::Error, PathBuf),
+
+    #[error("Unable to parse OSM PBF file {}: {0}", .1.display())]
+    InvalidData(String, PathBuf),
+
+    #[error("OSM file {} requires the unsupported feature {0}", .1.display())]
+    UnsupportedFeature(String, PathBuf),
+}
+
+/// Features of the PBF format that can be read, any other required feature is rejected
+const SUPPORTED_FEATURES: &[&str] = &["OsmSchema-V0.6", "DenseNodes"];
+
+/// Type of the geometries of a layer, which also decides the OSM elements it can include
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsmGeometryType {
+    /// Tagged nodes
+    Point,
+    /// Ways
+    Line,
+    /// Closed ways
+    Polygon,
+}
+
+/// A vector tile layer, and the OSM elements it includes
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsmLayerConfig {
+    pub geometry: OsmGeometryType,
+    /// Elements with any of these tags are included, given as `key` to match any value, or as `key=value`
+    pub filter: Vec<String>,
+    /// Tags kept as the feature properties [default: the keys of the filter, and `name`]
+    pub tags: Option<Vec<String>>,
+    /// Lowest zoom level with the features of the layer [default: 0]
+    pub minzoom: Option<u8>,
+}
+
+impl OsmLayerConfig {
+    fn new(geometry: OsmGeometryType, filter: &[&str], minzoom: u8) -> Self {
+        Self {
+            geometry,
+            filter: filter.iter().map(ToString::to_string).collect(),
+            tags: None,
+            minzoom: Some(minzoom),
+        }
+    }
+
+    /// Layers used when a source does not configure any
+    #[must_use]
+    pub fn default_layers() -> BTreeMap<String, Self> {
+        BTreeMap::from([
+            (
+                "roads".to_string(),
+                Self::new(OsmGeometryType::Line, &["highway"], 0),
+            ),
+            (
+                "buildings".to_string(),
+                Self::new(OsmGeometryType::Polygon, &["building"], 13),
+            ),
+            (
+                "pois".to_string(),
+                Self::new(
+                    OsmGeometryType::Point,
+                    &["amenity", "shop", "tourism", "leisure"],
+                    14,
+                ),
+            ),
+        ])
+    }
+}
+
+/// A layer config prepared for matching the tags of the elements
+#[derive(Debug)]
+struct LayerSchema {
+    name: String,
+    geometry: OsmGeometryType,
+    filter: Vec<(String, Option<String>)>,
+    tags: Vec<String>,
+    minzoom: u8,
+}
+
+impl LayerSchema {
+    fn new(name: String, cfg: OsmLayerConfig) -> Self {
+        let filter: Vec<_> = cfg
+            .filter
+            .iter()
+            .map(|v| match v.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (v.clone(), None),
+            })
+            .collect();
+        let tags = cfg.tags.unwrap_or_else(|| {
+            let mut tags: Vec<_> = filter.iter().map(|(key, _)| key.clone()).collect();
+            if !tags.iter().any(|t| t == "name") {
+                tags.push("name".to_string());
+            }
+            tags
+        });
+        Self {
+            name,
+            geometry: cfg.geometry,
+            filter,
+            tags,
+            minzoom: cfg.minzoom.unwrap_or_default(),
+        }
+    }
+
+    fn matches(&self, tags: &[(String, String)]) -> bool {
+        self.filter.iter().any(|(key, value)| {
+            tags.iter()
+                .any(|(k, v)| k == key && value.as_ref().map_or(true, |value| value == v))
+        })
+    }
+
+    fn properties(&self, tags: &[(String, String)]) -> Vec<(String, PropValue)> {
+        tags.iter()
+            .filter(|(k, _)| self.tags.contains(k))
+            .map(|(k, v)| (k.clone(), PropValue::String(v.clone())))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct OsmFeature {
+    id: u64,
+    geometry: Geometry,
+    bbox: Option<[f64; 4]>,
+    props: Vec<(String, PropValue)>,
+}
+
+#[derive(Debug)]
+struct OsmLayer {
+    schema: LayerSchema,
+    features: Vec<OsmFeature>,
+}
+
+/// A way included in some layers, waiting for the coordinates of its nodes
+struct PendingWay {
+    id: i64,
+    refs: Vec<i64>,
+    tags: Vec<(String, String)>,
+    layers: Vec<usize>,
+}
+
+/// Serves an `OpenStreetMap` PBF extract as vector tiles generated on the fly, with one tile layer
+/// per configured layer. Nodes and ways are included, the relations are ignored.
+#[derive(Clone)]
+pub struct OsmSource {
+    id: String,
+    path: PathBuf,
+    layers: Arc<Vec<OsmLayer>>,
+    tilejson: TileJSON,
+}
+
+impl Debug for OsmSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OsmSource {{ id: {}, path: {:?} }}", self.id, self.path)
+    }
+}
+
+impl OsmSource {
+    pub async fn new_box(
+        id: String,
+        path: PathBuf,
+        layers: Option<BTreeMap<String, OsmLayerConfig>>,
+    ) -> FileResult<Box<dyn Source>> {
+        let source = spawn_blocking(move || Self::new(id, path, layers))
+            .await
+            .map_err(|e| FileError::AquireConnError(e.to_string()))??;
+        Ok(Box::new(source))
+    }
+
+    fn new(
+        id: String,
+        path: PathBuf,
+        layers: Option<BTreeMap<String, OsmLayerConfig>>,
+    ) -> OsmResult<Self> {
+        let data = std::fs::read(&path).map_err(|e| IoError(e, path.clone()))?;
+        let mut layers: Vec<_> = layers
+            .unwrap_or_else(OsmLayerConfig::default_layers)
+            .into_iter()
+            .map(|(name, cfg)| OsmLayer {
+                schema: LayerSchema::new(name, cfg),
+                features: Vec::new(),
+            })
+            .collect();
+        read_features(&data, &mut layers).map_err(|e| match e {
+            ReadError::Invalid(e) => InvalidData(e, path.clone()),
+            ReadError::Unsupported(e) => UnsupportedFeature(e, path.clone()),
+        })?;
+
+        let bounds = layers
+            .iter()
+            .flat_map(|l| &l.features)
+            .filter_map(|f| f.bbox)
+            .map(|bbox| Srs::Wgs84.to_bounds(bbox))
+            .reduce(|a, b| a + b);
+        let vector_layers = layers
+            .iter()
+            .map(|l| VectorLayer {
+                fields: l
+                    .schema
+                    .tags
+                    .iter()
+                    .map(|t| (t.clone(), "String".to_string()))
+                    .collect(),
+                minzoom: Some(l.schema.minzoom),
+                ..VectorLayer::new(l.schema.name.clone(), BTreeMap::new())
+            })
+            .collect();
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            vector_layers: vector_layers,
+        };
+        tilejson.bounds = bounds.or(Some(Bounds::MAX));
+
+        info!(
+            "Serving {} features of OSM file {}",
+            layers.iter().map(|l| l.features.len()).sum::<usize>(),
+            path.display()
+        );
+        Ok(Self {
+            id,
+            path,
+            layers: Arc::new(layers),
+            tilejson,
+        })
+    }
+
+    fn read_tile(&self, xyz: TileCoord) -> TileData {
+        let transform = TileTransform::new(xyz, Srs::Wgs84);
+        let search = transform.search_bbox();
+        let layers = self
+            .layers
+            .iter()
+            .filter(|l| xyz.z >= l.schema.minzoom)
+            .map(|l| {
+                let mut layer = LayerBuilder::new(&l.schema.name, DEFAULT_MVT_EXTENT);
+                for feature in &l.features {
+                    if intersects(feature.bbox, search) {
+                        for part in feature.geometry.to_tile(&|c| transform.apply(c)) {
+                            layer.add_feature(Some(feature.id), &part, &feature.props);
+                        }
+                    }
+                }
+                layer.build()
+            })
+            .collect();
+        encode_tile(layers)
+    }
+}
+
+#[async_trait]
+impl Source for OsmSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        Format::Mvt.into()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "osm"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let src = self.clone();
+        let xyz = *xyz;
+        let tile = spawn_blocking(move || src.read_tile(xyz))
+            .await
+            .map_err(|e| MartinError::InternalError(e.into()))?;
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| FileError::IoError(e, self.path.clone()))?;
+        Ok(())
+    }
+}
+
+enum ReadError {
+    Invalid(String),
+    Unsupported(String),
+}
+
+impl From<prost::DecodeError> for ReadError {
+    fn from(e: prost::DecodeError) -> Self {
+        Self::Invalid(e.to_string())
+    }
+}
+
+/// Read all nodes and ways of the file, adding the matching ones to the layers.
+/// The node coordinates are kept until all ways are read, because the ways may come first.
+fn read_features(mut data: &[u8], layers: &mut [OsmLayer]) -> Result<(), ReadError> {
+    let mut nodes: HashMap<i64, Coord> = HashMap::new();
+    let mut ways = Vec::new();
+    while !data.is_empty() {
+        let len = data.get(..4).ok_or_else(truncated)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let rest = &data[4..];
+        let header = BlobHeader::decode(rest.get(..len).ok_or_else(truncated)?)?;
+        let rest = &rest[len..];
+        let len = usize::try_from(header.datasize).map_err(|_| truncated())?;
+        let blob = Blob::decode(rest.get(..len).ok_or_else(truncated)?)?;
+        data = &rest[len..];
+
+        match header.r#type.as_str() {
+            "OSMHeader" => {
+                let header = HeaderBlock::decode(blob.into_data()?.as_slice())?;
+                if let Some(feature) = header
+                    .required_features
+                    .into_iter()
+                    .find(|v| !SUPPORTED_FEATURES.contains(&v.as_str()))
+                {
+                    return Err(ReadError::Unsupported(feature));
+                }
+            }
+            "OSMData" => {
+                let block = PrimitiveBlock::decode(blob.into_data()?.as_slice())?;
+                read_block(&block, layers, &mut nodes, &mut ways);
+            }
+            // unknown blob types must be skipped according to the specification
+            _ => {}
+        }
+    }
+
+    for way in ways {
+        let coords: Vec<Coord> = way
+            .refs
+            .iter()
+            .filter_map(|r| nodes.get(r))
+            .copied()
+            .collect();
+        if coords.len() < 2 {
+            continue;
+        }
+        let closed = coords.len() >= 4 && coords.first() == coords.last();
+        for idx in way.layers {
+            let layer = &mut layers[idx];
+            let geometry = match layer.schema.geometry {
+                OsmGeometryType::Polygon if closed => {
+                    Geometry::Polygons(vec![vec![coords.clone()]])
+                }
+                OsmGeometryType::Line => Geometry::Lines(vec![coords.clone()]),
+                _ => continue,
+            };
+            add_feature(layer, way.id, geometry, &way.tags);
+        }
+    }
+    Ok(())
+}
+
+fn truncated() -> ReadError {
+    ReadError::Invalid("truncated blob".to_string())
+}
+
+fn read_block(
+    block: &PrimitiveBlock,
+    layers: &mut [OsmLayer],
+    nodes: &mut HashMap<i64, Coord>,
+    ways: &mut Vec<PendingWay>,
+) {
+    let strings: Vec<String> = block
+        .stringtable
+        .s
+        .iter()
+        .map(|v| String::from_utf8_lossy(v).to_string())
+        .collect();
+    let string = |idx: u32| strings.get(idx as usize).cloned().unwrap_or_default();
+    let tags = |keys: &[u32], vals: &[u32]| -> Vec<(String, String)> {
+        keys.iter()
+            .zip(vals)
+            .map(|(k, v)| (string(*k), string(*v)))
+            .collect()
+    };
+    // coordinates are stored in units of nanodegrees multiplied by the granularity
+    #[allow(clippy::cast_precision_loss)]
+    let coord = |lon: i64, lat: i64| -> Coord {
+        let granularity = i64::from(block.granularity());
+        [
+            (block.lon_offset() + granularity * lon) as f64 * 1e-9,
+            (block.lat_offset() + granularity * lat) as f64 * 1e-9,
+        ]
+    };
+    for group in &block.primitivegroup {
+        for node in &group.nodes {
+            let tags = tags(&node.keys, &node.vals);
+            add_node(layers, nodes, node.id, coord(node.lon, node.lat), &tags);
+        }
+        if let Some(dense) = &group.dense {
+            // the ids and the coordinates are delta-encoded, and the tags of each node end with a 0
+            let mut keys_vals = dense.keys_vals.iter();
+            let (mut id, mut lat, mut lon) = (0, 0, 0);
+            for ((d_id, d_lat), d_lon) in dense.id.iter().zip(&dense.lat).zip(&dense.lon) {
+                id += d_id;
+                lat += d_lat;
+                lon += d_lon;
+                let mut tags = Vec::new();
+                while let Some(&key) = keys_vals.next().filter(|v| **v != 0) {
+                    let Some(&value) = keys_vals.next() else {
+                        break;
+                    };
+                    tags.push((
+                        string(u32::try_from(key).unwrap_or_default()),
+                        string(u32::try_from(value).unwrap_or_default()),
+                    ));
+                }
+                add_node(layers, nodes, id, coord(lon, lat), &tags);
+            }
+        }
+        for way in &group.ways {
+            let tags = tags(&way.keys, &way.vals);
+            let matching: Vec<_> = layers
+                .iter()
+                .enumerate()
+                .filter(|(_, l)| l.schema.geometry != OsmGeometryType::Point)
+                .filter(|(_, l)| l.schema.matches(&tags))
+                .map(|(idx, _)| idx)
+                .collect();
+            if !matching.is_empty() {
+                let mut refs = Vec::with_capacity(way.refs.len());
+                let mut node = 0;
+                for delta in &way.refs {
+                    node += delta;
+                    refs.push(node);
+                }
+                ways.push(PendingWay {
+                    id: way.id,
+                    refs,
+                    tags,
+                    layers: matching,
+                });
+            }
+        }
+    }
+}
+
+fn add_node(
+    layers: &mut [OsmLayer],
+    nodes: &mut HashMap<i64, Coord>,
+    id: i64,
+    coord: Coord,
+    tags: &[(String, String)],
+) {
+    nodes.insert(id, coord);
+    if tags.is_empty() {
+        return;
+    }
+    for layer in layers {
+        if layer.schema.geometry == OsmGeometryType::Point && layer.schema.matches(tags) {
+            add_feature(layer, id, Geometry::Points(vec![coord]), tags);
+        }
+    }
+}
+
+fn add_feature(layer: &mut OsmLayer, id: i64, geometry: Geometry, tags: &[(String, String)]) {
+    layer.features.push(OsmFeature {
+        // negative ids are used by the editors for the new elements, which are not in the extracts
+        id: u64::try_from(id).unwrap_or_default(),
+        bbox: geometry.bbox(),
+        geometry,
+        props: layer.schema.properties(tags),
+    });
+}
+
+// The messages below are the parts of the fileformat.proto and osmformat.proto schemas needed
+// to read the nodes and the ways, see https://github.com/openstreetmap/OSM-binary
+
+#[derive(Clone, PartialEq, Message)]
+struct BlobHeader {
+    #[prost(string, required, tag = "1")]
+    r#type: String,
+    #[prost(int32, required, tag = "3")]
+    datasize: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Blob {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    raw: Option<Vec<u8>>,
+    #[prost(int32, optional, tag = "2")]
+    raw_size: Option<i32>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    zlib_data: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "7")]
+    zstd_data: Option<Vec<u8>>,
+}
+
+impl Blob {
+    fn into_data(self) -> Result<Vec<u8>, ReadError> {
+        let err = |e: std::io::Error| ReadError::Invalid(e.to_string());
+        let raw_size = usize::try_from(self.raw_size()).unwrap_or(0);
+        if let Some(raw) = self.raw {
+            Ok(raw)
+        } else if let Some(data) = self.zlib_data {
+            let mut result = Vec::with_capacity(raw_size);
+            ZlibDecoder::new(data.as_slice())
+                .read_to_end(&mut result)
+                .map_err(err)?;
+            Ok(result)
+        } else if let Some(data) = self.zstd_data {
+            decode_zstd(&data).map_err(err)
+        } else {
+            Err(ReadError::Unsupported("blob compression".to_string()))
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HeaderBlock {
+    #[prost(string, repeated, tag = "4")]
+    required_features: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PrimitiveBlock {
+    #[prost(message, required, tag = "1")]
+    stringtable: StringTable,
+    #[prost(message, repeated, tag = "2")]
+    primitivegroup: Vec<PrimitiveGroup>,
+    #[prost(int32, optional, tag = "17", default = "100")]
+    granularity: Option<i32>,
+    #[prost(int64, optional, tag = "19", default = "0")]
+    lat_offset: Option<i64>,
+    #[prost(int64, optional, tag = "20", default = "0")]
+    lon_offset: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StringTable {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    s: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PrimitiveGroup {
+    #[prost(message, repeated, tag = "1")]
+    nodes: Vec<Node>,
+    #[prost(message, optional, tag = "2")]
+    dense: Option<DenseNodes>,
+    #[prost(message, repeated, tag = "3")]
+    ways: Vec<Way>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Node {
+    #[prost(sint64, required, tag = "1")]
+    id: i64,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    keys: Vec<u32>,
+    #[prost(uint32, repeated, packed = "true", tag = "3")]
+    vals: Vec<u32>,
+    #[prost(sint64, required, tag = "8")]
+    lat: i64,
+    #[prost(sint64, required, tag = "9")]
+    lon: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DenseNodes {
+    #[prost(sint64, repeated, packed = "true", tag = "1")]
+    id: Vec<i64>,
+    #[prost(sint64, repeated, packed = "true", tag = "8")]
+    lat: Vec<i64>,
+    #[prost(sint64, repeated, packed = "true", tag = "9")]
+    lon: Vec<i64>,
+    #[prost(int32, repeated, packed = "true", tag = "10")]
+    keys_vals: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Way {
+    #[prost(int64, required, tag = "1")]
+    id: i64,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    keys: Vec<u32>,
+    #[prost(uint32, repeated, packed = "true", tag = "3")]
+    vals: Vec<u32>,
+    #[prost(sint64, repeated, packed = "true", tag = "8")]
+    refs: Vec<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::utils::mvt::Tile;
+
+    fn write_blob(file: &mut Vec<u8>, kind: &str, data: &[u8], compress: bool) {
+        let blob = if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            Blob {
+                raw_size: Some(i32::try_from(data.len()).unwrap()),
+                zlib_data: Some(encoder.finish().unwrap()),
+                ..Blob::default()
+            }
+        } else {
+            Blob {
+                raw: Some(data.to_vec()),
+                ..Blob::default()
+            }
+        }
+        .encode_to_vec();
+        let header = BlobHeader {
+            r#type: kind.to_string(),
+            datasize: i32::try_from(blob.len()).unwrap(),
+        }
+        .encode_to_vec();
+        file.extend(u32::try_from(header.len()).unwrap().to_be_bytes());
+        file.extend(header);
+        file.extend(blob);
+    }
+
+    /// A cafe as a dense node, a square building, and a road that starts in the building corner
+    fn write_file(path: &std::path::Path) {
+        let strings = ["", "amenity", "cafe", "name", "Corner", "building", "yes"];
+        let block = PrimitiveBlock {
+            stringtable: StringTable {
+                s: strings.iter().map(|s| s.as_bytes().to_vec()).collect(),
+            },
+            primitivegroup: vec![
+                PrimitiveGroup {
+                    dense: Some(DenseNodes {
+                        id: vec![1, 1, 1, 1],
+                        // in units of 100 nanodegrees, i.e. 0.01 degrees is 100_000
+                        lat: vec![0, 100_000, 0, -100_000],
+                        lon: vec![0, 0, 100_000, 0],
+                        keys_vals: vec![0, 1, 2, 3, 4, 0, 0, 0],
+                    }),
+                    ..PrimitiveGroup::default()
+                },
+                PrimitiveGroup {
+                    nodes: vec![Node {
+                        id: 5,
+                        lat: 5_000_000,
+                        lon: 5_000_000,
+                        ..Node::default()
+                    }],
+                    ways: vec![
+                        Way {
+                            id: 10,
+                            keys: vec![5],
+                            vals: vec![6],
+                            refs: vec![1, 1, 1, 1, -3],
+                        },
+                        Way {
+                            id: 11,
+                            keys: vec![7],
+                            vals: vec![3],
+                            refs: vec![1, 4],
+                        },
+                    ],
+                    ..PrimitiveGroup::default()
+                },
+            ],
+            ..PrimitiveBlock::default()
+        };
+        let mut file = Vec::new();
+        let header = HeaderBlock {
+            required_features: vec!["OsmSchema-V0.6".to_string(), "DenseNodes".to_string()],
+        };
+        write_blob(&mut file, "OSMHeader", &header.encode_to_vec(), false);
+        write_blob(&mut file, "OSMData", &block.encode_to_vec(), true);
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn osm_tiles() {
+        let path = std::env::temp_dir().join(format!("martin-{}.osm.pbf", std::process::id()));
+        write_file(&path);
+        let layers = BTreeMap::from([
+            (
+                "pois".to_string(),
+                OsmLayerConfig::new(OsmGeometryType::Point, &["amenity=cafe"], 0),
+            ),
+            (
+                "buildings".to_string(),
+                OsmLayerConfig::new(OsmGeometryType::Polygon, &["building"], 0),
+            ),
+            (
+                "roads".to_string(),
+                OsmLayerConfig {
+                    tags: Some(vec!["highway".to_string()]),
+                    ..OsmLayerConfig::new(OsmGeometryType::Line, &["highway"], 10)
+                },
+            ),
+        ]);
+        let src = OsmSource::new("city".to_string(), path.clone(), Some(layers)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let count = |name: &str| {
+            src.layers
+                .iter()
+                .find(|l| l.schema.name == name)
+                .unwrap()
+                .features
+                .len()
+        };
+        assert_eq!(count("pois"), 1);
+        assert_eq!(count("buildings"), 1);
+        // the way with key 7 refers to a string that does not exist, and matches nothing
+        assert_eq!(count("roads"), 0);
+
+        let tj = src.get_tilejson();
+        let bounds = tj.bounds.unwrap();
+        assert!((bounds.left - 0.0).abs() < 1e-9 && (bounds.top - 0.01).abs() < 1e-9);
+        let vector_layers = tj.vector_layers.as_ref().unwrap();
+        assert_eq!(vector_layers.len(), 3);
+        assert_eq!(vector_layers[0].id, "buildings");
+        assert!(vector_layers[0].fields.contains_key("building"));
+        assert!(vector_layers[0].fields.contains_key("name"));
+        assert_eq!(vector_layers[2].minzoom, Some(10));
+
+        let xyz = TileCoord {
+            z: 14,
+            x: 8192,
+            y: 8191,
+        };
+        let tile = Tile::decode(src.read_tile(xyz).as_slice()).unwrap();
+        let names: Vec<_> = tile.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["buildings", "pois"]);
+        let cafe = &tile.layers[1];
+        assert_eq!(cafe.features[0].id, Some(2));
+        assert_eq!(cafe.keys, vec!["amenity", "name"]);
+        assert!(src.read_tile(TileCoord { z: 2, x: 0, y: 0 }).is_empty());
+    }
+
+    #[test]
+    fn osm_unsupported_feature() {
+        let path = std::env::temp_dir().join(format!("martin-{}-hist.osm.pbf", std::process::id()));
+        let mut file = Vec::new();
+        let header = HeaderBlock {
+            required_features: vec!["HistoricalInformation".to_string()],
+        };
+        write_blob(&mut file, "OSMHeader", &header.encode_to_vec(), false);
+        std::fs::write(&path, file).unwrap();
+        let result = OsmSource::new("hist".to_string(), path.clone(), None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(UnsupportedFeature(..))));
+    }
+}
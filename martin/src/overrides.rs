@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use log::info;
+use martin_tile_utils::TileInfo;
+use serde::{Deserialize, Serialize};
+use tilejson::{Bounds, Center, TileJSON};
+
+use crate::source::{PoolStatus, Source, TileData, TileInfoSources, UrlQuery};
+use crate::utils::MAX_ZOOM;
+use crate::{MartinResult, TileCoord, TileMatrixSet};
+
+pub type OverrideResult<T> = Result<T, OverrideError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OverrideError {
+    #[error("Overrides are configured for source {0}, but there is no such source")]
+    UnknownSource(String),
+
+    #[error("Zoom {1} of source {0} is above the maximum zoom {MAX_ZOOM}")]
+    InvalidZoom(String, u8),
+
+    #[error("Minzoom {1} of source {0} is above its maxzoom {2}")]
+    InvalidZoomRange(String, u8, u8),
+}
+
+/// Settings of a source that replace the ones it was created with, e.g. of an auto-discovered table
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceOverride {
+    /// Lowest zoom level served by the source. The tiles below it are empty.
+    pub minzoom: Option<u8>,
+    /// Highest zoom level served by the source. The tiles above it are empty.
+    pub maxzoom: Option<u8>,
+    /// Serve the source, but do not list it in the catalog
+    pub hidden: Option<bool>,
+    /// `TileJSON` name, also shown in the catalog
+    pub name: Option<String>,
+    /// `TileJSON` description, also shown in the catalog
+    pub description: Option<String>,
+    /// `TileJSON` attribution, also shown in the catalog
+    pub attribution: Option<String>,
+    /// `TileJSON` default center and zoom, as `[lon, lat, zoom]`
+    pub center: Option<Center>,
+    /// `TileJSON` bounds, as `[west, south, east, north]`
+    pub bounds: Option<Bounds>,
+    /// `TileJSON` legend, e.g. an HTML or a Markdown text
+    pub legend: Option<String>,
+}
+
+impl SourceOverride {
+    /// Check if the source must be wrapped, i.e. anything but its visibility is overridden
+    #[must_use]
+    pub fn changes_source(&self) -> bool {
+        self.minzoom.is_some()
+            || self.maxzoom.is_some()
+            || self.name.is_some()
+            || self.description.is_some()
+            || self.attribution.is_some()
+            || self.center.is_some()
+            || self.bounds.is_some()
+            || self.legend.is_some()
+    }
+}
+
+pub type SourceOverrides = BTreeMap<String, SourceOverride>;
+
+/// Wrap the configured sources, so that their zoom levels are limited to the configured ones,
+/// and their `TileJSON` has the configured metadata
+pub fn apply_overrides(
+    sources: Vec<TileInfoSources>,
+    configs: &SourceOverrides,
+) -> MartinResult<Vec<TileInfoSources>> {
+    if let Some(id) = configs.keys().find(|id| {
+        !sources
+            .iter()
+            .flatten()
+            .any(|src| src.get_id() == id.as_str())
+    }) {
+        return Err(OverrideError::UnknownSource(id.clone()).into());
+    }
+
+    let mut result = Vec::with_capacity(sources.len());
+    for group in sources {
+        let mut wrapped = TileInfoSources::default();
+        for src in group {
+            match configs.get(src.get_id()) {
+                Some(cfg) if cfg.changes_source() => {
+                    wrapped.push(Box::new(OverrideSource::new(src, cfg)?));
+                }
+                _ => wrapped.push(src),
+            }
+        }
+        result.push(wrapped);
+    }
+    Ok(result)
+}
+
+/// A source with the configured `TileJSON` metadata, that only serves the tiles within the configured zoom
+/// levels. The tiles of the other zoom levels are not requested from the wrapped source, even by the
+/// prefetching and the scheduled jobs.
+#[derive(Debug)]
+pub struct OverrideSource {
+    source: Box<dyn Source>,
+    tilejson: TileJSON,
+}
+
+impl Clone for OverrideSource {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_source(),
+            tilejson: self.tilejson.clone(),
+        }
+    }
+}
+
+impl OverrideSource {
+    pub fn new(source: Box<dyn Source>, cfg: &SourceOverride) -> OverrideResult<Self> {
+        let id = source.get_id().to_string();
+        for zoom in [cfg.minzoom, cfg.maxzoom].into_iter().flatten() {
+            if zoom > MAX_ZOOM {
+                return Err(OverrideError::InvalidZoom(id, zoom));
+            }
+        }
+        let mut tilejson = source.get_tilejson().clone();
+        tilejson.minzoom = cfg.minzoom.or(tilejson.minzoom);
+        tilejson.maxzoom = cfg.maxzoom.or(tilejson.maxzoom);
+        if let (Some(minzoom), Some(maxzoom)) = (tilejson.minzoom, tilejson.maxzoom) {
+            if minzoom > maxzoom {
+                return Err(OverrideError::InvalidZoomRange(id, minzoom, maxzoom));
+            }
+        }
+        if cfg.minzoom.is_some() || cfg.maxzoom.is_some() {
+            info!(
+                "Serving source {id} from zoom {} to zoom {}",
+                tilejson.minzoom.unwrap_or(0),
+                tilejson.maxzoom.unwrap_or(MAX_ZOOM)
+            );
+        }
+        tilejson.name = cfg.name.clone().or(tilejson.name);
+        tilejson.description = cfg.description.clone().or(tilejson.description);
+        tilejson.attribution = cfg.attribution.clone().or(tilejson.attribution);
+        tilejson.center = cfg.center.or(tilejson.center);
+        tilejson.bounds = cfg.bounds.or(tilejson.bounds);
+        tilejson.legend = cfg.legend.clone().or(tilejson.legend);
+        Ok(Self { source, tilejson })
+    }
+}
+
+#[async_trait]
+impl Source for OverrideSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn support_utfgrid(&self) -> bool {
+        self.source.support_utfgrid()
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.source.is_cacheable()
+    }
+
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.source.get_tile_matrix_set()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        if !self.is_valid_zoom(xyz.z) {
+            return Ok(TileData::new());
+        }
+        self.source.get_tile(xyz, url_query).await
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        self.source.get_pool_status()
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TableSource {
+        tj: TileJSON,
+    }
+
+    #[async_trait]
+    impl Source for TableSource {
+        fn get_id(&self) -> &str {
+            "table"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            assert!((8..=14).contains(&xyz.z), "tile {xyz} is not allowed");
+            Ok(vec![1, 2, 3])
+        }
+    }
+
+    fn table() -> Box<dyn Source> {
+        Box::new(TableSource {
+            tj: tilejson! { tiles: vec![], minzoom: 0, maxzoom: 20 },
+        })
+    }
+
+    #[actix_rt::test]
+    async fn limit_zoom() {
+        let cfg = SourceOverride {
+            minzoom: Some(8),
+            maxzoom: Some(14),
+            ..Default::default()
+        };
+        let src = OverrideSource::new(table(), &cfg).unwrap();
+        assert_eq!(src.get_tilejson().minzoom, Some(8));
+        assert_eq!(src.get_tilejson().maxzoom, Some(14));
+        assert!(!src.is_valid_zoom(7));
+        assert!(!src.is_valid_zoom(15));
+        let tile = |z| TileCoord { z, x: 0, y: 0 };
+        assert_eq!(src.get_tile(&tile(8), &None).await.unwrap(), vec![1, 2, 3]);
+        assert!(src.get_tile(&tile(7), &None).await.unwrap().is_empty());
+        assert!(src.get_tile(&tile(20), &None).await.unwrap().is_empty());
+
+        // only the maxzoom is replaced
+        let cfg = SourceOverride {
+            maxzoom: Some(14),
+            ..Default::default()
+        };
+        let src = OverrideSource::new(table(), &cfg).unwrap();
+        assert_eq!(src.get_tilejson().minzoom, Some(0));
+
+        let cfg = SourceOverride {
+            minzoom: Some(21),
+            ..Default::default()
+        };
+        let result = OverrideSource::new(table(), &cfg);
+        assert!(matches!(result, Err(OverrideError::InvalidZoomRange(..))));
+        let cfg = SourceOverride {
+            maxzoom: Some(31),
+            ..Default::default()
+        };
+        let result = OverrideSource::new(table(), &cfg);
+        assert!(matches!(result, Err(OverrideError::InvalidZoom(..))));
+
+        let configs = SourceOverrides::from([("other".to_string(), SourceOverride::default())]);
+        let result = apply_overrides(vec![vec![table()]], &configs);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn override_metadata() {
+        let cfg: SourceOverride = serde_yaml::from_str(indoc! {"
+            attribution: '© OpenStreetMap contributors'
+            center: [8.5, 47.4, 12]
+            bounds: [5.9, 45.8, 10.5, 47.8]
+        "})
+        .unwrap();
+        assert!(cfg.changes_source());
+        let src = OverrideSource::new(table(), &cfg).unwrap();
+        let tj = src.get_tilejson();
+        assert_eq!(
+            tj.attribution.as_deref(),
+            Some("© OpenStreetMap contributors")
+        );
+        assert_eq!(tj.center, Some(Center::new(8.5, 47.4, 12)));
+        assert_eq!(tj.bounds, Some(Bounds::new(5.9, 45.8, 10.5, 47.8)));
+        // the other fields are kept
+        assert_eq!(tj.maxzoom, Some(20));
+        assert_eq!(tj.name, None);
+        let entry = src.get_catalog_entry();
+        assert_eq!(entry.attribution, tj.attribution);
+
+        let hidden = SourceOverride {
+            hidden: Some(true),
+            ..Default::default()
+        };
+        assert!(!hidden.changes_source());
+
+        let configs = SourceOverrides::from([("table".to_string(), cfg)]);
+        let sources = apply_overrides(vec![vec![table()]], &configs).unwrap();
+        assert_eq!(sources[0][0].get_tilejson().center, tj.center);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use async_trait::async_trait;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageOutputFormat};
+use log::info;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{PoolStatus, Source, TileData, TileInfoSources, UrlQuery};
+use crate::utils::mvt::overzoom_tile;
+use crate::utils::{decode_gzip, encode_gzip, MAX_ZOOM};
+use crate::{MartinResult, TileCoord, TileMatrixSet};
+
+pub type OverzoomResult<T> = Result<T, OverzoomError>;
+
+pub const OVERZOOM_MAX_DELTA_DEFAULT: u8 = 4;
+/// Highest zoom level that overzoomed tiles are served for
+pub const OVERZOOM_MAX_ZOOM: u8 = MAX_ZOOM;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OverzoomError {
+    #[error("Overzoom is configured for source {0}, but there is no such source")]
+    UnknownSource(String),
+
+    #[error("Source {0} has no maxzoom, so its tiles cannot be overzoomed")]
+    NoMaxZoom(String),
+
+    #[error("Only vector tiles and uncompressed PNG and JPEG tiles can be clipped, but source {1} has {0} tiles. Use the parent mode instead.")]
+    UnsupportedFormat(TileInfo, String),
+
+    #[error("Unable to clip vector tile {1} of source {2}: {0}")]
+    VectorError(String, TileCoord, String),
+
+    #[error("Unable to clip image tile {1} of source {2}: {0}")]
+    ImageError(image::ImageError, TileCoord, String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverzoomMode {
+    /// Scale the parent tile at the source maxzoom, and clip it to the requested tile
+    #[default]
+    Clip,
+    /// Return the parent tile at the source maxzoom as is, for the clients that overzoom it themselves
+    Parent,
+}
+
+/// Serving the tiles above the maxzoom of a source, generated from its tiles at maxzoom
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverzoomConfig {
+    /// How to generate the tiles above maxzoom [default: clip]
+    pub mode: Option<OverzoomMode>,
+    /// Number of zoom levels served above the source maxzoom [default: 4]
+    pub max_delta: Option<u8>,
+}
+
+pub type OverzoomConfigs = BTreeMap<String, OverzoomConfig>;
+
+/// Wrap the configured sources, so that their tiles above maxzoom are generated from the maxzoom tiles
+pub fn apply_overzoom(
+    sources: Vec<TileInfoSources>,
+    configs: &OverzoomConfigs,
+) -> MartinResult<Vec<TileInfoSources>> {
+    if let Some(id) = configs.keys().find(|id| {
+        !sources
+            .iter()
+            .flatten()
+            .any(|src| src.get_id() == id.as_str())
+    }) {
+        return Err(OverzoomError::UnknownSource(id.clone()).into());
+    }
+
+    let mut result = Vec::with_capacity(sources.len());
+    for group in sources {
+        let mut wrapped = TileInfoSources::default();
+        for src in group {
+            match configs.get(src.get_id()) {
+                Some(cfg) => wrapped.push(Box::new(OverzoomSource::new(src, cfg)?)),
+                None => wrapped.push(src),
+            }
+        }
+        result.push(wrapped);
+    }
+    Ok(result)
+}
+
+/// A source whose tiles above its original maxzoom are generated from the tiles at that maxzoom
+#[derive(Debug)]
+pub struct OverzoomSource {
+    source: Box<dyn Source>,
+    tilejson: TileJSON,
+    maxzoom: u8,
+    mode: OverzoomMode,
+}
+
+impl Clone for OverzoomSource {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_source(),
+            tilejson: self.tilejson.clone(),
+            maxzoom: self.maxzoom,
+            mode: self.mode,
+        }
+    }
+}
+
+impl OverzoomSource {
+    pub fn new(source: Box<dyn Source>, cfg: &OverzoomConfig) -> OverzoomResult<Self> {
+        let id = source.get_id().to_string();
+        let maxzoom = source
+            .get_tilejson()
+            .maxzoom
+            .ok_or_else(|| OverzoomError::NoMaxZoom(id.clone()))?;
+        let mode = cfg.mode.unwrap_or_default();
+        let info = source.get_tile_info();
+        if mode == OverzoomMode::Clip && !can_clip(info) {
+            return Err(OverzoomError::UnsupportedFormat(info, id));
+        }
+        let mut tilejson = source.get_tilejson().clone();
+        let delta = cfg.max_delta.unwrap_or(OVERZOOM_MAX_DELTA_DEFAULT);
+        tilejson.maxzoom = Some(maxzoom.saturating_add(delta).min(OVERZOOM_MAX_ZOOM));
+        info!(
+            "Serving the tiles of source {id} above zoom {maxzoom} up to zoom {} ({mode:?} mode)",
+            tilejson.maxzoom.unwrap_or_default()
+        );
+        Ok(Self {
+            source,
+            tilejson,
+            maxzoom,
+            mode,
+        })
+    }
+
+    /// Generate the tile from its ancestor at maxzoom, in the same format and encoding
+    fn clip_tile(
+        &self,
+        data: &[u8],
+        parent: TileCoord,
+        xyz: TileCoord,
+    ) -> OverzoomResult<TileData> {
+        let id = || self.source.get_id().to_string();
+        let info = self.source.get_tile_info();
+        if info.format == Format::Mvt {
+            let err = |e: String| OverzoomError::VectorError(e, xyz, id());
+            return if info.encoding == Encoding::Gzip {
+                let data = decode_gzip(data).map_err(|e| err(e.to_string()))?;
+                let tile = overzoom_tile(&data, parent, xyz).map_err(|e| err(e.to_string()))?;
+                encode_gzip(&tile).map_err(|e| err(e.to_string()))
+            } else {
+                overzoom_tile(data, parent, xyz).map_err(|e| err(e.to_string()))
+            };
+        }
+
+        let err = |e| OverzoomError::ImageError(e, xyz, id());
+        let (format, output) = match info.format {
+            Format::Jpeg => (ImageFormat::Jpeg, ImageOutputFormat::Jpeg(90)),
+            _ => (ImageFormat::Png, ImageOutputFormat::Png),
+        };
+        let image = image::load_from_memory_with_format(data, format).map_err(err)?;
+        let (width, height) = (image.width(), image.height());
+        let dz = xyz.z - parent.z;
+        let (size_x, size_y) = ((width >> dz).max(1), (height >> dz).max(1));
+        let (col, row) = (xyz.x - (parent.x << dz), xyz.y - (parent.y << dz));
+        let tile = image
+            .crop_imm(col * size_x, row * size_y, size_x, size_y)
+            .resize_exact(width, height, FilterType::Triangle);
+        let mut result = Cursor::new(Vec::new());
+        tile.write_to(&mut result, output).map_err(err)?;
+        Ok(result.into_inner())
+    }
+}
+
+fn can_clip(info: TileInfo) -> bool {
+    match info.format {
+        Format::Mvt => matches!(info.encoding, Encoding::Uncompressed | Encoding::Gzip),
+        Format::Png | Format::Jpeg => !info.encoding.is_encoded(),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl Source for OverzoomSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.source.is_cacheable()
+    }
+
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.source.get_tile_matrix_set()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        if xyz.z <= self.maxzoom {
+            return self.source.get_tile(xyz, url_query).await;
+        }
+        let dz = xyz.z - self.maxzoom;
+        let parent = TileCoord {
+            z: self.maxzoom,
+            x: xyz.x >> dz,
+            y: xyz.y >> dz,
+        };
+        let data = self.source.get_tile(&parent, url_query).await?;
+        if data.is_empty() || self.mode == OverzoomMode::Parent {
+            return Ok(data);
+        }
+        Ok(self.clip_tile(&data, parent, *xyz)?)
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        self.source.get_pool_status()
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView as _, Rgba, RgbaImage};
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::utils::mvt::{encode_tile, LayerBuilder, Tile, TileGeometry};
+
+    #[derive(Debug, Clone)]
+    struct ParentSource {
+        tj: TileJSON,
+        info: TileInfo,
+        data: TileData,
+    }
+
+    #[async_trait]
+    impl Source for ParentSource {
+        fn get_id(&self) -> &str {
+            "parent"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            self.info
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            assert!(xyz.z <= 2, "tile {xyz} is above maxzoom");
+            Ok(self.data.clone())
+        }
+    }
+
+    fn source(info: TileInfo, data: TileData, mode: OverzoomMode) -> OverzoomSource {
+        let src = ParentSource {
+            tj: tilejson! { tiles: vec![], maxzoom: 2 },
+            info,
+            data,
+        };
+        let cfg = OverzoomConfig {
+            mode: Some(mode),
+            max_delta: Some(2),
+        };
+        OverzoomSource::new(Box::new(src), &cfg).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn overzoom_vector_tiles() {
+        let mut layer = LayerBuilder::new("points", 4096);
+        layer.add_feature(
+            None,
+            &TileGeometry::Points(vec![[100, 100], [3000, 3000]]),
+            &[],
+        );
+        let data = encode_tile(vec![layer.build()]);
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+
+        let src = source(info, data.clone(), OverzoomMode::Clip);
+        assert_eq!(src.get_tilejson().maxzoom, Some(4));
+        assert!(!src.is_valid_zoom(5));
+        assert_eq!(
+            src.get_tile(&TileCoord { z: 2, x: 0, y: 0 }, &None)
+                .await
+                .unwrap(),
+            data
+        );
+        // only the first point is in the top left child tile
+        let tile = src
+            .get_tile(&TileCoord { z: 3, x: 0, y: 0 }, &None)
+            .await
+            .unwrap();
+        let tile = <Tile as prost::Message>::decode(tile.as_slice()).unwrap();
+        assert_eq!(tile.layers[0].features.len(), 1);
+
+        let src = source(info, data.clone(), OverzoomMode::Parent);
+        let tile = src
+            .get_tile(&TileCoord { z: 4, x: 3, y: 3 }, &None)
+            .await
+            .unwrap();
+        assert_eq!(tile, data);
+    }
+
+    #[actix_rt::test]
+    async fn overzoom_image_tiles() {
+        let mut image = RgbaImage::from_pixel(256, 256, Rgba([0, 0, 255, 255]));
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if x >= 128 && y < 128 {
+                *pixel = Rgba([255, 0, 0, 255]);
+            }
+        }
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, ImageOutputFormat::Png).unwrap();
+        let info = TileInfo::new(Format::Png, Encoding::Internal);
+        let src = source(info, data.into_inner(), OverzoomMode::Clip);
+
+        // the top right child tile is all red
+        let tile = src
+            .get_tile(&TileCoord { z: 3, x: 1, y: 0 }, &None)
+            .await
+            .unwrap();
+        let tile = image::load_from_memory(&tile).unwrap();
+        assert_eq!(tile.dimensions(), (256, 256));
+        assert_eq!(tile.get_pixel(10, 200), Rgba([255, 0, 0, 255]));
+
+        let webp = TileInfo::new(Format::Webp, Encoding::Internal);
+        let src = ParentSource {
+            tj: tilejson! { tiles: vec![], maxzoom: 2 },
+            info: webp,
+            data: Vec::new(),
+        };
+        let result = OverzoomSource::new(Box::new(src), &OverzoomConfig::default());
+        assert!(matches!(result, Err(OverzoomError::UnsupportedFormat(..))));
+    }
+}
@@ -1,24 +1,71 @@
 use std::ops::Add;
 use std::time::Duration;
 
-use futures::future::try_join;
+use futures::future::try_join3;
 use log::warn;
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
 
 use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::config::{copy_unrecognized_config, UnrecognizedValues};
-use crate::pg::config_function::FuncInfoSources;
+use crate::pg::config_function::{ClaimConfigs, FuncInfoSources, QueryParamTypes};
+use crate::pg::config_raster::RasterInfoSources;
 use crate::pg::config_table::TableInfoSources;
 use crate::pg::configurator::PgBuilder;
+use crate::pg::PgError::{InvalidExtent, InvalidRediscoverInterval};
 use crate::pg::PgResult;
 use crate::source::TileInfoSources;
 use crate::utils::{on_slow, IdResolver, OptBoolObj, OptOneMany};
-use crate::MartinResult;
+use crate::{MartinResult, TileMatrixSet};
 
 pub trait PgInfo {
     fn format_id(&self) -> String;
     fn to_tilejson(&self, source_id: String) -> TileJSON;
+
+    /// JWT claims passed to the source, keyed by the claim name
+    fn claims(&self) -> Option<&ClaimConfigs> {
+        None
+    }
+
+    /// Types of the URL query parameters passed to the source, keyed by the parameter name
+    fn query_params(&self) -> Option<&QueryParamTypes> {
+        None
+    }
+
+    /// Number of zoom levels above `maxzoom` that are generated from the `maxzoom` tiles
+    fn overzoom(&self) -> Option<u8> {
+        None
+    }
+
+    /// Settings of the dedicated connection pool of the source
+    fn pool(&self) -> Option<&PgPoolConfig> {
+        None
+    }
+
+    /// Whether `UTFGrid` interaction tiles are generated from the vector tiles of the source
+    fn utfgrid(&self) -> bool {
+        false
+    }
+
+    /// Tile grid that the tiles of the source are generated for
+    fn tile_matrix_set(&self) -> TileMatrixSet {
+        TileMatrixSet::default()
+    }
+
+    /// Materialized views that the source depends on, refreshed on demand or on a schedule
+    fn refresh_views(&self) -> Option<&Vec<String>> {
+        None
+    }
+
+    /// Maximum time in milliseconds of each tile query, after which the server cancels it
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        None
+    }
+
+    /// Name of the tile queries of the source in `pg_stat_activity` and in the server logs
+    fn application_name(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[serde_with::skip_serializing_none]
@@ -39,16 +86,66 @@ pub struct PgSslCerts {
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PgConfig {
     pub connection_string: Option<String>,
+    /// Connection strings of the read replicas that share the tile queries with the primary server
+    pub replicas: Option<Vec<String>>,
     #[serde(flatten)]
     pub ssl_certificates: PgSslCerts,
     pub default_srid: Option<i32>,
     pub auto_bounds: Option<BoundsCalcType>,
+    /// Maximum time in seconds to compute the bounds of a table or raster in the `quick` and `background` modes.
+    /// Defaults to 5 seconds for `quick`, and no limit for `background`.
+    pub bounds_timeout: Option<u64>,
     pub max_feature_count: Option<usize>,
+    /// Order table features so that identical data always produces byte-identical tiles
+    pub deterministic: Option<bool>,
+    /// Default tile extent of the table sources, unless set by the table or by `auto_publish.tables`
+    pub extent: Option<u32>,
+    /// Default buffer of the table sources, in tile coordinate space
+    pub buffer: Option<u32>,
+    /// Default for clipping the geometries of the table sources to the tile and its buffer
+    pub clip_geom: Option<bool>,
     pub pool_size: Option<usize>,
+    /// Close the pooled connections that have not been used for this many seconds [default: never]
+    pub idle_timeout_secs: Option<u64>,
+    /// Maximum number of prepared statements cached by each connection [default: unlimited]
+    pub statement_cache_size: Option<usize>,
+    /// The connection goes through a pooler in transaction mode, e.g. `PgBouncer` with `pool_mode = transaction`.
+    /// The statements are not cached, and each tile query runs in its own transaction.
+    pub transaction_pooling: Option<bool>,
+    /// Log the tile queries that take longer than this many milliseconds at the WARN level
+    pub slow_query_ms: Option<u64>,
+    /// Default `statement_timeout` in milliseconds of the tile queries, after which the server cancels them
+    /// and the request fails with 504 [default: the server setting]
+    pub statement_timeout_ms: Option<u64>,
+    /// Default `application_name` of the tile queries, shown in `pg_stat_activity` [default: the connection setting]
+    pub application_name: Option<String>,
+    /// Also log the `EXPLAIN` plan of the slow tile queries
+    pub explain_slow_queries: Option<bool>,
+    /// Channel to LISTEN on for the NOTIFY events that purge cached tiles or rediscover the sources
+    pub notify_channel: Option<String>,
+    /// Discover the tables and functions again every this many seconds, adding the new ones
+    /// and removing the dropped ones, like the `rediscover` scheduled task [default: never]
+    pub rediscover_interval_secs: Option<u64>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub auto_publish: OptBoolObj<PgCfgPublish>,
     pub tables: Option<TableInfoSources>,
     pub functions: Option<FuncInfoSources>,
+    /// Raster columns served as image tiles
+    pub rasters: Option<RasterInfoSources>,
+}
+
+/// Connection pool settings of a single source, which then uses its own pool
+/// instead of the one shared by the other sources of the same config entry.
+/// The settings that are not set are taken from the config entry.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PgPoolConfig {
+    /// Maximum number of connections
+    pub pool_size: Option<usize>,
+    /// Close the connections that have not been used for this many seconds
+    pub idle_timeout_secs: Option<u64>,
+    /// Maximum number of prepared statements cached by each connection
+    pub statement_cache_size: Option<usize>,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -60,6 +157,9 @@ pub struct PgCfgPublish {
     pub tables: OptBoolObj<PgCfgPublishTables>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub functions: OptBoolObj<PgCfgPublishFuncs>,
+    /// Raster columns are only published if enabled here
+    #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
+    pub rasters: OptBoolObj<PgCfgPublishRasters>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -79,6 +179,12 @@ pub struct PgCfgPublishTables {
     pub clip_geom: Option<bool>,
     pub buffer: Option<u32>,
     pub extent: Option<u32>,
+    /// Only publish the tables that match any of these glob or `/regex/` patterns
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub include: OptOneMany<String>,
+    /// Do not publish the tables that match any of these glob or `/regex/` patterns
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub exclude: OptOneMany<String>,
 }
 
 #[serde_with::skip_serializing_none]
@@ -89,6 +195,22 @@ pub struct PgCfgPublishFuncs {
     pub from_schemas: OptOneMany<String>,
     #[serde(alias = "id_format")]
     pub source_id_format: Option<String>,
+    /// Only publish the functions that match any of these glob or `/regex/` patterns
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub include: OptOneMany<String>,
+    /// Do not publish the functions that match any of these glob or `/regex/` patterns
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub exclude: OptOneMany<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PgCfgPublishRasters {
+    #[serde(alias = "from_schema")]
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub from_schemas: OptOneMany<String>,
+    #[serde(alias = "id_format")]
+    pub source_id_format: Option<String>,
 }
 
 impl PgConfig {
@@ -105,7 +227,31 @@ impl PgConfig {
                 copy_unrecognized_config(&mut res, &format!("functions.{k}."), &v.unrecognized);
             }
         }
-        if self.tables.is_none() && self.functions.is_none() && self.auto_publish.is_none() {
+        if let Some(ref rs) = self.rasters {
+            for (k, v) in rs {
+                copy_unrecognized_config(&mut res, &format!("rasters.{k}."), &v.unrecognized);
+            }
+        }
+        if self.extent == Some(0) {
+            return Err(InvalidExtent("postgres".to_string()));
+        }
+        if self.rediscover_interval_secs == Some(0) {
+            return Err(InvalidRediscoverInterval);
+        }
+        if let OptBoolObj::Object(PgCfgPublish {
+            tables: OptBoolObj::Object(v),
+            ..
+        }) = &self.auto_publish
+        {
+            if v.extent == Some(0) {
+                return Err(InvalidExtent("postgres.auto_publish.tables".to_string()));
+            }
+        }
+        if self.tables.is_none()
+            && self.functions.is_none()
+            && self.rasters.is_none()
+            && self.auto_publish.is_none()
+        {
             self.auto_publish = OptBoolObj::Bool(true);
         }
 
@@ -126,12 +272,20 @@ impl PgConfig {
                 }
             },
         );
-        let ((mut tables, tbl_info), (funcs, func_info)) =
-            try_join(inst_tables, pg.instantiate_functions()).await?;
+        let ((mut tables, tbl_info), (funcs, func_info), (rasters, raster_info)) = try_join3(
+            inst_tables,
+            pg.instantiate_functions(),
+            pg.instantiate_rasters(),
+        )
+        .await?;
 
         self.tables = Some(tbl_info);
         self.functions = Some(func_info);
+        if !raster_info.is_empty() || self.rasters.is_some() {
+            self.rasters = Some(raster_info);
+        }
         tables.extend(funcs);
+        tables.extend(rasters);
         Ok(tables)
     }
 }
@@ -147,7 +301,8 @@ mod tests {
     use crate::config::tests::assert_config;
     use crate::config::Config;
     use crate::pg::config_function::FunctionInfo;
-    use crate::pg::config_table::TableInfo;
+    use crate::pg::config_raster::{RasterFormat, Resampling};
+    use crate::pg::config_table::{LabelConfig, PolygonAnchor, TableInfo, ZmAttributes, ZmValue};
     use crate::test_utils::some;
     use crate::utils::OptOneMany::{Many, One};
 
@@ -273,4 +428,219 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn parse_pg_pool() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              pool_size: 20
+              idle_timeout_secs: 300
+              statement_cache_size: 100
+              transaction_pooling: true
+              functions:
+                heavy:
+                  schema: public
+                  function: heavy
+                  pool:
+                    pool_size: 4
+                    statement_cache_size: 10
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgres://postgres@localhost:5432/db"),
+                    pool_size: Some(20),
+                    idle_timeout_secs: Some(300),
+                    statement_cache_size: Some(100),
+                    transaction_pooling: Some(true),
+                    functions: Some(BTreeMap::from([(
+                        "heavy".to_string(),
+                        FunctionInfo {
+                            pool: Some(PgPoolConfig {
+                                pool_size: Some(4),
+                                idle_timeout_secs: None,
+                                statement_cache_size: Some(10),
+                            }),
+                            ..FunctionInfo::new("public".to_string(), "heavy".to_string(), None)
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_statement_timeout() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              statement_timeout_ms: 5000
+              application_name: martin
+              functions:
+                heavy:
+                  schema: public
+                  function: heavy
+                  statement_timeout_ms: 30000
+                  application_name: martin-heavy
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgres://postgres@localhost:5432/db"),
+                    statement_timeout_ms: Some(5000),
+                    application_name: some("martin"),
+                    functions: Some(BTreeMap::from([(
+                        "heavy".to_string(),
+                        FunctionInfo {
+                            statement_timeout_ms: Some(30000),
+                            application_name: some("martin-heavy"),
+                            ..FunctionInfo::new("public".to_string(), "heavy".to_string(), None)
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_labels() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              tables:
+                roads:
+                  schema: public
+                  table: roads
+                  srid: 4326
+                  geometry_column: geom
+                  labels: true
+                parks:
+                  schema: public
+                  table: parks
+                  srid: 4326
+                  geometry_column: geom
+                  labels:
+                    layer: park_names
+                    polygon_anchor: surface
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgres://postgres@localhost:5432/db"),
+                    tables: Some(BTreeMap::from([
+                        (
+                            "roads".to_string(),
+                            TableInfo {
+                                schema: "public".to_string(),
+                                table: "roads".to_string(),
+                                srid: 4326,
+                                geometry_column: "geom".to_string(),
+                                labels: OptBoolObj::Bool(true),
+                                ..Default::default()
+                            },
+                        ),
+                        (
+                            "parks".to_string(),
+                            TableInfo {
+                                schema: "public".to_string(),
+                                table: "parks".to_string(),
+                                srid: 4326,
+                                geometry_column: "geom".to_string(),
+                                labels: OptBoolObj::Object(LabelConfig {
+                                    layer: some("park_names"),
+                                    polygon_anchor: Some(PolygonAnchor::Surface),
+                                }),
+                                ..Default::default()
+                            },
+                        ),
+                    ])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn parse_pg_zm_attributes() {
+        let cfg: Config = serde_yaml::from_str(indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              tables:
+                pipes:
+                  schema: public
+                  table: pipes
+                  srid: 4326
+                  geometry_column: geom
+                  zm_attributes:
+                    z_min: depth_min
+        "})
+        .unwrap();
+        let One(pg) = &cfg.postgres else { panic!() };
+        let pipes = &pg.tables.as_ref().unwrap()["pipes"];
+        assert_eq!(
+            pipes.zm_attributes,
+            OptBoolObj::Object(ZmAttributes {
+                z_min: some("depth_min"),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            pipes.zm_attribute_names(),
+            vec![
+                ("depth_min".to_string(), ZmValue::ZMin),
+                ("elev_max".to_string(), ZmValue::ZMax),
+                ("measure_min".to_string(), ZmValue::MMin),
+                ("measure_max".to_string(), ZmValue::MMax),
+            ]
+        );
+        assert!(TableInfo::default().zm_attribute_names().is_empty());
+    }
+
+    #[test]
+    fn parse_pg_rasters() {
+        let mut cfg: Config = serde_yaml::from_str(indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              auto_publish:
+                rasters:
+                  source_id_format: 'raster_{table}'
+              rasters:
+                dem:
+                  schema: public
+                  table: elevation
+                  raster_column: rast
+                  bands: [1]
+                  colormap: pseudocolor
+                  format: webp
+                  resampling: bilinear
+                  tile_size: 512
+        "})
+        .unwrap();
+        let One(pg) = &mut cfg.postgres else { panic!() };
+        assert!(pg.finalize().unwrap().is_empty());
+        let dem = &pg.rasters.as_ref().unwrap()["dem"];
+        assert_eq!(dem.format_id(), "public.elevation.rast");
+        assert_eq!(dem.srid, 0);
+        assert_eq!(dem.bands, Some(vec![1]));
+        assert_eq!(dem.colormap, some("pseudocolor"));
+        assert_eq!(dem.format, Some(RasterFormat::Webp));
+        assert_eq!(dem.resampling, Some(Resampling::Bilinear));
+        assert_eq!(dem.tile_size, Some(512));
+        let OptBoolObj::Object(publish) = &pg.auto_publish else {
+            panic!()
+        };
+        assert_eq!(
+            publish.rasters,
+            OptBoolObj::Object(PgCfgPublishRasters {
+                source_id_format: some("raster_{table}"),
+                ..Default::default()
+            })
+        );
+    }
 }
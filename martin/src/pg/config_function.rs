@@ -1,12 +1,146 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tilejson::{Bounds, TileJSON};
 
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::overzoom::OVERZOOM_MAX_DELTA_DEFAULT;
+use crate::pg::config::{PgInfo, PgPoolConfig};
 use crate::pg::utils::{patch_json, InfoMap};
+use crate::TileMatrixSet;
 
 pub type FuncInfoSources = InfoMap<FunctionInfo>;
 
+pub type ClaimConfigs = BTreeMap<String, ClaimConfig>;
+
+/// How a validated JWT claim is passed to the function. A request without the claim is rejected.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct ClaimConfig {
+    /// Add the claim to the query parameters of the function under this name,
+    /// replacing any parameter with the same name sent by the client
+    pub param: Option<String>,
+    /// Set the claim as this session variable with `SET LOCAL` for the duration of the call,
+    /// e.g. `request.jwt.sub` to be read with `current_setting()` by row-level security policies
+    pub setting: Option<String>,
+}
+
+pub type QueryParamTypes = BTreeMap<String, QueryParamType>;
+
+/// Type of the values of a URL query parameter passed to a function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryParamKind {
+    String,
+    Int,
+    Float,
+    Bool,
+    /// Any JSON value, e.g. an object
+    Json,
+}
+
+/// Declared type of a URL query parameter, e.g. `int`, or `float[]` for a list of numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QueryParamType {
+    pub kind: QueryParamKind,
+    pub array: bool,
+}
+
+impl QueryParamType {
+    /// Convert the value of the parameter to JSON. Arrays are either comma-separated values,
+    /// e.g. `1,2,3`, or JSON arrays, e.g. `["a,b","c"]`. Returns `None` if the value is invalid.
+    #[must_use]
+    pub fn parse(self, text: &str) -> Option<Value> {
+        if !self.array {
+            return self.parse_item(text);
+        }
+        if text.starts_with('[') || self.kind == QueryParamKind::Json {
+            let values: Vec<Value> = serde_json::from_str(text).ok()?;
+            return values
+                .iter()
+                .all(|v| self.is_item(v))
+                .then_some(Value::Array(values));
+        }
+        if text.is_empty() {
+            return Some(Value::Array(Vec::new()));
+        }
+        text.split(',').map(|v| self.parse_item(v)).collect()
+    }
+
+    fn parse_item(self, text: &str) -> Option<Value> {
+        match self.kind {
+            QueryParamKind::String => Some(Value::from(text)),
+            QueryParamKind::Int => text.trim().parse::<i64>().ok().map(Value::from),
+            QueryParamKind::Float => {
+                let value = text.trim().parse::<f64>().ok()?;
+                value.is_finite().then(|| Value::from(value))
+            }
+            QueryParamKind::Bool => match text.trim() {
+                "true" | "1" => Some(Value::Bool(true)),
+                "false" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            QueryParamKind::Json => serde_json::from_str(text).ok(),
+        }
+    }
+
+    fn is_item(self, value: &Value) -> bool {
+        match self.kind {
+            QueryParamKind::String => value.is_string(),
+            QueryParamKind::Int => value.is_i64(),
+            QueryParamKind::Float => value.is_number(),
+            QueryParamKind::Bool => value.is_boolean(),
+            QueryParamKind::Json => true,
+        }
+    }
+}
+
+impl Display for QueryParamType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            QueryParamKind::String => "string",
+            QueryParamKind::Int => "int",
+            QueryParamKind::Float => "float",
+            QueryParamKind::Bool => "bool",
+            QueryParamKind::Json => "json",
+        };
+        let suffix = if self.array { "[]" } else { "" };
+        write!(f, "{kind}{suffix}")
+    }
+}
+
+impl TryFrom<String> for QueryParamType {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (name, array) = match value.strip_suffix("[]") {
+            Some(name) => (name, true),
+            None => (value.as_str(), false),
+        };
+        let kind = match name {
+            "string" | "text" => QueryParamKind::String,
+            "int" | "integer" => QueryParamKind::Int,
+            "float" | "number" => QueryParamKind::Float,
+            "bool" | "boolean" => QueryParamKind::Bool,
+            "json" => QueryParamKind::Json,
+            _ => {
+                return Err(format!(
+                    "unknown query parameter type {value}, expected string, int, float, bool, or json, optionally followed by []"
+                ))
+            }
+        };
+        Ok(Self { kind, array })
+    }
+}
+
+impl From<QueryParamType> for String {
+    fn from(value: QueryParamType) -> Self {
+        value.to_string()
+    }
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct FunctionInfo {
@@ -28,9 +162,47 @@ pub struct FunctionInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Validated JWT claims passed to the function, keyed by the claim name
+    pub claims: Option<ClaimConfigs>,
+
+    /// Types of the URL query parameters, e.g. `year: int` or `classes: string[]`. The declared parameters
+    /// are passed to the function as JSON values of that type, and the requests with invalid values fail with 400.
+    /// The other parameters are passed as before.
+    pub query_params: Option<QueryParamTypes>,
+
+    /// Generate the tiles above `maxzoom` by scaling and clipping the `maxzoom` tiles,
+    /// instead of calling the function with the deeper zoom levels. Requires `maxzoom` to be set.
+    pub support_overzoom: Option<bool>,
+
+    /// Number of zoom levels above `maxzoom` generated with `support_overzoom` [default: 4]
+    pub overzoom_max_delta: Option<u8>,
+
+    /// Use a separate connection pool with these settings for this source,
+    /// so that expensive functions cannot use up the connections of the other sources
+    pub pool: Option<PgPoolConfig>,
+
+    /// Serve the source by its URL, but do not list it in the catalog
+    pub hidden: Option<bool>,
+
+    /// Tile grid that the function generates the tiles for, either `WebMercatorQuad` or `WorldCRS84Quad`
+    /// [default: `WebMercatorQuad`]. The function must compute the tile envelopes of the grid itself.
+    pub tile_matrix_set: Option<TileMatrixSet>,
+
+    /// Materialized views the function reads from, e.g. `public.roads_mv`, refreshed by `POST /_/refresh/{source}`
+    /// and by the `refresh` scheduled task. The cached tiles of the source are purged after each refresh.
+    pub refresh_views: Option<Vec<String>>,
+
+    /// Maximum time in milliseconds of each tile query, after which the server cancels it
+    /// [default: `statement_timeout_ms` of the connection]
+    pub statement_timeout_ms: Option<u64>,
+
+    /// Name of the tile queries in `pg_stat_activity` and in the server logs, e.g. to find the expensive ones
+    /// [default: `application_name` of the connection]
+    pub application_name: Option<String>,
+
     /// TileJSON provided by the SQL function comment. Not serialized.
     #[serde(skip)]
-    pub tilejson: Option<serde_json::Value>,
+    pub tilejson: Option<Value>,
 
     #[serde(flatten, skip_serializing)]
     pub unrecognized: UnrecognizedValues,
@@ -38,7 +210,7 @@ pub struct FunctionInfo {
 
 impl FunctionInfo {
     #[must_use]
-    pub fn new(schema: String, function: String, tilejson: Option<serde_json::Value>) -> Self {
+    pub fn new(schema: String, function: String, tilejson: Option<Value>) -> Self {
         Self {
             schema,
             function,
@@ -82,4 +254,90 @@ impl PgInfo for FunctionInfo {
         tilejson.bounds = self.bounds;
         patch_json(tilejson, &self.tilejson)
     }
+
+    fn claims(&self) -> Option<&ClaimConfigs> {
+        self.claims.as_ref()
+    }
+
+    fn query_params(&self) -> Option<&QueryParamTypes> {
+        self.query_params.as_ref()
+    }
+
+    fn overzoom(&self) -> Option<u8> {
+        self.support_overzoom.unwrap_or_default().then(|| {
+            self.overzoom_max_delta
+                .unwrap_or(OVERZOOM_MAX_DELTA_DEFAULT)
+        })
+    }
+
+    fn pool(&self) -> Option<&PgPoolConfig> {
+        self.pool.as_ref()
+    }
+
+    fn tile_matrix_set(&self) -> TileMatrixSet {
+        self.tile_matrix_set.unwrap_or_default()
+    }
+
+    fn refresh_views(&self) -> Option<&Vec<String>> {
+        self.refresh_views.as_ref()
+    }
+
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        self.statement_timeout_ms
+    }
+
+    fn application_name(&self) -> Option<&str> {
+        self.application_name.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn typ(name: &str) -> QueryParamType {
+        QueryParamType::try_from(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_types() {
+        assert_eq!(typ("int").to_string(), "int");
+        assert_eq!(typ("number[]").to_string(), "float[]");
+        assert!(QueryParamType::try_from("date".to_string()).is_err());
+        let params: QueryParamTypes = serde_yaml::from_str("a: bool\nb: json[]").unwrap();
+        assert_eq!(params["a"], typ("bool"));
+        assert_eq!(
+            serde_yaml::to_string(&params).unwrap(),
+            "a: bool\nb: json[]\n"
+        );
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(typ("string").parse("007"), Some(json!("007")));
+        assert_eq!(typ("int").parse("2023"), Some(json!(2023)));
+        assert_eq!(typ("int").parse("20.5"), None);
+        assert_eq!(typ("float").parse("20.5"), Some(json!(20.5)));
+        assert_eq!(typ("float").parse("NaN"), None);
+        assert_eq!(typ("bool").parse("1"), Some(json!(true)));
+        assert_eq!(typ("bool").parse("yes"), None);
+        assert_eq!(typ("json").parse(r#"{"a":1}"#), Some(json!({"a": 1})));
+        assert_eq!(typ("json").parse("{"), None);
+        assert_eq!(typ("int[]").parse("1,2,3"), Some(json!([1, 2, 3])));
+        assert_eq!(typ("int[]").parse("[1,2]"), Some(json!([1, 2])));
+        assert_eq!(typ("int[]").parse("1,a"), None);
+        assert_eq!(typ("int[]").parse(r#"[1,"2"]"#), None);
+        assert_eq!(typ("string[]").parse(""), Some(json!([])));
+        assert_eq!(
+            typ("string[]").parse(r#"["a,b","c"]"#),
+            Some(json!(["a,b", "c"]))
+        );
+        assert_eq!(
+            typ("json[]").parse(r#"[{"a":1},2]"#),
+            Some(json!([{"a": 1}, 2]))
+        );
+        assert_eq!(typ("json[]").parse("1,2"), None);
+    }
 }
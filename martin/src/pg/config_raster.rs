@@ -0,0 +1,127 @@
+use martin_tile_utils::Format;
+use serde::{Deserialize, Serialize};
+use tilejson::{Bounds, TileJSON};
+
+use crate::args::BoundsCalcType;
+use crate::config::UnrecognizedValues;
+use crate::pg::config::PgInfo;
+use crate::pg::utils::InfoMap;
+
+pub type RasterInfoSources = InfoMap<RasterInfo>;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct RasterInfo {
+    /// Table schema
+    pub schema: String,
+
+    /// Table name
+    pub table: String,
+
+    /// Raster column name
+    pub raster_column: String,
+
+    /// Raster SRID, taken from the `raster_columns` view if not set
+    #[serde(default)]
+    pub srid: i32,
+
+    /// Number of raster bands
+    #[serde(skip_deserializing, skip_serializing)]
+    pub num_bands: Option<i32>,
+
+    /// Image format of the tiles: `png` (default), `webp`, or `jpeg`
+    pub format: Option<RasterFormat>,
+
+    /// Bands to render, starting from 1. Use one band for grayscale images or with a color map,
+    /// three bands for RGB, and four for RGBA. All bands are rendered if not set.
+    pub bands: Option<Vec<u32>>,
+
+    /// Color map of the first rendered band, as accepted by `ST_ColorMap`:
+    /// either a preset like `grayscale`, `pseudocolor`, `fire`, or `bluered`,
+    /// or lines of `value red green blue [alpha]`
+    pub colormap: Option<String>,
+
+    /// Resampling algorithm used to fit the raster into the tiles
+    pub resampling: Option<Resampling>,
+
+    /// Tile width and height in pixels, 256 by default
+    pub tile_size: Option<u16>,
+
+    pub minzoom: Option<u8>,
+
+    pub maxzoom: Option<u8>,
+
+    pub bounds: Option<Bounds>,
+
+    /// How to compute the bounds if they are not set, overriding the `auto_bounds` of the connection
+    pub auto_bounds: Option<BoundsCalcType>,
+
+    /// Serve the source by its URL, but do not list it in the catalog
+    pub hidden: Option<bool>,
+
+    #[serde(flatten, skip_serializing)]
+    pub unrecognized: UnrecognizedValues,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RasterFormat {
+    #[default]
+    Png,
+    Webp,
+    #[serde(alias = "jpg")]
+    Jpeg,
+}
+
+impl From<RasterFormat> for Format {
+    fn from(value: RasterFormat) -> Self {
+        match value {
+            RasterFormat::Png => Self::Png,
+            RasterFormat::Webp => Self::Webp,
+            RasterFormat::Jpeg => Self::Jpeg,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resampling {
+    #[default]
+    NearestNeighbor,
+    Bilinear,
+    Cubic,
+    CubicSpline,
+    Lanczos,
+}
+
+impl Resampling {
+    /// Algorithm name as expected by `ST_Transform` and the other raster functions
+    #[must_use]
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::NearestNeighbor => "NearestNeighbor",
+            Self::Bilinear => "Bilinear",
+            Self::Cubic => "Cubic",
+            Self::CubicSpline => "CubicSpline",
+            Self::Lanczos => "Lanczos",
+        }
+    }
+}
+
+impl PgInfo for RasterInfo {
+    fn format_id(&self) -> String {
+        format!("{}.{}.{}", self.schema, self.table, self.raster_column)
+    }
+
+    fn to_tilejson(&self, source_id: String) -> TileJSON {
+        let mut tilejson = tilejson::tilejson! {
+            tiles: vec![],  // tile source is required, but not yet known
+            name: source_id,
+            description: self.format_id(),
+        };
+        tilejson.minzoom = self.minzoom;
+        tilejson.maxzoom = self.maxzoom;
+        tilejson.bounds = self.bounds;
+        tilejson
+    }
+}
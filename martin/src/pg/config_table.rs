@@ -3,9 +3,13 @@ use std::collections::{BTreeMap, HashMap};
 use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON, VectorLayer};
 
+use crate::args::BoundsCalcType;
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::pg::config::{PgInfo, PgPoolConfig};
 use crate::pg::utils::{patch_json, InfoMap};
+use crate::utils::antimeridian::Antimeridian;
+use crate::utils::OptBoolObj;
+use crate::TileMatrixSet;
 
 pub type TableInfoSources = InfoMap<TableInfo>;
 
@@ -35,9 +39,14 @@ pub struct TableInfo {
     #[serde(skip_deserializing, skip_serializing)]
     pub is_view: Option<bool>,
 
-    /// Feature id column name
+    /// Feature id column name. Its value is used as the MVT feature ID, and is not included in the properties.
+    /// Must be an integer column, or a numeric one that is rounded to an integer.
     pub id_column: Option<String>,
 
+    /// Type of the feature id column, as reported by the database
+    #[serde(skip_deserializing, skip_serializing)]
+    pub id_column_type: Option<String>,
+
     /// An integer specifying the minimum zoom level
     pub minzoom: Option<u8>,
 
@@ -50,6 +59,9 @@ pub struct TableInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// How to compute the bounds if they are not set, overriding the `auto_bounds` of the connection
+    pub auto_bounds: Option<BoundsCalcType>,
+
     /// Tile extent in tile coordinate space
     pub extent: Option<u32>,
 
@@ -59,6 +71,32 @@ pub struct TableInfo {
     /// Boolean to control if geometries should be clipped or encoded as is
     pub clip_geom: Option<bool>,
 
+    /// Transform the geometries from the table SRID to Web Mercator [default: true].
+    /// Disable if the coordinates are already in Web Mercator, but the column has a different or unknown SRID.
+    pub reproject: Option<bool>,
+
+    /// Repair invalid geometries with `ST_MakeValid` before encoding them
+    pub make_valid: Option<bool>,
+
+    /// Handling of the features that cross the antimeridian or the Web Mercator latitude limits
+    pub antimeridian: Option<Antimeridian>,
+
+    /// Tile grid of the source, either `WebMercatorQuad` or `WorldCRS84Quad` [default: `WebMercatorQuad`]
+    pub tile_matrix_set: Option<TileMatrixSet>,
+
+    /// Add a layer with a label anchor point for each feature, either `true` or a label configuration
+    #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
+    pub labels: OptBoolObj<LabelConfig>,
+
+    /// Keep the Z and M ranges of 3D and measured geometries as feature attributes,
+    /// either `true` or the names of the attributes
+    #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
+    pub zm_attributes: OptBoolObj<ZmAttributes>,
+
+    /// Simplification, feature filtering, and property subsets of the zoom bands,
+    /// e.g. to keep the low zoom tiles of detailed tables small. The first matching rule is used.
+    pub zoom_rules: Option<Vec<ZoomRule>>,
+
     /// Geometry type
     pub geometry_type: Option<String>,
 
@@ -69,6 +107,44 @@ pub struct TableInfo {
     #[serde(skip_deserializing, skip_serializing)]
     pub prop_mapping: HashMap<String, String>,
 
+    /// Only encode these columns as tile properties. Selects from all the table columns if `properties` is not set.
+    pub include_columns: Option<Vec<String>>,
+
+    /// Do not encode these columns as tile properties. Selects from all the table columns if `properties` is not set.
+    pub exclude_columns: Option<Vec<String>>,
+
+    /// Property columns cast to another type before encoding, e.g. `numeric` to `float8`
+    pub casts: Option<BTreeMap<String, String>>,
+
+    /// URL query parameters that filter the features, mapped to the table columns they are compared to,
+    /// e.g. `?year=2023` only returns the features whose column equals 2023. Other parameters are ignored.
+    pub filter: Option<BTreeMap<String, String>>,
+
+    /// Types of the filter columns, keyed by the actual column name
+    #[serde(skip_deserializing, skip_serializing)]
+    pub filter_types: HashMap<String, String>,
+
+    /// Use a separate connection pool with these settings for this source
+    pub pool: Option<PgPoolConfig>,
+
+    /// Serve the source by its URL, but do not list it in the catalog
+    pub hidden: Option<bool>,
+
+    /// Also serve `UTFGrid` interaction tiles generated from the vector tiles at `/{source}/{z}/{x}/{y}.grid.json`
+    pub utfgrid: Option<bool>,
+
+    /// Materialized views the table depends on, e.g. `public.roads_mv`, refreshed by `POST /_/refresh/{source}`
+    /// and by the `refresh` scheduled task. The cached tiles of the source are purged after each refresh.
+    pub refresh_views: Option<Vec<String>>,
+
+    /// Maximum time in milliseconds of each tile query, after which the server cancels it
+    /// [default: `statement_timeout_ms` of the connection]
+    pub statement_timeout_ms: Option<u64>,
+
+    /// Name of the tile queries in `pg_stat_activity` and in the server logs, e.g. to find the expensive ones
+    /// [default: `application_name` of the connection]
+    pub application_name: Option<String>,
+
     #[serde(flatten, skip_serializing)]
     pub unrecognized: UnrecognizedValues,
 
@@ -77,6 +153,108 @@ pub struct TableInfo {
     pub tilejson: Option<serde_json::Value>,
 }
 
+/// Configuration of the label anchor points layer of a table source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct LabelConfig {
+    /// Name of the labels layer, defaults to the layer ID with a `_labels` suffix
+    pub layer: Option<String>,
+
+    /// How to compute the anchor point of the polygons
+    pub polygon_anchor: Option<PolygonAnchor>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PolygonAnchor {
+    /// The pole of inaccessibility, i.e. the center of the largest inscribed circle (`PostGIS` 3.1+)
+    #[default]
+    Pole,
+    /// Any point guaranteed to be inside the polygon, faster to compute
+    Surface,
+}
+
+/// Rendering rule of a zoom band of a table source. Sizes are in tile coordinate space,
+/// i.e. the tile is `extent` units wide, so the same rule gives similar results at each zoom.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ZoomRule {
+    /// Lowest zoom of the band, inclusive
+    pub minzoom: Option<u8>,
+    /// Highest zoom of the band, inclusive
+    pub maxzoom: Option<u8>,
+    /// Tolerance of `ST_SimplifyPreserveTopology`
+    pub simplify: Option<f64>,
+    /// Skip the polygons with a smaller area, in square tile units
+    pub min_area: Option<f64>,
+    /// Skip the lines with a smaller length
+    pub min_length: Option<f64>,
+    /// Only encode these properties, the others are omitted from the features
+    pub properties: Option<Vec<String>>,
+}
+
+/// Names of the feature attributes with the Z and M value ranges of each geometry.
+/// Each attribute is only set if the geometry has the matching dimension.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ZmAttributes {
+    /// Lowest Z value, `elev_min` by default
+    pub z_min: Option<String>,
+    /// Highest Z value, `elev_max` by default
+    pub z_max: Option<String>,
+    /// Lowest M value, `measure_min` by default
+    pub m_min: Option<String>,
+    /// Highest M value, `measure_max` by default
+    pub m_max: Option<String>,
+}
+
+/// A value range attribute of a [`ZmAttributes`] configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZmValue {
+    ZMin,
+    ZMax,
+    MMin,
+    MMax,
+}
+
+impl TableInfo {
+    /// Get the label configuration if the labels layer is enabled
+    #[must_use]
+    pub fn label_config(&self) -> Option<LabelConfig> {
+        match &self.labels {
+            OptBoolObj::NoValue | OptBoolObj::Bool(false) => None,
+            OptBoolObj::Bool(true) => Some(LabelConfig::default()),
+            OptBoolObj::Object(cfg) => Some(cfg.clone()),
+        }
+    }
+
+    /// Get the name of the labels layer if it is enabled, given the name of the main layer
+    #[must_use]
+    pub fn label_layer_id(&self, layer_id: &str) -> Option<String> {
+        self.label_config()
+            .map(|cfg| cfg.layer.unwrap_or_else(|| format!("{layer_id}_labels")))
+    }
+
+    /// Get the names of the enabled Z and M range attributes
+    #[must_use]
+    pub fn zm_attribute_names(&self) -> Vec<(String, ZmValue)> {
+        let cfg = match &self.zm_attributes {
+            OptBoolObj::NoValue | OptBoolObj::Bool(false) => return Vec::new(),
+            OptBoolObj::Bool(true) => ZmAttributes::default(),
+            OptBoolObj::Object(cfg) => cfg.clone(),
+        };
+        [
+            (cfg.z_min, "elev_min", ZmValue::ZMin),
+            (cfg.z_max, "elev_max", ZmValue::ZMax),
+            (cfg.m_min, "measure_min", ZmValue::MMin),
+            (cfg.m_max, "measure_max", ZmValue::MMax),
+        ]
+        .into_iter()
+        .map(|(name, default, value)| (name.unwrap_or_else(|| default.to_string()), value))
+        .collect()
+    }
+}
+
 impl PgInfo for TableInfo {
     fn format_id(&self) -> String {
         format!("{}.{}.{}", self.schema, self.table, self.geometry_column)
@@ -91,15 +269,46 @@ impl PgInfo for TableInfo {
         tilejson.minzoom = self.minzoom;
         tilejson.maxzoom = self.maxzoom;
         tilejson.bounds = self.bounds;
-        let layer = VectorLayer {
-            id: source_id,
-            fields: self.properties.clone().unwrap_or_default(),
+        let label_layer_id = self.label_layer_id(self.layer_id.as_ref().unwrap_or(&source_id));
+        let mut fields = self.properties.clone().unwrap_or_default();
+        for (name, _) in self.zm_attribute_names() {
+            fields.insert(name, "Number".to_string());
+        }
+        let new_layer = |id| VectorLayer {
+            id,
+            fields: fields.clone(),
             description: None,
             maxzoom: None,
             minzoom: None,
             other: BTreeMap::default(),
         };
-        tilejson.vector_layers = Some(vec![layer]);
+        let mut layers = vec![new_layer(source_id)];
+        layers.extend(label_layer_id.map(new_layer));
+        tilejson.vector_layers = Some(layers);
         patch_json(tilejson, &self.tilejson)
     }
+
+    fn pool(&self) -> Option<&PgPoolConfig> {
+        self.pool.as_ref()
+    }
+
+    fn utfgrid(&self) -> bool {
+        self.utfgrid.unwrap_or_default()
+    }
+
+    fn tile_matrix_set(&self) -> TileMatrixSet {
+        self.tile_matrix_set.unwrap_or_default()
+    }
+
+    fn refresh_views(&self) -> Option<&Vec<String>> {
+        self.refresh_views.as_ref()
+    }
+
+    fn statement_timeout_ms(&self) -> Option<u64> {
+        self.statement_timeout_ms
+    }
+
+    fn application_name(&self) -> Option<&str> {
+        self.application_name.as_deref()
+    }
 }
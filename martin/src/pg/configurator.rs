@@ -1,27 +1,33 @@
 use std::cmp::Ordering;
 use std::collections::HashSet;
+use std::time::Duration;
 
 use futures::future::join_all;
 use itertools::Itertools;
 use log::{debug, error, info, warn};
+use martin_tile_utils::Format;
 
 use crate::args::BoundsCalcType;
+use crate::overzoom::OVERZOOM_MAX_ZOOM;
 use crate::pg::config::{PgConfig, PgInfo};
 use crate::pg::config_function::{FuncInfoSources, FunctionInfo};
+use crate::pg::config_raster::{RasterInfo, RasterInfoSources};
 use crate::pg::config_table::{TableInfo, TableInfoSources};
 use crate::pg::function_source::query_available_function;
 use crate::pg::pg_source::{PgSource, PgSqlInfo};
 use crate::pg::pool::PgPool;
+use crate::pg::raster_source::{query_available_rasters, raster_to_query};
 use crate::pg::table_source::{
-    calc_srid, merge_table_info, query_available_tables, table_to_query,
+    calc_srid, merge_table_info, query_available_tables, table_to_query, INTEGER_TYPES,
 };
-use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, InfoMap};
+use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, BoundsFuture, InfoMap};
 use crate::pg::PgError::InvalidTableExtent;
-use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult};
+use crate::pg::{PgCfgPublish, PgResult, PublishFilter};
 use crate::source::TileInfoSources;
 use crate::utils::IdResolver;
 use crate::utils::OptOneMany::NoVals;
 use crate::OptBoolObj::{Bool, NoValue, Object};
+use crate::TileMatrixSet;
 
 pub type SqlFuncInfoMapMap = InfoMap<InfoMap<(PgSqlInfo, FunctionInfo)>>;
 pub type SqlTableInfoMapMapMap = InfoMap<InfoMap<InfoMap<TableInfo>>>;
@@ -31,6 +37,8 @@ pub type SqlTableInfoMapMapMap = InfoMap<InfoMap<InfoMap<TableInfo>>>;
 pub struct PgBuilderFuncs {
     schemas: Option<HashSet<String>>,
     source_id_format: String,
+    #[cfg_attr(test, serde(skip))]
+    filter: Option<PublishFilter>,
 }
 
 #[derive(Debug, Default, PartialEq)]
@@ -43,19 +51,36 @@ pub struct PgBuilderTables {
     clip_geom: Option<bool>,
     buffer: Option<u32>,
     extent: Option<u32>,
+    #[cfg_attr(test, serde(skip))]
+    filter: Option<PublishFilter>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PgBuilderRasters {
+    schemas: Option<HashSet<String>>,
+    source_id_format: String,
 }
 
 #[derive(Debug)]
 pub struct PgBuilder {
     pool: PgPool,
+    /// Used to create the dedicated connection pools of the sources that override the pool settings
+    config: PgConfig,
     default_srid: Option<i32>,
     auto_bounds: BoundsCalcType,
+    bounds_timeout: Option<Duration>,
     max_feature_count: Option<usize>,
+    deterministic: bool,
+    clip_geom: Option<bool>,
+    buffer: Option<u32>,
+    extent: Option<u32>,
     auto_functions: Option<PgBuilderFuncs>,
     auto_tables: Option<PgBuilderTables>,
+    auto_rasters: Option<PgBuilderRasters>,
     id_resolver: IdResolver,
     tables: TableInfoSources,
     functions: FuncInfoSources,
+    rasters: RasterInfoSources,
 }
 
 /// Combine `from_schema` field from the `config.auto_publish` and `config.auto_publish.tables/functions`
@@ -82,18 +107,26 @@ impl PgBuilder {
     pub async fn new(config: &PgConfig, id_resolver: IdResolver) -> PgResult<Self> {
         let pool = PgPool::new(config).await?;
 
-        let (auto_tables, auto_functions) = calc_auto(config);
+        let (auto_tables, auto_functions) = calc_auto(config)?;
 
         Ok(Self {
             pool,
+            config: config.clone(),
             default_srid: config.default_srid,
             auto_bounds: config.auto_bounds.unwrap_or_default(),
+            bounds_timeout: config.bounds_timeout.map(Duration::from_secs),
             max_feature_count: config.max_feature_count,
+            deterministic: config.deterministic.unwrap_or_default(),
+            clip_geom: config.clip_geom,
+            buffer: config.buffer,
+            extent: config.extent,
             id_resolver,
             tables: config.tables.clone().unwrap_or_default(),
             functions: config.functions.clone().unwrap_or_default(),
+            rasters: config.rasters.clone().unwrap_or_default(),
             auto_functions,
             auto_tables,
+            auto_rasters: calc_auto_rasters(config),
         })
     }
 
@@ -140,10 +173,13 @@ impl PgBuilder {
             let dup = if dup { "duplicate " } else { "" };
 
             let id2 = self.resolve_id(id, cfg_inf);
-            let Some(merged_inf) = merge_table_info(self.default_srid, &id2, cfg_inf, db_inf)
+            let Some(mut merged_inf) = merge_table_info(self.default_srid, &id2, cfg_inf, db_inf)
             else {
                 continue;
             };
+            merged_inf.clip_geom = merged_inf.clip_geom.or(self.clip_geom);
+            merged_inf.buffer = merged_inf.buffer.or(self.buffer);
+            merged_inf.extent = merged_inf.extent.or(self.extent);
             warn_on_rename(id, &id2, "Table");
             info!("Configured {dup}source {id2} from {}", summary(&merged_inf));
             pending.push(table_to_query(
@@ -151,7 +187,9 @@ impl PgBuilder {
                 merged_inf,
                 self.pool.clone(),
                 self.auto_bounds,
+                self.bounds_timeout,
                 self.max_feature_count,
+                self.deterministic,
             ));
         }
 
@@ -178,6 +216,12 @@ impl PgBuilder {
                         if used.contains(&(schema.as_str(), table.as_str(), geom_column.as_str())) {
                             continue;
                         }
+                        if let Some(filter) = &auto_tables.filter {
+                            if !filter.is_match(&[&schema, &table, &geom_column]) {
+                                debug!("Table {schema}.{table}.{geom_column} is not auto-published because of the include/exclude patterns");
+                                continue;
+                            }
+                        }
                         let source_id = auto_tables
                             .source_id_format
                             .replace("{schema}", &schema)
@@ -197,7 +241,9 @@ impl PgBuilder {
                             db_inf,
                             self.pool.clone(),
                             self.auto_bounds,
+                            self.bounds_timeout,
                             self.max_feature_count,
+                            self.deterministic,
                         ));
                     }
                 }
@@ -213,9 +259,9 @@ impl PgBuilder {
                     error!("Failed to create a source: {v}");
                     continue;
                 }
-                Ok((id, pg_sql, src_inf)) => {
+                Ok((id, pg_sql, src_inf, bounds)) => {
                     debug!("{id} query: {}", pg_sql.query);
-                    self.add_func_src(&mut res, id.clone(), &src_inf, pg_sql.clone());
+                    self.add_func_src(&mut res, id.clone(), &src_inf, pg_sql.clone(), bounds);
                     info_map.insert(id, src_inf);
                 }
             }
@@ -246,7 +292,7 @@ impl PgBuilder {
             let dup = if dup { "duplicate " } else { "" };
 
             let id2 = self.resolve_id(id, cfg_inf);
-            self.add_func_src(&mut res, id2.clone(), cfg_inf, pg_sql.clone());
+            self.add_func_src(&mut res, id2.clone(), cfg_inf, pg_sql.clone(), None);
             warn_on_rename(id, &id2, "Function");
             let signature = &pg_sql.signature;
             info!("Configured {dup}source {id2} from the function {signature}");
@@ -276,12 +322,18 @@ impl PgBuilder {
                     if used.contains(&(schema.as_str(), func.as_str())) {
                         continue;
                     }
+                    if let Some(filter) = &auto_funcs.filter {
+                        if !filter.is_match(&[&schema, &func]) {
+                            debug!("Function {schema}.{func} is not auto-published because of the include/exclude patterns");
+                            continue;
+                        }
+                    }
                     let source_id = auto_funcs
                         .source_id_format
                         .replace("{schema}", &schema)
                         .replace("{function}", &func);
                     let id2 = self.resolve_id(&source_id, &db_inf);
-                    self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone());
+                    self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone(), None);
                     info!("Discovered source {id2} from function {}", pg_sql.signature);
                     debug!("{id2} query: {}", pg_sql.query);
                     info_map.insert(id2, db_inf);
@@ -291,6 +343,151 @@ impl PgBuilder {
         Ok((res, info_map))
     }
 
+    #[allow(clippy::too_many_lines)]
+    pub async fn instantiate_rasters(&self) -> PgResult<(TileInfoSources, RasterInfoSources)> {
+        if self.rasters.is_empty() && self.auto_rasters.is_none() {
+            return Ok((TileInfoSources::default(), RasterInfoSources::new()));
+        }
+        let mut db_rasters_info = query_available_rasters(&self.pool).await?;
+        if db_rasters_info.is_empty() && !self.rasters.is_empty() {
+            warn!("No raster columns found in PostgreSQL database '{}'. Make sure the postgis_raster extension is installed.", self.get_id());
+        }
+
+        let mut used = HashSet::<(&str, &str, &str)>::new();
+        let mut pending = Vec::new();
+        for (id, cfg_inf) in &self.rasters {
+            let Some(db_tables) = find_info(&db_rasters_info, &cfg_inf.schema, "schema", id) else {
+                continue;
+            };
+            let Some(db_columns) = find_info(db_tables, &cfg_inf.table, "table", id) else {
+                continue;
+            };
+            let Some(db_inf) = find_info(db_columns, &cfg_inf.raster_column, "raster column", id)
+            else {
+                continue;
+            };
+
+            let dup = !used.insert((&cfg_inf.schema, &cfg_inf.table, &cfg_inf.raster_column));
+            let dup = if dup { "duplicate " } else { "" };
+
+            let id2 = self.resolve_id(id, cfg_inf);
+            let mut merged_inf = cfg_inf.clone();
+            merged_inf.schema.clone_from(&db_inf.schema);
+            merged_inf.table.clone_from(&db_inf.table);
+            merged_inf.raster_column.clone_from(&db_inf.raster_column);
+            merged_inf.num_bands = db_inf.num_bands;
+            let Some(srid) = calc_srid(
+                &cfg_inf.format_id(),
+                &id2,
+                db_inf.srid,
+                cfg_inf.srid,
+                self.default_srid,
+            ) else {
+                continue;
+            };
+            merged_inf.srid = srid;
+            warn_on_rename(id, &id2, "Raster");
+            info!(
+                "Configured {dup}source {id2} from {}",
+                raster_summary(&merged_inf)
+            );
+            pending.push(raster_to_query(
+                id2,
+                merged_inf,
+                self.pool.clone(),
+                self.auto_bounds,
+                self.bounds_timeout,
+            ));
+        }
+
+        // Sort the discovered sources by schema, table and raster column to ensure a consistent behavior
+        if let Some(auto_rasters) = &self.auto_rasters {
+            let schemas = auto_rasters
+                .schemas
+                .clone()
+                .unwrap_or_else(|| db_rasters_info.keys().cloned().collect());
+            info!(
+                "Auto-publishing rasters in schemas [{}] as '{}' sources",
+                schemas.iter().sorted().join(", "),
+                auto_rasters.source_id_format,
+            );
+
+            for schema in schemas.iter().sorted() {
+                let Some(schema) = normalize_key(&db_rasters_info, schema, "schema", "") else {
+                    continue;
+                };
+                let db_tables = db_rasters_info.remove(&schema).unwrap();
+                for (table, columns) in db_tables.into_iter().sorted_by(by_key) {
+                    for (column, mut db_inf) in columns.into_iter().sorted_by(by_key) {
+                        if used.contains(&(schema.as_str(), table.as_str(), column.as_str())) {
+                            continue;
+                        }
+                        let source_id = auto_rasters
+                            .source_id_format
+                            .replace("{schema}", &schema)
+                            .replace("{table}", &table)
+                            .replace("{column}", &column);
+                        let id2 = self.resolve_id(&source_id, &db_inf);
+                        let Some(srid) =
+                            calc_srid(&db_inf.format_id(), &id2, db_inf.srid, 0, self.default_srid)
+                        else {
+                            continue;
+                        };
+                        db_inf.srid = srid;
+                        info!("Discovered source {id2} from {}", raster_summary(&db_inf));
+                        pending.push(raster_to_query(
+                            id2,
+                            db_inf,
+                            self.pool.clone(),
+                            self.auto_bounds,
+                            self.bounds_timeout,
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut res = TileInfoSources::default();
+        let mut info_map = RasterInfoSources::new();
+        for src in join_all(pending).await {
+            match src {
+                Err(v) => error!("Failed to create a source: {v}"),
+                Ok((id, pg_sql, src_inf, bounds)) => {
+                    debug!("{id} query: {}", pg_sql.query);
+                    let tilejson = src_inf.to_tilejson(id.clone());
+                    let format = src_inf.format.unwrap_or_default();
+                    let source = PgSource::new(id.clone(), pg_sql, tilejson, self.pool.clone())
+                        .with_tile_info(Format::from(format).into());
+                    let mut source = self.with_query_settings(source, &src_inf);
+                    if let Some(bounds) = bounds {
+                        source = source.with_background_bounds(bounds);
+                    }
+                    res.push(Box::new(source));
+                    info_map.insert(id, src_inf);
+                }
+            }
+        }
+
+        Ok((res, info_map))
+    }
+
+    /// Apply the statement timeout and the application name of the source, or of the config entry
+    fn with_query_settings(&self, mut source: PgSource, info: &impl PgInfo) -> PgSource {
+        let timeout = info
+            .statement_timeout_ms()
+            .or(self.config.statement_timeout_ms);
+        if let Some(timeout) = timeout {
+            source = source.with_statement_timeout(Duration::from_millis(timeout));
+        }
+        let name = info
+            .application_name()
+            .or(self.config.application_name.as_deref());
+        if let Some(name) = name {
+            source = source.with_application_name(name.to_string());
+        }
+        source
+    }
+
     fn resolve_id<T: PgInfo>(&self, id: &str, src_inf: &T) -> String {
         let signature = format!("{}.{}", self.pool.get_id(), src_inf.format_id());
         self.id_resolver.resolve(id, signature)
@@ -302,9 +499,55 @@ impl PgBuilder {
         id: String,
         info: &impl PgInfo,
         sql: PgSqlInfo,
+        background_bounds: Option<BoundsFuture>,
     ) {
-        let tilejson = info.to_tilejson(id.clone());
-        let source = PgSource::new(id, sql, tilejson, self.pool.clone());
+        let mut tilejson = info.to_tilejson(id.clone());
+        let mut overzoom_from = None;
+        if let Some(delta) = info.overzoom() {
+            if let Some(maxzoom) = tilejson.maxzoom {
+                overzoom_from = Some(maxzoom);
+                tilejson.maxzoom = Some(maxzoom.saturating_add(delta).min(OVERZOOM_MAX_ZOOM));
+            } else {
+                warn!("Source {id} supports overzoom, but has no maxzoom. Overzoom is disabled.");
+            }
+        }
+        let pool = match info.pool() {
+            Some(overrides) => match self.pool.dedicated(&self.config, overrides, &id) {
+                Ok(pool) => {
+                    info!("Source {id} uses a dedicated connection pool");
+                    pool
+                }
+                Err(e) => {
+                    error!("Failed to create the connection pool of source {id}: {e}");
+                    return;
+                }
+            },
+            None => self.pool.clone(),
+        };
+        let mut source = PgSource::new(id, sql, tilejson, pool);
+        if let Some(maxzoom) = overzoom_from {
+            source = source.with_overzoom(maxzoom);
+        }
+        if let Some(claims) = info.claims() {
+            source = source.with_claims(claims.clone());
+        }
+        if let Some(params) = info.query_params().filter(|v| !v.is_empty()) {
+            source = source.with_query_params(params.clone());
+        }
+        if info.utfgrid() {
+            source = source.with_utfgrid();
+        }
+        let grid = info.tile_matrix_set();
+        if grid != TileMatrixSet::default() {
+            source = source.with_tile_matrix_set(grid);
+        }
+        if let Some(views) = info.refresh_views().filter(|v| !v.is_empty()) {
+            source = source.with_refresh_views(views.clone());
+        }
+        source = self.with_query_settings(source, info);
+        if let Some(bounds) = background_bounds {
+            source = source.with_background_bounds(bounds);
+        }
         sources.push(Box::new(source));
     }
 }
@@ -347,12 +590,13 @@ fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTabl
         };
         // ID column can be any integer type as defined in
         // https://github.com/postgis/postgis/blob/559c95d85564fb74fa9e3b7eafb74851810610da/postgis/mvt.c#L387C4-L387C66
-        if typ != "int4" && typ != "int8" && typ != "int2" {
+        if !INTEGER_TYPES.contains(&typ.as_str()) {
             warn!("Unable to use column `{key}` in table {}.{} as a tile feature ID because it has a non-integer type `{typ}`.", inf.schema, inf.table);
             continue;
         }
 
         inf.id_column = Some(column.to_string());
+        inf.id_column_type = Some(typ.clone());
         let mut final_props = props.clone();
         final_props.remove(column);
         inf.properties = Some(final_props);
@@ -367,7 +611,7 @@ fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTabl
     );
 }
 
-fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFuncs>) {
+fn calc_auto(config: &PgConfig) -> PgResult<(Option<PgBuilderTables>, Option<PgBuilderFuncs>)> {
     let auto_tables = if use_auto_publish(config, false) {
         let schemas = get_auto_schemas!(config, tables);
         let bld = if let Object(PgCfgPublish {
@@ -382,14 +626,18 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
                     .unwrap_or("{table}")
                     .to_string(),
                 id_columns: v.id_columns.opt_iter().map(|v| v.cloned().collect()),
-                clip_geom: v.clip_geom,
-                buffer: v.buffer,
-                extent: v.extent,
+                clip_geom: v.clip_geom.or(config.clip_geom),
+                buffer: v.buffer.or(config.buffer),
+                extent: v.extent.or(config.extent),
+                filter: PublishFilter::new(&v.include, &v.exclude)?,
             }
         } else {
             PgBuilderTables {
                 schemas,
                 source_id_format: "{table}".to_string(),
+                clip_geom: config.clip_geom,
+                buffer: config.buffer,
+                extent: config.extent,
                 ..Default::default()
             }
         };
@@ -399,32 +647,53 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
     };
 
     let auto_functions = if use_auto_publish(config, true) {
+        let cfg_funcs = if let Object(PgCfgPublish {
+            functions: Object(v),
+            ..
+        }) = &config.auto_publish
+        {
+            Some(v)
+        } else {
+            None
+        };
         Some(PgBuilderFuncs {
             schemas: get_auto_schemas!(config, functions),
-            source_id_format: if let Object(PgCfgPublish {
-                functions:
-                    Object(PgCfgPublishFuncs {
-                        source_id_format: Some(v),
-                        ..
-                    }),
-                ..
-            }) = &config.auto_publish
-            {
-                v.clone()
-            } else {
-                "{function}".to_string()
+            source_id_format: cfg_funcs
+                .and_then(|v| v.source_id_format.clone())
+                .unwrap_or_else(|| "{function}".to_string()),
+            filter: match cfg_funcs {
+                Some(v) => PublishFilter::new(&v.include, &v.exclude)?,
+                None => None,
             },
         })
     } else {
         None
     };
 
-    (auto_tables, auto_functions)
+    Ok((auto_tables, auto_functions))
+}
+
+/// Unlike tables and functions, rasters are only discovered if `auto_publish.rasters` is set
+fn calc_auto_rasters(config: &PgConfig) -> Option<PgBuilderRasters> {
+    let Object(PgCfgPublish { rasters, .. }) = &config.auto_publish else {
+        return None;
+    };
+    let source_id_format = match rasters {
+        NoValue | Bool(false) => return None,
+        Bool(true) => None,
+        Object(v) => v.source_id_format.clone(),
+    };
+    Some(PgBuilderRasters {
+        schemas: get_auto_schemas!(config, rasters),
+        source_id_format: source_id_format.unwrap_or_else(|| "{table}".to_string()),
+    })
 }
 
 fn use_auto_publish(config: &PgConfig, for_functions: bool) -> bool {
     match &config.auto_publish {
-        NoValue => config.tables.is_none() && config.functions.is_none(),
+        NoValue => {
+            config.tables.is_none() && config.functions.is_none() && config.rasters.is_none()
+        }
         Object(funcs) => {
             if for_functions {
                 // If auto_publish.functions is set, and currently asking for .tables which is missing,
@@ -470,6 +739,16 @@ fn summary(info: &TableInfo) -> String {
     )
 }
 
+fn raster_summary(info: &RasterInfo) -> String {
+    let bands = info
+        .num_bands
+        .map_or_else(String::new, |v| format!(", {v} bands"));
+    format!(
+        "raster {}.{} with {} column (SRID={}{bands})",
+        info.schema, info.table, info.raster_column, info.srid,
+    )
+}
+
 /// A comparator for sorting tuples by first element
 fn by_key<T>(a: &(String, T), b: &(String, T)) -> Ordering {
     a.0.cmp(&b.0)
@@ -489,7 +768,7 @@ mod tests {
     }
     fn auto(content: &str) -> AutoCfg {
         let cfg: PgConfig = serde_yaml::from_str(content).unwrap();
-        let (auto_table, auto_funcs) = calc_auto(&cfg);
+        let (auto_table, auto_funcs) = calc_auto(&cfg).unwrap();
         AutoCfg {
             auto_table,
             auto_funcs,
@@ -638,4 +917,61 @@ mod tests {
             auto_funcs: ~
             "###);
     }
+
+    #[test]
+    fn test_auto_publish_filter() {
+        let cfg = auto(indoc! {"
+            auto_publish:
+                tables:
+                    include: 'public.tiles_*'
+                    exclude: '*_staging'
+                functions:
+                    exclude: '/^audit\\./'"});
+        let tables = cfg.auto_table.unwrap().filter.unwrap();
+        assert!(tables.is_match(&["public", "tiles_roads", "geom"]));
+        assert!(!tables.is_match(&["public", "tiles_roads_staging", "geom"]));
+        let funcs = cfg.auto_funcs.unwrap().filter.unwrap();
+        assert!(!funcs.is_match(&["audit", "get_tiles"]));
+        assert!(funcs.is_match(&["public", "get_tiles"]));
+
+        let cfg: PgConfig = serde_yaml::from_str(indoc! {"
+            auto_publish:
+                tables:
+                    include: '/(/'"})
+        .unwrap();
+        assert!(calc_auto(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_auto_publish_tile_defaults() {
+        let cfg = auto(indoc! {"
+            extent: 2048
+            buffer: 256
+            clip_geom: false"});
+        assert_yaml_snapshot!(cfg, @r###"
+            ---
+            auto_table:
+              source_id_format: "{table}"
+              clip_geom: false
+              buffer: 256
+              extent: 2048
+            auto_funcs:
+              source_id_format: "{function}"
+            "###);
+
+        let cfg = auto(indoc! {"
+            buffer: 256
+            auto_publish:
+                tables:
+                    buffer: 512
+                    extent: 8192"});
+        assert_yaml_snapshot!(cfg, @r###"
+            ---
+            auto_table:
+              source_id_format: "{table}"
+              buffer: 512
+              extent: 8192
+            auto_funcs: ~
+            "###);
+    }
 }
@@ -6,6 +6,7 @@ use deadpool_postgres::{BuildError, PoolError};
 use semver::Version;
 
 use crate::pg::utils::query_to_json;
+use crate::pg::QueryParamType;
 use crate::source::UrlQuery;
 use crate::TileCoord;
 
@@ -55,6 +56,12 @@ pub enum PgError {
     #[error("Invalid extent setting in source {0} for table {1}: extent=0")]
     InvalidTableExtent(String, String),
 
+    #[error("Invalid extent setting in {0}: extent=0")]
+    InvalidExtent(String),
+
+    #[error("Invalid rediscover_interval_secs setting in postgres: it must be greater than 0")]
+    InvalidRediscoverInterval,
+
     #[error("Error preparing a query for the tile '{1}' ({2}): {3} {0}")]
     PrepareQueryError(#[source] TokioPgError, String, String, String),
 
@@ -63,4 +70,16 @@ pub enum PgError {
 
     #[error(r#"Unable to get tile {2:#} with {:?} params from {1}: {0}"#, query_to_json(.3))]
     GetTileWithQueryError(#[source] TokioPgError, String, TileCoord, UrlQuery),
+
+    #[error("Source {1} requires the JWT claim {0}")]
+    MissingClaim(String, String),
+
+    #[error("Invalid value {1:?} of the query parameter {0} of source {3}, expected {2}")]
+    InvalidQueryParam(String, String, QueryParamType, String),
+
+    #[error("Invalid auto_publish pattern {1}: {0}")]
+    InvalidPublishPattern(#[source] regex::Error, String),
+
+    #[error(r#"Unable to overzoom tile {2:#} of {1}, the function must return an uncompressed vector tile: {0}"#)]
+    OverzoomError(#[source] prost::DecodeError, String, TileCoord),
 }
@@ -8,7 +8,7 @@ use serde_json::Value;
 use crate::pg::config_function::FunctionInfo;
 use crate::pg::configurator::SqlFuncInfoMapMap;
 use crate::pg::pg_source::PgSqlInfo;
-use crate::pg::pool::PgPool;
+use crate::pg::pool::{begin_discovery, PgPool};
 use crate::pg::PgError::PostgresError;
 use crate::pg::PgResult;
 
@@ -19,7 +19,8 @@ use crate::pg::PgResult;
 pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapMap> {
     let mut res = SqlFuncInfoMapMap::new();
 
-    pool.get()
+    let mut conn = pool.get().await?;
+    begin_discovery(&mut conn)
         .await?
         .query(include_str!("scripts/query_available_function.sql"), &[])
         .await
@@ -0,0 +1,75 @@
+use deadpool_postgres::tokio_postgres::config::SslMode;
+use deadpool_postgres::tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use deadpool_postgres::tokio_postgres::{AsyncMessage, Config, NoTls, Socket};
+use futures::StreamExt as _;
+use log::{debug, info};
+use postgres_protocol::escape::escape_identifier;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::pg::tls::{make_connector, parse_conn_str};
+use crate::pg::PgError::PostgresError;
+use crate::pg::{PgConfig, PgResult};
+
+/// Open a dedicated connection to the database of the config, `LISTEN` on the channel,
+/// and send the payload of each notification. Returns once the connection is closed or lost.
+pub async fn listen_for_notifications(
+    config: &PgConfig,
+    channel: &str,
+    tx: UnboundedSender<String>,
+) -> PgResult<()> {
+    let conn_str = config.connection_string.as_deref().unwrap_or_default();
+    let (pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
+    if pg_cfg.get_ssl_mode() == SslMode::Disable {
+        listen(pg_cfg, NoTls, channel, tx).await
+    } else {
+        let connector = make_connector(&config.ssl_certificates, ssl_mode)?;
+        listen(pg_cfg, connector, channel, tx).await
+    }
+}
+
+async fn listen<T>(
+    pg_cfg: Config,
+    tls: T,
+    channel: &str,
+    tx: UnboundedSender<String>,
+) -> PgResult<()>
+where
+    T: MakeTlsConnect<Socket>,
+    T::Stream: Send + 'static,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let (client, mut connection) = pg_cfg
+        .connect(tls)
+        .await
+        .map_err(|e| PostgresError(e, "connecting to listen for notifications"))?;
+
+    // Notifications are only received while the connection is polled for its messages,
+    // which also drives the LISTEN query below
+    let messages = actix_rt::spawn(async move {
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message? {
+                AsyncMessage::Notification(n) => {
+                    debug!("Received notification on {}: {}", n.channel(), n.payload());
+                    if tx.send(n.payload().to_string()).is_err() {
+                        break;
+                    }
+                }
+                AsyncMessage::Notice(n) => debug!("PostgreSQL notice: {n}"),
+                _ => {}
+            }
+        }
+        Ok(())
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", escape_identifier(channel)))
+        .await
+        .map_err(|e| PostgresError(e, "listening for notifications"))?;
+    info!("Listening for PostgreSQL notifications on channel {channel}");
+
+    // the client must stay alive, otherwise the connection is closed
+    let result = messages.await.unwrap_or(Ok(()));
+    drop(client);
+    result.map_err(|e| PostgresError(e, "receiving notifications"))
+}
@@ -1,18 +1,30 @@
 mod config;
 mod config_function;
+mod config_raster;
 mod config_table;
 mod configurator;
 mod errors;
 mod function_source;
+mod listener;
 mod pg_source;
 mod pool;
+mod publish_filter;
+mod raster_source;
 mod table_source;
 mod tls;
 mod utils;
 
-pub use config::{PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts};
-pub use config_function::FunctionInfo;
+pub use config::{
+    PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishRasters, PgCfgPublishTables, PgConfig,
+    PgPoolConfig, PgSslCerts,
+};
+pub use config_function::{
+    ClaimConfig, ClaimConfigs, FunctionInfo, QueryParamKind, QueryParamType, QueryParamTypes,
+};
+pub use config_raster::{RasterFormat, RasterInfo, Resampling};
 pub use config_table::TableInfo;
 pub use errors::{PgError, PgResult};
 pub use function_source::query_available_function;
+pub use listener::listen_for_notifications;
 pub use pool::{PgPool, POOL_SIZE_DEFAULT};
+pub use publish_filter::PublishFilter;
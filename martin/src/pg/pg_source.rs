@@ -1,18 +1,34 @@
 use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use deadpool_postgres::tokio_postgres::types::{ToSql, Type};
-use log::debug;
+use deadpool_postgres::tokio_postgres::error::SqlState;
+use deadpool_postgres::tokio_postgres::types::{Json, ToSql, Type};
+use deadpool_postgres::GenericClient;
+use itertools::Itertools as _;
+use log::{debug, info, warn};
 use martin_tile_utils::Encoding::Uncompressed;
 use martin_tile_utils::Format::Mvt;
 use martin_tile_utils::TileInfo;
+use postgres_protocol::escape::escape_identifier;
+use serde_json::Value;
 use tilejson::TileJSON;
+use tracing::{info_span, Instrument as _};
 
+use crate::pg::config_function::{ClaimConfigs, QueryParamTypes};
 use crate::pg::pool::PgPool;
-use crate::pg::utils::query_to_json;
-use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PrepareQueryError};
-use crate::source::{Source, TileData, UrlQuery};
-use crate::{MartinResult, TileCoord};
+use crate::pg::utils::{query_to_json, BoundsFuture};
+use crate::pg::PgError::{
+    GetTileError, GetTileWithQueryError, InvalidQueryParam, MissingClaim, OverzoomError,
+    PostgresError, PrepareQueryError,
+};
+use crate::pg::{PgError, PgResult};
+use crate::source::{PoolStatus, Source, TileData, UrlQuery};
+use crate::srv::CLAIM_QUERY_PREFIX;
+use crate::utils::mvt::overzoom_tile;
+use crate::MartinError::SourceTimeout;
+use crate::{MartinError, MartinResult, TileCoord, TileMatrixSet};
 
 #[derive(Clone, Debug)]
 pub struct PgSource {
@@ -20,6 +36,22 @@ pub struct PgSource {
     info: PgSqlInfo,
     pool: PgPool,
     tilejson: TileJSON,
+    /// The TileJSON with the bounds computed in the background, once they are known
+    bounded_tilejson: Arc<OnceLock<TileJSON>>,
+    tile_info: TileInfo,
+    claims: ClaimConfigs,
+    /// Declared types of the URL query parameters
+    query_params: QueryParamTypes,
+    /// Tiles above this zoom are generated from the tiles at this zoom
+    overzoom_from: Option<u8>,
+    utfgrid: bool,
+    tile_matrix_set: TileMatrixSet,
+    /// Materialized views refreshed by [`Source::refresh_data`]
+    refresh_views: Vec<String>,
+    /// Maximum time of each tile query, enforced by the server with `statement_timeout`
+    statement_timeout: Option<Duration>,
+    /// Session settings of each tile query, e.g. `statement_timeout` and `application_name`
+    settings: Vec<(String, String)>,
 }
 
 impl PgSource {
@@ -30,83 +62,209 @@ impl PgSource {
             info,
             pool,
             tilejson,
+            bounded_tilejson: Arc::default(),
+            tile_info: TileInfo::new(Mvt, Uncompressed),
+            claims: ClaimConfigs::new(),
+            query_params: QueryParamTypes::new(),
+            overzoom_from: None,
+            utfgrid: false,
+            tile_matrix_set: TileMatrixSet::default(),
+            refresh_views: Vec::new(),
+            statement_timeout: None,
+            settings: Vec::new(),
         }
     }
-}
 
-#[async_trait]
-impl Source for PgSource {
-    fn get_id(&self) -> &str {
-        &self.id
+    /// Use a different tile format than the uncompressed MVT returned by tables and functions
+    #[must_use]
+    pub fn with_tile_info(mut self, tile_info: TileInfo) -> Self {
+        self.tile_info = tile_info;
+        self
     }
 
-    fn get_tilejson(&self) -> &TileJSON {
-        &self.tilejson
+    /// Pass these validated JWT claims to the function
+    #[must_use]
+    pub fn with_claims(mut self, claims: ClaimConfigs) -> Self {
+        self.claims = claims;
+        self
     }
 
-    fn get_tile_info(&self) -> TileInfo {
-        TileInfo::new(Mvt, Uncompressed)
+    /// Pass these URL query parameters to the function as JSON values of the declared types
+    #[must_use]
+    pub fn with_query_params(mut self, params: QueryParamTypes) -> Self {
+        self.query_params = params;
+        self
     }
 
-    fn clone_source(&self) -> Box<dyn Source> {
-        Box::new(self.clone())
+    /// Generate the tiles above `maxzoom` from the `maxzoom` tiles instead of querying them
+    #[must_use]
+    pub fn with_overzoom(mut self, maxzoom: u8) -> Self {
+        self.overzoom_from = Some(maxzoom);
+        self
     }
 
-    fn support_url_query(&self) -> bool {
-        self.info.use_url_query
+    /// Also serve `UTFGrid` interaction tiles generated from the vector tiles
+    #[must_use]
+    pub fn with_utfgrid(mut self) -> Self {
+        self.utfgrid = true;
+        self
     }
 
-    async fn get_tile(
+    /// Serve the tiles of another grid than Web Mercator, and advertise it in the TileJSON
+    #[must_use]
+    pub fn with_tile_matrix_set(mut self, grid: TileMatrixSet) -> Self {
+        self.tile_matrix_set = grid;
+        self.tilejson
+            .other
+            .insert("tile_matrix_set".to_string(), Value::from(grid.to_string()));
+        self
+    }
+
+    /// Refresh these materialized views, e.g. `public.roads_mv`, when the data of the source is refreshed
+    #[must_use]
+    pub fn with_refresh_views(mut self, views: Vec<String>) -> Self {
+        self.refresh_views = views;
+        self
+    }
+
+    /// Let the server cancel the tile queries that take longer than this
+    #[must_use]
+    pub fn with_statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        let millis = timeout.as_millis().to_string();
+        self.settings
+            .push(("statement_timeout".to_string(), millis));
+        self
+    }
+
+    /// Show the tile queries with this name in `pg_stat_activity` and in the server logs
+    #[must_use]
+    pub fn with_application_name(mut self, name: String) -> Self {
+        self.settings.push(("application_name".to_string(), name));
+        self
+    }
+
+    /// Serve the source right away, and add the bounds to its TileJSON once they have been computed
+    #[must_use]
+    pub fn with_background_bounds(self, bounds: BoundsFuture) -> Self {
+        let id = self.id.clone();
+        let mut tilejson = self.tilejson.clone();
+        let bounded_tilejson = self.bounded_tilejson.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            match bounds.await {
+                Ok(Some(bounds)) => {
+                    info!(
+                        "Computed the bounds {bounds} of source {id} in {:?}",
+                        start.elapsed()
+                    );
+                    tilejson.bounds = Some(bounds);
+                    let _ = bounded_tilejson.set(tilejson);
+                }
+                Ok(None) => debug!("Source {id} has no bounds"),
+                Err(e) => warn!("Unable to compute the bounds of source {id}: {e}"),
+            }
+        });
+        self
+    }
+
+    /// Take the claims added by the server out of the URL query, and map them to the function
+    /// parameters and to the session settings. Fails if any of the configured claims is missing.
+    fn apply_claims(&self, url_query: &UrlQuery) -> PgResult<(UrlQuery, Vec<(String, String)>)> {
+        let mut query: UrlQuery = url_query
+            .iter()
+            .filter(|(k, _)| !k.starts_with(CLAIM_QUERY_PREFIX))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let mut settings = Vec::new();
+        for (claim, cfg) in &self.claims {
+            let Some(value) = url_query.get(&format!("{CLAIM_QUERY_PREFIX}{claim}")) else {
+                return Err(MissingClaim(claim.clone(), self.id.clone()));
+            };
+            if let Some(param) = &cfg.param {
+                query.insert(param.clone(), value.clone());
+            }
+            if let Some(setting) = &cfg.setting {
+                // claims are JSON encoded, but the settings are plain text
+                let text = match serde_json::from_str(value) {
+                    Ok(Value::String(v)) => v,
+                    _ => value.clone(),
+                };
+                settings.push((setting.clone(), text));
+            }
+        }
+        Ok((query, settings))
+    }
+
+    /// Convert the URL query to the JSON argument of the function. The declared parameters must have
+    /// valid values, the others are passed as JSON if they can be parsed, and as strings otherwise.
+    /// Parameters set from the claims are already JSON encoded.
+    fn query_json(&self, url_query: &UrlQuery) -> PgResult<Json<HashMap<String, Value>>> {
+        let mut json = query_to_json(url_query);
+        for (name, typ) in &self.query_params {
+            let Some(text) = url_query.get(name) else {
+                continue;
+            };
+            if self.claims.values().any(|v| v.param.as_ref() == Some(name)) {
+                continue;
+            }
+            let value = typ.parse(text).ok_or_else(|| {
+                InvalidQueryParam(name.clone(), text.clone(), *typ, self.id.clone())
+            })?;
+            json.0.insert(name.clone(), value);
+        }
+        Ok(json)
+    }
+
+    async fn query_tile(
         &self,
+        client: &impl GenericClient,
         xyz: &TileCoord,
-        url_query: &Option<UrlQuery>,
-    ) -> MartinResult<TileData> {
-        let empty_query = HashMap::new();
-        let url_query = url_query.as_ref().unwrap_or(&empty_query);
-        let conn = self.pool.get().await?;
-
-        let param_types: &[Type] = if self.support_url_query() {
+        url_query: &UrlQuery,
+        json: Option<Json<HashMap<String, Value>>>,
+    ) -> PgResult<TileData> {
+        let param_types: &[Type] = if self.info.use_url_query {
             &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
         } else {
             &[Type::INT2, Type::INT8, Type::INT8]
         };
 
         let query = &self.info.query;
-        let prep_query = conn
-            .prepare_typed_cached(query, param_types)
-            .await
-            .map_err(|e| {
-                PrepareQueryError(
-                    e,
-                    self.id.to_string(),
-                    self.info.signature.to_string(),
-                    self.info.query.to_string(),
-                )
-            })?;
+        // the statements of a transaction pooler only exist until the end of the transaction
+        let prep_query = if self.pool.uses_transaction_pooling() {
+            client.prepare_typed(query, param_types).await
+        } else {
+            client.prepare_typed_cached(query, param_types).await
+        };
+        let prep_query = prep_query.map_err(|e| {
+            PrepareQueryError(
+                e,
+                self.id.to_string(),
+                self.info.signature.to_string(),
+                self.info.query.to_string(),
+            )
+        })?;
 
-        let tile = if self.support_url_query() {
-            let json = query_to_json(url_query);
+        let (z, x, y) = (i16::from(xyz.z), i64::from(xyz.x), i64::from(xyz.y));
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&z, &x, &y];
+        if let Some(json) = &json {
             debug!("SQL: {query} [{xyz}, {json:?}]");
-            let params: &[&(dyn ToSql + Sync)] = &[
-                &i16::from(xyz.z),
-                &i64::from(xyz.x),
-                &i64::from(xyz.y),
-                &json,
-            ];
-            conn.query_opt(&prep_query, params).await
+            params.push(json);
         } else {
             debug!("SQL: {query} [{xyz}]");
-            conn.query_opt(
-                &prep_query,
-                &[&i16::from(xyz.z), &i64::from(xyz.x), &i64::from(xyz.y)],
-            )
-            .await
-        };
+        }
+        let start = Instant::now();
+        let tile = client.query_opt(&prep_query, &params).await;
+        let elapsed = start.elapsed();
+        if self.pool.is_slow_query(elapsed) {
+            self.log_slow_query(client, xyz, json.as_ref(), &params, param_types, elapsed)
+                .await;
+        }
 
         let tile = tile
             .map(|row| row.and_then(|r| r.get::<_, Option<TileData>>(0)))
             .map_err(|e| {
-                if self.support_url_query() {
+                if self.info.use_url_query {
                     GetTileWithQueryError(e, self.id.to_string(), *xyz, url_query.clone())
                 } else {
                     GetTileError(e, self.id.to_string(), *xyz)
@@ -116,6 +274,206 @@ impl Source for PgSource {
 
         Ok(tile)
     }
+
+    /// Log a tile query that took longer than the `slow_query_ms` of its config,
+    /// with its plan if `explain_slow_queries` is set. The plan is not executed.
+    async fn log_slow_query(
+        &self,
+        client: &impl GenericClient,
+        xyz: &TileCoord,
+        json: Option<&Json<HashMap<String, Value>>>,
+        params: &[&(dyn ToSql + Sync)],
+        param_types: &[Type],
+        elapsed: Duration,
+    ) {
+        let id = &self.id;
+        let query = &self.info.query;
+        if let Some(json) = json {
+            let json = &json.0;
+            warn!("Slow query of source {id} for tile {xyz} took {elapsed:?}: {query} [{json:?}]");
+        } else {
+            warn!("Slow query of source {id} for tile {xyz} took {elapsed:?}: {query}");
+        }
+        if !self.pool.explain_slow_queries() {
+            return;
+        }
+        let plan = match client
+            .prepare_typed(&format!("EXPLAIN {query}"), param_types)
+            .await
+        {
+            Ok(stmt) => client.query(&stmt, params).await,
+            Err(e) => Err(e),
+        };
+        match plan {
+            Ok(rows) => {
+                let plan = rows.iter().map(|row| row.get::<_, String>(0)).join("\n");
+                warn!("Query plan of source {id} for tile {xyz}:\n{plan}");
+            }
+            Err(e) => warn!("Unable to get the query plan of source {id} for tile {xyz}: {e}"),
+        }
+    }
+
+    /// Query the tile, passing the claims as function parameters or session settings
+    async fn fetch_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> PgResult<TileData> {
+        let span = info_span!(
+            "postgres_query",
+            otel.kind = "client",
+            db.system = "postgresql",
+            db.statement = self.info.query.as_str(),
+            tile = %xyz,
+        );
+        self.fetch_tile_with_settings(xyz, url_query)
+            .instrument(span)
+            .await
+    }
+
+    /// Report the tile queries cancelled by the `statement_timeout` like the other source timeouts
+    fn map_timeout(&self, e: PgError, xyz: TileCoord) -> MartinError {
+        match (&e, self.statement_timeout) {
+            (GetTileError(pg, ..) | GetTileWithQueryError(pg, ..), Some(timeout))
+                if pg.code() == Some(&SqlState::QUERY_CANCELED) =>
+            {
+                SourceTimeout(self.id.clone(), xyz, timeout)
+            }
+            _ => e.into(),
+        }
+    }
+
+    async fn fetch_tile_with_settings(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> PgResult<TileData> {
+        let empty_query = HashMap::new();
+        let (url_query, claims) = self.apply_claims(url_query.as_ref().unwrap_or(&empty_query))?;
+        let settings: Vec<_> = self.settings.iter().chain(&claims).collect();
+        // invalid parameters are rejected before taking a connection
+        let json = if self.info.use_url_query {
+            Some(self.query_json(&url_query)?)
+        } else {
+            None
+        };
+        let mut conn = self.pool.get_for_tile().await?;
+        if settings.is_empty() && !self.pool.uses_transaction_pooling() {
+            return self.query_tile(&conn, xyz, &url_query, json).await;
+        }
+
+        // SET LOCAL only lasts until the end of the transaction, which is rolled back on errors.
+        // A transaction pooler also keeps the statement on the same server until the end of it.
+        let tx = conn
+            .transaction()
+            .await
+            .map_err(|e| PostgresError(e, "starting a transaction"))?;
+        for (name, value) in settings {
+            tx.execute("SELECT set_config($1, $2, true)", &[name, value])
+                .await
+                .map_err(|e| PostgresError(e, "setting session settings"))?;
+        }
+        let tile = self.query_tile(&tx, xyz, &url_query, json).await?;
+        tx.commit()
+            .await
+            .map_err(|e| PostgresError(e, "committing a transaction"))?;
+        Ok(tile)
+    }
+}
+
+#[async_trait]
+impl Source for PgSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.bounded_tilejson.get().unwrap_or(&self.tilejson)
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        // the claims are passed in the URL query, and must be part of the cache key
+        self.info.use_url_query || !self.claims.is_empty()
+    }
+
+    fn support_utfgrid(&self) -> bool {
+        self.utfgrid
+    }
+
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.tile_matrix_set
+    }
+
+    fn support_refresh(&self) -> bool {
+        !self.refresh_views.is_empty()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let Some(maxzoom) = self.overzoom_from.filter(|z| xyz.z > *z) else {
+            return self
+                .fetch_tile(xyz, url_query)
+                .await
+                .map_err(|e| self.map_timeout(e, *xyz));
+        };
+        let dz = xyz.z - maxzoom;
+        let parent = TileCoord {
+            z: maxzoom,
+            x: xyz.x >> dz,
+            y: xyz.y >> dz,
+        };
+        let data = self
+            .fetch_tile(&parent, url_query)
+            .await
+            .map_err(|e| self.map_timeout(e, *xyz))?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        Ok(overzoom_tile(&data, parent, *xyz)
+            .map_err(|e| OverzoomError(e, self.id.clone(), *xyz))?)
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        Some((self.pool.get_id().to_string(), self.pool.status()))
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.pool
+            .get()
+            .await?
+            .simple_query("SELECT 1")
+            .await
+            .map_err(|e| PostgresError(e, "running health check"))?;
+        Ok(())
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        let conn = self.pool.get().await?;
+        for view in &self.refresh_views {
+            let name = view.split('.').map(escape_identifier).join(".");
+            info!("Refreshing materialized view {view} of source {}", self.id);
+            // CONCURRENTLY keeps serving the old data while refreshing, but needs a unique index on the view
+            conn.batch_execute(&format!("REFRESH MATERIALIZED VIEW CONCURRENTLY {name}"))
+                .await
+                .map_err(|e| PostgresError(e, "refreshing a materialized view"))?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
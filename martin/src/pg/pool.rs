@@ -1,17 +1,29 @@
-use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Transaction};
 use log::{info, warn};
 use postgres::config::SslMode;
 use semver::Version;
 
-use crate::pg::config::PgConfig;
+use crate::pg::config::{PgConfig, PgPoolConfig};
 use crate::pg::tls::{make_connector, parse_conn_str, SslModeOverride};
 use crate::pg::PgError::{
     BadPostgisVersion, PostgisTooOld, PostgresError, PostgresPoolBuildError, PostgresPoolConnError,
 };
 use crate::pg::PgResult;
+use crate::source::PoolStatus;
+use crate::utils::saturation::record_pool_wait;
 
 pub const POOL_SIZE_DEFAULT: usize = 20;
 
+/// Shortest interval between the checks for idle connections
+const IDLE_CHECK_INTERVAL_MIN: Duration = Duration::from_secs(1);
+
+/// How long a server that failed to provide a connection is skipped before it is tried again
+pub const REPLICA_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
 // We require ST_TileEnvelope that was added in PostGIS 3.0.0
 // See https://postgis.net/docs/ST_TileEnvelope.html
 const MINIMUM_POSTGIS_VER: Version = Version::new(3, 0, 0);
@@ -24,18 +36,67 @@ pub struct PgPool {
     pool: Pool,
     // When true, we can use margin parameter in ST_TileEnvelope
     margin: bool,
+    /// The primary followed by the read replicas that share the tile queries.
+    /// Empty if no replicas are configured.
+    members: Vec<PoolMember>,
+    next_member: Arc<AtomicUsize>,
+    /// Maximum number of prepared statements cached by each connection
+    statement_cache_size: Option<usize>,
+    /// Connected through a transaction pooler, so the prepared statements do not outlive a transaction
+    transaction_pooling: bool,
+    /// Tile queries taking longer than this are logged
+    slow_query: Option<Duration>,
+    explain_slow_queries: bool,
+    /// Stops closing the idle connections once all clones of this pool are dropped
+    _alive: Arc<()>,
+}
+
+/// One of the servers that tile queries are distributed to
+#[derive(Clone, Debug)]
+struct PoolMember {
+    id: String,
+    pool: Pool,
+    /// When the server last failed to provide a connection, if it has not recovered since
+    failed_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PoolMember {
+    fn new(id: String, pool: Pool) -> Self {
+        Self {
+            id,
+            pool,
+            failed_at: Arc::default(),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.failed_at.lock().unwrap().map_or(true, |failed_at| {
+            failed_at.elapsed() >= REPLICA_RETRY_INTERVAL
+        })
+    }
+
+    fn set_failed(&self, failed: bool) {
+        let mut failed_at = self.failed_at.lock().unwrap();
+        if failed {
+            if failed_at.is_none() {
+                warn!(
+                    "Postgres server {} is not available, tile queries will use the other servers for {REPLICA_RETRY_INTERVAL:?}",
+                    self.id
+                );
+            }
+            *failed_at = Some(Instant::now());
+        } else if failed_at.take().is_some() {
+            info!("Postgres server {} is available again", self.id);
+        }
+    }
 }
 
 impl PgPool {
     pub async fn new(config: &PgConfig) -> PgResult<Self> {
-        let (id, mgr) = Self::parse_config(config)?;
-
-        let pool = Pool::builder(mgr)
-            .max_size(config.pool_size.unwrap_or(POOL_SIZE_DEFAULT))
-            .build()
-            .map_err(|e| PostgresPoolBuildError(e, id.clone()))?;
+        let mut pool = Self::connect(config, None)?;
 
-        let version: String = get_conn(&pool, id.as_str())
+        let mut conn = pool.get().await?;
+        let version: String = begin_discovery(&mut conn)
             .await?
             .query_one(
                 r"
@@ -60,12 +121,93 @@ SELECT
             warn!("PostGIS {version} is before the recommended {RECOMMENDED_POSTGIS_VER}. Margin parameter in ST_TileEnvelope is not supported, so tiles may be cut off at the edges.");
         }
 
-        let margin = version >= RECOMMENDED_POSTGIS_VER;
-        Ok(Self { id, pool, margin })
+        pool.margin = version >= RECOMMENDED_POSTGIS_VER;
+        Ok(pool)
     }
 
-    fn parse_config(config: &PgConfig) -> PgResult<(String, Manager)> {
+    /// Create a separate pool for a source that overrides the pool settings of its config entry,
+    /// so that its queries cannot use up the connections of the other sources.
+    /// The settings that are not overridden are taken from the `config`.
+    /// The pool is reported with the `{database}/{source_id}` ID.
+    pub fn dedicated(
+        &self,
+        config: &PgConfig,
+        overrides: &PgPoolConfig,
+        source_id: &str,
+    ) -> PgResult<Self> {
+        let mut pool = Self::connect(config, Some(overrides))?;
+        pool.id = format!("{}/{source_id}", pool.id);
+        pool.margin = self.margin;
+        Ok(pool)
+    }
+
+    /// Create the pools of the primary server and of the replicas without connecting to them
+    fn connect(config: &PgConfig, overrides: Option<&PgPoolConfig>) -> PgResult<Self> {
+        let pool_size = overrides
+            .and_then(|v| v.pool_size)
+            .or(config.pool_size)
+            .unwrap_or(POOL_SIZE_DEFAULT);
+        let statement_cache_size = overrides
+            .and_then(|v| v.statement_cache_size)
+            .or(config.statement_cache_size);
+        let idle_timeout = overrides
+            .and_then(|v| v.idle_timeout_secs)
+            .or(config.idle_timeout_secs)
+            .map(Duration::from_secs);
+
         let conn_str = config.connection_string.as_ref().unwrap().as_str();
+        let (id, pool) = Self::build_pool(config, conn_str, pool_size)?;
+
+        // Replicas are not queried here, so that a replica that is down does not prevent the startup.
+        // They must run the same PostGIS version as the primary.
+        let mut members = Vec::new();
+        if let Some(replicas) = config.replicas.as_ref().filter(|v| !v.is_empty()) {
+            members.push(PoolMember::new(id.clone(), pool.clone()));
+            for replica in replicas {
+                let (replica_id, replica_pool) = Self::build_pool(config, replica, pool_size)?;
+                info!("Distributing tile queries of {id} to replica {replica_id}");
+                members.push(PoolMember::new(replica_id, replica_pool));
+            }
+        }
+
+        let alive = Arc::new(());
+        if let Some(timeout) = idle_timeout {
+            let pools = if members.is_empty() {
+                vec![pool.clone()]
+            } else {
+                members.iter().map(|m| m.pool.clone()).collect()
+            };
+            actix_rt::spawn(close_idle_connections(
+                pools,
+                timeout,
+                Arc::downgrade(&alive),
+            ));
+        }
+
+        Ok(Self {
+            id,
+            pool,
+            margin: false,
+            members,
+            next_member: Arc::default(),
+            statement_cache_size,
+            transaction_pooling: config.transaction_pooling.unwrap_or_default(),
+            slow_query: config.slow_query_ms.map(Duration::from_millis),
+            explain_slow_queries: config.explain_slow_queries.unwrap_or_default(),
+            _alive: alive,
+        })
+    }
+
+    fn build_pool(config: &PgConfig, conn_str: &str, pool_size: usize) -> PgResult<(String, Pool)> {
+        let (id, mgr) = Self::parse_config(config, conn_str)?;
+        let pool = Pool::builder(mgr)
+            .max_size(pool_size)
+            .build()
+            .map_err(|e| PostgresPoolBuildError(e, id.clone()))?;
+        Ok((id, pool))
+    }
+
+    fn parse_config(config: &PgConfig, conn_str: &str) -> PgResult<(String, Manager)> {
         let (pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
 
         let id = pg_cfg.get_dbname().map_or_else(
@@ -100,23 +242,158 @@ SELECT
         Ok((id, mgr))
     }
 
+    /// Check if a tile query that took this long must be logged
+    #[must_use]
+    pub fn is_slow_query(&self, elapsed: Duration) -> bool {
+        self.slow_query.map_or(false, |v| elapsed > v)
+    }
+
+    /// Check if the connections go through a transaction pooler like `PgBouncer`, so that the prepared
+    /// statements cannot be cached, and each tile query must run in a transaction
+    #[must_use]
+    pub fn uses_transaction_pooling(&self) -> bool {
+        self.transaction_pooling
+    }
+
+    /// Check if the `EXPLAIN` plan of the slow tile queries must be logged
+    #[must_use]
+    pub fn explain_slow_queries(&self) -> bool {
+        self.explain_slow_queries
+    }
+
+    /// Get a connection to the primary server, used for discovery and health checks
     pub async fn get(&self) -> PgResult<Object> {
         get_conn(&self.pool, self.id.as_str()).await
     }
 
+    /// Get a connection for a tile query. With replicas, the servers take turns,
+    /// and the ones that failed recently are skipped until [`REPLICA_RETRY_INTERVAL`] passes.
+    pub async fn get_for_tile(&self) -> PgResult<Object> {
+        let conn = self.get_member_conn().await?;
+        if let Some(max) = self.statement_cache_size {
+            // The cache is emptied rather than trimmed, as it does not track which statements were used recently
+            if conn.statement_cache.size() >= max {
+                conn.statement_cache.clear();
+            }
+        }
+        Ok(conn)
+    }
+
+    async fn get_member_conn(&self) -> PgResult<Object> {
+        if self.members.is_empty() {
+            return self.get().await;
+        }
+        let count = self.members.len();
+        let start = self.next_member.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for idx in 0..count {
+            let member = &self.members[(start + idx) % count];
+            if !member.is_available() {
+                continue;
+            }
+            match get_conn(&member.pool, self.id.as_str()).await {
+                Ok(conn) => {
+                    member.set_failed(false);
+                    return Ok(conn);
+                }
+                Err(e) => {
+                    member.set_failed(true);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            // All servers have failed recently, so try the primary rather than failing right away
+            None => self.get().await,
+        }
+    }
+
     #[must_use]
     pub fn get_id(&self) -> &str {
         self.id.as_str()
     }
 
+    /// Usage of the connection pool, combined for the primary and all replicas
+    #[must_use]
+    pub fn status(&self) -> PoolStatus {
+        let pools = if self.members.is_empty() {
+            vec![&self.pool]
+        } else {
+            self.members.iter().map(|m| &m.pool).collect()
+        };
+        pools
+            .into_iter()
+            .map(Pool::status)
+            .fold(PoolStatus::default(), |acc, status| PoolStatus {
+                max_size: acc.max_size + status.max_size,
+                size: acc.size + status.size,
+                available: acc.available + status.available,
+                waiting: acc.waiting + status.waiting,
+            })
+    }
+
     #[must_use]
     pub fn supports_tile_margin(&self) -> bool {
         self.margin
     }
 }
 
+/// Periodically close the connections that have not been used for `timeout`,
+/// until the [`PgPool`] that owns the `pools` is dropped
+async fn close_idle_connections(pools: Vec<Pool>, timeout: Duration, alive: Weak<()>) {
+    let interval = (timeout / 2).max(IDLE_CHECK_INTERVAL_MIN);
+    loop {
+        tokio::time::sleep(interval).await;
+        if alive.strong_count() == 0 {
+            break;
+        }
+        for pool in &pools {
+            pool.retain(|_, metrics| metrics.last_used() < timeout);
+        }
+    }
+}
+
+/// Start a transaction for the discovery queries. Behind a transaction pooler like `PgBouncer`,
+/// a query and the statement prepared for it only run on the same server within a transaction.
+/// The transaction is rolled back when it is dropped.
+pub async fn begin_discovery(conn: &mut Object) -> PgResult<Transaction<'_>> {
+    conn.transaction()
+        .await
+        .map_err(|e| PostgresError(e, "starting a transaction"))
+}
+
 async fn get_conn(pool: &Pool, id: &str) -> PgResult<Object> {
-    pool.get()
+    let start = Instant::now();
+    let conn = pool
+        .get()
         .await
-        .map_err(|e| PostgresPoolConnError(e, id.to_string()))
+        .map_err(|e| PostgresPoolConnError(e, id.to_string()));
+    record_pool_wait(id, start.elapsed());
+    conn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_member_is_skipped() {
+        let config = PgConfig {
+            connection_string: Some("postgres://localhost/db?sslmode=disable".to_string()),
+            ..PgConfig::default()
+        };
+        let conn_str = config.connection_string.as_deref().unwrap();
+        let (id, pool) = PgPool::build_pool(&config, conn_str, 1).unwrap();
+        assert_eq!(id, "db");
+
+        let member = PoolMember::new(id, pool);
+        assert!(member.is_available());
+        member.set_failed(true);
+        assert!(!member.is_available());
+        *member.failed_at.lock().unwrap() = Instant::now().checked_sub(REPLICA_RETRY_INTERVAL);
+        assert!(member.is_available());
+        member.set_failed(false);
+        assert!(member.failed_at.lock().unwrap().is_none());
+    }
 }
@@ -0,0 +1,153 @@
+use regex::Regex;
+
+use crate::pg::PgError::InvalidPublishPattern;
+use crate::pg::PgResult;
+use crate::utils::OptOneMany;
+
+/// Include and exclude patterns for the auto-published tables and functions.
+///
+/// A pattern enclosed in slashes, e.g. `/^tiles_\d+$/`, is a regular expression that is searched for
+/// in the fully qualified name, i.e. `schema.table.column` or `schema.function`.
+/// Any other pattern is a glob with `*` and `?` wildcards that must match the whole name. A glob without dots
+/// matches the table or function name, `schema.table` also matches the schema, and `schema.table.column`
+/// also matches the geometry column.
+#[derive(Debug, Clone, Default)]
+pub struct PublishFilter {
+    include: Vec<NamePattern>,
+    exclude: Vec<NamePattern>,
+}
+
+#[derive(Debug, Clone)]
+enum NamePattern {
+    /// A glob converted to a regex, and the number of dot-separated name parts it matches
+    Glob(Regex, usize),
+    Regex(Regex),
+}
+
+impl PartialEq for PublishFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.patterns() == other.patterns()
+    }
+}
+
+impl PublishFilter {
+    /// Compile the patterns. Returns `None` if there are none, so that everything is published.
+    pub fn new(
+        include: &OptOneMany<String>,
+        exclude: &OptOneMany<String>,
+    ) -> PgResult<Option<Self>> {
+        let compile = |patterns: &OptOneMany<String>| -> PgResult<Vec<NamePattern>> {
+            patterns.iter().map(|v| NamePattern::new(v)).collect()
+        };
+        let filter = Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        };
+        Ok(if filter.include.is_empty() && filter.exclude.is_empty() {
+            None
+        } else {
+            Some(filter)
+        })
+    }
+
+    /// Check the name parts, i.e. `[schema, table, column]` or `[schema, function]`.
+    /// A name must match at least one of the include patterns if there are any, and none of the exclude patterns.
+    #[must_use]
+    pub fn is_match(&self, names: &[&str]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.is_match(names)))
+            && !self.exclude.iter().any(|p| p.is_match(names))
+    }
+
+    fn patterns(&self) -> (Vec<&str>, Vec<&str>) {
+        (
+            self.include.iter().map(NamePattern::as_str).collect(),
+            self.exclude.iter().map(NamePattern::as_str).collect(),
+        )
+    }
+}
+
+impl NamePattern {
+    fn new(pattern: &str) -> PgResult<Self> {
+        let err = |e| InvalidPublishPattern(e, pattern.to_string());
+        if let Some(re) = pattern
+            .strip_prefix('/')
+            .and_then(|v| v.strip_suffix('/'))
+            .filter(|v| !v.is_empty())
+        {
+            return Ok(Self::Regex(Regex::new(re).map_err(err)?));
+        }
+        let mut re = String::from("^");
+        for chr in pattern.chars() {
+            match chr {
+                '*' => re.push_str(".*"),
+                '?' => re.push('.'),
+                _ => re.push_str(&regex::escape(&chr.to_string())),
+            }
+        }
+        re.push('$');
+        let parts = pattern.split('.').count();
+        Ok(Self::Glob(Regex::new(&re).map_err(err)?, parts))
+    }
+
+    fn is_match(&self, names: &[&str]) -> bool {
+        match self {
+            Self::Glob(re, parts) => {
+                // The first name is always the schema, and the second one is the table or function
+                let (start, end) = if *parts == 1 { (1, 2) } else { (0, *parts) };
+                let end = end.min(names.len());
+                re.is_match(&names[start.min(end)..end].join("."))
+            }
+            Self::Regex(re) => re.is_match(&names.join(".")),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Glob(re, _) | Self::Regex(re) => re.as_str(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::OptOneMany::{Many, NoVals, One};
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PublishFilter {
+        let list = |v: &[&str]| Many(v.iter().map(ToString::to_string).collect());
+        PublishFilter::new(&list(include), &list(exclude))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn match_globs() {
+        let flt = filter(&["public.tiles_*"], &["*_staging"]);
+        assert!(flt.is_match(&["public", "tiles_roads", "geom"]));
+        assert!(!flt.is_match(&["public", "tiles_roads_staging", "geom"]));
+        assert!(!flt.is_match(&["internal", "tiles_roads", "geom"]));
+        assert!(!flt.is_match(&["public", "roads", "geom"]));
+
+        let flt = filter(&[], &["*.*.geom_??", "audit.*"]);
+        assert!(flt.is_match(&["public", "roads", "geom"]));
+        assert!(!flt.is_match(&["public", "roads", "geom_3d"]));
+        assert!(!flt.is_match(&["audit", "roads", "geom"]));
+        assert!(!flt.is_match(&["audit", "get_tiles"]));
+        assert!(flt.is_match(&["public", "get_tiles"]));
+    }
+
+    #[test]
+    fn match_regexes() {
+        let flt = filter(&[r"/^public\.tiles_\d+\./"], &[]);
+        assert!(flt.is_match(&["public", "tiles_12", "geom"]));
+        assert!(!flt.is_match(&["public", "tiles_x", "geom"]));
+        assert!(!flt.is_match(&["other", "tiles_12", "geom"]));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert_eq!(PublishFilter::new(&NoVals, &NoVals).unwrap(), None);
+        let res = PublishFilter::new(&One("/(/".to_string()), &NoVals);
+        assert!(matches!(res, Err(InvalidPublishPattern(_, p)) if p == "/(/"));
+    }
+}
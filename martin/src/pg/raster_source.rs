@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use futures::FutureExt as _;
+use itertools::Itertools as _;
+use log::warn;
+use postgis::ewkb;
+use postgres_protocol::escape::{escape_identifier, escape_literal};
+use tilejson::Bounds;
+
+use crate::args::BoundsCalcType;
+use crate::pg::config::PgInfo;
+use crate::pg::config_raster::{RasterFormat, RasterInfo};
+use crate::pg::pg_source::PgSqlInfo;
+use crate::pg::pool::{begin_discovery, PgPool};
+use crate::pg::utils::{compute_bounds, polygon_to_bbox, BoundsFuture, InfoMap};
+use crate::pg::PgError::PostgresError;
+use crate::pg::PgResult;
+
+pub type SqlRasterInfoMapMapMap = InfoMap<InfoMap<InfoMap<RasterInfo>>>;
+
+const DEFAULT_TILE_SIZE: u16 = 256;
+
+/// Get all raster columns registered in the `raster_columns` view.
+/// Returns an empty map if the `postgis_raster` extension is not installed.
+pub async fn query_available_rasters(pool: &PgPool) -> PgResult<SqlRasterInfoMapMapMap> {
+    let mut conn = pool.get().await?;
+    let tx = begin_discovery(&mut conn).await?;
+    let installed: bool = tx
+        .query_one("SELECT to_regclass('raster_columns') IS NOT NULL", &[])
+        .await
+        .map_err(|e| PostgresError(e, "checking for PostGIS raster support"))?
+        .get(0);
+    let mut res = SqlRasterInfoMapMapMap::new();
+    if !installed {
+        return Ok(res);
+    }
+
+    let rows = tx
+        .query(
+            "SELECT r_table_schema::text AS schema, r_table_name::text AS name,
+                    r_raster_column::text AS rast, srid, num_bands
+             FROM raster_columns",
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying available rasters"))?;
+
+    for row in &rows {
+        let info = RasterInfo {
+            schema: row.get("schema"),
+            table: row.get("name"),
+            raster_column: row.get("rast"),
+            srid: row.get::<_, Option<i32>>("srid").unwrap_or_default(),
+            num_bands: row.get("num_bands"),
+            ..Default::default()
+        };
+        if let Some(v) = res
+            .entry(info.schema.clone())
+            .or_default()
+            .entry(info.table.clone())
+            .or_default()
+            .insert(info.raster_column.clone(), info)
+        {
+            warn!("Unexpected duplicate raster {}", v.format_id());
+        }
+    }
+
+    Ok(res)
+}
+
+pub async fn raster_to_query(
+    id: String,
+    mut info: RasterInfo,
+    pool: PgPool,
+    bounds_type: BoundsCalcType,
+    bounds_timeout: Option<Duration>,
+) -> PgResult<(String, PgSqlInfo, RasterInfo, Option<BoundsFuture>)> {
+    let mut background_bounds = None;
+    if info.bounds.is_none() {
+        let estimate = estimate_raster_bounds(
+            pool.clone(),
+            info.schema.clone(),
+            info.table.clone(),
+            info.raster_column.clone(),
+        );
+        let exact = calc_raster_bounds(
+            pool.clone(),
+            escape_identifier(&info.schema),
+            escape_identifier(&info.table),
+            escape_identifier(&info.raster_column),
+            info.srid,
+        );
+        (info.bounds, background_bounds) = compute_bounds(
+            &id,
+            &info.format_id(),
+            info.auto_bounds.unwrap_or(bounds_type),
+            bounds_timeout,
+            estimate.boxed(),
+            exact.boxed(),
+        )
+        .await?;
+    }
+
+    let signature = format!("raster {}", info.format_id());
+    let query = raster_tile_sql(&info);
+    Ok((
+        id,
+        PgSqlInfo::new(query, false, signature),
+        info,
+        background_bounds,
+    ))
+}
+
+/// Use the extent of the raster that is kept in `raster_columns` by the raster constraints
+async fn estimate_raster_bounds(
+    pool: PgPool,
+    schema: String,
+    table: String,
+    column: String,
+) -> PgResult<Option<Bounds>> {
+    let mut conn = pool.get().await?;
+    let bounds = begin_discovery(&mut conn)
+        .await?
+        .query_opt(
+            "SELECT ST_Envelope(ST_Transform(extent, 4326)) AS bounds
+             FROM raster_columns
+             WHERE r_table_schema = $1 AND r_table_name = $2 AND r_raster_column = $3",
+            &[&schema, &table, &column],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying raster extent"))?
+        .and_then(|row| row.get::<_, Option<ewkb::Polygon>>("bounds"))
+        .and_then(|p| polygon_to_bbox(&p));
+    Ok(bounds)
+}
+
+async fn calc_raster_bounds(
+    pool: PgPool,
+    schema: String,
+    table: String,
+    column: String,
+    srid: i32,
+) -> PgResult<Option<Bounds>> {
+    let mut conn = pool.get().await?;
+    let bounds = begin_discovery(&mut conn)
+        .await?
+        .query_one(
+            &format!(
+                "SELECT ST_Transform(ST_SetSRID(ST_Extent(ST_Envelope({column})), {srid}), 4326) AS bounds
+                 FROM {schema}.{table}"
+            ),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying raster bounds"))?
+        .get::<_, Option<ewkb::Polygon>>("bounds")
+        .and_then(|p| polygon_to_bbox(&p));
+    Ok(bounds)
+}
+
+/// Build a query that renders a tile image from all rasters intersecting the tile.
+/// Each raster is warped onto the pixel grid of the tile, and the results are merged on top
+/// of an empty raster covering the whole tile, so that the image always has the full tile size.
+/// Tiles without any rasters produce `NULL`, i.e. an empty tile.
+#[must_use]
+pub fn raster_tile_sql(info: &RasterInfo) -> String {
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let column = escape_identifier(&info.raster_column);
+    let srid = info.srid;
+    let size = info.tile_size.unwrap_or(DEFAULT_TILE_SIZE);
+    let resampling = info.resampling.unwrap_or_default().as_sql();
+
+    let mut rast = format!("t.{column}");
+    if let Some(bands) = info.bands.as_ref().filter(|v| !v.is_empty()) {
+        rast = format!("ST_Band({rast}, ARRAY[{}])", bands.iter().join(", "));
+    }
+
+    let mut image = "ST_Clip(ST_Union(u.rast ORDER BY u.ord), (SELECT env FROM ref))".to_string();
+    if let Some(colormap) = &info.colormap {
+        image = format!("ST_ColorMap({image}, 1, {})", escape_literal(colormap));
+    }
+    let format = info.format.unwrap_or_default();
+    let encode = match format {
+        RasterFormat::Png => format!("ST_AsPNG({image})"),
+        RasterFormat::Webp => format!("ST_AsGDALRaster({image}, 'WEBP')"),
+        // JPEG has no alpha channel, so drop the one added by the color map
+        RasterFormat::Jpeg if info.colormap.is_some() => {
+            format!("ST_AsJPEG(ST_Band({image}, ARRAY[1, 2, 3]))")
+        }
+        RasterFormat::Jpeg => format!("ST_AsJPEG({image})"),
+    };
+
+    // The padding raster uses the band types and nodata values of the rendered rasters
+    format!(
+        r"
+WITH
+  ref AS (
+    SELECT env, ST_MakeEmptyRaster(
+      {size}, {size}, ST_XMin(env), ST_YMax(env),
+      (ST_XMax(env) - ST_XMin(env)) / {size}, (ST_YMin(env) - ST_YMax(env)) / {size}, 0, 0, 3857
+    ) AS rast
+    FROM (SELECT ST_TileEnvelope($1::integer, $2::integer, $3::integer) AS env) AS tile
+  ),
+  src AS (
+    SELECT ST_Transform({rast}, ref.rast, '{resampling}') AS rast
+    FROM {schema}.{table} AS t, ref
+    WHERE ST_ConvexHull(t.{column}) && ST_Transform(ref.env, {srid})
+  ),
+  padding AS (
+    SELECT ST_AddBand(ref.rast, (
+      SELECT array_agg(
+        ROW(NULL, ST_BandPixelType(s.rast, b), COALESCE(ST_BandNoDataValue(s.rast, b), 0),
+            ST_BandNoDataValue(s.rast, b))::addbandarg
+        ORDER BY b)
+      FROM (SELECT rast FROM src LIMIT 1) AS s, generate_series(1, ST_NumBands(s.rast)) AS b
+    )) AS rast
+    FROM ref
+    WHERE EXISTS (SELECT 1 FROM src)
+  )
+SELECT {encode}
+FROM (SELECT 0 AS ord, rast FROM padding UNION ALL SELECT 1, rast FROM src) AS u"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg::config_raster::Resampling;
+
+    #[test]
+    fn raster_tile_query() {
+        let mut info = RasterInfo {
+            schema: "public".to_string(),
+            table: "dem".to_string(),
+            raster_column: "rast".to_string(),
+            srid: 4326,
+            ..Default::default()
+        };
+        let sql = raster_tile_sql(&info);
+        assert!(sql.contains("ST_MakeEmptyRaster(\n      256, 256,"));
+        assert!(sql.contains("ST_Transform(t.\"rast\", ref.rast, 'NearestNeighbor')"));
+        assert!(sql.contains("FROM \"public\".\"dem\" AS t"));
+        assert!(sql.contains("ST_Transform(ref.env, 4326)"));
+        assert!(sql.contains("SELECT ST_AsPNG(ST_Clip("));
+
+        info.bands = Some(vec![1]);
+        info.colormap = Some("pseudocolor".to_string());
+        info.format = Some(RasterFormat::Jpeg);
+        info.resampling = Some(Resampling::Bilinear);
+        info.tile_size = Some(512);
+        let sql = raster_tile_sql(&info);
+        assert!(sql.contains("ST_MakeEmptyRaster(\n      512, 512,"));
+        assert!(sql.contains("ST_Transform(ST_Band(t.\"rast\", ARRAY[1]), ref.rast, 'Bilinear')"));
+        assert!(sql.contains("SELECT ST_AsJPEG(ST_Band(ST_ColorMap(ST_Clip("));
+        assert!(sql.contains(", 1, 'pseudocolor'), ARRAY[1, 2, 3]))"));
+    }
+}
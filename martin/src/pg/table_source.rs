@@ -1,30 +1,44 @@
 use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
 
-use futures::pin_mut;
+use std::time::Duration;
+
+use futures::FutureExt as _;
 use log::{debug, info, warn};
 use postgis::ewkb;
 use postgres_protocol::escape::{escape_identifier, escape_literal};
 use serde_json::Value;
 use tilejson::Bounds;
-use tokio::time::timeout;
 
-use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
+use crate::args::BoundsCalcType;
 use crate::pg::config::PgInfo;
-use crate::pg::config_table::TableInfo;
+use crate::pg::config_table::{PolygonAnchor, TableInfo, ZmValue, ZoomRule};
 use crate::pg::configurator::SqlTableInfoMapMapMap;
 use crate::pg::pg_source::PgSqlInfo;
-use crate::pg::pool::PgPool;
-use crate::pg::utils::{json_to_hashmap, normalize_key, polygon_to_bbox};
+use crate::pg::pool::{begin_discovery, PgPool};
+use crate::pg::utils::{
+    compute_bounds, find_kv_ignore_case, json_to_hashmap, normalize_key, polygon_to_bbox,
+    BoundsFuture,
+};
 use crate::pg::PgError::PostgresError;
 use crate::pg::PgResult;
+use crate::utils::antimeridian::Antimeridian;
+use crate::utils::mvt::{wgs84_to_mercator, MAX_LATITUDE};
+use crate::TileMatrixSet;
 
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
 static DEFAULT_CLIP_GEOM: bool = true;
 
+/// Column types that can be used as MVT feature IDs as they are
+pub const INTEGER_TYPES: &[&str] = &["int2", "int4", "int8"];
+/// Column types that are rounded to be used as MVT feature IDs
+const NUMERIC_TYPES: &[&str] = &["numeric", "float4", "float8"];
+
 pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMapMap> {
-    let conn = pool.get().await?;
-    let rows = conn
+    let mut conn = pool.get().await?;
+    let rows = begin_discovery(&mut conn)
+        .await?
         .query(include_str!("scripts/query_available_tables.sql"), &[])
         .await
         .map_err(|e| PostgresError(e, "querying available tables"))?;
@@ -95,66 +109,191 @@ fn escape_with_alias(mapping: &HashMap<String, String>, field: &str) -> String {
     }
 }
 
+/// A selected property column, cast to the configured type if any,
+/// and omitted at the zoom levels whose rule does not include it
+fn property_sql(info: &TableInfo, field: &str) -> String {
+    let cast = info.casts.as_ref().and_then(|v| v.get(field));
+    let is_hidden = |rule: &ZoomRule| {
+        rule.properties
+            .as_ref()
+            .map_or(false, |v| !v.iter().any(|p| p == field))
+    };
+    let has_rules = info.zoom_rules.iter().flatten().any(is_hidden);
+    if cast.is_none() && !has_rules {
+        return escape_with_alias(&info.prop_mapping, field);
+    }
+    let column = info.prop_mapping.get(field).map_or(field, String::as_str);
+    let mut value = escape_identifier(column);
+    if let Some(typ) = cast {
+        value = format!("{value}::{typ}");
+    }
+    if has_rules {
+        // ST_AsMVT skips the NULL properties
+        value = zoom_case_sql(info, &value, |rule| {
+            if is_hidden(rule) {
+                "NULL".to_string()
+            } else {
+                value.clone()
+            }
+        });
+    }
+    format!(", {value} AS {}", escape_identifier(field))
+}
+
+/// SQL condition matching the requested zoom with the zoom band of a rule
+fn zoom_band_sql(rule: &ZoomRule) -> String {
+    match (rule.minzoom, rule.maxzoom) {
+        (Some(min), Some(max)) => format!("$1::integer BETWEEN {min} AND {max}"),
+        (Some(min), None) => format!("$1::integer >= {min}"),
+        (None, Some(max)) => format!("$1::integer <= {max}"),
+        (None, None) => "true".to_string(),
+    }
+}
+
+/// SQL expression with the value of the first zoom rule matching the requested zoom, or the default value
+fn zoom_case_sql(info: &TableInfo, default: &str, value: impl Fn(&ZoomRule) -> String) -> String {
+    let mut sql = "CASE".to_string();
+    for rule in info.zoom_rules.iter().flatten() {
+        let _ = write!(sql, " WHEN {} THEN {}", zoom_band_sql(rule), value(rule));
+    }
+    format!("{sql} ELSE {default} END")
+}
+
+/// Size of a tile coordinate unit in the coordinates of the grid at the requested zoom,
+/// i.e. Web Mercator meters or degrees
+fn tile_unit_sql(extent: u32, grid: TileMatrixSet) -> String {
+    format!("({} / ({extent} * 2^$1::integer))", grid.zoom0_tile_size())
+}
+
+/// SQL expression of the requested tile envelope in the SRID of the grid,
+/// expanded by a `margin` relative to the tile size
+fn tile_envelope_sql(grid: TileMatrixSet, margin: Option<f64>) -> String {
+    let margin = margin
+        .map(|v| format!(", margin => {v}"))
+        .unwrap_or_default();
+    match grid {
+        TileMatrixSet::WebMercatorQuad => {
+            format!("ST_TileEnvelope($1::integer, $2::integer, $3::integer{margin})")
+        }
+        // The zoom 0 tiles are 180 degrees wide, like the zoom 1 tiles of a 360 degree square grid
+        // whose bottom half is below the south pole, and never requested
+        TileMatrixSet::WorldCrs84Quad => format!(
+            "ST_TileEnvelope($1::integer + 1, $2::integer, $3::integer, ST_MakeEnvelope(-180, -270, 180, 90, 4326){margin})"
+        ),
+    }
+}
+
+/// SQL condition skipping the polygons and the lines that are too small for the zoom rule
+/// of the requested zoom, or an empty string if no rule sets a minimum size
+fn min_size_sql(info: &TableInfo, geom: &str, extent: u32, grid: TileMatrixSet) -> String {
+    let rules = info.zoom_rules.iter().flatten();
+    if rules
+        .clone()
+        .all(|r| r.min_area.is_none() && r.min_length.is_none())
+    {
+        return String::new();
+    }
+    let unit = tile_unit_sql(extent, grid);
+    let cond = zoom_case_sql(info, "true", |rule| {
+        let mut conds = Vec::new();
+        if let Some(area) = rule.min_area {
+            conds.push(format!(
+                "(ST_Dimension({geom}) <> 2 OR ST_Area({geom}) >= {area} * {unit}^2)"
+            ));
+        }
+        if let Some(length) = rule.min_length {
+            conds.push(format!(
+                "(ST_Dimension({geom}) <> 1 OR ST_Length({geom}) >= {length} * {unit})"
+            ));
+        }
+        if conds.is_empty() {
+            "true".to_string()
+        } else {
+            conds.join(" AND ")
+        }
+    });
+    format!("\n    AND {cond}")
+}
+
+/// Type names may contain spaces, modifiers, and array brackets, e.g. `double precision` or `varchar(20)[]`
+fn is_valid_cast_type(typ: &str) -> bool {
+    !typ.trim().is_empty()
+        && typ
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || " _.,()[]".contains(c))
+}
+
+#[allow(clippy::too_many_lines)]
 pub async fn table_to_query(
     id: String,
     mut info: TableInfo,
     pool: PgPool,
     bounds_type: BoundsCalcType,
+    bounds_timeout: Option<Duration>,
     max_feature_count: Option<usize>,
-) -> PgResult<(String, PgSqlInfo, TableInfo)> {
+    deterministic: bool,
+) -> PgResult<(String, PgSqlInfo, TableInfo, Option<BoundsFuture>)> {
     let schema = escape_identifier(&info.schema);
     let table = escape_identifier(&info.table);
     let geometry_column = escape_identifier(&info.geometry_column);
     let srid = info.srid;
+    let reproject = info.reproject.unwrap_or(true);
+    let grid = info.tile_matrix_set.unwrap_or_default();
+    let grid_srid = grid.srid();
+    // without reprojection, the coordinates are used as those of the grid whatever the column SRID is
+    let geom_srid = if reproject { srid } else { grid_srid };
 
+    let mut background_bounds = None;
     if info.bounds.is_none() {
-        match bounds_type {
-            BoundsCalcType::Skip => {}
-            BoundsCalcType::Quick | BoundsCalcType::Calc => {
-                let bounds = calc_bounds(&pool, &schema, &table, &geometry_column, srid);
-                if bounds_type == BoundsCalcType::Calc {
-                    info.bounds = bounds.await?;
-                } else {
-                    pin_mut!(bounds);
-                    if let Ok(bounds) = timeout(DEFAULT_BOUNDS_TIMEOUT, &mut bounds).await {
-                        info.bounds = bounds?;
-                    } else {
-                        warn!(
-                            "Timeout computing {} bounds for {id}, aborting query. Use --auto-bounds=calc to wait until complete, or check the table for missing indices.",
-                            info.format_id(),
-                        );
-                    }
-                }
-            }
-        }
+        let estimate = estimate_bounds(
+            pool.clone(),
+            escape_literal(&info.schema),
+            escape_literal(&info.table),
+            escape_literal(&info.geometry_column),
+            geom_srid,
+        );
+        let exact = calc_bounds(
+            pool.clone(),
+            schema.clone(),
+            table.clone(),
+            geometry_column.clone(),
+            geom_srid,
+        );
+        (info.bounds, background_bounds) = compute_bounds(
+            &id,
+            &info.format_id(),
+            info.auto_bounds.unwrap_or(bounds_type),
+            bounds_timeout,
+            estimate.boxed(),
+            exact.boxed(),
+        )
+        .await?;
     }
 
-    let properties = if let Some(props) = &info.properties {
+    let mut properties = if let Some(props) = &info.properties {
         props
             .keys()
-            .map(|column| escape_with_alias(&info.prop_mapping, column))
+            .map(|column| property_sql(&info, column))
             .collect::<String>()
     } else {
         String::new()
     };
+    for (name, value) in info.zm_attribute_names() {
+        let value = zm_value_sql(&geometry_column, value);
+        let _ = write!(properties, ", {value} AS {}", escape_identifier(&name));
+    }
 
-    let (id_name, id_field) = if let Some(id_column) = &info.id_column {
-        (
-            format!(", {}", escape_literal(id_column)),
-            escape_with_alias(&info.prop_mapping, id_column),
-        )
-    } else {
-        (String::new(), String::new())
-    };
+    let (id_name, id_field) = feature_id_sql(&info);
 
     let extent = info.extent.unwrap_or(DEFAULT_EXTENT);
     let buffer = info.buffer.unwrap_or(DEFAULT_BUFFER);
 
+    let tile_envelope = tile_envelope_sql(grid, None);
     let bbox_search = if buffer == 0 {
-        "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
+        tile_envelope.clone()
     } else if pool.supports_tile_margin() {
         let margin = f64::from(buffer) / f64::from(extent);
-        format!("ST_TileEnvelope($1::integer, $2::integer, $3::integer, margin => {margin})")
+        tile_envelope_sql(grid, Some(margin))
     } else {
         // TODO: we should use ST_Expand here, but it may require a bit more math work,
         //       so might not be worth it as it is only used for PostGIS < v3.1.
@@ -162,64 +301,391 @@ pub async fn table_to_query(
         // let earth_circumference = 40075016.6855785;
         // let val = earth_circumference * buffer as f64 / extent as f64;
         // format!("ST_Expand(ST_TileEnvelope($1::integer, $2::integer, $3::integer), {val}/2^$1::integer)")
-        "ST_TileEnvelope($1::integer, $2::integer, $3::integer)".to_string()
+        tile_envelope.clone()
     };
 
+    let order_clause = if deterministic {
+        order_by_all_columns(&info, &format!("{schema}.{table}"))
+    } else {
+        String::new()
+    };
     let limit_clause = max_feature_count.map_or(String::new(), |v| format!("LIMIT {v}"));
     let layer_id = escape_literal(info.layer_id.as_ref().unwrap_or(&id));
     let clip_geom = info.clip_geom.unwrap_or(DEFAULT_CLIP_GEOM);
-    let query = format!(
+    let mut geom = if reproject {
+        format!("ST_CurveToLine({geometry_column})")
+    } else {
+        format!("ST_CurveToLine(ST_SetSRID({geometry_column}, {grid_srid}))")
+    };
+    if info.make_valid == Some(true) {
+        // ST_MakeValid may return a geometry collection, which cannot be encoded as MVT,
+        // so only keep its parts of the highest dimension (PostGIS v3.1+)
+        geom = if pool.supports_tile_margin() {
+            format!("ST_CollectionExtract(ST_MakeValid({geom}))")
+        } else {
+            format!("ST_MakeValid({geom})")
+        };
+    }
+    if let Some(mode) = info.antimeridian {
+        geom = antimeridian_sql(&geom, geom_srid, mode, grid);
+    }
+    let envelope = envelope_sql(&bbox_search, srid, reproject, info.bounds, grid);
+    let mut bbox_filter = format!("{geometry_column} && {envelope}");
+    if info.antimeridian == Some(Antimeridian::Wrap) && geom_srid == 4326 {
+        // features beyond the antimeridian are moved by a whole world width
+        for dx in [360, -360] {
+            bbox_filter = format!(
+                "{bbox_filter}\n    OR {geometry_column} && ST_Translate(ST_Transform({bbox_search}, {srid}), {dx}, 0)"
+            );
+        }
+    }
+    let attr_filter = filter_sql(&info);
+    let grid_geom = if reproject {
+        format!("ST_Transform({geometry_column}, {grid_srid})")
+    } else {
+        format!("ST_SetSRID({geometry_column}, {grid_srid})")
+    };
+    let size_filter = min_size_sql(&info, &grid_geom, extent, grid);
+    if !attr_filter.is_empty() || !size_filter.is_empty() {
+        bbox_filter = format!("({bbox_filter}){attr_filter}{size_filter}");
+    }
+    let mut tile_geom = format!("ST_Transform({geom}, {grid_srid})");
+    if info
+        .zoom_rules
+        .iter()
+        .flatten()
+        .any(|r| r.simplify.is_some())
+    {
+        let unit = tile_unit_sql(extent, grid);
+        let tolerance = zoom_case_sql(&info, "0", |rule| {
+            rule.simplify
+                .map_or("0".to_string(), |v| format!("{v} * {unit}"))
+        });
+        tile_geom = format!("ST_SimplifyPreserveTopology({tile_geom}, {tolerance})");
+    }
+    let tile_query = format!(
         r#"
 SELECT
   ST_AsMVT(tile, {layer_id}, {extent}, 'geom'{id_name})
 FROM (
   SELECT
     ST_AsMVTGeom(
-        ST_Transform(ST_CurveToLine({geometry_column}), 3857),
-        ST_TileEnvelope($1::integer, $2::integer, $3::integer),
+        {tile_geom},
+        {tile_envelope},
         {extent}, {buffer}, {clip_geom}
     ) AS geom
     {id_field}{properties}
   FROM
     {schema}.{table}
   WHERE
-    {geometry_column} && ST_Transform({bbox_search}, {srid})
+    {bbox_filter}
+  {order_clause}
   {limit_clause}
-) AS tile;
-"#
+) AS tile"#
+    );
+
+    let query = if let Some(cfg) = info.label_config() {
+        let label_layer_id = info
+            .label_layer_id(info.layer_id.as_ref().unwrap_or(&id))
+            .unwrap_or_default();
+        let label_layer_id = escape_literal(&label_layer_id);
+        let mut anchor = cfg.polygon_anchor.unwrap_or_default();
+        if anchor == PolygonAnchor::Pole && !pool.supports_tile_margin() {
+            warn!("Polygon pole of inaccessibility requires PostGIS v3.1+, using a point on surface for the labels of {id}");
+            anchor = PolygonAnchor::Surface;
+        }
+        let label_point = label_point_sql("label_src.martin_label_geom", anchor);
+        // The anchor point is computed from the whole geometry, so that the same label
+        // is placed at the same location regardless of the tile that contains it.
+        format!(
+            r"
+SELECT COALESCE(({tile_query}
+), ''::bytea) || COALESCE((
+SELECT
+  ST_AsMVT(label, {label_layer_id}, {extent}, 'geom'{id_name})
+FROM (
+  SELECT
+    ST_AsMVTGeom(
+        {label_point},
+        {tile_envelope},
+        {extent}, {buffer}, true
+    ) AS geom
+    {id_field}{properties}
+  FROM
+    {schema}.{table},
+    LATERAL (SELECT ST_Transform({geom}, {grid_srid}) AS martin_label_geom) AS label_src
+  WHERE
+    {bbox_filter}
+  {order_clause}
+  {limit_clause}
+) AS label
+), ''::bytea);
+"
+        )
+    } else {
+        format!("{tile_query};")
+    };
+    let query = query.trim().to_string();
+
+    let use_url_query = !attr_filter.is_empty();
+    Ok((
+        id,
+        PgSqlInfo::new(query, use_url_query, info.format_id()),
+        info,
+        background_bounds,
+    ))
+}
+
+/// The `feature_id_name` parameter of `ST_AsMVT`, and the selected ID column.
+/// `ST_AsMVT` only uses integer columns as feature IDs, so the numeric ones are rounded to `bigint`.
+fn feature_id_sql(info: &TableInfo) -> (String, String) {
+    let Some(id_column) = &info.id_column else {
+        return (String::new(), String::new());
+    };
+    let id_name = format!(", {}", escape_literal(id_column));
+    let is_numeric = info
+        .id_column_type
+        .as_deref()
+        .map_or(false, |typ| NUMERIC_TYPES.contains(&typ));
+    let id_field = if is_numeric {
+        let column = info
+            .prop_mapping
+            .get(id_column)
+            .map_or(id_column.as_str(), String::as_str);
+        format!(
+            ", round({})::int8 AS {}",
+            escape_identifier(column),
+            escape_identifier(id_column)
+        )
+    } else {
+        escape_with_alias(&info.prop_mapping, id_column)
+    };
+    (id_name, id_field)
+}
+
+/// SQL conditions comparing the filter columns with the URL query parameters passed as JSON in `$4`.
+/// A condition is ignored if its parameter is missing, so that a single prepared query serves all requests.
+fn filter_sql(info: &TableInfo) -> String {
+    let Some(filter) = &info.filter else {
+        return String::new();
+    };
+    let mut sql = String::new();
+    for (param, column) in filter {
+        let value = format!("($4::json ->> {})", escape_literal(param));
+        let cond = match info.filter_types.get(column) {
+            Some(typ) => format!(
+                "{} = {value}::{}",
+                escape_identifier(column),
+                escape_identifier(typ)
+            ),
+            None => format!("{}::text = {value}", escape_identifier(column)),
+        };
+        let _ = write!(sql, "\n    AND ({value} IS NULL OR {cond})");
+    }
+    sql
+}
+
+/// SQL expression of a tile envelope of the grid in the SRID of the geometry column, so that
+/// the spatial index can be used to find the features of the tile
+fn envelope_sql(
+    envelope: &str,
+    srid: i32,
+    reproject: bool,
+    bounds: Option<Bounds>,
+    grid: TileMatrixSet,
+) -> String {
+    if !reproject {
+        return format!("ST_SetSRID({envelope}, {srid})");
+    }
+    if srid == 3857 || srid == 4326 {
+        // tile envelopes are rectangles in both
+        return format!("ST_Transform({envelope}, {srid})");
+    }
+    // The edges of the envelope may be curved in other projections, so they are segmented.
+    // These projections may also be undefined for the rest of the world, e.g. national grids,
+    // so the envelope is clipped to the table bounds first.
+    let envelope = match bounds {
+        Some(b) if b.left <= b.right => {
+            let ([x1, y1], [x2, y2]) = match grid {
+                TileMatrixSet::WebMercatorQuad => (
+                    wgs84_to_mercator(b.left, b.bottom),
+                    wgs84_to_mercator(b.right, b.top),
+                ),
+                TileMatrixSet::WorldCrs84Quad => ([b.left, b.bottom], [b.right, b.top]),
+            };
+            let grid_srid = grid.srid();
+            format!(
+                "ST_ClipByBox2D({envelope}, ST_MakeEnvelope({x1}, {y1}, {x2}, {y2}, {grid_srid}))"
+            )
+        }
+        _ => envelope.to_string(),
+    };
+    // an eighth of the zoom 0 tile, divided by `2^z` for the other zoom levels
+    let segment = grid.zoom0_tile_size() / 8.0;
+    format!("ST_Transform(ST_Segmentize({envelope}, {segment} / 2^$1::integer), {srid})")
+}
+
+/// SQL expression that handles the antimeridian crossing of a geometry, returning it in EPSG:4326
+/// clipped to the latitudes that can be shown in the grid
+fn antimeridian_sql(geom: &str, srid: i32, mode: Antimeridian, grid: TileMatrixSet) -> String {
+    let geom = if srid == 4326 {
+        geom.to_string()
+    } else {
+        format!("ST_Transform({geom}, 4326)")
+    };
+    let geom = match mode {
+        Antimeridian::Clip => geom,
+        Antimeridian::Wrap => format!("ST_WrapX(ST_WrapX({geom}, 180, -360), -180, 360)"),
+        // only the features spanning more than half of the world are assumed to cross the antimeridian
+        Antimeridian::Split => format!(
+            "ST_WrapX(CASE WHEN ST_XMax({geom}) - ST_XMin({geom}) > 180 THEN ST_ShiftLongitude({geom}) ELSE {geom} END, 180, -360)"
+        ),
+    };
+    let max_lat = match grid {
+        TileMatrixSet::WebMercatorQuad => MAX_LATITUDE,
+        TileMatrixSet::WorldCrs84Quad => 90.0,
+    };
+    format!("ST_ClipByBox2D({geom}, ST_MakeEnvelope(-180, -{max_lat}, 180, {max_lat}, 4326))")
+}
+
+/// SQL expression of the Z or M range value of a geometry, or `NULL` if it has no such dimension.
+/// These values are lost when the geometry is encoded as MVT.
+fn zm_value_sql(geom: &str, value: ZmValue) -> String {
+    // ST_Zmflag: 0 = 2D, 1 = M, 2 = Z, 3 = ZM
+    let (flags, expr) = match value {
+        ZmValue::ZMin => ("2, 3", format!("ST_ZMin({geom})")),
+        ZmValue::ZMax => ("2, 3", format!("ST_ZMax({geom})")),
+        ZmValue::MMin => (
+            "1, 3",
+            format!("(SELECT min(ST_M(pt.geom)) FROM ST_DumpPoints({geom}) AS pt)"),
+        ),
+        ZmValue::MMax => (
+            "1, 3",
+            format!("(SELECT max(ST_M(pt.geom)) FROM ST_DumpPoints({geom}) AS pt)"),
+        ),
+    };
+    format!("CASE WHEN ST_Zmflag({geom}) IN ({flags}) THEN {expr} END")
+}
+
+/// SQL expression of a label anchor point for the given web mercator geometry:
+/// the middle of the longest line, the chosen anchor of a polygon, or any point of the other geometries.
+fn label_point_sql(geom: &str, anchor: PolygonAnchor) -> String {
+    let polygon_point = match anchor {
+        PolygonAnchor::Pole => format!("(ST_MaximumInscribedCircle({geom})).center"),
+        PolygonAnchor::Surface => format!("ST_PointOnSurface({geom})"),
+    };
+    format!(
+        "CASE ST_GeometryType({geom})
+          WHEN 'ST_LineString' THEN ST_LineInterpolatePoint({geom}, 0.5)
+          WHEN 'ST_MultiLineString' THEN (
+            SELECT ST_LineInterpolatePoint(part.geom, 0.5)
+            FROM ST_Dump(ST_LineMerge({geom})) AS part
+            ORDER BY ST_Length(part.geom) DESC
+            LIMIT 1)
+          WHEN 'ST_Polygon' THEN {polygon_point}
+          WHEN 'ST_MultiPolygon' THEN {polygon_point}
+          ELSE ST_PointOnSurface({geom})
+        END"
     )
-    .trim()
-    .to_string();
+}
+
+/// Sort features by the ID, the geometry, and all properties, so that the tile features are
+/// always in the same order. Columns are qualified with the table name to avoid matching the
+/// output aliases, and properties are compared as text because not all types can be sorted.
+fn order_by_all_columns(info: &TableInfo, table: &str) -> String {
+    let column = |v: &String| {
+        let name = info.prop_mapping.get(v).unwrap_or(v);
+        format!("{table}.{}", escape_identifier(name))
+    };
+    let mut columns = Vec::new();
+    if let Some(id_column) = &info.id_column {
+        columns.push(column(id_column));
+    }
+    columns.push(format!(
+        "ST_AsEWKB({table}.{})",
+        escape_identifier(&info.geometry_column)
+    ));
+    for prop in info.properties.iter().flat_map(|v| v.keys()) {
+        columns.push(format!("{}::text", column(prop)));
+    }
+    format!("ORDER BY {}", columns.join(", "))
+}
 
-    Ok((id, PgSqlInfo::new(query, false, info.format_id()), info))
+/// Estimate the bounds from the statistics gathered by ANALYZE, which is much faster than [`calc_bounds`].
+/// The names are escaped literals, not identifiers.
+async fn estimate_bounds(
+    pool: PgPool,
+    schema: String,
+    table: String,
+    geometry_column: String,
+    srid: i32,
+) -> PgResult<Option<Bounds>> {
+    let mut conn = pool.get().await?;
+    let bounds = begin_discovery(&mut conn)
+        .await?
+        .query_one(
+            &format!(
+                r#"
+WITH estimated AS (
+    SELECT ST_SetSRID(ST_EstimatedExtent({schema}, {table}, {geometry_column})::geometry, {srid}) AS eb
+),
+src_bounds AS (
+    -- the extent of a single point or of a straight line is not a polygon
+    SELECT CASE
+               WHEN ST_GeometryType(eb) = 'ST_Polygon' THEN eb
+               ELSE ST_Envelope(ST_Expand(eb, 1))
+           END AS sb
+    FROM estimated
+)
+SELECT ST_Envelope(ST_Transform(
+            ST_Segmentize(sb, GREATEST(ST_XMax(sb) - ST_XMin(sb), ST_YMax(sb) - ST_YMin(sb)) / 16),
+            4326
+        )) AS bounds
+FROM src_bounds;
+                "#
+            ),
+            &[],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "estimating table bounds"))?
+        .get::<_, Option<ewkb::Polygon>>("bounds")
+        .and_then(|p| polygon_to_bbox(&p));
+    Ok(bounds)
 }
 
 async fn calc_bounds(
-    pool: &PgPool,
-    schema: &str,
-    table: &str,
-    geometry_column: &str,
+    pool: PgPool,
+    schema: String,
+    table: String,
+    geometry_column: String,
     srid: i32,
 ) -> PgResult<Option<Bounds>> {
-    Ok(pool.get()
+    let mut conn = pool.get().await?;
+    let bounds = begin_discovery(&mut conn)
         .await?
         .query_one(&format!(
             r#"
-WITH real_bounds AS (SELECT ST_SetSRID(ST_Extent({geometry_column}), {srid}) AS rb FROM {schema}.{table})
-SELECT ST_Transform(
-            CASE
-                WHEN (SELECT ST_GeometryType(rb) FROM real_bounds LIMIT 1) = 'ST_Point'
-                THEN ST_SetSRID(ST_Extent(ST_Expand({geometry_column}, 1)), {srid})
-                ELSE (SELECT * FROM real_bounds)
-            END,
+WITH real_bounds AS (SELECT ST_SetSRID(ST_Extent({geometry_column}), {srid}) AS rb FROM {schema}.{table}),
+src_bounds AS (
+    SELECT CASE
+               WHEN (SELECT ST_GeometryType(rb) FROM real_bounds LIMIT 1) = 'ST_Point'
+               THEN ST_SetSRID(ST_Extent(ST_Expand({geometry_column}, 1)), {srid})
+               ELSE (SELECT * FROM real_bounds)
+           END AS sb
+    FROM {schema}.{table}
+)
+-- the edges are segmented, because they may be curved in EPSG:4326
+SELECT ST_Envelope(ST_Transform(
+            ST_Segmentize(sb, GREATEST(ST_XMax(sb) - ST_XMin(sb), ST_YMax(sb) - ST_YMin(sb)) / 16),
             4326
-        ) AS bounds
-FROM {schema}.{table};
+        )) AS bounds
+FROM src_bounds;
                 "#), &[])
         .await
         .map_err(|e| PostgresError(e, "querying table bounds"))?
         .get::<_, Option<ewkb::Polygon>>("bounds")
-        .and_then(|p| polygon_to_bbox(&p)))
+        .and_then(|p| polygon_to_bbox(&p));
+    Ok(bounds)
 }
 
 #[must_use]
@@ -238,7 +704,12 @@ pub fn merge_table_info(
         geometry_column: db_inf.geometry_column.clone(),
         geometry_index: db_inf.geometry_index,
         is_view: db_inf.is_view,
-        srid: calc_srid(&table_id, new_id, db_inf.srid, cfg_inf.srid, default_srid)?,
+        srid: if cfg_inf.reproject == Some(false) && db_inf.srid == 0 && cfg_inf.srid == 0 {
+            // the coordinates are used as they are, so the unknown SRID does not matter
+            0
+        } else {
+            calc_srid(&table_id, new_id, db_inf.srid, cfg_inf.srid, default_srid)?
+        },
         prop_mapping: HashMap::new(),
         ..cfg_inf.clone()
     };
@@ -255,19 +726,117 @@ pub fn merge_table_info(
 
     if let Some(id_column) = &cfg_inf.id_column {
         let prop = normalize_key(props, id_column.as_str(), "id_column", new_id)?;
-        inf.prop_mapping.insert(id_column.clone(), prop);
+        let typ = &props[&prop];
+        if INTEGER_TYPES.contains(&typ.as_str()) || NUMERIC_TYPES.contains(&typ.as_str()) {
+            inf.id_column_type = Some(typ.clone());
+            inf.prop_mapping.insert(id_column.clone(), prop);
+        } else {
+            // ST_AsMVT silently ignores the ID columns of other types
+            warn!("Column {prop} of type {typ} cannot be used as the feature ID of source {new_id}, only integer and numeric columns are supported. The features will have no IDs.");
+            inf.id_column = None;
+        }
     }
 
-    if let Some(p) = &cfg_inf.properties {
+    select_columns(&mut inf, cfg_inf, props, new_id)?;
+
+    if let Some(p) = &inf.properties {
         for key in p.keys() {
             let prop = normalize_key(props, key.as_str(), "property", new_id)?;
             inf.prop_mapping.insert(key.clone(), prop);
         }
     }
 
+    if let Some(casts) = &mut inf.casts {
+        let properties = inf.properties.get_or_insert_with(BTreeMap::new);
+        let mut normalized = BTreeMap::new();
+        for (column, typ) in casts.iter() {
+            if !is_valid_cast_type(typ) {
+                warn!("Unable to configure source {new_id} because the cast of column {column} to '{typ}' is not a valid type name");
+                return None;
+            }
+            let key = normalize_key(properties, column, "cast column", new_id)?;
+            // the property is reported with its new type
+            properties.insert(key.clone(), typ.clone());
+            normalized.insert(key, typ.clone());
+        }
+        *casts = normalized;
+    }
+
+    if let Some(rules) = &mut inf.zoom_rules {
+        let properties = inf.properties.clone().unwrap_or_default();
+        for column in rules
+            .iter_mut()
+            .flat_map(|r| r.properties.iter_mut().flatten())
+        {
+            *column = normalize_key(&properties, column, "zoom rule property", new_id)?;
+        }
+    }
+
+    if let Some(filter) = &mut inf.filter {
+        inf.filter_types.clear();
+        for column in filter.values_mut() {
+            let col = normalize_key(props, column.as_str(), "filter column", new_id)?;
+            inf.filter_types.insert(col.clone(), props[&col].clone());
+            *column = col;
+        }
+    }
+
     Some(inf)
 }
 
+/// Select the property columns with `include_columns` and `exclude_columns`, from the configured
+/// properties or from all the table columns
+fn select_columns(
+    inf: &mut TableInfo,
+    cfg_inf: &TableInfo,
+    props: &BTreeMap<String, String>,
+    new_id: &str,
+) -> Option<()> {
+    if cfg_inf.properties.is_none()
+        && (cfg_inf.include_columns.is_some() || cfg_inf.exclude_columns.is_some())
+    {
+        // select from all the table columns, except for the feature ID
+        let id_column = inf.id_column.as_ref().and_then(|v| inf.prop_mapping.get(v));
+        inf.properties = Some(
+            props
+                .iter()
+                .filter(|(k, _)| Some(*k) != id_column)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+    }
+
+    if let Some(columns) = &cfg_inf.include_columns {
+        let available = inf.properties.take().unwrap_or_default();
+        let mut included = BTreeMap::new();
+        for column in columns {
+            let key = normalize_key(&available, column, "included column", new_id)?;
+            included.insert(key.clone(), available[&key].clone());
+        }
+        inf.properties = Some(included);
+    }
+
+    if let (Some(columns), Some(properties)) = (&cfg_inf.exclude_columns, &mut inf.properties) {
+        for column in columns {
+            let key = if properties.contains_key(column) {
+                Some(column.clone())
+            } else {
+                find_kv_ignore_case(properties, column)
+                    .ok()
+                    .flatten()
+                    .cloned()
+            };
+            if let Some(key) = key {
+                properties.remove(&key);
+            } else {
+                debug!("Excluded column {column} of source {new_id} is not a property");
+            }
+        }
+    }
+
+    Some(())
+}
+
 #[must_use]
 pub fn calc_srid(
     table_id: &str,
@@ -295,3 +864,211 @@ pub fn calc_srid(
         (_, cfg, _) => Some(cfg),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::TileMatrixSet::{WebMercatorQuad, WorldCrs84Quad};
+
+    #[test]
+    fn order_by_all_columns_uses_table_columns() {
+        let info = TableInfo {
+            schema: "public".to_string(),
+            table: "points".to_string(),
+            geometry_column: "geom".to_string(),
+            id_column: Some("id".to_string()),
+            properties: Some(BTreeMap::from([
+                ("name".to_string(), "text".to_string()),
+                ("kind".to_string(), "int4".to_string()),
+            ])),
+            prop_mapping: HashMap::from([("kind".to_string(), "Kind".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            order_by_all_columns(&info, r#""public"."points""#),
+            r#"ORDER BY "public"."points"."id", ST_AsEWKB("public"."points"."geom"), "public"."points"."Kind"::text, "public"."points"."name"::text"#
+        );
+    }
+
+    #[test]
+    fn envelope_in_table_srid() {
+        let env = "ST_TileEnvelope($1::integer, $2::integer, $3::integer)";
+        assert_eq!(
+            envelope_sql(env, 4326, true, None, WebMercatorQuad),
+            format!("ST_Transform({env}, 4326)")
+        );
+        assert_eq!(
+            envelope_sql(env, 0, false, None, WebMercatorQuad),
+            format!("ST_SetSRID({env}, 0)")
+        );
+        assert_eq!(
+            envelope_sql(env, 27700, true, None, WebMercatorQuad),
+            format!("ST_Transform(ST_Segmentize({env}, 5009377.085697311 / 2^$1::integer), 27700)")
+        );
+        let bounds = Bounds::new(-8.0, 49.0, 2.0, 61.0);
+        let sql = envelope_sql(env, 27700, true, Some(bounds), WebMercatorQuad);
+        assert!(sql.starts_with(&format!(
+            "ST_Transform(ST_Segmentize(ST_ClipByBox2D({env}, ST_MakeEnvelope(-890555.92"
+        )));
+        assert!(sql.ends_with(", 3857)), 5009377.085697311 / 2^$1::integer), 27700)"));
+
+        let env = tile_envelope_sql(WorldCrs84Quad, None);
+        assert_eq!(
+            env,
+            "ST_TileEnvelope($1::integer + 1, $2::integer, $3::integer, ST_MakeEnvelope(-180, -270, 180, 90, 4326))"
+        );
+        let sql = envelope_sql(&env, 27700, true, Some(bounds), WorldCrs84Quad);
+        assert!(
+            sql.contains("ST_MakeEnvelope(-8, 49, 2, 61, 4326)), 22.5 / 2^$1::integer), 27700)")
+        );
+    }
+
+    #[test]
+    fn feature_id_is_integer() {
+        let mut info = TableInfo {
+            schema: "public".to_string(),
+            table: "parcels".to_string(),
+            geometry_column: "geom".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(feature_id_sql(&info), (String::new(), String::new()));
+
+        info.id_column = Some("gid".to_string());
+        info.id_column_type = Some("int8".to_string());
+        assert_eq!(
+            feature_id_sql(&info),
+            (", 'gid'".to_string(), r#", "gid""#.to_string())
+        );
+
+        info.id_column_type = Some("numeric".to_string());
+        info.prop_mapping = HashMap::from([("gid".to_string(), "GID".to_string())]);
+        assert_eq!(
+            feature_id_sql(&info),
+            (
+                ", 'gid'".to_string(),
+                r#", round("GID")::int8 AS "gid""#.to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn select_and_cast_columns() {
+        let db_inf = TableInfo {
+            schema: "public".to_string(),
+            table: "buildings".to_string(),
+            geometry_column: "geom".to_string(),
+            srid: 4326,
+            properties: Some(BTreeMap::from([
+                ("gid".to_string(), "int4".to_string()),
+                ("Height".to_string(), "numeric".to_string()),
+                ("name".to_string(), "text".to_string()),
+                ("tags".to_string(), "jsonb".to_string()),
+                ("updated".to_string(), "timestamp".to_string()),
+            ])),
+            ..Default::default()
+        };
+        let mut cfg_inf = TableInfo {
+            schema: "public".to_string(),
+            table: "buildings".to_string(),
+            geometry_column: "geom".to_string(),
+            id_column: Some("gid".to_string()),
+            exclude_columns: Some(vec!["updated".to_string()]),
+            casts: Some(BTreeMap::from([
+                ("height".to_string(), "float8".to_string()),
+                ("tags".to_string(), "text".to_string()),
+            ])),
+            ..Default::default()
+        };
+        let id = "buildings".to_string();
+        let inf = merge_table_info(None, &id, &cfg_inf, &db_inf).unwrap();
+        assert_eq!(
+            inf.properties,
+            Some(BTreeMap::from([
+                ("Height".to_string(), "float8".to_string()),
+                ("name".to_string(), "text".to_string()),
+                ("tags".to_string(), "text".to_string()),
+            ]))
+        );
+        assert_eq!(
+            property_sql(&inf, "Height"),
+            r#", "Height"::float8 AS "Height""#
+        );
+        assert_eq!(property_sql(&inf, "name"), r#", "name""#);
+
+        cfg_inf.include_columns = Some(vec!["name".to_string(), "updated".to_string()]);
+        cfg_inf.casts = None;
+        let inf = merge_table_info(None, &id, &cfg_inf, &db_inf).unwrap();
+        assert_eq!(
+            inf.properties,
+            Some(BTreeMap::from([("name".to_string(), "text".to_string())]))
+        );
+
+        cfg_inf.casts = Some(BTreeMap::from([(
+            "name".to_string(),
+            "text; DROP TABLE buildings".to_string(),
+        )]));
+        assert!(merge_table_info(None, &id, &cfg_inf, &db_inf).is_none());
+    }
+
+    #[test]
+    fn filter_uses_url_query() {
+        let mut info = TableInfo {
+            schema: "public".to_string(),
+            table: "roads".to_string(),
+            geometry_column: "geom".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(filter_sql(&info), "");
+
+        info.filter = Some(BTreeMap::from([
+            ("year".to_string(), "built_year".to_string()),
+            ("kind".to_string(), "category".to_string()),
+        ]));
+        info.filter_types = HashMap::from([("built_year".to_string(), "int4".to_string())]);
+        assert_eq!(
+            filter_sql(&info),
+            r#"
+    AND (($4::json ->> 'kind') IS NULL OR "category"::text = ($4::json ->> 'kind'))
+    AND (($4::json ->> 'year') IS NULL OR "built_year" = ($4::json ->> 'year')::"int4")"#
+        );
+    }
+
+    #[test]
+    fn zoom_rules() {
+        let mut info = TableInfo {
+            schema: "public".to_string(),
+            table: "roads".to_string(),
+            geometry_column: "geom".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(min_size_sql(&info, "g", 4096, WebMercatorQuad), "");
+        assert_eq!(property_sql(&info, "name"), r#", "name""#);
+
+        info.zoom_rules = Some(vec![
+            ZoomRule {
+                maxzoom: Some(5),
+                min_length: Some(8.0),
+                properties: Some(vec!["kind".to_string()]),
+                ..Default::default()
+            },
+            ZoomRule {
+                minzoom: Some(6),
+                maxzoom: Some(9),
+                min_area: Some(2.0),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(
+            property_sql(&info, "name"),
+            r#", CASE WHEN $1::integer <= 5 THEN NULL WHEN $1::integer BETWEEN 6 AND 9 THEN "name" ELSE "name" END AS "name""#
+        );
+        assert_eq!(property_sql(&info, "kind"), r#", "kind""#);
+        let unit = "(40075016.68557849 / (4096 * 2^$1::integer))";
+        assert_eq!(
+            min_size_sql(&info, "g", 4096, WebMercatorQuad),
+            format!("\n    AND CASE WHEN $1::integer <= 5 THEN (ST_Dimension(g) <> 1 OR ST_Length(g) >= 8 * {unit}) WHEN $1::integer BETWEEN 6 AND 9 THEN (ST_Dimension(g) <> 2 OR ST_Area(g) >= 2 * {unit}^2) ELSE true END")
+        );
+    }
+}
@@ -1,12 +1,72 @@
 use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
 use deadpool_postgres::tokio_postgres::types::Json;
+use futures::future::BoxFuture;
+use futures::FutureExt as _;
 use log::{error, info, warn};
 use postgis::{ewkb, LineString, Point, Polygon};
 use tilejson::{Bounds, TileJSON};
+use tokio::time::timeout;
 
+use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
+use crate::pg::PgResult;
 use crate::source::UrlQuery;
 
+/// Bounds of a table or a raster that are computed by the database
+pub type BoundsFuture = BoxFuture<'static, PgResult<Option<Bounds>>>;
+
+/// Get the bounds of the `id` source using the `bounds_type` strategy, with either the `estimate`
+/// or the `exact` query. Returns the bounds known right away, and in the background mode
+/// the exact bounds that are only awaited once the source is published.
+pub async fn compute_bounds(
+    id: &str,
+    format_id: &str,
+    bounds_type: BoundsCalcType,
+    bounds_timeout: Option<Duration>,
+    estimate: BoundsFuture,
+    exact: BoundsFuture,
+) -> PgResult<(Option<Bounds>, Option<BoundsFuture>)> {
+    match bounds_type {
+        BoundsCalcType::Skip => Ok((None, None)),
+        BoundsCalcType::Calc => Ok((exact.await?, None)),
+        BoundsCalcType::Quick => {
+            let limit = bounds_timeout.unwrap_or(DEFAULT_BOUNDS_TIMEOUT);
+            if let Ok(bounds) = timeout(limit, exact).await {
+                Ok((bounds?, None))
+            } else {
+                warn!("Timeout computing {format_id} bounds for {id}, aborting query. Use --auto-bounds=calc to wait until complete, --auto-bounds=background to compute them after startup, or check the table for missing indices.");
+                Ok((None, None))
+            }
+        }
+        BoundsCalcType::Estimate => match estimate.await {
+            Ok(Some(bounds)) => Ok((Some(bounds), None)),
+            Ok(None) => {
+                warn!("Unable to estimate {format_id} bounds for {id} without the table statistics. Run ANALYZE on the table.");
+                Ok((None, None))
+            }
+            Err(e) => {
+                warn!("Unable to estimate {format_id} bounds for {id}: {e}");
+                Ok((None, None))
+            }
+        },
+        BoundsCalcType::Background => {
+            let id = id.to_string();
+            let format_id = format_id.to_string();
+            let background = async move {
+                let Some(limit) = bounds_timeout else {
+                    return exact.await;
+                };
+                timeout(limit, exact).await.unwrap_or_else(|_| {
+                    warn!("Timeout computing {format_id} bounds for {id} in the background, the source will have no bounds.");
+                    Ok(None)
+                })
+            };
+            Ok((None, Some(background.boxed())))
+        }
+    }
+}
+
 #[must_use]
 pub fn json_to_hashmap(value: &serde_json::Value) -> InfoMap<String> {
     let mut result = BTreeMap::new();
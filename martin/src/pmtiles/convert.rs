@@ -0,0 +1,318 @@
+//! Conversion between `MBTiles` and `PMTiles` archives. The tiles are streamed from one file
+//! to the other, so that even planet-sized archives can be converted without loading them in memory.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use futures::TryStreamExt;
+use log::info;
+use martin_tile_utils::TileInfo;
+use mbtiles::sqlx::{query, Connection as _, Row, SqliteConnection};
+use mbtiles::{init_mbtiles_schema, invert_y_value, CopyDuplicateMode, MbtType, Mbtiles};
+use serde_json::{Map, Value};
+use tilejson::TileJSON;
+
+use super::writer::{tile_coord, Entry};
+use super::{PmtSource, PmtWriter};
+use crate::file_config::FileError::{InvalidMetadata, IoError};
+use crate::file_config::FileResult;
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd};
+use crate::{MartinResult, TileCoord};
+
+/// Number of tiles inserted into the `MBTiles` file in one transaction
+const BATCH_SIZE: usize = 1000;
+const HEADER_SIZE: usize = 127;
+
+/// Convert an `MBTiles` file into a new clustered `PMTiles` archive, returning the number of tiles.
+/// Tiles with identical data are only stored once.
+pub async fn mbtiles_to_pmtiles(src: &Path, dst: PathBuf) -> MartinResult<u64> {
+    let mbt = Mbtiles::new(src)?;
+    let mut conn = mbt.open_readonly().await?;
+    let metadata = mbt.get_metadata(&mut conn).await?;
+    let mut tilejson = metadata.tilejson;
+    // the extra values of the `json` metadata are kept as the top level keys of the PMTiles metadata
+    if let Some(Value::Object(json)) = metadata.json {
+        tilejson.other.extend(json);
+    }
+
+    info!("Converting {mbt} into {}", dst.display());
+    let mut writer = PmtWriter::new(dst)?;
+    let mut count = 0;
+    let mut rows = query(
+        "SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles WHERE tile_data NOTNULL",
+    )
+    .fetch(&mut conn);
+    while let Some(row) = rows.try_next().await.map_err(mbtiles::MbtError::from)? {
+        let z: u8 = row.get(0);
+        let xyz = TileCoord {
+            z,
+            x: row.get(1),
+            y: invert_y_value(z, row.get(2)),
+        };
+        writer.add_tile(xyz, row.get(3))?;
+        count += 1;
+    }
+
+    writer.finish(metadata.tile_info, &tilejson)?;
+    Ok(count)
+}
+
+/// Convert a `PMTiles` archive into a new `MBTiles` file of the given schema, returning the number of tiles.
+/// With the normalized schema, tiles with identical data are only stored once.
+pub async fn pmtiles_to_mbtiles(src: &Path, dst: &Path, mbt_type: MbtType) -> MartinResult<u64> {
+    let source = PmtSource::new(String::new(), src.to_path_buf()).await?;
+    let mut archive = Archive::open(src)?;
+
+    let mbt = Mbtiles::new(dst)?;
+    let mut conn = mbt.open_or_new().await?;
+    init_mbtiles_schema(&mut conn, mbt_type).await?;
+    let (tilejson, json) = mbtiles_metadata(&source.tilejson, source.tile_info);
+    mbt.insert_metadata(&mut conn, &tilejson).await?;
+    if let Some(json) = json {
+        mbt.set_metadata_value(&mut conn, "json", json).await?;
+    }
+
+    info!("Converting {} into {mbt}", src.display());
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    // directories are visited depth-first, so that only one leaf directory is in memory at a time
+    let mut stack = vec![archive.read_directory(archive.root_offset, archive.root_length)?];
+    while let Some(entries) = stack.last_mut() {
+        let Some(entry) = entries.next() else {
+            stack.pop();
+            continue;
+        };
+        if entry.run_length == 0 {
+            let offset = archive.leaf_offset + entry.offset;
+            stack.push(archive.read_directory(offset, u64::from(entry.length))?);
+            continue;
+        }
+        let data = archive.read(archive.data_offset + entry.offset, u64::from(entry.length))?;
+        for tile_id in entry.tile_id..entry.tile_id + u64::from(entry.run_length) {
+            let xyz = tile_coord(tile_id);
+            // the tiles are inserted with XYZ coordinates, and stored with TMS ones
+            batch.push((xyz.z, xyz.x, xyz.y, data.clone()));
+            if batch.len() >= BATCH_SIZE {
+                count += save_batch(&mbt, &mut conn, mbt_type, &mut batch).await?;
+            }
+        }
+    }
+    count += save_batch(&mbt, &mut conn, mbt_type, &mut batch).await?;
+    Ok(count)
+}
+
+async fn save_batch(
+    mbt: &Mbtiles,
+    conn: &mut SqliteConnection,
+    mbt_type: MbtType,
+    batch: &mut Vec<(u8, u32, u32, Vec<u8>)>,
+) -> MartinResult<u64> {
+    let mut tx = conn.begin().await.map_err(mbtiles::MbtError::from)?;
+    mbt.insert_tiles(&mut tx, mbt_type, CopyDuplicateMode::Override, batch)
+        .await?;
+    tx.commit().await.map_err(mbtiles::MbtError::from)?;
+    let count = batch.len() as u64;
+    batch.clear();
+    Ok(count)
+}
+
+/// Split the `PMTiles` metadata into the `MBTiles` metadata values, and the `json` metadata value
+/// with the vector layers and any other non-string value
+fn mbtiles_metadata(tilejson: &TileJSON, tile_info: TileInfo) -> (TileJSON, Option<String>) {
+    let mut tilejson = tilejson.clone();
+    let mut json = Map::new();
+    if let Some(vector_layers) = tilejson.vector_layers.take() {
+        if let Ok(value) = serde_json::to_value(vector_layers) {
+            json.insert("vector_layers".to_string(), value);
+        }
+    }
+    let other = std::mem::take(&mut tilejson.other);
+    for (key, value) in other {
+        if value.is_string() {
+            tilejson.other.insert(key, value);
+        } else {
+            json.insert(key, value);
+        }
+    }
+    tilejson
+        .other
+        .entry("format".to_string())
+        .or_insert_with(|| Value::String(tile_info.format.to_string()));
+    let json = (!json.is_empty()).then(|| Value::Object(json).to_string());
+    (tilejson, json)
+}
+
+/// Layout of a `PMTiles` v3 archive, read directly from the file to iterate over all its tiles
+struct Archive {
+    path: PathBuf,
+    file: File,
+    root_offset: u64,
+    root_length: u64,
+    leaf_offset: u64,
+    data_offset: u64,
+    internal_compression: u8,
+}
+
+impl Archive {
+    fn open(path: &Path) -> FileResult<Self> {
+        let mut file = File::open(path).map_err(|e| IoError(e, path.to_path_buf()))?;
+        let mut header = [0_u8; HEADER_SIZE];
+        file.read_exact(&mut header)
+            .map_err(|e| IoError(e, path.to_path_buf()))?;
+        if &header[0..7] != b"PMTiles" || header[7] != 3 {
+            let msg = "only PMTiles version 3 archives are supported".to_string();
+            return Err(InvalidMetadata(msg, path.to_path_buf()));
+        }
+        let u64_at = |pos: usize| {
+            let mut v = [0_u8; 8];
+            v.copy_from_slice(&header[pos..pos + 8]);
+            u64::from_le_bytes(v)
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            root_offset: u64_at(8),
+            root_length: u64_at(16),
+            leaf_offset: u64_at(40),
+            data_offset: u64_at(56),
+            internal_compression: header[97],
+        })
+    }
+
+    fn read(&mut self, offset: u64, length: u64) -> FileResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| (&mut self.file).take(length).read_to_end(&mut buf))
+            .and_then(|len| {
+                if len as u64 == length {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::UnexpectedEof.into())
+                }
+            })
+            .map_err(|e| IoError(e, self.path.clone()))?;
+        Ok(buf)
+    }
+
+    fn read_directory(
+        &mut self,
+        offset: u64,
+        length: u64,
+    ) -> FileResult<std::vec::IntoIter<Entry>> {
+        let data = self.read(offset, length)?;
+        let data = match self.internal_compression {
+            1 => Ok(data),
+            2 => decode_gzip(&data),
+            3 => decode_brotli(&data),
+            4 => decode_zstd(&data),
+            v => {
+                let msg = format!("unsupported internal compression {v}");
+                return Err(InvalidMetadata(msg, self.path.clone()));
+            }
+        }
+        .map_err(|e| IoError(e, self.path.clone()))?;
+        parse_directory(&data)
+            .map(Vec::into_iter)
+            .ok_or_else(|| InvalidMetadata("invalid directory".to_string(), self.path.clone()))
+    }
+}
+
+/// Deserialize a directory, the inverse of the serialization by the [`PmtWriter`]
+fn parse_directory(mut data: &[u8]) -> Option<Vec<Entry>> {
+    let count = usize::try_from(read_varint(&mut data)?).ok()?;
+    // each entry takes at least 4 bytes, so do not trust larger counts
+    let mut entries = Vec::with_capacity(count.min(data.len() / 4));
+    let mut tile_id = 0;
+    for _ in 0..count {
+        tile_id += read_varint(&mut data)?;
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for e in &mut entries {
+        e.run_length = u32::try_from(read_varint(&mut data)?).ok()?;
+    }
+    for e in &mut entries {
+        e.length = u32::try_from(read_varint(&mut data)?).ok()?;
+    }
+    for i in 0..entries.len() {
+        let v = read_varint(&mut data)?;
+        entries[i].offset = if v == 0 && i > 0 {
+            // zero means that the tile data directly follows the data of the previous entry
+            entries[i - 1].offset + u64::from(entries[i - 1].length)
+        } else {
+            v.checked_sub(1)?
+        };
+    }
+    Some(entries)
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("martin-pmt-convert-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let pmt = dir.join("world_cities.pmtiles");
+        let mbt = dir.join("world_cities.mbtiles");
+
+        let count = mbtiles_to_pmtiles(&src, pmt.clone()).await.unwrap();
+        assert!(count > 0);
+        let mbt_type = MbtType::Normalized { hash_view: true };
+        assert_eq!(
+            pmtiles_to_mbtiles(&pmt, &mbt, mbt_type).await.unwrap(),
+            count
+        );
+
+        let src = Mbtiles::new(&src).unwrap();
+        let mut src_conn = src.open_readonly().await.unwrap();
+        let dst = Mbtiles::new(&mbt).unwrap();
+        let mut dst_conn = dst.open_readonly().await.unwrap();
+        assert_eq!(
+            mbtiles::calc_agg_tiles_hash(&mut src_conn).await.unwrap(),
+            mbtiles::calc_agg_tiles_hash(&mut dst_conn).await.unwrap(),
+        );
+        let src_meta = src.get_metadata(&mut src_conn).await.unwrap();
+        let dst_meta = dst.get_metadata(&mut dst_conn).await.unwrap();
+        assert_eq!(src_meta.tile_info, dst_meta.tile_info);
+        assert_eq!(src_meta.tilejson.name, dst_meta.tilejson.name);
+        assert_eq!(
+            src_meta.tilejson.vector_layers,
+            dst_meta.tilejson.vector_layers
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_varints() {
+        let mut data: &[u8] = &[0xac, 0x02, 0x01];
+        assert_eq!(read_varint(&mut data), Some(300));
+        assert_eq!(read_varint(&mut data), Some(1));
+        assert_eq!(read_varint(&mut data), None);
+    }
+}
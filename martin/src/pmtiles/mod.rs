@@ -6,21 +6,89 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use log::{trace, warn};
 use martin_tile_utils::{Encoding, Format, TileInfo};
-use pmtiles::async_reader::AsyncPmTilesReader;
+use moka::future::Cache;
+use pmtiles::async_reader::{AsyncBackend, AsyncPmTilesReader};
+use pmtiles::cache::{DirCacheResult, DirectoryCache};
 use pmtiles::mmap::MmapBackend;
-use pmtiles::{Compression, TileType};
+use pmtiles::{Compression, Directory, Header, PmtResult, TileType};
 use tilejson::TileJSON;
 
 use crate::file_config::FileError::{InvalidMetadata, IoError};
-use crate::file_config::FileResult;
+use crate::file_config::{is_url, FileResult};
 use crate::source::{Source, TileData, UrlQuery};
 use crate::{MartinResult, TileCoord};
 
+mod remote;
+pub use remote::RemoteBackend;
+
+mod convert;
+pub use convert::{mbtiles_to_pmtiles, pmtiles_to_mbtiles};
+
+mod writer;
+pub use writer::PmtWriter;
+
+/// Maximum number of leaf directories kept in memory for each archive
+const DIR_CACHE_SIZE: u64 = 1024;
+
+/// Keeps recently used leaf directories, so that each tile usually needs just one read.
+/// This matters most for remote archives, where each read is a separate request.
+struct DirCache(Cache<usize, Arc<Directory>>);
+
+impl DirCache {
+    fn new() -> Self {
+        Self(Cache::new(DIR_CACHE_SIZE))
+    }
+}
+
+#[async_trait]
+impl DirectoryCache for DirCache {
+    async fn get_dir_entry(&self, offset: usize, tile_id: u64) -> DirCacheResult {
+        match self.0.get(&offset).await {
+            Some(dir) => dir.find_tile_id(tile_id).into(),
+            None => DirCacheResult::NotCached,
+        }
+    }
+
+    async fn insert_dir(&self, offset: usize, directory: Directory) {
+        self.0.insert(offset, Arc::new(directory)).await;
+    }
+}
+
+enum PmtReader {
+    File(AsyncPmTilesReader<MmapBackend, DirCache>),
+    Remote(AsyncPmTilesReader<RemoteBackend, DirCache>, RemoteBackend),
+}
+
+impl PmtReader {
+    fn get_header(&self) -> &Header {
+        match self {
+            Self::File(r) => r.get_header(),
+            Self::Remote(r, _) => r.get_header(),
+        }
+    }
+
+    async fn get_tile(&self, z: u8, x: u64, y: u64) -> Option<Vec<u8>> {
+        match self {
+            Self::File(r) => r.get_tile(z, x, y).await,
+            Self::Remote(r, _) => r.get_tile(z, x, y).await,
+        }
+        .map(|v| v.to_vec())
+    }
+
+    async fn parse_tilejson(&self) -> PmtResult<TileJSON> {
+        match self {
+            Self::File(r) => r.parse_tilejson(Vec::new()).await,
+            Self::Remote(r, _) => r.parse_tilejson(Vec::new()).await,
+        }
+    }
+}
+
+/// Serves a `PMTiles` archive from a local file, an `http(s)://` URL, or an `s3://bucket/key` URL
 #[derive(Clone)]
 pub struct PmtSource {
     id: String,
     path: PathBuf,
-    pmtiles: Arc<AsyncPmTilesReader<MmapBackend>>,
+    pmtiles: Arc<PmtReader>,
     tilejson: TileJSON,
     tile_info: TileInfo,
 }
@@ -37,25 +105,34 @@ impl PmtSource {
     }
 
     async fn new(id: String, path: PathBuf) -> FileResult<Self> {
-        let backend = MmapBackend::try_from(path.as_path())
-            .await
-            .map_err(|e| {
+        let on_err = |e| {
+            IoError(
                 io::Error::new(
                     io::ErrorKind::Other,
                     format!("{e:?}: Cannot open file {}", path.display()),
-                )
-            })
-            .map_err(|e| IoError(e, path.clone()))?;
-
-        let reader = AsyncPmTilesReader::try_from_source(backend).await;
-        let reader = reader
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("{e:?}: Cannot open file {}", path.display()),
-                )
-            })
-            .map_err(|e| IoError(e, path.clone()))?;
+                ),
+                path.clone(),
+            )
+        };
+        let reader = match path.to_str() {
+            Some(url) if is_url(&path) => {
+                let backend = RemoteBackend::new(url);
+                let reader =
+                    AsyncPmTilesReader::try_from_cached_source(backend.clone(), DirCache::new())
+                        .await
+                        .map_err(on_err)?;
+                PmtReader::Remote(reader, backend)
+            }
+            _ => {
+                let backend = MmapBackend::try_from(path.as_path())
+                    .await
+                    .map_err(on_err)?;
+                let reader = AsyncPmTilesReader::try_from_cached_source(backend, DirCache::new())
+                    .await
+                    .map_err(on_err)?;
+                PmtReader::File(reader)
+            }
+        };
         let hdr = &reader.get_header();
 
         if hdr.tile_type != TileType::Mvt && hdr.tile_compression != Compression::None {
@@ -96,7 +173,7 @@ impl PmtSource {
             }
         };
 
-        let tilejson = reader.parse_tilejson(Vec::new()).await.unwrap_or_else(|e| {
+        let tilejson = reader.parse_tilejson().await.unwrap_or_else(|e| {
             warn!("{e:?}: Unable to parse metadata for {}", path.display());
             hdr.get_tilejson(Vec::new())
         });
@@ -125,6 +202,10 @@ impl Source for PmtSource {
         self.tile_info
     }
 
+    fn get_source_type(&self) -> &'static str {
+        "pmtiles"
+    }
+
     fn clone_source(&self) -> Box<dyn Source> {
         Box::new(self.clone())
     }
@@ -140,7 +221,7 @@ impl Source for PmtSource {
             .get_tile(xyz.z, u64::from(xyz.x), u64::from(xyz.y))
             .await
         {
-            Ok(t.to_vec())
+            Ok(t)
         } else {
             trace!(
                 "Couldn't find tile data in {}/{}/{} of {}",
@@ -152,4 +233,18 @@ impl Source for PmtSource {
             Ok(Vec::new())
         }
     }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        if let PmtReader::Remote(_, backend) = self.pmtiles.as_ref() {
+            backend
+                .read(0, 1)
+                .await
+                .map_err(|e| IoError(io::Error::new(io::ErrorKind::Other, e), self.path.clone()))?;
+        } else {
+            tokio::fs::metadata(&self.path)
+                .await
+                .map_err(|e| IoError(e, self.path.clone()))?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use sha2::{Digest, Sha256};
+use tilejson::{Bounds, TileJSON};
+
+use crate::file_config::FileError::IoError;
+use crate::file_config::FileResult;
+use crate::utils::encode_gzip;
+use crate::TileCoord;
+
+const HEADER_SIZE: usize = 127;
+/// The header and the root directory must fit into the first 16 KiB of the archive
+const MAX_ROOT_DIR_SIZE: usize = 16_384 - HEADER_SIZE;
+/// Initial number of entries of each leaf directory, doubled until the root directory fits
+const LEAF_SIZE_START: usize = 4096;
+
+/// `TileJSON` fields that are not copied to the metadata, because the header has them
+const HEADER_TILEJSON_KEYS: &[&str] = &[
+    "tilejson", "tiles", "bounds", "center", "minzoom", "maxzoom",
+];
+
+/// Compression of the directories and of the metadata
+const INTERNAL_COMPRESSION_GZIP: u8 = 2;
+
+/// Directory entry: a run of tiles with the same data, or a leaf directory if `run_length` is 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Entry {
+    pub(super) tile_id: u64,
+    pub(super) offset: u64,
+    pub(super) length: u32,
+    pub(super) run_length: u32,
+}
+
+/// Writes a clustered `PMTiles` v3 archive. The tiles can be added in any order: their data
+/// is appended to a temporary file next to the archive, and copied in the tile ID order
+/// by [`PmtWriter::finish`]. Tiles with identical data are only stored once.
+pub struct PmtWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    tmp: BufWriter<File>,
+    tmp_len: u64,
+    /// Offset and length of each distinct tile content in the temporary file
+    contents: HashMap<[u8; 32], (u64, u32)>,
+    /// Tile ID, offset, and length in the temporary file of all added tiles
+    tiles: Vec<(u64, u64, u32)>,
+    min_zoom: u8,
+    max_zoom: u8,
+}
+
+impl PmtWriter {
+    /// Create a writer of a new archive. The file is only created by [`PmtWriter::finish`].
+    pub fn new(path: PathBuf) -> FileResult<Self> {
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(|e| IoError(e, tmp_path.clone()))?;
+        Ok(Self {
+            path,
+            tmp_path,
+            tmp: BufWriter::new(tmp),
+            tmp_len: 0,
+            contents: HashMap::new(),
+            tiles: Vec::new(),
+            min_zoom: u8::MAX,
+            max_zoom: 0,
+        })
+    }
+
+    /// Add a non-empty tile. Each tile must only be added once.
+    pub fn add_tile(&mut self, xyz: TileCoord, data: &[u8]) -> FileResult<()> {
+        let hash: [u8; 32] = Sha256::digest(data).into();
+        let (offset, length) = if let Some(v) = self.contents.get(&hash) {
+            *v
+        } else {
+            let length = u32::try_from(data.len())
+                .map_err(|e| IoError(invalid_data(e), self.tmp_path.clone()))?;
+            self.tmp
+                .write_all(data)
+                .map_err(|e| IoError(e, self.tmp_path.clone()))?;
+            let v = (self.tmp_len, length);
+            self.tmp_len += u64::from(length);
+            self.contents.insert(hash, v);
+            v
+        };
+        self.tiles.push((tile_id(xyz), offset, length));
+        self.min_zoom = self.min_zoom.min(xyz.z);
+        self.max_zoom = self.max_zoom.max(xyz.z);
+        Ok(())
+    }
+
+    /// Write the archive with the added tiles, and remove the temporary file.
+    /// The `tilejson` is stored as the metadata, and its bounds and center are used in the header.
+    #[allow(clippy::too_many_lines)]
+    pub fn finish(mut self, info: TileInfo, tilejson: &TileJSON) -> FileResult<()> {
+        let tmp_err = |e| IoError(e, self.tmp_path.clone());
+        self.tmp.flush().map_err(tmp_err)?;
+        self.tiles.sort_unstable_by_key(|v| v.0);
+
+        // Tile contents are stored in the order they are first used, which clusters the archive
+        let mut out_offsets: HashMap<u64, u64> = HashMap::new();
+        let mut order = Vec::new();
+        let mut data_len = 0;
+        let mut entries: Vec<Entry> = Vec::new();
+        for &(tile_id, tmp_offset, length) in &self.tiles {
+            let offset = *out_offsets.entry(tmp_offset).or_insert_with(|| {
+                order.push((tmp_offset, length));
+                data_len += u64::from(length);
+                data_len - u64::from(length)
+            });
+            match entries.last_mut() {
+                Some(last)
+                    if last.offset == offset
+                        && last.tile_id + u64::from(last.run_length) == tile_id =>
+                {
+                    last.run_length += 1;
+                }
+                _ => entries.push(Entry {
+                    tile_id,
+                    offset,
+                    length,
+                    run_length: 1,
+                }),
+            }
+        }
+
+        let (root, leaves) = build_directories(&entries).map_err(tmp_err)?;
+        let mut metadata = serde_json::to_value(tilejson).map_err(|e| tmp_err(e.into()))?;
+        if let Some(obj) = metadata.as_object_mut() {
+            // these are stored in the header, and the readers add them to the TileJSON
+            for key in HEADER_TILEJSON_KEYS {
+                obj.remove(*key);
+            }
+        }
+        let metadata = serde_json::to_vec(&metadata).map_err(|e| tmp_err(e.into()))?;
+        let metadata = encode_gzip(&metadata).map_err(tmp_err)?;
+
+        let bounds = tilejson.bounds.unwrap_or(Bounds::MAX_TILED);
+        let (center_lon, center_lat, center_zoom) = match tilejson.center {
+            Some(c) => (c.longitude, c.latitude, c.zoom),
+            None => (
+                (bounds.left + bounds.right) / 2.0,
+                (bounds.bottom + bounds.top) / 2.0,
+                self.min_zoom.min(self.max_zoom),
+            ),
+        };
+        let root_offset = HEADER_SIZE as u64;
+        let metadata_offset = root_offset + root.len() as u64;
+        let leaves_offset = metadata_offset + metadata.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for v in [
+            root_offset,
+            root.len() as u64,
+            metadata_offset,
+            metadata.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            data_len,
+            self.tiles.len() as u64,
+            entries.len() as u64,
+            order.len() as u64,
+        ] {
+            header.extend_from_slice(&v.to_le_bytes());
+        }
+        header.push(1); // clustered
+        header.push(INTERNAL_COMPRESSION_GZIP);
+        header.push(tile_compression(info.encoding));
+        header.push(tile_type(info.format));
+        if self.tiles.is_empty() {
+            header.extend_from_slice(&[0, 0]);
+        } else {
+            header.extend_from_slice(&[self.min_zoom, self.max_zoom]);
+        }
+        for v in [bounds.left, bounds.bottom, bounds.right, bounds.top] {
+            header.extend_from_slice(&to_e7(v).to_le_bytes());
+        }
+        header.push(center_zoom);
+        header.extend_from_slice(&to_e7(center_lon).to_le_bytes());
+        header.extend_from_slice(&to_e7(center_lat).to_le_bytes());
+
+        let out_err = |e| IoError(e, self.path.clone());
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)
+            .map_err(out_err)?;
+        let mut out = BufWriter::new(file);
+        for part in [&header, &root, &metadata, &leaves] {
+            out.write_all(part).map_err(out_err)?;
+        }
+        let mut tmp = self.tmp.into_inner().map_err(|e| tmp_err(e.into_error()))?;
+        let mut buf = Vec::new();
+        for (tmp_offset, length) in order {
+            buf.resize(length as usize, 0);
+            tmp.seek(SeekFrom::Start(tmp_offset)).map_err(tmp_err)?;
+            tmp.read_exact(&mut buf).map_err(tmp_err)?;
+            out.write_all(&buf).map_err(out_err)?;
+        }
+        out.flush().map_err(out_err)?;
+        drop(tmp);
+        remove_file(&self.tmp_path).map_err(tmp_err)?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// ID of a tile in the `PMTiles` archive: the number of tiles at the lower zoom levels,
+/// plus the position of the tile along the Hilbert curve of its zoom level
+pub(super) fn tile_id(xyz: TileCoord) -> u64 {
+    let mut id = ((1_u64 << (u32::from(xyz.z) * 2)) - 1) / 3;
+    let (mut x, mut y) = (u64::from(xyz.x), u64::from(xyz.y));
+    let mut s = if xyz.z == 0 { 0 } else { 1_u64 << (xyz.z - 1) };
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        id += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = s.wrapping_sub(1).wrapping_sub(x);
+                y = s.wrapping_sub(1).wrapping_sub(y);
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s >>= 1;
+    }
+    id
+}
+
+/// Tile coordinates of a tile ID, the inverse of [`tile_id`]
+#[allow(clippy::cast_possible_truncation)]
+pub(super) fn tile_coord(tile_id: u64) -> TileCoord {
+    let mut z = 0_u8;
+    let mut first_id = 0_u64;
+    while z < 31 && first_id + (1_u64 << (u32::from(z) * 2)) <= tile_id {
+        first_id += 1_u64 << (u32::from(z) * 2);
+        z += 1;
+    }
+    let size = 1_u64 << z;
+    let mut pos = tile_id - first_id;
+    let (mut x, mut y) = (0_u64, 0_u64);
+    let mut s = 1_u64;
+    while s < size {
+        let rx = 1 & (pos / 2);
+        let ry = 1 & (pos ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        pos /= 4;
+        s *= 2;
+    }
+    TileCoord {
+        z,
+        x: x as u32,
+        y: y as u32,
+    }
+}
+
+/// Serialize the root directory, and the leaf directories if the root would be too large
+fn build_directories(entries: &[Entry]) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+    if root.len() <= MAX_ROOT_DIR_SIZE {
+        return Ok((root, Vec::new()));
+    }
+    let mut leaf_size = LEAF_SIZE_START;
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: u32::try_from(leaf.len()).map_err(invalid_data)?,
+                run_length: 0,
+            });
+            leaves.extend(leaf);
+        }
+        let root = serialize_directory(&root_entries)?;
+        if root.len() <= MAX_ROOT_DIR_SIZE {
+            return Ok((root, leaves));
+        }
+        leaf_size *= 2;
+    }
+}
+
+fn serialize_directory(entries: &[Entry]) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+    let mut last_id = 0;
+    for e in entries {
+        write_varint(&mut buf, e.tile_id - last_id);
+        last_id = e.tile_id;
+    }
+    for e in entries {
+        write_varint(&mut buf, u64::from(e.run_length));
+    }
+    for e in entries {
+        write_varint(&mut buf, u64::from(e.length));
+    }
+    for (i, e) in entries.iter().enumerate() {
+        // zero means that the tile data directly follows the data of the previous entry
+        if i > 0 && e.offset == entries[i - 1].offset + u64::from(entries[i - 1].length) {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, e.offset + 1);
+        }
+    }
+    encode_gzip(&buf)
+}
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn to_e7(value: f64) -> i32 {
+    (value * 10_000_000.0).round() as i32
+}
+
+fn tile_compression(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Uncompressed | Encoding::Internal => 1,
+        Encoding::Gzip => 2,
+        Encoding::Brotli => 3,
+        Encoding::Zstd => 4,
+        Encoding::Zlib => 0,
+    }
+}
+
+fn tile_type(format: Format) -> u8 {
+    match format {
+        Format::Mvt => 1,
+        Format::Png => 2,
+        Format::Jpeg => 3,
+        Format::Webp => 4,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pmtiles::async_reader::AsyncPmTilesReader;
+    use pmtiles::{Compression, TileType};
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[test]
+    fn hilbert_tile_id() {
+        let id = |z, x, y| tile_id(TileCoord { z, x, y });
+        assert_eq!(id(0, 0, 0), 0);
+        assert_eq!(id(1, 0, 0), 1);
+        assert_eq!(id(1, 1, 0), 4);
+        assert_eq!(id(2, 1, 3), 11);
+        assert_eq!(id(3, 3, 0), 26);
+        assert_eq!(id(20, 0, 0), 366_503_875_925);
+
+        for xyz in [
+            (0, 0, 0),
+            (2, 1, 3),
+            (3, 3, 0),
+            (12, 1234, 4000),
+            (20, 0, 0),
+        ] {
+            let (z, x, y) = xyz;
+            assert_eq!(tile_coord(id(z, x, y)), TileCoord { z, x, y });
+        }
+    }
+
+    #[actix_rt::test]
+    async fn write_and_read() {
+        let dir = std::env::temp_dir().join(format!("martin-pmt-writer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.pmtiles");
+        let _ = remove_file(&path);
+
+        let mut writer = PmtWriter::new(path.clone()).unwrap();
+        // enough distinct tiles for leaf directories, added in reverse order
+        for y in (0..128).rev() {
+            for x in (0..256).rev() {
+                let data = format!("{x}/{y}");
+                writer
+                    .add_tile(TileCoord { z: 8, x, y }, data.as_bytes())
+                    .unwrap();
+            }
+        }
+        // identical tiles are stored once
+        for x in 0..4 {
+            writer
+                .add_tile(TileCoord { z: 2, x, y: 0 }, b"same")
+                .unwrap();
+        }
+        let tj = tilejson! { tiles: vec![], name: "test".to_string() };
+        let info = TileInfo::new(Format::Png, Encoding::Internal);
+        writer.finish(info, &tj).unwrap();
+        assert!(!dir.join("test.pmtiles.tmp").exists());
+
+        let reader = AsyncPmTilesReader::new_with_path(&path).await.unwrap();
+        let header = reader.get_header();
+        assert_eq!(header.tile_type, TileType::Png);
+        assert_eq!(header.tile_compression, Compression::None);
+        assert_eq!((header.min_zoom, header.max_zoom), (2, 8));
+        for (x, y) in [(0, 0), (255, 127), (17, 99)] {
+            let tile = reader.get_tile(8, x, y).await.unwrap();
+            assert_eq!(tile.as_ref(), format!("{x}/{y}").as_bytes());
+        }
+        assert!(reader.get_tile(8, 0, 200).await.is_none());
+        for x in 0..4 {
+            let tile = reader.get_tile(2, x, 0).await.unwrap();
+            assert_eq!(tile.as_ref(), b"same");
+        }
+        let metadata = reader.get_metadata().await.unwrap();
+        assert!(metadata.contains("\"name\":\"test\""));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
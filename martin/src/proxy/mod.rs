@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+
+use async_trait::async_trait;
+use log::{info, trace};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, ETAG, EXPIRES,
+};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, Bounds, TileJSON};
+
+use crate::source::{Source, TileData, TileHeaders, TileInfoSources, UrlQuery};
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd};
+use crate::{IdResolver, MartinResult, TileCoord};
+
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProxyError {
+    #[error("Unable to fetch {1}: {0}")]
+    HttpError(reqwest::Error, String),
+
+    #[error("Upstream server returned {0} for {1}")]
+    UpstreamStatus(StatusCode, String),
+
+    #[error("Header {0} of proxy source {1} is not valid")]
+    InvalidHeader(String, String),
+
+    #[error("Unable to parse TileJSON {1}: {0}")]
+    InvalidTileJson(serde_json::Error, String),
+
+    #[error("TileJSON {0} has no tile URLs")]
+    NoTileUrl(String),
+
+    #[error("Tile format {0} of proxy source {1} is not supported")]
+    InvalidFormat(String, String),
+
+    #[error("Unable to detect the tile format of proxy source {0}, set the format explicitly")]
+    UnknownFormat(String),
+
+    #[error("Unable to decode tile {1} of proxy source {2}: {0}")]
+    DecodeError(io::Error, TileCoord, String),
+}
+
+/// Upstream XYZ tile endpoint, or a `TileJSON` document describing one
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// Tile URL template with `{z}`, `{x}`, and `{y}` placeholders, or a `TileJSON` URL
+    pub url: String,
+    /// HTTP headers added to every upstream request, e.g. for authorization
+    pub headers: Option<BTreeMap<String, String>>,
+    /// Tile format, e.g. `mvt` or `png`. Detected from the first tile if not set.
+    pub format: Option<String>,
+    pub minzoom: Option<u8>,
+    pub maxzoom: Option<u8>,
+    pub bounds: Option<Bounds>,
+    pub attribution: Option<String>,
+    /// Keep the upstream tiles in the main tile cache, `true` by default
+    pub cache: Option<bool>,
+    /// Forward the `Cache-Control`, `ETag`, and `Expires` headers of the upstream tiles,
+    /// `true` by default
+    pub cache_headers: Option<bool>,
+    /// `Cache-Control` header of the tiles, replacing the upstream one
+    pub cache_control: Option<String>,
+    /// Serve the source by its URL, but do not list it in the catalog
+    pub hidden: Option<bool>,
+}
+
+pub type ProxyConfigs = BTreeMap<String, ProxyConfig>;
+
+/// Resolve the configured proxy sources, renaming them if their IDs are already in use
+pub async fn resolve_proxies(
+    configs: &mut ProxyConfigs,
+    idr: IdResolver,
+) -> MartinResult<TileInfoSources> {
+    let mut results = TileInfoSources::default();
+    let mut resolved = ProxyConfigs::new();
+    for (id, cfg) in std::mem::take(configs) {
+        let id = idr.resolve(&id, cfg.url.clone());
+        info!("Configured proxy source {id} from {}", cfg.url);
+        results.push(Box::new(ProxySource::new(id.clone(), cfg.clone()).await?));
+        resolved.insert(id, cfg);
+    }
+    *configs = resolved;
+    Ok(results)
+}
+
+/// Serves tiles of an upstream XYZ endpoint, decompressing them so that they can be
+/// re-encoded for each client the same way as the tiles of any other source
+#[derive(Clone)]
+pub struct ProxySource {
+    id: String,
+    client: Client,
+    headers: HeaderMap,
+    template: String,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+    cache: bool,
+    cache_headers: bool,
+    cache_control: Option<String>,
+}
+
+impl Debug for ProxySource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ProxySource {{ id: {}, url: {} }}",
+            self.id, self.template
+        )
+    }
+}
+
+impl ProxySource {
+    pub async fn new(id: String, cfg: ProxyConfig) -> ProxyResult<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in cfg.headers.iter().flatten() {
+            let err = || ProxyError::InvalidHeader(name.clone(), id.clone());
+            headers.insert(
+                HeaderName::try_from(name.as_str()).map_err(|_| err())?,
+                HeaderValue::try_from(value.as_str()).map_err(|_| err())?,
+            );
+        }
+        let client = Client::new();
+
+        let (template, mut tilejson) = if is_template(&cfg.url) {
+            (cfg.url.clone(), tilejson! { tiles: vec![] })
+        } else {
+            let data = fetch(&client, &headers, &cfg.url).await?;
+            let mut tilejson: TileJSON = serde_json::from_slice(&data)
+                .map_err(|e| ProxyError::InvalidTileJson(e, cfg.url.clone()))?;
+            let template = tilejson
+                .tiles
+                .first()
+                .cloned()
+                .ok_or_else(|| ProxyError::NoTileUrl(cfg.url.clone()))?;
+            tilejson.tiles = vec![];
+            (template, tilejson)
+        };
+        if cfg.minzoom.is_some() {
+            tilejson.minzoom = cfg.minzoom;
+        }
+        if cfg.maxzoom.is_some() {
+            tilejson.maxzoom = cfg.maxzoom;
+        }
+        if cfg.bounds.is_some() {
+            tilejson.bounds = cfg.bounds;
+        }
+        if cfg.attribution.is_some() {
+            tilejson.attribution = cfg.attribution;
+        }
+
+        let mut source = Self {
+            id,
+            client,
+            headers,
+            template,
+            tilejson,
+            tile_info: Format::Mvt.into(),
+            cache: cfg.cache.unwrap_or(true),
+            cache_headers: cfg.cache_headers.unwrap_or(true),
+            cache_control: cfg.cache_control,
+        };
+        source.tile_info = match cfg.format {
+            Some(format) => Format::parse(&format)
+                .ok_or_else(|| ProxyError::InvalidFormat(format, source.id.clone()))?
+                .into(),
+            None => source.detect_tile_info().await?,
+        };
+        Ok(source)
+    }
+
+    /// Detect the tile format from the first tile at the minimum zoom
+    async fn detect_tile_info(&self) -> ProxyResult<TileInfo> {
+        let xyz = TileCoord {
+            z: self.tilejson.minzoom.unwrap_or(0),
+            x: 0,
+            y: 0,
+        };
+        let (data, _) = self.fetch_tile(&xyz).await?;
+        if data.is_empty() {
+            return Err(ProxyError::UnknownFormat(self.id.clone()));
+        }
+        Ok(match TileInfo::detect(&data) {
+            Some(info) if !info.encoding.is_encoded() => info.format.into(),
+            // Uncompressed MVT data starts with the layer field (tag 3, length-delimited)
+            None if data[0] == 0x1a => Format::Mvt.into(),
+            _ => Err(ProxyError::UnknownFormat(self.id.clone()))?,
+        })
+    }
+
+    fn tile_url(&self, xyz: &TileCoord) -> String {
+        self.template
+            .replace("{z}", &xyz.z.to_string())
+            .replace("{x}", &xyz.x.to_string())
+            .replace("{y}", &xyz.y.to_string())
+    }
+
+    /// Fetch an uncompressed tile from the upstream server, with its caching headers
+    async fn fetch_tile(&self, xyz: &TileCoord) -> ProxyResult<(TileData, TileHeaders)> {
+        let url = self.tile_url(xyz);
+        trace!("Fetching tile {xyz} of {} from {url}", self.id);
+        let response = self
+            .client
+            .get(&url)
+            .headers(self.headers.clone())
+            .send()
+            .await
+            .map_err(|e| ProxyError::HttpError(e, url.clone()))?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => {
+                return Ok((Vec::new(), TileHeaders::default()));
+            }
+            status if !status.is_success() => return Err(ProxyError::UpstreamStatus(status, url)),
+            _ => {}
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(ToString::to_string)
+        };
+        let headers = TileHeaders {
+            cache_control: header(CACHE_CONTROL),
+            // the tile is re-encoded for each client, so its bytes may differ from the upstream ones
+            etag: header(ETAG).map(|v| {
+                if v.starts_with("W/") {
+                    v
+                } else {
+                    format!("W/{v}")
+                }
+            }),
+            expires: header(EXPIRES),
+        };
+        let encoding = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| Encoding::parse(if v == "br" { "brotli" } else { v }));
+        let data = response
+            .bytes()
+            .await
+            .map_err(|e| ProxyError::HttpError(e, url))?;
+        // Some servers return pre-compressed vector tiles without the Content-Encoding header
+        let encoding = encoding.or_else(|| {
+            TileInfo::detect(&data)
+                .filter(|v| v.format == Format::Mvt)
+                .map(|v| v.encoding)
+        });
+        let err = |e| ProxyError::DecodeError(e, *xyz, self.id.clone());
+        let data = match encoding {
+            Some(Encoding::Gzip) => decode_gzip(&data).map_err(err)?,
+            Some(Encoding::Brotli) => decode_brotli(&data).map_err(err)?,
+            Some(Encoding::Zstd) => decode_zstd(&data).map_err(err)?,
+            _ => data.to_vec(),
+        };
+        Ok((data, headers))
+    }
+}
+
+#[async_trait]
+impl Source for ProxySource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "proxy"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.cache
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        Ok(self.fetch_tile(xyz).await?.0)
+    }
+
+    async fn get_tile_with_headers(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<(TileData, Option<TileHeaders>)> {
+        let (data, mut headers) = self.fetch_tile(xyz).await?;
+        if !self.cache_headers {
+            headers = TileHeaders::default();
+        }
+        if self.cache_control.is_some() {
+            headers.cache_control.clone_from(&self.cache_control);
+        }
+        let has_headers = headers != TileHeaders::default();
+        Ok((data, has_headers.then_some(headers)))
+    }
+}
+
+fn is_template(url: &str) -> bool {
+    url.contains("{z}") && url.contains("{x}") && url.contains("{y}")
+}
+
+/// Get the body of a URL, failing on any unsuccessful response
+async fn fetch(client: &Client, headers: &HeaderMap, url: &str) -> ProxyResult<Vec<u8>> {
+    let err = |e| ProxyError::HttpError(e, url.to_string());
+    let response = client
+        .get(url)
+        .headers(headers.clone())
+        .send()
+        .await
+        .map_err(err)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ProxyError::UpstreamStatus(status, url.to_string()));
+    }
+    Ok(response.bytes().await.map_err(err)?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proxy_config() {
+        let cfg: ProxyConfigs = serde_yaml::from_str(indoc::indoc! {"
+            basemap:
+              url: https://tiles.example.org/{z}/{x}/{y}.pbf
+              headers:
+                Authorization: Bearer token
+              maxzoom: 14
+              cache: false
+              cache_headers: false
+              cache_control: public, max-age=600
+            "})
+        .unwrap();
+        let basemap = &cfg["basemap"];
+        assert!(is_template(&basemap.url));
+        assert_eq!(basemap.maxzoom, Some(14));
+        assert_eq!(basemap.cache, Some(false));
+        assert_eq!(basemap.cache_headers, Some(false));
+        assert_eq!(
+            basemap.cache_control.as_deref(),
+            Some("public, max-age=600")
+        );
+        assert_eq!(
+            basemap.headers.as_ref().unwrap()["Authorization"],
+            "Bearer token"
+        );
+        assert!(!is_template("https://tiles.example.org/tiles.json"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Reading the attributes of the shapefile features from the [dBASE](https://www.clicketyclick.dk/databases/xbase/format/dbf.html) `.dbf` file.
+//! Texts are decoded as UTF-8, falling back to Latin-1 used by many older files.
+
+use crate::utils::mvt::PropValue;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbfField {
+    pub name: String,
+    kind: u8,
+    offset: usize,
+    length: usize,
+    decimals: u8,
+}
+
+impl DbfField {
+    /// Type of the field values in `TileJSON`, or `None` if they are not used as properties
+    #[must_use]
+    pub fn field_type(&self) -> Option<&'static str> {
+        match self.kind {
+            b'C' | b'D' => Some("String"),
+            b'N' | b'F' => Some("Number"),
+            b'L' => Some("Boolean"),
+            _ => None,
+        }
+    }
+
+    fn value(&self, data: &[u8]) -> Option<PropValue> {
+        let text = decode_text(data);
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+        match self.kind {
+            b'C' => Some(PropValue::String(text.to_string())),
+            // numbers too large for the field are written as asterisks
+            b'N' | b'F' if self.decimals == 0 => match text.parse() {
+                Ok(v) => Some(PropValue::Int(v)),
+                Err(_) => text.parse().ok().map(PropValue::Double),
+            },
+            b'N' | b'F' => text.parse().ok().map(PropValue::Double),
+            b'L' => match text.as_bytes()[0] {
+                b'T' | b't' | b'Y' | b'y' => Some(PropValue::Bool(true)),
+                b'F' | b'f' | b'N' | b'n' => Some(PropValue::Bool(false)),
+                _ => None,
+            },
+            b'D' if text.len() == 8 && text.bytes().all(|b| b.is_ascii_digit()) => Some(
+                PropValue::String(format!("{}-{}-{}", &text[..4], &text[4..6], &text[6..])),
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DbfTable<'a> {
+    pub fields: Vec<DbfField>,
+    data: &'a [u8],
+    count: usize,
+    header_len: usize,
+    record_len: usize,
+}
+
+impl<'a> DbfTable<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < 32 {
+            return Err("the dbf header is truncated".to_string());
+        }
+        let count = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let header_len = usize::from(u16::from_le_bytes([data[8], data[9]]));
+        let record_len = usize::from(u16::from_le_bytes([data[10], data[11]]));
+
+        let mut fields = Vec::new();
+        // each record starts with the deletion flag
+        let mut offset = 1;
+        for desc in data[32..header_len.min(data.len())].chunks_exact(32) {
+            if desc[0] == 0x0D {
+                break;
+            }
+            let length = usize::from(desc[16]);
+            let name_len = desc[..11].iter().position(|b| *b == 0).unwrap_or(11);
+            fields.push(DbfField {
+                name: decode_text(&desc[..name_len]).trim().to_string(),
+                kind: desc[11],
+                offset,
+                length,
+                decimals: desc[17],
+            });
+            offset += length;
+        }
+        if offset > record_len {
+            return Err("the dbf fields are longer than the records".to_string());
+        }
+        Ok(Self {
+            fields,
+            data,
+            count,
+            header_len,
+            record_len,
+        })
+    }
+
+    /// Get the properties of a record, or `None` if it is deleted or missing
+    #[must_use]
+    pub fn properties(&self, idx: usize) -> Option<Vec<(String, PropValue)>> {
+        if idx >= self.count {
+            return None;
+        }
+        let start = self.header_len + idx * self.record_len;
+        let record = self.data.get(start..start + self.record_len)?;
+        if record[0] == b'*' {
+            return None;
+        }
+        Some(
+            self.fields
+                .iter()
+                .filter_map(|f| {
+                    let value = f.value(&record[f.offset..f.offset + f.length])?;
+                    Some((f.name.clone(), value))
+                })
+                .collect(),
+        )
+    }
+}
+
+fn decode_text(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(v) => v.trim_end_matches('\0').to_string(),
+        Err(_) => data
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| char::from(*b))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Write a table with the given fields as `(name, type, length, decimals)`, and the records
+    pub fn write_dbf(fields: &[(&str, u8, u8, u8)], records: &[&[&[u8]]]) -> Vec<u8> {
+        let record_len: usize = 1 + fields.iter().map(|f| usize::from(f.2)).sum::<usize>();
+        let header_len = 32 + 32 * fields.len() + 1;
+        let mut data = vec![3, 124, 1, 1];
+        data.extend(u32::try_from(records.len()).unwrap().to_le_bytes());
+        data.extend(u16::try_from(header_len).unwrap().to_le_bytes());
+        data.extend(u16::try_from(record_len).unwrap().to_le_bytes());
+        data.resize(32, 0);
+        for (name, kind, length, decimals) in fields {
+            let mut desc = [0_u8; 32];
+            desc[..name.len()].copy_from_slice(name.as_bytes());
+            desc[11] = *kind;
+            desc[16] = *length;
+            desc[17] = *decimals;
+            data.extend(desc);
+        }
+        data.push(0x0D);
+        for record in records {
+            data.push(b' ');
+            for (value, field) in record.iter().zip(fields) {
+                let mut value = value.to_vec();
+                value.resize(usize::from(field.2), b' ');
+                data.extend(value);
+            }
+        }
+        data.push(0x1A);
+        data
+    }
+
+    #[test]
+    fn dbf_properties() {
+        let data = write_dbf(
+            &[
+                ("NAME", b'C', 12, 0),
+                ("POP", b'N', 8, 0),
+                ("AREA", b'N', 10, 3),
+                ("CAPITAL", b'L', 1, 0),
+                ("FOUNDED", b'D', 8, 0),
+                ("SHAPE", b'M', 10, 0),
+            ],
+            &[
+                &[
+                    b"Z\xfcrich",
+                    b"  421878",
+                    b"    87.880",
+                    b"F",
+                    b"12180101",
+                    b"1",
+                ],
+                &[b"", b"********", b"", b"?", b"", b""],
+            ],
+        );
+        let table = DbfTable::new(&data).unwrap();
+        let types: Vec<_> = table.fields.iter().map(DbfField::field_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                Some("String"),
+                Some("Number"),
+                Some("Number"),
+                Some("Boolean"),
+                Some("String"),
+                None
+            ]
+        );
+        assert_eq!(
+            table.properties(0).unwrap(),
+            vec![
+                ("NAME".to_string(), PropValue::String("Zürich".to_string())),
+                ("POP".to_string(), PropValue::Int(421_878)),
+                ("AREA".to_string(), PropValue::Double(87.88)),
+                ("CAPITAL".to_string(), PropValue::Bool(false)),
+                (
+                    "FOUNDED".to_string(),
+                    PropValue::String("1218-01-01".to_string())
+                ),
+            ]
+        );
+        assert_eq!(table.properties(1).unwrap(), vec![]);
+        assert_eq!(table.properties(2), None);
+    }
+}
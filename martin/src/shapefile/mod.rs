@@ -0,0 +1,583 @@
+//! Vector tiles generated from [ESRI Shapefiles](https://www.esri.com/content/dam/esrisites/sitecore-archive/Files/Pdfs/library/whitepapers/pdfs/shapefile.pdf).
+//! The geometries are read from the `.shp` file, the attributes from the `.dbf` file,
+//! and the coordinates are converted to WGS84 using the `.prj` file. A zip archive with
+//! several shapefiles is served as one source, with a tile layer per shapefile.
+//! All features are kept in memory, so this is meant for previews and small datasets.
+
+mod dbf;
+mod prj;
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::io::{Cursor, Read as _};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{info, trace};
+use martin_tile_utils::{Format, TileInfo};
+use tilejson::{tilejson, Bounds, TileJSON, VectorLayer};
+
+use crate::file_config::{has_extension, FileError, FileResult};
+use crate::shapefile::dbf::DbfTable;
+use crate::shapefile::prj::Projection;
+use crate::shapefile::ShapefileError::{
+    InvalidArchive, InvalidShapefile, IoError, NoShapefiles, UnsupportedProjection,
+};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::utils::mvt::{
+    encode_tile, intersects, LayerBuilder, PropValue, Srs, TileTransform, DEFAULT_MVT_EXTENT,
+};
+use crate::utils::saturation::spawn_blocking;
+use crate::utils::wkb::{Coord, Geometry};
+use crate::{MartinError, MartinResult, TileCoord};
+
+pub type ShapefileResult<T> = Result<T, ShapefileError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ShapefileError {
+    #[error("Unable to read shapefile {}: {0}", .1.display())]
+    IoError(std::io::Error, PathBuf),
+
+    #[error("Unable to read shapefile archive {}: {0}", .1.display())]
+    InvalidArchive(zip::result::ZipError, PathBuf),
+
+    #[error("Archive {} has no shapefiles", .0.display())]
+    NoShapefiles(PathBuf),
+
+    #[error("Unable to parse shapefile {}: {0}", .1.display())]
+    InvalidShapefile(String, PathBuf),
+
+    #[error("Shapefile {} uses the unsupported {0}, only geographic, Web Mercator, and Transverse Mercator projections can be used", .1.display())]
+    UnsupportedProjection(String, PathBuf),
+}
+
+/// The files of one shapefile, the `.dbf` and `.prj` files are optional
+struct ShapefileData {
+    name: String,
+    shp: Vec<u8>,
+    dbf: Option<Vec<u8>>,
+    prj: Option<String>,
+}
+
+#[derive(Debug)]
+struct ShpFeature {
+    id: u64,
+    geometry: Geometry,
+    bbox: Option<[f64; 4]>,
+    props: Vec<(String, PropValue)>,
+}
+
+#[derive(Debug)]
+struct ShpLayer {
+    name: String,
+    fields: BTreeMap<String, String>,
+    features: Vec<ShpFeature>,
+}
+
+/// Serves a shapefile, or a zip archive of shapefiles, as vector tiles generated on the fly.
+/// The features are converted to WGS84 once at startup, and kept in memory.
+#[derive(Clone)]
+pub struct ShapefileSource {
+    id: String,
+    path: PathBuf,
+    layers: Arc<Vec<ShpLayer>>,
+    tilejson: TileJSON,
+}
+
+impl Debug for ShapefileSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ShapefileSource {{ id: {}, path: {:?} }}",
+            self.id, self.path
+        )
+    }
+}
+
+impl ShapefileSource {
+    pub async fn new_box(id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
+        let source = spawn_blocking(move || Self::new(id, path))
+            .await
+            .map_err(|e| FileError::AquireConnError(e.to_string()))??;
+        Ok(Box::new(source))
+    }
+
+    fn new(id: String, path: PathBuf) -> ShapefileResult<Self> {
+        let files = if has_extension(&path, "zip") {
+            read_archive(&path)?
+        } else {
+            vec![read_files(&path, &id)?]
+        };
+        let layers = files
+            .into_iter()
+            .map(|file| {
+                read_layer(file).map_err(|e| match e {
+                    ReadError::Invalid(e) => InvalidShapefile(e, path.clone()),
+                    ReadError::Unsupported(e) => UnsupportedProjection(e, path.clone()),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let bounds = layers
+            .iter()
+            .flat_map(|l| &l.features)
+            .filter_map(|f| f.bbox)
+            .map(|bbox| Srs::Wgs84.to_bounds(bbox))
+            .reduce(|a, b| a + b);
+        let vector_layers = layers
+            .iter()
+            .map(|l| VectorLayer {
+                fields: l.fields.clone(),
+                ..VectorLayer::new(l.name.clone(), BTreeMap::new())
+            })
+            .collect();
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            vector_layers: vector_layers,
+        };
+        tilejson.bounds = bounds.or(Some(Bounds::MAX));
+
+        info!(
+            "Serving {} features in {} layers of shapefile {}",
+            layers.iter().map(|l| l.features.len()).sum::<usize>(),
+            layers.len(),
+            path.display()
+        );
+        Ok(Self {
+            id,
+            path,
+            layers: Arc::new(layers),
+            tilejson,
+        })
+    }
+
+    fn read_tile(&self, xyz: TileCoord) -> TileData {
+        let transform = TileTransform::new(xyz, Srs::Wgs84);
+        let search = transform.search_bbox();
+        let layers = self
+            .layers
+            .iter()
+            .map(|l| {
+                let mut layer = LayerBuilder::new(&l.name, DEFAULT_MVT_EXTENT);
+                for feature in &l.features {
+                    if intersects(feature.bbox, search) {
+                        for part in feature.geometry.to_tile(&|c| transform.apply(c)) {
+                            layer.add_feature(Some(feature.id), &part, &feature.props);
+                        }
+                    }
+                }
+                layer.build()
+            })
+            .collect();
+        encode_tile(layers)
+    }
+}
+
+#[async_trait]
+impl Source for ShapefileSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        Format::Mvt.into()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "shapefile"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let src = self.clone();
+        let xyz = *xyz;
+        let tile = spawn_blocking(move || src.read_tile(xyz))
+            .await
+            .map_err(|e| MartinError::InternalError(e.into()))?;
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| FileError::IoError(e, self.path.clone()))?;
+        Ok(())
+    }
+}
+
+/// Read a `.shp` file and its sibling files, which may use upper case extensions too
+fn read_files(path: &Path, name: &str) -> ShapefileResult<ShapefileData> {
+    let shp = std::fs::read(path).map_err(|e| IoError(e, path.to_path_buf()))?;
+    let sibling = |ext: &str| -> ShapefileResult<Option<Vec<u8>>> {
+        for ext in [ext.to_string(), ext.to_ascii_uppercase()] {
+            let path = path.with_extension(ext);
+            if path.is_file() {
+                let data = std::fs::read(&path).map_err(|e| IoError(e, path))?;
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    };
+    Ok(ShapefileData {
+        name: name.to_string(),
+        shp,
+        dbf: sibling("dbf")?,
+        prj: sibling("prj")?.map(|v| String::from_utf8_lossy(&v).to_string()),
+    })
+}
+
+/// Read all shapefiles of a zip archive, named after their `.shp` files and sorted by their paths
+fn read_archive(path: &Path) -> ShapefileResult<Vec<ShapefileData>> {
+    let err = |e| InvalidArchive(e, path.to_path_buf());
+    let data = std::fs::read(path).map_err(|e| IoError(e, path.to_path_buf()))?;
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(err)?;
+    let mut names: Vec<String> = archive.file_names().map(ToString::to_string).collect();
+    names.sort();
+    let mut result = Vec::new();
+    for shp_name in &names {
+        if !shp_name.to_ascii_lowercase().ends_with(".shp") {
+            continue;
+        }
+        let stem = &shp_name[..shp_name.len() - 4];
+        let mut read = |ext: &str| -> ShapefileResult<Option<Vec<u8>>> {
+            let Some(name) = names.iter().find(|n| {
+                n.strip_prefix(stem)
+                    .map_or(false, |e| e.eq_ignore_ascii_case(ext))
+            }) else {
+                return Ok(None);
+            };
+            let mut content = Vec::new();
+            archive
+                .by_name(name)
+                .map_err(err)?
+                .read_to_end(&mut content)
+                .map_err(|e| IoError(e, path.to_path_buf()))?;
+            Ok(Some(content))
+        };
+        let shp = read(".shp")?.unwrap_or_default();
+        let dbf = read(".dbf")?;
+        let prj = read(".prj")?.map(|v| String::from_utf8_lossy(&v).to_string());
+        let name = stem.rsplit('/').next().unwrap_or(stem).to_string();
+        result.push(ShapefileData {
+            name,
+            shp,
+            dbf,
+            prj,
+        });
+    }
+    if result.is_empty() {
+        return Err(NoShapefiles(path.to_path_buf()));
+    }
+    Ok(result)
+}
+
+enum ReadError {
+    Invalid(String),
+    Unsupported(String),
+}
+
+fn read_layer(file: ShapefileData) -> Result<ShpLayer, ReadError> {
+    let projection = match &file.prj {
+        Some(prj) => Projection::from_wkt(prj).map_err(ReadError::Unsupported)?,
+        // most files without a projection use longitudes and latitudes
+        None => Projection::Geographic {
+            prime_meridian: 0.0,
+        },
+    };
+    let table = file
+        .dbf
+        .as_deref()
+        .map(DbfTable::new)
+        .transpose()
+        .map_err(ReadError::Invalid)?;
+    let fields = table
+        .iter()
+        .flat_map(|t| &t.fields)
+        .filter_map(|f| Some((f.name.clone(), f.field_type()?.to_string())))
+        .collect();
+
+    let data = file.shp.as_slice();
+    if data.len() < 100 || read_i32_be(data, 0) != Some(9994) {
+        return Err(ReadError::Invalid(format!(
+            "{} is not a shapefile",
+            file.name
+        )));
+    }
+    let mut features = Vec::new();
+    let mut pos = 100;
+    let mut idx = 0;
+    while pos + 8 <= data.len() {
+        let number = read_i32_be(data, pos).unwrap_or_default();
+        let len = read_i32_be(data, pos + 4)
+            .and_then(|v| usize::try_from(v).ok())
+            .map(|v| v * 2)
+            .unwrap_or_default();
+        let record = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| ReadError::Invalid(format!("record {number} is truncated")))?;
+        pos += 8 + len;
+        // the attributes are stored in the same order as the geometries
+        let props = match &table {
+            Some(table) => table.properties(idx),
+            None => Some(Vec::new()),
+        };
+        idx += 1;
+        let (Some(props), Some(geometry)) = (props, read_geometry(record)) else {
+            continue;
+        };
+        let geometry = reproject(geometry, &projection);
+        features.push(ShpFeature {
+            id: u64::try_from(number).unwrap_or_default(),
+            bbox: geometry.bbox(),
+            geometry,
+            props,
+        });
+    }
+    Ok(ShpLayer {
+        name: file.name,
+        fields,
+        features,
+    })
+}
+
+fn read_i32_be(data: &[u8], pos: usize) -> Option<i32> {
+    let v = data.get(pos..pos + 4)?;
+    Some(i32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+fn read_i32(data: &[u8], pos: usize) -> Option<i32> {
+    let v = data.get(pos..pos + 4)?;
+    Some(i32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+}
+
+fn read_points(data: &[u8], pos: usize, count: usize) -> Option<Vec<Coord>> {
+    let points = data.get(pos..pos + count * 16)?;
+    Some(
+        points
+            .chunks_exact(16)
+            .map(|v| {
+                let x = f64::from_le_bytes(v[..8].try_into().unwrap_or_default());
+                let y = f64::from_le_bytes(v[8..].try_into().unwrap_or_default());
+                [x, y]
+            })
+            .collect(),
+    )
+}
+
+/// Read the geometry of a record, or `None` for the null shapes, the multipatches, and the invalid records.
+/// The Z and M values are ignored.
+fn read_geometry(record: &[u8]) -> Option<Geometry> {
+    let shape_type = read_i32(record, 0)?;
+    match shape_type {
+        1 | 11 | 21 => Some(Geometry::Points(read_points(record, 4, 1)?)),
+        8 | 18 | 28 => {
+            let count = usize::try_from(read_i32(record, 36)?).ok()?;
+            Some(Geometry::Points(read_points(record, 40, count)?))
+        }
+        3 | 13 | 23 | 5 | 15 | 25 => {
+            let parts = usize::try_from(read_i32(record, 36)?).ok()?;
+            let count = usize::try_from(read_i32(record, 40)?).ok()?;
+            let points = read_points(record, 44 + parts * 4, count)?;
+            let mut starts = (0..parts)
+                .map(|i| usize::try_from(read_i32(record, 44 + i * 4)?).ok())
+                .collect::<Option<Vec<_>>>()?;
+            starts.push(count);
+            let paths = starts
+                .windows(2)
+                .map(|w| points.get(w[0]..w[1]).map(<[Coord]>::to_vec))
+                .collect::<Option<Vec<_>>>()?;
+            Some(if matches!(shape_type, 3 | 13 | 23) {
+                Geometry::Lines(paths)
+            } else {
+                Geometry::Polygons(group_rings(paths))
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Twice the signed area of a ring, positive for the counterclockwise rings
+fn signed_area(ring: &[Coord]) -> f64 {
+    ring.windows(2)
+        .map(|w| w[0][0] * w[1][1] - w[1][0] * w[0][1])
+        .sum()
+}
+
+/// Group the rings into polygons. The exterior rings are clockwise, and each is followed by its holes.
+/// If no ring is clockwise, the winding order is not reliable, and all rings are treated as exterior.
+fn group_rings(rings: Vec<Vec<Coord>>) -> Vec<Vec<Vec<Coord>>> {
+    let any_exterior = rings.iter().any(|r| signed_area(r) < 0.0);
+    let mut polygons: Vec<Vec<Vec<Coord>>> = Vec::new();
+    for ring in rings {
+        match polygons.last_mut() {
+            Some(polygon) if any_exterior && signed_area(&ring) > 0.0 => polygon.push(ring),
+            _ => polygons.push(vec![ring]),
+        }
+    }
+    polygons
+}
+
+fn reproject(geometry: Geometry, projection: &Projection) -> Geometry {
+    let convert = |ring: Vec<Coord>| -> Vec<Coord> {
+        ring.into_iter().map(|c| projection.to_wgs84(c)).collect()
+    };
+    match geometry {
+        Geometry::Points(v) => Geometry::Points(convert(v)),
+        Geometry::Lines(v) => Geometry::Lines(v.into_iter().map(convert).collect()),
+        Geometry::Polygons(v) => Geometry::Polygons(
+            v.into_iter()
+                .map(|p| p.into_iter().map(convert).collect())
+                .collect(),
+        ),
+        Geometry::Collection(v) => {
+            Geometry::Collection(v.into_iter().map(|g| reproject(g, projection)).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use prost::Message as _;
+
+    use super::*;
+    use crate::shapefile::dbf::tests::write_dbf;
+    use crate::utils::mvt::Tile;
+
+    fn write_record(file: &mut Vec<u8>, number: i32, content: &[u8]) {
+        file.extend(number.to_be_bytes());
+        file.extend((i32::try_from(content.len()).unwrap() / 2).to_be_bytes());
+        file.extend(content);
+    }
+
+    fn write_points(content: &mut Vec<u8>, points: &[Coord]) {
+        for [x, y] in points {
+            content.extend(x.to_le_bytes());
+            content.extend(y.to_le_bytes());
+        }
+    }
+
+    /// A shapefile with a point, a null shape, and a polygon with a hole
+    fn write_shp() -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut content = 1_i32.to_le_bytes().to_vec();
+        write_points(&mut content, &[[10.0, 10.0]]);
+        write_record(&mut records, 1, &content);
+        write_record(&mut records, 2, &0_i32.to_le_bytes());
+
+        let exterior = [[0.0, 0.0], [0.0, 5.0], [5.0, 5.0], [5.0, 0.0], [0.0, 0.0]];
+        let hole = [[1.0, 1.0], [2.0, 1.0], [2.0, 2.0], [1.0, 2.0], [1.0, 1.0]];
+        let mut content = 5_i32.to_le_bytes().to_vec();
+        write_points(&mut content, &[[0.0, 0.0], [5.0, 5.0]]);
+        content.extend(2_i32.to_le_bytes());
+        content.extend(10_i32.to_le_bytes());
+        content.extend(0_i32.to_le_bytes());
+        content.extend(5_i32.to_le_bytes());
+        write_points(&mut content, &exterior);
+        write_points(&mut content, &hole);
+        write_record(&mut records, 3, &content);
+
+        let mut file = 9994_i32.to_be_bytes().to_vec();
+        file.resize(24, 0);
+        file.extend((i32::try_from(100 + records.len()).unwrap() / 2).to_be_bytes());
+        file.extend(1000_i32.to_le_bytes());
+        file.extend(0_i32.to_le_bytes());
+        file.resize(100, 0);
+        file.extend(records);
+        file
+    }
+
+    fn write_attributes() -> Vec<u8> {
+        write_dbf(
+            &[("name", b'C', 10, 0), ("rank", b'N', 4, 0)],
+            &[&[b"well", b"1"], &[b"null", b"2"], &[b"park", b"3"]],
+        )
+    }
+
+    #[test]
+    fn shapefile_tiles() {
+        let dir = std::env::temp_dir().join(format!("martin-shp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("places.shp");
+        std::fs::write(&path, write_shp()).unwrap();
+        std::fs::write(dir.join("places.DBF"), write_attributes()).unwrap();
+        let src = ShapefileSource::new("places".to_string(), path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let layer = &src.layers[0];
+        assert_eq!(layer.name, "places");
+        assert_eq!(layer.features.len(), 2);
+        let Geometry::Polygons(polygons) = &layer.features[1].geometry else {
+            panic!("expected a polygon");
+        };
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(polygons[0].len(), 2);
+        assert_eq!(
+            layer.features[1].props,
+            vec![
+                ("name".to_string(), PropValue::String("park".to_string())),
+                ("rank".to_string(), PropValue::Int(3)),
+            ]
+        );
+
+        let tj = src.get_tilejson();
+        assert_eq!(tj.bounds, Some(Bounds::new(0.0, 0.0, 10.0, 10.0)));
+        let fields = &tj.vector_layers.as_ref().unwrap()[0].fields;
+        assert_eq!(fields["name"], "String");
+        assert_eq!(fields["rank"], "Number");
+
+        let tile = Tile::decode(src.read_tile(TileCoord { z: 0, x: 0, y: 0 }).as_slice()).unwrap();
+        let ids: Vec<_> = tile.layers[0].features.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![Some(1), Some(3)]);
+        assert!(src.read_tile(TileCoord { z: 2, x: 0, y: 0 }).is_empty());
+    }
+
+    #[test]
+    fn shapefile_archive() {
+        let path = std::env::temp_dir().join(format!("martin-{}-shp.zip", std::process::id()));
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::FileOptions::default();
+        let utm = r#"PROJCS["WGS 84 / UTM zone 31N",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]],PRIMEM["Greenwich",0]],PROJECTION["Transverse_Mercator"],PARAMETER["central_meridian",3],PARAMETER["scale_factor",0.9996],PARAMETER["false_easting",500000],UNIT["metre",1]]"#;
+        for (name, data) in [
+            ("data/wells.shp", write_shp()),
+            ("data/wells.dbf", write_attributes()),
+            ("data/utm.SHP", write_shp()),
+            ("data/utm.prj", utm.as_bytes().to_vec()),
+            ("readme.txt", b"sample".to_vec()),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap();
+        let src = ShapefileSource::new("archive".to_string(), path.clone()).unwrap();
+
+        let names: Vec<_> = src.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["utm", "wells"]);
+        assert!(src.layers[0].fields.is_empty());
+        let bbox = src.layers[0].features[0].bbox.unwrap();
+        assert!((bbox[0] + 1.4887).abs() < 1e-3 && bbox[1].abs() < 1e-3);
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        zip.start_file("readme.txt", options).unwrap();
+        zip.finish().unwrap();
+        let result = ShapefileSource::new("empty".to_string(), path.clone());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(NoShapefiles(_))));
+    }
+}
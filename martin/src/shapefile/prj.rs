@@ -0,0 +1,322 @@
+//! Conversion of the shapefile coordinates to WGS84, using the projection given as
+//! [WKT](https://docs.ogc.org/is/18-010r7/18-010r7.html) in the `.prj` file.
+//! Geographic, Web Mercator, and Transverse Mercator (e.g. UTM) projections are supported.
+//! Datum shifts are ignored, which is fine for previews, but may move the features by up to a few hundred meters.
+
+use crate::utils::mvt::mercator_to_wgs84;
+use crate::utils::wkb::Coord;
+
+/// A WKT node like `UNIT["Meter",1.0]`, with its quoted strings, numbers, and child nodes
+#[derive(Debug, Default)]
+struct WktNode {
+    name: String,
+    strings: Vec<String>,
+    numbers: Vec<f64>,
+    children: Vec<WktNode>,
+}
+
+impl WktNode {
+    fn child(&self, name: &str) -> Option<&Self> {
+        self.children
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Get a `PARAMETER["name",value]` of a projected CRS
+    fn parameter(&self, name: &str) -> Option<f64> {
+        self.children
+            .iter()
+            .filter(|c| c.name.eq_ignore_ascii_case("parameter"))
+            .find(|c| c.strings.first().map_or(false, |v| normalize(v) == name))
+            .and_then(|c| c.numbers.first().copied())
+    }
+
+    fn number(&self, name: &str, idx: usize) -> Option<f64> {
+        self.child(name).and_then(|c| c.numbers.get(idx).copied())
+    }
+}
+
+/// Lowercase the name, using underscores instead of spaces and dashes like the ESRI names
+fn normalize(name: &str) -> String {
+    name.to_ascii_lowercase().replace([' ', '-'], "_")
+}
+
+fn parse_wkt(text: &str) -> Result<WktNode, String> {
+    let mut chars = text.trim().chars().peekable();
+    let node = parse_node(&mut chars)?;
+    Ok(node)
+}
+
+fn parse_node(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Result<WktNode, String> {
+    let mut node = WktNode::default();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        node.name.push(c);
+    }
+    if node.name.is_empty() {
+        return Err("expected a WKT keyword".to_string());
+    }
+    if chars.next_if(|c| *c == '[' || *c == '(').is_none() {
+        return Err(format!("expected the values of {}", node.name));
+    }
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek() {
+            Some('"') => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        // a quote is escaped by doubling it
+                        Some('"') if chars.next_if_eq(&'"').is_some() => value.push('"'),
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                node.strings.push(value);
+            }
+            Some(c) if c.is_ascii_digit() || *c == '-' || *c == '+' || *c == '.' => {
+                let mut value = String::new();
+                while let Some(c) = chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    value.push(c);
+                }
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("invalid number {value}"))?;
+                node.numbers.push(value);
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let child = parse_node(chars)?;
+                node.children.push(child);
+            }
+            _ => return Err(format!("invalid values of {}", node.name)),
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some(',') => {}
+            Some(']' | ')') => return Ok(node),
+            _ => return Err(format!("unterminated {}", node.name)),
+        }
+    }
+}
+
+/// Projection of the shapefile coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Longitude and latitude in degrees, relative to the prime meridian
+    Geographic {
+        prime_meridian: f64,
+    },
+    WebMercator,
+    TransverseMercator(TransverseMercator),
+}
+
+impl Projection {
+    /// Parse the content of a `.prj` file
+    pub fn from_wkt(text: &str) -> Result<Self, String> {
+        let root = parse_wkt(text)?;
+        let kind = root.name.to_ascii_uppercase();
+        if kind == "GEOGCS" || kind == "GEOGCRS" {
+            return Ok(Self::Geographic {
+                prime_meridian: root.number("PRIMEM", 0).unwrap_or_default(),
+            });
+        }
+        if kind != "PROJCS" {
+            return Err(format!("{} coordinate system", root.name));
+        }
+        let name = root
+            .strings
+            .first()
+            .map(|v| normalize(v))
+            .unwrap_or_default();
+        let projection = root
+            .child("PROJECTION")
+            .and_then(|v| v.strings.first())
+            .map(|v| normalize(v))
+            .unwrap_or_default();
+        if projection.contains("pseudo_mercator")
+            || projection.contains("auxiliary_sphere")
+            || name.contains("pseudo_mercator")
+            || name.contains("web_mercator")
+        {
+            return Ok(Self::WebMercator);
+        }
+        if projection != "transverse_mercator" {
+            return Err(format!("{projection} projection"));
+        }
+
+        let geogcs = root.child("GEOGCS");
+        let spheroid = geogcs
+            .and_then(|v| v.child("DATUM"))
+            .and_then(|v| v.child("SPHEROID"));
+        let semi_major = spheroid.and_then(|v| v.numbers.first().copied());
+        let inverse_flattening = spheroid.and_then(|v| v.numbers.get(1).copied());
+        Ok(Self::TransverseMercator(TransverseMercator::new(
+            semi_major.unwrap_or(6_378_137.0),
+            // a sphere has no flattening, and its inverse flattening is written as 0
+            inverse_flattening
+                .filter(|v| *v > 0.0)
+                .map_or(0.0, |v| 1.0 / v),
+            geogcs
+                .and_then(|v| v.number("PRIMEM", 0))
+                .unwrap_or_default()
+                + root.parameter("central_meridian").unwrap_or_default(),
+            root.parameter("latitude_of_origin").unwrap_or_default(),
+            root.parameter("scale_factor").unwrap_or(1.0),
+            root.parameter("false_easting").unwrap_or_default(),
+            root.parameter("false_northing").unwrap_or_default(),
+            root.number("UNIT", 0).unwrap_or(1.0),
+        )))
+    }
+
+    #[must_use]
+    pub fn to_wgs84(&self, coord: Coord) -> Coord {
+        match self {
+            Self::Geographic { prime_meridian } => [coord[0] + prime_meridian, coord[1]],
+            Self::WebMercator => mercator_to_wgs84(coord[0], coord[1]),
+            Self::TransverseMercator(v) => v.to_wgs84(coord),
+        }
+    }
+}
+
+/// Inverse of the ellipsoidal Transverse Mercator projection, using the series
+/// of J. P. Snyder, "Map Projections: A Working Manual", 1987, pages 63-64
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransverseMercator {
+    semi_major: f64,
+    /// Squared eccentricity of the ellipsoid
+    es: f64,
+    central_meridian: f64,
+    scale_factor: f64,
+    false_easting: f64,
+    false_northing: f64,
+    /// Meters in one unit of the coordinates
+    unit: f64,
+    /// Meridian distance of the latitude of origin
+    origin_distance: f64,
+}
+
+impl TransverseMercator {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        semi_major: f64,
+        flattening: f64,
+        central_meridian: f64,
+        latitude_of_origin: f64,
+        scale_factor: f64,
+        false_easting: f64,
+        false_northing: f64,
+        unit: f64,
+    ) -> Self {
+        let mut result = Self {
+            semi_major,
+            es: 2.0 * flattening - flattening * flattening,
+            central_meridian,
+            scale_factor,
+            false_easting,
+            false_northing,
+            unit,
+            origin_distance: 0.0,
+        };
+        result.origin_distance = result.meridian_distance(latitude_of_origin.to_radians());
+        result
+    }
+
+    fn meridian_distance(&self, lat: f64) -> f64 {
+        let (e2, e4, e6) = (self.es, self.es.powi(2), self.es.powi(3));
+        self.semi_major
+            * ((1.0 - e2 / 4.0 - 3.0 * e4 / 64.0 - 5.0 * e6 / 256.0) * lat
+                - (3.0 * e2 / 8.0 + 3.0 * e4 / 32.0 + 45.0 * e6 / 1024.0) * (2.0 * lat).sin()
+                + (15.0 * e4 / 256.0 + 45.0 * e6 / 1024.0) * (4.0 * lat).sin()
+                - (35.0 * e6 / 3072.0) * (6.0 * lat).sin())
+    }
+
+    #[must_use]
+    pub fn to_wgs84(&self, coord: Coord) -> Coord {
+        let (a, es, k0) = (self.semi_major, self.es, self.scale_factor);
+        let x = (coord[0] - self.false_easting) * self.unit;
+        let y = (coord[1] - self.false_northing) * self.unit;
+        let ep2 = es / (1.0 - es);
+
+        let m = self.origin_distance + y / k0;
+        let mu = m / (a * (1.0 - es / 4.0 - 3.0 * es.powi(2) / 64.0 - 5.0 * es.powi(3) / 256.0));
+        let e1 = (1.0 - (1.0 - es).sqrt()) / (1.0 + (1.0 - es).sqrt());
+        let phi1 = mu
+            + (3.0 * e1 / 2.0 - 27.0 * e1.powi(3) / 32.0) * (2.0 * mu).sin()
+            + (21.0 * e1.powi(2) / 16.0 - 55.0 * e1.powi(4) / 32.0) * (4.0 * mu).sin()
+            + (151.0 * e1.powi(3) / 96.0) * (6.0 * mu).sin()
+            + (1097.0 * e1.powi(4) / 512.0) * (8.0 * mu).sin();
+
+        let (sin1, cos1, tan1) = (phi1.sin(), phi1.cos(), phi1.tan());
+        let c1 = ep2 * cos1.powi(2);
+        let t1 = tan1.powi(2);
+        let n1 = a / (1.0 - es * sin1.powi(2)).sqrt();
+        let r1 = a * (1.0 - es) / (1.0 - es * sin1.powi(2)).powf(1.5);
+        let d = x / (n1 * k0);
+
+        let lat = phi1
+            - (n1 * tan1 / r1)
+                * (d.powi(2) / 2.0
+                    - (5.0 + 3.0 * t1 + 10.0 * c1 - 4.0 * c1.powi(2) - 9.0 * ep2) * d.powi(4)
+                        / 24.0
+                    + (61.0 + 90.0 * t1 + 298.0 * c1 + 45.0 * t1.powi(2)
+                        - 252.0 * ep2
+                        - 3.0 * c1.powi(2))
+                        * d.powi(6)
+                        / 720.0);
+        let lon = (d - (1.0 + 2.0 * t1 + c1) * d.powi(3) / 6.0
+            + (5.0 - 2.0 * c1 + 28.0 * t1 - 3.0 * c1.powi(2) + 8.0 * ep2 + 24.0 * t1.powi(2))
+                * d.powi(5)
+                / 120.0)
+            / cos1;
+        [self.central_meridian + lon.to_degrees(), lat.to_degrees()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UTM_31N: &str = r#"PROJCS["WGS_1984_UTM_Zone_31N",GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984",SPHEROID["WGS_1984",6378137.0,298.257223563]],PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],PROJECTION["Transverse_Mercator"],PARAMETER["False_Easting",500000.0],PARAMETER["False_Northing",0.0],PARAMETER["Central_Meridian",3.0],PARAMETER["Scale_Factor",0.9996],PARAMETER["Latitude_Of_Origin",0.0],UNIT["Meter",1.0]]"#;
+
+    fn assert_coord(actual: Coord, expected: Coord) {
+        assert!(
+            (actual[0] - expected[0]).abs() < 1e-6 && (actual[1] - expected[1]).abs() < 1e-6,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn utm() {
+        let Projection::TransverseMercator(tm) = Projection::from_wkt(UTM_31N).unwrap() else {
+            panic!("expected Transverse Mercator");
+        };
+        assert_coord(tm.to_wgs84([500_000.0, 0.0]), [3.0, 0.0]);
+        // northing of the 45th parallel on the central meridian
+        assert_coord(tm.to_wgs84([500_000.0, 4_982_950.400]), [3.0, 45.0]);
+        // one degree east of the central meridian at the 45th parallel
+        assert_coord(tm.to_wgs84([578_815.302, 4_983_436.768]), [4.0, 45.0]);
+    }
+
+    #[test]
+    fn other_projections() {
+        let wkt = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]],PRIMEM["Greenwich",0],UNIT["degree",0.0174532925199433]]"#;
+        assert_eq!(
+            Projection::from_wkt(wkt),
+            Ok(Projection::Geographic {
+                prime_meridian: 0.0
+            })
+        );
+        let wkt = r#"PROJCS["WGS 84 / Pseudo-Mercator",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]]],PROJECTION["Mercator_1SP"],UNIT["metre",1]]"#;
+        assert_eq!(Projection::from_wkt(wkt), Ok(Projection::WebMercator));
+        let wkt =
+            r#"PROJCS["Lambert",GEOGCS["GRS 1980"],PROJECTION["Lambert_Conformal_Conic_2SP"]]"#;
+        assert_eq!(
+            Projection::from_wkt(wkt),
+            Err("lambert_conformal_conic_2sp projection".to_string())
+        );
+        assert!(Projection::from_wkt("PROJCS[\"broken\"").is_err());
+    }
+}
@@ -1,14 +1,20 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
+use std::time::Duration;
 
 use actix_web::error::ErrorNotFound;
+use actix_web::http::header::HeaderMap;
 use async_trait::async_trait;
-use log::debug;
+use futures::future::join_all;
+use itertools::Itertools as _;
+use log::{debug, warn};
 use martin_tile_utils::TileInfo;
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
 
-use crate::{MartinResult, TileCoord};
+use crate::variants::{VariantConfig, VariantConfigs};
+use crate::MartinError::{InvalidComposite, InvalidSource, InvalidVariant};
+use crate::{MartinResult, TileCoord, TileMatrixSet};
 
 pub type TileData = Vec<u8>;
 pub type UrlQuery = HashMap<String, String>;
@@ -18,39 +24,308 @@ pub type TileInfoSource = Box<dyn Source>;
 pub type TileInfoSources = Vec<TileInfoSource>;
 
 #[derive(Default, Clone)]
-pub struct TileSources(HashMap<String, Box<dyn Source>>);
+pub struct TileSources {
+    sources: HashMap<String, Box<dyn Source>>,
+    /// Named composite sources, mapping a composite ID to the IDs of its member sources
+    composites: BTreeMap<String, Vec<String>>,
+    /// Sources served by one of several variant sources, selected for each request
+    variants: VariantConfigs,
+    /// Sources that are served, but not listed in the catalog
+    hidden: HashSet<String>,
+    /// Sources added by an application embedding Martin, which are not rediscovered
+    embedded: HashSet<String>,
+    /// Sources added with the admin API, which are not rediscovered
+    admin_added: HashSet<String>,
+    /// Sources removed with the admin API, which are not published again when they are rediscovered
+    admin_deleted: HashSet<String>,
+}
 pub type TileCatalog = BTreeMap<String, CatalogSourceEntry>;
 
 impl TileSources {
     #[must_use]
     pub fn new(sources: Vec<TileInfoSources>) -> Self {
-        Self(
-            sources
+        Self {
+            sources: sources
                 .into_iter()
                 .flatten()
                 .map(|src| (src.get_id().to_string(), src))
                 .collect(),
-        )
+            composites: BTreeMap::new(),
+            variants: VariantConfigs::new(),
+            hidden: HashSet::new(),
+            embedded: HashSet::new(),
+            admin_added: HashSet::new(),
+            admin_deleted: HashSet::new(),
+        }
+    }
+
+    /// Add a source created by an application embedding Martin. Unlike the configured sources,
+    /// it is kept as is when the sources are discovered again.
+    pub fn add_embedded(&mut self, source: TileInfoSource) -> MartinResult<()> {
+        let id = source.get_id().to_string();
+        if self.sources.contains_key(&id) {
+            let msg = "a source with the same ID already exists".to_string();
+            return Err(InvalidSource(id, msg));
+        }
+        self.embedded.insert(id.clone());
+        self.sources.insert(id, source);
+        Ok(())
+    }
+
+    /// Check if a source was added by an application embedding Martin
+    #[must_use]
+    pub fn is_embedded(&self, id: &str) -> bool {
+        self.embedded.contains(id)
+    }
+
+    /// Add a source with the admin API. Like the embedded sources, it is kept as is when the sources
+    /// are discovered again.
+    pub fn add_admin_source(&mut self, source: TileInfoSource) {
+        let id = source.get_id().to_string();
+        self.admin_deleted.remove(&id);
+        self.admin_added.insert(id.clone());
+        self.sources.insert(id, source);
+    }
+
+    /// Remove a source with the admin API, so that it is not published again when the sources
+    /// are discovered again. Returns the source if it existed.
+    pub fn delete_admin_source(&mut self, id: &str) -> Option<TileInfoSource> {
+        self.admin_added.remove(id);
+        self.admin_deleted.insert(id.to_string());
+        self.sources.remove(id)
+    }
+
+    /// Check if a source is managed by the discovery of the configured sources, i.e. it was not added
+    /// by an application embedding Martin or with the admin API, nor removed with the admin API
+    #[must_use]
+    pub fn is_discovered(&self, id: &str) -> bool {
+        !self.embedded.contains(id)
+            && !self.admin_added.contains(id)
+            && !self.admin_deleted.contains(id)
+    }
+
+    /// Set the named composite sources. Each composite must have a unique ID, and consist of
+    /// existing sources with the same format and encoding.
+    pub fn set_composites(
+        &mut self,
+        composites: &BTreeMap<String, Vec<String>>,
+    ) -> MartinResult<()> {
+        for (id, members) in composites {
+            let err = |msg: String| Err(InvalidComposite(id.clone(), msg));
+            if self.sources.contains_key(id) {
+                return err("a source with the same ID already exists".to_string());
+            }
+            let mut info: Option<TileInfo> = None;
+            for member in members {
+                let Some(src) = self.sources.get(member) else {
+                    return err(format!("source {member} does not exist"));
+                };
+                let src_inf = src.get_tile_info();
+                match info {
+                    Some(inf) if inf != src_inf => {
+                        return err(format!("cannot merge sources with {inf} with {src_inf}"));
+                    }
+                    _ => info = Some(src_inf),
+                }
+            }
+            if info.is_none() {
+                return err("no sources are listed".to_string());
+            }
+        }
+        self.composites = composites.clone();
+        Ok(())
+    }
+
+    /// Set the sources with variants. Each one must have a unique ID, and all of its variants
+    /// must be existing sources with the same format and encoding.
+    pub fn set_variants(&mut self, variants: &VariantConfigs) -> MartinResult<()> {
+        for (id, cfg) in variants {
+            let err = |msg: String| Err(InvalidVariant(id.clone(), msg));
+            if self.sources.contains_key(id) || self.composites.contains_key(id) {
+                return err("a source with the same ID already exists".to_string());
+            }
+            let mut info: Option<TileInfo> = None;
+            for src_id in cfg.sources.values() {
+                let Some(src) = self.sources.get(src_id) else {
+                    return err(format!("source {src_id} does not exist"));
+                };
+                let src_inf = src.get_tile_info();
+                match info {
+                    Some(inf) if inf != src_inf => {
+                        return err(format!("cannot mix sources with {inf} and {src_inf}"));
+                    }
+                    _ => info = Some(src_inf),
+                }
+            }
+            match cfg.default_variant() {
+                None => return err("no variants are listed".to_string()),
+                Some(v) if !cfg.sources.contains_key(v) => {
+                    return err(format!("default variant {v} is not listed"));
+                }
+                Some(_) => {}
+            }
+        }
+        self.variants = variants.clone();
+        Ok(())
+    }
+
+    /// Set the sources that are served by their URLs, but are not listed in the catalog
+    pub fn set_hidden(&mut self, ids: impl IntoIterator<Item = String>) {
+        self.hidden = ids.into_iter().collect();
+    }
+
+    /// Check if any of the comma-separated sources, or any member of a named composite, is hidden
+    #[must_use]
+    pub fn is_hidden(&self, source_ids: &str) -> bool {
+        !self.hidden.is_empty()
+            && source_ids.split(',').any(|id| {
+                self.hidden.contains(id)
+                    || self.composites.get(id).map_or(false, |members| {
+                        members.iter().any(|v| self.hidden.contains(v))
+                    })
+            })
     }
 
     #[must_use]
     pub fn get_catalog(&self) -> TileCatalog {
-        self.0
+        let mut catalog: TileCatalog = self
+            .sources
             .iter()
             .map(|(id, src)| (id.to_string(), src.get_catalog_entry()))
-            .collect()
+            .collect();
+        for (id, members) in &self.composites {
+            let entries: Vec<_> = members.iter().filter_map(|v| catalog.get(v)).collect();
+            let Some(first) = entries.first() else {
+                continue;
+            };
+            let attribution = entries
+                .iter()
+                .filter_map(|v| v.attribution.as_deref())
+                .unique()
+                .join("\n");
+            let entry = CatalogSourceEntry {
+                content_type: first.content_type.clone(),
+                content_encoding: first.content_encoding.clone(),
+                name: None,
+                description: Some(format!("Composite of {}", members.join(","))),
+                attribution: Some(attribution).filter(|v| !v.is_empty()),
+                tile_matrix_set: first.tile_matrix_set,
+            };
+            catalog.insert(id.clone(), entry);
+        }
+        for (id, cfg) in &self.variants {
+            let Some(mut entry) = cfg
+                .default_variant()
+                .and_then(|v| catalog.get(&cfg.sources[v]))
+                .cloned()
+            else {
+                continue;
+            };
+            entry.name = None;
+            entry.description = Some(format!(
+                "Variants {} selected with ?{}=",
+                cfg.sources.keys().join(","),
+                cfg.query_param()
+            ));
+            catalog.insert(id.clone(), entry);
+        }
+        // the hidden sources are still used by the composite and variant entries above
+        catalog.retain(|id, _| !self.hidden.contains(id));
+        catalog
+    }
+
+    /// Add a new source, replacing any existing source with the same ID
+    pub fn insert(&mut self, source: TileInfoSource) {
+        self.sources.insert(source.get_id().to_string(), source);
+    }
+
+    /// Remove a source, returning it if it existed
+    pub fn remove(&mut self, id: &str) -> Option<TileInfoSource> {
+        self.sources.remove(id)
+    }
+
+    /// IDs of all sources, without the composite and variant sources
+    #[must_use]
+    pub fn source_ids(&self) -> BTreeSet<String> {
+        self.sources.keys().cloned().collect()
+    }
+
+    /// Check if a source exists. Composite sources are not included.
+    #[must_use]
+    pub fn contains(&self, id: &str) -> bool {
+        self.sources.contains_key(id)
+    }
+
+    #[must_use]
+    pub fn is_composite(&self, id: &str) -> bool {
+        self.composites.contains_key(id)
+    }
+
+    #[must_use]
+    pub fn is_variant(&self, id: &str) -> bool {
+        self.variants.contains_key(id)
+    }
+
+    #[must_use]
+    pub fn get_variants(&self, id: &str) -> Option<&VariantConfig> {
+        self.variants.get(id)
+    }
+
+    /// Replace the sources with variants by the variant selected by the request.
+    /// Returns the new comma-separated source IDs, and the request headers used for the selection.
+    #[must_use]
+    pub fn resolve_variants<'a>(
+        &'a self,
+        source_ids: &str,
+        query: &str,
+        headers: &HeaderMap,
+    ) -> (String, Vec<&'a str>) {
+        let mut vary = Vec::new();
+        let ids = source_ids
+            .split(',')
+            .map(|id| match self.variants.get(id) {
+                Some(cfg) => {
+                    if let Some(header) = cfg.header.as_deref() {
+                        if !vary.contains(&header) {
+                            vary.push(header);
+                        }
+                    }
+                    cfg.select(query, headers).unwrap_or(id)
+                }
+                None => id,
+            })
+            .join(",");
+        (ids, vary)
+    }
+
+    /// The comma-separated source IDs, followed by the IDs of the sources serving them, i.e. the members
+    /// of the named composites and all variants of the sources with variants, so that the rules of each
+    /// of these sources can be applied to a request
+    #[must_use]
+    pub fn member_ids(&self, source_ids: &str) -> String {
+        let mut ids: Vec<&str> = source_ids.split(',').collect();
+        for id in source_ids.split(',') {
+            if let Some(members) = self.composites.get(id) {
+                ids.extend(members.iter().map(String::as_str));
+            }
+            if let Some(cfg) = self.variants.get(id) {
+                ids.extend(cfg.sources.values().map(String::as_str));
+            }
+        }
+        ids.into_iter().unique().join(",")
     }
 
     pub fn get_source(&self, id: &str) -> actix_web::Result<&dyn Source> {
         Ok(self
-            .0
+            .sources
             .get(id)
             .ok_or_else(|| ErrorNotFound(format!("Source {id} does not exist")))?
             .as_ref())
     }
 
     /// Get a list of sources, and the tile info for the merged sources.
-    /// Ensure that all sources have the same format and encoding.
+    /// Named composites are replaced with their member sources.
+    /// Ensure that all sources have the same format, encoding, and tile matrix set.
     /// If zoom is specified, filter out sources that do not support it.
     pub fn get_sources(
         &self,
@@ -59,9 +334,16 @@ impl TileSources {
     ) -> actix_web::Result<(Vec<&dyn Source>, bool, TileInfo)> {
         let mut sources = Vec::new();
         let mut info: Option<TileInfo> = None;
+        let mut grid: Option<TileMatrixSet> = None;
         let mut use_url_query = false;
 
-        for id in source_ids.split(',') {
+        let ids = source_ids
+            .split(',')
+            .flat_map(|id| match self.composites.get(id) {
+                Some(members) => members.iter().map(String::as_str).collect(),
+                None => vec![id],
+            });
+        for id in ids {
             let src = self.get_source(id)?;
             let src_inf = src.get_tile_info();
             use_url_query |= src.support_url_query();
@@ -75,6 +357,13 @@ impl TileSources {
                 )))?,
                 None => info = Some(src_inf),
             }
+            let src_grid = src.get_tile_matrix_set();
+            match grid {
+                Some(grid) if grid != src_grid => Err(ErrorNotFound(format!(
+                    "Cannot merge sources with {grid} tiles with {src_grid} tiles"
+                )))?,
+                _ => grid = Some(src_grid),
+            }
 
             // TODO: Use chained-if-let once available
             if match zoom {
@@ -90,6 +379,40 @@ impl TileSources {
         Ok((sources, use_url_query, info.unwrap()))
     }
 
+    /// Check the health of every source, waiting at most `timeout` for each one.
+    /// Returns a map of source IDs to the error message for all sources that failed.
+    pub async fn check_health(&self, timeout: Duration) -> BTreeMap<String, String> {
+        let checks = self.sources.iter().map(|(id, src)| async move {
+            let result = match tokio::time::timeout(timeout, src.check_health()).await {
+                Ok(Ok(())) => return None,
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => format!("Health check timed out after {}ms", timeout.as_millis()),
+            };
+            warn!("Source {id} failed health check: {result}");
+            Some((id.clone(), result))
+        });
+        join_all(checks).await.into_iter().flatten().collect()
+    }
+
+    /// Count the number of sources of each type, e.g. `postgres`, `mbtiles`, `pmtiles`.
+    #[must_use]
+    pub fn get_source_type_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for src in self.sources.values() {
+            *counts.entry(src.get_source_type()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Get the usage of all connection pools used by the sources, keyed by the pool ID.
+    #[must_use]
+    pub fn get_pool_status(&self) -> BTreeMap<String, PoolStatus> {
+        self.sources
+            .values()
+            .filter_map(|src| src.get_pool_status())
+            .collect()
+    }
+
     pub fn check_zoom(src: &dyn Source, id: &str, zoom: u8) -> bool {
         let is_valid = src.is_valid_zoom(zoom);
         if !is_valid {
@@ -100,21 +423,71 @@ impl TileSources {
 }
 
 #[async_trait]
-pub trait Source: Send + Debug {
+pub trait Source: Send + Sync + Debug {
     fn get_id(&self) -> &str;
 
     fn get_tilejson(&self) -> &TileJSON;
 
     fn get_tile_info(&self) -> TileInfo;
 
+    /// Short name of the backend type, e.g. `postgres` or `mbtiles`
+    fn get_source_type(&self) -> &'static str;
+
     fn clone_source(&self) -> Box<dyn Source>;
 
     fn support_url_query(&self) -> bool {
         false
     }
 
+    /// Whether `UTFGrid` interaction tiles are generated from the vector tiles of this source
+    fn support_utfgrid(&self) -> bool {
+        false
+    }
+
+    /// Whether the tiles of this source may be kept in the main tile cache
+    fn is_cacheable(&self) -> bool {
+        true
+    }
+
+    /// Tile grid of the source, i.e. how the tile coordinates map to the earth
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        TileMatrixSet::default()
+    }
+
     async fn get_tile(&self, xyz: &TileCoord, query: &Option<UrlQuery>) -> MartinResult<TileData>;
 
+    /// Get a tile with the caching headers to send with it, e.g. the headers of an upstream server.
+    /// Only used when the tile is not taken from a tile cache.
+    async fn get_tile_with_headers(
+        &self,
+        xyz: &TileCoord,
+        query: &Option<UrlQuery>,
+    ) -> MartinResult<(TileData, Option<TileHeaders>)> {
+        Ok((self.get_tile(xyz, query).await?, None))
+    }
+
+    /// Report connection pool usage with the pool ID, if the source uses a pool.
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        None
+    }
+
+    /// Verify that the backend of this source is still reachable.
+    /// Used by the deep health check, so it should be cheap.
+    async fn check_health(&self) -> MartinResult<()> {
+        Ok(())
+    }
+
+    /// Whether [`Source::refresh_data`] does anything, e.g. if the source depends on materialized views
+    fn support_refresh(&self) -> bool {
+        false
+    }
+
+    /// Refresh the data the tiles are generated from, e.g. the materialized views of a `PostgreSQL` source.
+    /// The cached tiles are purged by the caller.
+    async fn refresh_data(&self) -> MartinResult<()> {
+        Ok(())
+    }
+
     fn is_valid_zoom(&self, zoom: u8) -> bool {
         let tj = self.get_tilejson();
         tj.minzoom.map_or(true, |minzoom| zoom >= minzoom)
@@ -131,6 +504,8 @@ pub trait Source: Send + Debug {
             name: tilejson.name.as_ref().filter(|v| *v != id).cloned(),
             description: tilejson.description.clone(),
             attribution: tilejson.attribution.clone(),
+            tile_matrix_set: Some(self.get_tile_matrix_set())
+                .filter(|v| *v != TileMatrixSet::default()),
         }
     }
 }
@@ -141,6 +516,18 @@ impl Clone for Box<dyn Source> {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// Maximum number of connections in the pool
+    pub max_size: usize,
+    /// Number of currently open connections
+    pub size: usize,
+    /// Number of idle connections
+    pub available: usize,
+    /// Number of requests waiting for a connection
+    pub waiting: usize,
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CatalogSourceEntry {
@@ -149,6 +536,8 @@ pub struct CatalogSourceEntry {
     pub name: Option<String>,
     pub description: Option<String>,
     pub attribution: Option<String>,
+    /// Tile grid of the source, only listed if it is not `WebMercatorQuad`
+    pub tile_matrix_set: Option<TileMatrixSet>,
 }
 
 #[cfg(test)]
@@ -163,14 +552,32 @@ mod tests {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Tile {
     pub data: TileData,
     pub info: TileInfo,
+    /// Caching headers replacing the ones Martin would send
+    pub headers: Option<TileHeaders>,
+    /// Made of stale cached tiles that are being fetched again, so it must not be cached in another form
+    pub stale: bool,
 }
 
 impl Tile {
     #[must_use]
     pub fn new(data: TileData, info: TileInfo) -> Self {
-        Self { data, info }
+        Self {
+            data,
+            info,
+            headers: None,
+            stale: false,
+        }
     }
 }
+
+/// Caching headers of a tile, e.g. forwarded from an upstream server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TileHeaders {
+    pub cache_control: Option<String>,
+    pub etag: Option<String>,
+    pub expires: Option<String>,
+}
@@ -1,10 +1,12 @@
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap};
-use std::fmt::Debug;
+use std::fmt::{Debug, Formatter};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use futures::future::try_join_all;
-use log::{info, warn};
+use log::{debug, info, warn};
+use moka::future::Cache;
 use serde::{Deserialize, Serialize};
 use spreet::fs::get_svg_input_paths;
 use spreet::resvg::usvg::{Error as ResvgError, Options, Tree, TreeParsing};
@@ -12,6 +14,17 @@ use spreet::sprite::{sprite_name, Sprite, Spritesheet, SpritesheetBuilder};
 use tokio::io::AsyncReadExt;
 
 use crate::file_config::{FileConfigEnum, FileResult};
+use crate::utils::remote::{download, download_dir, is_remote, remote_id, RemoteError};
+use crate::MartinResult;
+
+/// Extensions of the files downloaded for the remote sprite sources
+const SPRITE_EXTENSIONS: &[&str] = &["svg"];
+
+/// Highest pixel ratio of the high-DPI spritesheets, requested with the "@2x" or "@3x" suffix
+const MAX_PIXEL_RATIO: u8 = 3;
+
+/// Maximum number of generated spritesheets to keep while the sprite directories are watched
+const SPRITESHEET_CACHE_SIZE: u64 = 256;
 
 #[derive(thiserror::Error, Debug)]
 pub enum SpriteError {
@@ -41,6 +54,9 @@ pub enum SpriteError {
 
     #[error("Unable to generate spritesheet")]
     UnableToGenerateSpritesheet,
+
+    #[error(transparent)]
+    RemoteError(#[from] RemoteError),
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
@@ -51,10 +67,24 @@ pub struct CatalogSpriteEntry {
 pub type SpriteCatalog = BTreeMap<String, CatalogSpriteEntry>;
 
 #[derive(Debug, Clone, Default)]
-pub struct SpriteSources(HashMap<String, SpriteSource>);
+pub struct SpriteSources {
+    sources: HashMap<String, SpriteSource>,
+    /// Generated spritesheets, only kept if the sprite directories are watched for changes
+    cache: Option<SpritesheetCache>,
+}
+
+#[derive(Clone)]
+struct SpritesheetCache(Cache<String, Arc<Spritesheet>>);
+
+impl Debug for SpritesheetCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SpritesheetCache({} entries)", self.0.entry_count())
+    }
+}
 
 impl SpriteSources {
-    pub fn resolve(config: &mut FileConfigEnum) -> FileResult<Self> {
+    /// Resolve the configured sprite directories, and download the remote sprite sources
+    pub async fn resolve(config: &mut FileConfigEnum) -> MartinResult<Self> {
         let Some(cfg) = config.extract_file_config() else {
             return Ok(Self::default());
         };
@@ -66,11 +96,33 @@ impl SpriteSources {
         if let Some(sources) = cfg.sources {
             for (id, source) in sources {
                 configs.insert(id.clone(), source.clone());
-                results.add_source(id, source.abs_path()?);
+                let path = source.get_path();
+                if is_remote(path) {
+                    let url = path.to_string_lossy().to_string();
+                    let dir = download(&url, &download_dir("sprites", &id), SPRITE_EXTENSIONS)
+                        .await
+                        .map_err(SpriteError::from)?;
+                    results.add_source(id, dir, Some(url));
+                } else {
+                    results.add_source(id, source.abs_path()?, None);
+                }
             }
         };
 
         for path in cfg.paths {
+            if is_remote(&path) {
+                let url = path.to_string_lossy().to_string();
+                let Some(id) = remote_id(&url) else {
+                    warn!("Ignoring remote sprite source with no name from {url}");
+                    continue;
+                };
+                let dir = download(&url, &download_dir("sprites", &id), SPRITE_EXTENSIONS)
+                    .await
+                    .map_err(SpriteError::from)?;
+                directories.push(path);
+                results.add_source(id, dir, Some(url));
+                continue;
+            }
             let Some(name) = path.file_name() else {
                 warn!(
                     "Ignoring sprite source with no name from {}",
@@ -79,7 +131,7 @@ impl SpriteSources {
                 continue;
             };
             directories.push(path.clone());
-            results.add_source(name.to_string_lossy().to_string(), path);
+            results.add_source(name.to_string_lossy().to_string(), path, None);
         }
 
         *config = FileConfigEnum::new_extended(directories, configs, cfg.unrecognized);
@@ -87,10 +139,54 @@ impl SpriteSources {
         Ok(results)
     }
 
+    /// Keep the generated spritesheets until [`Self::invalidate`] is called,
+    /// instead of generating them again for every request
+    pub fn enable_cache(&mut self) {
+        self.cache = Some(SpritesheetCache(Cache::new(SPRITESHEET_CACHE_SIZE)));
+    }
+
+    /// Drop all generated spritesheets, e.g. after the SVG files have changed
+    pub fn invalidate(&self) {
+        if let Some(cache) = &self.cache {
+            debug!("Removing all generated spritesheets from the cache");
+            cache.0.invalidate_all();
+        }
+    }
+
+    /// Download all remote sprite sources again, keeping the previous files of the ones that fail.
+    /// Returns the number of refreshed sources.
+    pub async fn refresh_remote(&self) -> usize {
+        let mut count = 0;
+        for (id, source) in &self.sources {
+            let Some(url) = &source.remote else {
+                continue;
+            };
+            match download(url, &download_dir("sprites", id), SPRITE_EXTENSIONS).await {
+                Ok(_) => count += 1,
+                Err(e) => {
+                    warn!("Unable to refresh sprite source {id}, keeping its previous files: {e}");
+                }
+            }
+        }
+        count
+    }
+
+    /// Check if any of the sprite sources is downloaded from a remote location
+    #[must_use]
+    pub fn has_remote(&self) -> bool {
+        self.sources.values().any(|v| v.remote.is_some())
+    }
+
+    /// Directories of all sprite sources
+    #[must_use]
+    pub fn get_directories(&self) -> Vec<PathBuf> {
+        self.sources.values().map(|v| v.path.clone()).collect()
+    }
+
     pub fn get_catalog(&self) -> FileResult<SpriteCatalog> {
         // TODO: all sprite generation should be pre-cached
         Ok(self
-            .0
+            .sources
             .iter()
             .map(|(id, source)| {
                 let mut images = get_svg_input_paths(&source.path, true)
@@ -103,49 +199,64 @@ impl SpriteSources {
             .collect())
     }
 
-    fn add_source(&mut self, id: String, path: PathBuf) {
+    fn add_source(&mut self, id: String, path: PathBuf, remote: Option<String>) {
         let disp_path = path.display();
         if path.is_file() {
             warn!("Ignoring non-directory sprite source {id} from {disp_path}");
         } else {
-            match self.0.entry(id) {
+            match self.sources.entry(id) {
                 Entry::Occupied(v) => {
                     warn!("Ignoring duplicate sprite source {} from {disp_path} because it was already configured for {}",
                     v.key(), v.get().path.display());
                 }
                 Entry::Vacant(v) => {
-                    info!("Configured sprite source {} from {disp_path}", v.key());
-                    v.insert(SpriteSource { path });
+                    let from = remote.clone().unwrap_or_else(|| disp_path.to_string());
+                    info!("Configured sprite source {} from {from}", v.key());
+                    v.insert(SpriteSource { path, remote });
                 }
             }
         };
     }
 
     /// Given a list of IDs in a format "id1,id2,id3", return a spritesheet with them all.
-    /// `ids` may optionally end with "@2x" to request a high-DPI spritesheet.
-    pub async fn get_sprites(&self, ids: &str) -> Result<Spritesheet, SpriteError> {
-        let (ids, dpi) = if let Some(ids) = ids.strip_suffix("@2x") {
-            (ids, 2)
-        } else {
-            (ids, 1)
-        };
+    /// `ids` may optionally end with "@2x" or "@3x" to request a high-DPI spritesheet.
+    pub async fn get_sprites(&self, ids: &str) -> Result<Arc<Spritesheet>, SpriteError> {
+        if let Some(cache) = &self.cache {
+            if let Some(sheet) = cache.0.get(ids).await {
+                return Ok(sheet);
+            }
+        }
 
-        let sprite_ids = ids
+        let (sprite_ids, dpi) = parse_pixel_ratio(ids);
+        let sprite_ids = sprite_ids
             .split(',')
             .map(|id| {
-                self.0
+                self.sources
                     .get(id)
                     .ok_or_else(|| SpriteError::SpriteNotFound(id.to_string()))
             })
             .collect::<Result<Vec<_>, SpriteError>>()?;
 
-        get_spritesheet(sprite_ids.into_iter(), dpi).await
+        let sheet = Arc::new(get_spritesheet(sprite_ids.into_iter(), dpi).await?);
+        if let Some(cache) = &self.cache {
+            cache.0.insert(ids.to_string(), sheet.clone()).await;
+        }
+        Ok(sheet)
     }
 }
 
+/// Split the optional "@2x" or "@3x" pixel ratio suffix from the sprite IDs
+fn parse_pixel_ratio(ids: &str) -> (&str, u8) {
+    (2..=MAX_PIXEL_RATIO)
+        .find_map(|ratio| Some((ids.strip_suffix(&format!("@{ratio}x"))?, ratio)))
+        .unwrap_or((ids, 1))
+}
+
 #[derive(Clone, Debug)]
 pub struct SpriteSource {
     path: PathBuf,
+    /// URL the SVG files were downloaded from
+    remote: Option<String>,
 }
 
 async fn parse_sprite(
@@ -208,11 +319,12 @@ mod tests {
             PathBuf::from("../tests/fixtures/sprites/src2"),
         ]);
 
-        let sprites = SpriteSources::resolve(&mut cfg).unwrap().0;
+        let sprites = SpriteSources::resolve(&mut cfg).await.unwrap().sources;
         assert_eq!(sprites.len(), 2);
 
         test_src(sprites.values(), 1, "all_1").await;
         test_src(sprites.values(), 2, "all_2").await;
+        test_src(sprites.values(), 3, "all_3").await;
 
         test_src(sprites.get("src1").into_iter(), 1, "src1_1").await;
         test_src(sprites.get("src1").into_iter(), 2, "src1_2").await;
@@ -221,6 +333,51 @@ mod tests {
         test_src(sprites.get("src2").into_iter(), 2, "src2_2").await;
     }
 
+    #[actix_rt::test]
+    async fn test_cached_sprites() {
+        let dir = std::env::temp_dir().join(format!("martin-sprites-{}", std::process::id()));
+        let src = dir.join("icons");
+        std::fs::create_dir_all(&src).unwrap();
+        let fixtures = PathBuf::from("../tests/fixtures/sprites/src1");
+        std::fs::copy(fixtures.join("bear.svg"), src.join("bear.svg")).unwrap();
+
+        let mut sprites = SpriteSources::resolve(&mut FileConfigEnum::new(vec![src.clone()]))
+            .await
+            .unwrap();
+        sprites.enable_cache();
+        let images =
+            |sheet: Arc<Spritesheet>| sheet.get_index().keys().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            images(sprites.get_sprites("icons").await.unwrap()),
+            ["bear"]
+        );
+
+        std::fs::copy(
+            fixtures.join("another_bicycle.svg"),
+            src.join("another_bicycle.svg"),
+        )
+        .unwrap();
+        assert_eq!(
+            images(sprites.get_sprites("icons").await.unwrap()),
+            ["bear"]
+        );
+        sprites.invalidate();
+        assert_eq!(
+            images(sprites.get_sprites("icons").await.unwrap()),
+            ["another_bicycle", "bear"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pixel_ratio() {
+        assert_eq!(parse_pixel_ratio("src1,src2"), ("src1,src2", 1));
+        assert_eq!(parse_pixel_ratio("src1,src2@2x"), ("src1,src2", 2));
+        assert_eq!(parse_pixel_ratio("src1@3x"), ("src1", 3));
+        assert_eq!(parse_pixel_ratio("src1@4x"), ("src1@4x", 1));
+    }
+
     async fn test_src(
         sources: impl Iterator<Item = &SpriteSource>,
         pixel_ratio: u8,
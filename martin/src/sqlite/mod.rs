@@ -0,0 +1,266 @@
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use log::{info, trace};
+use martin_tile_utils::TileInfo;
+use mbtiles::invert_y_value;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use tilejson::{tilejson, TileJSON};
+
+use crate::file_config::{FileError, FileResult};
+use crate::source::{Source, TileData, UrlQuery};
+use crate::sqlite::SqliteError::{EmptyTable, QueryError, SqlError, UnknownFormat};
+use crate::utils::mvt::{tile_bbox, Srs};
+use crate::{MartinResult, TileCoord};
+
+pub type SqliteResult<T> = Result<T, SqliteError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SqliteError {
+    #[error("Unable to read SQLite file {}: {0}", .1.display())]
+    SqlError(sqlx::Error, PathBuf),
+
+    #[error("Table {1} of SQLite file {} has no tiles", .0.display())]
+    EmptyTable(PathBuf, String),
+
+    #[error("Tiles of table {1} in SQLite file {} have an unknown format", .0.display())]
+    UnknownFormat(PathBuf, String),
+
+    #[error("Unable to get tile {2:#} from source {1}: {0}")]
+    QueryError(sqlx::Error, String, TileCoord),
+}
+
+/// Numbering of the tile rows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileScheme {
+    /// Rows are counted from the south, like in `MBTiles`
+    #[default]
+    Tms,
+    /// Rows are counted from the north, like in the tile URLs
+    Xyz,
+}
+
+/// Table and column names of a tile archive that does not follow the `MBTiles` schema exactly
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TileTableConfig {
+    /// Table or view with the tiles [default: `tiles`]
+    pub table: Option<String>,
+    /// Column with the zoom level [default: `zoom_level`]
+    pub zoom_column: Option<String>,
+    /// Column with the tile column [default: `tile_column`]
+    pub x_column: Option<String>,
+    /// Column with the tile row [default: `tile_row`]
+    pub y_column: Option<String>,
+    /// Column with the tile data [default: `tile_data`]
+    pub data_column: Option<String>,
+    /// Numbering of the tile rows [default: tms]
+    pub scheme: Option<TileScheme>,
+}
+
+/// Serves the tiles of an `SQLite` file with a configurable tile table, for the archives made
+/// by legacy tools that are close to `MBTiles`, but have no metadata or use other names.
+#[derive(Clone)]
+pub struct SqliteSource {
+    id: String,
+    path: PathBuf,
+    pool: SqlitePool,
+    /// Query of a single tile, with the zoom, column, and row parameters
+    query: String,
+    scheme: TileScheme,
+    tilejson: TileJSON,
+    tile_info: TileInfo,
+}
+
+impl Debug for SqliteSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SqliteSource {{ id: {}, path: {:?} }}",
+            self.id, self.path
+        )
+    }
+}
+
+impl SqliteSource {
+    pub async fn new_box(
+        id: String,
+        path: PathBuf,
+        cfg: TileTableConfig,
+    ) -> FileResult<Box<dyn Source>> {
+        Ok(Box::new(SqliteSource::new(id, path, cfg).await?))
+    }
+
+    async fn new(id: String, path: PathBuf, cfg: TileTableConfig) -> SqliteResult<Self> {
+        let opt = SqliteConnectOptions::new().filename(&path).read_only(true);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(opt)
+            .await
+            .map_err(|e| SqlError(e, path.clone()))?;
+
+        let name =
+            |v: Option<String>, default: &str| escape_identifier(v.as_deref().unwrap_or(default));
+        let table_name = cfg.table.clone().unwrap_or_else(|| "tiles".to_string());
+        let table = escape_identifier(&table_name);
+        let z = name(cfg.zoom_column, "zoom_level");
+        let x = name(cfg.x_column, "tile_column");
+        let y = name(cfg.y_column, "tile_row");
+        let data = name(cfg.data_column, "tile_data");
+        let scheme = cfg.scheme.unwrap_or_default();
+
+        let err = |e| SqlError(e, path.clone());
+        let zooms = sqlx::query(&format!("SELECT min({z}), max({z}) FROM {table}"))
+            .fetch_one(&pool)
+            .await
+            .map_err(err)?;
+        let (Some(minzoom), Some(maxzoom)) = (zooms.get::<Option<u8>, _>(0), zooms.get(1)) else {
+            return Err(EmptyTable(path, table_name));
+        };
+
+        let sample = format!("SELECT {data} FROM {table} WHERE {data} IS NOT NULL LIMIT 1");
+        let tile_info = sqlx::query(&sample)
+            .fetch_optional(&pool)
+            .await
+            .map_err(err)?
+            .and_then(|row| TileInfo::detect(&row.get::<Vec<u8>, _>(0)));
+        let Some(tile_info) = tile_info else {
+            return Err(UnknownFormat(path, table_name));
+        };
+
+        let range = sqlx::query(&format!(
+            "SELECT min({x}), min({y}), max({x}), max({y}) FROM {table} WHERE {z} = ?"
+        ))
+        .bind(maxzoom)
+        .fetch_one(&pool)
+        .await
+        .map_err(err)?;
+        let range: [u32; 4] = [range.get(0), range.get(1), range.get(2), range.get(3)];
+
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            bounds: tile_range_bounds(maxzoom, range, scheme),
+        };
+        tilejson.minzoom = Some(minzoom);
+        tilejson.maxzoom = Some(maxzoom);
+        info!(
+            "Serving table {table_name} of {} with {scheme:?} rows",
+            path.display()
+        );
+
+        Ok(Self {
+            id,
+            path,
+            pool,
+            query: format!("SELECT {data} FROM {table} WHERE {z} = ? AND {x} = ? AND {y} = ?"),
+            scheme,
+            tilejson,
+            tile_info,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for SqliteSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.tile_info
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        _url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let y = match self.scheme {
+            TileScheme::Tms => invert_y_value(xyz.z, xyz.y),
+            TileScheme::Xyz => xyz.y,
+        };
+        let tile = sqlx::query(&self.query)
+            .bind(xyz.z)
+            .bind(xyz.x)
+            .bind(y)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| FileError::from(QueryError(e, self.id.clone(), *xyz)))?
+            .and_then(|row| row.get::<Option<Vec<u8>>, _>(0))
+            .unwrap_or_default();
+        if tile.is_empty() {
+            trace!("Couldn't find tile data in {xyz:#} of {}", self.id);
+        }
+        Ok(tile)
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| FileError::from(SqlError(e, self.path.clone())))?;
+        Ok(())
+    }
+}
+
+fn escape_identifier(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Bounds of the tiles between the min and max column and row of a zoom level
+fn tile_range_bounds(
+    z: u8,
+    [min_x, min_y, max_x, max_y]: [u32; 4],
+    scheme: TileScheme,
+) -> tilejson::Bounds {
+    let (top, bottom) = match scheme {
+        TileScheme::Tms => (invert_y_value(z, max_y), invert_y_value(z, min_y)),
+        TileScheme::Xyz => (min_y, max_y),
+    };
+    let first = tile_bbox(TileCoord {
+        z,
+        x: min_x,
+        y: top,
+    });
+    let last = tile_bbox(TileCoord {
+        z,
+        x: max_x,
+        y: bottom,
+    });
+    Srs::WebMercator.to_bounds([first[0], last[1], last[2], first[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_of_tile_range() {
+        let world = tile_range_bounds(1, [0, 0, 1, 1], TileScheme::Xyz);
+        assert_eq!(world, tile_range_bounds(1, [0, 0, 1, 1], TileScheme::Tms));
+        assert!((world.left + 180.0).abs() < 1e-9);
+        assert!((world.top - 85.051_128_779_806_59).abs() < 1e-9);
+
+        // the north-west quarter of the world is the first row with xyz, and the second with tms
+        let xyz = tile_range_bounds(1, [0, 0, 0, 0], TileScheme::Xyz);
+        let tms = tile_range_bounds(1, [0, 1, 0, 1], TileScheme::Tms);
+        assert_eq!(xyz, tms);
+        assert!(xyz.bottom.abs() < 1e-9);
+        assert!(xyz.right.abs() < 1e-9);
+    }
+}
@@ -0,0 +1,268 @@
+use std::path::PathBuf;
+
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorNotFound, ErrorUnauthorized};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use log::info;
+use serde::Deserialize;
+use subtle::ConstantTimeEq as _;
+
+use crate::cog::CogSource;
+use crate::geoparquet::GeoParquetSource;
+use crate::gpkg::GpkgSource;
+use crate::mbtiles::MbtSource;
+use crate::osm::OsmSource;
+use crate::pmtiles::PmtSource;
+use crate::shapefile::ShapefileSource;
+use crate::source::TileSources;
+use crate::sqlite::{SqliteSource, TileTableConfig};
+use crate::srv::config::AdminConfig;
+use crate::srv::server::map_internal_error;
+use crate::srv::{
+    CachePurger, Catalog, CatalogEvent, Scheduler, Throttle, Webhooks, RESERVED_KEYWORDS,
+};
+use crate::IdResolver;
+
+/// Make sure the admin API is enabled, and that the request has the right bearer token.
+pub fn authorize(req: &HttpRequest, admin: Option<&AdminConfig>) -> ActixResult<()> {
+    let Some(admin) = admin else {
+        // Pretend the admin API does not exist if it is not configured
+        return Err(ErrorNotFound("Admin API is not enabled"));
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // compared in constant time, so that the token cannot be guessed from the response times
+    let is_valid = token.map_or(false, |v| v.as_bytes().ct_eq(admin.token.as_bytes()).into());
+    if is_valid {
+        Ok(())
+    } else {
+        Err(ErrorUnauthorized("Invalid or missing admin token"))
+    }
+}
+
+#[derive(Deserialize)]
+struct PurgeRequest {
+    source_ids: String,
+}
+
+/// Remove all cached tiles of the given comma-separated sources, in this and all synced instances
+#[route("/_/purge/{source_ids}", method = "POST")]
+async fn post_purge(
+    req: HttpRequest,
+    path: Path<PurgeRequest>,
+    admin: Option<Data<AdminConfig>>,
+    sources: Data<ArcSwap<TileSources>>,
+    purger: Data<CachePurger>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let ids: Vec<String> = path
+        .source_ids
+        .split(',')
+        .map(ToString::to_string)
+        .collect();
+    {
+        let sources = sources.load();
+        if let Some(id) = ids.iter().find(|id| {
+            !sources.contains(id) && !sources.is_composite(id) && !sources.is_variant(id)
+        }) {
+            return Err(ErrorNotFound(format!("Source {id} does not exist")));
+        }
+    }
+    purger.purge(ids).await.map_err(map_internal_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum NewSourceType {
+    Mbtiles,
+    Pmtiles,
+    Cog,
+    Gpkg,
+    Geoparquet,
+    Osm,
+    Shapefile,
+    Sqlite,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewSourceRequest {
+    id: String,
+    #[serde(rename = "type")]
+    source_type: NewSourceType,
+    path: PathBuf,
+}
+
+/// Register a new file-based source without restarting the server
+#[route("/_/sources", method = "POST")]
+async fn post_source(
+    req: HttpRequest,
+    body: Json<NewSourceRequest>,
+    admin: Option<Data<AdminConfig>>,
+    sources: Data<ArcSwap<TileSources>>,
+    catalog: Data<ArcSwap<Catalog>>,
+    webhooks: Data<Webhooks>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let NewSourceRequest {
+        id,
+        source_type,
+        path,
+    } = body.into_inner();
+
+    let current = sources.load_full();
+    if current.contains(&id) || current.is_composite(&id) || current.is_variant(&id) {
+        return Err(ErrorConflict(format!("Source {id} already exists")));
+    }
+    // the ID must be one that the discovery would have kept as is
+    let idr = IdResolver::new(RESERVED_KEYWORDS);
+    for existing in current.source_ids() {
+        let _ = idr.resolve(&existing, existing.clone());
+    }
+    if id.is_empty() || idr.resolve(&id, format!("admin:{id}")) != id {
+        return Err(ErrorBadRequest(format!("Source ID {id} is not allowed")));
+    }
+
+    let source = match source_type {
+        NewSourceType::Mbtiles => MbtSource::new_box(id.clone(), path).await,
+        NewSourceType::Pmtiles => PmtSource::new_box(id.clone(), path).await,
+        NewSourceType::Cog => CogSource::new_box(id.clone(), path).await,
+        NewSourceType::Gpkg => GpkgSource::new_box(id.clone(), path, None).await,
+        NewSourceType::Geoparquet => GeoParquetSource::new_box(id.clone(), path).await,
+        NewSourceType::Osm => OsmSource::new_box(id.clone(), path, None).await,
+        NewSourceType::Shapefile => ShapefileSource::new_box(id.clone(), path).await,
+        NewSourceType::Sqlite => {
+            SqliteSource::new_box(id.clone(), path, TileTableConfig::default()).await
+        }
+    }
+    .map_err(|e| ErrorBadRequest(e.to_string()))?;
+    let entry = source.get_catalog_entry();
+
+    info!("Adding source {id} using the admin API");
+    sources.rcu(|current| {
+        let mut updated = TileSources::clone(current);
+        updated.add_admin_source(source.clone());
+        updated
+    });
+    update_catalog(&sources, &catalog);
+    webhooks.notify(CatalogEvent {
+        added: vec![id],
+        ..CatalogEvent::new(&catalog.load())
+    });
+
+    Ok(HttpResponse::Created().json(entry))
+}
+
+/// Disable a source without restarting the server, and drop its cached tiles
+#[route("/_/sources/{source_id}", method = "DELETE")]
+async fn delete_source(
+    req: HttpRequest,
+    path: Path<SourceRequest>,
+    admin: Option<Data<AdminConfig>>,
+    sources: Data<ArcSwap<TileSources>>,
+    catalog: Data<ArcSwap<Catalog>>,
+    purger: Data<CachePurger>,
+    webhooks: Data<Webhooks>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let id = &path.source_id;
+    if !sources.load().contains(id) {
+        return Err(ErrorNotFound(format!("Source {id} does not exist")));
+    }
+
+    info!("Removing source {id} using the admin API");
+    sources.rcu(|current| {
+        let mut updated = TileSources::clone(current);
+        updated.delete_admin_source(id);
+        updated
+    });
+    update_catalog(&sources, &catalog);
+    purger
+        .purge(vec![id.clone()])
+        .await
+        .map_err(map_internal_error)?;
+    webhooks.notify(CatalogEvent {
+        removed: vec![id.clone()],
+        ..CatalogEvent::new(&catalog.load())
+    });
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Discover all sources again, publishing the changes one source at a time,
+/// and report the added, refreshed, removed, and rolled back sources
+#[route("/_/reload", method = "POST")]
+async fn post_reload(
+    req: HttpRequest,
+    admin: Option<Data<AdminConfig>>,
+    scheduler: Data<Scheduler>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    info!("Reloading sources using the admin API");
+    let summary = scheduler
+        .rediscover_files(&[])
+        .await
+        .map_err(map_internal_error)?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Refresh the materialized views the source depends on, and purge its cached tiles on completion
+#[route("/_/refresh/{source_id}", method = "POST")]
+async fn post_refresh(
+    req: HttpRequest,
+    path: Path<SourceRequest>,
+    admin: Option<Data<AdminConfig>>,
+    scheduler: Data<Scheduler>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let id = &path.source_id;
+    let sources = scheduler.sources.load_full();
+    let src = sources.get_source(id)?;
+    if !src.support_refresh() {
+        return Err(ErrorBadRequest(format!(
+            "Source {id} has no materialized views to refresh"
+        )));
+    }
+    info!("Refreshing source {id} using the admin API");
+    scheduler
+        .refresh_source(src)
+        .await
+        .map_err(map_internal_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Report the bandwidth limit and the tile quota usage of all API keys for the current month
+#[route("/_/quotas", method = "GET")]
+async fn get_quotas(
+    req: HttpRequest,
+    admin: Option<Data<AdminConfig>>,
+    throttle: Data<Throttle>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    Ok(HttpResponse::Ok().json(throttle.report()))
+}
+
+#[derive(Deserialize)]
+struct SourceRequest {
+    source_id: String,
+}
+
+/// Rebuild the tile part of the catalog after the sources have changed, and record a new catalog version
+pub(crate) fn update_catalog(sources: &ArcSwap<TileSources>, catalog: &ArcSwap<Catalog>) {
+    let sources = sources.load();
+    let tiles = sources.get_catalog();
+    catalog.rcu(|current| {
+        let mut changes = current.changes.clone();
+        changes.update(&sources, &tiles);
+        Catalog {
+            tiles: tiles.clone(),
+            changes,
+            ..Catalog::clone(current)
+        }
+    });
+}
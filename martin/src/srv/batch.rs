@@ -0,0 +1,256 @@
+use std::time::Instant;
+
+use actix_web::body::to_bytes;
+use actix_web::error::{ErrorBadRequest, ErrorGatewayTimeout};
+use actix_web::http::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{route, HttpMessage as _, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use futures::{stream, StreamExt as _};
+use ring::rand::{SecureRandom as _, SystemRandom};
+use serde::Deserialize;
+
+use crate::source::TileSources;
+use crate::srv::server::{map_internal_error, resolve_sources};
+use crate::srv::throttle::TileQuota;
+use crate::srv::{get_request_claims, get_tile_response, TileOptions, TileRequest};
+
+/// Maximum number of tiles of a single batch request
+pub const BATCH_MAX_TILES: usize = 1000;
+/// Number of tiles of a batch request that are fetched at the same time
+const BATCH_CONCURRENCY: usize = 16;
+/// Header of each part with the HTTP status the tile would have been served with
+pub const BATCH_STATUS_HEADER: &str = "X-Tile-Status";
+
+#[derive(Deserialize)]
+struct BatchPath {
+    source_ids: String,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    /// Tiles in the `z/x/y` form
+    tiles: Vec<String>,
+}
+
+/// A tile of the batch response, or the error it failed with
+struct BatchPart {
+    location: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    data: Vec<u8>,
+}
+
+/// Serve many tiles of the same sources in a single `multipart/mixed` response, in the requested order.
+/// Each part has the status, the headers, and the body the tile would have been served with.
+#[route("/{source_ids}/tiles", method = "POST")]
+async fn post_tiles(
+    req: HttpRequest,
+    path: Path<BatchPath>,
+    body: Json<BatchRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    let (source_ids, _) = resolve_sources(&req, &sources, &path.source_ids)?;
+    let source_ids = &source_ids;
+    let tiles = body.into_inner().tiles;
+    if tiles.len() > BATCH_MAX_TILES {
+        return Err(ErrorBadRequest(format!(
+            "A batch may contain at most {BATCH_MAX_TILES} tiles, but {} were requested",
+            tiles.len()
+        )));
+    }
+    let requests = tiles
+        .iter()
+        .map(|tile| parse_tile_request(&path.source_ids, tile))
+        .collect::<ActixResult<Vec<_>>>()?;
+
+    let query = req.query_string();
+    let encodings = req.get_header();
+    let claims = get_request_claims(&req, options.jwt.as_ref())?;
+    // each tile is counted on its own, so that only the parts above the quota fail
+    let quota = TileQuota::of(&req);
+
+    let parts = stream::iter(requests)
+        .map(|tile| {
+            let (sources, options, quota) = (&sources, &options, &quota);
+            let claims = claims.as_ref();
+            let encodings = encodings.clone();
+            async move {
+                let location = format!("/{}/{}/{}/{}", tile.source_ids, tile.z, tile.x, tile.y);
+                if let Some(Err(e)) = quota.as_ref().map(|v| v.try_use(tile.z, 1)) {
+                    return BatchPart::from_error(location, &e.into());
+                }
+                let start = Instant::now();
+                let response = async {
+                    let xyz = tile.tile_coord()?;
+                    if let Some(traffic) = &options.traffic {
+                        traffic.record(&location, query);
+                    }
+                    let response = get_tile_response(
+                        sources, options, xyz, source_ids, query, claims, encodings,
+                    );
+                    if let Some(timeout) = options.request_timeout {
+                        tokio::time::timeout(timeout, response)
+                            .await
+                            .map_err(|_| ErrorGatewayTimeout("Tile request timed out"))?
+                    } else {
+                        response.await
+                    }
+                };
+                match response.await {
+                    Ok(response) => {
+                        let part = BatchPart::from_response(location, response).await;
+                        if let Some(usage) = &options.usage {
+                            usage.record(source_ids, part.data.len(), start.elapsed());
+                        }
+                        part
+                    }
+                    Err(e) => BatchPart::from_error(location, &e),
+                }
+            }
+        })
+        .buffered(BATCH_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut boundary = [0_u8; 16];
+    SystemRandom::new()
+        .fill(&mut boundary)
+        .map_err(|_| map_internal_error("Unable to generate a multipart boundary"))?;
+    let boundary = hex::encode(boundary);
+    Ok(HttpResponse::Ok()
+        .content_type(format!("multipart/mixed; boundary={boundary}"))
+        .body(write_multipart(&boundary, &parts)))
+}
+
+/// Parse a tile of the request body in the `z/x/y` form. Tiles that cannot exist
+/// are rejected later, so that only their part of the response fails.
+fn parse_tile_request(source_ids: &str, tile: &str) -> ActixResult<TileRequest> {
+    let parse = || {
+        let mut parts = tile.split('/');
+        let z = parts.next()?.parse().ok()?;
+        let x = parts.next()?.parse().ok()?;
+        let y = parts.next()?.parse().ok()?;
+        parts.next().is_none().then_some(TileRequest {
+            source_ids: source_ids.to_string(),
+            z,
+            x,
+            y,
+        })
+    };
+    parse().ok_or_else(|| ErrorBadRequest(format!("{tile} is not a valid z/x/y tile")))
+}
+
+impl BatchPart {
+    async fn from_response(location: String, response: HttpResponse) -> Self {
+        let status = response.status();
+        let headers = response.headers().clone();
+        match to_bytes(response.into_body()).await {
+            Ok(data) => Self {
+                location,
+                status,
+                headers,
+                data: data.to_vec(),
+            },
+            Err(e) => Self::from_error(location, &map_internal_error(e)),
+        }
+    }
+
+    fn from_error(location: String, error: &actix_web::Error) -> Self {
+        let response = error.error_response();
+        let mut headers = HeaderMap::new();
+        if let Some(value) = response.headers().get(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, value.clone());
+        }
+        Self {
+            location,
+            status: response.status(),
+            headers,
+            data: error.to_string().into_bytes(),
+        }
+    }
+}
+
+/// Write the parts of a `multipart/mixed` body. Each part has a `Content-Location` with the tile path,
+/// the status of the tile, the headers of its response, and a `Content-Length`.
+fn write_multipart(boundary: &str, parts: &[BatchPart]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        body.extend_from_slice(format!("Content-Location: {}\r\n", part.location).as_bytes());
+        body.extend_from_slice(
+            format!("{BATCH_STATUS_HEADER}: {}\r\n", part.status.as_u16()).as_bytes(),
+        );
+        for (name, value) in &part.headers {
+            if name != CONTENT_LENGTH {
+                body.extend_from_slice(name.as_str().as_bytes());
+                body.extend_from_slice(b": ");
+                body.extend_from_slice(value.as_bytes());
+                body.extend_from_slice(b"\r\n");
+            }
+        }
+        body.extend_from_slice(format!("Content-Length: {}\r\n\r\n", part.data.len()).as_bytes());
+        body.extend_from_slice(&part.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn tile_requests() {
+        let tile = parse_tile_request("a,b", "3/2/1").unwrap();
+        assert_eq!((tile.z, tile.x, tile.y), (3, 2, 1));
+        assert_eq!(tile.source_ids, "a,b");
+        assert!(parse_tile_request("a", "3/2").is_err());
+        assert!(parse_tile_request("a", "3/2/1/0").is_err());
+        assert!(parse_tile_request("a", "3/x/1").is_err());
+        // tiles outside of the grid only fail their own part
+        assert!(parse_tile_request("a", "1/5/5").is_ok());
+    }
+
+    #[test]
+    fn multipart_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let parts = [
+            BatchPart {
+                location: "/src/0/0/0".to_string(),
+                status: StatusCode::OK,
+                headers,
+                data: b"PNG".to_vec(),
+            },
+            BatchPart {
+                location: "/src/1/0/0".to_string(),
+                status: StatusCode::NO_CONTENT,
+                headers: HeaderMap::new(),
+                data: Vec::new(),
+            },
+        ];
+        let body = write_multipart("b0und", &parts);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--b0und\r\n\
+             Content-Location: /src/0/0/0\r\n\
+             X-Tile-Status: 200\r\n\
+             content-type: image/png\r\n\
+             Content-Length: 3\r\n\r\n\
+             PNG\r\n\
+             --b0und\r\n\
+             Content-Location: /src/1/0/0\r\n\
+             X-Tile-Status: 204\r\n\
+             Content-Length: 0\r\n\r\n\
+             \r\n\
+             --b0und--\r\n"
+        );
+    }
+}
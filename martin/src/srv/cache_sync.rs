@@ -0,0 +1,223 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use futures::StreamExt as _;
+use log::{debug, info, warn};
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileSources;
+use crate::srv::config::{CacheSyncConfig, CACHE_SYNC_CHANNEL_DEFAULT};
+use crate::srv::{CdnPurger, HostCache, SharedCache};
+use crate::utils::OptMainCache;
+use crate::{MartinError, MartinResult};
+
+/// How long to wait before re-subscribing after the pub/sub connection was lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PurgeMessage {
+    /// ID of the instance that sent the message, used to ignore our own messages
+    origin: String,
+    sources: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PurgeChannel {
+    client: redis::Client,
+    channel: String,
+    instance_id: String,
+}
+
+/// Removes tiles from the cache, and notifies the other Martin instances to do the same.
+#[derive(Clone, Default)]
+pub struct CachePurger {
+    cache: OptMainCache,
+    sync: Option<PurgeChannel>,
+    shared_cache: Option<SharedCache>,
+    host_cache: Option<HostCache>,
+    cdn: Option<CdnPurger>,
+    /// Used to expand the composite and variant sources into their members
+    sources: Option<Data<ArcSwap<TileSources>>>,
+}
+
+impl CachePurger {
+    pub fn new(cache: OptMainCache, config: Option<&CacheSyncConfig>) -> MartinResult<Self> {
+        let sync = if let Some(cfg) = config {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            Some(PurgeChannel {
+                client: redis::Client::open(cfg.redis_url.as_str())?,
+                channel: cfg
+                    .channel
+                    .clone()
+                    .unwrap_or_else(|| CACHE_SYNC_CHANNEL_DEFAULT.to_string()),
+                instance_id: format!("{}-{nanos}", std::process::id()),
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            cache,
+            sync,
+            shared_cache: None,
+            host_cache: None,
+            cdn: None,
+            sources: None,
+        })
+    }
+
+    /// Also purge the tiles from the shared cache. Only the instance receiving the purge request
+    /// removes them from Redis, the others just drop their in-memory copies.
+    #[must_use]
+    pub fn with_shared_cache(mut self, shared_cache: Option<SharedCache>) -> Self {
+        self.shared_cache = shared_cache;
+        self
+    }
+
+    /// Also purge the tiles from the host cache. Every instance does it, because each host has its own cache file.
+    #[must_use]
+    pub fn with_host_cache(mut self, host_cache: Option<HostCache>) -> Self {
+        self.host_cache = host_cache;
+        self
+    }
+
+    /// Also purge the tiles from the CDN. Like the shared cache, only the instance receiving the purge request does it.
+    #[must_use]
+    pub fn with_cdn(mut self, cdn: Option<CdnPurger>) -> Self {
+        self.cdn = cdn;
+        self
+    }
+
+    /// Also purge the member sources of the purged composite and variant sources
+    #[must_use]
+    pub fn with_sources(mut self, sources: Data<ArcSwap<TileSources>>) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    #[must_use]
+    pub fn is_synced(&self) -> bool {
+        self.sync.is_some()
+    }
+
+    /// Purge all cached tiles of the given sources, here and in all other instances.
+    /// Composite and variant sources are purged along with all of their member sources.
+    pub async fn purge(&self, source_ids: Vec<String>) -> MartinResult<()> {
+        let source_ids = self.expand(source_ids);
+        self.purge_local(&source_ids)?;
+        if let Some(shared_cache) = &self.shared_cache {
+            shared_cache.purge(&source_ids).await?;
+        }
+        if let Some(sync) = &self.sync {
+            let msg = PurgeMessage {
+                origin: sync.instance_id.clone(),
+                sources: source_ids.clone(),
+            };
+            let payload =
+                serde_json::to_string(&msg).map_err(|e| MartinError::InternalError(e.into()))?;
+            let mut conn = sync.client.get_multiplexed_tokio_connection().await?;
+            conn.publish::<_, _, ()>(&sync.channel, payload).await?;
+        }
+        if let Some(cdn) = &self.cdn {
+            cdn.purge(&source_ids).await?;
+        }
+        Ok(())
+    }
+
+    /// Add the members of the composite and variant sources to the source IDs
+    fn expand(&self, source_ids: Vec<String>) -> Vec<String> {
+        let Some(sources) = &self.sources else {
+            return source_ids;
+        };
+        sources
+            .load()
+            .member_ids(&source_ids.join(","))
+            .split(',')
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    fn purge_local(&self, source_ids: &[String]) -> MartinResult<()> {
+        if let Some(cache) = &self.cache {
+            info!("Purging cached tiles of {}", source_ids.join(","));
+            let ids = source_ids.to_vec();
+            cache
+                .invalidate_entries_if(move |key, _| ids.iter().any(|id| key.uses_source(id)))
+                .map_err(|e| MartinError::InternalError(e.into()))?;
+        }
+        if let Some(host_cache) = &self.host_cache {
+            host_cache.purge(source_ids)?;
+        }
+        Ok(())
+    }
+
+    /// Listen for purge requests from other instances, re-subscribing if the connection is lost.
+    /// Returns immediately if cache synchronization is not configured.
+    pub async fn listen(self) {
+        let Some(sync) = &self.sync else {
+            return;
+        };
+        loop {
+            if let Err(e) = self.subscribe(sync).await {
+                warn!(
+                    "Cache purge subscription to {} failed: {e}. Retrying in {}s",
+                    sync.channel,
+                    RESUBSCRIBE_DELAY.as_secs()
+                );
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn subscribe(&self, sync: &PurgeChannel) -> MartinResult<()> {
+        let mut pubsub = sync.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(&sync.channel).await?;
+        info!("Listening for cache purge requests on {}", sync.channel);
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = msg.get_payload()?;
+            match serde_json::from_str::<PurgeMessage>(&payload) {
+                Ok(msg) if msg.origin == sync.instance_id => {}
+                Ok(msg) => {
+                    debug!("Received cache purge from {}", msg.origin);
+                    // the composites and variants of this instance may differ from those of the sender
+                    self.purge_local(&self.expand(msg.sources))?;
+                }
+                Err(e) => warn!("Ignoring invalid cache purge message {payload}: {e}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{new_main_cache, CacheKey, CacheValue, TileExpiration};
+    use crate::TileCoord;
+
+    #[actix_rt::test]
+    async fn purge_local_only() {
+        let cache = new_main_cache(1, TileExpiration::default()).unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let key_a = CacheKey::tile("a", xyz, None);
+        let key_b = CacheKey::tile("b", xyz, None);
+        cache.insert(key_a.clone(), CacheValue::Tile(vec![1])).await;
+        cache.insert(key_b.clone(), CacheValue::Tile(vec![2])).await;
+        let key_ab = CacheKey::GeoJson("b,a".to_string(), xyz, None);
+        cache
+            .insert(key_ab.clone(), CacheValue::Tile(vec![3]))
+            .await;
+
+        let purger = CachePurger::new(Some(cache.clone()), None).unwrap();
+        assert!(!purger.is_synced());
+        purger.purge(vec!["a".to_string()]).await.unwrap();
+        assert!(cache.get(&key_a).await.is_none());
+        assert!(cache.get(&key_ab).await.is_none());
+        assert!(cache.get(&key_b).await.is_some());
+    }
+}
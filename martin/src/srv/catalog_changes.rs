@@ -0,0 +1,254 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use actix_web::error::ErrorGone;
+use actix_web::web::{Data, Query};
+use actix_web::{route, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+
+use crate::source::{TileCatalog, TileSources};
+use crate::srv::Catalog;
+
+/// Number of individual source changes kept for `/_/catalog/changes`.
+/// Clients that fall further behind must read the whole catalog again.
+pub const CATALOG_CHANGES_MAX: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceChange {
+    version: u64,
+    id: String,
+    kind: ChangeKind,
+}
+
+/// Versions of the tile catalog. The version is incremented every time
+/// the catalog is updated with at least one added, removed or modified source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CatalogChanges {
+    version: u64,
+    /// A hash of the catalog entry and the `TileJSON` of each source, to detect modified sources
+    fingerprints: BTreeMap<String, u64>,
+    changes: VecDeque<SourceChange>,
+}
+
+/// Sources that changed since the requested version, with each source listed only once
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogDiff {
+    pub version: u64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl CatalogChanges {
+    #[must_use]
+    pub fn new(sources: &TileSources, tiles: &TileCatalog) -> Self {
+        Self {
+            fingerprints: fingerprints(sources, tiles),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Record the differences between the previous catalog and the current one
+    pub fn update(&mut self, sources: &TileSources, tiles: &TileCatalog) {
+        let new = fingerprints(sources, tiles);
+        let version = self.version + 1;
+        let mut changed = false;
+        for (id, hash) in &new {
+            let kind = match self.fingerprints.get(id) {
+                None => ChangeKind::Added,
+                Some(old) if old != hash => ChangeKind::Modified,
+                Some(_) => continue,
+            };
+            self.push(version, id, kind);
+            changed = true;
+        }
+        let old = std::mem::replace(&mut self.fingerprints, new);
+        for id in old.into_keys() {
+            if !self.fingerprints.contains_key(&id) {
+                self.push(version, &id, ChangeKind::Removed);
+                changed = true;
+            }
+        }
+        if changed {
+            self.version = version;
+        }
+    }
+
+    fn push(&mut self, version: u64, id: &str, kind: ChangeKind) {
+        if self.changes.len() == CATALOG_CHANGES_MAX {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(SourceChange {
+            version,
+            id: id.to_string(),
+            kind,
+        });
+    }
+
+    /// Combine all changes after the `since` version. Returns `None` if that version is unknown,
+    /// either because the changes have been discarded, or because the server was restarted.
+    #[must_use]
+    pub fn diff(&self, since: u64) -> Option<CatalogDiff> {
+        if since > self.version {
+            return None;
+        }
+        // The oldest kept version may be incomplete, so only the changes after it can be combined
+        if self.changes.len() == CATALOG_CHANGES_MAX
+            && self.changes.front().map_or(false, |c| since < c.version)
+        {
+            return None;
+        }
+
+        // The first and the last change of each source decide if it existed before, and if it exists now
+        let mut sources: BTreeMap<&str, (ChangeKind, ChangeKind)> = BTreeMap::new();
+        for change in self.changes.iter().filter(|c| c.version > since) {
+            sources
+                .entry(change.id.as_str())
+                .and_modify(|(_, last)| *last = change.kind)
+                .or_insert((change.kind, change.kind));
+        }
+
+        let mut diff = CatalogDiff {
+            version: self.version,
+            ..CatalogDiff::default()
+        };
+        for (id, (first, last)) in sources {
+            let existed = first != ChangeKind::Added;
+            let exists = last != ChangeKind::Removed;
+            match (existed, exists) {
+                (false, true) => diff.added.push(id.to_string()),
+                (true, false) => diff.removed.push(id.to_string()),
+                (true, true) => diff.modified.push(id.to_string()),
+                (false, false) => {}
+            }
+        }
+        Some(diff)
+    }
+}
+
+fn fingerprints(sources: &TileSources, tiles: &TileCatalog) -> BTreeMap<String, u64> {
+    tiles
+        .iter()
+        .map(|(id, entry)| {
+            let mut hasher = DefaultHasher::new();
+            serde_json::to_string(entry).ok().hash(&mut hasher);
+            // Composite sources change when any of their members change
+            if let Ok((members, _, _)) = sources.get_sources(id, None) {
+                for src in members {
+                    serde_json::to_string(src.get_tilejson())
+                        .ok()
+                        .hash(&mut hasher);
+                }
+            }
+            (id.clone(), hasher.finish())
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ChangesRequest {
+    #[serde(default)]
+    since: u64,
+}
+
+/// List the sources that were added, removed, or modified since the given catalog version
+#[route("/_/catalog/changes", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_catalog_changes(
+    query: Query<ChangesRequest>,
+    catalog: Data<ArcSwap<Catalog>>,
+) -> ActixResult<HttpResponse> {
+    let catalog = catalog.load();
+    let diff = catalog.changes.diff(query.since).ok_or_else(|| {
+        ErrorGone(format!(
+            "Catalog version {} is not available, the current version is {}. Read the whole catalog again.",
+            query.since,
+            catalog.changes.version()
+        ))
+    })?;
+    Ok(HttpResponse::Ok().json(diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CatalogSourceEntry;
+
+    fn catalog(entries: &[(&str, &str)]) -> TileCatalog {
+        entries
+            .iter()
+            .map(|(id, name)| {
+                let entry = CatalogSourceEntry {
+                    name: Some((*name).to_string()),
+                    ..CatalogSourceEntry::default()
+                };
+                ((*id).to_string(), entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn combine_changes() {
+        let sources = TileSources::default();
+        let mut changes = CatalogChanges::new(&sources, &catalog(&[("a", "A"), ("b", "B")]));
+        assert_eq!(changes.version(), 0);
+        assert_eq!(changes.diff(0), Some(CatalogDiff::default()));
+
+        changes.update(&sources, &catalog(&[("a", "A2"), ("c", "C")]));
+        changes.update(&sources, &catalog(&[("a", "A2"), ("c", "C")]));
+        assert_eq!(changes.version(), 1);
+        changes.update(&sources, &catalog(&[("a", "A2"), ("b", "B"), ("d", "D")]));
+        assert_eq!(changes.version(), 2);
+
+        let diff = changes.diff(0).unwrap();
+        assert_eq!(diff.version, 2);
+        assert_eq!(diff.added, vec!["d"]);
+        assert_eq!(diff.removed, Vec::<String>::new());
+        // b was removed and added back, c was added and removed again
+        assert_eq!(diff.modified, vec!["a", "b"]);
+
+        let diff = changes.diff(1).unwrap();
+        assert_eq!(diff.added, vec!["b", "d"]);
+        assert_eq!(diff.removed, vec!["c"]);
+        assert!(diff.modified.is_empty());
+
+        assert_eq!(changes.diff(2).unwrap().added.len(), 0);
+        assert_eq!(changes.diff(3), None);
+    }
+
+    #[test]
+    fn discard_old_changes() {
+        let sources = TileSources::default();
+        let mut changes = CatalogChanges::new(&sources, &TileCatalog::new());
+        for idx in 0..=CATALOG_CHANGES_MAX {
+            let id = format!("s{idx}");
+            changes.update(&sources, &catalog(&[(id.as_str(), "")]));
+        }
+        // Each update after the first one removes a source and adds another
+        let last = changes.version();
+        assert_eq!(last, CATALOG_CHANGES_MAX as u64 + 1);
+        assert!(changes.diff(0).is_none());
+        assert!(changes.diff(last - 500).is_none());
+        // The sources that were added and removed again in between are not listed
+        let diff = changes.diff(last - 499).unwrap();
+        assert_eq!(diff.added, vec![format!("s{CATALOG_CHANGES_MAX}")]);
+        assert_eq!(
+            diff.removed,
+            vec![format!("s{}", CATALOG_CHANGES_MAX - 499)]
+        );
+    }
+}
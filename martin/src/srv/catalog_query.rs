@@ -0,0 +1,121 @@
+use martin_tile_utils::Format;
+use serde::Deserialize;
+
+use crate::source::{CatalogSourceEntry, TileCatalog};
+
+/// Header with the number of tile sources matching the catalog query, before the pagination
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+/// Query parameters of `/catalog`, selecting a page of the matching tile sources
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct CatalogQuery {
+    /// Kind of the tile sources: `vector`, `raster`, a format like `png`, or a content type
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Case-insensitive text contained in the ID, the name, or the description of the sources
+    pub q: Option<String>,
+    /// Maximum number of tile sources to return
+    pub limit: Option<usize>,
+    /// Number of matching tile sources to skip, in the order of their IDs
+    pub offset: Option<usize>,
+}
+
+impl CatalogQuery {
+    /// Check if the whole tile catalog is requested
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.kind.is_none() && self.q.is_none() && self.limit.is_none() && self.offset.is_none()
+    }
+
+    /// Get the requested page of the matching tile sources, and the total number of matching sources
+    #[must_use]
+    pub fn apply(&self, catalog: &TileCatalog) -> (TileCatalog, usize) {
+        let text = self.q.as_ref().map(|v| v.to_lowercase());
+        let matching = catalog.iter().filter(|(id, entry)| {
+            self.kind.as_ref().map_or(true, |v| is_kind(entry, v))
+                && text.as_ref().map_or(true, |v| contains_text(id, entry, v))
+        });
+        let total = matching.clone().count();
+        let page = matching
+            .skip(self.offset.unwrap_or_default())
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect();
+        (page, total)
+    }
+}
+
+fn is_kind(entry: &CatalogSourceEntry, kind: &str) -> bool {
+    let content_type = entry.content_type.as_str();
+    match kind.to_ascii_lowercase().as_str() {
+        "vector" => content_type == Format::Mvt.content_type(),
+        "raster" => content_type.starts_with("image/"),
+        kind => match Format::parse(kind) {
+            Some(format) => content_type == format.content_type(),
+            None => content_type.eq_ignore_ascii_case(kind),
+        },
+    }
+}
+
+fn contains_text(id: &str, entry: &CatalogSourceEntry, text: &str) -> bool {
+    [
+        Some(id),
+        entry.name.as_deref(),
+        entry.description.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .any(|v| v.to_lowercase().contains(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use super::*;
+
+    fn entry(content_type: &str, name: &str) -> CatalogSourceEntry {
+        CatalogSourceEntry {
+            content_type: content_type.to_string(),
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn ids(query: &str) -> (Vec<String>, usize) {
+        let catalog = TileCatalog::from([
+            (
+                "roads".to_string(),
+                entry("application/x-protobuf", "Roads"),
+            ),
+            (
+                "rail".to_string(),
+                entry("application/x-protobuf", "Railways"),
+            ),
+            (
+                "ortho".to_string(),
+                entry("image/jpeg", "Orthophoto of the roads"),
+            ),
+            ("hillshade".to_string(), entry("image/png", "Hillshade")),
+        ]);
+        let query = Query::<CatalogQuery>::from_query(query).unwrap();
+        let (page, total) = query.apply(&catalog);
+        (page.into_keys().collect(), total)
+    }
+
+    #[test]
+    fn catalog_query() {
+        assert_eq!(ids("type=vector").0, vec!["rail", "roads"]);
+        assert_eq!(ids("type=raster").0, vec!["hillshade", "ortho"]);
+        assert_eq!(ids("type=png").0, vec!["hillshade"]);
+        assert_eq!(ids("type=image/jpeg").0, vec!["ortho"]);
+        assert_eq!(ids("q=ROAD").0, vec!["ortho", "roads"]);
+        assert_eq!(ids("q=road&type=vector").0, vec!["roads"]);
+        assert_eq!(
+            ids("limit=2"),
+            (vec!["hillshade".into(), "ortho".into()], 4)
+        );
+        assert_eq!(ids("limit=2&offset=3"), (vec!["roads".into()], 4));
+        assert_eq!(ids("type=raster&offset=5"), (vec![], 2));
+    }
+}
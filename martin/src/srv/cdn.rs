@@ -0,0 +1,410 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use log::info;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, RequestBuilder, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::s3::aws_post;
+use crate::MartinError::{CdnPurgeError, InvalidCdnConfig};
+use crate::{MartinResult, TileCoord};
+
+pub const CDN_TIMEOUT_SECS_DEFAULT: u64 = 10;
+pub const SURROGATE_KEY_HEADER_DEFAULT: &str = "Surrogate-Key";
+/// Header of the cache tags purged by the Cloudflare API
+const CLOUDFLARE_TAG_HEADER: &str = "Cache-Tag";
+/// Maximum number of keys in a single Fastly purge request
+const FASTLY_MAX_KEYS: usize = 256;
+/// Maximum number of tags in a single Cloudflare purge request
+const CLOUDFLARE_MAX_TAGS: usize = 30;
+const CLOUDFRONT_API: &str = "https://cloudfront.amazonaws.com/2020-05-31";
+/// `CloudFront` is a global service, its API is always signed for this region
+const CLOUDFRONT_REGION: &str = "us-east-1";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CdnConfig {
+    /// Keys of the tile responses with the `{id}`, `{z}`, `{x}`, and `{y}` placeholders, e.g. `src:{id}` and `z:{z}`.
+    /// The keys with `{id}` are repeated for each source of a composite tile.
+    pub surrogate_keys: Option<Vec<String>>,
+    /// Header of the keys [default: `Cache-Tag` when purging Cloudflare, `Surrogate-Key` otherwise]
+    pub surrogate_key_header: Option<String>,
+    /// Purge the tiles of the sources from the CDN whenever they are purged from the caches of Martin
+    pub purge: Option<CdnPurgeConfig>,
+    /// Maximum time in seconds to wait for each purge request [default: 10]
+    pub timeout_secs: Option<u64>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum CdnPurgeConfig {
+    /// Purge the surrogate keys of the sources with the Fastly API
+    Fastly {
+        service_id: String,
+        api_token: String,
+    },
+    /// Purge the cache tags of the sources with the Cloudflare API
+    Cloudflare { zone_id: String, api_token: String },
+    /// Invalidate the tile paths of the sources, e.g. `/src/*`, with the `CloudFront` API.
+    /// The requests are signed with the standard AWS environment variables.
+    Cloudfront {
+        distribution_id: String,
+        /// Path of Martin in the distribution, e.g. `/tiles`
+        path_prefix: Option<String>,
+    },
+}
+
+impl CdnConfig {
+    /// Make sure the header is valid, and that the sources can be purged by their keys
+    pub fn finalize(&self) -> MartinResult<()> {
+        let keys = SurrogateKeys::new(self)?;
+        match &self.purge {
+            Some(CdnPurgeConfig::Fastly { .. } | CdnPurgeConfig::Cloudflare { .. })
+                if keys.as_ref().map_or(true, |v| v.purge_keys("id").is_empty()) =>
+            {
+                Err(InvalidCdnConfig(
+                    "purging requires a surrogate key with {id} and without {z}, {x}, or {y}, e.g. src:{id}".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Header with the surrogate keys of the tile responses
+#[derive(Clone, Debug)]
+pub struct SurrogateKeys {
+    header: HeaderName,
+    templates: Arc<Vec<String>>,
+    separator: &'static str,
+}
+
+impl SurrogateKeys {
+    /// Get the header of the configured keys, if any
+    pub fn new(config: &CdnConfig) -> MartinResult<Option<Self>> {
+        let Some(templates) = config.surrogate_keys.clone().filter(|v| !v.is_empty()) else {
+            return Ok(None);
+        };
+        if let Some(key) = templates
+            .iter()
+            .find(|v| v.is_empty() || v.contains([' ', ',']))
+        {
+            return Err(InvalidCdnConfig(format!(
+                "surrogate key '{key}' must not be empty or contain spaces or commas"
+            )));
+        }
+        let default_header = if matches!(config.purge, Some(CdnPurgeConfig::Cloudflare { .. })) {
+            CLOUDFLARE_TAG_HEADER
+        } else {
+            SURROGATE_KEY_HEADER_DEFAULT
+        };
+        let name = config
+            .surrogate_key_header
+            .as_deref()
+            .unwrap_or(default_header);
+        let header = HeaderName::try_from(name)
+            .map_err(|e| InvalidCdnConfig(format!("header {name}: {e}")))?;
+        // Cloudflare expects comma-separated tags, Fastly and most others space-separated keys
+        let separator = if header.as_str().eq_ignore_ascii_case(CLOUDFLARE_TAG_HEADER) {
+            ","
+        } else {
+            " "
+        };
+        Ok(Some(Self {
+            header,
+            templates: Arc::new(templates),
+            separator,
+        }))
+    }
+
+    /// Header of a tile of one or more sources
+    #[must_use]
+    pub fn header(&self, source_ids: &[&str], xyz: TileCoord) -> Option<(HeaderName, HeaderValue)> {
+        let mut keys: Vec<String> = Vec::new();
+        for template in self.templates.iter() {
+            let key = template
+                .replace("{z}", &xyz.z.to_string())
+                .replace("{x}", &xyz.x.to_string())
+                .replace("{y}", &xyz.y.to_string());
+            if key.contains("{id}") {
+                for id in source_ids {
+                    keys.push(key.replace("{id}", id));
+                }
+            } else {
+                keys.push(key);
+            }
+        }
+        keys.dedup();
+        let value = HeaderValue::from_str(&keys.join(self.separator)).ok()?;
+        Some((self.header.clone(), value))
+    }
+
+    /// Keys shared by all tiles of a source, used to purge them
+    #[must_use]
+    pub fn purge_keys(&self, source_id: &str) -> Vec<String> {
+        self.templates
+            .iter()
+            .filter(|v| v.contains("{id}") && !["{z}", "{x}", "{y}"].iter().any(|p| v.contains(p)))
+            .map(|v| v.replace("{id}", source_id))
+            .collect()
+    }
+}
+
+/// Removes the tiles of the purged sources from the CDN
+#[derive(Clone, Debug)]
+pub struct CdnPurger {
+    client: Client,
+    config: CdnPurgeConfig,
+    keys: Option<SurrogateKeys>,
+}
+
+impl CdnPurger {
+    /// Create the purger if purging is configured. The config must have been finalized.
+    pub fn new(config: Option<&CdnConfig>) -> MartinResult<Option<Self>> {
+        let Some((config, purge)) = config.and_then(|c| Some((c, c.purge.clone()?))) else {
+            return Ok(None);
+        };
+        let client = Client::builder()
+            .timeout(Duration::from_secs(
+                config.timeout_secs.unwrap_or(CDN_TIMEOUT_SECS_DEFAULT),
+            ))
+            .build()
+            .map_err(|e| InvalidCdnConfig(e.to_string()))?;
+        Ok(Some(Self {
+            client,
+            config: purge,
+            keys: SurrogateKeys::new(config)?,
+        }))
+    }
+
+    /// Purge all tiles of the given sources from the CDN
+    pub async fn purge(&self, source_ids: &[String]) -> MartinResult<()> {
+        info!("Purging CDN cache of {}", source_ids.join(","));
+        for request in self.requests(source_ids)? {
+            request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| CdnPurgeError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn requests(&self, source_ids: &[String]) -> MartinResult<Vec<RequestBuilder>> {
+        let keys: Vec<String> = match &self.keys {
+            Some(keys) => source_ids.iter().flat_map(|v| keys.purge_keys(v)).collect(),
+            None => Vec::new(),
+        };
+        let requests = match &self.config {
+            CdnPurgeConfig::Fastly {
+                service_id,
+                api_token,
+            } => keys
+                .chunks(FASTLY_MAX_KEYS)
+                .map(|keys| {
+                    self.client
+                        .post(format!("https://api.fastly.com/service/{service_id}/purge"))
+                        .header("Fastly-Key", api_token)
+                        .header(SURROGATE_KEY_HEADER_DEFAULT, keys.join(" "))
+                })
+                .collect(),
+            CdnPurgeConfig::Cloudflare { zone_id, api_token } => keys
+                .chunks(CLOUDFLARE_MAX_TAGS)
+                .map(|tags| {
+                    self.client
+                        .post(format!(
+                            "https://api.cloudflare.com/client/v4/zones/{zone_id}/purge_cache"
+                        ))
+                        .bearer_auth(api_token)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(serde_json::json!({ "tags": tags }).to_string())
+                })
+                .collect(),
+            CdnPurgeConfig::Cloudfront {
+                distribution_id,
+                path_prefix,
+            } => {
+                let url = format!("{CLOUDFRONT_API}/distribution/{distribution_id}/invalidation");
+                let url = Url::parse(&url).map_err(|e| InvalidCdnConfig(e.to_string()))?;
+                let prefix = path_prefix.as_deref().unwrap_or_default();
+                let prefix = prefix.trim_end_matches('/');
+                let paths: Vec<_> = source_ids
+                    .iter()
+                    .map(|id| format!("{prefix}/{id}/*"))
+                    .collect();
+                let body = invalidation_batch(&paths);
+                let request = aws_post(&self.client, "cloudfront", CLOUDFRONT_REGION, &url, body)
+                    .header(CONTENT_TYPE, "application/xml");
+                vec![request]
+            }
+        };
+        Ok(requests)
+    }
+}
+
+/// Body of a `CloudFront` invalidation request
+fn invalidation_batch(paths: &[String]) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut items = String::new();
+    for path in paths {
+        let _ = write!(items, "<Path>{}</Path>", xml_escape(path));
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><InvalidationBatch xmlns="http://cloudfront.amazonaws.com/doc/2020-05-31/"><CallerReference>martin-{nanos}</CallerReference><Paths><Quantity>{}</Quantity><Items>{items}</Items></Paths></InvalidationBatch>"#,
+        paths.len()
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let cfg: CdnConfig = serde_yaml::from_str(indoc! {"
+            surrogate_keys: ['src:{id}', 'z:{z}']
+            purge:
+              provider: fastly
+              service_id: SU1Z0isxPaozGVKXdv0eY
+              api_token: secret
+        "})
+        .unwrap();
+        assert_eq!(
+            cfg.purge,
+            Some(CdnPurgeConfig::Fastly {
+                service_id: "SU1Z0isxPaozGVKXdv0eY".to_string(),
+                api_token: "secret".to_string(),
+            })
+        );
+        assert!(cfg.finalize().is_ok());
+
+        let cfg: CdnConfig = serde_yaml::from_str(indoc! {"
+            surrogate_keys: ['tile:{id}/{z}/{x}/{y}']
+            purge:
+              provider: cloudflare
+              zone_id: abc
+              api_token: secret
+        "})
+        .unwrap();
+        assert!(cfg.finalize().is_err());
+
+        let cfg: CdnConfig = serde_yaml::from_str(indoc! {"
+            purge:
+              provider: cloudfront
+              distribution_id: EDFDVBD6EXAMPLE
+        "})
+        .unwrap();
+        assert!(cfg.finalize().is_ok());
+
+        let cfg = CdnConfig {
+            surrogate_keys: Some(vec!["a b".to_string()]),
+            ..CdnConfig::default()
+        };
+        assert!(cfg.finalize().is_err());
+    }
+
+    #[test]
+    fn surrogate_keys() {
+        let mut cfg = CdnConfig {
+            surrogate_keys: Some(vec!["src:{id}".to_string(), "z:{z}".to_string()]),
+            ..CdnConfig::default()
+        };
+        let keys = SurrogateKeys::new(&cfg).unwrap().unwrap();
+        let xyz = TileCoord { z: 3, x: 1, y: 2 };
+        let (name, value) = keys.header(&["a", "b"], xyz).unwrap();
+        assert_eq!(name.as_str(), "surrogate-key");
+        assert_eq!(value, "src:a src:b z:3");
+        assert_eq!(keys.purge_keys("a"), vec!["src:a"]);
+
+        cfg.purge = Some(CdnPurgeConfig::Cloudflare {
+            zone_id: "zone".to_string(),
+            api_token: "secret".to_string(),
+        });
+        let keys = SurrogateKeys::new(&cfg).unwrap().unwrap();
+        let (name, value) = keys.header(&["a"], xyz).unwrap();
+        assert_eq!(name.as_str(), "cache-tag");
+        assert_eq!(value, "src:a,z:3");
+
+        assert!(SurrogateKeys::new(&CdnConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn purge_requests() {
+        let purger = |purge| {
+            CdnPurger::new(Some(&CdnConfig {
+                surrogate_keys: Some(vec!["src:{id}".to_string(), "z:{z}".to_string()]),
+                purge: Some(purge),
+                ..CdnConfig::default()
+            }))
+            .unwrap()
+            .unwrap()
+        };
+        let ids = ["a".to_string(), "b".to_string()];
+
+        let fastly = purger(CdnPurgeConfig::Fastly {
+            service_id: "svc".to_string(),
+            api_token: "secret".to_string(),
+        });
+        let req = fastly.requests(&ids).unwrap().remove(0).build().unwrap();
+        assert_eq!(
+            req.url().as_str(),
+            "https://api.fastly.com/service/svc/purge"
+        );
+        assert_eq!(req.headers()["fastly-key"], "secret");
+        assert_eq!(req.headers()["surrogate-key"], "src:a src:b");
+
+        let cloudflare = purger(CdnPurgeConfig::Cloudflare {
+            zone_id: "zone".to_string(),
+            api_token: "secret".to_string(),
+        });
+        let req = cloudflare
+            .requests(&ids)
+            .unwrap()
+            .remove(0)
+            .build()
+            .unwrap();
+        assert_eq!(
+            req.url().as_str(),
+            "https://api.cloudflare.com/client/v4/zones/zone/purge_cache"
+        );
+        assert_eq!(req.headers()["authorization"], "Bearer secret");
+        let body = req.body().and_then(reqwest::Body::as_bytes).unwrap();
+        assert_eq!(body, br#"{"tags":["src:a","src:b"]}"#);
+
+        let cloudfront = purger(CdnPurgeConfig::Cloudfront {
+            distribution_id: "DIST".to_string(),
+            path_prefix: Some("/tiles/".to_string()),
+        });
+        let req = cloudfront
+            .requests(&ids)
+            .unwrap()
+            .remove(0)
+            .build()
+            .unwrap();
+        assert_eq!(
+            req.url().as_str(),
+            "https://cloudfront.amazonaws.com/2020-05-31/distribution/DIST/invalidation"
+        );
+        let body = req.body().and_then(reqwest::Body::as_bytes).unwrap();
+        let body = std::str::from_utf8(body).unwrap();
+        assert!(body.contains(
+            "<Quantity>2</Quantity><Items><Path>/tiles/a/*</Path><Path>/tiles/b/*</Path></Items>"
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+pub const BROTLI_QUALITY_DEFAULT: u32 = 11;
+pub const GZIP_LEVEL_DEFAULT: u32 = 6;
+
+/// Compression levels of the tiles compressed by Martin for the clients
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Brotli quality from 0 (fastest) to 11 (smallest) [default: 11]
+    pub brotli: Option<u32>,
+    /// Gzip level from 0 (fastest) to 9 (smallest) [default: 6]
+    pub gzip: Option<u32>,
+    /// Levels of the tiles with the given content type, e.g. `application/x-protobuf`,
+    /// overriding the levels above
+    pub content_types: Option<BTreeMap<String, CompressionLevelsConfig>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionLevelsConfig {
+    pub brotli: Option<u32>,
+    pub gzip: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionLevels {
+    pub brotli: u32,
+    pub gzip: u32,
+}
+
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        Self {
+            brotli: BROTLI_QUALITY_DEFAULT,
+            gzip: GZIP_LEVEL_DEFAULT,
+        }
+    }
+}
+
+impl CompressionLevels {
+    /// Override the levels set by the config, keeping them in the supported range
+    fn with(self, brotli: Option<u32>, gzip: Option<u32>) -> Self {
+        Self {
+            brotli: brotli.map_or(self.brotli, |v| v.min(11)),
+            gzip: gzip.map_or(self.gzip, |v| v.min(9)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TileCompression {
+    levels: CompressionLevels,
+    content_types: Arc<BTreeMap<String, CompressionLevels>>,
+}
+
+impl TileCompression {
+    #[must_use]
+    pub fn new(config: &CompressionConfig) -> Self {
+        let levels = CompressionLevels::default().with(config.brotli, config.gzip);
+        let content_types = config
+            .content_types
+            .iter()
+            .flatten()
+            .map(|(typ, cfg)| (typ.clone(), levels.with(cfg.brotli, cfg.gzip)))
+            .collect();
+        Self {
+            levels,
+            content_types: Arc::new(content_types),
+        }
+    }
+
+    /// Get the compression levels of the tiles with the given content type
+    #[must_use]
+    pub fn levels(&self, content_type: &str) -> CompressionLevels {
+        self.content_types
+            .get(content_type)
+            .copied()
+            .unwrap_or(self.levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn content_type_levels() {
+        let config: CompressionConfig = serde_yaml::from_str(indoc! {"
+            brotli: 5
+            content_types:
+              application/x-protobuf:
+                brotli: 4
+              application/json:
+                gzip: 12
+        "})
+        .unwrap();
+        let compression = TileCompression::new(&config);
+        let levels = |brotli, gzip| CompressionLevels { brotli, gzip };
+        assert_eq!(compression.levels("image/png"), levels(5, 6));
+        assert_eq!(compression.levels("application/x-protobuf"), levels(4, 6));
+        // the levels are limited to the supported range
+        assert_eq!(compression.levels("application/json"), levels(5, 9));
+
+        let compression = TileCompression::new(&CompressionConfig::default());
+        assert_eq!(compression.levels("image/png"), levels(11, 6));
+    }
+}
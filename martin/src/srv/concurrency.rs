@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::utils::saturation::record_rejected_tile;
+use crate::MartinError::{InvalidConcurrencyLimit, SourceOverloaded};
+use crate::MartinResult;
+
+pub const MAX_QUEUED_DEFAULT: usize = 100;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConcurrencyLimitConfig {
+    /// Maximum number of tiles each source generates at the same time [default: unlimited]
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of tile requests waiting for each source, the others get `503 Service Unavailable` [default: 100]
+    pub max_queued: Option<usize>,
+    /// Limits of individual sources, keyed by source ID
+    pub sources: Option<BTreeMap<String, SourceConcurrencyLimitConfig>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SourceConcurrencyLimitConfig {
+    /// Maximum number of tiles the source generates at the same time [default: `max_concurrent` of all sources]
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of tile requests waiting for the source [default: `max_queued` of all sources]
+    pub max_queued: Option<usize>,
+}
+
+impl ConcurrencyLimitConfig {
+    /// Make sure that every limited source can generate at least one tile at a time
+    pub fn finalize(&self) -> MartinResult<()> {
+        if self.max_concurrent == Some(0) {
+            return Err(InvalidConcurrencyLimit("all sources".to_string()));
+        }
+        for (id, cfg) in self.sources.iter().flatten() {
+            if cfg.max_concurrent == Some(0) {
+                return Err(InvalidConcurrencyLimit(id.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Permits and waiting requests of a single source
+#[derive(Debug)]
+struct Limiter {
+    permits: Semaphore,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+impl Limiter {
+    fn new(max_concurrent: Option<usize>, max_queued: Option<usize>) -> Option<Arc<Self>> {
+        Some(Arc::new(Self {
+            permits: Semaphore::new(max_concurrent?),
+            queued: AtomicUsize::new(0),
+            max_queued: max_queued.unwrap_or(MAX_QUEUED_DEFAULT),
+        }))
+    }
+}
+
+/// Removes a request from the queue of its source once it got a permit, or was cancelled
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Limits of the concurrent tile fetches of each source, so that one expensive source cannot
+/// take all database connections or threads from the others
+#[derive(Clone, Debug, Default)]
+pub struct ConcurrencyLimits(Arc<Limits>);
+
+#[derive(Debug, Default)]
+struct Limits {
+    config: ConcurrencyLimitConfig,
+    /// Limiters of the sources that were used since the start, created on the first use
+    sources: Mutex<BTreeMap<String, Option<Arc<Limiter>>>>,
+}
+
+impl ConcurrencyLimits {
+    #[must_use]
+    pub fn new(config: Option<&ConcurrencyLimitConfig>) -> Self {
+        Self(Arc::new(Limits {
+            config: config.cloned().unwrap_or_default(),
+            sources: Mutex::default(),
+        }))
+    }
+
+    fn get(&self, source_id: &str) -> Option<Arc<Limiter>> {
+        let mut sources = self.0.sources.lock().unwrap();
+        sources
+            .entry(source_id.to_string())
+            .or_insert_with(|| {
+                let config = &self.0.config;
+                let source = config
+                    .sources
+                    .as_ref()
+                    .and_then(|v| v.get(source_id))
+                    .copied()
+                    .unwrap_or_default();
+                Limiter::new(
+                    source.max_concurrent.or(config.max_concurrent),
+                    source.max_queued.or(config.max_queued),
+                )
+            })
+            .clone()
+    }
+
+    /// Fetch a tile of the source once it has a free permit. If too many requests are already waiting
+    /// for the source, fail right away instead of waiting.
+    pub async fn run<T>(
+        &self,
+        source_id: &str,
+        fetch: impl Future<Output = MartinResult<T>>,
+    ) -> MartinResult<T> {
+        let Some(limiter) = self.get(source_id) else {
+            return fetch.await;
+        };
+        let _permit = if let Ok(permit) = limiter.permits.try_acquire() {
+            permit
+        } else {
+            if limiter.queued.fetch_add(1, Ordering::Relaxed) >= limiter.max_queued {
+                limiter.queued.fetch_sub(1, Ordering::Relaxed);
+                record_rejected_tile(source_id);
+                return Err(SourceOverloaded(source_id.to_string()));
+            }
+            let _queued = Queued(&limiter.queued);
+            // the semaphore is never closed
+            limiter
+                .permits
+                .acquire()
+                .await
+                .map_err(|_| SourceOverloaded(source_id.to_string()))?
+        };
+        fetch.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn limits() {
+        let limits = ConcurrencyLimits::new(Some(&ConcurrencyLimitConfig {
+            max_concurrent: None,
+            max_queued: Some(1),
+            sources: Some(BTreeMap::from([(
+                "slow".to_string(),
+                SourceConcurrencyLimitConfig {
+                    max_concurrent: Some(1),
+                    max_queued: None,
+                },
+            )])),
+        }));
+        assert!(limits.get("other").is_none());
+
+        let slow = || {
+            limits.run("slow", async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(1)
+            })
+        };
+        // one request is served, one waits, and the third one is rejected
+        let (a, b, c) = tokio::join!(slow(), slow(), slow());
+        assert_eq!(a.unwrap(), 1);
+        assert_eq!(b.unwrap(), 1);
+        assert!(matches!(c, Err(SourceOverloaded(id)) if id == "slow"));
+
+        // the queue is empty again
+        assert_eq!(slow().await.unwrap(), 1);
+        assert_eq!(
+            limits.get("slow").unwrap().queued.load(Ordering::Relaxed),
+            0
+        );
+        // other sources are not limited
+        let others = (0..10).map(|_| limits.run("other", async { Ok(()) }));
+        assert!(futures::future::try_join_all(others).await.is_ok());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let cfg = ConcurrencyLimitConfig {
+            max_concurrent: Some(0),
+            ..ConcurrencyLimitConfig::default()
+        };
+        assert!(cfg.finalize().is_err());
+        let cfg = ConcurrencyLimitConfig {
+            sources: Some(BTreeMap::from([(
+                "src".to_string(),
+                SourceConcurrencyLimitConfig {
+                    max_concurrent: Some(0),
+                    max_queued: None,
+                },
+            )])),
+            ..ConcurrencyLimitConfig::default()
+        };
+        assert!(cfg.finalize().is_err());
+        assert!(ConcurrencyLimitConfig::default().finalize().is_ok());
+    }
+}
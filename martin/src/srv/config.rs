@@ -1,14 +1,322 @@
+use std::collections::BTreeMap;
+
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
+use crate::aliases::{rename_ids, rename_keys, SourceAliases};
+use crate::srv::cdn::CdnConfig;
+use crate::srv::compression::CompressionConfig;
+use crate::srv::concurrency::ConcurrencyLimitConfig;
+use crate::srv::empty_tiles::EmptyTileConfig;
+use crate::srv::encryption::{validate_encryption, EncryptionConfig};
+use crate::srv::host_cache::HostCacheConfig;
+use crate::srv::ip_filter::IpFilterConfig;
+use crate::srv::jwt::{JwtConfig, JwtValidator};
+use crate::srv::prefetch::PrefetchConfig;
+use crate::srv::public_url::PublicUrl;
+use crate::srv::referer_filter::{validate_referer_filter, RefererRules};
+use crate::srv::scheduler::ScheduledTask;
+use crate::srv::shared_cache::SharedCacheConfig;
+use crate::srv::static_files::StaticFilesConfig;
+use crate::srv::tenants::{validate_tenants, TenantConfigs};
+use crate::srv::throttle::ThrottleConfig;
+use crate::srv::tile_size::TileSizeLimitConfig;
+use crate::srv::traffic::TrafficProfileConfig;
+use crate::srv::transcode::TranscodeConfig;
+use crate::srv::usage::UsageStatsConfig;
+use crate::srv::webhooks::WebhookConfig;
+use crate::utils::OptOneMany;
+use crate::MartinError::{
+    GrpcNotCompiled, InvalidGrpcConfig, InvalidH2Window, InvalidRedirectStatus,
+};
+use crate::MartinResult;
+
 pub const KEEP_ALIVE_DEFAULT: u64 = 75;
 pub const LISTEN_ADDRESSES_DEFAULT: &str = "0.0.0.0:3000";
+pub const REDIRECT_STATUS_DEFAULT: u16 = 301;
+pub const CACHE_SYNC_CHANNEL_DEFAULT: &str = "martin:purge";
+pub const MAX_VECTOR_LAYERS_DEFAULT: usize = 1000;
+pub const MAX_LAYER_FIELDS_DEFAULT: usize = 1000;
+pub const GRPC_STREAM_CONCURRENCY_DEFAULT: usize = 16;
+/// Largest HTTP/2 flow control window, 2^31-1 bytes, rounded down to kilobytes
+pub const H2_WINDOW_KB_MAX: u32 = 2_097_151;
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct SrvConfig {
     pub keep_alive: Option<u64>,
-    pub listen_addresses: Option<String>,
+    /// One or more socket addresses to bind, e.g. `0.0.0.0:3000` and `[::]:3000`
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub listen_addresses: OptOneMany<String>,
+    /// Socket addresses serving the `/_/` admin endpoints and `/metrics`, e.g. `127.0.0.1:9000`.
+    /// If set, these routes respond with 404 on the `listen_addresses`.
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub internal_listen_addresses: OptOneMany<String>,
     pub worker_processes: Option<usize>,
+    /// Maximum time in milliseconds to serve a tile request, after which 504 is returned
+    pub request_timeout_ms: Option<u64>,
+    /// Maximum time in milliseconds for each source to return a tile, after which 504 is returned
+    pub backend_timeout_ms: Option<u64>,
+    /// Maximum number of tiles each source generates at the same time, and of the requests waiting for it
+    pub concurrency_limit: Option<ConcurrencyLimitConfig>,
+    /// Maximum number of concurrent connections of each worker [default: 25000]
+    pub max_connections: Option<usize>,
+    /// Maximum number of connections waiting to be accepted [default: 1024]
+    pub backlog: Option<u32>,
+    /// Maximum time in milliseconds for a client to send the request headers, 0 to disable [default: 5000]
+    pub client_request_timeout_ms: Option<u64>,
+    /// Maximum time in milliseconds for a client to acknowledge the closing of its connection, 0 to disable [default: 0]
+    pub client_disconnect_timeout_ms: Option<u64>,
+    /// Seconds to keep serving after `SIGINT` or `SIGTERM` while `/health/ready` returns 503,
+    /// so that the load balancers stop routing to this instance before it stops [default: 0]
+    pub shutdown_drain_secs: Option<u64>,
+    /// Also accept HTTP/2 without TLS (h2c with prior knowledge) on the same listeners as HTTP/1.1
+    pub h2c: Option<bool>,
+    /// Initial HTTP/2 flow control window of each stream in kilobytes [default: 1024]
+    pub h2_stream_window_kb: Option<u32>,
+    /// Initial HTTP/2 flow control window of each connection in kilobytes [default: 2048]
+    pub h2_connection_window_kb: Option<u32>,
+    /// Maximum number of threads of each worker for the blocking tasks, e.g. file reads [default: 512 / workers]
+    pub max_blocking_threads: Option<usize>,
+    /// Maximum size in kilobytes of the request bodies, e.g. of the posted styles and admin requests
+    pub max_payload_kb: Option<usize>,
+    /// URL the clients use to reach this server, e.g. `https://example.com/tiles`, used for the URLs
+    /// in the `TileJSON` and the styles instead of the request and its forwarding headers
+    pub public_url: Option<String>,
+    /// Proxies allowed to set the `Forwarded`, `X-Forwarded-Proto/Host/Prefix`, and `X-Rewrite-URL` headers,
+    /// and to report the client address to the `ip_filter` with the `X-Forwarded-For` header.
+    /// The URL headers of all clients are used if this is not set, but `X-Forwarded-For` is ignored.
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    /// Redirect requests for old source IDs to the new ones, e.g. after a layer was renamed
+    pub redirects: Option<BTreeMap<String, RedirectConfig>>,
+    /// Admin API settings. The `/_/` admin endpoints are disabled unless this is set.
+    pub admin: Option<AdminConfig>,
+    /// Propagate cache purges to other Martin instances via a pub/sub channel
+    pub cache_sync: Option<CacheSyncConfig>,
+    /// Second level tile cache in Redis, shared by all Martin instances
+    pub shared_cache: Option<SharedCacheConfig>,
+    /// Tile cache in a file shared by all Martin processes on the same host, used before the Redis cache
+    pub host_cache: Option<HostCacheConfig>,
+    /// Allow or deny access based on the client IP address
+    pub ip_filter: Option<IpFilterConfig>,
+    /// Web apps allowed to embed the tiles and `TileJSON` of these sources, based on the `Origin`
+    /// and `Referer` headers, keyed by source ID
+    pub referer_filter: Option<BTreeMap<String, RefererRules>>,
+    /// Limit the total bandwidth, and the number of tiles per API key
+    pub throttle: Option<ThrottleConfig>,
+    /// Add `Link: rel=prefetch` headers for the sibling tiles to tile responses
+    pub prefetch_hints: Option<bool>,
+    /// Speculatively generate tiles around cache misses while the server is idle
+    pub prefetch: Option<PrefetchConfig>,
+    /// Tasks to run periodically in the background, e.g. source rediscovery or cache seeding
+    pub schedule: Option<Vec<ScheduledTask>>,
+    /// Watch the directories of file sources and sprites, and reload them when the files change
+    pub watch_files: Option<bool>,
+    /// Post a JSON event to these URLs when sources are added, removed, or refreshed
+    pub webhooks: Option<WebhookConfig>,
+    /// Surrogate keys of the tile responses, and the purging of the CDN when the tiles of a source are purged
+    pub cdn: Option<CdnConfig>,
+    /// Response to the empty tiles of these sources instead of `204 No Content`, keyed by source ID
+    pub empty_tiles: Option<BTreeMap<String, EmptyTileConfig>>,
+    /// Maximum size of the tiles, and the response to the larger ones, e.g. to surface tiles too large for the clients
+    pub tile_size_limit: Option<TileSizeLimitConfig>,
+    /// Transcode the PNG and JPEG tiles to the format preferred by the `Accept` header of the clients
+    pub transcode: Option<TranscodeConfig>,
+    /// Brotli and gzip levels of the tiles compressed for the clients, e.g. to trade the ratio for throughput
+    pub compression: Option<CompressionConfig>,
+    /// Encrypt the tiles of these sources, keyed by source ID
+    pub encryption: Option<BTreeMap<String, EncryptionConfig>>,
+    /// Validate the `Authorization: Bearer` tokens, and pass their claims to the function sources
+    pub jwt: Option<JwtConfig>,
+    /// Add a `Server-Timing` header to tile responses with the time spent in the sources and the encoding
+    pub server_timing: Option<bool>,
+    /// Count the requested tile paths, to be exported as a load test profile with the admin API
+    pub traffic_profile: Option<TrafficProfileConfig>,
+    /// Count the requests, bytes, and latencies of each source over time, reported by the admin API
+    pub usage_stats: Option<UsageStatsConfig>,
+    /// Customers whose sources are served under `/{tenant}/` with their own catalog, token, and CORS origins
+    pub tenants: Option<TenantConfigs>,
+    /// Limits of the `TileJSON` merged from many sources, e.g. `/src1,src2,...,srcN`
+    pub composite_tilejson: Option<CompositeTileJsonConfig>,
+    /// Serve a directory of static files, e.g. a map frontend, under its own path
+    pub static_files: Option<StaticFilesConfig>,
+    /// Serve the gRPC API on a separate port, requires Martin built with the `grpc` feature
+    pub grpc: Option<GrpcConfig>,
+    /// Serve the demo map at the root path, only set by `martin demo`
+    #[serde(skip)]
+    pub demo: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AdminConfig {
+    /// Token that must be sent as `Authorization: Bearer <token>` to use the admin API
+    pub token: String,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GrpcConfig {
+    /// Address of the gRPC API, e.g. `127.0.0.1:50051`
+    pub listen_addresses: String,
+    /// Number of tiles of each `GetTiles` stream fetched at the same time [default: 16]
+    pub stream_concurrency: Option<usize>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CacheSyncConfig {
+    /// Redis connection URL, e.g. `redis://localhost:6379`
+    pub redis_url: String,
+    /// Pub/sub channel name shared by all instances [default: `martin:purge`]
+    pub channel: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompositeTileJsonConfig {
+    /// Maximum number of distinct vector layers, the layers of the remaining sources are omitted [default: 1000]
+    pub max_vector_layers: Option<usize>,
+    /// Maximum number of fields of each merged vector layer [default: 1000]
+    pub max_layer_fields: Option<usize>,
+}
+
+impl CompositeTileJsonConfig {
+    #[must_use]
+    pub fn max_vector_layers(&self) -> usize {
+        self.max_vector_layers.unwrap_or(MAX_VECTOR_LAYERS_DEFAULT)
+    }
+
+    #[must_use]
+    pub fn max_layer_fields(&self) -> usize {
+        self.max_layer_fields.unwrap_or(MAX_LAYER_FIELDS_DEFAULT)
+    }
+}
+
+impl SrvConfig {
+    /// Validate the server configuration
+    pub fn finalize(&self) -> MartinResult<()> {
+        PublicUrl::new(self.public_url.as_deref(), self.trusted_proxies.as_ref())?;
+        for (from, redirect) in self.redirects.iter().flatten() {
+            let status = redirect.status();
+            if status != 301 && status != 308 {
+                return Err(InvalidRedirectStatus(status, from.clone()));
+            }
+        }
+        for task in self.schedule.iter().flatten() {
+            task.finalize()?;
+        }
+        let windows = [
+            ("h2_stream_window_kb", self.h2_stream_window_kb),
+            ("h2_connection_window_kb", self.h2_connection_window_kb),
+        ];
+        for (name, window) in windows {
+            if let Some(kb) = window.filter(|v| !(1..=H2_WINDOW_KB_MAX).contains(v)) {
+                return Err(InvalidH2Window(name, kb));
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            validate_encryption(encryption)?;
+        }
+        if let Some(referer_filter) = &self.referer_filter {
+            validate_referer_filter(referer_filter)?;
+        }
+        if let Some(jwt) = &self.jwt {
+            JwtValidator::new(jwt)?;
+        }
+        if let Some(tenants) = &self.tenants {
+            validate_tenants(tenants)?;
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.finalize()?;
+        }
+        if let Some(concurrency_limit) = &self.concurrency_limit {
+            concurrency_limit.finalize()?;
+        }
+        if let Some(cdn) = &self.cdn {
+            cdn.finalize()?;
+        }
+        if let Some(static_files) = &self.static_files {
+            static_files.finalize(self.tenants.as_ref())?;
+        }
+        if let Some(grpc) = &self.grpc {
+            if !cfg!(feature = "grpc") {
+                return Err(GrpcNotCompiled);
+            }
+            if grpc.stream_concurrency == Some(0) {
+                return Err(InvalidGrpcConfig("stream_concurrency must not be 0".into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the per-source settings of the renamed sources to their new IDs, so that the rules
+    /// of a source do not depend on the ID it was created with
+    pub fn rename_sources(&mut self, aliases: &SourceAliases) {
+        let ip_rules = self.ip_filter.as_mut().and_then(|v| v.sources.as_mut());
+        let concurrency = self.concurrency_limit.as_mut();
+        let tile_size = self.tile_size_limit.as_mut();
+        if let Some(rules) = ip_rules {
+            rename_keys(rules, aliases);
+        }
+        if let Some(limits) = concurrency.and_then(|v| v.sources.as_mut()) {
+            rename_keys(limits, aliases);
+        }
+        if let Some(limits) = tile_size.and_then(|v| v.sources.as_mut()) {
+            rename_keys(limits, aliases);
+        }
+        if let Some(rules) = &mut self.referer_filter {
+            rename_keys(rules, aliases);
+        }
+        if let Some(empty_tiles) = &mut self.empty_tiles {
+            rename_keys(empty_tiles, aliases);
+        }
+        if let Some(encryption) = &mut self.encryption {
+            rename_keys(encryption, aliases);
+        }
+        for tenant in self.tenants.iter_mut().flat_map(|v| v.values_mut()) {
+            rename_ids(&mut tenant.sources, aliases);
+        }
+        if let Some(ids) = self.transcode.as_mut().and_then(|v| v.sources.as_mut()) {
+            rename_ids(ids, aliases);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RedirectConfig {
+    /// Redirect to the given source ID using the default status code
+    Target(String),
+    Config(RedirectConfigObj),
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RedirectConfigObj {
+    /// New source ID
+    pub to: String,
+    /// HTTP status code to use, either 301 or 308. Defaults to 301.
+    pub status: Option<u16>,
+}
+
+impl RedirectConfig {
+    #[must_use]
+    pub fn target(&self) -> &str {
+        match self {
+            Self::Target(to) | Self::Config(RedirectConfigObj { to, .. }) => to,
+        }
+    }
+
+    #[must_use]
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Config(RedirectConfigObj {
+                status: Some(status),
+                ..
+            }) => *status,
+            _ => REDIRECT_STATUS_DEFAULT,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -16,7 +324,40 @@ mod tests {
     use indoc::indoc;
 
     use super::*;
-    use crate::test_utils::some;
+
+    #[test]
+    fn renamed_sources() {
+        let mut cfg: SrvConfig = serde_yaml::from_str(indoc! {"
+            ip_filter:
+              sources:
+                internal: { allow: [10.0.0.0/8] }
+            referer_filter:
+              internal: { allow: [app.example.com] }
+              private: { allow: [other.example.com] }
+            tile_size_limit:
+              sources:
+                internal: { max_kb: 100 }
+            empty_tiles:
+              internal: { mode: not_found }
+            tenants:
+              acme:
+                sources: [internal, public]
+        "})
+        .unwrap();
+        cfg.rename_sources(&SourceAliases::from([(
+            "internal".to_string(),
+            "private".to_string(),
+        )]));
+        let ip_rules = &cfg.ip_filter.unwrap().sources.unwrap();
+        assert_eq!(ip_rules["private"], ip_rules["internal"]);
+        // the settings of the new ID are kept
+        let referer = cfg.referer_filter.unwrap();
+        assert_eq!(referer["private"].allow, ["other.example.com"]);
+        let tile_size = cfg.tile_size_limit.unwrap().sources.unwrap();
+        assert_eq!(tile_size["private"].max_kb, Some(100));
+        assert!(cfg.empty_tiles.unwrap().contains_key("private"));
+        assert_eq!(cfg.tenants.unwrap()["acme"].sources, ["private", "public"]);
+    }
 
     #[test]
     fn parse_empty_config() {
@@ -29,9 +370,91 @@ mod tests {
             .unwrap(),
             SrvConfig {
                 keep_alive: Some(75),
-                listen_addresses: some("0.0.0.0:3000"),
+                listen_addresses: OptOneMany::One("0.0.0.0:3000".to_string()),
                 worker_processes: Some(8),
+                ..Default::default()
             }
         );
     }
+
+    #[test]
+    fn parse_listen_addresses() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            listen_addresses: ['0.0.0.0:3000', '[::]:3000']
+            internal_listen_addresses: '127.0.0.1:9000'
+        "})
+        .unwrap();
+        assert_eq!(
+            cfg.listen_addresses,
+            OptOneMany::Many(vec!["0.0.0.0:3000".to_string(), "[::]:3000".to_string()])
+        );
+        assert_eq!(
+            cfg.internal_listen_addresses,
+            OptOneMany::One("127.0.0.1:9000".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_runtime_options() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            max_connections: 50000
+            backlog: 4096
+            client_request_timeout_ms: 2000
+            max_blocking_threads: 64
+            max_payload_kb: 1024
+        "})
+        .unwrap();
+        assert_eq!(cfg.max_connections, Some(50000));
+        assert_eq!(cfg.backlog, Some(4096));
+        assert_eq!(cfg.client_request_timeout_ms, Some(2000));
+        assert_eq!(cfg.max_blocking_threads, Some(64));
+        assert_eq!(cfg.max_payload_kb, Some(1024));
+    }
+
+    #[test]
+    fn parse_h2_windows() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            h2c: true
+            h2_stream_window_kb: 4096
+            h2_connection_window_kb: 16384
+        "})
+        .unwrap();
+        assert_eq!(cfg.h2_stream_window_kb, Some(4096));
+        assert_eq!(cfg.h2_connection_window_kb, Some(16384));
+        assert!(cfg.finalize().is_ok());
+
+        let cfg = SrvConfig {
+            h2_stream_window_kb: Some(0),
+            ..SrvConfig::default()
+        };
+        assert!(matches!(
+            cfg.finalize(),
+            Err(InvalidH2Window("h2_stream_window_kb", 0))
+        ));
+        let cfg = SrvConfig {
+            h2_connection_window_kb: Some(H2_WINDOW_KB_MAX + 1),
+            ..SrvConfig::default()
+        };
+        assert!(cfg.finalize().is_err());
+    }
+
+    #[test]
+    fn parse_redirects() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            redirects:
+              old_roads: roads
+              old_water:
+                to: water
+                status: 308
+        "})
+        .unwrap();
+        let redirects = cfg.redirects.unwrap();
+        assert_eq!(
+            redirects["old_roads"],
+            RedirectConfig::Target("roads".to_string())
+        );
+        assert_eq!(redirects["old_roads"].status(), 301);
+        assert_eq!(redirects["old_water"].target(), "water");
+        assert_eq!(redirects["old_water"].status(), 308);
+    }
 }
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+
+use actix_web::error::{ErrorBadRequest, ErrorGatewayTimeout};
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use futures::{stream, StreamExt as _, TryStreamExt as _};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileSources;
+use crate::srv::server::{check_tile_access, map_internal_error, redirect_sources};
+use crate::srv::throttle::TileQuota;
+use crate::srv::{get_request_claims, get_tile_content, JwtClaims, SourceRedirects, TileOptions};
+use crate::terrain::{get_served_encoding, TerrainHeights};
+use crate::utils::mvt::{wgs84_to_mercator, MAX_LATITUDE, MERCATOR_MAX};
+use crate::utils::MAX_ZOOM;
+use crate::{TileCoord, TileMatrixSet};
+
+/// Maximum number of points of a single batch request
+pub const ELEVATION_MAX_POINTS: usize = 1000;
+/// Number of tiles of a batch request that are fetched at the same time
+const ELEVATION_CONCURRENCY: usize = 16;
+/// Zoom of the tiles used for the sources without a `maxzoom`
+const ELEVATION_ZOOM_DEFAULT: u8 = 14;
+
+#[derive(Deserialize)]
+struct ElevationPath {
+    source_id: String,
+}
+
+#[derive(Deserialize)]
+struct ElevationQuery {
+    lng: f64,
+    lat: f64,
+    /// Zoom of the tiles to read the height from [default: `maxzoom` of the source]
+    z: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct ElevationRequest {
+    /// Points in the `[lng, lat]` form
+    points: Vec<[f64; 2]>,
+    /// Zoom of the tiles to read the heights from [default: `maxzoom` of the source]
+    z: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PointElevation {
+    lng: f64,
+    lat: f64,
+    z: u8,
+    /// Height in meters, or `None` if the source has no data at the point
+    elevation: Option<f64>,
+}
+
+/// Height of a point, read from the tile of a raster DEM source containing it
+#[route("/{source_id}/elevation", method = "GET", method = "HEAD")]
+async fn get_elevation(
+    req: HttpRequest,
+    path: Path<ElevationPath>,
+    query: Query<ElevationQuery>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_id, &sources, redirects, 1) {
+        return Ok(resp);
+    }
+    check_tile_access(&req, &path.source_id, &sources.member_ids(&path.source_id))?;
+    let claims = get_request_claims(&req, options.jwt.as_ref())?;
+    let ElevationQuery { lng, lat, z } = query.into_inner();
+    let mut elevations = get_elevations(
+        &sources,
+        &options,
+        &path.source_id,
+        &[[lng, lat]],
+        z,
+        claims.as_ref(),
+        TileQuota::of(&req).as_ref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(elevations.swap_remove(0)))
+}
+
+/// Heights of many points of the same source, in the requested order.
+/// Each tile is fetched once, regardless of how many of the points it contains.
+#[route("/{source_id}/elevation", method = "POST")]
+async fn post_elevations(
+    req: HttpRequest,
+    path: Path<ElevationPath>,
+    body: Json<ElevationRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    check_tile_access(&req, &path.source_id, &sources.member_ids(&path.source_id))?;
+    let ElevationRequest { points, z } = body.into_inner();
+    if points.len() > ELEVATION_MAX_POINTS {
+        return Err(ErrorBadRequest(format!(
+            "A batch may contain at most {ELEVATION_MAX_POINTS} points, but {} were requested",
+            points.len()
+        )));
+    }
+    let claims = get_request_claims(&req, options.jwt.as_ref())?;
+    let elevations = get_elevations(
+        &sources,
+        &options,
+        &path.source_id,
+        &points,
+        z,
+        claims.as_ref(),
+        TileQuota::of(&req).as_ref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(elevations))
+}
+
+async fn get_elevations(
+    sources: &TileSources,
+    options: &TileOptions,
+    source_id: &str,
+    points: &[[f64; 2]],
+    zoom: Option<u8>,
+    claims: Option<&JwtClaims>,
+    quota: Option<&TileQuota>,
+) -> ActixResult<Vec<PointElevation>> {
+    let src = sources.get_source(source_id)?;
+    let encoding = get_served_encoding(src).ok_or_else(|| {
+        ErrorBadRequest(format!(
+            "Source {source_id} is not a raster DEM source with a terrain encoding"
+        ))
+    })?;
+    if options.encryption.is_encrypted(source_id) {
+        return Err(ErrorBadRequest(format!(
+            "Tiles of {source_id} are encrypted, and cannot be queried for elevations"
+        )));
+    }
+    if src.get_tile_matrix_set() != TileMatrixSet::WebMercatorQuad {
+        return Err(ErrorBadRequest(format!(
+            "Elevations can only be queried from the WebMercatorQuad tiles, but {source_id} has {} tiles",
+            src.get_tile_matrix_set()
+        )));
+    }
+    let z = zoom.unwrap_or_else(|| src.get_tilejson().maxzoom.unwrap_or(ELEVATION_ZOOM_DEFAULT));
+    if z > MAX_ZOOM || !src.is_valid_zoom(z) {
+        return Err(ErrorBadRequest(format!(
+            "Source {source_id} has no tiles at zoom {z}"
+        )));
+    }
+
+    let mut tiles: HashMap<TileCoord, Vec<(usize, f64, f64)>> = HashMap::new();
+    for (idx, &[lng, lat]) in points.iter().enumerate() {
+        let (xyz, x, y) = locate_point(lng, lat, z).ok_or_else(|| {
+            ErrorBadRequest(format!("{lng},{lat} is not a valid Web Mercator point"))
+        })?;
+        tiles.entry(xyz).or_default().push((idx, x, y));
+    }
+    if let Some(quota) = quota {
+        quota.try_use(z, u64::try_from(tiles.len()).unwrap_or(u64::MAX))?;
+    }
+
+    let info = src.get_tile_info();
+    let claims = claims.filter(|_| src.support_url_query());
+    let heights = stream::iter(tiles)
+        .map(|(xyz, positions)| async move {
+            let tile = get_tile_content(&[src], options, info, &xyz, None, claims, None).await?;
+            if tile.data.is_empty() {
+                return Ok(Vec::new());
+            }
+            let heights = TerrainHeights::decode(&tile.data, encoding, xyz, source_id)
+                .map_err(map_internal_error)?;
+            Ok::<_, actix_web::Error>(
+                positions
+                    .into_iter()
+                    .map(|(idx, x, y)| (idx, heights.get(x, y)))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .buffer_unordered(ELEVATION_CONCURRENCY)
+        .try_collect::<Vec<_>>();
+    let heights = if let Some(timeout) = options.request_timeout {
+        tokio::time::timeout(timeout, heights).await.map_err(|_| {
+            warn!("Request for the elevations of {source_id} timed out after {timeout:?}");
+            ErrorGatewayTimeout("Elevation request timed out")
+        })?
+    } else {
+        heights.await
+    }?;
+
+    let mut elevations: Vec<_> = points
+        .iter()
+        .map(|&[lng, lat]| PointElevation {
+            lng,
+            lat,
+            z,
+            elevation: None,
+        })
+        .collect();
+    for (idx, elevation) in heights.into_iter().flatten() {
+        elevations[idx].elevation = elevation;
+    }
+    Ok(elevations)
+}
+
+/// Get the Web Mercator tile containing the point, and the position of the point within it,
+/// from `0.0` at its top left corner to `1.0` at its bottom right corner
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn locate_point(lng: f64, lat: f64, z: u8) -> Option<(TileCoord, f64, f64)> {
+    if !(lng.abs() <= 180.0 && lat.abs() <= MAX_LATITUDE) {
+        return None;
+    }
+    let [x, y] = wgs84_to_mercator(lng, lat);
+    let tiles = f64::from(1_u32 << z);
+    // the points on the east and south edges of the grid are in the last tiles
+    let position = |v: f64| {
+        let v = (v * tiles).clamp(0.0, tiles);
+        let tile = v.floor().min(tiles - 1.0);
+        (tile as u32, v - tile)
+    };
+    let (tile_x, x) = position((x + MERCATOR_MAX) / (2.0 * MERCATOR_MAX));
+    let (tile_y, y) = position((MERCATOR_MAX - y) / (2.0 * MERCATOR_MAX));
+    let xyz = TileCoord {
+        z,
+        x: tile_x,
+        y: tile_y,
+    };
+    Some((xyz, x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use async_trait::async_trait;
+    use image::{ImageOutputFormat, RgbImage};
+    use martin_tile_utils::{Format, TileInfo};
+    use serde_json::{json, Value};
+    use tilejson::{tilejson, TileJSON};
+
+    use super::*;
+    use crate::source::{Source, TileData, UrlQuery};
+    use crate::srv::{Throttle, ThrottleConfig};
+    use crate::terrain::{TerrainConfig, TerrainEncoding, TerrainSource};
+    use crate::MartinResult;
+
+    #[test]
+    fn locate_points() {
+        let (xyz, x, y) = locate_point(0.0, 0.0, 0).unwrap();
+        assert_eq!((xyz.z, xyz.x, xyz.y), (0, 0, 0));
+        assert!((x - 0.5).abs() < 1e-9 && (y - 0.5).abs() < 1e-9);
+
+        let (xyz, x, y) = locate_point(-180.0, MAX_LATITUDE, 3).unwrap();
+        assert_eq!((xyz.x, xyz.y), (0, 0));
+        assert!(x.abs() < 1e-6 && y.abs() < 1e-6);
+        let (xyz, x, y) = locate_point(180.0, -MAX_LATITUDE, 3).unwrap();
+        assert_eq!((xyz.x, xyz.y), (7, 7));
+        assert!((x - 1.0).abs() < 1e-6 && (y - 1.0).abs() < 1e-6);
+
+        let (xyz, _, _) = locate_point(8.54, 47.37, 12).unwrap();
+        assert_eq!((xyz.x, xyz.y), (2145, 1434));
+
+        assert!(locate_point(180.1, 0.0, 0).is_none());
+        assert!(locate_point(0.0, 86.0, 0).is_none());
+        assert!(locate_point(f64::NAN, 0.0, 0).is_none());
+    }
+
+    /// Terrarium tiles with a height of 0 meters in the first column of tiles, and 100 meters in the others,
+    /// and without any tiles below the first row
+    #[derive(Debug, Clone)]
+    struct DemSource {
+        id: &'static str,
+        tj: TileJSON,
+    }
+
+    #[async_trait]
+    impl Source for DemSource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            Format::Png.into()
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            if xyz.y > 0 {
+                return Ok(Vec::new());
+            }
+            let pixel = if xyz.x == 0 {
+                [128, 0, 0]
+            } else {
+                [128, 100, 0]
+            };
+            let image = RgbImage::from_pixel(4, 4, image::Rgb(pixel));
+            let mut result = Cursor::new(Vec::new());
+            image.write_to(&mut result, ImageOutputFormat::Png).unwrap();
+            Ok(result.into_inner())
+        }
+    }
+
+    fn sources(output: Option<TerrainEncoding>) -> TileSources {
+        let src = |id| DemSource {
+            id,
+            tj: tilejson! { tiles: vec![], maxzoom: 1 },
+        };
+        let cfg = TerrainConfig {
+            encoding: TerrainEncoding::Terrarium,
+            output,
+        };
+        let dem = TerrainSource::new(Box::new(src("dem")), &cfg).unwrap();
+        TileSources::new(vec![vec![Box::new(dem), Box::new(src("png"))]])
+    }
+
+    #[actix_rt::test]
+    async fn elevations() {
+        let options = TileOptions::default();
+        let points = [[-90.0, 45.0], [90.0, 45.0], [90.0, -45.0]];
+        for output in [
+            None,
+            Some(TerrainEncoding::Mapbox),
+            Some(TerrainEncoding::Geotiff),
+        ] {
+            let sources = sources(output);
+            let elevations = get_elevations(&sources, &options, "dem", &points, None, None, None)
+                .await
+                .unwrap();
+            let heights: Vec<_> = elevations.iter().map(|v| v.elevation).collect();
+            assert_eq!(heights, [Some(0.0), Some(100.0), None]);
+            assert!(elevations.iter().all(|v| v.z == 1));
+        }
+
+        let sources = sources(None);
+        let elevations =
+            get_elevations(&sources, &options, "dem", &points[..1], Some(0), None, None)
+                .await
+                .unwrap();
+        assert_eq!(
+            elevations,
+            [PointElevation {
+                lng: -90.0,
+                lat: 45.0,
+                z: 0,
+                elevation: Some(0.0)
+            }]
+        );
+        let get = |z, point: [f64; 2]| {
+            let sources = &sources;
+            let options = &options;
+            async move { get_elevations(sources, options, "dem", &[point], z, None, None).await }
+        };
+        assert!(get(Some(2), [0.0, 0.0]).await.is_err());
+        assert!(get(None, [0.0, 90.0]).await.is_err());
+        for id in ["png", "missing"] {
+            let elevations = get_elevations(&sources, &options, id, &points, None, None, None);
+            assert!(elevations.await.is_err());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn elevation_routes() {
+        let sources = Data::new(ArcSwap::from_pointee(sources(None)));
+        let app = init_service(
+            App::new()
+                .app_data(sources)
+                .app_data(Data::new(TileOptions::default()))
+                .service(get_elevation)
+                .service(post_elevations),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/dem/elevation?lng=90&lat=45");
+        let body: Value = call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(
+            body,
+            json!({ "lng": 90.0, "lat": 45.0, "z": 1, "elevation": 100.0 })
+        );
+        let req = TestRequest::post()
+            .uri("/dem/elevation")
+            .set_json(json!({ "points": [[-90, 45], [90, -45]], "z": 0 }));
+        let body: Value = call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(
+            body,
+            json!([
+                { "lng": -90.0, "lat": 45.0, "z": 0, "elevation": 0.0 },
+                { "lng": 90.0, "lat": -45.0, "z": 0, "elevation": 0.0 },
+            ])
+        );
+
+        for req in [
+            TestRequest::get().uri("/dem/elevation?lng=90"),
+            TestRequest::get().uri("/png/elevation?lng=90&lat=45"),
+            TestRequest::post()
+                .uri("/dem/elevation")
+                .set_json(json!({ "points": vec![[0, 0]; ELEVATION_MAX_POINTS + 1] })),
+        ] {
+            let resp = call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn elevation_quotas() {
+        let throttle = Throttle::new(ThrottleConfig {
+            anonymous_max_zoom: Some(0),
+            ..ThrottleConfig::default()
+        });
+        let app = init_service(
+            App::new()
+                .wrap(throttle.clone())
+                .app_data(Data::new(ArcSwap::from_pointee(sources(None))))
+                .app_data(Data::new(TileOptions::default()))
+                .service(get_elevation)
+                .service(post_elevations),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/dem/elevation?lng=90&lat=45");
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = TestRequest::post()
+            .uri("/dem/elevation")
+            .set_json(json!({ "points": [[-90, 45], [90, -45]], "z": 0 }));
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(throttle.report().anonymous.used, 1);
+    }
+}
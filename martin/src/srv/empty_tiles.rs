@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileData;
+use crate::MartinError::EmptyTileReadError;
+use crate::MartinResult;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyTileMode {
+    /// Respond with `204 No Content`
+    NoContent,
+    /// Respond with `404 Not Found`
+    NotFound,
+    /// Respond with `200 OK` and the fallback body
+    Fallback,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EmptyTileConfig {
+    /// How to respond to the requests of empty tiles [default: `fallback` if a fallback file is set, `no_content` otherwise]
+    pub mode: Option<EmptyTileMode>,
+    /// File returned as the body of the empty tiles, e.g. a transparent PNG. It must have the format
+    /// and the encoding of the source tiles. Without it, the fallback body is an empty tile.
+    pub fallback: Option<PathBuf>,
+}
+
+/// Response to an empty tile
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EmptyTile {
+    #[default]
+    NoContent,
+    NotFound,
+    Fallback(TileData),
+}
+
+impl EmptyTile {
+    /// Get the response of the modes without a body
+    #[must_use]
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            Self::NotFound => HttpResponse::NotFound().finish(),
+            Self::NoContent | Self::Fallback(_) => HttpResponse::NoContent().finish(),
+        }
+    }
+}
+
+/// Responses to the empty tiles of the configured sources, keyed by source ID
+#[derive(Clone, Debug, Default)]
+pub struct EmptyTiles(Arc<BTreeMap<String, EmptyTile>>);
+
+impl EmptyTiles {
+    /// Read the fallback files of all configured sources
+    pub fn new(config: Option<&BTreeMap<String, EmptyTileConfig>>) -> MartinResult<Self> {
+        let mut sources = BTreeMap::new();
+        for (id, cfg) in config.into_iter().flatten() {
+            let default_mode = if cfg.fallback.is_some() {
+                EmptyTileMode::Fallback
+            } else {
+                EmptyTileMode::NoContent
+            };
+            let tile = match cfg.mode.unwrap_or(default_mode) {
+                EmptyTileMode::NoContent => EmptyTile::NoContent,
+                EmptyTileMode::NotFound => EmptyTile::NotFound,
+                EmptyTileMode::Fallback => EmptyTile::Fallback(match &cfg.fallback {
+                    Some(path) => {
+                        std::fs::read(path).map_err(|e| EmptyTileReadError(e, path.clone()))?
+                    }
+                    None => TileData::new(),
+                }),
+            };
+            sources.insert(id.clone(), tile);
+        }
+        Ok(Self(Arc::new(sources)))
+    }
+
+    /// Get the response to an empty tile of the comma-separated sources. The sources of a composite
+    /// request must all be configured the same way, otherwise `204 No Content` is used.
+    #[must_use]
+    pub fn get(&self, source_ids: &str) -> &EmptyTile {
+        static NO_CONTENT: EmptyTile = EmptyTile::NoContent;
+        let mut tiles = source_ids.split(',').map(|id| self.0.get(id));
+        match tiles.next().flatten() {
+            Some(first) if tiles.all(|v| v == Some(first)) => first,
+            _ => &NO_CONTENT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn empty_tile_responses() {
+        let config: BTreeMap<String, EmptyTileConfig> = serde_yaml::from_str(indoc! {"
+            roads:
+              mode: not_found
+            water:
+              mode: not_found
+            basemap:
+              mode: fallback
+            satellite:
+              fallback: ../tests/fixtures/sprites/src1/another_bicycle.svg
+        "})
+        .unwrap();
+        let empty = EmptyTiles::new(Some(&config)).unwrap();
+        assert_eq!(empty.get("roads"), &EmptyTile::NotFound);
+        assert_eq!(empty.get("roads,water"), &EmptyTile::NotFound);
+        assert_eq!(empty.get("roads,basemap"), &EmptyTile::NoContent);
+        assert_eq!(empty.get("roads,other"), &EmptyTile::NoContent);
+        assert_eq!(empty.get("other"), &EmptyTile::NoContent);
+        assert_eq!(empty.get("basemap"), &EmptyTile::Fallback(Vec::new()));
+        assert!(matches!(empty.get("satellite"), EmptyTile::Fallback(v) if !v.is_empty()));
+
+        let config = BTreeMap::from([(
+            "roads".to_string(),
+            EmptyTileConfig {
+                mode: None,
+                fallback: Some(PathBuf::from("/missing/empty.png")),
+            },
+        )]);
+        assert!(EmptyTiles::new(Some(&config)).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::Result as ActixResult;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::MartinError::InvalidEncryptionKey;
+use crate::{MartinResult, TileCoord};
+
+/// Value of the `X-Tile-Encryption` response header of the encrypted tiles
+pub const ENCRYPTION_ALGORITHM: &str = "aes-gcm";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// AES key as a hex string, 32 characters for AES-128 or 64 characters for AES-256.
+    /// Use `${VAR}` to read it from an environment variable instead of the config file.
+    pub key: String,
+    /// Key identifier sent to the clients in the `X-Tile-Key-Id` header, e.g. to rotate keys
+    pub key_id: Option<String>,
+}
+
+impl EncryptionConfig {
+    fn parse_key(&self) -> Option<LessSafeKey> {
+        let hex = self.key.trim();
+        if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let algorithm = match bytes.len() {
+            16 => &AES_128_GCM,
+            32 => &AES_256_GCM,
+            _ => return None,
+        };
+        Some(LessSafeKey::new(UnboundKey::new(algorithm, &bytes).ok()?))
+    }
+}
+
+/// Make sure all configured keys can be used
+pub fn validate_encryption(config: &BTreeMap<String, EncryptionConfig>) -> MartinResult<()> {
+    for (id, cfg) in config {
+        if cfg.parse_key().is_none() {
+            return Err(InvalidEncryptionKey(id.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Key used to encrypt the tiles of a source
+pub struct TileKey {
+    /// The key, or `None` if the configured key is invalid, so that the tiles are never sent unencrypted
+    key: Option<LessSafeKey>,
+    key_id: Option<String>,
+    /// Normalized hex value of the key, to find the sources sharing the same key
+    secret: String,
+}
+
+/// Encryption keys of the tile sources, keyed by source ID
+#[derive(Clone, Default)]
+pub struct TileEncryption {
+    keys: BTreeMap<String, Arc<TileKey>>,
+}
+
+impl Debug for TileEncryption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ids: Vec<_> = self.keys.keys().collect();
+        write!(f, "TileEncryption {{ sources: {ids:?} }}")
+    }
+}
+
+impl TileEncryption {
+    #[must_use]
+    pub fn new(config: Option<&BTreeMap<String, EncryptionConfig>>) -> Self {
+        let keys = config
+            .into_iter()
+            .flatten()
+            .map(|(id, cfg)| {
+                let key = TileKey {
+                    key: cfg.parse_key(),
+                    key_id: cfg.key_id.clone(),
+                    secret: cfg.key.trim().to_ascii_lowercase(),
+                };
+                (id.clone(), Arc::new(key))
+            })
+            .collect();
+        Self { keys }
+    }
+
+    #[must_use]
+    pub fn is_encrypted(&self, source_id: &str) -> bool {
+        self.keys.contains_key(source_id)
+    }
+
+    /// Find the key of the requested sources. Encrypted sources can only be combined
+    /// with the sources that use the same key, because each response has a single key.
+    pub fn get_key(&self, source_ids: &[&str]) -> ActixResult<Option<&TileKey>> {
+        let mut keys = source_ids.iter().map(|id| self.keys.get(*id));
+        let Some(first) = keys.next() else {
+            return Ok(None);
+        };
+        if !keys.all(|k| match (first, k) {
+            (Some(a), Some(b)) => a.secret == b.secret && a.key_id == b.key_id,
+            (a, b) => a.is_none() && b.is_none(),
+        }) {
+            return Err(ErrorBadRequest(
+                "Encrypted sources can only be combined with the sources using the same key",
+            ));
+        }
+        Ok(first.map(AsRef::as_ref))
+    }
+}
+
+impl TileKey {
+    #[must_use]
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Encrypt the tile data, returning the random nonce followed by the ciphertext and the tag.
+    /// The `z/x/y` path of the tile is used as the associated data, so that a tile cannot be
+    /// passed off as another one.
+    pub fn encrypt(&self, xyz: TileCoord, data: &[u8]) -> ActixResult<Vec<u8>> {
+        let err = || ErrorInternalServerError("Unable to encrypt the tile");
+        let key = self.key.as_ref().ok_or_else(err)?;
+        let mut nonce = [0_u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| err())?;
+        let mut payload = data.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(tile_aad(xyz)),
+            &mut payload,
+        )
+        .map_err(|_| err())?;
+        let mut result = Vec::with_capacity(NONCE_LEN + payload.len());
+        result.extend_from_slice(&nonce);
+        result.extend(payload);
+        Ok(result)
+    }
+}
+
+fn tile_aad(xyz: TileCoord) -> String {
+    format!("{}/{}/{}", xyz.z, xyz.x, xyz.y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(key: &str) -> EncryptionConfig {
+        EncryptionConfig {
+            key: key.to_string(),
+            key_id: Some("v1".to_string()),
+        }
+    }
+
+    #[test]
+    fn encrypt_tiles() {
+        let key = "000102030405060708090a0b0c0d0e0f000102030405060708090a0b0c0d0e0f";
+        let config = BTreeMap::from([
+            ("a".to_string(), cfg(key)),
+            ("b".to_string(), cfg(&key[..32])),
+            ("c".to_string(), cfg(&key.to_uppercase())),
+        ]);
+        validate_encryption(&config).unwrap();
+        let enc = TileEncryption::new(Some(&config));
+        assert!(enc.is_encrypted("a"));
+        assert!(!enc.is_encrypted("d"));
+        assert!(enc.get_key(&["d"]).unwrap().is_none());
+        assert!(enc.get_key(&["a", "d"]).is_err());
+        assert!(enc.get_key(&["a", "b"]).is_err());
+        assert!(enc.get_key(&["a", "c"]).unwrap().is_some());
+
+        let tile_key = enc.get_key(&["a"]).unwrap().unwrap();
+        assert_eq!(tile_key.key_id(), Some("v1"));
+        let xyz = TileCoord { z: 1, x: 0, y: 1 };
+        let data = tile_key.encrypt(xyz, b"tile").unwrap();
+        assert_eq!(data.len(), NONCE_LEN + 4 + AES_256_GCM.tag_len());
+
+        let decrypt = |aad: &str| {
+            let (nonce, payload) = data.split_at(NONCE_LEN);
+            let mut payload = payload.to_vec();
+            let key = cfg(key).parse_key().unwrap();
+            let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+            key.open_in_place(nonce, Aad::from(aad), &mut payload)
+                .map(|v| v.to_vec())
+        };
+        assert_eq!(decrypt("1/0/1").unwrap(), b"tile");
+        assert!(decrypt("1/0/0").is_err());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        for key in ["", "abc", "zz0102030405060708090a0b0c0d0e0f", "0001"] {
+            let config = BTreeMap::from([("a".to_string(), cfg(key))]);
+            assert!(validate_encryption(&config).is_err(), "{key}");
+            let enc = TileEncryption::new(Some(&config));
+            let tile_key = enc.get_key(&["a"]).unwrap().unwrap();
+            assert!(tile_key
+                .encrypt(TileCoord { z: 0, x: 0, y: 0 }, b"")
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse, Responder};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+
+use crate::demo::DemoMode;
+use crate::source::TileSources;
+use crate::srv::config::AdminConfig;
+use crate::srv::{Catalog, TileOptions};
+
+/// Subsystems compiled into this binary
+pub const COMPILED_FEATURES: &[&str] = &[
+    "cog",
+    "fonts",
+    "geoparquet",
+    "gpkg",
+    "mbtiles",
+    "metrics",
+    "osm",
+    "pmtiles",
+    "postgres",
+    "proxy",
+    "shapefile",
+    "sprites",
+    "sqlite",
+];
+
+/// Subsystems of this server, so that the deployment tooling can check the running binary
+/// and its configuration before sending traffic to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureReport {
+    pub version: &'static str,
+    /// Subsystems compiled into this binary
+    pub compiled: &'static [&'static str],
+    /// Optional subsystems, and whether they are used with the current configuration
+    pub enabled: BTreeMap<&'static str, bool>,
+}
+
+impl FeatureReport {
+    #[must_use]
+    pub fn new(
+        sources: &TileSources,
+        catalog: &Catalog,
+        options: Option<&TileOptions>,
+        admin: bool,
+        demo: bool,
+    ) -> Self {
+        let source_types = sources.get_source_type_counts();
+        let mut enabled: BTreeMap<_, _> = COMPILED_FEATURES
+            .iter()
+            .map(|v| (*v, source_types.contains_key(v)))
+            .collect();
+        enabled.insert("fonts", !catalog.fonts.is_empty());
+        enabled.insert("sprites", !catalog.sprites.is_empty());
+        enabled.insert("metrics", true);
+        enabled.insert("admin", admin);
+        enabled.insert("demo", demo);
+        let opt = |f: fn(&TileOptions) -> bool| options.map_or(false, f);
+        enabled.insert("cache", opt(|v| v.cache.is_some()));
+        enabled.insert("shared_cache", opt(|v| v.shared_cache.is_some()));
+        enabled.insert("host_cache", opt(|v| v.host_cache.is_some()));
+        enabled.insert("prefetch", opt(|v| v.prefetch.is_some()));
+        enabled.insert("jwt", opt(|v| v.jwt.is_some()));
+        enabled.insert("traffic_profile", opt(|v| v.traffic.is_some()));
+        enabled.insert("usage_stats", opt(|v| v.usage.is_some()));
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            compiled: COMPILED_FEATURES,
+            enabled,
+        }
+    }
+}
+
+/// Report the subsystems of this binary, and which of them are in use
+#[route("/_/features", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_features(
+    sources: Data<ArcSwap<TileSources>>,
+    catalog: Data<ArcSwap<Catalog>>,
+    options: Option<Data<TileOptions>>,
+    admin: Option<Data<AdminConfig>>,
+    demo: Option<Data<DemoMode>>,
+) -> impl Responder {
+    let report = FeatureReport::new(
+        &sources.load(),
+        &catalog.load(),
+        options.as_ref().map(Data::get_ref),
+        admin.is_some(),
+        demo.is_some(),
+    );
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_report() {
+        let report = FeatureReport::new(
+            &TileSources::default(),
+            &Catalog::default(),
+            None,
+            true,
+            false,
+        );
+        assert!(report.compiled.contains(&"postgres"));
+        assert!(!report.enabled["postgres"]);
+        assert!(report.enabled["metrics"]);
+        assert!(report.enabled["admin"]);
+        assert!(!report.enabled["demo"]);
+        assert!(!report.enabled["cache"]);
+    }
+}
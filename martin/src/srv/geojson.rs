@@ -0,0 +1,338 @@
+use std::time::Instant;
+
+use actix_web::error::{ErrorBadRequest, ErrorGatewayTimeout, ErrorNotFound};
+use actix_web::http::header::{CACHE_CONTROL, VARY};
+use actix_web::web::{Data, Path};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use log::warn;
+use martin_tile_utils::Format;
+use prost::Message as _;
+use serde_json::{json, Map, Value};
+
+use crate::source::{TileData, TileSources};
+use crate::srv::server::{map_internal_error, redirect_sources, resolve_sources};
+use crate::srv::throttle::use_tile;
+use crate::srv::utfgrid::tile_to_utfgrid;
+use crate::srv::{
+    get_request_claims, get_tile_content, JwtClaims, SourceRedirects, TileOptions, TileRequest,
+};
+use crate::utils::mvt::{decode_geometry, GeomType, Tile, TileGeometry, DEFAULT_MVT_EXTENT};
+use crate::utils::{CacheKey, CacheValue};
+use crate::{TileCoord, TileMatrixSet};
+
+/// JSON formats that vector tiles are converted to, for the clients that cannot render vector tiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TileConversion {
+    GeoJson,
+    UtfGrid,
+}
+
+impl TileConversion {
+    fn name(self) -> &'static str {
+        match self {
+            Self::GeoJson => "GeoJSON",
+            Self::UtfGrid => "UTFGrid",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::GeoJson => "application/geo+json",
+            Self::UtfGrid => "application/json",
+        }
+    }
+
+    fn cache_key(self, ids: String, xyz: TileCoord, query: Option<String>) -> CacheKey {
+        match self {
+            Self::GeoJson => CacheKey::GeoJson(ids, xyz, query),
+            Self::UtfGrid => CacheKey::UtfGrid(ids, xyz, query),
+        }
+    }
+
+    fn convert(
+        self,
+        data: &[u8],
+        xyz: TileCoord,
+        grid: TileMatrixSet,
+    ) -> Result<Value, prost::DecodeError> {
+        match self {
+            Self::GeoJson => tile_to_geojson(data, xyz, grid),
+            Self::UtfGrid => tile_to_utfgrid(data),
+        }
+    }
+}
+
+/// Serve a vector tile of one or more sources as a `GeoJSON` `FeatureCollection` with WGS84 coordinates,
+/// for the clients that cannot render vector tiles. Each feature has a `layer` member with its layer name.
+/// The converted tiles are stored in the tile cache, and purged together with their sources.
+#[route(
+    "/{source_ids}/{z}/{x}/{y}.geojson",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_geojson_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 3) {
+        return Ok(resp);
+    }
+    get_converted_tile(&req, &path, &sources, &options, TileConversion::GeoJson).await
+}
+
+pub(crate) async fn get_converted_tile(
+    req: &HttpRequest,
+    path: &TileRequest,
+    sources: &TileSources,
+    options: &TileOptions,
+    conversion: TileConversion,
+) -> ActixResult<HttpResponse> {
+    let xyz = path.tile_coord()?;
+    let (source_ids, vary) = resolve_sources(req, sources, &path.source_ids)?;
+    use_tile(req, xyz.z)?;
+
+    let source_ids = &source_ids;
+    let claims = get_request_claims(req, options.jwt.as_ref())?;
+    if let Some(traffic) = &options.traffic {
+        traffic.record(req.path(), req.query_string());
+    }
+    let start = Instant::now();
+    let content = convert_tile(
+        sources,
+        options,
+        xyz,
+        source_ids,
+        req.query_string(),
+        claims.as_ref(),
+        conversion,
+    );
+    let data = if let Some(timeout) = options.request_timeout {
+        tokio::time::timeout(timeout, content).await.map_err(|_| {
+            let name = conversion.name();
+            warn!("Request for {name} tile {xyz} of {source_ids} timed out after {timeout:?}");
+            ErrorGatewayTimeout("Tile request timed out")
+        })?
+    } else {
+        content.await
+    }?;
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(conversion.content_type());
+    if let Some(max_age) = options.expiration.max_age(xyz.z) {
+        let max_age = max_age.as_secs();
+        let scope = if claims.is_some() {
+            "private"
+        } else {
+            "public"
+        };
+        response.insert_header((CACHE_CONTROL, format!("{scope}, max-age={max_age}")));
+    }
+    if !vary.is_empty() {
+        response.insert_header((VARY, vary.join(", ")));
+    }
+    if let Some(usage) = &options.usage {
+        usage.record(source_ids, data.len(), start.elapsed());
+    }
+    Ok(response.body(data))
+}
+
+async fn convert_tile(
+    sources: &TileSources,
+    options: &TileOptions,
+    xyz: TileCoord,
+    source_ids: &str,
+    query: &str,
+    claims: Option<&JwtClaims>,
+    conversion: TileConversion,
+) -> ActixResult<TileData> {
+    let (sources, use_url_query, info) = sources.get_sources(source_ids, Some(xyz.z))?;
+    let name = conversion.name();
+    if info.format != Format::Mvt {
+        return Err(ErrorBadRequest(format!(
+            "Only vector tiles can be converted to {name}, but {source_ids} has {info} tiles"
+        )));
+    }
+    if conversion == TileConversion::UtfGrid {
+        if let Some(src) = sources.iter().find(|src| !src.support_utfgrid()) {
+            return Err(ErrorNotFound(format!(
+                "Source {} does not serve UTFGrid tiles",
+                src.get_id()
+            )));
+        }
+    }
+    if let Some(src) = sources
+        .iter()
+        .find(|src| options.encryption.is_encrypted(src.get_id()))
+    {
+        return Err(ErrorBadRequest(format!(
+            "Tiles of {} are encrypted, and cannot be converted to {name}",
+            src.get_id()
+        )));
+    }
+    let query = Some(query).filter(|q| use_url_query && !q.is_empty());
+    let claims = claims.filter(|_| use_url_query);
+
+    // composites are keyed by their members, so that the tiles are purged together with them
+    let ids = sources
+        .iter()
+        .map(|src| src.get_id())
+        .collect::<Vec<_>>()
+        .join(",");
+    // the claims are part of the key, so that the users never get each other's features
+    let cache_query = match (query, claims) {
+        (query, Some(claims)) => Some(format!(
+            "{}#{}",
+            query.unwrap_or_default(),
+            Value::Object(claims.clone())
+        )),
+        (query, None) => query.map(str::to_string),
+    };
+    let key = conversion.cache_key(ids, xyz, cache_query);
+    if let Some(cache) = &options.cache {
+        if let Some(CacheValue::Tile(data)) = cache.get(&key).await {
+            return Ok(data);
+        }
+    }
+
+    // without the accepted encodings, the tile is always decompressed
+    let tile =
+        get_tile_content(sources.as_slice(), options, info, &xyz, query, claims, None).await?;
+    // all sources have the same grid, as checked by `get_sources`
+    let grid = sources
+        .first()
+        .map(|src| src.get_tile_matrix_set())
+        .unwrap_or_default();
+    let json = conversion
+        .convert(&tile.data, xyz, grid)
+        .map_err(map_internal_error)?;
+    let data = serde_json::to_vec(&json).map_err(map_internal_error)?;
+    // the stale tiles are being fetched again, and their conversion would outlive them
+    if let (Some(cache), false) = (&options.cache, tile.stale) {
+        cache.insert(key, CacheValue::Tile(data.clone())).await;
+    }
+    Ok(data)
+}
+
+/// Convert an uncompressed vector tile of the `grid` into a `GeoJSON` `FeatureCollection`
+fn tile_to_geojson(
+    data: &[u8],
+    xyz: TileCoord,
+    grid: TileMatrixSet,
+) -> Result<Value, prost::DecodeError> {
+    let tile = Tile::decode(data)?;
+    let [min_x, min_y, max_x, max_y] = grid.tile_bbox(xyz);
+    let mut features = Vec::new();
+    for layer in &tile.layers {
+        let extent = f64::from(layer.extent.unwrap_or(DEFAULT_MVT_EXTENT).max(1));
+        let to_wgs84 = |[x, y]: [i32; 2]| {
+            let [lon, lat] = grid.to_wgs84([
+                min_x + f64::from(x) / extent * (max_x - min_x),
+                max_y - f64::from(y) / extent * (max_y - min_y),
+            ]);
+            json!([lon, lat])
+        };
+        for feature in &layer.features {
+            let Some(geometry) = feature
+                .r#type
+                .and_then(|v| GeomType::try_from(v).ok())
+                .and_then(|t| decode_geometry(t, &feature.geometry))
+                .and_then(|g| geometry_to_geojson(&g, &to_wgs84))
+            else {
+                continue;
+            };
+            let properties: Map<String, Value> = feature
+                .properties(layer)
+                .map(|(k, v)| (k.to_string(), v.to_json().unwrap_or_default()))
+                .collect();
+            let mut result = json!({
+                "type": "Feature",
+                "layer": layer.name,
+                "geometry": geometry,
+                "properties": properties,
+            });
+            if let Some(id) = feature.id {
+                result["id"] = id.into();
+            }
+            features.push(result);
+        }
+    }
+    Ok(json!({"type": "FeatureCollection", "features": features}))
+}
+
+/// Get the `GeoJSON` geometry object, using the `Multi*` types only if there is more than one part
+fn geometry_to_geojson(
+    geom: &TileGeometry,
+    to_wgs84: &impl Fn([i32; 2]) -> Value,
+) -> Option<Value> {
+    let line = |v: &Vec<[i32; 2]>| Value::Array(v.iter().map(|p| to_wgs84(*p)).collect());
+    let polygon = |v: &Vec<Vec<[i32; 2]>>| Value::Array(v.iter().map(line).collect());
+    let (geom_type, coordinates) = match geom {
+        TileGeometry::Points(v) => match v.as_slice() {
+            [] => None?,
+            [point] => ("Point", to_wgs84(*point)),
+            _ => ("MultiPoint", line(v)),
+        },
+        TileGeometry::Lines(v) => match v.as_slice() {
+            [] => None?,
+            [single] => ("LineString", line(single)),
+            _ => ("MultiLineString", v.iter().map(line).collect()),
+        },
+        TileGeometry::Polygons(v) => match v.as_slice() {
+            [] => None?,
+            [single] => ("Polygon", polygon(single)),
+            _ => ("MultiPolygon", v.iter().map(polygon).collect()),
+        },
+    };
+    Some(json!({"type": geom_type, "coordinates": coordinates}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mvt::{encode_tile, LayerBuilder, PropValue};
+
+    #[test]
+    fn convert_tile() {
+        let mut layer = LayerBuilder::new("places", 4096);
+        let props = [("name".to_string(), PropValue::String("a".to_string()))];
+        layer.add_feature(Some(7), &TileGeometry::Points(vec![[2048, 2048]]), &props);
+        layer.add_feature(
+            None,
+            &TileGeometry::Lines(vec![vec![[0, 0], [4096, 4096]], vec![[0, 4096], [4096, 0]]]),
+            &[],
+        );
+        let data = encode_tile(vec![layer.build()]);
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let geojson = tile_to_geojson(&data, xyz, TileMatrixSet::WebMercatorQuad).unwrap();
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(
+            features[0],
+            json!({
+                "type": "Feature",
+                "id": 7,
+                "layer": "places",
+                "geometry": {"type": "Point", "coordinates": [0.0, 0.0]},
+                "properties": {"name": "a"},
+            })
+        );
+        assert_eq!(features[1]["geometry"]["type"], "MultiLineString");
+        let start = &features[1]["geometry"]["coordinates"][0][0];
+        assert_eq!(start[0], -180.0);
+        assert!((start[1].as_f64().unwrap() - 85.051_128).abs() < 1e-6);
+
+        let geographic = tile_to_geojson(&data, xyz, TileMatrixSet::WorldCrs84Quad).unwrap();
+        let point = &geographic["features"][0]["geometry"]["coordinates"];
+        assert_eq!(point, &json!([-90.0, 0.0]));
+
+        let empty = tile_to_geojson(&[], xyz, TileMatrixSet::WebMercatorQuad).unwrap();
+        assert_eq!(empty, json!({"type": "FeatureCollection", "features": []}));
+    }
+}
@@ -0,0 +1,793 @@
+use std::net::{IpAddr, TcpListener};
+use std::pin::Pin;
+
+use actix_web::body::to_bytes;
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use futures::{Stream, StreamExt};
+use log::{debug, error, info};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::source::TileSources;
+use crate::srv::config::{GrpcConfig, GRPC_STREAM_CONCURRENCY_DEFAULT};
+use crate::srv::throttle::QuotaExceeded;
+use crate::srv::{
+    get_tile_response, merge_tilejson, Catalog, IpFilter, RefererFilter, Tenants, Throttle,
+    TileOptions, TileRequest,
+};
+use crate::MartinError::BindingError;
+use crate::MartinResult;
+
+#[allow(unused_qualifications, clippy::pedantic)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/martin.v1.Tiles.rs"));
+}
+use generated::tiles_server::{Tiles, TilesServer};
+
+// The messages of proto/martin.proto, keep both in sync
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListSourcesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListSourcesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub sources: Vec<SourceInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SourceInfo {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub content_type: String,
+    #[prost(string, optional, tag = "3")]
+    pub name: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub description: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub attribution: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileJsonRequest {
+    #[prost(string, tag = "1")]
+    pub source_ids: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileJsonResponse {
+    #[prost(string, tag = "1")]
+    pub tilejson: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileRequest {
+    #[prost(string, tag = "1")]
+    pub source_ids: String,
+    #[prost(uint32, tag = "2")]
+    pub z: u32,
+    #[prost(uint32, tag = "3")]
+    pub x: u32,
+    #[prost(uint32, tag = "4")]
+    pub y: u32,
+    #[prost(string, tag = "5")]
+    pub query: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileResponse {
+    #[prost(string, tag = "1")]
+    pub source_ids: String,
+    #[prost(uint32, tag = "2")]
+    pub z: u32,
+    #[prost(uint32, tag = "3")]
+    pub x: u32,
+    #[prost(uint32, tag = "4")]
+    pub y: u32,
+    #[prost(bytes = "vec", tag = "5")]
+    pub data: Vec<u8>,
+    #[prost(string, tag = "6")]
+    pub content_type: String,
+    #[prost(string, optional, tag = "7")]
+    pub error: Option<String>,
+}
+
+/// gRPC API serving the same sources, catalog, and caches as the HTTP endpoints, for the backends
+/// that fetch many tiles at once. The tiles are always sent uncompressed.
+/// The IP rules, the allowed web apps, the tenant sources, and the quotas of the HTTP server apply to the gRPC calls as well.
+#[derive(Clone)]
+pub struct GrpcService {
+    sources: Data<ArcSwap<TileSources>>,
+    catalog: Data<ArcSwap<Catalog>>,
+    options: TileOptions,
+    throttle: Throttle,
+    ip_filter: IpFilter,
+    referer_filter: RefererFilter,
+    /// The gRPC clients are served like the clients of the root HTTP routes, without the tenant sources
+    tenants: Tenants,
+    /// Base of the tile URLs in the `TileJSON`, the URLs are relative if unset
+    public_url: String,
+    stream_concurrency: usize,
+}
+
+impl GrpcService {
+    #[must_use]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &GrpcConfig,
+        sources: Data<ArcSwap<TileSources>>,
+        catalog: Data<ArcSwap<Catalog>>,
+        options: TileOptions,
+        throttle: Throttle,
+        ip_filter: IpFilter,
+        referer_filter: RefererFilter,
+        tenants: Tenants,
+        public_url: Option<&str>,
+    ) -> Self {
+        Self {
+            sources,
+            catalog,
+            options,
+            throttle,
+            ip_filter,
+            referer_filter,
+            tenants,
+            public_url: public_url
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            stream_concurrency: config
+                .stream_concurrency
+                .unwrap_or(GRPC_STREAM_CONCURRENCY_DEFAULT),
+        }
+    }
+
+    /// Identify the client of a call like the HTTP middlewares do, rejecting the call if the client
+    /// is not allowed by the IP rules, or if the bandwidth limit or the daily request quota is reached.
+    /// The peer address is unknown if the call did not come from the network, e.g. in the tests.
+    #[allow(clippy::result_large_err)]
+    fn client<T>(&self, request: &Request<T>) -> Result<Client, Status> {
+        let metadata = request.metadata();
+        let ip = request.remote_addr().map(|peer| {
+            let forwarded = metadata.get_all("x-forwarded-for");
+            let forwarded = forwarded.iter().filter_map(|v| v.to_str().ok());
+            self.ip_filter.client_ip(peer.ip(), forwarded)
+        });
+        if let Some(ip) = ip {
+            if !self.ip_filter.config().is_allowed(&ip) {
+                debug!("Rejected gRPC call from {ip}");
+                return Err(Status::permission_denied("Access denied"));
+            }
+        }
+        if self.throttle.bandwidth_wait_time().is_some() {
+            return Err(Status::resource_exhausted("The bandwidth limit is reached"));
+        }
+        let api_key = self.throttle.grpc_api_key(metadata);
+        self.throttle
+            .try_use_request(api_key.clone())
+            .map_err(quota_error)?;
+        let headers = metadata.clone().into_headers().into();
+        Ok(Client {
+            ip,
+            api_key,
+            headers,
+        })
+    }
+
+    /// Select the variants requested by the client, and check the access rules of the requested sources,
+    /// of the selected variants, and of the members of the named composites, like the HTTP handlers.
+    /// Returns the IDs of the selected sources.
+    #[allow(clippy::result_large_err)]
+    fn resolve_sources(
+        &self,
+        client: &Client,
+        sources: &TileSources,
+        source_ids: &str,
+        query: &str,
+    ) -> Result<String, Status> {
+        let (resolved, _) = sources.resolve_variants(source_ids, query, &client.headers);
+        let member_ids = sources.member_ids(&resolved);
+        let ids = format!("{source_ids},{member_ids}");
+        if let Some(ip) = client.ip {
+            if !self.ip_filter.config().is_source_allowed(&ip, &ids) {
+                debug!("Rejected gRPC request for {source_ids} from {ip}");
+                return Err(Status::permission_denied("Access denied"));
+            }
+        }
+        // the origin of the web apps is sent as the `origin` or `referer` metadata, e.g. by gRPC-Web
+        if self.referer_filter.is_allowed(&client.headers, &ids) == Some(false) {
+            debug!("Rejected gRPC request for {source_ids} from another origin");
+            return Err(Status::permission_denied("Access denied"));
+        }
+        if !self.tenants.is_served(None, source_ids, &member_ids) {
+            return Err(Status::not_found(format!(
+                "Source {source_ids} does not exist"
+            )));
+        }
+        Ok(resolved)
+    }
+
+    /// Fetch a tile like the HTTP tile handlers, with the same size limits and empty tiles,
+    /// returning the uncompressed data and the content type
+    async fn get_tile_data(
+        &self,
+        req: &GetTileRequest,
+        client: &Client,
+    ) -> Result<(Vec<u8>, String), Status> {
+        let z = u8::try_from(req.z)
+            .map_err(|_| Status::invalid_argument(format!("Zoom {} is invalid", req.z)))?;
+        let request = TileRequest {
+            source_ids: req.source_ids.clone(),
+            z,
+            x: req.x,
+            y: req.y,
+        };
+        let xyz = request.tile_coord().map_err(|e| map_error(&e))?;
+        let sources = self.sources.load_full();
+        let source_ids = self.resolve_sources(client, &sources, &req.source_ids, &req.query)?;
+        self.throttle
+            .try_use_tiles(client.api_key.clone(), xyz.z, 1)
+            .map_err(quota_error)?;
+        let (src_list, _, info) = sources
+            .get_sources(&source_ids, Some(xyz.z))
+            .map_err(|e| map_error(&e))?;
+        let ids: Vec<_> = src_list.iter().map(|src| src.get_id()).collect();
+        // the key is only shared with the HTTP clients, so the tiles cannot be sent in plain text
+        if self
+            .options
+            .encryption
+            .get_key(&ids)
+            .map_err(|e| map_error(&e))?
+            .is_some()
+        {
+            return Err(Status::failed_precondition(format!(
+                "Encrypted sources {} are not served over gRPC",
+                req.source_ids
+            )));
+        }
+        let content_type = info.format.content_type().to_string();
+        let response = get_tile_response(
+            &sources,
+            &self.options,
+            xyz,
+            &source_ids,
+            &req.query,
+            None,
+            None,
+        )
+        .await
+        .map_err(|e| map_error(&e))?;
+        // the oversized and the empty tiles have no body
+        let data = to_bytes(response.into_body())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.throttle.consume_bandwidth(data.len() as u64);
+        Ok((data.to_vec(), content_type))
+    }
+
+    async fn get_tile_response(
+        &self,
+        req: GetTileRequest,
+        client: &Client,
+    ) -> Result<GetTileResponse, Status> {
+        let (data, content_type) = self.get_tile_data(&req, client).await?;
+        Ok(GetTileResponse {
+            source_ids: req.source_ids,
+            z: req.z,
+            x: req.x,
+            y: req.y,
+            data,
+            content_type,
+            error: None,
+        })
+    }
+}
+
+/// Client of a gRPC call, checked against the IP rules and the quotas of the HTTP server
+#[derive(Debug, Clone, Default)]
+struct Client {
+    ip: Option<IpAddr>,
+    api_key: Option<String>,
+    /// Metadata of the call, e.g. to select the source variants by header
+    headers: HeaderMap,
+}
+
+/// A tile requested by a gRPC handler, its client, and the channel of its response
+type TileJob = (
+    GetTileRequest,
+    Client,
+    oneshot::Sender<Result<GetTileResponse, Status>>,
+);
+
+/// Fetch the tiles of the gRPC handlers of a worker thread
+async fn run_tile_jobs(service: GrpcService, mut jobs: mpsc::UnboundedReceiver<TileJob>) {
+    while let Some((req, client, response)) = jobs.recv().await {
+        let service = service.clone();
+        actix_rt::spawn(async move {
+            let _ = response.send(service.get_tile_response(req, &client).await);
+        });
+    }
+}
+
+/// gRPC handlers of a worker thread. The tile futures of the HTTP handlers cannot be sent to other threads,
+/// so the tiles are fetched by a task of the worker's actix system instead of by the handlers.
+#[derive(Clone)]
+struct TilesApi {
+    service: GrpcService,
+    jobs: mpsc::UnboundedSender<TileJob>,
+}
+
+impl TilesApi {
+    /// Must be called from within an actix runtime
+    fn new(service: GrpcService) -> Self {
+        let (jobs, receiver) = mpsc::unbounded_channel();
+        actix_rt::spawn(run_tile_jobs(service.clone(), receiver));
+        Self { service, jobs }
+    }
+
+    async fn get_tile_response(
+        &self,
+        req: GetTileRequest,
+        client: Client,
+    ) -> Result<GetTileResponse, Status> {
+        let (response, receiver) = oneshot::channel();
+        self.jobs
+            .send((req, client, response))
+            .map_err(|_| Status::unavailable("The gRPC worker is stopped"))?;
+        receiver
+            .await
+            .map_err(|_| Status::internal("The tile request was dropped"))?
+    }
+
+    /// Respond to each tile request of the stream in order, fetching several tiles at the same time
+    /// The stream is counted as a single request of the client, and each of its tiles as a tile
+    fn get_tiles_stream(
+        &self,
+        requests: impl Stream<Item = Result<GetTileRequest, Status>> + Send + 'static,
+        client: Client,
+    ) -> <Self as Tiles>::GetTilesStream {
+        let api = self.clone();
+        let tiles = requests
+            .map(move |req| {
+                let api = api.clone();
+                let client = client.clone();
+                async move {
+                    let req = req?;
+                    let failed = GetTileResponse {
+                        source_ids: req.source_ids.clone(),
+                        z: req.z,
+                        x: req.x,
+                        y: req.y,
+                        ..GetTileResponse::default()
+                    };
+                    // a failed tile must not end the stream with the other tiles
+                    Ok(api
+                        .get_tile_response(req, client)
+                        .await
+                        .unwrap_or_else(|e| GetTileResponse {
+                            error: Some(e.message().to_string()),
+                            ..failed
+                        }))
+                }
+            })
+            .buffered(self.service.stream_concurrency);
+        Box::pin(tiles)
+    }
+}
+
+#[tonic::async_trait]
+impl Tiles for TilesApi {
+    async fn list_sources(
+        &self,
+        request: Request<ListSourcesRequest>,
+    ) -> Result<Response<ListSourcesResponse>, Status> {
+        self.service.client(&request)?;
+        let catalog = self.service.catalog.load();
+        let tile_sources = self.service.sources.load();
+        let sources = self
+            .service
+            .tenants
+            .filter_catalog(&catalog.tiles, &tile_sources, None)
+            .into_iter()
+            .map(|(id, entry)| SourceInfo {
+                id,
+                content_type: entry.content_type,
+                name: entry.name,
+                description: entry.description,
+                attribution: entry.attribution,
+            })
+            .collect();
+        Ok(Response::new(ListSourcesResponse { sources }))
+    }
+
+    async fn get_tile_json(
+        &self,
+        request: Request<GetTileJsonRequest>,
+    ) -> Result<Response<GetTileJsonResponse>, Status> {
+        let client = self.service.client(&request)?;
+        let source_ids = request.into_inner().source_ids;
+        let sources = self.service.sources.load_full();
+        let resolved = self
+            .service
+            .resolve_sources(&client, &sources, &source_ids, "")?;
+        let src_list = sources
+            .get_sources(&resolved, None)
+            .map_err(|e| map_error(&e))?
+            .0;
+        let tiles_url = format!("{}/{source_ids}/{{z}}/{{x}}/{{y}}", self.service.public_url);
+        let limits = self.service.options.composite_tilejson;
+        let mut tilejson = merge_tilejson(&src_list, tiles_url, limits);
+        if let Some(variants) = sources.get_variants(&source_ids) {
+            tilejson
+                .other
+                .insert("variants".to_string(), variants.to_tilejson_value());
+            tilejson.name = Some(source_ids);
+        } else if sources.is_composite(&source_ids) {
+            tilejson.name = Some(source_ids);
+        }
+        let tilejson =
+            serde_json::to_string(&tilejson).map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(GetTileJsonResponse { tilejson }))
+    }
+
+    async fn get_tile(
+        &self,
+        request: Request<GetTileRequest>,
+    ) -> Result<Response<GetTileResponse>, Status> {
+        let client = self.service.client(&request)?;
+        let tile = self.get_tile_response(request.into_inner(), client).await?;
+        Ok(Response::new(tile))
+    }
+
+    type GetTilesStream = Pin<Box<dyn Stream<Item = Result<GetTileResponse, Status>> + Send>>;
+
+    async fn get_tiles(
+        &self,
+        request: Request<Streaming<GetTileRequest>>,
+    ) -> Result<Response<Self::GetTilesStream>, Status> {
+        let client = self.service.client(&request)?;
+        Ok(Response::new(
+            self.get_tiles_stream(request.into_inner(), client),
+        ))
+    }
+}
+
+/// Map the HTTP errors of the tile handlers to the gRPC status codes
+fn map_error(e: &actix_web::Error) -> Status {
+    let message = e.to_string();
+    match e.as_response_error().status_code() {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => {
+            Status::unavailable(message)
+        }
+        StatusCode::GATEWAY_TIMEOUT => Status::deadline_exceeded(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Map the rejections of the quotas to the gRPC status codes, like the `403` and `429` of the HTTP endpoints
+fn quota_error(e: QuotaExceeded) -> Status {
+    match e {
+        QuotaExceeded::MaxZoom(_) => Status::permission_denied(e.to_string()),
+        _ => Status::resource_exhausted(e.to_string()),
+    }
+}
+
+/// Bind the gRPC address, and serve the API from `workers` threads sharing the listener.
+/// Each thread runs its own actix system, like the workers of the HTTP server.
+pub fn start_grpc(config: &GrpcConfig, service: &GrpcService, workers: usize) -> MartinResult<()> {
+    let addr = &config.listen_addresses;
+    let bind_err = |e| BindingError(e, addr.clone());
+    let listener = TcpListener::bind(addr).map_err(bind_err)?;
+    listener.set_nonblocking(true).map_err(bind_err)?;
+    for idx in 0..workers {
+        let listener = listener.try_clone().map_err(bind_err)?;
+        let service = service.clone();
+        std::thread::Builder::new()
+            .name(format!("martin-grpc-{idx}"))
+            .spawn(move || actix_rt::System::new().block_on(serve(listener, service)))
+            .map_err(bind_err)?;
+    }
+    info!("Serving the gRPC API on {addr}");
+    Ok(())
+}
+
+async fn serve(listener: TcpListener, service: GrpcService) {
+    let incoming = match tokio::net::TcpListener::from_std(listener) {
+        Ok(v) => TcpListenerStream::new(v),
+        Err(e) => return error!("Unable to use the gRPC listener: {e}"),
+    };
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(TilesServer::new(TilesApi::new(service)))
+        .serve_with_incoming(incoming)
+        .await
+    {
+        error!("gRPC server failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use tonic::transport::server::TcpConnectInfo;
+
+    use super::*;
+    use crate::config::tests::parse_cfg;
+    use crate::Builder;
+
+    async fn api() -> TilesApi {
+        api_with("").await
+    }
+
+    /// The API of the `cities` source, with additional server settings
+    async fn api_with(srv: &str) -> TilesApi {
+        api_from(&format!(
+            "{srv}\n{}",
+            indoc! {"
+                mbtiles:
+                  sources:
+                    cities: ../tests/fixtures/mbtiles/world_cities.mbtiles
+            "}
+        ))
+        .await
+    }
+
+    async fn api_from(yaml: &str) -> TilesApi {
+        let config = parse_cfg(yaml);
+        let martin = Builder::from_config(config).build().await.unwrap();
+        let data = martin.into_data().unwrap();
+        let grpc = GrpcConfig {
+            listen_addresses: "127.0.0.1:0".to_string(),
+            stream_concurrency: Some(2),
+        };
+        TilesApi::new(data.grpc_service(&grpc, Some("http://tiles.example.com/")))
+    }
+
+    fn tile(source_ids: &str, z: u32, x: u32, y: u32) -> GetTileRequest {
+        GetTileRequest {
+            source_ids: source_ids.to_string(),
+            z,
+            x,
+            y,
+            query: String::new(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn sources_and_tilejson() {
+        let api = api().await;
+        let sources = api
+            .list_sources(Request::new(ListSourcesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .sources;
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].id, "cities");
+        assert_eq!(sources[0].content_type, "application/x-protobuf");
+
+        let req = GetTileJsonRequest {
+            source_ids: "cities".to_string(),
+        };
+        let tilejson = api.get_tile_json(Request::new(req)).await.unwrap();
+        let tilejson: serde_json::Value =
+            serde_json::from_str(&tilejson.into_inner().tilejson).unwrap();
+        assert_eq!(
+            tilejson["tiles"][0],
+            "http://tiles.example.com/cities/{z}/{x}/{y}"
+        );
+
+        let req = GetTileJsonRequest {
+            source_ids: "missing".to_string(),
+        };
+        let err = api.get_tile_json(Request::new(req)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+    }
+
+    #[actix_rt::test]
+    async fn tiles() {
+        let api = api().await;
+        let resp = api.get_tile(Request::new(tile("cities", 0, 0, 0))).await;
+        let resp = resp.unwrap().into_inner();
+        assert!(!resp.data.is_empty());
+        assert_eq!(resp.content_type, "application/x-protobuf");
+        // the tiles are sent uncompressed
+        assert_ne!(resp.data[..2], [0x1f, 0x8b]);
+
+        let err = api.get_tile(Request::new(tile("cities", 31, 0, 0))).await;
+        assert_eq!(err.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let requests = [
+            tile("cities", 0, 0, 0),
+            tile("cities", 1, 5, 0),
+            tile("cities", 6, 0, 0),
+        ];
+        let stream = futures::stream::iter(requests.map(Ok));
+        let tiles: Vec<_> = api
+            .get_tiles_stream(stream, Client::default())
+            .collect()
+            .await;
+        let tiles: Vec<_> = tiles.into_iter().map(Result::unwrap).collect();
+        assert_eq!(tiles.len(), 3);
+        assert!(tiles[0].error.is_none() && !tiles[0].data.is_empty());
+        // a failed tile is reported in its response, and the stream goes on
+        assert_eq!((tiles[1].z, tiles[1].x), (1, 5));
+        assert!(tiles[1].error.is_some());
+        assert!(tiles[2].error.is_none() && tiles[2].data.is_empty());
+    }
+
+    /// A request from the network, with the peer address and the metadata of the client
+    fn request<T>(message: T, peer: &str, api_key: Option<&'static str>) -> Request<T> {
+        let mut req = Request::new(message);
+        req.extensions_mut().insert(TcpConnectInfo {
+            local_addr: None,
+            remote_addr: Some(peer.parse().unwrap()),
+        });
+        if let Some(key) = api_key {
+            req.metadata_mut().insert("x-api-key", key.parse().unwrap());
+        }
+        req
+    }
+
+    #[actix_rt::test]
+    async fn ip_rules_and_quotas() {
+        let api = api_with(indoc! {"
+            ip_filter:
+              deny: [10.0.0.13/32]
+              sources:
+                cities:
+                  allow: [10.0.0.0/8]
+            throttle:
+              anonymous_max_zoom: 2
+              anonymous_daily_requests: 4
+              api_keys:
+                partner: { monthly_tiles: 3 }
+        "})
+        .await;
+        let peer = "10.1.2.3:5000";
+        let code =
+            |r: Result<Response<GetTileResponse>, Status>| r.map(|_| ()).map_err(|e| e.code());
+
+        // the rules of the server, and of the source
+        let req = request(ListSourcesRequest {}, "10.0.0.13:5000", None);
+        let err = api.list_sources(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let req = request(tile("cities", 0, 0, 0), "192.168.1.1:5000", None);
+        assert_eq!(
+            code(api.get_tile(req).await),
+            Err(tonic::Code::PermissionDenied)
+        );
+        let req = GetTileJsonRequest {
+            source_ids: "cities".to_string(),
+        };
+        let err = api
+            .get_tile_json(request(req, "192.168.1.1:5000", None))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+        // the quotas of the anonymous clients, and of an API key
+        let req = request(tile("cities", 2, 0, 0), peer, None);
+        assert_eq!(code(api.get_tile(req).await), Ok(()));
+        let req = request(tile("cities", 3, 0, 0), peer, None);
+        assert_eq!(
+            code(api.get_tile(req).await),
+            Err(tonic::Code::PermissionDenied)
+        );
+        let req = request(tile("cities", 6, 0, 0), peer, Some("partner"));
+        assert_eq!(code(api.get_tile(req).await), Ok(()));
+
+        // a stream is a single request, with each of its tiles counted
+        let requests = [
+            tile("cities", 6, 0, 0),
+            tile("cities", 6, 0, 1),
+            tile("cities", 6, 0, 2),
+        ];
+        let stream = futures::stream::iter(requests.map(Ok));
+        let req = request(stream, peer, Some("partner"));
+        let client = api.service.client(&req).unwrap();
+        let tiles: Vec<_> = api
+            .get_tiles_stream(req.into_inner(), client)
+            .collect()
+            .await;
+        let errors: Vec<_> = tiles.into_iter().filter_map(|t| t.unwrap().error).collect();
+        assert_eq!(errors, ["The monthly tile quota has been used up"]);
+
+        // the daily requests of the anonymous clients, including the calls rejected by the source rules
+        let req = request(ListSourcesRequest {}, peer, None);
+        let err = api.list_sources(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[actix_rt::test]
+    async fn tenants_and_limits() {
+        let api = api_from(indoc! {"
+            tenants:
+              acme:
+                sources: [private]
+            composites:
+              mixed: [cities, private]
+            tile_size_limit:
+              sources:
+                cities: { max_kb: 1, policy: no_content }
+            mbtiles:
+              sources:
+                cities: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                private: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        "})
+        .await;
+
+        // the tenant sources are not served or listed, even as members of a composite
+        let sources = api
+            .list_sources(Request::new(ListSourcesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .sources;
+        let ids: Vec<_> = sources.iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(ids, ["cities"]);
+        for source_ids in ["private", "mixed", "cities,private"] {
+            let req = GetTileJsonRequest {
+                source_ids: source_ids.to_string(),
+            };
+            let err = api.get_tile_json(Request::new(req)).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::NotFound, "{source_ids}");
+            let err = api.get_tile(Request::new(tile(source_ids, 0, 0, 0))).await;
+            assert_eq!(
+                err.unwrap_err().code(),
+                tonic::Code::NotFound,
+                "{source_ids}"
+            );
+        }
+
+        // the oversized tiles are dropped like by the HTTP endpoints
+        let resp = api.get_tile(Request::new(tile("cities", 0, 0, 0))).await;
+        let resp = resp.unwrap().into_inner();
+        assert!(resp.data.is_empty());
+        assert_eq!(resp.content_type, "application/x-protobuf");
+        assert!(resp.error.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn web_apps() {
+        let api = api_with(indoc! {"
+            referer_filter:
+              cities:
+                allow: [https://app.example.com]
+        "})
+        .await;
+        let get = |origin: Option<&'static str>| {
+            let mut req = Request::new(tile("cities", 0, 0, 0));
+            if let Some(origin) = origin {
+                req.metadata_mut().insert("origin", origin.parse().unwrap());
+            }
+            api.get_tile(req)
+        };
+        assert!(get(Some("https://app.example.com")).await.is_ok());
+        let err = get(Some("https://evil.com")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = get(None).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn status_codes() {
+        use actix_web::error::{ErrorBadRequest, ErrorNotFound, ErrorServiceUnavailable};
+        assert_eq!(
+            map_error(&ErrorBadRequest("zoom")).code(),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(map_error(&ErrorNotFound("")).code(), tonic::Code::NotFound);
+        assert_eq!(
+            map_error(&ErrorServiceUnavailable("")).code(),
+            tonic::Code::Unavailable
+        );
+    }
+}
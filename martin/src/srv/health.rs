@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse};
+use log::{info, warn};
+use serde::Serialize;
+
+/// Whether this instance should receive traffic, reported by `/health/ready`.
+/// The server only starts listening once its sources are discovered and its pools are connected,
+/// so it is ready unless it is draining before a shutdown, or reloading its sources.
+#[derive(Clone, Debug, Default)]
+pub struct Readiness(Arc<ReadinessState>);
+
+#[derive(Debug, Default)]
+struct ReadinessState {
+    draining: AtomicBool,
+    reloads: AtomicUsize,
+}
+
+/// Keeps the instance not ready until the reload it was created for is done
+#[must_use]
+pub struct ReloadGuard(Readiness);
+
+impl Drop for ReloadGuard {
+    fn drop(&mut self) {
+        self.0 .0.reloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Readiness {
+    /// Report the instance as not ready until the returned guard is dropped
+    pub fn begin_reload(&self) -> ReloadGuard {
+        self.0.reloads.fetch_add(1, Ordering::Relaxed);
+        ReloadGuard(self.clone())
+    }
+
+    /// Report the instance as not ready for the rest of its life, e.g. once a shutdown was requested
+    pub fn start_draining(&self) {
+        self.0.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// `None` if the instance is ready, otherwise the reason it is not
+    #[must_use]
+    pub fn not_ready_reason(&self) -> Option<&'static str> {
+        if self.0.draining.load(Ordering::Relaxed) {
+            Some("draining")
+        } else if self.0.reloads.load(Ordering::Relaxed) > 0 {
+            Some("reloading")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReadinessStatus {
+    status: &'static str,
+}
+
+/// Return 200 OK as long as the process is up. Used for liveness probes.
+#[route("/health/live", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_health_live() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .body("OK")
+}
+
+/// Return 200 OK if the instance should receive traffic, or 503 while it is draining
+/// before a shutdown or reloading its sources. Used for readiness probes.
+#[route("/health/ready", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_health_ready(readiness: Data<Readiness>) -> HttpResponse {
+    let reason = readiness.not_ready_reason();
+    let mut response = if reason.is_none() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(ReadinessStatus {
+            status: reason.unwrap_or("ready"),
+        })
+}
+
+/// Stop the server on `SIGINT` or `SIGTERM` after reporting it as not ready for `drain`,
+/// so that the load balancers stop sending new requests before the listeners are closed.
+/// The signal handling of the server must be disabled.
+pub fn drain_on_shutdown(server: ServerHandle, readiness: Readiness, drain: Duration) {
+    actix_rt::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!(
+            "Shutting down after draining for {}s, /health/ready now returns 503",
+            drain.as_secs()
+        );
+        readiness.start_draining();
+        actix_rt::time::sleep(drain).await;
+        server.stop(true).await;
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = actix_rt::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        Err(e) => {
+            warn!("Unable to listen for SIGTERM, only SIGINT starts the drain: {e}");
+            let _ = actix_rt::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = actix_rt::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness() {
+        let readiness = Readiness::default();
+        assert_eq!(readiness.not_ready_reason(), None);
+        let first = readiness.begin_reload();
+        let second = readiness.begin_reload();
+        assert_eq!(readiness.not_ready_reason(), Some("reloading"));
+        drop(first);
+        assert_eq!(readiness.not_ready_reason(), Some("reloading"));
+        drop(second);
+        assert_eq!(readiness.not_ready_reason(), None);
+        readiness.start_draining();
+        assert_eq!(readiness.not_ready_reason(), Some("draining"));
+    }
+}
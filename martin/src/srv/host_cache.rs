@@ -0,0 +1,429 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileData;
+use crate::utils::{CacheKey, TileExpiration};
+use crate::MartinError::HostCacheError;
+use crate::MartinResult;
+
+pub const HOST_CACHE_SIZE_MB_DEFAULT: u64 = 256;
+pub const HOST_CACHE_SLOT_SIZE_KB_DEFAULT: u32 = 64;
+
+/// Identifies the file layout, so that a file written by an incompatible version is reset
+const MAGIC: &[u8; 8] = b"MRTNHC01";
+/// The file header is padded, so that the slots are aligned to the memory pages
+const FILE_HEADER_SIZE: u64 = 4096;
+/// Checksum, key hash, expiration time, key length, and data length
+const SLOT_HEADER_SIZE: usize = 32;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HostCacheConfig {
+    /// Cache file shared by all processes, preferably on a memory-backed file system like `/dev/shm`
+    pub path: PathBuf,
+    /// Size of the cache file in megabytes [default: 256]
+    pub size_mb: Option<u64>,
+    /// Size of each cache entry in kilobytes. Larger tiles are not stored. [default: 64]
+    pub slot_size_kb: Option<u32>,
+}
+
+/// Tile cache stored in a file that is shared by all Martin processes on the same host.
+///
+/// All worker threads of one process already share the in-memory cache. This cache avoids keeping
+/// a copy of the same hot tiles in each process when several Martin processes run on one host.
+/// On a memory-backed file system the file lives in the shared page cache, so reading it is
+/// a memory copy without any disk access. The file is divided into fixed-size slots,
+/// and each tile is stored in the slot selected by the hash of its key, replacing the previous one.
+/// Every slot has a checksum, so a slot that is being written by another process is a cache miss.
+#[derive(Debug, Clone)]
+pub struct HostCache {
+    file: Arc<File>,
+    path: PathBuf,
+    slot_size: usize,
+    slot_count: u64,
+    expiration: TileExpiration,
+}
+
+impl HostCache {
+    pub fn new(config: &HostCacheConfig, expiration: TileExpiration) -> MartinResult<Self> {
+        let size_mb = config.size_mb.unwrap_or(HOST_CACHE_SIZE_MB_DEFAULT);
+        let slot_size_kb = config
+            .slot_size_kb
+            .unwrap_or(HOST_CACHE_SLOT_SIZE_KB_DEFAULT)
+            .max(1);
+        let slot_size = slot_size_kb as usize * 1024;
+        let slot_count = (size_mb * 1024 / u64::from(slot_size_kb)).max(1);
+        let path = config.path.clone();
+        let file =
+            open_file(&path, slot_size, slot_count).map_err(|e| HostCacheError(e, path.clone()))?;
+        info!(
+            "Using host tile cache at {} with {slot_count} entries of {slot_size_kb}KB",
+            path.display()
+        );
+        Ok(Self {
+            file: Arc::new(file),
+            path,
+            slot_size,
+            slot_count,
+            expiration,
+        })
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &CacheKey) -> Option<TileData> {
+        let key = cache_key(key);
+        match self.read_slot(&key) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Unable to read {key} from the host cache: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &CacheKey, data: &TileData) {
+        let expires_at = self
+            .expiration
+            .cache_ttl(key, data)
+            .map_or(0, |age| now() + age.as_secs());
+        let key = cache_key(key);
+        if SLOT_HEADER_SIZE + key.len() + data.len() > self.slot_size {
+            return;
+        }
+        if let Err(e) = self.write_slot(&key, data, expires_at) {
+            warn!("Unable to store {key} in the host cache: {e}");
+        }
+    }
+
+    /// Remove all cached tiles of the given sources, including the `GeoJSON` tiles that use them
+    pub fn purge(&self, source_ids: &[String]) -> MartinResult<()> {
+        for slot in 0..self.slot_count {
+            let offset = self.offset(slot);
+            let key = self
+                .read_key(offset)
+                .map_err(|e| HostCacheError(e, self.path.clone()))?;
+            let Some(key) = key else {
+                continue;
+            };
+            let ids = match key.split_once('/') {
+                Some(("tile" | "geojson", rest)) => rest.split('/').next().unwrap_or_default(),
+                _ => continue,
+            };
+            if ids.split(',').any(|id| source_ids.iter().any(|s| s == id)) {
+                write_at(&self.file, &[0; 8], offset)
+                    .map_err(|e| HostCacheError(e, self.path.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn offset(&self, slot: u64) -> u64 {
+        FILE_HEADER_SIZE + slot * self.slot_size as u64
+    }
+
+    fn slot_offset(&self, key: &str) -> (u64, u64) {
+        let hash = fnv_hash(key.as_bytes());
+        (hash, self.offset(hash % self.slot_count))
+    }
+
+    fn read_slot(&self, key: &str) -> io::Result<Option<TileData>> {
+        let (hash, offset) = self.slot_offset(key);
+        let mut header = [0; SLOT_HEADER_SIZE];
+        read_at(&self.file, &mut header, offset)?;
+        let Some(slot) = SlotHeader::parse(&header) else {
+            return Ok(None);
+        };
+        if slot.key_hash != hash
+            || slot.key_len != key.len()
+            || SLOT_HEADER_SIZE + slot.key_len + slot.data_len > self.slot_size
+            || (slot.expires_at != 0 && slot.expires_at <= now())
+        {
+            return Ok(None);
+        }
+        let mut body = vec![0; slot.key_len + slot.data_len];
+        read_at(&self.file, &mut body, offset + SLOT_HEADER_SIZE as u64)?;
+        if slot.checksum != checksum(&header, &body) || &body[..slot.key_len] != key.as_bytes() {
+            return Ok(None);
+        }
+        body.drain(..slot.key_len);
+        Ok(Some(body))
+    }
+
+    fn write_slot(&self, key: &str, data: &[u8], expires_at: u64) -> io::Result<()> {
+        let (hash, offset) = self.slot_offset(key);
+        let mut buf = Vec::with_capacity(SLOT_HEADER_SIZE + key.len() + data.len());
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&hash.to_le_bytes());
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+        buf.extend_from_slice(&to_u32(key.len()).to_le_bytes());
+        buf.extend_from_slice(&to_u32(data.len()).to_le_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(data);
+        let (header, body) = buf.split_at(SLOT_HEADER_SIZE);
+        let checksum = checksum(header, body);
+        buf[..8].copy_from_slice(&checksum.to_le_bytes());
+        // A single write, so that other processes see either the old or the new slot, or a checksum mismatch
+        write_at(&self.file, &buf, offset)
+    }
+
+    /// Read the key of a slot without validating the data, used to find the slots to purge
+    fn read_key(&self, offset: u64) -> io::Result<Option<String>> {
+        let mut header = [0; SLOT_HEADER_SIZE];
+        read_at(&self.file, &mut header, offset)?;
+        let Some(slot) = SlotHeader::parse(&header) else {
+            return Ok(None);
+        };
+        if SLOT_HEADER_SIZE + slot.key_len > self.slot_size {
+            return Ok(None);
+        }
+        let mut key = vec![0; slot.key_len];
+        read_at(&self.file, &mut key, offset + SLOT_HEADER_SIZE as u64)?;
+        Ok(String::from_utf8(key).ok())
+    }
+}
+
+struct SlotHeader {
+    checksum: u64,
+    key_hash: u64,
+    expires_at: u64,
+    key_len: usize,
+    data_len: usize,
+}
+
+impl SlotHeader {
+    /// Parse the slot header, or return `None` if the slot is empty or was purged
+    fn parse(header: &[u8; SLOT_HEADER_SIZE]) -> Option<Self> {
+        let u64_at = |pos: usize| u64::from_le_bytes(header[pos..pos + 8].try_into().unwrap());
+        let u32_at = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+        let slot = Self {
+            checksum: u64_at(0),
+            key_hash: u64_at(8),
+            expires_at: u64_at(16),
+            key_len: u32_at(24) as usize,
+            data_len: u32_at(28) as usize,
+        };
+        (slot.checksum != 0 && slot.key_len != 0).then_some(slot)
+    }
+}
+
+/// Open the cache file, and reset it unless it was created with the same layout
+fn open_file(path: &Path, slot_size: usize, slot_count: u64) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&(slot_size as u64).to_le_bytes());
+    header.extend_from_slice(&slot_count.to_le_bytes());
+    let file_size = FILE_HEADER_SIZE + slot_count * slot_size as u64;
+
+    let mut existing = vec![0; header.len()];
+    if file.metadata()?.len() == file_size
+        && read_at(&file, &mut existing, 0).is_ok()
+        && existing == header
+    {
+        return Ok(file);
+    }
+    if file.metadata()?.len() > 0 {
+        warn!(
+            "Host cache {} was created with different settings, clearing it",
+            path.display()
+        );
+    }
+    file.set_len(0)?;
+    file.set_len(file_size)?;
+    write_at(&file, &header, 0)?;
+    Ok(file)
+}
+
+fn cache_key(key: &CacheKey) -> String {
+    let (kind, ids, xyz, query) = match key {
+        CacheKey::Tile(id, xyz, query) => ("tile", id, xyz, query),
+        CacheKey::GeoJson(ids, xyz, query) => ("geojson", ids, xyz, query),
+        CacheKey::UtfGrid(ids, xyz, query) => ("utfgrid", ids, xyz, query),
+        CacheKey::Encoded(ids, xyz, query, enc) => {
+            (enc.content_encoding().unwrap_or("tile"), ids, xyz, query)
+        }
+    };
+    let mut result = format!("{kind}/{ids}/{}/{}/{}", xyz.z, xyz.x, xyz.y);
+    if let Some(query) = query.as_ref().filter(|q| !q.is_empty()) {
+        result.push('?');
+        result.push_str(query);
+    }
+    result
+}
+
+/// The checksum of everything in the slot after the checksum itself. Never zero, which marks empty slots.
+fn checksum(header: &[u8], body: &[u8]) -> u64 {
+    let hash = fnv_hash_from(fnv_hash(&header[8..]), body);
+    hash.max(1)
+}
+
+/// FNV-1a is used because all processes sharing the file must compute the same hashes,
+/// and the standard hasher output is not guaranteed to be stable between Rust releases.
+fn fnv_hash(data: &[u8]) -> u64 {
+    fnv_hash_from(0xcbf2_9ce4_8422_2325, data)
+}
+
+fn fnv_hash_from(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn to_u32(value: usize) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt as _;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt as _;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::source::UrlQuery;
+    use crate::TileCoord;
+
+    fn new_cache(name: &str, size_mb: u64, expiration: TileExpiration) -> HostCache {
+        let path = std::env::temp_dir().join(format!(
+            "martin-host-cache-{name}-{}.bin",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let cfg = HostCacheConfig {
+            path,
+            size_mb: Some(size_mb),
+            slot_size_kb: Some(1),
+        };
+        HostCache::new(&cfg, expiration).unwrap()
+    }
+
+    #[test]
+    fn shared_between_instances() {
+        let cache = new_cache("shared", 1, TileExpiration::default());
+        let xyz = TileCoord { z: 3, x: 2, y: 1 };
+        let key = CacheKey::tile("roads", xyz, None);
+        assert_eq!(cache.get(&key), None);
+        cache.insert(&key, &vec![1, 2, 3]);
+        assert_eq!(cache.get(&key), Some(vec![1, 2, 3]));
+        assert_eq!(
+            cache.get(&CacheKey::tile("roads", xyz, Some(&UrlQuery::new()))),
+            Some(vec![1, 2, 3])
+        );
+        let query = [("lang".to_string(), "en".to_string())].into();
+        assert_eq!(cache.get(&CacheKey::tile("roads", xyz, Some(&query))), None);
+
+        // Another process opening the same file with the same settings sees the cached tiles
+        let cfg = HostCacheConfig {
+            path: cache.path.clone(),
+            size_mb: Some(1),
+            slot_size_kb: Some(1),
+        };
+        let other = HostCache::new(&cfg, TileExpiration::default()).unwrap();
+        assert_eq!(other.get(&key), Some(vec![1, 2, 3]));
+
+        // Tiles that do not fit into a slot are not stored
+        let large = CacheKey::tile("large", xyz, None);
+        other.insert(&large, &vec![0; 1024]);
+        assert_eq!(cache.get(&large), None);
+
+        // Different settings reset the file
+        let cfg = HostCacheConfig {
+            slot_size_kb: Some(2),
+            ..cfg
+        };
+        let other = HostCache::new(&cfg, TileExpiration::default()).unwrap();
+        assert_eq!(other.get(&key), None);
+        std::fs::remove_file(&cache.path).unwrap();
+    }
+
+    #[test]
+    fn purge_and_expire() {
+        let expiration = TileExpiration::new(Some(&BTreeMap::from([(10, 0)])));
+        let cache = new_cache("purge", 4, expiration);
+        let xyz = TileCoord { z: 3, x: 2, y: 1 };
+        let key_a = CacheKey::tile("a", xyz, None);
+        let key_b = CacheKey::tile("b", xyz, None);
+        let key_geojson = CacheKey::GeoJson("b,a".to_string(), xyz, None);
+        cache.insert(&key_a, &vec![1]);
+        cache.insert(&key_b, &vec![2]);
+        cache.insert(&key_geojson, &vec![3]);
+        cache.purge(&["a".to_string()]).unwrap();
+        assert_eq!(cache.get(&key_a), None);
+        assert_eq!(cache.get(&key_geojson), None);
+        assert_eq!(cache.get(&key_b), Some(vec![2]));
+
+        // Zoom 10 and above expire immediately
+        let key = CacheKey::tile("a", TileCoord { z: 10, x: 0, y: 0 }, None);
+        cache.insert(&key, &vec![4]);
+        assert_eq!(cache.get(&key), None);
+        std::fs::remove_file(&cache.path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_slot_is_a_miss() {
+        let cache = new_cache("corrupt", 1, TileExpiration::default());
+        let key = CacheKey::tile("a", TileCoord { z: 0, x: 0, y: 0 }, None);
+        cache.insert(&key, &vec![1, 2, 3]);
+        let (_, offset) = cache.slot_offset(&cache_key(&key));
+        let pos = offset + SLOT_HEADER_SIZE as u64 + cache_key(&key).len() as u64;
+        write_at(&cache.file, &[9], pos).unwrap();
+        assert_eq!(cache.get(&key), None);
+        std::fs::remove_file(&cache.path).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpResponse};
+use futures::future::LocalBoxFuture;
+
+/// Check if the path is one of the operational routes, i.e. the `/_/` admin endpoints or `/metrics`
+#[must_use]
+pub fn is_internal_route(path: &str) -> bool {
+    path.starts_with("/_/") || path == "/metrics"
+}
+
+/// Middleware that serves the admin endpoints and the metrics only on the internal listeners,
+/// responding with `404 Not Found` on all other listeners.
+/// All routes are served everywhere until the internal listener addresses are set.
+#[derive(Debug, Clone, Default)]
+pub struct InternalRoutes(Arc<OnceLock<Vec<SocketAddr>>>);
+
+impl InternalRoutes {
+    /// Set the addresses of the internal listeners once they are bound
+    pub fn set_listeners(&self, addrs: Vec<SocketAddr>) {
+        let _ = self.0.set(addrs);
+    }
+
+    #[must_use]
+    pub fn is_allowed(&self, local_addr: SocketAddr, path: &str) -> bool {
+        self.0.get().map_or(true, |addrs| {
+            addrs.contains(&local_addr) || !is_internal_route(path)
+        })
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for InternalRoutes
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = InternalRoutesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(InternalRoutesMiddleware {
+            service,
+            routes: self.clone(),
+        }))
+    }
+}
+
+pub struct InternalRoutesMiddleware<S> {
+    service: S,
+    routes: InternalRoutes,
+}
+
+impl<S, B> Service<ServiceRequest> for InternalRoutesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let local_addr = req.app_config().local_addr();
+        if !self.routes.is_allowed(local_addr, req.path()) {
+            let resp = HttpResponse::NotFound().finish().map_into_right_body();
+            return Box::pin(ready(Ok(req.into_response(resp))));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_listeners() {
+        let public: SocketAddr = "0.0.0.0:3000".parse().unwrap();
+        let internal: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let routes = InternalRoutes::default();
+        assert!(routes.is_allowed(public, "/_/reload"));
+        assert!(routes.is_allowed(public, "/metrics"));
+
+        routes.set_listeners(vec![internal]);
+        assert!(!routes.is_allowed(public, "/_/reload"));
+        assert!(!routes.is_allowed(public, "/metrics"));
+        assert!(routes.is_allowed(public, "/metrics_layer/0/0/0"));
+        assert!(routes.is_allowed(public, "/catalog"));
+        assert!(routes.is_allowed(internal, "/_/reload"));
+        assert!(routes.is_allowed(internal, "/metrics"));
+        assert!(routes.is_allowed(internal, "/catalog"));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorForbidden;
+use actix_web::http::header::HeaderMap;
+use actix_web::{Error, HttpMessage as _, HttpRequest, HttpResponse, Result as ActixResult};
+use futures::future::LocalBoxFuture;
+use ipnet::IpNet;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IpRules {
+    /// If set, only clients from these networks are allowed
+    pub allow: Option<Vec<IpNet>>,
+    /// Clients from these networks are always rejected
+    pub deny: Option<Vec<IpNet>>,
+}
+
+impl IpRules {
+    #[must_use]
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if contains(self.deny.as_ref(), ip) {
+            return false;
+        }
+        self.allow.is_none() || contains(self.allow.as_ref(), ip)
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IpFilterConfig {
+    /// Rules applied to all requests
+    #[serde(flatten)]
+    pub rules: IpRules,
+    /// Additional rules for individual sources, keyed by the ID of a tile source, a sprite, a style, or a font
+    pub sources: Option<BTreeMap<String, IpRules>>,
+}
+
+impl IpFilterConfig {
+    /// Check if the client may access the server at all
+    #[must_use]
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.rules.is_allowed(ip)
+    }
+
+    /// Check if the client may access all of the comma-separated source IDs, e.g. `src1,src2`
+    #[must_use]
+    pub fn is_source_allowed(&self, ip: &IpAddr, source_ids: &str) -> bool {
+        let Some(sources) = &self.sources else {
+            return true;
+        };
+        source_ids
+            .split(',')
+            .filter_map(|id| sources.get(id))
+            .all(|rules| rules.is_allowed(ip))
+    }
+}
+
+/// Client address of a request, stored in the request extensions by the [`IpFilter`] middleware,
+/// so that the handlers can check the rules of the sources once they know the requested IDs
+#[derive(Debug, Clone)]
+struct ClientAddr {
+    filter: Arc<IpFilterInner>,
+    ip: IpAddr,
+}
+
+/// Check the rules of the comma-separated source, sprite, style, or font IDs requested by a client,
+/// responding with `403 Forbidden` if any of them do not allow the client. Requests are not checked
+/// if the server is not wrapped with the [`IpFilter`] middleware.
+pub(crate) fn check_source_access(req: &HttpRequest, source_ids: &str) -> ActixResult<()> {
+    let Some(client) = req.extensions().get::<ClientAddr>().cloned() else {
+        return Ok(());
+    };
+    if client
+        .filter
+        .config
+        .is_source_allowed(&client.ip, source_ids)
+    {
+        Ok(())
+    } else {
+        debug!("Rejected request for {source_ids} from {}", client.ip);
+        Err(ErrorForbidden("Access denied"))
+    }
+}
+
+/// Find the client address, skipping over the `trusted_proxies` in the `X-Forwarded-For` chain.
+/// The header is ignored if no proxies are trusted.
+#[must_use]
+pub fn client_ip(
+    trusted_proxies: Option<&Vec<IpNet>>,
+    peer: IpAddr,
+    headers: &HeaderMap,
+) -> IpAddr {
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok());
+    forwarded_client_ip(trusted_proxies, peer, forwarded)
+}
+
+/// Same as [`client_ip`], with the values of the `X-Forwarded-For` headers or gRPC metadata
+fn forwarded_client_ip<'a>(
+    trusted_proxies: Option<&Vec<IpNet>>,
+    peer: IpAddr,
+    forwarded: impl Iterator<Item = &'a str>,
+) -> IpAddr {
+    if !contains(trusted_proxies, &peer) {
+        return peer;
+    }
+    let forwarded = forwarded
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+        // A malformed value cannot be trusted, so stop at the last known address
+        let Ok(ip) = ip else { break };
+        client = ip;
+        if !contains(trusted_proxies, &ip) {
+            break;
+        }
+    }
+    client
+}
+
+fn contains(nets: Option<&Vec<IpNet>>, ip: &IpAddr) -> bool {
+    nets.map_or(false, |nets| nets.iter().any(|net| net.contains(ip)))
+}
+
+/// Middleware that rejects requests with `403 Forbidden` based on the client IP address.
+/// The rules of the individual sources are checked by the handlers, see [`IpFilterConfig::sources`].
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter(Arc<IpFilterInner>);
+
+#[derive(Debug, Default)]
+struct IpFilterInner {
+    config: IpFilterConfig,
+    /// Proxies allowed to report the client address with the `X-Forwarded-For` header,
+    /// i.e. the `trusted_proxies` of the server
+    trusted_proxies: Option<Vec<IpNet>>,
+}
+
+impl IpFilter {
+    #[must_use]
+    pub fn new(config: IpFilterConfig, trusted_proxies: Option<&Vec<IpNet>>) -> Self {
+        Self(Arc::new(IpFilterInner {
+            config,
+            trusted_proxies: trusted_proxies.cloned(),
+        }))
+    }
+
+    /// Find the client address of a gRPC call from its peer and its `x-forwarded-for` metadata
+    #[cfg(feature = "grpc")]
+    pub(crate) fn client_ip<'a>(
+        &self,
+        peer: IpAddr,
+        forwarded: impl Iterator<Item = &'a str>,
+    ) -> IpAddr {
+        forwarded_client_ip(self.0.trusted_proxies.as_ref(), peer, forwarded)
+    }
+
+    #[cfg(feature = "grpc")]
+    pub(crate) fn config(&self) -> &IpFilterConfig {
+        &self.0.config
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for IpFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = IpFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IpFilterMiddleware {
+            service,
+            filter: self.0.clone(),
+        }))
+    }
+}
+
+pub struct IpFilterMiddleware<S> {
+    service: S,
+    filter: Arc<IpFilterInner>,
+}
+
+impl<S, B> Service<ServiceRequest> for IpFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(peer) = req.peer_addr() {
+            let filter = &self.filter;
+            let ip = client_ip(filter.trusted_proxies.as_ref(), peer.ip(), req.headers());
+            if !filter.config.is_allowed(&ip) {
+                debug!("Rejected request for {} from {ip}", req.path());
+                let resp = HttpResponse::Forbidden().finish().map_into_right_body();
+                return Box::pin(ready(Ok(req.into_response(resp))));
+            }
+            req.extensions_mut().insert(ClientAddr {
+                filter: filter.clone(),
+                ip,
+            });
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use indoc::indoc;
+
+    use super::*;
+
+    fn ip(v: &str) -> IpAddr {
+        v.parse().unwrap()
+    }
+
+    fn cfg() -> IpFilterConfig {
+        serde_yaml::from_str(indoc! {"
+            allow: [10.0.0.0/8, 192.168.0.0/16]
+            deny: [10.0.0.13/32]
+            sources:
+              internal:
+                allow: [10.0.0.0/8]
+        "})
+        .unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let cfg = cfg();
+        assert!(cfg.is_allowed(&ip("10.1.2.3")));
+        assert!(!cfg.is_allowed(&ip("10.0.0.13")));
+        assert!(!cfg.is_allowed(&ip("8.8.8.8")));
+        assert!(cfg.is_source_allowed(&ip("192.168.5.5"), "public"));
+        assert!(!cfg.is_source_allowed(&ip("192.168.5.5"), "internal"));
+        assert!(!cfg.is_source_allowed(&ip("192.168.5.5"), "public,internal"));
+        assert!(cfg.is_source_allowed(&ip("10.1.2.3"), "public,internal"));
+    }
+
+    #[test]
+    fn renamed_sources() {
+        use crate::aliases::{rename_keys, SourceAliases};
+
+        let mut cfg = cfg();
+        let aliases = SourceAliases::from([("internal".to_string(), "private".to_string())]);
+        rename_keys(cfg.sources.as_mut().unwrap(), &aliases);
+        assert!(!cfg.is_source_allowed(&ip("192.168.5.5"), "private"));
+        assert!(!cfg.is_source_allowed(&ip("192.168.5.5"), "internal"));
+        assert!(cfg.is_source_allowed(&ip("10.1.2.3"), "private"));
+    }
+
+    #[test]
+    fn forwarded_client() {
+        let proxies: Vec<IpNet> = vec![
+            "192.168.1.1/32".parse().unwrap(),
+            "192.168.1.2/32".parse().unwrap(),
+        ];
+        let client_ip = |peer, headers| client_ip(Some(&proxies), peer, headers);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            HeaderValue::from_static("1.2.3.4, 10.2.3.4, 192.168.1.2"),
+        );
+        // untrusted peers cannot spoof their address
+        assert_eq!(client_ip(ip("8.8.8.8"), &headers), ip("8.8.8.8"));
+        // the last untrusted address in the chain is the client
+        assert_eq!(client_ip(ip("192.168.1.1"), &headers), ip("10.2.3.4"));
+        assert_eq!(
+            client_ip(ip("192.168.1.1"), &HeaderMap::new()),
+            ip("192.168.1.1")
+        );
+        // the header is ignored without any trusted proxies
+        assert_eq!(
+            super::client_ip(None, ip("192.168.1.1"), &headers),
+            ip("192.168.1.1")
+        );
+    }
+}
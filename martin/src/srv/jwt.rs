@@ -0,0 +1,368 @@
+use std::fmt::{Debug, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpRequest, Result as ActixResult};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine as _;
+use ring::hmac;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::MartinError::InvalidJwtConfig;
+use crate::MartinResult;
+
+/// Validated claims of a JWT
+pub type JwtClaims = Map<String, Value>;
+
+/// Prefix of the URL query keys that pass the validated claims to the sources.
+/// Keys with this prefix are always removed from the client requests.
+pub const CLAIM_QUERY_PREFIX: &str = "jwt:";
+
+/// Allowed clock difference when checking the `exp` and `nbf` claims
+const LEEWAY_SECS: f64 = 60.0;
+
+/// DER encoded OIDs of the supported public key algorithms
+const RSA_OID: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01];
+const EC_OID: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const P256_OID: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum JwtError {
+    #[error("Token is malformed")]
+    Malformed,
+
+    #[error("Token algorithm {0} is not supported by the configured key")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Token signature is invalid")]
+    InvalidSignature,
+
+    #[error("Token has expired")]
+    Expired,
+
+    #[error("Token is not valid yet")]
+    NotYetValid,
+
+    #[error("Token issuer is not accepted")]
+    InvalidIssuer,
+
+    #[error("Token audience is not accepted")]
+    InvalidAudience,
+}
+
+/// Validation of the JWTs sent by the clients, whose claims are passed to the function sources
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtConfig {
+    /// Shared secret of the HS256, HS384, and HS512 tokens
+    pub secret: Option<String>,
+    /// PEM encoded public key of the RS256 or ES256 tokens
+    pub public_key: Option<String>,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Required `aud` claim. Tokens with a list of audiences must include it.
+    pub audience: Option<String>,
+}
+
+#[derive(Clone)]
+enum PublicKey {
+    Rsa(Vec<u8>),
+    Ec(Vec<u8>),
+}
+
+/// Checks the signature and the registered claims of the tokens
+#[derive(Clone)]
+pub struct JwtValidator {
+    secret: Option<Vec<u8>>,
+    public_key: Option<PublicKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Debug for JwtValidator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "JwtValidator {{ issuer: {:?}, audience: {:?} }}",
+            self.issuer, self.audience
+        )
+    }
+}
+
+impl JwtValidator {
+    pub fn new(config: &JwtConfig) -> MartinResult<Self> {
+        let public_key = match &config.public_key {
+            Some(pem) => Some(parse_public_key(pem).ok_or_else(|| {
+                InvalidJwtConfig("public_key must be a PEM encoded RSA or P-256 key".to_string())
+            })?),
+            None => None,
+        };
+        if config.secret.is_none() && public_key.is_none() {
+            return Err(InvalidJwtConfig(
+                "either secret or public_key must be set".to_string(),
+            ));
+        }
+        Ok(Self {
+            secret: config.secret.as_ref().map(|v| v.as_bytes().to_vec()),
+            public_key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    /// Validate the token, and return its claims
+    pub fn validate(&self, token: &str) -> Result<JwtClaims, JwtError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(JwtError::Malformed);
+        };
+        let decode = |v: &str| URL_SAFE_NO_PAD.decode(v).map_err(|_| JwtError::Malformed);
+        let header: JwtClaims =
+            serde_json::from_slice(&decode(header)?).map_err(|_| JwtError::Malformed)?;
+        let alg = header
+            .get("alg")
+            .and_then(Value::as_str)
+            .ok_or(JwtError::Malformed)?;
+
+        let message = &token[..token.len() - signature.len() - 1];
+        self.verify(alg, message.as_bytes(), &decode(signature)?)?;
+
+        let claims: JwtClaims =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| JwtError::Malformed)?;
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), JwtError> {
+        let unsupported = || JwtError::UnsupportedAlgorithm(alg.to_string());
+        let invalid = |_| JwtError::InvalidSignature;
+        match alg {
+            "HS256" | "HS384" | "HS512" => {
+                let secret = self.secret.as_ref().ok_or_else(unsupported)?;
+                let algorithm = match alg {
+                    "HS256" => hmac::HMAC_SHA256,
+                    "HS384" => hmac::HMAC_SHA384,
+                    _ => hmac::HMAC_SHA512,
+                };
+                hmac::verify(&hmac::Key::new(algorithm, secret), message, signature)
+                    .map_err(invalid)
+            }
+            "RS256" => match &self.public_key {
+                Some(PublicKey::Rsa(key)) => {
+                    UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, key)
+                        .verify(message, signature)
+                        .map_err(invalid)
+                }
+                _ => Err(unsupported()),
+            },
+            "ES256" => match &self.public_key {
+                Some(PublicKey::Ec(key)) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key)
+                    .verify(message, signature)
+                    .map_err(invalid),
+                _ => Err(unsupported()),
+            },
+            _ => Err(unsupported()),
+        }
+    }
+
+    fn check_claims(&self, claims: &JwtClaims) -> Result<(), JwtError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if let Some(exp) = claims.get("exp") {
+            if exp.as_f64().ok_or(JwtError::Malformed)? + LEEWAY_SECS < now {
+                return Err(JwtError::Expired);
+            }
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            if nbf.as_f64().ok_or(JwtError::Malformed)? - LEEWAY_SECS > now {
+                return Err(JwtError::NotYetValid);
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(JwtError::InvalidIssuer);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let accepted = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(aud)) => aud.iter().any(|v| v.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !accepted {
+                return Err(JwtError::InvalidAudience);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the validated claims of the `Authorization: Bearer` token of the request.
+/// Requests without a token have no claims, and requests with an invalid token are rejected.
+pub fn get_request_claims(
+    req: &HttpRequest,
+    validator: Option<&JwtValidator>,
+) -> ActixResult<Option<JwtClaims>> {
+    let Some(validator) = validator else {
+        return Ok(None);
+    };
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        Some(token) => validator
+            .validate(token.trim())
+            .map(Some)
+            .map_err(ErrorUnauthorized),
+        None => Ok(None),
+    }
+}
+
+/// Get the public key from the `SubjectPublicKeyInfo` structure of a PEM encoded key
+fn parse_public_key(pem: &str) -> Option<PublicKey> {
+    let base64: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|v| !v.starts_with("-----"))
+        .collect();
+    let der = STANDARD.decode(base64).ok()?;
+    let (0x30, spki, _) = der_element(&der)? else {
+        return None;
+    };
+    let (0x30, algorithm, key) = der_element(spki)? else {
+        return None;
+    };
+    let (0x06, oid, params) = der_element(algorithm)? else {
+        return None;
+    };
+    let (0x03, bits, _) = der_element(key)? else {
+        return None;
+    };
+    // the first byte of a bit string is the number of unused bits
+    let key = bits.strip_prefix(&[0])?.to_vec();
+    if oid == RSA_OID {
+        Some(PublicKey::Rsa(key))
+    } else if oid == EC_OID && matches!(der_element(params)?, (0x06, P256_OID, _)) {
+        Some(PublicKey::Ec(key))
+    } else {
+        None
+    }
+}
+
+/// Split a DER element into its tag, its contents, and the rest of the input
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (usize::from(len), rest)
+    } else {
+        let count = usize::from(len & 0x7F);
+        if count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        let len = bytes.iter().fold(0, |acc, b| acc << 8 | usize::from(*b));
+        (len, rest)
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    use super::*;
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    fn hs256_token(secret: &str, claims: &Value) -> String {
+        let message = format!("{}.{}", encode(&json!({"alg": "HS256"})), encode(claims));
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hmac::sign(&key, message.as_bytes());
+        format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn hmac_tokens() {
+        let validator = JwtValidator::new(&JwtConfig {
+            secret: Some("secret".to_string()),
+            issuer: Some("auth".to_string()),
+            audience: Some("tiles".to_string()),
+            ..JwtConfig::default()
+        })
+        .unwrap();
+
+        let claims = json!({"sub": "u1", "org_id": 7, "iss": "auth", "aud": ["api", "tiles"]});
+        let token = hs256_token("secret", &claims);
+        assert_eq!(Value::Object(validator.validate(&token).unwrap()), claims);
+
+        let token = hs256_token("other", &claims);
+        assert_eq!(validator.validate(&token), Err(JwtError::InvalidSignature));
+        let token = hs256_token(
+            "secret",
+            &json!({"iss": "auth", "aud": "tiles", "exp": 1000}),
+        );
+        assert_eq!(validator.validate(&token), Err(JwtError::Expired));
+        let token = hs256_token("secret", &json!({"iss": "other", "aud": "tiles"}));
+        assert_eq!(validator.validate(&token), Err(JwtError::InvalidIssuer));
+        let token = hs256_token("secret", &json!({"iss": "auth", "aud": "api"}));
+        assert_eq!(validator.validate(&token), Err(JwtError::InvalidAudience));
+        assert_eq!(validator.validate("a.b"), Err(JwtError::Malformed));
+
+        let unsigned = format!("{}.{}.", encode(&json!({"alg": "none"})), encode(&claims));
+        assert_eq!(
+            validator.validate(&unsigned),
+            Err(JwtError::UnsupportedAlgorithm("none".to_string()))
+        );
+    }
+
+    #[test]
+    fn ec_tokens() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        // SubjectPublicKeyInfo of a P-256 key is a fixed prefix followed by the key
+        let mut spki = hex::decode("3059301306072a8648ce3d020106082a8648ce3d030107034200").unwrap();
+        spki.extend_from_slice(pair.public_key().as_ref());
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(spki)
+        );
+        let validator = JwtValidator::new(&JwtConfig {
+            public_key: Some(pem),
+            ..JwtConfig::default()
+        })
+        .unwrap();
+
+        let message = format!(
+            "{}.{}",
+            encode(&json!({"alg": "ES256", "typ": "JWT"})),
+            encode(&json!({"sub": "u1"}))
+        );
+        let signature = pair.sign(&rng, message.as_bytes()).unwrap();
+        let token = format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature));
+        assert_eq!(validator.validate(&token).unwrap()["sub"], "u1");
+
+        // the public key must not be usable as an HMAC secret
+        let token = hs256_token("anything", &json!({"sub": "u1"}));
+        assert_eq!(
+            validator.validate(&token),
+            Err(JwtError::UnsupportedAlgorithm("HS256".to_string()))
+        );
+        assert!(JwtValidator::new(&JwtConfig::default()).is_err());
+    }
+}
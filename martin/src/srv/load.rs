@@ -0,0 +1,154 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+
+use crate::source::{PoolStatus, TileSources};
+use crate::srv::admin::authorize;
+use crate::srv::config::AdminConfig;
+use crate::srv::RuntimeInfo;
+use crate::utils::saturation::{
+    get_pending_tiles, BLOCKING_QUEUE, CACHE_HITS, CACHE_MISSES, COMPRESSION_QUEUE,
+};
+
+/// Weights of the database pool saturation, the queue pressure, and the cache miss rate in the load score
+const POOL_WEIGHT: f64 = 0.4;
+const QUEUE_WEIGHT: f64 = 0.4;
+const CACHE_WEIGHT: f64 = 0.2;
+
+/// Load of this instance, used by autoscalers and least-loaded routing across replicas
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoadReport {
+    /// Weighted load of this instance, from 0 (idle) to 1 (saturated)
+    pub score: f64,
+    /// Share of the connections of the busiest database pool that are in use or waited for
+    pub pool_saturation: f64,
+    /// Queued work relative to the number of workers, approaching 1 as the queues grow
+    pub queue_pressure: f64,
+    /// Share of the tile requests served from a cache since the start, if any were counted
+    pub cache_hit_rate: Option<f64>,
+    pub pending_tiles: u64,
+    pub blocking_queue: u64,
+    pub compression_queue: u64,
+    pub workers: usize,
+}
+
+impl LoadReport {
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(
+        pools: impl IntoIterator<Item = PoolStatus>,
+        queues: [u64; 3],
+        cache: (u64, u64),
+        workers: usize,
+    ) -> Self {
+        let pool_saturation = pools
+            .into_iter()
+            .filter(|s| s.max_size > 0)
+            .map(|s| {
+                let busy = s.size.saturating_sub(s.available) + s.waiting;
+                (busy as f64 / s.max_size as f64).min(1.0)
+            })
+            .fold(0.0, f64::max);
+
+        let [pending_tiles, blocking_queue, compression_queue] = queues;
+        let waiting = (pending_tiles + blocking_queue + compression_queue) as f64;
+        let queue_pressure = waiting / (waiting + workers.max(1) as f64);
+
+        let (hits, misses) = cache;
+        let cache_hit_rate = (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64);
+
+        // without a cache, the score only depends on the pools and the queues
+        let score = match cache_hit_rate {
+            Some(rate) => {
+                POOL_WEIGHT * pool_saturation
+                    + QUEUE_WEIGHT * queue_pressure
+                    + CACHE_WEIGHT * (1.0 - rate)
+            }
+            None => {
+                (POOL_WEIGHT * pool_saturation + QUEUE_WEIGHT * queue_pressure)
+                    / (POOL_WEIGHT + QUEUE_WEIGHT)
+            }
+        };
+
+        Self {
+            score,
+            pool_saturation,
+            queue_pressure,
+            cache_hit_rate,
+            pending_tiles,
+            blocking_queue,
+            compression_queue,
+            workers,
+        }
+    }
+}
+
+/// Report the current load of this instance as a normalized score with its components
+#[route("/_/load", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_load(
+    req: HttpRequest,
+    admin: Option<Data<AdminConfig>>,
+    sources: Data<ArcSwap<TileSources>>,
+    info: Data<RuntimeInfo>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let report = LoadReport::new(
+        sources.load().get_pool_status().into_values(),
+        [
+            get_pending_tiles().values().sum(),
+            BLOCKING_QUEUE.load(Ordering::Relaxed),
+            COMPRESSION_QUEUE.load(Ordering::Relaxed),
+        ],
+        (
+            CACHE_HITS.load(Ordering::Relaxed),
+            CACHE_MISSES.load(Ordering::Relaxed),
+        ),
+        info.workers(),
+    );
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(max_size: usize, size: usize, available: usize, waiting: usize) -> PoolStatus {
+        PoolStatus {
+            max_size,
+            size,
+            available,
+            waiting,
+        }
+    }
+
+    #[test]
+    fn load_score() {
+        let idle = LoadReport::new([], [0, 0, 0], (0, 0), 4);
+        assert!(idle.score.abs() < 1e-9);
+        assert_eq!(idle.cache_hit_rate, None);
+
+        let report = LoadReport::new(
+            [pool(10, 5, 5, 0), pool(10, 10, 2, 0)],
+            [2, 1, 1],
+            (3, 1),
+            4,
+        );
+        assert!((report.pool_saturation - 0.8).abs() < 1e-9);
+        assert!((report.queue_pressure - 0.5).abs() < 1e-9);
+        assert!((report.cache_hit_rate.unwrap() - 0.75).abs() < 1e-9);
+        assert!((report.score - (0.32 + 0.2 + 0.05)).abs() < 1e-9);
+
+        // waiting requests saturate the pool, but never above 1
+        let saturated = LoadReport::new([pool(10, 10, 0, 20)], [0, 0, 0], (0, 0), 4);
+        assert!((saturated.pool_saturation - 1.0).abs() < 1e-9);
+        assert!((saturated.score - 0.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::{Display, Write as _};
+use std::sync::atomic::Ordering;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse, Responder};
+use arc_swap::ArcSwap;
+
+use crate::source::{PoolStatus, TileSources};
+use crate::utils::saturation::{
+    get_oversized_tiles, get_pending_tiles, get_pool_waits, get_rejected_tiles, BLOCKING_QUEUE,
+    CACHE_HITS, CACHE_MISSES, COALESCED_TILES, COMPRESSION_QUEUE, GLYPH_CACHE_HITS,
+    GLYPH_CACHE_MISSES, STALE_TILES_REFRESHED, STALE_TILES_SERVED, VARIANT_CACHE_HITS,
+    VARIANT_CACHE_MISSES,
+};
+
+/// Return saturation gauges in the Prometheus text format, so that autoscalers
+/// can scale on the amount of waiting work rather than on the CPU usage alone
+#[route("/metrics", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_metrics(sources: Data<ArcSwap<TileSources>>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(render_metrics(&sources.load()))
+}
+
+/// Metric name, help text, and the value of a database pool gauge
+type PoolGauge = (&'static str, &'static str, fn(&PoolStatus) -> usize);
+
+fn render_metrics(sources: &TileSources) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "martin_pending_tiles",
+        "gauge",
+        "Tile requests waiting for the source to generate the tile",
+    );
+    for (id, count) in get_pending_tiles() {
+        sample(&mut out, "martin_pending_tiles", "source", &id, count);
+    }
+
+    header(
+        &mut out,
+        "martin_tile_oversized_total",
+        "counter",
+        "Tiles larger than the size limit of their source",
+    );
+    for (id, count) in get_oversized_tiles() {
+        sample(
+            &mut out,
+            "martin_tile_oversized_total",
+            "source",
+            &id,
+            count,
+        );
+    }
+
+    header(
+        &mut out,
+        "martin_tile_rejected_total",
+        "counter",
+        "Tile requests rejected because too many requests were waiting for the source",
+    );
+    for (id, count) in get_rejected_tiles() {
+        sample(&mut out, "martin_tile_rejected_total", "source", &id, count);
+    }
+
+    let pools = sources.get_pool_status();
+    let pool_gauges: [PoolGauge; 3] = [
+        (
+            "martin_db_pool_size",
+            "Open connections of the database pool",
+            |s| s.size,
+        ),
+        (
+            "martin_db_pool_available",
+            "Idle connections of the database pool",
+            |s| s.available,
+        ),
+        (
+            "martin_db_pool_waiting",
+            "Requests waiting for a connection of the database pool",
+            |s| s.waiting,
+        ),
+    ];
+    for (name, help, value) in pool_gauges {
+        header(&mut out, name, "gauge", help);
+        for (id, status) in &pools {
+            sample(&mut out, name, "pool", id, value(status));
+        }
+    }
+
+    let waits = get_pool_waits();
+    header(
+        &mut out,
+        "martin_db_pool_wait_seconds_total",
+        "counter",
+        "Total time spent waiting for connections of the database pool",
+    );
+    for (id, wait) in &waits {
+        sample(
+            &mut out,
+            "martin_db_pool_wait_seconds_total",
+            "pool",
+            id,
+            wait.total.as_secs_f64(),
+        );
+    }
+    header(
+        &mut out,
+        "martin_db_pool_wait_count_total",
+        "counter",
+        "Connections taken from the database pool",
+    );
+    for (id, wait) in &waits {
+        sample(
+            &mut out,
+            "martin_db_pool_wait_count_total",
+            "pool",
+            id,
+            wait.count,
+        );
+    }
+
+    render_queues(&mut out);
+    out
+}
+
+/// Gauges of the tasks waiting for a thread, and counters of the tile and glyph caches
+fn render_queues(out: &mut String) {
+    header(
+        out,
+        "martin_blocking_queue_length",
+        "gauge",
+        "Blocking tasks, e.g. COG and GeoParquet tile reads, waiting for a thread",
+    );
+    let _ = writeln!(
+        out,
+        "martin_blocking_queue_length {}",
+        BLOCKING_QUEUE.load(Ordering::Relaxed)
+    );
+    header(
+        out,
+        "martin_compression_queue_depth",
+        "gauge",
+        "Tiles being compressed for the clients",
+    );
+    let _ = writeln!(
+        out,
+        "martin_compression_queue_depth {}",
+        COMPRESSION_QUEUE.load(Ordering::Relaxed)
+    );
+
+    for (name, help, counter) in [
+        (
+            "martin_tile_cache_hits_total",
+            "Tile requests served from the in-memory or the shared cache",
+            &CACHE_HITS,
+        ),
+        (
+            "martin_tile_cache_misses_total",
+            "Tile requests that were not in any of the caches",
+            &CACHE_MISSES,
+        ),
+        (
+            "martin_tile_coalesced_total",
+            "Tile requests that shared the result of a concurrent fetch of the same tile",
+            &COALESCED_TILES,
+        ),
+        (
+            "martin_tile_cache_stale_hits_total",
+            "Tile requests served with a stale tile while it was fetched again",
+            &STALE_TILES_SERVED,
+        ),
+        (
+            "martin_tile_cache_revalidated_total",
+            "Stale tiles fetched again in the background",
+            &STALE_TILES_REFRESHED,
+        ),
+        (
+            "martin_glyph_cache_hits_total",
+            "Glyph range requests served from the glyph cache",
+            &GLYPH_CACHE_HITS,
+        ),
+        (
+            "martin_glyph_cache_misses_total",
+            "Glyph range requests that had to be rendered",
+            &GLYPH_CACHE_MISSES,
+        ),
+    ] {
+        header(out, name, "counter", help);
+        let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+
+    for (name, help, counters) in [
+        (
+            "martin_tile_variant_cache_hits_total",
+            "Compressed tiles served from the in-memory cache, by encoding",
+            &VARIANT_CACHE_HITS,
+        ),
+        (
+            "martin_tile_variant_cache_misses_total",
+            "Tiles compressed for the clients and added to the in-memory cache, by encoding",
+            &VARIANT_CACHE_MISSES,
+        ),
+    ] {
+        header(out, name, "counter", help);
+        for (encoding, value) in counters.values() {
+            sample(out, name, "encoding", encoding, value);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, label: &str, label_value: &str, value: impl Display) {
+    let label_value = label_value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    let _ = writeln!(out, "{name}{{{label}=\"{label_value}\"}} {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::saturation::PendingTile;
+
+    #[test]
+    fn metrics_format() {
+        let _pending = PendingTile::new("metrics \"test\"");
+        let text = render_metrics(&TileSources::default());
+        assert!(text.contains("martin_pending_tiles{source=\"metrics \\\"test\\\"\"} 1\n"));
+        assert!(text.contains("# TYPE martin_db_pool_waiting gauge\n"));
+        assert!(text.contains("martin_blocking_queue_length "));
+        assert!(text.contains("martin_compression_queue_depth "));
+        assert!(text.contains("# TYPE martin_tile_cache_hits_total counter\n"));
+        assert!(text.contains("# TYPE martin_glyph_cache_misses_total counter\n"));
+        assert!(text.contains("martin_tile_variant_cache_hits_total{encoding=\"br\"} "));
+    }
+}
@@ -1,8 +1,188 @@
 mod config;
-pub use config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+pub use config::{
+    AdminConfig, CacheSyncConfig, CompositeTileJsonConfig, GrpcConfig, RedirectConfig,
+    RedirectConfigObj, SrvConfig, CACHE_SYNC_CHANNEL_DEFAULT, GRPC_STREAM_CONCURRENCY_DEFAULT,
+    H2_WINDOW_KB_MAX, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT, MAX_LAYER_FIELDS_DEFAULT,
+    MAX_VECTOR_LAYERS_DEFAULT, REDIRECT_STATUS_DEFAULT,
+};
+
+mod admin;
+
+mod batch;
+pub use batch::{BATCH_MAX_TILES, BATCH_STATUS_HEADER};
+
+mod cache_sync;
+pub use cache_sync::CachePurger;
+
+mod cdn;
+pub use cdn::{
+    CdnConfig, CdnPurgeConfig, CdnPurger, SurrogateKeys, CDN_TIMEOUT_SECS_DEFAULT,
+    SURROGATE_KEY_HEADER_DEFAULT,
+};
+
+mod concurrency;
+pub use concurrency::{
+    ConcurrencyLimitConfig, ConcurrencyLimits, SourceConcurrencyLimitConfig, MAX_QUEUED_DEFAULT,
+};
+
+mod catalog_changes;
+pub use catalog_changes::{CatalogChanges, CatalogDiff, CATALOG_CHANGES_MAX};
+
+mod catalog_query;
+pub use catalog_query::{CatalogQuery, TOTAL_COUNT_HEADER};
+
+mod compression;
+pub use compression::{
+    CompressionConfig, CompressionLevels, CompressionLevelsConfig, TileCompression,
+    BROTLI_QUALITY_DEFAULT, GZIP_LEVEL_DEFAULT,
+};
+
+mod elevation;
+pub use elevation::ELEVATION_MAX_POINTS;
+
+mod empty_tiles;
+pub use empty_tiles::{EmptyTile, EmptyTileConfig, EmptyTileMode, EmptyTiles};
+
+mod encryption;
+pub use encryption::{
+    validate_encryption, EncryptionConfig, TileEncryption, TileKey, ENCRYPTION_ALGORITHM,
+};
+
+mod features;
+pub use features::{FeatureReport, COMPILED_FEATURES};
+
+mod geojson;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "grpc")]
+pub use grpc::{start_grpc, GrpcService};
+
+mod health;
+pub use health::{Readiness, ReloadGuard};
+
+mod host_cache;
+pub use host_cache::{
+    HostCache, HostCacheConfig, HOST_CACHE_SIZE_MB_DEFAULT, HOST_CACHE_SLOT_SIZE_KB_DEFAULT,
+};
+
+mod internal_routes;
+pub use internal_routes::{is_internal_route, InternalRoutes, InternalRoutesMiddleware};
+
+mod ip_filter;
+pub use ip_filter::{IpFilter, IpFilterConfig, IpFilterMiddleware, IpRules};
+
+mod jwt;
+pub use jwt::{
+    get_request_claims, JwtClaims, JwtConfig, JwtError, JwtValidator, CLAIM_QUERY_PREFIX,
+};
+
+mod load;
+
+mod metrics;
+
+mod notifications;
+pub use notifications::start_notification_listeners;
+
+mod prefetch;
+pub use prefetch::{
+    ActiveRequest, PrefetchConfig, Prefetcher, PREFETCH_MIN_ZOOM_DEFAULT,
+    PREFETCH_QUEUE_SIZE_DEFAULT, PREFETCH_WORKERS_DEFAULT,
+};
+
+mod referer_filter;
+pub use referer_filter::{
+    validate_referer_filter, RefererFilter, RefererFilterMiddleware, RefererRules,
+};
+
+mod request_id;
+pub use request_id::{
+    current_request_id, get_request_id, RequestId, RequestIdMiddleware, RequestIdValue,
+    REQUEST_ID_HEADER,
+};
+
+mod scheduler;
+pub use scheduler::{ReloadSummary, ScheduledTask, Scheduler, TaskConfig};
+
+mod shared_cache;
+pub use shared_cache::{
+    SharedCache, SharedCacheConfig, SHARED_CACHE_MAX_ITEM_SIZE_DEFAULT,
+    SHARED_CACHE_PREFIX_DEFAULT, SHARED_CACHE_TIMEOUT_MS_DEFAULT,
+};
+
+mod tenants;
+pub use tenants::{
+    validate_tenants, TenantConfig, TenantConfigs, TenantId, Tenants, TenantsMiddleware,
+    TENANT_TOKEN_QUERY_PARAM,
+};
+
+mod throttle;
+pub use throttle::{
+    ApiKeyConfig, QuotaConfig, QuotaExceeded, QuotaUsage, Throttle, ThrottleConfig,
+    ThrottleMiddleware, ThrottleReport, API_KEY_HEADER_DEFAULT, API_KEY_QUERY_PARAM,
+};
+
+mod package;
+
+mod telemetry;
+pub use telemetry::{init_telemetry, shutdown_telemetry, RequestTracing, RequestTracingMiddleware};
+
+mod tile_size;
+pub use tile_size::{
+    OversizedTilePolicy, SourceTileSizeLimitConfig, TileSizeLimit, TileSizeLimitConfig,
+    TileSizeLimits,
+};
+
+mod timing;
+pub use timing::ServerTiming;
+
+mod traffic;
+pub use traffic::{
+    TrafficProfileConfig, TrafficRecorder, TRAFFIC_EXPORT_SIZE_DEFAULT, TRAFFIC_MAX_PATHS_DEFAULT,
+};
+
+mod transcode;
+pub use transcode::{RasterTranscoder, TranscodeConfig, TRANSCODE_QUALITY_DEFAULT};
+
+mod usage;
+pub use usage::{
+    UsageStats, UsageStatsConfig, UsageStatsReport, USAGE_INTERVAL_SECS_DEFAULT,
+    USAGE_RETENTION_DEFAULT,
+};
+
+mod utfgrid;
+
+mod public_url;
+pub(crate) use public_url::get_public_url;
+pub use public_url::{PublicUrl, RequestUrl, FORWARDED_PREFIX_HEADER, REWRITE_URL_HEADER};
+
+mod revalidate;
+pub use revalidate::Revalidator;
+
+mod retina;
+pub use retina::RETINA_TILE_SIZE;
+
+mod redirects;
+pub use redirects::SourceRedirects;
+
+mod style;
+
+mod static_files;
+pub use static_files::{StaticFilesConfig, STATIC_FILES_INDEX_DEFAULT};
+
+mod status;
+pub use status::RuntimeInfo;
+
+mod single_flight;
+pub use single_flight::SingleFlight;
+
+mod watcher;
+
+mod webhooks;
+pub use webhooks::{CatalogEvent, WebhookConfig, Webhooks, WEBHOOK_TIMEOUT_SECS_DEFAULT};
 
 mod server;
 pub use server::{
-    get_tile_content, get_tile_response, merge_tilejson, new_server, router, Catalog, TileRequest,
-    RESERVED_KEYWORDS,
+    get_tile_content, get_tile_response, merge_tilejson, new_server, router, Catalog, CatalogDump,
+    ServerData, TileOptions, TileRequest, RESERVED_KEYWORDS,
 };
@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::pg::{listen_for_notifications, PgConfig};
+use crate::srv::Scheduler;
+
+/// How long to wait before listening again after the connection was lost
+const RELISTEN_DELAY: Duration = Duration::from_secs(5);
+
+/// Notification payload in the JSON form
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotifyMessage {
+    /// Sources whose cached tiles are purged
+    #[serde(default)]
+    sources: Vec<String>,
+    /// Discover all sources again
+    #[serde(default)]
+    rediscover: bool,
+}
+
+impl NotifyMessage {
+    /// Parse a payload: an empty payload rediscovers the sources, a JSON object lists the sources to purge
+    /// and may request a rediscovery, and any other text is a comma-separated list of the sources to purge
+    fn parse(payload: &str) -> Self {
+        let payload = payload.trim();
+        if payload.is_empty() {
+            Self {
+                rediscover: true,
+                ..Self::default()
+            }
+        } else if payload.starts_with('{') {
+            serde_json::from_str(payload).unwrap_or_else(|e| {
+                warn!("Ignoring invalid notification {payload}: {e}");
+                Self::default()
+            })
+        } else {
+            Self {
+                sources: payload
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .collect(),
+                rediscover: false,
+            }
+        }
+    }
+}
+
+/// Listen for the `NOTIFY` events on the `notify_channel` of each `PostgreSQL` connection,
+/// purging the cached tiles of the named sources, or discovering all sources again
+pub fn start_notification_listeners(scheduler: &Scheduler) {
+    for pg in scheduler.discovery.postgres.iter() {
+        if let Some(channel) = &pg.notify_channel {
+            actix_rt::spawn(listen(scheduler.clone(), pg.clone(), channel.clone()));
+        }
+    }
+}
+
+/// Keep listening on the channel, reconnecting after the connection was lost.
+/// Notifications sent while disconnected are lost.
+async fn listen(scheduler: Scheduler, config: PgConfig, channel: String) {
+    loop {
+        let (tx, rx) = unbounded_channel();
+        let (result, ()) = tokio::join!(
+            listen_for_notifications(&config, &channel, tx),
+            handle(&scheduler, rx)
+        );
+        match result {
+            Ok(()) => warn!("PostgreSQL notification channel {channel} was closed"),
+            Err(e) => warn!("Unable to listen for PostgreSQL notifications on {channel}: {e}"),
+        }
+        info!(
+            "Listening on {channel} again in {}s",
+            RELISTEN_DELAY.as_secs()
+        );
+        tokio::time::sleep(RELISTEN_DELAY).await;
+    }
+}
+
+/// Apply the notifications until the channel is closed. Notifications that arrive together,
+/// e.g. one per modified row, are combined so that each source is purged only once.
+async fn handle(scheduler: &Scheduler, mut rx: UnboundedReceiver<String>) {
+    while let Some(payload) = rx.recv().await {
+        let mut sources = BTreeSet::new();
+        let mut rediscover = false;
+        let mut add = |payload: &str| {
+            let msg = NotifyMessage::parse(payload);
+            sources.extend(msg.sources);
+            rediscover |= msg.rediscover;
+        };
+        add(&payload);
+        while let Ok(payload) = rx.try_recv() {
+            add(&payload);
+        }
+
+        if rediscover {
+            info!("Rediscovering sources because of a PostgreSQL notification");
+            if let Err(e) = scheduler.rediscover_files(&[]).await {
+                warn!("Source rediscovery failed, keeping the current sources: {e}");
+            }
+        }
+        for id in sources {
+            info!("Purging cached tiles of {id} because of a PostgreSQL notification");
+            scheduler.purge(&id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_payload() {
+        let rediscover = NotifyMessage {
+            rediscover: true,
+            ..NotifyMessage::default()
+        };
+        assert_eq!(NotifyMessage::parse(""), rediscover);
+        assert_eq!(
+            NotifyMessage::parse("roads, parks"),
+            NotifyMessage {
+                sources: vec!["roads".to_string(), "parks".to_string()],
+                rediscover: false,
+            }
+        );
+        assert_eq!(
+            NotifyMessage::parse(r#"{"sources": ["roads"], "rediscover": true}"#),
+            NotifyMessage {
+                sources: vec!["roads".to_string()],
+                rediscover: true,
+            }
+        );
+        assert_eq!(
+            NotifyMessage::parse(r#"{"source": "roads"}"#),
+            NotifyMessage::default()
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+use std::io::{Cursor, Write as _};
+
+use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::{Bytes, Data};
+use actix_web::{route, web, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use serde_json::Value;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::fonts::{FontResult, FontSources};
+use crate::sprites::SpriteSources;
+use crate::srv::ip_filter::check_source_access;
+use crate::srv::server::{map_font_error, map_internal_error, map_sprite_error};
+
+/// Number of codepoints in each glyph range, must match the font endpoint
+const GLYPH_RANGE_SIZE: u32 = 256;
+/// Highest glyph range start, covering the Basic Multilingual Plane
+const MAX_GLYPH_RANGE_START: u32 = 0xFFFF - GLYPH_RANGE_SIZE + 1;
+
+/// Sprites and fonts served by Martin that a style depends on
+#[derive(Debug, Default, PartialEq, Eq)]
+struct StyleResources {
+    /// Sprite source IDs, in the same "id1,id2" format as the sprite endpoint
+    sprites: BTreeSet<String>,
+    /// Font stacks, in the same "font1,font2" format as the font endpoint
+    fontstacks: BTreeSet<String>,
+}
+
+impl StyleResources {
+    fn new(style: &Value, fonts: &BTreeSet<String>) -> Self {
+        let mut result = Self::default();
+
+        // `sprite` is either a single URL, or a list of objects with an `id` and a `url`
+        let sprite_urls = match &style["sprite"] {
+            Value::String(url) => vec![url.as_str()],
+            Value::Array(items) => items.iter().filter_map(|v| v["url"].as_str()).collect(),
+            _ => vec![],
+        };
+        result.sprites = sprite_urls.into_iter().filter_map(get_sprite_ids).collect();
+
+        if let Some(layers) = style["layers"].as_array() {
+            for layer in layers {
+                collect_fontstacks(&layer["layout"]["text-font"], fonts, &mut result.fontstacks);
+            }
+        }
+        result
+    }
+}
+
+/// Get the sprite IDs from a Martin sprite URL, e.g. `http://host/sprite/id1,id2`
+fn get_sprite_ids(url: &str) -> Option<String> {
+    let (_, ids) = url.rsplit_once("/sprite/")?;
+    let ids = ids.split(['?', '#']).next().unwrap_or_default();
+    let ids = ids.strip_suffix("@2x").unwrap_or(ids);
+    (!ids.is_empty() && !ids.contains('/')).then(|| ids.to_string())
+}
+
+/// Find all font stacks in a `text-font` value. A font stack is a list of strings,
+/// but the value can also be an expression that contains several font stacks, e.g. `step`.
+/// Only the fonts known to this server are kept, which also skips the expression operators.
+fn collect_fontstacks(value: &Value, fonts: &BTreeSet<String>, result: &mut BTreeSet<String>) {
+    let Some(items) = value.as_array() else {
+        return;
+    };
+    if items.iter().all(Value::is_string) {
+        let stack: Vec<_> = items
+            .iter()
+            .filter_map(Value::as_str)
+            .filter(|name| fonts.contains(*name))
+            .collect();
+        if !stack.is_empty() {
+            result.insert(stack.join(","));
+        }
+    } else {
+        for item in items {
+            collect_fontstacks(item, fonts, result);
+        }
+    }
+}
+
+/// Bundle the sprite sheets and glyph ranges used by the posted style JSON into a ZIP archive.
+/// The archive uses the same paths as the sprite and font endpoints, and includes the style itself.
+#[route("/package", method = "POST")]
+async fn post_package(
+    req: HttpRequest,
+    body: Bytes,
+    sprites: Data<SpriteSources>,
+    fonts: Data<ArcSwap<FontSources>>,
+) -> ActixResult<HttpResponse> {
+    let style: Value = serde_json::from_slice(&body)
+        .map_err(|e| ErrorBadRequest(format!("Invalid style: {e}")))?;
+    if !style.is_object() {
+        return Err(ErrorBadRequest("Invalid style: expected a JSON object"));
+    }
+    let fonts = fonts.load();
+    let resources = StyleResources::new(&style, &fonts.get_font_names());
+    for ids in resources.sprites.iter().chain(&resources.fontstacks) {
+        check_source_access(&req, ids)?;
+    }
+
+    let mut files = Vec::new();
+    for ids in &resources.sprites {
+        for suffix in ["", "@2x"] {
+            let sheet = sprites
+                .get_sprites(&format!("{ids}{suffix}"))
+                .await
+                .map_err(map_sprite_error)?;
+            let index = serde_json::to_vec(&sheet.get_index()).map_err(map_internal_error)?;
+            let png = sheet.encode_png().map_err(map_internal_error)?;
+            files.push((format!("sprite/{ids}{suffix}.json"), index, true));
+            files.push((format!("sprite/{ids}{suffix}.png"), png, false));
+        }
+    }
+
+    // Rendering glyphs is CPU-heavy, so it must not block the async executor
+    let archive = web::block(move || -> FontResult<_> {
+        for stack in &resources.fontstacks {
+            for start in (0..=MAX_GLYPH_RANGE_START).step_by(GLYPH_RANGE_SIZE as usize) {
+                let end = start + GLYPH_RANGE_SIZE - 1;
+                let data = fonts.get_font_range(stack, start, end)?;
+                if !data.is_empty() {
+                    files.push((format!("font/{stack}/{start}-{end}"), data, true));
+                }
+            }
+        }
+        files.push(("style.json".to_string(), body.to_vec(), true));
+        Ok(write_archive(files))
+    })
+    .await
+    .map_err(map_internal_error)?
+    .map_err(map_font_error)?
+    .map_err(map_internal_error)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("package.zip".to_string())],
+        })
+        .body(archive))
+}
+
+/// Write files as `(path, data, compress)` into a ZIP archive
+fn write_archive(files: Vec<(String, Vec<u8>, bool)>) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, data, compress) in files {
+        let method = if compress {
+            CompressionMethod::Deflated
+        } else {
+            CompressionMethod::Stored
+        };
+        zip.start_file(path, FileOptions::default().compression_method(method))?;
+        zip.write_all(&data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn style_resources() {
+        let fonts = ["Noto Sans Regular", "Noto Sans Bold", "Open Sans Regular"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let style = json!({
+            "version": 8,
+            "sprite": [
+                {"id": "default", "url": "http://localhost:3000/sprite/src1,src2"},
+                {"id": "hidpi", "url": "https://example.org/sprite/src3@2x?key=1"},
+                {"id": "external", "url": "https://example.org/sprites/roads"}
+            ],
+            "layers": [
+                {"id": "a", "type": "symbol", "layout": {"text-font": ["Noto Sans Regular", "Unknown"]}},
+                {"id": "b", "type": "symbol", "layout": {"text-font": [
+                    "step", ["zoom"], ["literal", ["Open Sans Regular"]],
+                    10, ["literal", ["Noto Sans Bold", "Noto Sans Regular"]]
+                ]}},
+                {"id": "c", "type": "symbol", "layout": {"text-font": ["get", "font"]}},
+                {"id": "d", "type": "fill"}
+            ]
+        });
+        let resources = StyleResources::new(&style, &fonts);
+        assert_eq!(
+            resources.sprites,
+            BTreeSet::from(["src1,src2".to_string(), "src3".to_string()])
+        );
+        assert_eq!(
+            resources.fontstacks,
+            BTreeSet::from([
+                "Noto Sans Bold,Noto Sans Regular".to_string(),
+                "Noto Sans Regular".to_string(),
+                "Open Sans Regular".to_string(),
+            ])
+        );
+
+        let style = json!({"sprite": "http://localhost:3000/sprite/src1", "layers": []});
+        let resources = StyleResources::new(&style, &fonts);
+        assert_eq!(resources.sprites, BTreeSet::from(["src1".to_string()]));
+        assert!(resources.fontstacks.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+
+use crate::source::{TileSources, UrlQuery};
+use crate::utils::{CacheKey, CacheValue, MainCache, OptMainCache};
+use crate::TileCoord;
+
+pub const PREFETCH_MIN_ZOOM_DEFAULT: u8 = 10;
+pub const PREFETCH_WORKERS_DEFAULT: usize = 1;
+pub const PREFETCH_QUEUE_SIZE_DEFAULT: usize = 1000;
+
+/// How often an idle-waiting worker checks if all user requests have finished
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PrefetchConfig {
+    /// Only cache misses at this zoom or higher trigger prefetching [default: 10]
+    pub min_zoom: Option<u8>,
+    /// Only cache misses at this zoom or lower trigger prefetching
+    pub max_zoom: Option<u8>,
+    /// Number of background workers generating tiles [default: 1]
+    pub workers: Option<usize>,
+    /// Maximum number of queued tiles, new tiles are dropped when the queue is full [default: 1000]
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Debug)]
+struct PrefetchJob {
+    source_id: String,
+    xyz: TileCoord,
+    query: Option<UrlQuery>,
+}
+
+/// Low priority background queue that speculatively generates the neighbors and the parent
+/// of tiles that were not found in the cache, but only while no user requests are being served.
+#[derive(Debug, Clone)]
+pub struct Prefetcher {
+    sender: Sender<PrefetchJob>,
+    active: Arc<AtomicUsize>,
+    min_zoom: u8,
+    max_zoom: u8,
+}
+
+/// Marks a user request as being served until dropped, pausing the prefetch workers.
+#[derive(Debug)]
+pub struct ActiveRequest(Arc<AtomicUsize>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Prefetcher {
+    /// Start the background workers. Prefetching requires the tile cache,
+    /// so nothing is started if the cache is disabled.
+    #[must_use]
+    pub fn start(
+        config: &PrefetchConfig,
+        cache: &OptMainCache,
+        sources: &Data<ArcSwap<TileSources>>,
+    ) -> Option<Self> {
+        let Some(cache) = cache else {
+            warn!("Tile prefetching is ignored because the tile cache is disabled");
+            return None;
+        };
+        let (sender, receiver) = channel(config.queue_size.unwrap_or(PREFETCH_QUEUE_SIZE_DEFAULT));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let active = Arc::new(AtomicUsize::new(0));
+        for _ in 0..config.workers.unwrap_or(PREFETCH_WORKERS_DEFAULT) {
+            actix_rt::spawn(run_worker(
+                receiver.clone(),
+                active.clone(),
+                cache.clone(),
+                sources.clone(),
+            ));
+        }
+        Some(Self {
+            sender,
+            active,
+            min_zoom: config.min_zoom.unwrap_or(PREFETCH_MIN_ZOOM_DEFAULT),
+            max_zoom: config.max_zoom.unwrap_or(u8::MAX),
+        })
+    }
+
+    #[must_use]
+    pub fn track_request(&self) -> ActiveRequest {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveRequest(self.active.clone())
+    }
+
+    /// Queue the tiles around a tile that was not in the cache.
+    pub fn on_miss(&self, source_id: &str, xyz: TileCoord, query: Option<&UrlQuery>) {
+        if xyz.z < self.min_zoom || xyz.z > self.max_zoom {
+            return;
+        }
+        for xyz in get_nearby_tiles(xyz) {
+            let job = PrefetchJob {
+                source_id: source_id.to_string(),
+                xyz,
+                query: query.cloned(),
+            };
+            if self.sender.try_send(job).is_err() {
+                trace!("Prefetch queue is full, skipping the rest of the tiles around {xyz}");
+                return;
+            }
+        }
+    }
+}
+
+/// Get the parent tile and the tiles surrounding the given one at the same zoom level.
+fn get_nearby_tiles(xyz: TileCoord) -> Vec<TileCoord> {
+    let mut result = Vec::with_capacity(9);
+    if xyz.z > 0 {
+        result.push(TileCoord {
+            z: xyz.z - 1,
+            x: xyz.x / 2,
+            y: xyz.y / 2,
+        });
+    }
+    let max = (1_u64 << xyz.z) - 1;
+    let x = u64::from(xyz.x);
+    let y = u64::from(xyz.y);
+    for ny in y.saturating_sub(1)..=(y + 1).min(max) {
+        for nx in x.saturating_sub(1)..=(x + 1).min(max) {
+            if nx != x || ny != y {
+                // Both values are within the tile range of a u8 zoom level
+                #[allow(clippy::cast_possible_truncation)]
+                result.push(TileCoord {
+                    z: xyz.z,
+                    x: nx as u32,
+                    y: ny as u32,
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Get the other tiles that share the same parent tile.
+pub(crate) fn get_sibling_tiles(xyz: TileCoord) -> Vec<TileCoord> {
+    if xyz.z == 0 {
+        return Vec::new();
+    }
+    let (x0, y0) = (xyz.x & !1, xyz.y & !1);
+    [(x0, y0), (x0 + 1, y0), (x0, y0 + 1), (x0 + 1, y0 + 1)]
+        .into_iter()
+        .filter(|&(x, y)| x != xyz.x || y != xyz.y)
+        .map(|(x, y)| TileCoord { z: xyz.z, x, y })
+        .collect()
+}
+
+async fn run_worker(
+    receiver: Arc<Mutex<Receiver<PrefetchJob>>>,
+    active: Arc<AtomicUsize>,
+    cache: MainCache,
+    sources: Data<ArcSwap<TileSources>>,
+) {
+    loop {
+        let Some(job) = receiver.lock().await.recv().await else {
+            return;
+        };
+        while active.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+        let sources = sources.load_full();
+        let Ok(src) = sources.get_source(&job.source_id) else {
+            continue;
+        };
+        let query = if src.support_url_query() {
+            job.query
+        } else {
+            None
+        };
+        let key = CacheKey::tile(&job.source_id, job.xyz, query.as_ref());
+        if !src.is_valid_zoom(job.xyz.z) || cache.contains_key(&key) {
+            continue;
+        }
+        match src.get_tile(&job.xyz, &query).await {
+            Ok(data) => {
+                trace!("Prefetched {key:?}");
+                cache.insert(key, CacheValue::Tile(data)).await;
+            }
+            Err(e) => debug!("Unable to prefetch {key:?}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xyz(z: u8, x: u32, y: u32) -> TileCoord {
+        TileCoord { z, x, y }
+    }
+
+    #[test]
+    fn nearby_tiles() {
+        assert_eq!(
+            get_nearby_tiles(xyz(0, 0, 0)),
+            Vec::<TileCoord>::new(),
+            "the world tile has no parent or neighbors"
+        );
+        assert_eq!(
+            get_nearby_tiles(xyz(1, 0, 0)),
+            vec![xyz(0, 0, 0), xyz(1, 1, 0), xyz(1, 0, 1), xyz(1, 1, 1)]
+        );
+        let tiles = get_nearby_tiles(xyz(3, 4, 5));
+        assert_eq!(tiles.len(), 9);
+        assert_eq!(tiles[0], xyz(2, 2, 2));
+        assert!(!tiles.contains(&xyz(3, 4, 5)));
+        assert!(tiles.contains(&xyz(3, 3, 4)));
+        assert!(tiles.contains(&xyz(3, 5, 6)));
+    }
+
+    #[test]
+    fn sibling_tiles() {
+        assert!(get_sibling_tiles(xyz(0, 0, 0)).is_empty());
+        assert_eq!(
+            get_sibling_tiles(xyz(3, 5, 2)),
+            vec![xyz(3, 4, 2), xyz(3, 4, 3), xyz(3, 5, 3)]
+        );
+    }
+}
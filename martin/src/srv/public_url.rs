@@ -0,0 +1,212 @@
+use std::net::IpAddr;
+
+use actix_web::http::header::{HeaderValue, HOST};
+use actix_web::http::Uri;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+
+use crate::MartinError::InvalidPublicUrl;
+use crate::MartinResult;
+
+/// Header with the path prefix removed by a reverse proxy, e.g. `/tiles`
+pub const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+/// Header with the full public path of the request, set by some reverse proxies
+pub const REWRITE_URL_HEADER: &str = "x-rewrite-url";
+
+/// Public URL of a request, used to build the absolute URLs in the `TileJSON` and the styles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestUrl {
+    pub scheme: String,
+    pub host: String,
+    /// Path prefix added by the reverse proxies, without a trailing slash, e.g. `/tiles`
+    pub prefix: String,
+    /// Public path of the request, including the prefix
+    pub path: String,
+}
+
+impl RequestUrl {
+    /// URL of the root of this server, without a trailing slash, e.g. `https://example.com/tiles`
+    #[must_use]
+    pub fn base_url(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.host, self.prefix)
+    }
+}
+
+/// Finds the URL that the clients used to reach the server, either from the configured public URL,
+/// or from the request and the forwarding headers of the trusted proxies
+#[derive(Debug, Clone, Default)]
+pub struct PublicUrl {
+    /// Scheme, host, and path prefix of the configured `public_url`
+    public_url: Option<(String, String, String)>,
+    /// Only these proxies may set the forwarding headers. All of them are trusted if unset.
+    trusted_proxies: Option<Vec<IpNet>>,
+}
+
+impl PublicUrl {
+    pub fn new(
+        public_url: Option<&str>,
+        trusted_proxies: Option<&Vec<IpNet>>,
+    ) -> MartinResult<Self> {
+        let public_url = match public_url {
+            Some(url) => Some(parse_public_url(url)?),
+            None => None,
+        };
+        Ok(Self {
+            public_url,
+            trusted_proxies: trusted_proxies.cloned(),
+        })
+    }
+
+    /// Get the public URL of the request
+    #[must_use]
+    pub fn get(&self, req: &HttpRequest) -> RequestUrl {
+        let path = req.path();
+        if let Some((scheme, host, prefix)) = &self.public_url {
+            return RequestUrl {
+                scheme: scheme.clone(),
+                host: host.clone(),
+                prefix: prefix.clone(),
+                path: format!("{prefix}{path}"),
+            };
+        }
+
+        if !self.is_trusted(req.peer_addr().map(|v| v.ip())) {
+            let host = req.headers().get(HOST).and_then(|v| v.to_str().ok());
+            let scheme = if req.app_config().secure() {
+                "https"
+            } else {
+                "http"
+            };
+            return RequestUrl {
+                scheme: scheme.to_string(),
+                host: host.unwrap_or_else(|| req.app_config().host()).to_string(),
+                prefix: String::new(),
+                path: path.to_string(),
+            };
+        }
+
+        // the connection info uses the `Forwarded` and `X-Forwarded-Proto/Host` headers
+        let info = req.connection_info();
+        let rewritten = req
+            .headers()
+            .get(REWRITE_URL_HEADER)
+            .and_then(parse_x_rewrite_url);
+        let (prefix, path) = if let Some(public) = rewritten {
+            (
+                public.strip_suffix(path).unwrap_or_default().to_string(),
+                public,
+            )
+        } else {
+            let prefix = req
+                .headers()
+                .get(FORWARDED_PREFIX_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim_end_matches('/'))
+                .filter(|v| v.starts_with('/'))
+                .unwrap_or_default();
+            (prefix.to_string(), format!("{prefix}{path}"))
+        };
+        RequestUrl {
+            scheme: info.scheme().to_string(),
+            host: info.host().to_string(),
+            prefix,
+            path,
+        }
+    }
+
+    fn is_trusted(&self, peer: Option<IpAddr>) -> bool {
+        match (&self.trusted_proxies, peer) {
+            (None, _) => true,
+            (Some(nets), Some(ip)) => nets.iter().any(|net| net.contains(&ip)),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Get the public URL of the request, using the settings of the server if they are registered
+pub(crate) fn get_public_url(req: &HttpRequest) -> RequestUrl {
+    match req.app_data::<Data<PublicUrl>>() {
+        Some(public_url) => public_url.get(req),
+        None => PublicUrl::default().get(req),
+    }
+}
+
+/// Split the public URL into its scheme, host, and path prefix without a trailing slash
+fn parse_public_url(url: &str) -> MartinResult<(String, String, String)> {
+    let err = || InvalidPublicUrl(url.to_string());
+    let uri = url.parse::<Uri>().map_err(|_| err())?;
+    let scheme = uri.scheme_str().filter(|v| *v == "http" || *v == "https");
+    match (scheme, uri.authority(), uri.query()) {
+        (Some(scheme), Some(host), None) => Ok((
+            scheme.to_string(),
+            host.to_string(),
+            uri.path().trim_end_matches('/').to_string(),
+        )),
+        _ => Err(err()),
+    }
+}
+
+fn parse_x_rewrite_url(header: &HeaderValue) -> Option<String> {
+    header
+        .to_str()
+        .ok()
+        .and_then(|header| header.parse::<Uri>().ok())
+        .map(|uri| uri.path().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn request(peer: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/roads")
+            .peer_addr(format!("{peer}:12345").parse().unwrap())
+            .insert_header((HOST, "internal:3000"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header(("x-forwarded-host", "tiles.example.com"))
+            .insert_header((FORWARDED_PREFIX_HEADER, "/martin/"))
+    }
+
+    #[test]
+    fn forwarded_url() {
+        let req = request("10.0.0.1").to_http_request();
+        let url = PublicUrl::default().get(&req);
+        assert_eq!(url.base_url(), "https://tiles.example.com/martin");
+        assert_eq!(url.path, "/martin/roads");
+
+        // only the trusted proxies may set the forwarding headers
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let public_url = PublicUrl::new(None, Some(&trusted)).unwrap();
+        assert_eq!(
+            public_url.get(&req).base_url(),
+            "https://tiles.example.com/martin"
+        );
+        let req = request("8.8.8.8").to_http_request();
+        let url = public_url.get(&req);
+        assert_eq!(url.base_url(), "http://internal:3000");
+        assert_eq!(url.path, "/roads");
+
+        let req = request("10.0.0.1")
+            .insert_header((REWRITE_URL_HEADER, "/v1/tiles/roads?x=1"))
+            .to_http_request();
+        let url = public_url.get(&req);
+        assert_eq!(url.prefix, "/v1/tiles");
+        assert_eq!(url.path, "/v1/tiles/roads");
+    }
+
+    #[test]
+    fn configured_url() {
+        let public_url = PublicUrl::new(Some("https://maps.example.com/tiles/"), None).unwrap();
+        let url = public_url.get(&request("10.0.0.1").to_http_request());
+        assert_eq!(url.base_url(), "https://maps.example.com/tiles");
+        assert_eq!(url.path, "/tiles/roads");
+
+        assert!(PublicUrl::new(Some("maps.example.com"), None).is_err());
+        assert!(PublicUrl::new(Some("ftp://maps.example.com"), None).is_err());
+        assert!(PublicUrl::new(Some("https://maps.example.com/?a=1"), None).is_err());
+    }
+}
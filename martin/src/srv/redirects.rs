@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+use crate::source::TileSources;
+use crate::srv::config::RedirectConfig;
+
+/// Redirects from old source IDs to the new ones, e.g. after a layer has been renamed.
+/// A redirect is only used if there is no source with the old ID.
+#[derive(Debug, Clone, Default)]
+pub struct SourceRedirects(HashMap<String, (String, StatusCode)>);
+
+impl SourceRedirects {
+    #[must_use]
+    pub fn new(config: Option<&BTreeMap<String, RedirectConfig>>) -> Self {
+        Self(
+            config
+                .into_iter()
+                .flatten()
+                .map(|(from, cfg)| {
+                    let status =
+                        StatusCode::from_u16(cfg.status()).unwrap_or(StatusCode::MOVED_PERMANENTLY);
+                    (from.clone(), (cfg.target().to_string(), status))
+                })
+                .collect(),
+        )
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// If any of the comma-separated source IDs should be redirected, return the new list of IDs
+    /// together with the status code to use. If several IDs are redirected, the strongest
+    /// status code wins, i.e. 308 is used if any of the redirects require it.
+    #[must_use]
+    pub fn resolve(&self, source_ids: &str, sources: &TileSources) -> Option<(String, StatusCode)> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut status = None;
+        let ids = source_ids
+            .split(',')
+            .map(|id| match self.0.get(id) {
+                Some((to, code))
+                    if !sources.contains(id)
+                        && !sources.is_composite(id)
+                        && !sources.is_variant(id) =>
+                {
+                    status = status.max(Some(*code));
+                    to.as_str()
+                }
+                _ => id,
+            })
+            .collect::<Vec<_>>();
+        status.map(|status| (ids.join(","), status))
+    }
+
+    /// Build a redirect response for the given request path, replacing the path segment
+    /// `suffix_len` segments from the end with the new source IDs.
+    #[must_use]
+    pub fn redirect(
+        path: &str,
+        query: &str,
+        suffix_len: usize,
+        new_ids: &str,
+        status: StatusCode,
+    ) -> HttpResponse {
+        let mut segments: Vec<&str> = path.split('/').collect();
+        if let Some(idx) = segments.len().checked_sub(suffix_len + 1) {
+            segments[idx] = new_ids;
+        }
+        let mut location = segments.join("/");
+        if !query.is_empty() {
+            location.push('?');
+            location.push_str(query);
+        }
+        HttpResponse::build(status)
+            .insert_header((LOCATION, location))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_location() {
+        let resp =
+            SourceRedirects::redirect("/old/1/2/3", "", 3, "new", StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/new/1/2/3");
+
+        let resp = SourceRedirects::redirect(
+            "/tiles/a,old",
+            "token=1",
+            0,
+            "a,new",
+            StatusCode::PERMANENT_REDIRECT,
+        );
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/tiles/a,new?token=1"
+        );
+    }
+
+    #[test]
+    fn resolve_ids() {
+        let cfg = BTreeMap::from([
+            ("old".to_string(), RedirectConfig::Target("new".to_string())),
+            (
+                "old2".to_string(),
+                RedirectConfig::Config(crate::srv::config::RedirectConfigObj {
+                    to: "new2".to_string(),
+                    status: Some(308),
+                }),
+            ),
+        ]);
+        let redirects = SourceRedirects::new(Some(&cfg));
+        let sources = TileSources::default();
+        assert_eq!(redirects.resolve("foo,bar", &sources), None);
+        assert_eq!(
+            redirects.resolve("old,bar", &sources),
+            Some(("new,bar".to_string(), StatusCode::MOVED_PERMANENTLY))
+        );
+        assert_eq!(
+            redirects.resolve("old,old2", &sources),
+            Some(("new,new2".to_string(), StatusCode::PERMANENT_REDIRECT))
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorForbidden;
+use actix_web::http::header::{HeaderMap, HeaderValue, ORIGIN, REFERER, VARY};
+use actix_web::http::Uri;
+use actix_web::{Error, HttpMessage as _, HttpRequest, Result as ActixResult};
+use futures::future::LocalBoxFuture;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::MartinError::InvalidRefererPattern;
+use crate::MartinResult;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RefererRules {
+    /// Origins of the web apps allowed to embed the source, e.g. `https://app.example.com`.
+    /// The scheme and the port are optional, and `*.` matches any subdomain, e.g. `https://*.example.com`.
+    pub allow: Vec<String>,
+    /// Allow the requests without the `Origin` and `Referer` headers, e.g. from native apps [default: false]
+    pub allow_missing: Option<bool>,
+}
+
+/// Allowed origin, parsed from a pattern like `https://*.example.com:8443`
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct OriginPattern {
+    scheme: Option<String>,
+    /// Lowercase host name, without the leading `*.` of the wildcard patterns
+    host: String,
+    subdomains: bool,
+    port: Option<u16>,
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.trim().trim_end_matches('/').to_ascii_lowercase();
+        let (scheme, authority) = match pattern.split_once("://") {
+            Some((scheme, authority)) => (Some(scheme.to_string()), authority),
+            None => (None, pattern.as_str()),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().ok()?)),
+            None => (authority, None),
+        };
+        let (host, subdomains) = match host.strip_prefix("*.") {
+            Some(host) => (host, true),
+            None => (host, false),
+        };
+        let valid = |v: &str| !v.is_empty() && !v.contains(['*', '/', '?', '#', '@']);
+        if !valid(host) || !scheme.as_deref().map_or(true, valid) {
+            return None;
+        }
+        Some(Self {
+            scheme,
+            host: host.to_string(),
+            subdomains,
+            port,
+        })
+    }
+
+    fn matches(&self, origin: &Uri) -> bool {
+        let Some(host) = origin.host() else {
+            return false;
+        };
+        let host = host.to_ascii_lowercase();
+        let host_matches = if self.subdomains {
+            host.strip_suffix(&self.host)
+                .map_or(false, |v| v.ends_with('.'))
+        } else {
+            host == self.host
+        };
+        host_matches
+            && origin.port_u16() == self.port
+            && self
+                .scheme
+                .as_ref()
+                .map_or(true, |v| origin.scheme_str() == Some(v))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SourceRules {
+    allow: Vec<OriginPattern>,
+    allow_missing: bool,
+}
+
+/// Check the allowed origins of all sources, returning an error for the first invalid pattern.
+pub fn validate_referer_filter(config: &BTreeMap<String, RefererRules>) -> MartinResult<()> {
+    for (id, rules) in config {
+        for pattern in &rules.allow {
+            if OriginPattern::parse(pattern).is_none() {
+                return Err(InvalidRefererPattern(pattern.clone(), id.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the header with the origin of the web app that made the request: the `Origin` header,
+/// or the `Referer` header if the browser did not send the origin.
+fn origin_header(headers: &HeaderMap) -> Option<&HeaderValue> {
+    headers
+        .get(ORIGIN)
+        .filter(|v| v.as_bytes() != b"null")
+        .or_else(|| headers.get(REFERER))
+}
+
+/// Middleware that lets the handlers reject the requests for the tiles and `TileJSON` of the configured sources
+/// with `403 Forbidden`, unless they were made by one of the allowed web apps, see [`check_referer_access`].
+#[derive(Debug, Clone, Default)]
+pub struct RefererFilter(Arc<BTreeMap<String, SourceRules>>);
+
+impl RefererFilter {
+    /// Create the filter from a validated configuration, see [`validate_referer_filter`].
+    /// Invalid patterns are ignored.
+    #[must_use]
+    pub fn new(config: &BTreeMap<String, RefererRules>) -> Self {
+        let rules = config
+            .iter()
+            .map(|(id, rules)| {
+                let rules = SourceRules {
+                    allow: rules
+                        .allow
+                        .iter()
+                        .filter_map(|v| OriginPattern::parse(v))
+                        .collect(),
+                    allow_missing: rules.allow_missing.unwrap_or_default(),
+                };
+                (id.clone(), rules)
+            })
+            .collect();
+        Self(Arc::new(rules))
+    }
+
+    /// Check if a request with the given headers may access the comma-separated source IDs.
+    /// Returns `None` if none of the sources are restricted.
+    #[must_use]
+    pub fn is_allowed(&self, headers: &HeaderMap, source_ids: &str) -> Option<bool> {
+        let mut rules = source_ids
+            .split(',')
+            .filter_map(|id| self.0.get(id))
+            .peekable();
+        rules.peek()?;
+        let origin = origin_header(headers).map(|v| v.to_str().ok()?.parse::<Uri>().ok());
+        Some(rules.all(|rules| match &origin {
+            None => rules.allow_missing,
+            Some(Some(origin)) => rules.allow.iter().any(|v| v.matches(origin)),
+            Some(None) => false,
+        }))
+    }
+}
+
+/// The filter of the server, inserted into the request extensions by the [`RefererFilter`] middleware
+/// so that the handlers can check the sources once the requested sources are resolved
+#[derive(Clone, Debug)]
+struct RefererRequest(RefererFilter);
+
+/// Marks the requests checked against the rules of a restricted source
+#[derive(Clone, Copy, Debug)]
+struct RefererChecked;
+
+/// Check the allowed web apps of the comma-separated source IDs requested by a client, responding with
+/// `403 Forbidden` if any of them do not allow it. Requests are not checked if the server is not wrapped
+/// with the [`RefererFilter`] middleware.
+pub(crate) fn check_referer_access(req: &HttpRequest, source_ids: &str) -> ActixResult<()> {
+    let Some(RefererRequest(filter)) = req.extensions().get::<RefererRequest>().cloned() else {
+        return Ok(());
+    };
+    let Some(allowed) = filter.is_allowed(req.headers(), source_ids) else {
+        return Ok(());
+    };
+    req.extensions_mut().insert(RefererChecked);
+    if allowed {
+        Ok(())
+    } else {
+        debug!(
+            "Rejected request for {source_ids} from origin {:?}",
+            origin_header(req.headers())
+        );
+        Err(ErrorForbidden("Access denied"))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RefererFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RefererFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RefererFilterMiddleware {
+            service,
+            filter: self.clone(),
+        }))
+    }
+}
+
+pub struct RefererFilterMiddleware<S> {
+    service: S,
+    filter: RefererFilter,
+}
+
+impl<S, B> Service<ServiceRequest> for RefererFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if self.filter.0.is_empty() {
+            return Box::pin(self.service.call(req));
+        }
+        req.extensions_mut()
+            .insert(RefererRequest(self.filter.clone()));
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if res.request().extensions().contains::<RefererChecked>() {
+                // the response depends on the embedding site, so it must not be shared with other sites
+                res.headers_mut()
+                    .append(VARY, HeaderValue::from_static("Origin, Referer"));
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderName;
+    use indoc::indoc;
+
+    use super::*;
+
+    fn filter() -> RefererFilter {
+        let cfg: BTreeMap<String, RefererRules> = serde_yaml::from_str(indoc! {"
+            licensed:
+              allow: [https://app.example.com, 'https://*.partner.org', 'localhost:8080']
+            internal:
+              allow: [intranet.example.com]
+              allow_missing: true
+        "})
+        .unwrap();
+        validate_referer_filter(&cfg).unwrap();
+        RefererFilter::new(&cfg)
+    }
+
+    fn headers(name: HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn origins() {
+        let filter = filter();
+        let check = |name, value, ids| filter.is_allowed(&headers(name, value), ids);
+
+        assert_eq!(check(ORIGIN, "https://evil.com", "public"), None);
+        assert_eq!(filter.is_allowed(&HeaderMap::new(), "public"), None);
+
+        let origin = "https://app.example.com";
+        assert_eq!(check(ORIGIN, origin, "licensed"), Some(true));
+        assert_eq!(
+            check(ORIGIN, "https://APP.example.com", "licensed"),
+            Some(true)
+        );
+        assert_eq!(
+            check(ORIGIN, "http://app.example.com", "licensed"),
+            Some(false)
+        );
+        assert_eq!(
+            check(ORIGIN, "https://app.example.com:8443", "licensed"),
+            Some(false)
+        );
+        assert_eq!(
+            check(ORIGIN, "https://a.b.partner.org", "licensed"),
+            Some(true)
+        );
+        assert_eq!(
+            check(ORIGIN, "https://partner.org", "licensed"),
+            Some(false)
+        );
+        assert_eq!(
+            check(ORIGIN, "https://evilpartner.org", "licensed"),
+            Some(false)
+        );
+        assert_eq!(
+            check(ORIGIN, "http://localhost:8080", "licensed"),
+            Some(true)
+        );
+        assert_eq!(check(ORIGIN, "not a url", "licensed"), Some(false));
+
+        let referer = "https://app.example.com/maps/view?x=1";
+        assert_eq!(check(REFERER, referer, "licensed"), Some(true));
+        assert_eq!(check(REFERER, referer, "public,internal"), Some(false));
+        let referer = "http://intranet.example.com/";
+        assert_eq!(check(REFERER, referer, "public,internal"), Some(true));
+
+        assert_eq!(
+            filter.is_allowed(&HeaderMap::new(), "licensed"),
+            Some(false)
+        );
+        assert_eq!(filter.is_allowed(&HeaderMap::new(), "internal"), Some(true));
+        assert_eq!(check(ORIGIN, "null", "internal"), Some(true));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in [
+            "",
+            "https://",
+            "https://app.example.com/maps",
+            "*.com:http",
+            "a.*.com",
+        ] {
+            let cfg = BTreeMap::from([(
+                "src".to_string(),
+                RefererRules {
+                    allow: vec![pattern.to_string()],
+                    allow_missing: None,
+                },
+            )]);
+            assert!(validate_referer_filter(&cfg).is_err(), "{pattern}");
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::future::{ready, Ready};
+
+use actix_web::body::{to_bytes, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::{Error, HttpMessage as _};
+use futures::future::LocalBoxFuture;
+use ring::rand::{SecureRandom as _, SystemRandom};
+
+/// Header with the ID of a request, accepted from the client and echoed in the response
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest request ID accepted from a client, longer ones are replaced with a generated ID
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// ID of the request handled by the current task
+    static REQUEST_ID: String;
+}
+
+/// ID of the request handled by the current task, e.g. to include it in the log lines.
+/// Returns `None` outside of the requests wrapped with [`RequestId`].
+#[must_use]
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Get the valid request ID sent by the client, or generate a random one.
+/// Only short IDs of letters, digits, and `-_.:` are accepted, so that they cannot forge log lines.
+#[must_use]
+pub fn get_request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| {
+            !v.is_empty()
+                && v.len() <= MAX_REQUEST_ID_LEN
+                && v.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        })
+        .map_or_else(new_request_id, ToString::to_string)
+}
+
+fn new_request_id() -> String {
+    let mut id = [0_u8; 16];
+    // the system random generator does not fail on the supported platforms
+    let _ = SystemRandom::new().fill(&mut id);
+    hex::encode(id)
+}
+
+/// ID of the current request, stored in the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestIdValue(pub String);
+
+/// Middleware that assigns an ID to each request, taken from the `X-Request-Id` header or generated.
+/// The ID is available to the log lines with [`current_request_id`], recorded in the tracing span,
+/// sent back in the `X-Request-Id` response header, and appended to the text of the error responses.
+#[derive(Debug, Clone, Default)]
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = get_request_id(req.headers());
+        tracing::Span::current().record("request.id", id.as_str());
+        req.extensions_mut().insert(RequestIdValue(id.clone()));
+        let fut = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let mut res = with_error_id(fut.await?, &id).await?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        }))
+    }
+}
+
+/// Append the request ID to the text of an error response, so that it can be reported by the users
+async fn with_error_id<B: MessageBody + 'static>(
+    res: ServiceResponse<B>,
+    id: &str,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let is_text = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("text/plain"));
+    if !is_text || !(res.status().is_client_error() || res.status().is_server_error()) {
+        return Ok(res.map_into_left_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        ErrorInternalServerError(e.to_string())
+    })?;
+    let text = if body.is_empty() {
+        format!("Request ID: {id}")
+    } else {
+        format!("{}\nRequest ID: {id}", String::from_utf8_lossy(&body))
+    };
+    let res = res
+        .set_body(text)
+        .map_into_boxed_body()
+        .map_into_right_body();
+    Ok(ServiceResponse::new(req, res))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    #[test]
+    fn request_ids() {
+        let headers = |v: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderValue::from_static(v),
+            );
+            headers
+        };
+        assert_eq!(get_request_id(&headers("abc-123:4.5_6")), "abc-123:4.5_6");
+        // invalid IDs are replaced with generated ones
+        let id = get_request_id(&headers("abc\" injected=1"));
+        assert_eq!(id.len(), 32);
+        assert_ne!(get_request_id(&HeaderMap::new()), id);
+        assert!(current_request_id().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn request_id_middleware() {
+        let app = init_service(
+            App::new()
+                .wrap(RequestId)
+                .route(
+                    "/ok",
+                    web::get()
+                        .to(|| async { HttpResponse::Ok().body(current_request_id().unwrap()) }),
+                )
+                .route(
+                    "/err",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(actix_web::error::ErrorNotFound("No such source"))
+                    }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/ok")
+            .insert_header((REQUEST_ID_HEADER, "req-1"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "req-1");
+        assert_eq!(read_body(resp).await, "req-1");
+
+        let req = TestRequest::get().uri("/err").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        let body = read_body(resp).await;
+        assert_eq!(
+            body,
+            format!("No such source\nRequest ID: {}", id.to_str().unwrap())
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::io::Cursor;
+use std::time::Instant;
+
+use actix_web::error::{ErrorBadRequest, ErrorGatewayTimeout, ErrorNotFound};
+use actix_web::http::header::{CACHE_CONTROL, VARY};
+use actix_web::web::{Data, Path};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use futures::future::try_join_all;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView as _, ImageFormat, ImageOutputFormat, ImageResult};
+use log::warn;
+use martin_tile_utils::{Format, TileInfo};
+
+use crate::source::{TileData, TileSources};
+use crate::srv::server::{map_internal_error, redirect_sources, resolve_sources};
+use crate::srv::throttle::use_tile;
+use crate::srv::{
+    get_request_claims, get_tile_content, JwtClaims, SourceRedirects, TileOptions, TileRequest,
+};
+use crate::utils::MAX_ZOOM;
+use crate::TileCoord;
+
+/// Width and height of the high-resolution tiles in pixels
+pub const RETINA_TILE_SIZE: u32 = 512;
+/// Quality of the high-resolution JPEG tiles
+const RETINA_JPEG_QUALITY: u8 = 90;
+
+/// Serve a 512 pixel tile of a PNG or JPEG raster source for the high-DPI screens. The tile is
+/// stitched from the four tiles of the next zoom, or upsampled if any of them is not available.
+/// The extension selects the format of the served tile, independent of the stored one.
+#[route(
+    "/{source_ids}/{z}/{x}/{y}@2x.{format}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn get_retina_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 3) {
+        return Ok(resp);
+    }
+    let format = req
+        .match_info()
+        .get("format")
+        .and_then(Format::parse)
+        .filter(|v| matches!(v, Format::Png | Format::Jpeg))
+        .ok_or_else(|| ErrorNotFound("High-resolution tiles are only served as png or jpg"))?;
+    let xyz = path.tile_coord()?;
+    let (source_ids, vary) = resolve_sources(&req, &sources, &path.source_ids)?;
+    // counted as a tile of the zoom level it is stitched from
+    use_tile(&req, xyz.z + 1)?;
+
+    let source_ids = &source_ids;
+    let claims = get_request_claims(&req, options.jwt.as_ref())?;
+    if let Some(traffic) = &options.traffic {
+        traffic.record(req.path(), req.query_string());
+    }
+    let start = Instant::now();
+    let image = get_retina_image(
+        &sources,
+        &options,
+        xyz,
+        source_ids,
+        req.query_string(),
+        claims.as_ref(),
+    );
+    let image = if let Some(timeout) = options.request_timeout {
+        tokio::time::timeout(timeout, image).await.map_err(|_| {
+            let msg = format!("high-resolution tile {xyz} of {source_ids}");
+            warn!("Request for {msg} timed out after {timeout:?}");
+            ErrorGatewayTimeout("Tile request timed out")
+        })?
+    } else {
+        image.await
+    }?;
+    let Some(image) = image else {
+        return Ok(options.empty_tiles.get(source_ids).response());
+    };
+    let data = encode_image(&image, format).map_err(map_internal_error)?;
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(format.content_type());
+    if let Some(max_age) = options.expiration.max_age(xyz.z) {
+        let max_age = max_age.as_secs();
+        let scope = if claims.is_some() {
+            "private"
+        } else {
+            "public"
+        };
+        response.insert_header((CACHE_CONTROL, format!("{scope}, max-age={max_age}")));
+    }
+    if !vary.is_empty() {
+        response.insert_header((VARY, vary.join(", ")));
+    }
+    if let Some(usage) = &options.usage {
+        usage.record(source_ids, data.len(), start.elapsed());
+    }
+    Ok(response.body(data))
+}
+
+/// Get the high-resolution image of a tile, or `None` if the sources have no tile there
+async fn get_retina_image(
+    sources: &TileSources,
+    options: &TileOptions,
+    xyz: TileCoord,
+    source_ids: &str,
+    query: &str,
+    claims: Option<&JwtClaims>,
+) -> ActixResult<Option<DynamicImage>> {
+    let (all_sources, use_url_query, info) = sources.get_sources(source_ids, None)?;
+    if !matches!(info.format, Format::Png | Format::Jpeg) {
+        return Err(ErrorBadRequest(format!(
+            "Only PNG and JPEG tiles can be served in high resolution, but {source_ids} has {info} tiles"
+        )));
+    }
+    if let Some(src) = all_sources
+        .iter()
+        .find(|src| options.encryption.is_encrypted(src.get_id()))
+    {
+        return Err(ErrorBadRequest(format!(
+            "Tiles of {} are encrypted, and cannot be served in high resolution",
+            src.get_id()
+        )));
+    }
+    let query = Some(query).filter(|q| use_url_query && !q.is_empty());
+    let claims = claims.filter(|_| use_url_query);
+
+    if xyz.z < MAX_ZOOM {
+        let (children_sources, _, _) = sources.get_sources(source_ids, Some(xyz.z + 1))?;
+        if !children_sources.is_empty() {
+            let children_sources = &children_sources;
+            let children = try_join_all(get_child_tiles(xyz).map(|child| async move {
+                let tile =
+                    get_tile_content(children_sources, options, info, &child, query, claims, None)
+                        .await?;
+                Ok::<_, actix_web::Error>(tile.data)
+            }))
+            .await?;
+            if children.iter().all(|v| !v.is_empty()) {
+                let children = children
+                    .iter()
+                    .map(|v| decode_image(v, info))
+                    .collect::<ImageResult<Vec<_>>>()
+                    .map_err(map_internal_error)?;
+                return Ok(Some(stitch_tiles(&children)));
+            }
+        }
+    }
+
+    let (parent_sources, _, _) = sources.get_sources(source_ids, Some(xyz.z))?;
+    if parent_sources.is_empty() {
+        return Ok(None);
+    }
+    let tile = get_tile_content(&parent_sources, options, info, &xyz, query, claims, None).await?;
+    if tile.data.is_empty() {
+        return Ok(None);
+    }
+    let image = decode_image(&tile.data, info).map_err(map_internal_error)?;
+    Ok(Some(image.resize_exact(
+        RETINA_TILE_SIZE,
+        RETINA_TILE_SIZE,
+        FilterType::Lanczos3,
+    )))
+}
+
+/// The four tiles of the next zoom covering the tile, in the row-major order
+fn get_child_tiles(xyz: TileCoord) -> [TileCoord; 4] {
+    [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| TileCoord {
+        z: xyz.z + 1,
+        x: xyz.x * 2 + dx,
+        y: xyz.y * 2 + dy,
+    })
+}
+
+fn decode_image(data: &TileData, info: TileInfo) -> ImageResult<DynamicImage> {
+    let format = if info.format == Format::Jpeg {
+        ImageFormat::Jpeg
+    } else {
+        ImageFormat::Png
+    };
+    image::load_from_memory_with_format(data, format)
+}
+
+/// Place the four child tiles into the quadrants of a high-resolution tile,
+/// resampling the ones that are not exactly half of its size
+fn stitch_tiles(children: &[DynamicImage]) -> DynamicImage {
+    let half = RETINA_TILE_SIZE / 2;
+    let mut result = DynamicImage::new_rgba8(RETINA_TILE_SIZE, RETINA_TILE_SIZE);
+    for (idx, child) in (0_u32..).zip(children) {
+        let child = if child.dimensions() == (half, half) {
+            child.to_rgba8()
+        } else {
+            child
+                .resize_exact(half, half, FilterType::Lanczos3)
+                .to_rgba8()
+        };
+        let x = i64::from(idx % 2 * half);
+        let y = i64::from(idx / 2 * half);
+        imageops::replace(&mut result, &DynamicImage::ImageRgba8(child), x, y);
+    }
+    result
+}
+
+fn encode_image(image: &DynamicImage, format: Format) -> ImageResult<Vec<u8>> {
+    let mut result = Cursor::new(Vec::new());
+    if format == Format::Jpeg {
+        // JPEG has no alpha channel
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut result, ImageOutputFormat::Jpeg(RETINA_JPEG_QUALITY))?;
+    } else {
+        image.write_to(&mut result, ImageOutputFormat::Png)?;
+    }
+    Ok(result.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn tile(size: u32, color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(size, size, Rgba(color)))
+    }
+
+    #[test]
+    fn child_tiles() {
+        let children = get_child_tiles(TileCoord { z: 3, x: 2, y: 5 });
+        let coords: Vec<_> = children.iter().map(|t| (t.z, t.x, t.y)).collect();
+        assert_eq!(coords, vec![(4, 4, 10), (4, 5, 10), (4, 4, 11), (4, 5, 11)]);
+    }
+
+    #[test]
+    fn stitch_child_tiles() {
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let clear = [0, 0, 0, 0];
+        // the tiles of another size are resampled to fit their quadrant
+        let children = [
+            tile(256, red),
+            tile(256, green),
+            tile(512, blue),
+            tile(64, clear),
+        ];
+        let image = stitch_tiles(&children);
+        assert_eq!(image.dimensions(), (RETINA_TILE_SIZE, RETINA_TILE_SIZE));
+        assert_eq!(image.get_pixel(10, 10).0, red);
+        assert_eq!(image.get_pixel(300, 10).0, green);
+        assert_eq!(image.get_pixel(10, 300).0, blue);
+        assert_eq!(image.get_pixel(300, 300).0, clear);
+
+        let png = encode_image(&image, Format::Png).unwrap();
+        assert_eq!(image::guess_format(&png).unwrap(), ImageFormat::Png);
+        let jpeg = encode_image(&image, Format::Jpeg).unwrap();
+        let decoded = image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!(decoded.dimensions(), (RETINA_TILE_SIZE, RETINA_TILE_SIZE));
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, trace};
+
+use crate::source::{Source, UrlQuery};
+use crate::srv::server::store_cached_tile;
+use crate::srv::TileOptions;
+use crate::utils::saturation::{STALE_TILES_REFRESHED, STALE_TILES_SERVED};
+use crate::{CacheKey, TileCoord};
+
+/// Fetches the stale tiles again in the background, while the stale tiles are served
+#[derive(Clone, Default)]
+pub struct Revalidator {
+    running: Arc<Mutex<HashSet<CacheKey>>>,
+}
+
+impl Debug for Revalidator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let count = self.running.lock().map_or(0, |v| v.len());
+        f.debug_struct("Revalidator")
+            .field("running", &count)
+            .finish()
+    }
+}
+
+impl Revalidator {
+    /// Fetch the stale tile again in the background and store it in all caches, unless it is
+    /// being fetched already. If the fetch fails, the stale tile is served until its stale window has passed.
+    /// The compressed and converted tiles made of the stale tile are removed once it is fetched again.
+    pub fn revalidate(
+        &self,
+        src: &dyn Source,
+        options: &TileOptions,
+        key: CacheKey,
+        xyz: TileCoord,
+        query: Option<UrlQuery>,
+    ) {
+        STALE_TILES_SERVED.fetch_add(1, Ordering::Relaxed);
+        if !self.running.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        trace!("Revalidating stale {key:?}");
+        let src = src.clone_source();
+        let options = options.clone();
+        let running = self.running.clone();
+        actix_rt::spawn(async move {
+            let fetch = src.get_tile(&xyz, &query);
+            match options.concurrency_limits.run(src.get_id(), fetch).await {
+                Ok(data) => {
+                    STALE_TILES_REFRESHED.fetch_add(1, Ordering::Relaxed);
+                    store_cached_tile(&options, key.clone(), &data).await;
+                    if let Some(cache) = &options.cache {
+                        let tile = key.clone();
+                        if let Err(e) =
+                            cache.invalidate_entries_if(move |k, _| k.is_derived_from(&tile))
+                        {
+                            debug!("Unable to remove the tiles made of the stale {key:?}: {e}");
+                        }
+                    }
+                }
+                Err(e) => debug!("Unable to revalidate {key:?}, serving the stale tile: {e}"),
+            }
+            running.lock().unwrap().remove(&key);
+        });
+    }
+}
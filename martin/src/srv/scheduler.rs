@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval_at, timeout, Instant, MissedTickBehavior};
+
+use crate::config::Config;
+use crate::fonts::FontSources;
+use crate::source::{Source, TileSources};
+use crate::sprites::SpriteSources;
+use crate::srv::admin::update_catalog;
+use crate::srv::{CachePurger, Catalog, CatalogEvent, Readiness, Webhooks, RESERVED_KEYWORDS};
+use crate::utils::{CacheKey, OptMainCache, TileExpiration};
+use crate::MartinError::InvalidScheduledTask;
+use crate::{IdResolver, MartinResult, TileCoord};
+
+/// Maximum time to wait for a rediscovered source to pass validation
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScheduledTask {
+    /// How often to run the task, in seconds. The first run happens one interval after startup.
+    pub interval_secs: u64,
+    #[serde(flatten)]
+    pub task: TaskConfig,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum TaskConfig {
+    /// Discover all tile sources again, adding the new ones, removing the missing ones,
+    /// and recomputing the bounds of table sources
+    Rediscover,
+    /// Remove expired tiles from the cache, freeing their memory right away
+    PruneCache,
+    /// Regenerate the given tiles of the given sources, and store them in the cache
+    Seed {
+        sources: Vec<String>,
+        /// Tiles in the `z/x/y` form
+        tiles: Option<Vec<String>>,
+        /// File with one `z/x/y` tile per line, read on every run
+        tiles_file: Option<PathBuf>,
+    },
+    /// Refresh the materialized views the given sources depend on, and purge their cached tiles
+    Refresh { sources: Vec<String> },
+    /// Download the remote sprite sources again, and regenerate their spritesheets
+    RefreshSprites,
+}
+
+impl ScheduledTask {
+    /// Validate the task configuration
+    pub fn finalize(&self) -> MartinResult<()> {
+        if self.interval_secs == 0 {
+            return Err(InvalidScheduledTask(
+                "interval_secs must be greater than 0".to_string(),
+            ));
+        }
+        if let TaskConfig::Seed { tiles, .. } = &self.task {
+            for tile in tiles.iter().flatten() {
+                if parse_tile(tile).is_none() {
+                    return Err(InvalidScheduledTask(format!(
+                        "{tile} is not a valid z/x/y tile"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a tile in the `z/x/y` form, making sure it exists at its zoom level
+fn parse_tile(value: &str) -> Option<TileCoord> {
+    let mut parts = value.trim().split('/');
+    let z: u8 = parts.next()?.parse().ok()?;
+    let x: u32 = parts.next()?.parse().ok()?;
+    let y: u32 = parts.next()?.parse().ok()?;
+    let max = 1_u64 << z.min(32);
+    (parts.next().is_none() && z <= 32 && u64::from(x) < max && u64::from(y) < max)
+        .then_some(TileCoord { z, x, y })
+}
+
+/// Changes made by discovering the sources again
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub refreshed: Vec<String>,
+    pub removed: Vec<String>,
+    /// New or changed sources that failed validation, with the error.
+    /// Changed sources keep serving their previous version.
+    pub rolled_back: BTreeMap<String, String>,
+}
+
+impl ReloadSummary {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.refreshed.is_empty()
+            && self.removed.is_empty()
+            && self.rolled_back.is_empty()
+    }
+}
+
+/// Make sure a discovered source works before it is published: its backend must pass
+/// the health check, and it must return the first tile of its lowest zoom level.
+async fn validate_source(src: &dyn Source) -> Result<(), String> {
+    let check = async {
+        src.check_health().await?;
+        let z = src.get_tilejson().minzoom.unwrap_or(0);
+        src.get_tile(&TileCoord { z, x: 0, y: 0 }, &None).await?;
+        MartinResult::Ok(())
+    };
+    match timeout(VALIDATION_TIMEOUT, check).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!(
+            "Validation timed out after {}s",
+            VALIDATION_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+/// Everything the scheduled tasks may need to access or modify
+#[derive(Clone)]
+pub struct Scheduler {
+    pub discovery: Config,
+    pub sources: Data<ArcSwap<TileSources>>,
+    pub catalog: Data<ArcSwap<Catalog>>,
+    pub cache: OptMainCache,
+    /// Expiration of the cached tiles, applied to the seeded tiles like to the requested ones
+    pub expiration: TileExpiration,
+    pub purger: CachePurger,
+    pub sprites: SpriteSources,
+    pub fonts: Data<ArcSwap<FontSources>>,
+    pub readiness: Readiness,
+    pub webhooks: Webhooks,
+}
+
+impl Scheduler {
+    /// Start a background loop for each task
+    pub fn start(self, tasks: &[ScheduledTask]) {
+        for task in tasks {
+            let needs_cache = !matches!(
+                task.task,
+                TaskConfig::Rediscover | TaskConfig::Refresh { .. } | TaskConfig::RefreshSprites
+            );
+            if self.cache.is_none() && needs_cache {
+                warn!("Scheduled task {task:?} is ignored because the tile cache is disabled");
+                continue;
+            }
+            actix_rt::spawn(self.clone().run(task.clone()));
+        }
+    }
+
+    async fn run(self, task: ScheduledTask) {
+        let period = Duration::from_secs(task.interval_secs);
+        let mut timer = interval_at(Instant::now() + period, period);
+        // Do not try to catch up if a run took longer than the interval
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            debug!("Running scheduled task {:?}", task.task);
+            match &task.task {
+                TaskConfig::Rediscover => self.rediscover().await,
+                TaskConfig::PruneCache => self.prune_cache().await,
+                TaskConfig::Seed {
+                    sources,
+                    tiles,
+                    tiles_file,
+                } => {
+                    self.seed(sources, tiles.as_ref(), tiles_file.as_ref())
+                        .await;
+                }
+                TaskConfig::Refresh { sources } => {
+                    let current = self.sources.load_full();
+                    for id in sources {
+                        match current.get_source(id) {
+                            Ok(src) => {
+                                if let Err(e) = self.refresh_source(src).await {
+                                    warn!("Unable to refresh the data of source {id}: {e}");
+                                }
+                            }
+                            Err(_) => {
+                                warn!("Unable to refresh {id} because the source does not exist");
+                            }
+                        }
+                    }
+                }
+                TaskConfig::RefreshSprites => self.refresh_sprites().await,
+            }
+        }
+    }
+
+    /// Refresh the data the source depends on, e.g. its materialized views,
+    /// and purge its cached tiles once the refresh has completed
+    pub(crate) async fn refresh_source(&self, src: &dyn Source) -> MartinResult<()> {
+        src.refresh_data().await?;
+        self.purge(src.get_id()).await;
+        self.webhooks.notify(CatalogEvent {
+            refreshed: vec![src.get_id().to_string()],
+            ..CatalogEvent::new(&self.catalog.load())
+        });
+        Ok(())
+    }
+
+    async fn rediscover(&self) {
+        if let Err(e) = self.rediscover_files(&[]).await {
+            warn!("Source rediscovery failed, keeping the current sources: {e}");
+        }
+    }
+
+    /// Discover all tile sources again, and apply the changes one source at a time.
+    /// New sources, and the sources read from any of the `changed` absolute file paths, must pass
+    /// validation before they are published. Otherwise the previous version of the source is kept.
+    /// The cached tiles of the removed and replaced sources are purged, and the webhooks are notified.
+    /// `/health/ready` returns 503 until the reload is done.
+    pub(crate) async fn rediscover_files(
+        &self,
+        changed: &[PathBuf],
+    ) -> MartinResult<ReloadSummary> {
+        let _reloading = self.readiness.begin_reload();
+        let mut config = self.discovery.clone();
+        let idr = IdResolver::new(RESERVED_KEYWORDS);
+        let mut new_sources = config.resolve_tile_sources(idr).await?;
+        // the sources of an embedding application and the changes of the admin API are left alone
+        let (old_ids, new_ids) = {
+            let current = self.sources.load();
+            let discovered = |ids: BTreeSet<String>| -> BTreeSet<String> {
+                ids.into_iter()
+                    .filter(|v| current.is_discovered(v))
+                    .collect()
+            };
+            (
+                discovered(current.source_ids()),
+                discovered(new_sources.source_ids()),
+            )
+        };
+        let changed_ids: BTreeSet<_> = config
+            .find_file_sources(changed)
+            .into_iter()
+            .filter(|id| old_ids.contains(id) && new_ids.contains(id))
+            .collect();
+        let mut summary = ReloadSummary::default();
+
+        // Sources with unchanged files are replaced together, e.g. to update the table bounds
+        let unchanged: Vec<_> = old_ids
+            .intersection(&new_ids)
+            .filter(|id| !changed_ids.contains(*id))
+            .filter_map(|id| new_sources.remove(id))
+            .collect();
+        if !unchanged.is_empty() {
+            self.update_sources(|sources| {
+                for src in &unchanged {
+                    sources.insert(src.clone());
+                }
+            });
+        }
+
+        for id in old_ids.difference(&new_ids) {
+            info!("Removing source {id} because it no longer exists");
+            self.update_sources(|sources| {
+                sources.remove(id);
+            });
+            self.purge(id).await;
+            summary.removed.push(id.clone());
+        }
+
+        for id in new_ids.difference(&old_ids).chain(&changed_ids) {
+            let Some(src) = new_sources.remove(id) else {
+                continue;
+            };
+            let is_new = !old_ids.contains(id);
+            if let Err(e) = validate_source(src.as_ref()).await {
+                if is_new {
+                    warn!("New source {id} failed validation and is not published: {e}");
+                } else {
+                    warn!(
+                        "Changed source {id} failed validation, keeping the previous version: {e}"
+                    );
+                }
+                summary.rolled_back.insert(id.clone(), e);
+                continue;
+            }
+            self.update_sources(|sources| sources.insert(src.clone()));
+            if is_new {
+                info!("Rediscovered new source {id}");
+                summary.added.push(id.clone());
+            } else {
+                info!("Refreshed source {id} because its file has changed");
+                self.purge(id).await;
+                summary.refreshed.push(id.clone());
+            }
+        }
+
+        if !summary.is_empty() {
+            info!(
+                "Reloaded sources: {} added, {} refreshed, {} removed, {} rolled back",
+                summary.added.len(),
+                summary.refreshed.len(),
+                summary.removed.len(),
+                summary.rolled_back.len()
+            );
+            self.webhooks.notify(CatalogEvent {
+                added: summary.added.clone(),
+                removed: summary.removed.clone(),
+                refreshed: summary.refreshed.clone(),
+                ..CatalogEvent::new(&self.catalog.load())
+            });
+        }
+        Ok(summary)
+    }
+
+    async fn refresh_sprites(&self) {
+        if !self.sprites.has_remote() {
+            warn!(
+                "Scheduled sprite refresh has nothing to do, as there are no remote sprite sources"
+            );
+            return;
+        }
+        if self.sprites.refresh_remote().await > 0 {
+            if let Err(e) = self.reload_sprites() {
+                warn!("Unable to reload the sprites: {e}");
+            }
+        }
+    }
+
+    /// Drop the generated spritesheets, and list the sprites of the catalog again
+    /// after the SVG files of the sprite directories have changed
+    pub(crate) fn reload_sprites(&self) -> MartinResult<()> {
+        self.sprites.invalidate();
+        let sprites = self.sprites.get_catalog()?;
+        self.catalog.rcu(|current| Catalog {
+            sprites: sprites.clone(),
+            ..Catalog::clone(current)
+        });
+        info!("Reloaded sprites: {} sprite sources", sprites.len());
+        Ok(())
+    }
+
+    /// Scan the font directories again after fonts were added, removed, or modified,
+    /// and publish the new fonts and their catalog. The current fonts are kept if any of them fails.
+    pub(crate) fn reload_fonts(&self) -> MartinResult<()> {
+        let fonts = self.fonts.load().reload()?;
+        let catalog = fonts.get_catalog();
+        self.fonts.store(Arc::new(fonts));
+        self.catalog.rcu(|current| Catalog {
+            fonts: catalog.clone(),
+            ..Catalog::clone(current)
+        });
+        info!("Reloaded fonts: {} fonts", catalog.len());
+        Ok(())
+    }
+
+    /// Publish a modified copy of the current sources, and update the catalog
+    fn update_sources(&self, update: impl Fn(&mut TileSources)) {
+        self.sources.rcu(|current| {
+            let mut updated = TileSources::clone(current);
+            update(&mut updated);
+            updated
+        });
+        update_catalog(&self.sources, &self.catalog);
+    }
+
+    pub(crate) async fn purge(&self, id: &str) {
+        if let Err(e) = self.purger.purge(vec![id.to_string()]).await {
+            warn!("Unable to purge the tiles of source {id}: {e}");
+        }
+    }
+
+    async fn prune_cache(&self) {
+        if let Some(cache) = &self.cache {
+            let before = cache.entry_count();
+            cache.run_pending_tasks().await;
+            debug!(
+                "Pruned the tile cache from {before} to {} entries",
+                cache.entry_count()
+            );
+        }
+    }
+
+    async fn seed(&self, ids: &[String], tiles: Option<&Vec<String>>, file: Option<&PathBuf>) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let mut coords: Vec<TileCoord> = tiles
+            .into_iter()
+            .flatten()
+            .filter_map(|v| parse_tile(v))
+            .collect();
+        if let Some(file) = file {
+            match tokio::fs::read_to_string(file).await {
+                Ok(contents) => {
+                    for line in contents.lines().filter(|v| !v.trim().is_empty()) {
+                        if let Some(xyz) = parse_tile(line) {
+                            coords.push(xyz);
+                        } else {
+                            warn!("Ignoring invalid tile {line} in {}", file.display());
+                        }
+                    }
+                }
+                Err(e) => warn!("Unable to read seed tiles from {}: {e}", file.display()),
+            }
+        }
+
+        let sources = self.sources.load_full();
+        let mut count = 0;
+        for id in ids {
+            let Ok(src) = sources.get_source(id) else {
+                warn!("Unable to seed tiles of {id} because the source does not exist");
+                continue;
+            };
+            for xyz in coords.iter().filter(|xyz| src.is_valid_zoom(xyz.z)) {
+                match src.get_tile(xyz, &None).await {
+                    Ok(data) => {
+                        let key = CacheKey::tile(id, *xyz, None);
+                        let value = self.expiration.new_value(&key, data);
+                        cache.insert(key, value).await;
+                        count += 1;
+                    }
+                    Err(e) => warn!("Unable to seed tile {xyz} of {id}: {e}"),
+                }
+            }
+        }
+        debug!("Seeded {count} tiles");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::file_config::FileConfigEnum;
+    use crate::mbtiles::MbtSource;
+    use crate::proxy::{ProxyConfig, ProxyConfigs};
+    use crate::utils::{new_main_cache, CacheValue};
+
+    #[test]
+    fn parse_tasks() {
+        let tasks: Vec<ScheduledTask> = serde_yaml::from_str(indoc! {"
+            - task: rediscover
+              interval_secs: 3600
+            - task: seed
+              interval_secs: 600
+              sources: [roads]
+              tiles: [0/0/0, 1/1/0]
+            - task: refresh
+              interval_secs: 900
+              sources: [roads]
+            - task: refresh_sprites
+              interval_secs: 86400
+        "})
+        .unwrap();
+        assert_eq!(
+            tasks,
+            vec![
+                ScheduledTask {
+                    interval_secs: 3600,
+                    task: TaskConfig::Rediscover,
+                },
+                ScheduledTask {
+                    interval_secs: 600,
+                    task: TaskConfig::Seed {
+                        sources: vec!["roads".to_string()],
+                        tiles: Some(vec!["0/0/0".to_string(), "1/1/0".to_string()]),
+                        tiles_file: None,
+                    },
+                },
+                ScheduledTask {
+                    interval_secs: 900,
+                    task: TaskConfig::Refresh {
+                        sources: vec!["roads".to_string()],
+                    },
+                },
+                ScheduledTask {
+                    interval_secs: 86400,
+                    task: TaskConfig::RefreshSprites,
+                },
+            ]
+        );
+        assert!(tasks.iter().all(|v| v.finalize().is_ok()));
+    }
+
+    #[actix_rt::test]
+    async fn rolling_reload() {
+        let dir = std::env::temp_dir().join(format!("martin-reload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+        std::fs::copy(fixture, dir.join("cities.mbtiles")).unwrap();
+        std::fs::copy(fixture, dir.join("old.mbtiles")).unwrap();
+
+        let mut discovery = Config {
+            mbtiles: FileConfigEnum::Path(dir.clone()),
+            ..Config::default()
+        };
+        let idr = IdResolver::new(RESERVED_KEYWORDS);
+        let sources = discovery.clone().resolve_tile_sources(idr).await.unwrap();
+        // An upstream server that refuses connections fails the validation
+        let upstream = ProxyConfig {
+            url: "http://127.0.0.1:9/{z}/{x}/{y}.png".to_string(),
+            format: Some("png".to_string()),
+            ..ProxyConfig::default()
+        };
+        discovery.proxy = Some(ProxyConfigs::from([("upstream".to_string(), upstream)]));
+        let catalog = Catalog {
+            tiles: sources.get_catalog(),
+            ..Catalog::default()
+        };
+        let scheduler = Scheduler {
+            discovery,
+            sources: Data::new(ArcSwap::from_pointee(sources)),
+            catalog: Data::new(ArcSwap::from_pointee(catalog)),
+            cache: None,
+            expiration: TileExpiration::default(),
+            purger: CachePurger::new(None, None).unwrap(),
+            sprites: SpriteSources::default(),
+            fonts: Data::new(ArcSwap::from_pointee(FontSources::default())),
+            readiness: Readiness::default(),
+            webhooks: Webhooks::default(),
+        };
+
+        std::fs::copy(fixture, dir.join("new.mbtiles")).unwrap();
+        std::fs::remove_file(dir.join("old.mbtiles")).unwrap();
+
+        let summary = scheduler.rediscover_files(&[]).await.unwrap();
+        assert_eq!(summary.added, vec!["new".to_string()]);
+        assert_eq!(summary.removed, vec!["old".to_string()]);
+        assert!(summary.refreshed.is_empty());
+        assert_eq!(
+            summary.rolled_back.keys().collect::<Vec<_>>(),
+            vec!["upstream"]
+        );
+        assert_eq!(
+            scheduler.sources.load().source_ids(),
+            BTreeSet::from(["cities".to_string(), "new".to_string()])
+        );
+        let catalog = scheduler.catalog.load();
+        assert_eq!(
+            catalog.tiles.keys().collect::<Vec<_>>(),
+            vec!["cities", "new"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn admin_changes_survive_reload() {
+        let dir = std::env::temp_dir().join(format!("martin-admin-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = "../tests/fixtures/mbtiles/world_cities.mbtiles";
+        std::fs::copy(fixture, dir.join("cities.mbtiles")).unwrap();
+        std::fs::copy(fixture, dir.join("old.mbtiles")).unwrap();
+
+        let discovery = Config {
+            mbtiles: FileConfigEnum::Path(dir.clone()),
+            ..Config::default()
+        };
+        let idr = IdResolver::new(RESERVED_KEYWORDS);
+        let sources = discovery.clone().resolve_tile_sources(idr).await.unwrap();
+        let catalog = Catalog {
+            tiles: sources.get_catalog(),
+            ..Catalog::default()
+        };
+        let scheduler = Scheduler {
+            discovery,
+            sources: Data::new(ArcSwap::from_pointee(sources)),
+            catalog: Data::new(ArcSwap::from_pointee(catalog)),
+            cache: None,
+            expiration: TileExpiration::default(),
+            purger: CachePurger::new(None, None).unwrap(),
+            sprites: SpriteSources::default(),
+            fonts: Data::new(ArcSwap::from_pointee(FontSources::default())),
+            readiness: Readiness::default(),
+            webhooks: Webhooks::default(),
+        };
+
+        // a file outside of the discovered directory is added, and a discovered source is deleted
+        let extra = MbtSource::new_box("extra".to_string(), fixture.into())
+            .await
+            .unwrap();
+        scheduler.update_sources(|sources| sources.add_admin_source(extra.clone()));
+        scheduler.update_sources(|sources| {
+            sources.delete_admin_source("cities");
+        });
+
+        let summary = scheduler.rediscover_files(&[]).await.unwrap();
+        assert!(summary.added.is_empty());
+        assert!(summary.removed.is_empty());
+        assert_eq!(
+            scheduler.sources.load().source_ids(),
+            BTreeSet::from(["extra".to_string(), "old".to_string()])
+        );
+        assert_eq!(
+            scheduler.catalog.load().tiles.keys().collect::<Vec<_>>(),
+            vec!["extra", "old"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn seed_with_expiration() {
+        let mut discovery = Config {
+            mbtiles: FileConfigEnum::Path("../tests/fixtures/mbtiles/world_cities.mbtiles".into()),
+            ..Config::default()
+        };
+        let idr = IdResolver::new(RESERVED_KEYWORDS);
+        let sources = discovery.resolve_tile_sources(idr).await.unwrap();
+        let expiration = TileExpiration::new(Some(&BTreeMap::from([(0, 60)])))
+            .with_stale_windows(Some(&BTreeMap::from([("world_cities".to_string(), 30)])));
+        let cache = new_main_cache(1, expiration.clone());
+        let scheduler = Scheduler {
+            discovery,
+            sources: Data::new(ArcSwap::from_pointee(sources)),
+            catalog: Data::new(ArcSwap::from_pointee(Catalog::default())),
+            cache: cache.clone(),
+            expiration,
+            purger: CachePurger::new(None, None).unwrap(),
+            sprites: SpriteSources::default(),
+            fonts: Data::new(ArcSwap::from_pointee(FontSources::default())),
+            readiness: Readiness::default(),
+            webhooks: Webhooks::default(),
+        };
+
+        let tiles = vec!["0/0/0".to_string()];
+        scheduler
+            .seed(&["world_cities".to_string()], Some(&tiles), None)
+            .await;
+        let key = CacheKey::tile("world_cities", TileCoord { z: 0, x: 0, y: 0 }, None);
+        let value = cache.unwrap().get(&key).await.unwrap();
+        // the seeded tile becomes stale after its max-age, just like a requested one
+        assert!(matches!(value, CacheValue::Expiring(..)));
+    }
+
+    #[test]
+    fn invalid_tiles() {
+        assert_eq!(parse_tile("2/3/1"), Some(TileCoord { z: 2, x: 3, y: 1 }));
+        assert_eq!(parse_tile("2/4/1"), None);
+        assert_eq!(parse_tile("1/0"), None);
+        assert_eq!(parse_tile("1/0/0/0"), None);
+        assert_eq!(parse_tile("a/0/0"), None);
+    }
+}
@@ -1,13 +1,21 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::string::ToString;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use actix_cors::Cors;
 use actix_http::ContentEncoding;
+use actix_web::body::{BodySize, MessageBody as _};
 use actix_web::dev::Server;
-use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::error::{
+    ErrorBadRequest, ErrorGatewayTimeout, ErrorInternalServerError, ErrorNotFound,
+    ErrorServiceUnavailable, ErrorUnauthorized,
+};
 use actix_web::http::header::{
-    AcceptEncoding, ContentType, Encoding as HeaderEnc, HeaderValue, Preference, CACHE_CONTROL,
-    CONTENT_ENCODING,
+    Accept, AcceptEncoding, ContentType, Encoding as HeaderEnc, HeaderMap, HeaderName, HeaderValue,
+    Preference, CACHE_CONTROL, CONTENT_ENCODING, ETAG, EXPIRES, LINK, VARY,
 };
 use actix_web::http::Uri;
 use actix_web::middleware::TrailingSlash;
@@ -16,33 +24,78 @@ use actix_web::{
     middleware, route, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
     Result as ActixResult,
 };
+use arc_swap::ArcSwap;
 use futures::future::try_join_all;
 use itertools::Itertools as _;
-use log::error;
+use log::{error, trace, warn};
 use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::{Deserialize, Serialize};
-use tilejson::{tilejson, TileJSON};
+use tilejson::{tilejson, TileJSON, VectorLayer};
+use tracing::{info_span, Instrument as _};
 
 use crate::config::ServerState;
+use crate::demo::{demo_index, DemoMode};
 use crate::fonts::{FontCatalog, FontError, FontSources};
-use crate::source::{Source, TileCatalog, TileSources, UrlQuery};
+use crate::pg::PgError;
+use crate::source::{Source, TileCatalog, TileData, TileHeaders, TileSources, UrlQuery};
 use crate::sprites::{SpriteCatalog, SpriteError, SpriteSources};
-use crate::srv::config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
-use crate::utils::{decode_brotli, decode_gzip, encode_brotli, encode_gzip};
-use crate::MartinError::BindingError;
-use crate::{MartinResult, Tile, TileCoord};
+use crate::srv::config::{
+    AdminConfig, CompositeTileJsonConfig, SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT,
+};
+use crate::srv::health::drain_on_shutdown;
+use crate::srv::ip_filter::check_source_access;
+use crate::srv::prefetch::get_sibling_tiles;
+use crate::srv::referer_filter::check_referer_access;
+use crate::srv::tenants::check_tenant_access;
+use crate::srv::throttle::use_tile;
+use crate::srv::watcher::start_watcher;
+#[cfg(feature = "grpc")]
+use crate::srv::{config::GrpcConfig, GrpcService};
+use crate::srv::{
+    get_public_url, get_request_claims, start_notification_listeners, CachePurger, CatalogChanges,
+    CatalogQuery, CdnPurger, CompressionLevels, ConcurrencyLimits, EmptyTile, EmptyTiles,
+    HostCache, InternalRoutes, IpFilter, JwtClaims, JwtValidator, Prefetcher, PublicUrl,
+    RasterTranscoder, Readiness, RefererFilter, RequestId, RequestTracing, RequestUrl, Revalidator,
+    RuntimeInfo, Scheduler, ServerTiming, SharedCache, SingleFlight, SourceRedirects,
+    StaticFilesConfig, SurrogateKeys, TaskConfig, TenantId, Tenants, Throttle, TileCompression,
+    TileEncryption, TileSizeLimits, TrafficRecorder, UsageStats, Webhooks, CLAIM_QUERY_PREFIX,
+    ENCRYPTION_ALGORITHM, TOTAL_COUNT_HEADER,
+};
+use crate::styles::{StyleCatalog, StyleError, StyleSources};
+use crate::terrain::TerrainError;
+use crate::utils::mvt::{filter_layers, filter_properties};
+use crate::utils::saturation::{
+    GaugeGuard, PendingTile, CACHE_HITS, CACHE_MISSES, COMPRESSION_QUEUE, VARIANT_CACHE_HITS,
+    VARIANT_CACHE_MISSES,
+};
+use crate::utils::{
+    decode_brotli, decode_gzip, decode_zstd, encode_brotli_with_quality, encode_gzip_with_level,
+    encode_zstd, CacheKey, CacheValue, OptMainCache, TileExpiration, MAX_ZOOM,
+};
+use crate::MartinError::{BindingError, SourceTimeout};
+use crate::{MartinError, MartinResult, Tile, TileCoord, TileMatrixSet};
 
 /// List of keywords that cannot be used as source IDs. Some of these are reserved for future use.
 /// Reserved keywords must never end in a "dot number" (e.g. ".1").
 /// This list is documented in the `docs/src/using.md` file, which should be kept in sync.
 pub const RESERVED_KEYWORDS: &[&str] = &[
-    "_", "catalog", "config", "font", "health", "help", "index", "manifest", "metrics", "refresh",
-    "reload", "sprite", "status",
+    "_", "catalog", "config", "font", "health", "help", "index", "manifest", "metrics", "package",
+    "refresh", "reload", "sprite", "status", "style",
 ];
 
+/// Format of the access log, i.e. the default format of actix-web with the request ID
+const LOG_FORMAT: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
+
+/// Maximum time to wait for each source backend to respond during a deep health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Asks the search engines not to index the tiles and `TileJSON` of the hidden sources
+const NOINDEX_HEADER: (&str, &str) = ("x-robots-tag", "noindex");
+
 static SUPPORTED_ENCODINGS: &[HeaderEnc] = &[
     HeaderEnc::brotli(),
     HeaderEnc::gzip(),
+    HeaderEnc::zstd(),
     HeaderEnc::identity(),
 ];
 
@@ -51,29 +104,207 @@ pub struct Catalog {
     pub tiles: TileCatalog,
     pub sprites: SpriteCatalog,
     pub fonts: FontCatalog,
+    #[serde(default, skip_serializing_if = "StyleCatalog::is_empty")]
+    pub styles: StyleCatalog,
+    #[serde(skip)]
+    pub changes: CatalogChanges,
 }
 
 impl Catalog {
     pub fn new(state: &ServerState) -> MartinResult<Self> {
+        let tiles = state.tiles.get_catalog();
         Ok(Self {
-            tiles: state.tiles.get_catalog(),
+            changes: CatalogChanges::new(&state.tiles, &tiles),
+            tiles,
             sprites: state.sprites.get_catalog()?,
             fonts: state.fonts.get_catalog(),
+            styles: state.styles.get_catalog(),
         })
     }
 }
 
+/// The catalog with the `TileJSON` of each tile source, as printed by `--dump-catalog`
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogDump {
+    #[serde(flatten)]
+    pub catalog: Catalog,
+    /// `TileJSON` of each tile source in the catalog, with a `tiles` URL relative to the server root
+    pub tilejson: BTreeMap<String, TileJSON>,
+}
+
+impl CatalogDump {
+    pub fn new(state: &ServerState, limits: CompositeTileJsonConfig) -> MartinResult<Self> {
+        let catalog = Catalog::new(state)?;
+        let mut tilejson = BTreeMap::new();
+        for id in catalog.tiles.keys() {
+            // the variant sources are described by their default variant, as without any request headers
+            let (source_ids, _) = state.tiles.resolve_variants(id, "", &HeaderMap::new());
+            let Ok((sources, ..)) = state.tiles.get_sources(&source_ids, None) else {
+                continue;
+            };
+            let mut tj = merge_tilejson(&sources, format!("/{id}/{{z}}/{{x}}/{{y}}"), limits);
+            if let Some(variants) = state.tiles.get_variants(id) {
+                tj.name = Some(id.clone());
+                tj.other
+                    .insert("variants".to_string(), variants.to_tilejson_value());
+            } else if state.tiles.is_composite(id) {
+                tj.name = Some(id.clone());
+            }
+            tilejson.insert(id.clone(), tj);
+        }
+        Ok(Self { catalog, tilejson })
+    }
+}
+
+#[derive(Deserialize)]
+struct HealthRequest {
+    #[serde(default)]
+    deep: bool,
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    failed: BTreeMap<String, String>,
+}
+
 #[derive(Deserialize)]
 struct TileJsonRequest {
     source_ids: String,
 }
 
+/// Settings that affect how tiles are fetched and served
+#[derive(Debug, Clone, Default)]
+pub struct TileOptions {
+    pub cache: OptMainCache,
+    /// Redis cache shared with other instances, used after the in-memory cache
+    pub shared_cache: Option<SharedCache>,
+    /// File cache shared with the other processes on this host, used before the Redis cache
+    pub host_cache: Option<HostCache>,
+    pub expiration: TileExpiration,
+    /// Maximum time to serve a single tile request
+    pub request_timeout: Option<Duration>,
+    /// Maximum time for each source to produce its tile
+    pub backend_timeout: Option<Duration>,
+    /// Maximum number of tiles each source generates at the same time
+    pub concurrency_limits: ConcurrencyLimits,
+    /// Background generation of the tiles around cache misses
+    pub prefetch: Option<Prefetcher>,
+    /// Add `Link: rel=prefetch` headers for the sibling tiles
+    pub prefetch_hints: bool,
+    /// Keys of the sources whose tiles are encrypted
+    pub encryption: TileEncryption,
+    /// Validation of the tokens whose claims are passed to the function sources
+    pub jwt: Option<JwtValidator>,
+    /// Counts of the requested tile paths, exported as a load test profile
+    pub traffic: Option<TrafficRecorder>,
+    /// Requests, bytes, and latencies of each source over time
+    pub usage: Option<UsageStats>,
+    /// Add the `Server-Timing` header to tile responses
+    pub server_timing: bool,
+    /// Limits of the `TileJSON` merged from many sources
+    pub composite_tilejson: CompositeTileJsonConfig,
+    /// Concurrent fetches of the same tile, shared by all requests for it, with the caching headers
+    /// of the tile and whether it is stale, see [`Tile::stale`]
+    pub single_flight: SingleFlight<(TileData, Option<TileHeaders>, bool)>,
+    /// Responses to the empty tiles of each source, `204 No Content` by default
+    pub empty_tiles: EmptyTiles,
+    /// Maximum size of the tiles of each source
+    pub tile_size_limits: TileSizeLimits,
+    /// Transcoding of the raster tiles to the format preferred by the clients
+    pub transcoder: Option<RasterTranscoder>,
+    /// Compression levels of the tiles compressed for the clients
+    pub compression: TileCompression,
+    /// Background fetches of the stale tiles of the sources with a stale window
+    pub revalidator: Revalidator,
+    /// Header with the surrogate keys of the tiles, used to purge them from the CDN
+    pub surrogate_keys: Option<SurrogateKeys>,
+}
+
+impl TileOptions {
+    #[must_use]
+    pub fn new(config: &SrvConfig, state: &ServerState) -> Self {
+        Self {
+            cache: state.cache.clone(),
+            shared_cache: None,
+            host_cache: None,
+            expiration: state.tile_expiration.clone(),
+            request_timeout: config.request_timeout_ms.map(Duration::from_millis),
+            backend_timeout: config.backend_timeout_ms.map(Duration::from_millis),
+            concurrency_limits: ConcurrencyLimits::new(config.concurrency_limit.as_ref()),
+            prefetch: None,
+            prefetch_hints: config.prefetch_hints.unwrap_or_default(),
+            encryption: TileEncryption::new(config.encryption.as_ref()),
+            // the config is validated by finalize(), and the sources requiring claims
+            // reject all requests if the validator is missing
+            jwt: config.jwt.as_ref().and_then(|v| JwtValidator::new(v).ok()),
+            traffic: config.traffic_profile.as_ref().map(TrafficRecorder::new),
+            usage: config.usage_stats.as_ref().map(UsageStats::new),
+            server_timing: config.server_timing.unwrap_or_default(),
+            composite_tilejson: config.composite_tilejson.unwrap_or_default(),
+            single_flight: SingleFlight::default(),
+            empty_tiles: EmptyTiles::default(),
+            tile_size_limits: TileSizeLimits::new(config.tile_size_limit.as_ref()),
+            transcoder: config.transcode.as_ref().map(RasterTranscoder::new),
+            compression: config
+                .compression
+                .as_ref()
+                .map(TileCompression::new)
+                .unwrap_or_default(),
+            revalidator: Revalidator::default(),
+            // the keys are validated by finalize()
+            surrogate_keys: config
+                .cdn
+                .as_ref()
+                .and_then(|v| SurrogateKeys::new(v).ok().flatten()),
+        }
+    }
+
+    /// Check if any of the tile caches is enabled
+    #[must_use]
+    pub fn is_cached(&self) -> bool {
+        self.cache.is_some() || self.host_cache.is_some() || self.shared_cache.is_some()
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct TileRequest {
+    pub(crate) source_ids: String,
+    pub(crate) z: u8,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+}
+
+impl TileRequest {
+    /// Get the requested tile, rejecting the tiles that cannot exist before any source is queried.
+    /// The tiles are checked against the widest grid, and against the grid of each source once it is known.
+    pub(crate) fn tile_coord(&self) -> ActixResult<TileCoord> {
+        let xyz = TileCoord {
+            z: self.z,
+            x: self.x,
+            y: self.y,
+        };
+        if xyz.z > MAX_ZOOM {
+            return Err(ErrorBadRequest(format!(
+                "Zoom {} is above the maximum zoom {MAX_ZOOM}",
+                xyz.z
+            )));
+        }
+        if !TileMatrixSet::WorldCrs84Quad.is_valid(xyz) {
+            return Err(ErrorNotFound(format!(
+                "Tile {xyz:#} is outside of the tile grid of zoom {}",
+                xyz.z
+            )));
+        }
+        Ok(xyz)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QuadkeyTileRequest {
     source_ids: String,
-    z: u8,
-    x: u32,
-    y: u32,
+    quadkey: String,
 }
 
 pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
@@ -81,6 +312,27 @@ pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
     ErrorInternalServerError(e.to_string())
 }
 
+fn map_tile_error(e: MartinError) -> actix_web::Error {
+    match e {
+        SourceTimeout(..) => {
+            warn!("{e}");
+            ErrorGatewayTimeout(e.to_string())
+        }
+        MartinError::SourceOverloaded(..) => {
+            warn!("{e}");
+            ErrorServiceUnavailable(e.to_string())
+        }
+        MartinError::PostgresError(PgError::MissingClaim(..)) => ErrorUnauthorized(e.to_string()),
+        MartinError::PostgresError(PgError::InvalidQueryParam(..)) => {
+            ErrorBadRequest(e.to_string())
+        }
+        MartinError::TerrainError(TerrainError::InvalidEncoding(..)) => {
+            ErrorBadRequest(e.to_string())
+        }
+        _ => map_internal_error(e),
+    }
+}
+
 pub fn map_sprite_error(e: SpriteError) -> actix_web::Error {
     use SpriteError::SpriteNotFound;
     match e {
@@ -89,6 +341,13 @@ pub fn map_sprite_error(e: SpriteError) -> actix_web::Error {
     }
 }
 
+pub fn map_style_error(e: StyleError) -> actix_web::Error {
+    match e {
+        StyleError::StyleNotFound(_) => ErrorNotFound(e.to_string()),
+        _ => map_internal_error(e),
+    }
+}
+
 pub fn map_font_error(e: FontError) -> actix_web::Error {
     #[allow(clippy::enum_glob_use)]
     use FontError::*;
@@ -102,23 +361,48 @@ pub fn map_font_error(e: FontError) -> actix_web::Error {
     }
 }
 
-/// Root path will eventually have a web front. For now, just a stub.
+/// Root path will eventually have a web front. For now, just a stub, or the map in demo mode.
 #[route("/", method = "GET", method = "HEAD")]
 #[allow(clippy::unused_async)]
-async fn get_index() -> &'static str {
+async fn get_index(demo: Option<Data<DemoMode>>) -> HttpResponse {
+    if demo.is_some() {
+        return demo_index();
+    }
     // todo: once this becomes more substantial, add wrap = "middleware::Compress::default()"
-    "Martin server is running. Eventually this will be a nice web front.\n\n\
-    A list of all available sources is at /catalog\n\n\
-    See documentation https://github.com/maplibre/martin"
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(
+            "Martin server is running. Eventually this will be a nice web front.\n\n\
+        A list of all available sources is at /catalog\n\n\
+        See documentation https://github.com/maplibre/martin",
+        )
 }
 
 /// Return 200 OK if healthy. Used for readiness and liveness probes.
+/// With `?deep=true`, every source backend is checked, and 503 is returned if any of them fail.
 #[route("/health", method = "GET", method = "HEAD")]
-#[allow(clippy::unused_async)]
-async fn get_health() -> impl Responder {
-    HttpResponse::Ok()
+async fn get_health(
+    query: Query<HealthRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+) -> ActixResult<HttpResponse> {
+    if !query.deep {
+        return Ok(HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-cache"))
+            .body("OK"));
+    }
+
+    let failed = sources.load_full().check_health(HEALTH_CHECK_TIMEOUT).await;
+    let mut response = if failed.is_empty() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response
         .insert_header((CACHE_CONTROL, "no-cache"))
-        .message_body("OK")
+        .json(HealthStatus {
+            status: if failed.is_empty() { "ok" } else { "unhealthy" },
+            failed,
+        }))
 }
 
 #[route(
@@ -128,15 +412,55 @@ async fn get_health() -> impl Responder {
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_catalog(catalog: Data<Catalog>) -> impl Responder {
-    HttpResponse::Ok().json(catalog)
+async fn get_catalog(
+    catalog: Data<ArcSwap<Catalog>>,
+    sources: Data<ArcSwap<TileSources>>,
+    tenants: Option<Data<Tenants>>,
+    tenant: Option<Data<TenantId>>,
+    query: Query<CatalogQuery>,
+) -> impl Responder {
+    let catalog = catalog.load();
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header(("X-Catalog-Version", catalog.changes.version()));
+    let tiles = match tenants.filter(|v| !v.is_empty()) {
+        Some(tenants) => {
+            let tenant = tenant.as_deref().map(AsRef::as_ref);
+            Cow::Owned(tenants.filter_catalog(&catalog.tiles, &sources.load(), tenant))
+        }
+        None if query.is_empty() => return resp.json(catalog.as_ref()),
+        None => Cow::Borrowed(&catalog.tiles),
+    };
+    let tiles = if query.is_empty() {
+        tiles.into_owned()
+    } else {
+        let (page, total) = query.apply(&tiles);
+        resp.insert_header((TOTAL_COUNT_HEADER, total));
+        page
+    };
+    if tenant.is_some() {
+        // a tenant catalog only lists the tile sources of the tenant
+        resp.json(Catalog {
+            tiles,
+            ..Catalog::default()
+        })
+    } else {
+        resp.json(Catalog {
+            tiles,
+            sprites: catalog.sprites.clone(),
+            fonts: catalog.fonts.clone(),
+            styles: catalog.styles.clone(),
+            changes: CatalogChanges::default(),
+        })
+    }
 }
 
 #[route("/sprite/{source_ids}.png", method = "GET", method = "HEAD")]
 async fn get_sprite_png(
+    req: HttpRequest,
     path: Path<TileJsonRequest>,
     sprites: Data<SpriteSources>,
 ) -> ActixResult<HttpResponse> {
+    check_source_access(&req, &path.source_ids)?;
     let sheet = sprites
         .get_sprites(&path.source_ids)
         .await
@@ -153,9 +477,11 @@ async fn get_sprite_png(
     wrap = "middleware::Compress::default()"
 )]
 async fn get_sprite_json(
+    req: HttpRequest,
     path: Path<TileJsonRequest>,
     sprites: Data<SpriteSources>,
 ) -> ActixResult<HttpResponse> {
+    check_source_access(&req, &path.source_ids)?;
     let sheet = sprites
         .get_sprites(&path.source_ids)
         .await
@@ -163,6 +489,18 @@ async fn get_sprite_json(
     Ok(HttpResponse::Ok().json(sheet.get_index()))
 }
 
+/// List the glyph ranges of every font, to find out which ranges are worth requesting
+#[route(
+    "/font",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_font_coverage(fonts: Data<ArcSwap<FontSources>>) -> impl Responder {
+    HttpResponse::Ok().json(fonts.load().get_coverage())
+}
+
 #[derive(Deserialize, Debug)]
 struct FontRequest {
     fontstack: String,
@@ -176,8 +514,14 @@ struct FontRequest {
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_font(path: Path<FontRequest>, fonts: Data<FontSources>) -> ActixResult<HttpResponse> {
+async fn get_font(
+    req: HttpRequest,
+    path: Path<FontRequest>,
+    fonts: Data<ArcSwap<FontSources>>,
+) -> ActixResult<HttpResponse> {
+    check_source_access(&req, &path.fontstack)?;
     let data = fonts
+        .load()
         .get_font_range(&path.fontstack, path.start, path.end)
         .map_err(map_font_error)?;
     Ok(HttpResponse::Ok()
@@ -195,46 +539,96 @@ async fn get_font(path: Path<FontRequest>, fonts: Data<FontSources>) -> ActixRes
 async fn git_source_info(
     req: HttpRequest,
     path: Path<TileJsonRequest>,
-    sources: Data<TileSources>,
+    sources: Data<ArcSwap<TileSources>>,
+    redirects: Option<Data<SourceRedirects>>,
+    options: Option<Data<TileOptions>>,
 ) -> ActixResult<HttpResponse> {
-    let sources = sources.get_sources(&path.source_ids, None)?.0;
-    let info = req.connection_info();
-    let tiles_path = get_request_path(&req);
-    let tiles_url = get_tiles_url(info.scheme(), info.host(), req.query_string(), &tiles_path)?;
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 0) {
+        return Ok(resp);
+    }
+    let is_composite = sources.is_composite(&path.source_ids);
+    let variants = sources.get_variants(&path.source_ids);
+    let (source_ids, vary) = resolve_sources(&req, &sources, &path.source_ids)?;
+    let src_list = sources.get_sources(&source_ids, None)?.0;
+    let url = get_public_url(&req);
+    let query = req.query_string();
+    let tiles_url = get_tiles_url(&url, query, "")?;
+    let grids_url = if src_list.iter().all(|src| src.support_utfgrid()) {
+        Some(get_tiles_url(&url, query, ".grid.json")?)
+    } else {
+        None
+    };
 
-    Ok(HttpResponse::Ok().json(merge_tilejson(&sources, tiles_url)))
+    let limits = options.map(|v| v.composite_tilejson).unwrap_or_default();
+    let mut tilejson = merge_tilejson(&src_list, tiles_url, limits);
+    tilejson.grids = grids_url.map(|v| vec![v]);
+    if is_composite {
+        tilejson.name = Some(path.source_ids.clone());
+    }
+    if let Some(variants) = variants {
+        tilejson.name = Some(path.source_ids.clone());
+        tilejson
+            .other
+            .insert("variants".to_string(), variants.to_tilejson_value());
+    }
+    let mut response = HttpResponse::Ok();
+    if sources.is_hidden(&source_ids) {
+        response.insert_header(NOINDEX_HEADER);
+    }
+    let mut response = response.json(tilejson);
+    add_vary_headers(&mut response, &vary);
+    Ok(response)
 }
 
-fn get_request_path(req: &HttpRequest) -> String {
-    req.headers()
-        .get("x-rewrite-url")
-        .and_then(parse_x_rewrite_url)
-        .unwrap_or_else(|| req.path().to_owned())
+/// If any of the requested source IDs have been renamed, redirect the client to the new ones.
+/// `suffix_len` is the number of path segments following the source IDs, e.g. 3 for `/{z}/{x}/{y}`.
+pub(crate) fn redirect_sources(
+    req: &HttpRequest,
+    source_ids: &str,
+    sources: &TileSources,
+    redirects: Option<Data<SourceRedirects>>,
+    suffix_len: usize,
+) -> Option<HttpResponse> {
+    let (new_ids, status) = redirects?.resolve(source_ids, sources)?;
+    Some(SourceRedirects::redirect(
+        &get_public_url(req).path,
+        req.query_string(),
+        suffix_len,
+        &new_ids,
+        status,
+    ))
 }
 
-fn get_tiles_url(
-    scheme: &str,
-    host: &str,
-    query_string: &str,
-    tiles_path: &str,
-) -> ActixResult<String> {
+/// Get the URL template of the tiles, with a `suffix` like `.grid.json` for the other tile formats
+fn get_tiles_url(url: &RequestUrl, query_string: &str, suffix: &str) -> ActixResult<String> {
+    let tiles_path = &url.path;
     let path_and_query = if query_string.is_empty() {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}")
+        format!("{tiles_path}/{{z}}/{{x}}/{{y}}{suffix}")
     } else {
-        format!("{tiles_path}/{{z}}/{{x}}/{{y}}?{query_string}")
+        format!("{tiles_path}/{{z}}/{{x}}/{{y}}{suffix}?{query_string}")
     };
 
     Uri::builder()
-        .scheme(scheme)
-        .authority(host)
+        .scheme(url.scheme.as_str())
+        .authority(url.host.as_str())
         .path_and_query(path_and_query)
         .build()
         .map(|tiles_url| tiles_url.to_string())
         .map_err(|e| ErrorBadRequest(format!("Can't build tiles URL: {e}")))
 }
 
+/// Merge the `TileJSON` of several sources into one.
+///
+/// The vector layers with the same ID are combined into one layer with the fields of all of them,
+/// and the number of layers and fields is capped by the `limits`, so that the requests listing
+/// hundreds of sources do not produce huge documents.
 #[must_use]
-pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
+pub fn merge_tilejson(
+    sources: &[&dyn Source],
+    tiles_url: String,
+    limits: CompositeTileJsonConfig,
+) -> TileJSON {
     if sources.len() == 1 {
         let mut tj = sources[0].get_tilejson().clone();
         tj.tiles = vec![tiles_url];
@@ -244,6 +638,11 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
     let mut attributions = vec![];
     let mut descriptions = vec![];
     let mut names = vec![];
+    let mut layers: Vec<VectorLayer> = Vec::new();
+    let mut layer_index: HashMap<&str, usize> = HashMap::new();
+    let mut omitted_layers = 0_usize;
+    let max_layers = limits.max_vector_layers();
+    let max_fields = limits.max_layer_fields();
     let mut result = tilejson! {
         tiles: vec![tiles_url],
     };
@@ -251,18 +650,24 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
     for src in sources {
         let tj = src.get_tilejson();
 
-        if let Some(vector_layers) = &tj.vector_layers {
-            if let Some(ref mut a) = result.vector_layers {
-                a.extend(vector_layers.iter().cloned());
+        for layer in tj.vector_layers.iter().flatten() {
+            if let Some(&idx) = layer_index.get(layer.id.as_str()) {
+                merge_vector_layer(&mut layers[idx], layer, max_fields);
+            } else if layers.len() < max_layers {
+                layer_index.insert(&layer.id, layers.len());
+                let mut new_layer = VectorLayer {
+                    fields: BTreeMap::new(),
+                    ..layer.clone()
+                };
+                merge_vector_layer(&mut new_layer, layer, max_fields);
+                layers.push(new_layer);
             } else {
-                result.vector_layers = Some(vector_layers.clone());
+                omitted_layers += 1;
             }
         }
 
         if let Some(v) = &tj.attribution {
-            if !attributions.contains(&v) {
-                attributions.push(v);
-            }
+            attributions.push(v.as_str());
         }
 
         if let Some(bounds) = tj.bounds {
@@ -279,9 +684,7 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
         }
 
         if let Some(v) = &tj.description {
-            if !descriptions.contains(&v) {
-                descriptions.push(v);
-            }
+            descriptions.push(v.as_str());
         }
 
         if let Some(maxzoom) = tj.maxzoom {
@@ -305,88 +708,522 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
         }
 
         if let Some(name) = &tj.name {
-            if !names.contains(&name) {
-                names.push(name);
-            }
+            names.push(name.as_str());
         }
     }
 
+    if !layers.is_empty() {
+        result.vector_layers = Some(layers);
+    }
+
+    if omitted_layers > 0 {
+        warn!("Omitted {omitted_layers} vector layers from the composite TileJSON of {} sources, the limit is {max_layers} layers", sources.len());
+        result.other.insert(
+            "omitted_vector_layers".to_string(),
+            serde_json::Value::from(omitted_layers),
+        );
+    }
+
     if !attributions.is_empty() {
-        result.attribution = Some(attributions.into_iter().join("\n"));
+        result.attribution = Some(attributions.into_iter().unique().join("\n"));
     }
 
     if !descriptions.is_empty() {
-        result.description = Some(descriptions.into_iter().join("\n"));
+        result.description = Some(descriptions.into_iter().unique().join("\n"));
     }
 
     if !names.is_empty() {
-        result.name = Some(names.into_iter().join(","));
+        result.name = Some(names.into_iter().unique().join(","));
     }
 
     result
 }
 
+/// Add the fields and zoom levels of another layer with the same ID
+fn merge_vector_layer(target: &mut VectorLayer, layer: &VectorLayer, max_fields: usize) {
+    for (name, typ) in &layer.fields {
+        if target.fields.len() >= max_fields {
+            break;
+        }
+        if !target.fields.contains_key(name) {
+            target.fields.insert(name.clone(), typ.clone());
+        }
+    }
+    target.minzoom = match (target.minzoom, layer.minzoom) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => None,
+    };
+    target.maxzoom = match (target.maxzoom, layer.maxzoom) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => None,
+    };
+}
+
 #[route("/{source_ids}/{z}/{x}/{y}", method = "GET", method = "HEAD")]
 async fn get_tile(
     req: HttpRequest,
     path: Path<TileRequest>,
-    sources: Data<TileSources>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
 ) -> ActixResult<HttpResponse> {
-    let xyz = TileCoord {
-        z: path.z,
-        x: path.x,
-        y: path.y,
-    };
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 3) {
+        return Ok(resp);
+    }
+    let xyz = path.tile_coord()?;
+    serve_tile(&req, &sources, &options, xyz, &path.source_ids).await
+}
+
+/// Serve a tile addressed by its [Bing Maps quadkey](https://learn.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system)
+/// instead of its zoom and coordinates, for the clients that only support quadkeys
+#[route("/{source_ids}/quadkey/{quadkey}", method = "GET", method = "HEAD")]
+async fn get_quadkey_tile(
+    req: HttpRequest,
+    path: Path<QuadkeyTileRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 2) {
+        return Ok(resp);
+    }
+    let xyz = TileCoord::from_quadkey(&path.quadkey).ok_or_else(|| {
+        ErrorBadRequest(format!(
+            "Invalid quadkey {}, expected up to {MAX_ZOOM} digits from 0 to 3",
+            path.quadkey
+        ))
+    })?;
+    serve_tile(&req, &sources, &options, xyz, &path.source_ids).await
+}
 
-    let source_ids = &path.source_ids;
+/// Serve a tile of the requested sources, applying the request timeout and recording the usage
+async fn serve_tile(
+    req: &HttpRequest,
+    sources: &TileSources,
+    options: &TileOptions,
+    xyz: TileCoord,
+    source_ids: &str,
+) -> ActixResult<HttpResponse> {
+    let (source_ids, vary) = resolve_sources(req, sources, source_ids)?;
+    use_tile(req, xyz.z)?;
     let query = req.query_string();
+    let source_ids = &source_ids;
     let encodings = req.get_header::<AcceptEncoding>();
+    let claims = get_request_claims(req, options.jwt.as_ref())?;
+    let _active = options.prefetch.as_ref().map(Prefetcher::track_request);
+    let start = Instant::now();
+    if let Some(traffic) = &options.traffic {
+        traffic.record(req.path(), query);
+    }
 
-    get_tile_response(sources.as_ref(), xyz, source_ids, query, encodings).await
+    let response = get_tile_response(
+        sources,
+        options,
+        xyz,
+        source_ids,
+        query,
+        claims.as_ref(),
+        encodings,
+    );
+    let mut response = if let Some(timeout) = options.request_timeout {
+        tokio::time::timeout(timeout, response).await.map_err(|_| {
+            warn!("Request for tile {xyz} of {source_ids} timed out after {timeout:?}");
+            ErrorGatewayTimeout("Tile request timed out")
+        })?
+    } else {
+        response.await
+    }?;
+    add_vary_headers(&mut response, &vary);
+    if let Some(transcoder) = &options.transcoder {
+        let accept = req.get_header::<Accept>();
+        response = transcoder.transcode_response(response, source_ids, accept.as_ref())?;
+    }
+    if let Some(usage) = &options.usage {
+        if let BodySize::Sized(bytes) = response.body().size() {
+            usage.record(
+                source_ids,
+                usize::try_from(bytes).unwrap_or(usize::MAX),
+                start.elapsed(),
+            );
+        }
+    }
+    Ok(response)
+}
+
+/// Select the variants requested by the client, and check the access rules of the requested sources,
+/// of the selected variants, and of the members of the named composites.
+/// Returns the IDs of the selected sources, and the request headers used for the selection.
+pub(crate) fn resolve_sources<'a>(
+    req: &HttpRequest,
+    sources: &'a TileSources,
+    source_ids: &str,
+) -> ActixResult<(String, Vec<&'a str>)> {
+    let (resolved, vary) = sources.resolve_variants(source_ids, req.query_string(), req.headers());
+    check_tile_access(req, source_ids, &sources.member_ids(&resolved))?;
+    Ok((resolved, vary))
 }
 
+/// Check the IP rules, the allowed web apps, and the tenants of the requested tile sources, and of the sources
+/// serving them, see [`TileSources::member_ids`]
+pub(crate) fn check_tile_access(
+    req: &HttpRequest,
+    source_ids: &str,
+    member_ids: &str,
+) -> ActixResult<()> {
+    let ids = format!("{source_ids},{member_ids}");
+    check_source_access(req, &ids)?;
+    check_referer_access(req, &ids)?;
+    check_tenant_access(req, source_ids, member_ids)
+}
+
+/// Tell the caches that the response depends on the headers used to select the source variants
+fn add_vary_headers(response: &mut HttpResponse, vary: &[&str]) {
+    for header in vary {
+        if let Ok(value) = HeaderValue::from_str(header) {
+            response.headers_mut().append(VARY, value);
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)]
 pub async fn get_tile_response(
     sources: &TileSources,
+    options: &TileOptions,
     xyz: TileCoord,
     source_ids: &str,
     query: &str,
+    claims: Option<&JwtClaims>,
     encodings: Option<AcceptEncoding>,
 ) -> ActixResult<HttpResponse> {
-    let (sources, use_url_query, info) = sources.get_sources(source_ids, Some(xyz.z))?;
+    let hidden = sources.is_hidden(source_ids);
+    let start = Instant::now();
+    let (sources, use_url_query, info) = info_span!("get_sources", source_ids)
+        .in_scope(|| sources.get_sources(source_ids, Some(xyz.z)))?;
+    let lookup = start.elapsed();
+    let ids: Vec<_> = sources.iter().map(|src| src.get_id()).collect();
+    let key = options.encryption.get_key(&ids)?;
+    let filter = get_tile_filter(info, query)?;
 
     let query = use_url_query.then_some(query);
-    let tile = get_tile_content(sources.as_slice(), info, &xyz, query, encodings.as_ref()).await?;
+    let claims = claims.filter(|_| use_url_query);
+    // the tile is compressed after its layers and properties are filtered
+    let content_enc = encodings.as_ref().filter(|_| filter.is_none());
+    let content = async {
+        let tile =
+            get_tile_content(&sources, options, info, &xyz, query, claims, content_enc).await?;
+        match &filter {
+            Some(filter) => filter_tile(tile, filter, encodings.as_ref(), &options.compression),
+            None => Ok(tile),
+        }
+    };
+    let (tile, timing) = if options.server_timing {
+        let (tile, mut timing) = ServerTiming::collect(content).await;
+        timing.set_lookup(lookup);
+        (tile?, Some(timing))
+    } else {
+        (content.await?, None)
+    };
 
-    Ok(if tile.data.is_empty() {
+    let oversized = options
+        .tile_size_limits
+        .check(source_ids, xyz, tile.data.len())?;
+    let empty_tile = options.empty_tiles.get(source_ids);
+    let tile = match empty_tile {
+        EmptyTile::Fallback(data) if tile.data.is_empty() => recompress(
+            Tile::new(data.clone(), info),
+            encodings.as_ref(),
+            &options.compression,
+        )?,
+        _ => tile,
+    };
+    // tiles generated for the claims of a token must not be shared by other users
+    let scope = if claims.is_some() {
+        "private"
+    } else {
+        "public"
+    };
+    let mut response = if oversized {
         HttpResponse::NoContent().finish()
+    } else if tile.data.is_empty() && !matches!(empty_tile, EmptyTile::Fallback(_)) {
+        let mut response = empty_tile.response();
+        if let Some(max_age) = options.expiration.empty_max_age() {
+            let value = format!("{scope}, max-age={}", max_age.as_secs());
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(CACHE_CONTROL, value);
+            }
+        }
+        response
     } else {
         let mut response = HttpResponse::Ok();
-        response.content_type(tile.info.format.content_type());
-        if let Some(val) = tile.info.encoding.content_encoding() {
-            response.insert_header((CONTENT_ENCODING, val));
+        if let Some(max_age) = options.expiration.max_age(xyz.z) {
+            let max_age = max_age.as_secs();
+            let stale = options
+                .expiration
+                .stale_window(source_ids)
+                .map(|v| format!(", stale-while-revalidate={}", v.as_secs()))
+                .unwrap_or_default();
+            response.insert_header((CACHE_CONTROL, format!("{scope}, max-age={max_age}{stale}")));
         }
-        response.body(tile.data)
-    })
+        if let Some(headers) = &tile.headers {
+            if let Some(val) = &headers.cache_control {
+                response.insert_header((CACHE_CONTROL, val.as_str()));
+            }
+            if let Some(val) = &headers.etag {
+                response.insert_header((ETAG, val.as_str()));
+            }
+            if let Some(val) = &headers.expires {
+                response.insert_header((EXPIRES, val.as_str()));
+            }
+        }
+        let mut data = tile.data;
+        if let Some(key) = key {
+            // The compressed tile is encrypted as is, so its type and encoding are only
+            // known to the clients that can decrypt it
+            data = key.encrypt(xyz, &data)?;
+            response.content_type(ContentType::octet_stream());
+            response.insert_header(("X-Tile-Encryption", ENCRYPTION_ALGORITHM));
+            if let Some(key_id) = key.key_id() {
+                response.insert_header(("X-Tile-Key-Id", key_id));
+            }
+            response.insert_header(("X-Tile-Content-Type", tile.info.format.content_type()));
+            if let Some(val) = tile.info.encoding.content_encoding() {
+                response.insert_header(("X-Tile-Content-Encoding", val));
+            }
+            response.insert_header((VARY, "Accept-Encoding"));
+        } else {
+            response.content_type(tile.info.format.content_type());
+            if let Some(val) = tile.info.encoding.content_encoding() {
+                response.insert_header((CONTENT_ENCODING, val));
+            }
+        }
+        if options.prefetch_hints {
+            if let Some(links) = get_prefetch_links(xyz, query) {
+                response.insert_header((LINK, links));
+            }
+        }
+        response.body(data)
+    };
+    if let Some((name, value)) = options
+        .surrogate_keys
+        .as_ref()
+        .and_then(|v| v.header(&ids, xyz))
+    {
+        response.headers_mut().insert(name, value);
+    }
+    if hidden {
+        let (name, value) = NOINDEX_HEADER;
+        response.headers_mut().insert(
+            HeaderName::from_static(name),
+            HeaderValue::from_static(value),
+        );
+    }
+    if let Some(timing) = timing {
+        if let Ok(value) = HeaderValue::from_str(&timing.header_value(start.elapsed())) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("server-timing"), value);
+        }
+    }
+    Ok(response)
+}
+
+/// Parts of a vector tile requested with the comma-separated query parameters
+#[derive(Debug, Default, PartialEq, Eq)]
+struct TileFilter {
+    /// Layers to keep, from the `layers` parameter
+    layers: Option<Vec<String>>,
+    /// Feature properties to keep, from the `props` parameter
+    props: Option<Vec<String>>,
+    /// Feature properties to remove, from the `exclude_props` parameter
+    exclude_props: Option<Vec<String>>,
+}
+
+impl TileFilter {
+    fn keep_property(&self, key: &str) -> bool {
+        match (&self.props, &self.exclude_props) {
+            (Some(props), _) => props.iter().any(|v| v == key),
+            (None, Some(excluded)) => !excluded.iter().any(|v| v == key),
+            (None, None) => true,
+        }
+    }
+}
+
+/// Parts of the vector tile requested with the `layers`, `props` and `exclude_props` query parameters.
+/// Layers and properties can only be removed from vector tiles, other formats ignore the parameters.
+fn get_tile_filter(info: TileInfo, query: &str) -> ActixResult<Option<TileFilter>> {
+    if info.format != Format::Mvt || query.is_empty() {
+        return Ok(None);
+    }
+    let mut query = Query::<UrlQuery>::from_query(query)?.into_inner();
+    let mut list = |name: &str| {
+        query
+            .remove(name)
+            .map(|v| {
+                v.split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|v| !v.is_empty())
+    };
+    let filter = TileFilter {
+        layers: list("layers"),
+        props: list("props"),
+        exclude_props: list("exclude_props"),
+    };
+    if filter.props.is_some() && filter.exclude_props.is_some() {
+        return Err(ErrorBadRequest(
+            "Only one of the props and exclude_props parameters can be used",
+        ));
+    }
+    Ok(Some(filter).filter(|v| *v != TileFilter::default()))
+}
+
+/// Remove the layers and the feature properties that were not requested from an uncompressed
+/// vector tile, and compress the rest into the preferred encoding
+fn filter_tile(
+    mut tile: Tile,
+    filter: &TileFilter,
+    accept_enc: Option<&AcceptEncoding>,
+    compression: &TileCompression,
+) -> ActixResult<Tile> {
+    if let Some(layers) = &filter.layers {
+        let layers: Vec<_> = layers.iter().map(String::as_str).collect();
+        if let Some(data) = filter_layers(&tile.data, &layers).map_err(map_internal_error)? {
+            tile.data = data;
+        }
+    }
+    if (filter.props.is_some() || filter.exclude_props.is_some()) && !tile.data.is_empty() {
+        let data = filter_properties(&tile.data, |key| filter.keep_property(key))
+            .map_err(map_internal_error)?;
+        if let Some(data) = data {
+            tile.data = data;
+        }
+    }
+    if tile.data.is_empty() {
+        Ok(tile)
+    } else {
+        recompress(tile, accept_enc, compression)
+    }
+}
+
+/// Links to the sibling tiles, relative to the requested tile URL, so that they work behind proxies.
+fn get_prefetch_links(xyz: TileCoord, query: Option<&str>) -> Option<String> {
+    let query = match query {
+        Some(q) if !q.is_empty() => format!("?{q}"),
+        _ => String::new(),
+    };
+    let links = get_sibling_tiles(xyz)
+        .into_iter()
+        .map(|t| format!("<../{}/{}{query}>; rel=prefetch", t.x, t.y))
+        .join(", ");
+    (!links.is_empty()).then_some(links)
 }
 
 pub async fn get_tile_content(
     sources: &[&dyn Source],
+    options: &TileOptions,
     info: TileInfo,
     xyz: &TileCoord,
     query: Option<&str>,
+    claims: Option<&JwtClaims>,
     encodings: Option<&AcceptEncoding>,
 ) -> ActixResult<Tile> {
     if sources.is_empty() {
         return Err(ErrorNotFound("No valid sources found"));
     }
-    let query = match query {
-        Some(v) if !v.is_empty() => Some(Query::<UrlQuery>::from_query(v)?.into_inner()),
-        _ => None,
+    if let Some(src) = sources
+        .iter()
+        .find(|src| !src.get_tile_matrix_set().is_valid(*xyz))
+    {
+        return Err(ErrorNotFound(format!(
+            "Tile {xyz:#} is outside of the {} grid of source {}",
+            src.get_tile_matrix_set(),
+            src.get_id()
+        )));
+    }
+    let mut query = match query {
+        Some(v) if !v.is_empty() => Query::<UrlQuery>::from_query(v)?.into_inner(),
+        _ => UrlQuery::new(),
     };
+    // Claims can only be added by the server, never by the client
+    query.retain(|k, _| !k.starts_with(CLAIM_QUERY_PREFIX));
+    for (name, value) in claims.into_iter().flatten() {
+        query.insert(format!("{CLAIM_QUERY_PREFIX}{name}"), value.to_string());
+    }
+    let query = (!query.is_empty()).then_some(query);
+
+    // the compressed tile is cached too, so that popular tiles are not compressed for each request
+    let variant = get_variant_key(sources, options, info, xyz, query.as_ref(), encodings);
+    if let (Some(cache), Some((key, encoding))) = (&options.cache, &variant) {
+        if let Some(CacheValue::Tile(data)) = cache.get(key).await {
+            trace!("Cache hit for {key:?}");
+            VARIANT_CACHE_HITS.increment(*encoding);
+            return Ok(Tile::new(data, info.encoding(*encoding)));
+        }
+    }
 
-    let mut tiles = try_join_all(sources.iter().map(|s| s.get_tile(xyz, &query)))
-        .await
-        .map_err(map_internal_error)?;
+    let tiles = try_join_all(sources.iter().map(|src| {
+        let span = info_span!("get_source_tile", source = src.get_id(), tile = %xyz);
+        let fetch = async {
+            let limits = &options.concurrency_limits;
+            if !src.is_cacheable() {
+                let fetch = src.get_tile_with_headers(xyz, &query);
+                return ServerTiming::time_source(src.get_id(), limits.run(src.get_id(), fetch))
+                    .await
+                    .map(|(data, headers)| (data, headers, false));
+            }
+            // the tiles that may be cached are the same for all requests, so they can be shared
+            let key_query = if src.support_url_query() {
+                query.as_ref()
+            } else {
+                None
+            };
+            let key = CacheKey::tile(src.get_id(), *xyz, key_query);
+            let fetch = async {
+                if !options.is_cached() {
+                    let fetch = src.get_tile_with_headers(xyz, &query);
+                    return ServerTiming::time_source(
+                        src.get_id(),
+                        limits.run(src.get_id(), fetch),
+                    )
+                    .await
+                    .map(|(data, headers)| (data, headers, false));
+                }
+                let fetch = limits.run(src.get_id(), src.get_tile(xyz, &query));
+                let fetch = ServerTiming::time_source(src.get_id(), fetch);
+                let (data, stale) =
+                    get_cached_tile(*src, options, xyz, query.as_ref(), fetch).await?;
+                Ok((data, None, stale))
+            };
+            options.single_flight.run(key, fetch).await
+        };
+        async move {
+            if let Some(timeout) = options.backend_timeout {
+                tokio::time::timeout(timeout, fetch)
+                    .await
+                    .unwrap_or_else(|_| Err(SourceTimeout(src.get_id().to_string(), *xyz, timeout)))
+            } else {
+                fetch.await
+            }
+        }
+        .instrument(span)
+    }))
+    .await
+    .map_err(map_tile_error)?;
+    let stale = tiles.iter().any(|(_, _, stale)| *stale);
+    let (mut tiles, mut headers): (Vec<_>, Vec<_>) = tiles
+        .into_iter()
+        .map(|(data, headers, _)| (data, headers))
+        .unzip();
+    // the caching headers of a single source do not apply to the tiles merged from several ones
+    let headers = if headers.len() == 1 {
+        headers.pop().flatten()
+    } else {
+        None
+    };
 
     // Make sure tiles can be concatenated, or if not, that there is only one non-empty tile for each zoom level
     // TODO: can zlib, brotli, or zstd be concatenated?
@@ -404,27 +1241,169 @@ pub async fn get_tile_content(
     // Minor optimization to prevent concatenation if there are less than 2 tiles
     let data = match layer_count {
         1 => tiles.swap_remove(0),
-        0 => return Ok(Tile::new(Vec::new(), info)),
+        0 => {
+            return Ok(Tile {
+                stale,
+                ..Tile::new(Vec::new(), info)
+            })
+        }
         _ => tiles.concat(),
     };
 
     // decide if (re-)encoding of the tile data is needed, and recompress if so
-    let tile = recompress(Tile::new(data, info), encodings)?;
+    let mut tile = info_span!("recompress", tile_info = %info)
+        .in_scope(|| recompress(Tile::new(data, info), encodings, &options.compression))?;
+    tile.headers = headers;
+    tile.stale = stale;
+
+    if let (Some(cache), Some((key, encoding))) = (&options.cache, variant) {
+        if tile.info.encoding == encoding {
+            VARIANT_CACHE_MISSES.increment(encoding);
+            // the stale tiles are being fetched again, and their compressed copy would outlive them
+            if !stale {
+                cache.insert(key, CacheValue::Tile(tile.data.clone())).await;
+            }
+        }
+    }
 
     Ok(tile)
 }
 
-fn recompress(mut tile: Tile, accept_enc: Option<&AcceptEncoding>) -> ActixResult<Tile> {
+/// Cache key of the tile compressed into the encoding preferred by the client, if the cached
+/// tiles of the sources would have to be compressed for it
+fn get_variant_key(
+    sources: &[&dyn Source],
+    options: &TileOptions,
+    info: TileInfo,
+    xyz: &TileCoord,
+    query: Option<&UrlQuery>,
+    encodings: Option<&AcceptEncoding>,
+) -> Option<(CacheKey, Encoding)> {
+    if options.cache.is_none() || !sources.iter().all(|src| src.is_cacheable()) {
+        return None;
+    }
+    let encoding = get_variant_encoding(info, encodings?)?;
+    let ids = sources.iter().map(|src| src.get_id()).join(",");
+    let query = query.filter(|_| sources.iter().any(|src| src.support_url_query()));
+    Some((CacheKey::encoded(&ids, *xyz, query, encoding), encoding))
+}
+
+/// The encoding `recompress` compresses the tile into, or `None` if the tile is served as it is stored
+fn get_variant_encoding(info: TileInfo, accept_enc: &AcceptEncoding) -> Option<Encoding> {
+    if info.encoding.is_encoded() {
+        if is_accepted(accept_enc, info.encoding) {
+            return None;
+        }
+    } else if info.encoding != Encoding::Uncompressed {
+        return None;
+    }
+    match accept_enc.negotiate(SUPPORTED_ENCODINGS.iter()) {
+        Some(HeaderEnc::Known(enc)) => to_encoding(enc).filter(Encoding::is_encoded),
+        _ => None,
+    }
+}
+
+fn is_accepted(accept_enc: &AcceptEncoding, encoding: Encoding) -> bool {
+    accept_enc.iter().any(|e| {
+        if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
+            to_encoding(enc) == Some(encoding)
+        } else {
+            false
+        }
+    })
+}
+
+/// Get a single tile of a source from the in-memory or the shared cache,
+/// or use `fetch` to get it and store it in both caches.
+/// A stale tile is returned right away, and fetched again in the background.
+async fn get_cached_tile(
+    src: &dyn Source,
+    options: &TileOptions,
+    xyz: &TileCoord,
+    query: Option<&UrlQuery>,
+    fetch: impl Future<Output = MartinResult<TileData>>,
+) -> MartinResult<(TileData, bool)> {
+    let query = if src.support_url_query() { query } else { None };
+    let key = CacheKey::tile(src.get_id(), *xyz, query);
+    if let Some(cache) = &options.cache {
+        if let Some(value) = cache.get(&key).await {
+            trace!("Cache hit for {key:?}");
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            ServerTiming::record_cache(true);
+            let (data, stale) = value.into_tile();
+            if stale {
+                let query = query.cloned();
+                options
+                    .revalidator
+                    .revalidate(src, options, key, *xyz, query);
+            }
+            return Ok((data, stale));
+        }
+    }
+    if let Some(host) = &options.host_cache {
+        if let Some(data) = host.get(&key) {
+            trace!("Host cache hit for {key:?}");
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            ServerTiming::record_cache(true);
+            if let Some(cache) = &options.cache {
+                let value = options.expiration.new_value(&key, data.clone());
+                cache.insert(key, value).await;
+            }
+            return Ok((data, false));
+        }
+    }
+    if let Some(shared) = &options.shared_cache {
+        if let Some(data) = shared.get(&key).await {
+            trace!("Shared cache hit for {key:?}");
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            ServerTiming::record_cache(true);
+            if let Some(host) = &options.host_cache {
+                host.insert(&key, &data);
+            }
+            if let Some(cache) = &options.cache {
+                let value = options.expiration.new_value(&key, data.clone());
+                cache.insert(key, value).await;
+            }
+            return Ok((data, false));
+        }
+    }
+    if options.is_cached() {
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+        ServerTiming::record_cache(false);
+    }
+    if let Some(prefetch) = &options.prefetch {
+        prefetch.on_miss(src.get_id(), *xyz, query);
+    }
+    let pending = PendingTile::new(src.get_id());
+    let data = fetch.await?;
+    drop(pending);
+    store_cached_tile(options, key, &data).await;
+    Ok((data, false))
+}
+
+/// Store a tile of a single source in all configured caches
+pub(crate) async fn store_cached_tile(options: &TileOptions, key: CacheKey, data: &TileData) {
+    if let Some(shared) = &options.shared_cache {
+        shared.insert(&key, data).await;
+    }
+    if let Some(host) = &options.host_cache {
+        host.insert(&key, data);
+    }
+    if let Some(cache) = &options.cache {
+        let value = options.expiration.new_value(&key, data.clone());
+        cache.insert(key, value).await;
+    }
+}
+
+fn recompress(
+    mut tile: Tile,
+    accept_enc: Option<&AcceptEncoding>,
+    compression: &TileCompression,
+) -> ActixResult<Tile> {
     if let Some(accept_enc) = accept_enc {
         if tile.info.encoding.is_encoded() {
             // already compressed, see if we can send it as is, or need to re-compress
-            if !accept_enc.iter().any(|e| {
-                if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
-                    to_encoding(enc) == Some(tile.info.encoding)
-                } else {
-                    false
-                }
-            }) {
+            if !is_accepted(accept_enc, tile.info.encoding) {
                 // need to re-compress the tile - uncompress it first
                 tile = decode(tile)?;
             }
@@ -433,7 +1412,8 @@ fn recompress(mut tile: Tile, accept_enc: Option<&AcceptEncoding>) -> ActixResul
             // only apply compression if the content supports it
             if let Some(HeaderEnc::Known(enc)) = accept_enc.negotiate(SUPPORTED_ENCODINGS.iter()) {
                 // (re-)compress the tile into the preferred encoding
-                tile = encode(tile, enc)?;
+                let levels = compression.levels(tile.info.format.content_type());
+                tile = encode(tile, enc, levels)?;
             }
         }
         Ok(tile)
@@ -443,20 +1423,37 @@ fn recompress(mut tile: Tile, accept_enc: Option<&AcceptEncoding>) -> ActixResul
     }
 }
 
-fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
+fn encode(tile: Tile, enc: ContentEncoding, levels: CompressionLevels) -> ActixResult<Tile> {
+    let _compressing = GaugeGuard::new(&COMPRESSION_QUEUE);
+    ServerTiming::time_encode(|| encode_data(tile, enc, levels))
+}
+
+fn encode_data(tile: Tile, enc: ContentEncoding, levels: CompressionLevels) -> ActixResult<Tile> {
     Ok(match enc {
         ContentEncoding::Brotli => Tile::new(
-            encode_brotli(&tile.data)?,
+            encode_brotli_with_quality(&tile.data, levels.brotli)?,
             tile.info.encoding(Encoding::Brotli),
         ),
-        ContentEncoding::Gzip => {
-            Tile::new(encode_gzip(&tile.data)?, tile.info.encoding(Encoding::Gzip))
+        ContentEncoding::Gzip => Tile::new(
+            encode_gzip_with_level(&tile.data, levels.gzip)?,
+            tile.info.encoding(Encoding::Gzip),
+        ),
+        ContentEncoding::Zstd => {
+            Tile::new(encode_zstd(&tile.data)?, tile.info.encoding(Encoding::Zstd))
         }
         _ => tile,
     })
 }
 
 fn decode(tile: Tile) -> ActixResult<Tile> {
+    if tile.info.encoding.is_encoded() {
+        ServerTiming::time_decode(|| decode_data(tile))
+    } else {
+        Ok(tile)
+    }
+}
+
+fn decode_data(tile: Tile) -> ActixResult<Tile> {
     let info = tile.info;
     Ok(if info.encoding.is_encoded() {
         match info.encoding {
@@ -468,6 +1465,10 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
                 decode_brotli(&tile.data)?,
                 info.encoding(Encoding::Uncompressed),
             ),
+            Encoding::Zstd => Tile::new(
+                decode_zstd(&tile.data)?,
+                info.encoding(Encoding::Uncompressed),
+            ),
             _ => Err(ErrorBadRequest(format!(
                 "Tile is is stored as {info}, but the client does not accept this encoding"
             )))?,
@@ -482,73 +1483,406 @@ fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
         ContentEncoding::Identity => Encoding::Uncompressed,
         ContentEncoding::Gzip => Encoding::Gzip,
         ContentEncoding::Brotli => Encoding::Brotli,
-        // TODO: Deflate => Encoding::Zstd or Encoding::Zlib ?
+        ContentEncoding::Zstd => Encoding::Zstd,
+        // TODO: Deflate => Encoding::Zlib ?
         _ => None?,
     })
 }
 
 pub fn router(cfg: &mut web::ServiceConfig) {
     cfg.service(get_health)
+        .service(super::health::get_health_live)
+        .service(super::health::get_health_ready)
+        .service(super::admin::post_purge)
+        .service(super::admin::post_source)
+        .service(super::admin::delete_source)
+        .service(super::admin::post_reload)
+        .service(super::admin::post_refresh)
+        .service(super::admin::get_quotas)
+        .service(super::load::get_load)
+        .service(super::catalog_changes::get_catalog_changes)
+        .service(super::traffic::get_traffic_profile)
+        .service(super::usage::get_usage_stats)
+        .service(super::status::get_status)
+        .service(super::features::get_features)
+        .service(super::metrics::get_metrics)
         .service(get_index)
+        .service(crate::demo::get_demo_style)
         .service(get_catalog)
+        .service(get_font_coverage)
+        .service(super::style::get_style)
         .service(git_source_info)
+        .service(super::geojson::get_geojson_tile)
+        .service(super::utfgrid::get_utfgrid_tile)
+        .service(super::retina::get_retina_tile)
+        .service(super::style::get_preview_style)
+        .service(super::style::get_preview_page)
+        .service(super::elevation::get_elevation)
+        .service(super::elevation::post_elevations)
         .service(get_tile)
+        .service(get_quadkey_tile)
         .service(get_sprite_json)
         .service(get_sprite_png)
-        .service(get_font);
+        .service(get_font)
+        .service(super::package::post_package)
+        .service(super::batch::post_tiles);
+}
+
+/// Routes served under the path of each tenant, limited to its tile sources
+fn tenant_router(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_catalog)
+        .service(git_source_info)
+        .service(super::geojson::get_geojson_tile)
+        .service(super::utfgrid::get_utfgrid_tile)
+        .service(super::retina::get_retina_tile)
+        .service(get_tile)
+        .service(get_quadkey_tile)
+        .service(super::batch::post_tiles);
+}
+
+/// Everything the request handlers need, shared by all workers.
+/// Use [`ServerData::configure`] to serve Martin from an existing actix-web `App` or `Scope`.
+#[derive(Clone)]
+pub struct ServerData {
+    tiles: Data<ArcSwap<TileSources>>,
+    tile_options: TileOptions,
+    sprites: SpriteSources,
+    fonts: Data<ArcSwap<FontSources>>,
+    styles: StyleSources,
+    catalog: Data<ArcSwap<Catalog>>,
+    redirects: SourceRedirects,
+    runtime_info: RuntimeInfo,
+    purger: CachePurger,
+    scheduler: Scheduler,
+    throttle: Throttle,
+    ip_filter: IpFilter,
+    referer_filter: RefererFilter,
+    tenants: Tenants,
+    public_url: PublicUrl,
+    /// Maximum size of the request bodies in bytes, the actix defaults are used if unset
+    max_payload: Option<usize>,
+    admin: Option<AdminConfig>,
+    static_files: Option<StaticFilesConfig>,
+    demo: bool,
+}
+
+impl ServerData {
+    /// Prepare the data of the request handlers, and start the background tasks of the config,
+    /// e.g. the cache synchronization, the file watcher, and the scheduled tasks.
+    /// Must be called from within an actix runtime.
+    pub fn new(config: &SrvConfig, state: ServerState) -> MartinResult<Self> {
+        let catalog = Data::new(ArcSwap::from_pointee(Catalog::new(&state)?));
+        let mut tile_options = TileOptions::new(config, &state);
+        let tenants = Tenants::new(config.tenants.as_ref());
+        tenants.validate_sources(&state.tiles)?;
+        if let Some(static_files) = &config.static_files {
+            static_files.validate_sources(&state.tiles)?;
+        }
+        let tiles = Data::new(ArcSwap::from_pointee(state.tiles));
+        if let Some(prefetch) = &config.prefetch {
+            tile_options.prefetch = Prefetcher::start(prefetch, &tile_options.cache, &tiles);
+        }
+        if let Some(shared_cache) = &config.shared_cache {
+            let expiration = state.tile_expiration.clone();
+            tile_options.shared_cache = Some(SharedCache::new(shared_cache, expiration)?);
+        }
+        if let Some(host_cache) = &config.host_cache {
+            let expiration = state.tile_expiration.clone();
+            tile_options.host_cache = Some(HostCache::new(host_cache, expiration)?);
+        }
+        tile_options.empty_tiles = EmptyTiles::new(config.empty_tiles.as_ref())?;
+        let worker_processes = config.worker_processes.unwrap_or_else(num_cpus::get);
+        let purger = CachePurger::new(state.cache.clone(), config.cache_sync.as_ref())?
+            .with_shared_cache(tile_options.shared_cache.clone())
+            .with_host_cache(tile_options.host_cache.clone())
+            .with_cdn(CdnPurger::new(config.cdn.as_ref())?)
+            .with_sources(tiles.clone());
+        if purger.is_synced() {
+            actix_rt::spawn(purger.clone().listen());
+        }
+        let watch_files = config.watch_files.unwrap_or_default();
+        let mut sprites = state.sprites;
+        let fonts = Data::new(ArcSwap::from_pointee(state.fonts));
+        if watch_files {
+            // spritesheets can only be kept while the changes of their SVG files are detected
+            sprites.enable_cache();
+        }
+        let scheduler = Scheduler {
+            discovery: state.discovery,
+            sources: tiles.clone(),
+            catalog: catalog.clone(),
+            cache: state.cache.clone(),
+            expiration: tile_options.expiration.clone(),
+            purger: purger.clone(),
+            sprites: sprites.clone(),
+            fonts: fonts.clone(),
+            readiness: Readiness::default(),
+            webhooks: Webhooks::new(config.webhooks.as_ref())?,
+        };
+        if watch_files {
+            start_watcher(scheduler.clone());
+        }
+        start_notification_listeners(&scheduler);
+        let mut tasks = config.schedule.clone().unwrap_or_default();
+        if let Some(task) = scheduler.discovery.pg_rediscover_task() {
+            // a scheduled rediscovery that runs at least as often already covers the tables
+            let covered = tasks
+                .iter()
+                .any(|v| v.task == TaskConfig::Rediscover && v.interval_secs <= task.interval_secs);
+            if !covered {
+                tasks.push(task);
+            }
+        }
+        if !tasks.is_empty() {
+            scheduler.clone().start(&tasks);
+        }
+        let throttle = Throttle::new(config.throttle.clone().unwrap_or_default());
+        throttle.start_saving();
+
+        Ok(Self {
+            tiles,
+            tile_options,
+            sprites,
+            fonts,
+            styles: state.styles,
+            catalog,
+            redirects: SourceRedirects::new(config.redirects.as_ref()),
+            runtime_info: RuntimeInfo::new(worker_processes),
+            purger,
+            scheduler,
+            throttle,
+            ip_filter: IpFilter::new(
+                config.ip_filter.clone().unwrap_or_default(),
+                config.trusted_proxies.as_ref(),
+            ),
+            referer_filter: RefererFilter::new(&config.referer_filter.clone().unwrap_or_default()),
+            tenants,
+            public_url: PublicUrl::new(
+                config.public_url.as_deref(),
+                config.trusted_proxies.as_ref(),
+            )?,
+            max_payload: config.max_payload_kb.map(|v| v * 1024),
+            admin: config.admin.clone(),
+            static_files: config.static_files.clone(),
+            demo: config.demo,
+        })
+    }
+
+    /// Register the shared data and all routes of Martin, e.g. with
+    /// `App::new().service(web::scope("/tiles").configure(|cfg| data.configure(cfg)))`.
+    /// The rate limits, the IP rules, the allowed origins, and the tenant rules are only applied when wrapping
+    /// the scope with [`ServerData::throttle`], [`ServerData::ip_filter`], [`ServerData::referer_filter`],
+    /// and [`ServerData::tenants`].
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.tiles.clone())
+            .app_data(Data::new(self.tile_options.clone()))
+            .app_data(Data::new(self.sprites.clone()))
+            .app_data(self.fonts.clone())
+            .app_data(Data::new(self.styles.clone()))
+            .app_data(self.catalog.clone())
+            .app_data(Data::new(self.redirects.clone()))
+            .app_data(Data::new(self.runtime_info.clone()))
+            .app_data(Data::new(self.purger.clone()))
+            .app_data(Data::new(self.scheduler.clone()))
+            .app_data(Data::new(self.scheduler.readiness.clone()))
+            .app_data(Data::new(self.scheduler.webhooks.clone()))
+            .app_data(Data::new(self.throttle.clone()))
+            .app_data(Data::new(self.tenants.clone()))
+            .app_data(Data::new(self.public_url.clone()));
+
+        if let Some(limit) = self.max_payload {
+            cfg.app_data(web::PayloadConfig::new(limit))
+                .app_data(web::JsonConfig::default().limit(limit));
+        }
+
+        // the demo map is only served by `martin demo`
+        if self.demo {
+            cfg.app_data(Data::new(DemoMode));
+        }
+
+        // admin endpoints are disabled unless the admin config is present
+        if let Some(admin) = &self.admin {
+            cfg.app_data(Data::new(admin.clone()));
+        }
+
+        // the static files are served under a path that no source, tenant, or endpoint uses
+        if let Some(static_files) = &self.static_files {
+            cfg.service(static_files.service());
+        }
+
+        // the tenant scopes must be registered before the routes matching any source ID
+        for name in self.tenants.names() {
+            cfg.service(
+                web::scope(&format!("/{name}"))
+                    .app_data(Data::new(TenantId(name.clone())))
+                    .configure(tenant_router),
+            );
+        }
+
+        router(cfg);
+    }
+
+    /// gRPC API serving the same sources and caches as the HTTP routes
+    #[cfg(feature = "grpc")]
+    #[must_use]
+    pub fn grpc_service(&self, config: &GrpcConfig, public_url: Option<&str>) -> GrpcService {
+        GrpcService::new(
+            config,
+            self.tiles.clone(),
+            self.catalog.clone(),
+            self.tile_options.clone(),
+            self.throttle.clone(),
+            self.ip_filter.clone(),
+            self.referer_filter.clone(),
+            self.tenants.clone(),
+            public_url,
+        )
+    }
+
+    /// Readiness reported by `/health/ready`, e.g. to report the instance as draining before a shutdown
+    #[must_use]
+    pub fn readiness(&self) -> Readiness {
+        self.scheduler.readiness.clone()
+    }
+
+    /// Middleware enforcing the configured rate limits and quotas
+    #[must_use]
+    pub fn throttle(&self) -> Throttle {
+        self.throttle.clone()
+    }
+
+    /// Middleware enforcing the configured IP rules
+    #[must_use]
+    pub fn ip_filter(&self) -> IpFilter {
+        self.ip_filter.clone()
+    }
+
+    /// Middleware enforcing the configured allowed origins of the sources
+    #[must_use]
+    pub fn referer_filter(&self) -> RefererFilter {
+        self.referer_filter.clone()
+    }
+
+    /// Middleware enforcing the tenant tokens, and serving the tenant sources only under their tenant
+    #[must_use]
+    pub fn tenants(&self) -> Tenants {
+        self.tenants.clone()
+    }
+
+    /// CORS middleware allowing the configured origins of each tenant, and any origin otherwise
+    #[must_use]
+    pub fn cors(&self) -> Cors {
+        self.tenants.cors()
+    }
 }
 
-/// Create a new initialized Actix `App` instance together with the listening address.
-pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server, String)> {
-    let catalog = Catalog::new(&state)?;
+/// Create a new initialized Actix `App` instance together with the public listening addresses.
+pub fn new_server(config: SrvConfig, state: ServerState) -> MartinResult<(Server, Vec<String>)> {
+    let data = ServerData::new(&config, state)?;
+    let readiness = data.readiness();
     let keep_alive = Duration::from_secs(config.keep_alive.unwrap_or(KEEP_ALIVE_DEFAULT));
     let worker_processes = config.worker_processes.unwrap_or_else(num_cpus::get);
-    let listen_addresses = config
-        .listen_addresses
-        .unwrap_or_else(|| LISTEN_ADDRESSES_DEFAULT.to_owned());
-
-    let server = HttpServer::new(move || {
-        let cors_middleware = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET"]);
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = &config.grpc {
+        let service = data.grpc_service(grpc, config.public_url.as_deref());
+        crate::srv::start_grpc(grpc, &service, worker_processes)?;
+    }
+    let mut listen_addresses: Vec<_> = config.listen_addresses.into_iter().collect();
+    if listen_addresses.is_empty() {
+        listen_addresses.push(LISTEN_ADDRESSES_DEFAULT.to_owned());
+    }
+    let internal_addresses: Vec<_> = config.internal_listen_addresses.into_iter().collect();
+    let internal_routes = InternalRoutes::default();
+    let routes = internal_routes.clone();
 
+    let mut server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(state.tiles.clone()))
-            .app_data(Data::new(state.sprites.clone()))
-            .app_data(Data::new(state.fonts.clone()))
-            .app_data(Data::new(catalog.clone()))
-            .wrap(cors_middleware)
+            .wrap(data.throttle())
+            .wrap(data.referer_filter())
+            .wrap(data.ip_filter())
+            .wrap(data.tenants())
+            .wrap(data.cors())
+            .wrap(routes.clone())
             .wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
-            .wrap(middleware::Logger::default())
-            .configure(router)
+            .wrap(RequestId)
+            .wrap(middleware::Logger::new(LOG_FORMAT))
+            .wrap(RequestTracing)
+            .configure(|cfg| data.configure(cfg))
     })
-    .bind(listen_addresses.clone())
-    .map_err(|e| BindingError(e, listen_addresses.clone()))?
     .keep_alive(keep_alive)
     .shutdown_timeout(0)
-    .workers(worker_processes)
-    .run();
+    .workers(worker_processes);
 
-    Ok((server, listen_addresses))
-}
+    let drain = config.shutdown_drain_secs.filter(|v| *v > 0);
+    if drain.is_some() {
+        // the signals are handled by the drain instead
+        server = server.disable_signals();
+    }
+    if let Some(max_connections) = config.max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if let Some(kb) = config.h2_stream_window_kb {
+        server = server.h2_initial_window_size(kb * 1024);
+    }
+    if let Some(kb) = config.h2_connection_window_kb {
+        server = server.h2_initial_connection_window_size(kb * 1024);
+    }
+    if let Some(timeout) = config.client_request_timeout_ms {
+        server = server.client_request_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(timeout) = config.client_disconnect_timeout_ms {
+        server = server.client_disconnect_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(threads) = config.max_blocking_threads {
+        server = server.worker_max_blocking_threads(threads);
+    }
+    // the backlog is only used by the listeners created after it is set
+    if let Some(backlog) = config.backlog {
+        server = server.backlog(backlog);
+    }
+
+    // an address may resolve to several sockets, so the internal ones are those bound last
+    let mut public_count = 0;
+    for (idx, addr) in listen_addresses
+        .iter()
+        .chain(&internal_addresses)
+        .enumerate()
+    {
+        if idx == listen_addresses.len() {
+            public_count = server.addrs().len();
+        }
+        // h2c connections are recognized by the HTTP/2 preface, other connections are served as HTTP/1.x
+        let bound = if config.h2c.unwrap_or_default() {
+            server.bind_auto_h2c(addr)
+        } else {
+            server.bind(addr)
+        };
+        server = bound.map_err(|e| BindingError(e, addr.clone()))?;
+    }
+    if !internal_addresses.is_empty() {
+        internal_routes.set_listeners(server.addrs().split_off(public_count));
+    }
+    let server = server.run();
+    if let Some(drain) = drain {
+        drain_on_shutdown(server.handle(), readiness, Duration::from_secs(drain));
+    }
 
-fn parse_x_rewrite_url(header: &HeaderValue) -> Option<String> {
-    header
-        .to_str()
-        .ok()
-        .and_then(|header| header.parse::<Uri>().ok())
-        .map(|uri| uri.path().to_owned())
+    Ok((server, listen_addresses))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
-
+    use actix_web::http::StatusCode;
     use async_trait::async_trait;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
     use tilejson::{tilejson, Bounds, VectorLayer};
 
     use super::*;
-    use crate::source::{Source, TileData};
+    use crate::source::Source;
+    use crate::srv::{OversizedTilePolicy, TileSizeLimitConfig};
+    use crate::utils::new_main_cache;
 
     #[derive(Debug, Clone)]
     struct TestSource {
@@ -569,6 +1903,10 @@ mod tests {
             unimplemented!()
         }
 
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
         fn clone_source(&self) -> Box<dyn Source> {
             unimplemented!()
         }
@@ -578,8 +1916,293 @@ mod tests {
             _xyz: &TileCoord,
             _url_query: &Option<UrlQuery>,
         ) -> MartinResult<TileData> {
-            unimplemented!()
+            // Behave like a backend that never responds in time
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(Vec::new())
+        }
+    }
+
+    /// Returns the number of the previous fetches as the tile data
+    #[derive(Debug, Clone)]
+    struct CountingSource {
+        tj: TileJSON,
+        fetches: Arc<AtomicU64>,
+    }
+
+    #[async_trait]
+    impl Source for CountingSource {
+        fn get_id(&self) -> &str {
+            "counting"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: &TileCoord,
+            _url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            let count = self.fetches.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![u8::try_from(count).unwrap()])
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stale_while_revalidate() {
+        let src = CountingSource {
+            tj: tilejson! { tiles: vec![] },
+            fetches: Arc::default(),
+        };
+        // the tiles become stale right away, and are served for another minute
+        let expiration = TileExpiration::new(Some(&BTreeMap::from([(0, 0)])))
+            .with_stale_windows(Some(&BTreeMap::from([("counting".to_string(), 60)])));
+        let options = TileOptions {
+            cache: new_main_cache(1, expiration.clone()),
+            expiration,
+            ..TileOptions::default()
+        };
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let get = || get_cached_tile(&src, &options, &xyz, None, src.get_tile(&xyz, &None));
+
+        assert_eq!(get().await.unwrap(), (vec![0], false));
+        // the stale tile is served, while it is fetched again in the background
+        assert_eq!(get().await.unwrap(), (vec![0], true));
+        for _ in 0..100 {
+            if src.fetches.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(src.fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(get().await.unwrap().0, [1]);
+    }
+
+    #[actix_rt::test]
+    async fn test_stale_compressed_tiles() {
+        let src = CountingSource {
+            tj: tilejson! { tiles: vec![] },
+            fetches: Arc::default(),
+        };
+        let expiration = TileExpiration::new(Some(&BTreeMap::from([(0, 60)])))
+            .with_stale_windows(Some(&BTreeMap::from([("counting".to_string(), 60)])));
+        let options = TileOptions {
+            cache: new_main_cache(1, expiration.clone()),
+            expiration,
+            ..TileOptions::default()
+        };
+        let cache = options.cache.as_ref().unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let tile_key = CacheKey::tile("counting", xyz, None);
+        let gzip_key = CacheKey::encoded("counting", xyz, None, Encoding::Gzip);
+        let accept = actix_web::test::TestRequest::default()
+            .insert_header((actix_web::http::header::ACCEPT_ENCODING, "gzip"))
+            .to_http_request()
+            .get_header::<AcceptEncoding>()
+            .unwrap();
+        let sources: [&dyn Source; 1] = [&src];
+        let info = src.get_tile_info();
+        let get = || get_tile_content(&sources, &options, info, &xyz, None, None, Some(&accept));
+        let fetches = &src.fetches;
+        let fetched = |count| async move {
+            for _ in 0..100 {
+                if fetches.load(Ordering::Relaxed) == count {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(fetches.load(Ordering::Relaxed), count);
+        };
+
+        // the compressed stale tile is not cached
+        let stale = CacheValue::Expiring(vec![9], Instant::now());
+        cache.insert(tile_key.clone(), stale).await;
+        assert!(get().await.unwrap().stale);
+        assert!(cache.get(&gzip_key).await.is_none());
+        fetched(1).await;
+        assert!(!get().await.unwrap().stale);
+        assert!(cache.get(&gzip_key).await.is_some());
+
+        // and the compressed tiles are removed once their tile is fetched again
+        let revalidator = options.revalidator.clone();
+        revalidator.revalidate(&src, &options, tile_key, xyz, None);
+        fetched(2).await;
+        for _ in 0..100 {
+            if cache.get(&gzip_key).await.is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert!(cache.get(&gzip_key).await.is_none());
+    }
+
+    #[test]
+    fn test_catalog_dump() {
+        let src = CountingSource {
+            tj: tilejson! { tiles: vec![], name: "Counting".to_string() },
+            fetches: Arc::default(),
+        };
+        let state = ServerState {
+            discovery: crate::Config::default(),
+            cache: None,
+            tile_expiration: TileExpiration::default(),
+            tiles: TileSources::new(vec![vec![Box::new(src)]]),
+            sprites: SpriteSources::default(),
+            fonts: FontSources::default(),
+            styles: StyleSources::default(),
+        };
+        let dump = CatalogDump::new(&state, CompositeTileJsonConfig::default()).unwrap();
+        assert_eq!(dump.catalog.tiles.keys().collect::<Vec<_>>(), ["counting"]);
+        let tj = &dump.tilejson["counting"];
+        assert_eq!(tj.tiles, ["/counting/{z}/{x}/{y}"]);
+        assert_eq!(tj.name.as_deref(), Some("Counting"));
+
+        let json = serde_json::to_value(&dump).unwrap();
+        assert!(json["tiles"]["counting"].is_object());
+        assert!(json["tilejson"]["counting"].is_object());
+    }
+
+    #[actix_rt::test]
+    async fn test_oversized_tiles() {
+        let src = CountingSource {
+            tj: tilejson! { tiles: vec![] },
+            fetches: Arc::default(),
+        };
+        let sources = TileSources::new(vec![vec![Box::new(src)]]);
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let status = |policy| {
+            let options = TileOptions {
+                tile_size_limits: TileSizeLimits::new(Some(&TileSizeLimitConfig {
+                    max_kb: Some(0),
+                    policy: Some(policy),
+                    sources: None,
+                })),
+                ..TileOptions::default()
+            };
+            let sources = &sources;
+            async move {
+                match get_tile_response(sources, &options, xyz, "counting", "", None, None).await {
+                    Ok(response) => response.status(),
+                    Err(e) => e.as_response_error().status_code(),
+                }
+            }
+        };
+        assert_eq!(status(OversizedTilePolicy::Warn).await, StatusCode::OK);
+        assert_eq!(
+            status(OversizedTilePolicy::NoContent).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(OversizedTilePolicy::Error).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_outside_grid() {
+        let src = CountingSource {
+            tj: tilejson! { tiles: vec![] },
+            fetches: Arc::default(),
+        };
+        let options = TileOptions::default();
+        let info = src.get_tile_info();
+        // the second zoom 0 tile only exists in the WorldCRS84Quad grid
+        let xyz = TileCoord { z: 0, x: 1, y: 0 };
+        let err = get_tile_content(&[&src], &options, info, &xyz, None, None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(src.fetches.load(Ordering::Relaxed), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_backend_timeout() {
+        let src = TestSource {
+            tj: tilejson! { tiles: vec![] },
+        };
+        let options = TileOptions {
+            backend_timeout: Some(Duration::from_millis(10)),
+            ..TileOptions::default()
+        };
+        let info = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let err = get_tile_content(&[&src], &options, info, &xyz, None, None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_variant_encoding() {
+        let accept = |value| {
+            actix_web::test::TestRequest::default()
+                .insert_header((actix_web::http::header::ACCEPT_ENCODING, value))
+                .to_http_request()
+                .get_header::<AcceptEncoding>()
+                .unwrap()
+        };
+        let raw = TileInfo::new(Format::Mvt, Encoding::Uncompressed);
+        let gzip = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let png = TileInfo::new(Format::Png, Encoding::Internal);
+        let br = Some(Encoding::Brotli);
+        assert_eq!(get_variant_encoding(raw, &accept("br, gzip")), br);
+        assert_eq!(
+            get_variant_encoding(raw, &accept("gzip, br;q=0.5")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(get_variant_encoding(raw, &accept("identity")), None);
+        // stored tiles in an accepted encoding are served as they are
+        assert_eq!(get_variant_encoding(gzip, &accept("gzip, br")), None);
+        assert_eq!(get_variant_encoding(gzip, &accept("br")), br);
+        assert_eq!(get_variant_encoding(png, &accept("gzip, br")), None);
+    }
+
+    #[test]
+    fn test_tile_filter() {
+        let mvt = TileInfo::new(Format::Mvt, Encoding::Gzip);
+        let filter = |q| get_tile_filter(mvt, q).unwrap();
+        let list = |v: &[&str]| Some(v.iter().map(ToString::to_string).collect::<Vec<_>>());
+        assert_eq!(filter(""), None);
+        assert_eq!(filter("token=abc"), None);
+        assert_eq!(filter("layers=&props="), None);
+        assert_eq!(
+            filter("layers=water,,roads&token=abc"),
+            Some(TileFilter {
+                layers: list(&["water", "roads"]),
+                ..TileFilter::default()
+            })
+        );
+
+        let props = filter("props=name,class").unwrap();
+        assert_eq!(props.props, list(&["name", "class"]));
+        assert!(props.keep_property("name"));
+        assert!(!props.keep_property("import_id"));
+        let excluded = filter("exclude_props=geom_len,import_id").unwrap();
+        assert!(excluded.keep_property("name"));
+        assert!(!excluded.keep_property("import_id"));
+        assert!(get_tile_filter(mvt, "props=name&exclude_props=class").is_err());
+
+        let png = TileInfo::new(Format::Png, Encoding::Internal);
+        assert_eq!(
+            get_tile_filter(png, "layers=water&props=name").unwrap(),
+            None
+        );
     }
 
     #[test]
@@ -600,7 +2223,7 @@ mod tests {
                 ],
             },
         };
-        let tj = merge_tilejson(&[&src1], url.clone());
+        let tj = merge_tilejson(&[&src1], url.clone(), CompositeTileJsonConfig::default());
         assert_eq!(
             TileJSON {
                 tiles: vec![url.clone()],
@@ -625,7 +2248,11 @@ mod tests {
             },
         };
 
-        let tj = merge_tilejson(&[&src1, &src2], url.clone());
+        let tj = merge_tilejson(
+            &[&src1, &src2],
+            url.clone(),
+            CompositeTileJsonConfig::default(),
+        );
         assert_eq!(tj.tiles, vec![url]);
         assert_eq!(tj.name, Some("layer1,layer2".to_string()));
         assert_eq!(tj.minzoom, Some(5));
@@ -645,4 +2272,49 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_merge_tilejson_limits() {
+        let url = "http://localhost:8888/foo/{z}/{x}/{y}".to_string();
+        let sources: Vec<TestSource> = (0..5)
+            .map(|i| TestSource {
+                tj: tilejson! {
+                    tiles: vec![],
+                    name: format!("src{i}"),
+                    attribution: "same".to_string(),
+                    vector_layers: vec![
+                        VectorLayer::new("shared".to_string(),
+                        BTreeMap::from([(format!("f{i}"), "String".to_string())])),
+                        VectorLayer::new(format!("own{i}"), BTreeMap::new()),
+                    ],
+                },
+            })
+            .collect();
+        let sources: Vec<&dyn Source> = sources.iter().map(|v| v as &dyn Source).collect();
+
+        let tj = merge_tilejson(&sources, url.clone(), CompositeTileJsonConfig::default());
+        assert_eq!(tj.attribution, Some("same".to_string()));
+        assert_eq!(tj.name, Some("src0,src1,src2,src3,src4".to_string()));
+        let layers = tj.vector_layers.unwrap();
+        assert_eq!(layers.len(), 6);
+        assert_eq!(layers[0].id, "shared");
+        assert_eq!(layers[0].fields.len(), 5);
+        assert!(!tj.other.contains_key("omitted_vector_layers"));
+
+        let limits = CompositeTileJsonConfig {
+            max_vector_layers: Some(3),
+            max_layer_fields: Some(2),
+        };
+        let tj = merge_tilejson(&sources, url, limits);
+        let layers = tj.vector_layers.unwrap();
+        assert_eq!(
+            layers.iter().map(|v| v.id.as_str()).collect::<Vec<_>>(),
+            vec!["shared", "own0", "own1"]
+        );
+        assert_eq!(
+            layers[0].fields.keys().collect::<Vec<_>>(),
+            vec!["f0", "f1"]
+        );
+        assert_eq!(tj.other["omitted_vector_layers"], 3);
+    }
 }
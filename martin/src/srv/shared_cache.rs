@@ -0,0 +1,217 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::source::TileData;
+use crate::utils::{CacheKey, TileExpiration};
+use crate::MartinResult;
+
+pub const SHARED_CACHE_PREFIX_DEFAULT: &str = "martin:";
+pub const SHARED_CACHE_MAX_ITEM_SIZE_DEFAULT: usize = 1024 * 1024;
+pub const SHARED_CACHE_TIMEOUT_MS_DEFAULT: u64 = 200;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SharedCacheConfig {
+    /// Redis connection URL, e.g. `redis://localhost:6379`
+    pub redis_url: String,
+    /// Prefix of all keys stored by Martin, to share one Redis with other applications [default: `martin:`]
+    pub key_prefix: Option<String>,
+    /// Time to live of the cached tiles in seconds. Uses `tile_max_age` if not set.
+    pub ttl: Option<u64>,
+    /// Tiles larger than this many bytes are not stored in the shared cache [default: 1048576]
+    pub max_item_size: Option<usize>,
+    /// Maximum time in milliseconds to wait for Redis before treating it as a cache miss [default: 200]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Second level tile cache stored in Redis, shared by all Martin instances that use the same key prefix.
+/// Redis failures are logged and treated as cache misses, so that tiles are still served.
+#[derive(Debug, Clone)]
+pub struct SharedCache {
+    client: redis::Client,
+    conn: Arc<Mutex<Option<MultiplexedConnection>>>,
+    prefix: String,
+    ttl: Option<Duration>,
+    expiration: TileExpiration,
+    max_item_size: usize,
+    timeout: Duration,
+}
+
+impl SharedCache {
+    pub fn new(config: &SharedCacheConfig, expiration: TileExpiration) -> MartinResult<Self> {
+        info!("Using shared tile cache at {}", config.redis_url);
+        Ok(Self {
+            client: redis::Client::open(config.redis_url.as_str())?,
+            conn: Arc::default(),
+            prefix: config
+                .key_prefix
+                .clone()
+                .unwrap_or_else(|| SHARED_CACHE_PREFIX_DEFAULT.to_string()),
+            ttl: config.ttl.map(Duration::from_secs),
+            expiration,
+            max_item_size: config
+                .max_item_size
+                .unwrap_or(SHARED_CACHE_MAX_ITEM_SIZE_DEFAULT),
+            timeout: Duration::from_millis(
+                config.timeout_ms.unwrap_or(SHARED_CACHE_TIMEOUT_MS_DEFAULT),
+            ),
+        })
+    }
+
+    /// Redis key of a cached value, e.g. `martin:tile/roads/3/2/1?lang=en`
+    #[must_use]
+    pub fn key(&self, key: &CacheKey) -> String {
+        let (kind, ids, xyz, query) = match key {
+            CacheKey::Tile(id, xyz, query) => ("tile", id, xyz, query),
+            CacheKey::GeoJson(ids, xyz, query) => ("geojson", ids, xyz, query),
+            CacheKey::UtfGrid(ids, xyz, query) => ("utfgrid", ids, xyz, query),
+            CacheKey::Encoded(ids, xyz, query, enc) => {
+                (enc.content_encoding().unwrap_or("tile"), ids, xyz, query)
+            }
+        };
+        let mut result = format!("{}{kind}/{ids}/{}/{}/{}", self.prefix, xyz.z, xyz.x, xyz.y);
+        if let Some(query) = query.as_ref().filter(|q| !q.is_empty()) {
+            result.push('?');
+            result.push_str(query);
+        }
+        result
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        let mut conn = self.conn.lock().await;
+        if let Some(conn) = conn.as_ref() {
+            return Ok(conn.clone());
+        }
+        let new_conn = self.client.get_multiplexed_tokio_connection().await?;
+        *conn = Some(new_conn.clone());
+        Ok(new_conn)
+    }
+
+    /// Drop the connection after an error, so that the next request reconnects
+    async fn reset(&self) {
+        *self.conn.lock().await = None;
+    }
+
+    pub async fn get(&self, key: &CacheKey) -> Option<TileData> {
+        let redis_key = self.key(key);
+        let get = async {
+            let mut conn = self.connection().await?;
+            conn.get::<_, Option<Vec<u8>>>(&redis_key).await
+        };
+        match tokio::time::timeout(self.timeout, get).await {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                warn!("Unable to get {redis_key} from the shared cache: {e}");
+                self.reset().await;
+                None
+            }
+            Err(_) => {
+                warn!("Timeout getting {redis_key} from the shared cache");
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: &CacheKey, data: &TileData) {
+        if data.len() > self.max_item_size {
+            return;
+        }
+        let ttl = match self.expiration.empty_max_age() {
+            Some(max_age) if data.is_empty() => Some(max_age),
+            _ => self.ttl.or_else(|| self.expiration.cache_ttl(key, data)),
+        };
+        let redis_key = self.key(key);
+        let set = async {
+            let mut conn = self.connection().await?;
+            match ttl {
+                Some(ttl) => conn.set_ex(&redis_key, data, ttl.as_secs().max(1)).await,
+                None => conn.set(&redis_key, data).await,
+            }
+        };
+        match tokio::time::timeout(self.timeout, set).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("Unable to store {redis_key} in the shared cache: {e}");
+                self.reset().await;
+            }
+            Err(_) => warn!("Timeout storing {redis_key} in the shared cache"),
+        }
+    }
+
+    /// Remove all cached tiles of the given sources, including the `GeoJSON` tiles that use them
+    pub async fn purge(&self, source_ids: &[String]) -> MartinResult<()> {
+        let mut conn = self.connection().await?;
+        let mut keys = Vec::new();
+        {
+            let pattern = format!("{}*", escape_pattern(&self.prefix));
+            let mut iter = conn.scan_match::<_, String>(pattern).await?;
+            while let Some(key) = iter.next_item().await {
+                let Some(path) = key.strip_prefix(&self.prefix) else {
+                    continue;
+                };
+                let ids = match path.split_once('/') {
+                    Some(("tile" | "geojson", rest)) => rest.split('/').next().unwrap_or_default(),
+                    _ => continue,
+                };
+                if ids.split(',').any(|id| source_ids.iter().any(|s| s == id)) {
+                    keys.push(key);
+                }
+            }
+        }
+        for chunk in keys.chunks(1000) {
+            conn.del::<_, ()>(chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Escape the glob characters used by the Redis `SCAN MATCH` patterns
+fn escape_pattern(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TileCoord;
+
+    #[test]
+    fn shared_cache_keys() {
+        let cfg = SharedCacheConfig {
+            redis_url: "redis://localhost:6379".to_string(),
+            key_prefix: Some("tiles:".to_string()),
+            ttl: None,
+            max_item_size: None,
+            timeout_ms: None,
+        };
+        let cache = SharedCache::new(&cfg, TileExpiration::default()).unwrap();
+        let xyz = TileCoord { z: 3, x: 2, y: 1 };
+        assert_eq!(
+            cache.key(&CacheKey::tile("roads", xyz, None)),
+            "tiles:tile/roads/3/2/1"
+        );
+        let query = [("lang".to_string(), "en".to_string())].into();
+        assert_eq!(
+            cache.key(&CacheKey::tile("roads", xyz, Some(&query))),
+            "tiles:tile/roads/3/2/1?lang=en"
+        );
+        assert_eq!(
+            cache.key(&CacheKey::GeoJson("a,b".to_string(), xyz, None)),
+            "tiles:geojson/a,b/3/2/1"
+        );
+        assert_eq!(escape_pattern("a*b[c]"), r"a\*b\[c\]");
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::utils::saturation::COALESCED_TILES;
+use crate::{CacheKey, MartinResult};
+
+type Flights<T> = Arc<Mutex<HashMap<CacheKey, broadcast::Sender<T>>>>;
+
+/// Runs only one fetch of each tile at a time. The concurrent requests for the same tile
+/// wait for that fetch, and share its result.
+#[derive(Clone)]
+pub struct SingleFlight<T> {
+    flights: Flights<T>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            flights: Arc::default(),
+        }
+    }
+}
+
+impl<T> Debug for SingleFlight<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let count = self.flights.lock().map_or(0, |v| v.len());
+        f.debug_struct("SingleFlight")
+            .field("in_flight", &count)
+            .finish()
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    /// Run the fetch, unless the same tile is being fetched already.
+    /// Errors are not shared: if the running fetch fails or is cancelled,
+    /// the waiting requests run their own fetch, so that each one gets its own error.
+    pub async fn run(
+        &self,
+        key: CacheKey,
+        fetch: impl Future<Output = MartinResult<T>>,
+    ) -> MartinResult<T> {
+        let receiver = match self.flights.lock().unwrap().entry(key.clone()) {
+            Entry::Occupied(v) => Some(v.get().subscribe()),
+            Entry::Vacant(v) => {
+                v.insert(broadcast::channel(1).0);
+                None
+            }
+        };
+        if let Some(mut receiver) = receiver {
+            if let Ok(value) = receiver.recv().await {
+                COALESCED_TILES.fetch_add(1, Ordering::Relaxed);
+                return Ok(value);
+            }
+            return fetch.await;
+        }
+
+        // the flight ends even if this request is cancelled, e.g. by a timeout
+        let flight = Flight {
+            flights: &self.flights,
+            key: Some(key),
+        };
+        let result = fetch.await;
+        if let (Some(sender), Ok(value)) = (flight.land(), &result) {
+            // there may be no waiting requests, so the send may fail
+            let _ = sender.send(value.clone());
+        }
+        result
+    }
+}
+
+/// Removes the running fetch of a tile when dropped
+struct Flight<'a, T> {
+    flights: &'a Flights<T>,
+    key: Option<CacheKey>,
+}
+
+impl<T> Flight<'_, T> {
+    fn land(mut self) -> Option<broadcast::Sender<T>> {
+        let key = self.key.take()?;
+        self.flights.lock().unwrap().remove(&key)
+    }
+}
+
+impl<T> Drop for Flight<'_, T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Ok(mut flights) = self.flights.lock() {
+                flights.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::{MartinError, TileCoord};
+
+    #[actix_rt::test]
+    async fn coalesce_fetches() {
+        let flights = SingleFlight::<Vec<u8>>::default();
+        let fetches = AtomicUsize::new(0);
+        let key = |z| CacheKey::tile("src", TileCoord { z, x: 0, y: 0 }, None);
+        let fetch = |fail: bool| {
+            let fetches = &fetches;
+            async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if fail {
+                    Err(MartinError::NoSources)
+                } else {
+                    Ok(vec![1, 2, 3])
+                }
+            }
+        };
+
+        let results = join_all([
+            flights.run(key(0), fetch(false)),
+            flights.run(key(0), fetch(false)),
+            flights.run(key(0), fetch(false)),
+            flights.run(key(1), fetch(false)),
+        ])
+        .await;
+        assert!(results
+            .iter()
+            .all(|v| v.as_ref().unwrap() == &vec![1, 2, 3]));
+        assert_eq!(fetches.swap(0, Ordering::Relaxed), 2);
+        assert!(flights.flights.lock().unwrap().is_empty());
+
+        // the waiting requests fetch the tile again if the first fetch fails
+        let results = join_all([
+            flights.run(key(0), fetch(true)),
+            flights.run(key(0), fetch(false)),
+        ])
+        .await;
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use actix_files::{Files, NamedFile};
+use actix_web::dev::{fn_service, ServiceRequest, ServiceResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::source::TileSources;
+use crate::srv::{TenantConfigs, RESERVED_KEYWORDS};
+use crate::MartinError::InvalidStaticFiles;
+use crate::MartinResult;
+
+pub const STATIC_FILES_INDEX_DEFAULT: &str = "index.html";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StaticFilesConfig {
+    /// URL path of the files, a single path segment like `/app` that is not used by any source or endpoint
+    pub path: String,
+    /// Directory with the files to serve
+    pub dir: PathBuf,
+    /// File served for the directory paths, relative to `dir` [default: index.html]
+    pub index: Option<String>,
+    /// Serve the index file instead of `404 Not Found` for the missing files, e.g. for the routes of a single page app [default: true]
+    pub spa: Option<bool>,
+}
+
+impl StaticFilesConfig {
+    #[must_use]
+    pub fn index(&self) -> &str {
+        self.index.as_deref().unwrap_or(STATIC_FILES_INDEX_DEFAULT)
+    }
+
+    /// Name of the path, i.e. `app` for `/app`
+    fn name(&self) -> &str {
+        self.path.trim_matches('/')
+    }
+
+    /// Make sure that the files do not shadow the endpoints, and that the directory exists
+    pub fn finalize(&self, tenants: Option<&TenantConfigs>) -> MartinResult<()> {
+        let err = |msg: &str| Err(InvalidStaticFiles(self.path.clone(), msg.to_string()));
+        let name = self.name();
+        if !self.path.starts_with('/') || name.is_empty() || name.contains(['/', ',', '.']) {
+            return err("it must be a single path segment like /app");
+        }
+        if RESERVED_KEYWORDS.contains(&name) {
+            return err("it is a reserved keyword");
+        }
+        if tenants.map_or(false, |v| v.contains_key(name)) {
+            return err("it is the path of a tenant");
+        }
+        if !self.dir.is_dir() {
+            return err(&format!("{} is not a directory", self.dir.display()));
+        }
+        if !self.dir.join(self.index()).is_file() {
+            return err(&format!("index file {} does not exist", self.index()));
+        }
+        Ok(())
+    }
+
+    /// Make sure that no tile source is hidden by the files
+    pub fn validate_sources(&self, sources: &TileSources) -> MartinResult<()> {
+        let name = self.name();
+        if sources.get_source(name).is_ok() || sources.is_composite(name) {
+            let err = format!("source {name} would be hidden by the files");
+            return Err(InvalidStaticFiles(self.path.clone(), err));
+        }
+        Ok(())
+    }
+
+    /// Service serving the files, must be registered before the routes matching any source ID
+    #[must_use]
+    pub fn service(&self) -> Files {
+        let files = Files::new(&format!("/{}", self.name()), &self.dir).index_file(self.index());
+        if !self.spa.unwrap_or(true) {
+            return files;
+        }
+        let index = self.dir.join(self.index());
+        files.default_handler(fn_service(move |req: ServiceRequest| {
+            let index = index.clone();
+            async move {
+                let (req, _) = req.into_parts();
+                let res = NamedFile::open_async(index).await?.into_response(&req);
+                Ok(ServiceResponse::new(req, res))
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::srv::TenantConfig;
+
+    fn config(path: &str, dir: &str) -> StaticFilesConfig {
+        StaticFilesConfig {
+            path: path.to_string(),
+            dir: PathBuf::from(dir),
+            index: None,
+            spa: None,
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = "../tests/fixtures/static";
+        assert!(config("/app", dir).finalize(None).is_ok());
+        assert!(config("/app/", dir).finalize(None).is_ok());
+        assert!(config("app", dir).finalize(None).is_err());
+        assert!(config("/", dir).finalize(None).is_err());
+        assert!(config("/a/b", dir).finalize(None).is_err());
+        assert!(config("/catalog", dir).finalize(None).is_err());
+        assert!(config("/app", "../tests/fixtures/missing")
+            .finalize(None)
+            .is_err());
+        let tenants = TenantConfigs::from([("app".to_string(), TenantConfig::default())]);
+        assert!(config("/app", dir).finalize(Some(&tenants)).is_err());
+        let cfg = StaticFilesConfig {
+            index: Some("missing.html".to_string()),
+            ..config("/app", dir)
+        };
+        assert!(cfg.finalize(None).is_err());
+    }
+}
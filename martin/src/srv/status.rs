@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse, Responder};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+
+use crate::source::{PoolStatus, TileSources};
+use crate::srv::Catalog;
+
+/// Process-wide information used by the `/status` endpoint
+#[derive(Debug, Clone)]
+pub struct RuntimeInfo {
+    started: Instant,
+    workers: usize,
+}
+
+impl RuntimeInfo {
+    #[must_use]
+    pub fn new(workers: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            workers,
+        }
+    }
+
+    /// Number of web server workers
+    #[must_use]
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct ServerStatus {
+    version: &'static str,
+    uptime_secs: u64,
+    workers: usize,
+    /// Number of tile sources of each type
+    sources: BTreeMap<&'static str, usize>,
+    sprites: usize,
+    fonts: usize,
+    pools: BTreeMap<String, PoolStatus>,
+    memory_rss_bytes: Option<u64>,
+}
+
+/// Return runtime statistics of this server as JSON
+#[route("/status", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_status(
+    info: Data<RuntimeInfo>,
+    sources: Data<ArcSwap<TileSources>>,
+    catalog: Data<ArcSwap<Catalog>>,
+) -> impl Responder {
+    let sources = sources.load();
+    let catalog = catalog.load();
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(ServerStatus {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: info.started.elapsed().as_secs(),
+            workers: info.workers,
+            sources: sources.get_source_type_counts(),
+            sprites: catalog.sprites.len(),
+            fonts: catalog.fonts.len(),
+            pools: sources.get_pool_status(),
+            memory_rss_bytes: get_memory_usage(),
+        })
+}
+
+/// Resident set size of the current process, if the platform supports it
+#[cfg(target_os = "linux")]
+fn get_memory_usage() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_memory_usage() -> Option<u64> {
+    None
+}
@@ -0,0 +1,330 @@
+use std::collections::HashSet;
+
+use actix_web::error::ErrorBadRequest;
+use actix_web::web::{Data, Path};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use martin_tile_utils::Format;
+use serde_json::{json, Value};
+use tilejson::{Bounds, VectorLayer};
+
+use crate::source::TileSources;
+use crate::srv::get_public_url;
+use crate::srv::ip_filter::check_source_access;
+use crate::srv::server::{check_tile_access, map_style_error, redirect_sources};
+use crate::srv::SourceRedirects;
+use crate::styles::StyleSources;
+
+/// Opacity of the polygon fills, so that the overlapping layers remain visible
+const FILL_OPACITY: f64 = 0.4;
+
+/// Map page of the preview endpoint, with `{title}`, `{style}` and `{bounds}` placeholders
+const PREVIEW_PAGE: &str = include_str!("preview.html");
+
+#[derive(serde::Deserialize)]
+struct StyleRequest {
+    source_ids: String,
+}
+
+/// A minimal `MapLibre` style that renders every layer of a vector source, or of a composite source,
+/// with a color derived from the layer name. Useful for previewing the data, and as a starting point for styling.
+#[route("/{source_ids}/style.json", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_preview_style(
+    req: HttpRequest,
+    path: Path<StyleRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 1) {
+        return Ok(resp);
+    }
+    check_tile_access(
+        &req,
+        &path.source_ids,
+        &sources.member_ids(&path.source_ids),
+    )?;
+    let src_list = sources.get_sources(&path.source_ids, None)?.0;
+    if let Some(src) = src_list
+        .iter()
+        .find(|src| src.get_tile_info().format != Format::Mvt)
+    {
+        return Err(ErrorBadRequest(format!(
+            "Source {} is not a vector tile source",
+            src.get_id()
+        )));
+    }
+    let layers: Vec<&VectorLayer> = src_list
+        .iter()
+        .filter_map(|src| src.get_tilejson().vector_layers.as_ref())
+        .flatten()
+        .collect();
+
+    let tilejson_url = get_tilejson_url(&req, "/style.json");
+    Ok(HttpResponse::Ok().json(preview_style(&path.source_ids, &tilejson_url, &layers)))
+}
+
+/// A map page of a source, or of a composite source, zoomed to its bounds. Vector sources use the preview style,
+/// and the properties of the features under the pointer are shown in the inspect mode.
+#[route("/{source_ids}/preview", method = "GET", method = "HEAD")]
+#[allow(clippy::unused_async)]
+async fn get_preview_page(
+    req: HttpRequest,
+    path: Path<StyleRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 1) {
+        return Ok(resp);
+    }
+    check_tile_access(
+        &req,
+        &path.source_ids,
+        &sources.member_ids(&path.source_ids),
+    )?;
+    let (src_list, _, info) = sources.get_sources(&path.source_ids, None)?;
+    let tilejson_url = get_tilejson_url(&req, "/preview");
+    let style = match info.format {
+        Format::Mvt => {
+            let layers: Vec<&VectorLayer> = src_list
+                .iter()
+                .filter_map(|src| src.get_tilejson().vector_layers.as_ref())
+                .flatten()
+                .collect();
+            preview_style(&path.source_ids, &tilejson_url, &layers)
+        }
+        Format::Png | Format::Jpeg | Format::Webp | Format::Gif => {
+            raster_style(&path.source_ids, &tilejson_url)
+        }
+        format => {
+            return Err(ErrorBadRequest(format!(
+                "Sources {} with {format} tiles cannot be shown on a map",
+                path.source_ids
+            )))
+        }
+    };
+    let bounds = src_list
+        .iter()
+        .filter_map(|src| src.get_tilejson().bounds)
+        .reduce(|a, b| a + b)
+        .unwrap_or(Bounds::new(-180.0, -85.0, 180.0, 85.0));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(preview_page(&path.source_ids, &style, bounds)))
+}
+
+/// Absolute URL of the source `TileJSON`, i.e. the request URL without the `suffix`, keeping the query string
+fn get_tilejson_url(req: &HttpRequest, suffix: &str) -> String {
+    let url = get_public_url(req);
+    let tilejson_path = url.path.strip_suffix(suffix).unwrap_or(&url.path);
+    let tilejson_url = format!("{}://{}{tilejson_path}", url.scheme, url.host);
+    if req.query_string().is_empty() {
+        tilejson_url
+    } else {
+        format!("{tilejson_url}?{}", req.query_string())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct StyleFileRequest {
+    style_id: String,
+}
+
+/// A configured style file, with its relative URLs pointing to this server.
+/// The base URL is the configured `public_url`, or is taken from the forwarding headers of the trusted proxies.
+#[route(
+    "/style/{style_id}",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_style(
+    req: HttpRequest,
+    path: Path<StyleFileRequest>,
+    styles: Data<StyleSources>,
+) -> ActixResult<HttpResponse> {
+    check_source_access(&req, &path.style_id)?;
+    let base_url = get_public_url(&req).base_url();
+    let style = styles
+        .get_style(&path.style_id, &base_url)
+        .await
+        .map_err(map_style_error)?;
+    Ok(HttpResponse::Ok().json(style))
+}
+
+/// Build a style with a fill, a line and a circle layer for each vector layer, so that any geometry type is shown.
+fn preview_style(source_id: &str, tilejson_url: &str, vector_layers: &[&VectorLayer]) -> Value {
+    let mut seen = HashSet::new();
+    let mut layers = Vec::new();
+    for layer in vector_layers {
+        // Composite sources may contain several layers with the same name, but style layer IDs must be unique
+        if !seen.insert(layer.id.as_str()) {
+            continue;
+        }
+        let color = layer_color(&layer.id);
+        let layer_types = [
+            (
+                "fill",
+                "Polygon",
+                json!({ "fill-color": color, "fill-opacity": FILL_OPACITY }),
+            ),
+            (
+                "line",
+                "LineString",
+                json!({ "line-color": color, "line-width": 1.5 }),
+            ),
+            (
+                "circle",
+                "Point",
+                json!({
+                    "circle-color": color,
+                    "circle-radius": 4,
+                    "circle-stroke-color": "#ffffff",
+                    "circle-stroke-width": 1,
+                }),
+            ),
+        ];
+        for (layer_type, geometry_type, paint) in layer_types {
+            let mut style_layer = json!({
+                "id": format!("{}-{layer_type}", layer.id),
+                "type": layer_type,
+                "source": source_id,
+                "source-layer": layer.id,
+                "filter": ["==", "$type", geometry_type],
+                "paint": paint,
+            });
+            if let Some(minzoom) = layer.minzoom {
+                style_layer["minzoom"] = json!(minzoom);
+            }
+            if let Some(maxzoom) = layer.maxzoom {
+                // Layer maxzoom is exclusive in styles, but inclusive in TileJSON
+                style_layer["maxzoom"] = json!(u16::from(maxzoom) + 1);
+            }
+            layers.push(style_layer);
+        }
+    }
+
+    json!({
+        "version": 8,
+        "name": source_id,
+        "sources": {
+            source_id: {
+                "type": "vector",
+                "url": tilejson_url,
+            }
+        },
+        "layers": layers,
+    })
+}
+
+/// Build a style with a single layer showing the raster tiles
+fn raster_style(source_id: &str, tilejson_url: &str) -> Value {
+    json!({
+        "version": 8,
+        "name": source_id,
+        "sources": {
+            source_id: {
+                "type": "raster",
+                "url": tilejson_url,
+                "tileSize": 256,
+            }
+        },
+        "layers": [{ "id": source_id, "type": "raster", "source": source_id }],
+    })
+}
+
+/// Fill in the preview page. The values are inserted into a script, so `</` is escaped to keep
+/// layer names like `</script>` from ending it early.
+fn preview_page(source_id: &str, style: &Value, bounds: Bounds) -> String {
+    let script_value = |v: &Value| v.to_string().replace("</", "<\\/");
+    let title = source_id
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    let bounds = json!([[bounds.left, bounds.bottom], [bounds.right, bounds.top]]);
+    PREVIEW_PAGE
+        .replace("{title}", &title)
+        .replace("{style}", &script_value(style))
+        .replace("{bounds}", &script_value(&bounds))
+}
+
+/// A color that only depends on the layer name, so that a layer looks the same on every request and every server.
+/// Uses FNV-1a because the standard hasher output is not guaranteed to be stable between Rust releases.
+fn layer_color(layer_id: &str) -> String {
+    let hash = layer_id.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    let hue = hash % 360;
+    let saturation = 60 + (hash >> 16) % 20;
+    let lightness = 40 + (hash >> 24) % 15;
+    format!("hsl({hue}, {saturation}%, {lightness}%)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(id: &str, minzoom: Option<u8>, maxzoom: Option<u8>) -> VectorLayer {
+        VectorLayer {
+            minzoom,
+            maxzoom,
+            ..VectorLayer::new(id.to_string(), std::collections::BTreeMap::default())
+        }
+    }
+
+    #[test]
+    fn colors_are_deterministic() {
+        assert_eq!(layer_color("roads"), layer_color("roads"));
+        assert_ne!(layer_color("roads"), layer_color("water"));
+        assert!(layer_color("roads").starts_with("hsl("));
+    }
+
+    #[test]
+    fn style_has_layers_for_each_geometry() {
+        let roads = layer("roads", Some(4), Some(14));
+        let water = layer("water", None, None);
+        let layers = [&roads, &water, &roads];
+        let style = preview_style("src", "http://localhost:3000/src", &layers);
+
+        assert_eq!(style["version"], 8);
+        assert_eq!(style["sources"]["src"]["url"], "http://localhost:3000/src");
+        let ids: Vec<_> = style["layers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "roads-fill",
+                "roads-line",
+                "roads-circle",
+                "water-fill",
+                "water-line",
+                "water-circle"
+            ]
+        );
+        let fill = &style["layers"][0];
+        assert_eq!(fill["source-layer"], "roads");
+        assert_eq!(fill["minzoom"], 4);
+        assert_eq!(fill["maxzoom"], 15);
+        assert_eq!(fill["filter"], json!(["==", "$type", "Polygon"]));
+        assert_eq!(fill["paint"]["fill-color"], json!(layer_color("roads")));
+        assert!(style["layers"][3].get("minzoom").is_none());
+    }
+
+    #[test]
+    fn preview_page_is_filled() {
+        let roads = layer("</script>", None, None);
+        let style = preview_style("a<b", "http://localhost:3000/a<b", &[&roads]);
+        let page = preview_page("a<b", &style, Bounds::new(-10.0, -5.0, 10.0, 5.0));
+        assert!(page.contains("<title>a&lt;b - Martin preview</title>"));
+        assert!(page.contains("const bounds = [[-10.0,-5.0],[10.0,5.0]];"));
+        assert_eq!(page.matches("</script>").count(), 2);
+        assert!(!page.contains("{style}"));
+    }
+}
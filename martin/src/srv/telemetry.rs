@@ -0,0 +1,184 @@
+use std::future::{ready, Ready};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName};
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use log::{info, warn};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, Key, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument as _};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use tracing_subscriber::layer::SubscriberExt as _;
+
+use crate::args::Env;
+use crate::MartinError::TelemetryError;
+use crate::MartinResult;
+
+/// Environment variables of the OTLP endpoint. Tracing is only enabled if one of them is set.
+const OTLP_ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+];
+
+/// Export the tracing spans to an OTLP collector over HTTP, configured with the standard
+/// `OTEL_*` environment variables. Does nothing unless an OTLP endpoint is set.
+/// Returns true if the tracing was enabled.
+pub fn init_telemetry<'a>(env: &impl Env<'a>) -> MartinResult<bool> {
+    if env.get_env_str("OTEL_SDK_DISABLED").as_deref() == Some("true") {
+        return Ok(false);
+    }
+    let Some(endpoint) = OTLP_ENDPOINT_VARS
+        .iter()
+        .find_map(|var| env.get_env_str(var))
+    else {
+        return Ok(false);
+    };
+
+    // the service name can still be set with OTEL_SERVICE_NAME or OTEL_RESOURCE_ATTRIBUTES
+    let mut resource = Resource::default();
+    let service_name = resource.get(Key::from_static_str("service.name"));
+    if service_name.map_or(true, |v| v.as_str().starts_with("unknown_service")) {
+        resource = resource.merge(&Resource::new([KeyValue::new("service.name", "martin")]));
+    }
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http())
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+        .map_err(TelemetryError)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        warn!("Tracing was already initialized, the spans will not be exported to {endpoint}");
+        return Ok(false);
+    }
+    info!("Exporting traces to {endpoint}");
+    Ok(true)
+}
+
+/// Send the remaining spans to the collector
+pub fn shutdown_telemetry() {
+    global::shutdown_tracer_provider();
+}
+
+/// Middleware that runs each request in a tracing span, continuing the trace of the
+/// W3C `traceparent` header of the request, if any. The span is a no-op if the tracing is disabled.
+#[derive(Debug, Clone, Default)]
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware { service }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let route = req.match_pattern().unwrap_or_else(|| "unknown".to_string());
+        let span = info_span!(
+            "request",
+            otel.name = %format!("{} {route}", req.method()),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.request.method = %req.method(),
+            http.route = %route,
+            url.path = req.path(),
+            request.id = Empty,
+            http.response.status_code = Empty,
+        );
+        let parent =
+            global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+        span.set_parent(parent);
+
+        let fut = span.in_scope(|| self.service.call(req));
+        Box::pin(
+            async move {
+                let res = fut.await;
+                let status = match &res {
+                    Ok(res) => res.status(),
+                    Err(e) => e.as_response_error().status_code(),
+                };
+                let span = tracing::Span::current();
+                span.record("http.response.status_code", status.as_u16());
+                if status.is_server_error() {
+                    span.record("otel.status_code", "ERROR");
+                }
+                res
+            }
+            .instrument(span),
+        )
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use opentelemetry::propagation::TextMapPropagator as _;
+    use opentelemetry::trace::TraceContextExt as _;
+
+    use super::*;
+
+    #[test]
+    fn extract_trace_context() {
+        let req = TestRequest::default()
+            .insert_header((
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ))
+            .to_srv_request();
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+        let span = cx.span();
+        let span_cx = span.span_context();
+        assert!(span_cx.is_remote());
+        assert_eq!(
+            span_cx.trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(span_cx.span_id().to_string(), "b7ad6b7169203331");
+    }
+}
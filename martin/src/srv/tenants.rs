@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_cors::Cors;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{Error, HttpMessage as _, HttpRequest, HttpResponse, Result as ActixResult};
+use futures::future::LocalBoxFuture;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq as _;
+
+use crate::source::{TileCatalog, TileSources, UrlQuery};
+use crate::srv::RESERVED_KEYWORDS;
+use crate::MartinError::InvalidTenant;
+use crate::MartinResult;
+
+/// Query parameter with the tenant token, for the clients that cannot set the `Authorization` header
+pub const TENANT_TOKEN_QUERY_PARAM: &str = "token";
+
+/// Sources of a single customer, served under `/{tenant}/` with their own catalog and secret
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TenantConfig {
+    /// IDs of the tile sources of the tenant. They are only served under the tenant path.
+    pub sources: Vec<String>,
+    /// Secret that must be sent as `Authorization: Bearer <token>` or `?token=<token>`
+    pub token: Option<String>,
+    /// Origins allowed to request the tenant tiles from a browser [default: any origin]
+    pub cors_origins: Option<Vec<String>>,
+}
+
+pub type TenantConfigs = BTreeMap<String, TenantConfig>;
+
+/// Make sure the tenant names can be used as the first path segment, and that no source is shared
+pub fn validate_tenants(tenants: &TenantConfigs) -> MartinResult<()> {
+    let mut owners = HashMap::new();
+    for (name, cfg) in tenants {
+        if name.is_empty() || name.contains(['/', ',', '.']) {
+            let err = "it must not be empty, or contain '/', ',', or '.'";
+            return Err(InvalidTenant(name.clone(), err.to_string()));
+        }
+        if RESERVED_KEYWORDS.contains(&name.as_str()) {
+            return Err(InvalidTenant(
+                name.clone(),
+                "it is a reserved keyword".into(),
+            ));
+        }
+        for id in &cfg.sources {
+            if let Some(other) = owners.insert(id, name) {
+                let err = format!("source {id} also belongs to tenant {other}");
+                return Err(InvalidTenant(name.clone(), err));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name of the tenant whose scope is serving the request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TenantId(pub String);
+
+/// Middleware that checks the tenant token, and only serves the tenant sources under the tenant path.
+/// Requests for the tenant sources outside of their tenant get `404 Not Found`. The owners of the sources
+/// are checked by the handlers, see [`check_tenant_access`].
+#[derive(Clone, Debug, Default)]
+pub struct Tenants(Arc<TenantsInner>);
+
+/// Tenants of the server, stored in the request extensions by the [`Tenants`] middleware,
+/// so that the handlers can check the owners of the sources once the requested sources are resolved
+#[derive(Clone, Debug)]
+struct TenantRules(Tenants);
+
+/// Check that the requested tile sources belong to the tenant of the request, or to no tenant outside of
+/// the tenant paths, responding with `404 Not Found` otherwise. The sources serving them, e.g. the members
+/// of a named composite, must not belong to another tenant, see [`Tenants::is_served`].
+/// Requests are not checked if the server is not wrapped with the [`Tenants`] middleware.
+pub(crate) fn check_tenant_access(
+    req: &HttpRequest,
+    source_ids: &str,
+    member_ids: &str,
+) -> ActixResult<()> {
+    let Some(TenantRules(tenants)) = req.extensions().get::<TenantRules>().cloned() else {
+        return Ok(());
+    };
+    let tenant = req.app_data::<Data<TenantId>>().map(|v| v.0.as_str());
+    if tenants.is_served(tenant, source_ids, member_ids) {
+        Ok(())
+    } else {
+        debug!("Rejected request for {source_ids} of another tenant");
+        Err(ErrorNotFound(format!("Source {source_ids} does not exist")))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TenantsInner {
+    tenants: TenantConfigs,
+    /// Tenant of each source ID
+    owners: HashMap<String, String>,
+}
+
+impl Tenants {
+    #[must_use]
+    pub fn new(config: Option<&TenantConfigs>) -> Self {
+        let tenants = config.cloned().unwrap_or_default();
+        let owners = tenants
+            .iter()
+            .flat_map(|(name, cfg)| cfg.sources.iter().map(|id| (id.clone(), name.clone())))
+            .collect();
+        Self(Arc::new(TenantsInner { tenants, owners }))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.tenants.is_empty()
+    }
+
+    /// Names of all configured tenants
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.0.tenants.keys()
+    }
+
+    /// Make sure all tenant sources exist
+    pub fn validate_sources(&self, sources: &TileSources) -> MartinResult<()> {
+        for (id, name) in &self.0.owners {
+            if !sources.contains(id) && !sources.is_composite(id) && !sources.is_variant(id) {
+                let err = format!("source {id} does not exist");
+                return Err(InvalidTenant(name.clone(), err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the token of the requests under a tenant path, e.g. `/acme/src1,src2/0/0/0`,
+    /// returning the error status otherwise
+    pub fn check(&self, path: &str, headers: &HeaderMap, query: &str) -> Result<(), StatusCode> {
+        let first = path.trim_start_matches('/').split('/').next().unwrap_or("");
+        let Some(token) = self.0.tenants.get(first).and_then(|v| v.token.as_ref()) else {
+            return Ok(());
+        };
+        // compared in constant time, so that the token cannot be guessed from the response times
+        let is_valid = request_token(headers, query)
+            .map_or(false, |v| v.as_bytes().ct_eq(token.as_bytes()).into());
+        if is_valid {
+            Ok(())
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+
+    /// Check if the sources may be served under the path of the tenant, or outside of the tenant paths
+    /// if `tenant` is `None`. The requested IDs must belong to the tenant, and the IDs of the sources
+    /// serving them, see [`TileSources::member_ids`], to the tenant or to no tenant at all.
+    #[must_use]
+    pub fn is_served(&self, tenant: Option<&str>, source_ids: &str, member_ids: &str) -> bool {
+        let owner = |id: &str| self.0.owners.get(id).map(String::as_str);
+        source_ids.split(',').all(|id| owner(id) == tenant)
+            && member_ids
+                .split(',')
+                .all(|id| owner(id).map_or(true, |v| Some(v) == tenant))
+    }
+
+    /// Keep only the sources served under the path of the tenant in the catalog, or outside of the tenant paths
+    /// if `tenant` is `None`, see [`Tenants::is_served`]. The variant sources are listed if their default variant is served.
+    #[must_use]
+    pub fn filter_catalog(
+        &self,
+        catalog: &TileCatalog,
+        sources: &TileSources,
+        tenant: Option<&TenantId>,
+    ) -> TileCatalog {
+        let tenant = tenant.map(|v| v.0.as_str());
+        let mut catalog = catalog.clone();
+        catalog.retain(|id, _| {
+            let (resolved, _) = sources.resolve_variants(id, "", &HeaderMap::new());
+            self.is_served(tenant, id, &sources.member_ids(&resolved))
+        });
+        catalog
+    }
+
+    /// CORS middleware allowing only the configured origins of each tenant
+    #[must_use]
+    pub fn cors(&self) -> Cors {
+        let cors = Cors::default().allowed_methods(vec!["GET"]);
+        if self.0.tenants.values().all(|v| v.cors_origins.is_none()) {
+            return cors.allow_any_origin();
+        }
+        let tenants = self.clone();
+        cors.allowed_origin_fn(move |origin, req| {
+            let name = req.uri.path().trim_start_matches('/').split('/').next();
+            let origins = name
+                .and_then(|v| tenants.0.tenants.get(v))
+                .and_then(|v| v.cors_origins.as_ref());
+            origins.map_or(true, |v| {
+                v.iter().any(|v| v.as_bytes() == origin.as_bytes())
+            })
+        })
+    }
+}
+
+fn request_token(headers: &HeaderMap, query: &str) -> Option<String> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(ToString::to_string)
+        .or_else(|| {
+            Query::<UrlQuery>::from_query(query)
+                .ok()
+                .and_then(|mut q| q.remove(TENANT_TOKEN_QUERY_PARAM))
+        })
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Tenants
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = TenantsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TenantsMiddleware {
+            service,
+            tenants: self.clone(),
+        }))
+    }
+}
+
+pub struct TenantsMiddleware<S> {
+    service: S,
+    tenants: Tenants,
+}
+
+impl<S, B> Service<ServiceRequest> for TenantsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !self.tenants.is_empty() {
+            let result = self
+                .tenants
+                .check(req.path(), req.headers(), req.query_string());
+            if let Err(status) = result {
+                debug!("Rejected tenant request for {} with {status}", req.path());
+                let resp = HttpResponse::new(status).map_into_right_body();
+                return Box::pin(ready(Ok(req.into_response(resp))));
+            }
+            req.extensions_mut()
+                .insert(TenantRules(self.tenants.clone()));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+    use indoc::indoc;
+
+    use super::*;
+    use crate::source::CatalogSourceEntry;
+
+    fn tenants() -> Tenants {
+        let config: TenantConfigs = serde_yaml::from_str(indoc! {"
+            acme:
+              sources: [roads, parcels]
+              token: secret
+            globex:
+              sources: [water]
+        "})
+        .unwrap();
+        validate_tenants(&config).unwrap();
+        Tenants::new(Some(&config))
+    }
+
+    #[test]
+    fn tenant_access() {
+        let tenants = tenants();
+        let none = HeaderMap::new();
+        let check = |path, query| tenants.check(path, &none, query);
+        assert_eq!(check("/acme/roads/0/0/0", "token=secret"), Ok(()));
+        assert_eq!(check("/acme/catalog", "token=secret"), Ok(()));
+        assert_eq!(
+            check("/acme/roads/0/0/0", ""),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            check("/acme/roads/0/0/0", "token=bad"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(check("/globex/water/0/0/0", ""), Ok(()));
+        assert_eq!(check("/roads/0/0/0", ""), Ok(()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert_eq!(tenants.check("/acme/parcels", &headers, ""), Ok(()));
+    }
+
+    #[test]
+    fn source_owners() {
+        let tenants = tenants();
+        let served = |tenant, ids, members| tenants.is_served(tenant, ids, members);
+        assert!(served(Some("acme"), "roads,parcels", "roads,parcels"));
+        assert!(!served(Some("acme"), "water", "water"));
+        assert!(!served(Some("globex"), "roads,water", "roads,water"));
+        // the tenant sources are not served outside of their tenant
+        assert!(!served(None, "roads", "roads"));
+        assert!(!served(None, "public,water", "public,water"));
+        assert!(served(None, "public", "public"));
+        // nor by the composites and the variants of other tenants, or of no tenant
+        assert!(!served(None, "mixed", "mixed,public,roads"));
+        assert!(!served(Some("globex"), "water", "water,roads"));
+        assert!(served(Some("acme"), "roads", "roads,public"));
+    }
+
+    #[test]
+    fn tenant_catalog() {
+        let tenants = tenants();
+        let catalog: TileCatalog = ["roads", "water", "public"]
+            .into_iter()
+            .map(|id| (id.to_string(), CatalogSourceEntry::default()))
+            .collect();
+        let ids = |tenant: Option<&str>| {
+            let tenant = tenant.map(|v| TenantId(v.to_string()));
+            let catalog =
+                tenants.filter_catalog(&catalog, &TileSources::default(), tenant.as_ref());
+            catalog.into_keys().collect::<Vec<_>>()
+        };
+        assert_eq!(ids(None), vec!["public"]);
+        assert_eq!(ids(Some("acme")), vec!["roads"]);
+        assert_eq!(ids(Some("globex")), vec!["water"]);
+    }
+
+    #[test]
+    fn invalid_tenants() {
+        let config = |yaml| validate_tenants(&serde_yaml::from_str(yaml).unwrap());
+        assert!(config("acme: {sources: [roads]}").is_ok());
+        assert!(config("catalog: {sources: [roads]}").is_err());
+        assert!(config("'a/b': {sources: [roads]}").is_err());
+        assert!(config("{a: {sources: [roads]}, b: {sources: [roads]}}").is_err());
+    }
+}
@@ -0,0 +1,829 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::web::Query;
+use actix_web::{Error, HttpMessage as _, HttpRequest, HttpResponse, ResponseError};
+use futures::future::LocalBoxFuture;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::source::UrlQuery;
+
+pub const API_KEY_HEADER_DEFAULT: &str = "x-api-key";
+pub const API_KEY_QUERY_PARAM: &str = "api_key";
+/// How often the usage is written to the `usage_file`, if it has changed
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    /// Maximum number of response bytes per second sent by the whole server
+    pub max_bytes_per_sec: Option<u64>,
+    /// Header with the API key [default: `X-Api-Key`]. The key may also be passed as `?api_key=...`
+    pub api_key_header: Option<String>,
+    /// Quotas of each API key, either the number of tiles per calendar month, or a [`QuotaConfig`]
+    pub api_keys: Option<BTreeMap<String, ApiKeyConfig>>,
+    /// Number of tiles per calendar month for all requests without a known API key.
+    /// Unlimited if not set.
+    pub anonymous_monthly_tiles: Option<u64>,
+    /// Number of requests per day for all requests without a known API key.
+    /// Unlimited if not set.
+    pub anonymous_daily_requests: Option<u64>,
+    /// Highest zoom level of the tiles requested without a known API key.
+    /// Unlimited if not set.
+    pub anonymous_max_zoom: Option<u8>,
+    /// JSON file to keep the usage in, so that it is not reset on restart
+    pub usage_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ApiKeyConfig {
+    /// Number of tiles the key may request per calendar month
+    MonthlyTiles(u64),
+    Config(QuotaConfig),
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Number of tiles per calendar month (UTC). Unlimited if not set.
+    pub monthly_tiles: Option<u64>,
+    /// Number of requests of any kind per day (UTC). Unlimited if not set.
+    pub daily_requests: Option<u64>,
+    /// Highest zoom level of the requested tiles. Unlimited if not set.
+    pub max_zoom: Option<u8>,
+}
+
+impl ApiKeyConfig {
+    #[must_use]
+    pub fn quota(&self) -> QuotaConfig {
+        match self {
+            Self::MonthlyTiles(tiles) => QuotaConfig {
+                monthly_tiles: Some(*tiles),
+                ..QuotaConfig::default()
+            },
+            Self::Config(quota) => quota.clone(),
+        }
+    }
+}
+
+/// Token bucket with a one second burst, allowed to go into debt by a large response
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        // Precision loss is irrelevant for bandwidth values
+        #[allow(clippy::cast_precision_loss)]
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            available: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Get the time to wait until the bucket is no longer in debt
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        (self.available < 0.0).then(|| Duration::from_secs_f64(-self.available / self.rate))
+    }
+
+    fn consume(&mut self, now: Instant, bytes: u64) {
+        self.refill(now);
+        #[allow(clippy::cast_precision_loss)]
+        let bytes = bytes as f64;
+        self.available -= bytes;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    month: Month,
+    /// Days since the Unix epoch
+    day: u64,
+    /// Tiles served this month per API key, with `None` for anonymous requests
+    tiles: BTreeMap<Option<String>, u64>,
+    /// Requests made today per API key, with `None` for anonymous requests
+    requests: BTreeMap<Option<String>, u64>,
+}
+
+impl Usage {
+    /// Start new counters once the month or the day has changed
+    fn roll_over(&mut self, day: u64) {
+        if self.day == day {
+            return;
+        }
+        let month = Month::from_days(day);
+        if self.month != month {
+            self.month = month;
+            self.tiles.clear();
+        }
+        self.day = day;
+        self.requests.clear();
+    }
+}
+
+/// Usage as stored in the `usage_file`
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct SavedUsage {
+    /// Days since the Unix epoch
+    day: u64,
+    anonymous_tiles: u64,
+    anonymous_requests: u64,
+    tiles: BTreeMap<String, u64>,
+    requests: BTreeMap<String, u64>,
+}
+
+impl From<&Usage> for SavedUsage {
+    fn from(usage: &Usage) -> Self {
+        let split = |values: &BTreeMap<Option<String>, u64>| {
+            let anonymous = values.get(&None).copied().unwrap_or(0);
+            let keys = values
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone()?, *v)))
+                .collect();
+            (anonymous, keys)
+        };
+        let (anonymous_tiles, tiles) = split(&usage.tiles);
+        let (anonymous_requests, requests) = split(&usage.requests);
+        Self {
+            day: usage.day,
+            anonymous_tiles,
+            anonymous_requests,
+            tiles,
+            requests,
+        }
+    }
+}
+
+impl From<SavedUsage> for Usage {
+    fn from(saved: SavedUsage) -> Self {
+        let join = |anonymous: u64, keys: BTreeMap<String, u64>| {
+            keys.into_iter()
+                .map(|(k, v)| (Some(k), v))
+                .chain(std::iter::once((None, anonymous)))
+                .collect()
+        };
+        Self {
+            month: Month::from_days(saved.day),
+            day: saved.day,
+            tiles: join(saved.anonymous_tiles, saved.tiles),
+            requests: join(saved.anonymous_requests, saved.requests),
+        }
+    }
+}
+
+fn days_now() -> u64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs / 86400
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Month {
+    year: u64,
+    month: u64,
+}
+
+impl Month {
+    /// Convert days since the Unix epoch to a calendar month
+    fn from_days(days: u64) -> Self {
+        let (year, month, _) = civil_from_days(days);
+        Self { year, month }
+    }
+}
+
+/// Convert days since the Unix epoch to the year, month, and day of the month,
+/// see <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Tiles used this month
+    pub used: u64,
+    /// Tiles allowed per month
+    pub limit: Option<u64>,
+    /// Requests made today
+    pub requests_today: u64,
+    /// Requests allowed per day
+    pub daily_requests: Option<u64>,
+    pub max_zoom: Option<u8>,
+}
+
+impl QuotaUsage {
+    fn new(quota: &QuotaConfig, used: u64, requests_today: u64) -> Self {
+        Self {
+            used,
+            limit: quota.monthly_tiles,
+            requests_today,
+            daily_requests: quota.daily_requests,
+            max_zoom: quota.max_zoom,
+        }
+    }
+}
+
+/// Reason to reject a tile request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    DailyRequests,
+    MonthlyTiles,
+    MaxZoom(u8),
+}
+
+impl ResponseError for QuotaExceeded {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::MaxZoom(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DailyRequests => write!(f, "The daily request quota has been used up"),
+            Self::MonthlyTiles => write!(f, "The monthly tile quota has been used up"),
+            Self::MaxZoom(z) => write!(f, "Tiles above zoom level {z} are not allowed"),
+        }
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct ThrottleReport {
+    /// Calendar month of the tile quotas, e.g. `2024-01`
+    pub month: String,
+    /// Day of the request quotas, e.g. `2024-01-31`
+    pub date: String,
+    pub max_bytes_per_sec: Option<u64>,
+    pub anonymous: QuotaUsage,
+    pub api_keys: BTreeMap<String, QuotaUsage>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    config: ThrottleConfig,
+    header: String,
+    keys: BTreeMap<String, QuotaConfig>,
+    anonymous: QuotaConfig,
+    bandwidth: Option<Mutex<Bucket>>,
+    usage: Mutex<Usage>,
+    /// Whether the usage has changed since it was last saved
+    changed: AtomicBool,
+}
+
+/// Middleware that limits the total egress bandwidth, and the number of tiles, the number of requests,
+/// and the zoom levels per API key. It responds with `429 Too Many Requests` once a limit is reached,
+/// and with `403 Forbidden` for the tiles above the maximum zoom level. The requests are counted here,
+/// while the tiles are counted by the tile handlers, once the zoom levels of the tiles are known.
+/// Usage is kept in memory, and is only kept across restarts if `usage_file` is set.
+#[derive(Debug, Clone)]
+pub struct Throttle(Arc<ThrottleState>);
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(ThrottleConfig::default())
+    }
+}
+
+impl Throttle {
+    #[must_use]
+    pub fn new(config: ThrottleConfig) -> Self {
+        let header = config
+            .api_key_header
+            .clone()
+            .unwrap_or_else(|| API_KEY_HEADER_DEFAULT.to_string());
+        let keys = config
+            .api_keys
+            .iter()
+            .flatten()
+            .map(|(key, cfg)| (key.clone(), cfg.quota()))
+            .collect();
+        let anonymous = QuotaConfig {
+            monthly_tiles: config.anonymous_monthly_tiles,
+            daily_requests: config.anonymous_daily_requests,
+            max_zoom: config.anonymous_max_zoom,
+        };
+        let mut usage = config
+            .usage_file
+            .as_deref()
+            .and_then(load_usage)
+            .map_or_else(Usage::default, Usage::from);
+        usage.roll_over(days_now());
+        Self(Arc::new(ThrottleState {
+            bandwidth: config.max_bytes_per_sec.map(|v| Mutex::new(Bucket::new(v))),
+            usage: Mutex::new(usage),
+            changed: AtomicBool::new(false),
+            header,
+            keys,
+            anonymous,
+            config,
+        }))
+    }
+
+    /// Write the usage to the `usage_file` periodically, if configured.
+    /// Must be called from within an actix runtime.
+    pub fn start_saving(&self) {
+        let Some(path) = self.0.config.usage_file.clone() else {
+            return;
+        };
+        let throttle = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(USAGE_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if throttle.0.changed.swap(false, Ordering::Relaxed) {
+                    let saved = SavedUsage::from(&*throttle.0.usage.lock().unwrap());
+                    if let Err(e) = save_usage(&path, &saved).await {
+                        warn!("Unable to save the quota usage to {}: {e}", path.display());
+                    }
+                }
+            }
+        });
+    }
+
+    /// Find the known API key of the request, if any
+    pub(crate) fn api_key(&self, req: &HttpRequest) -> Option<String> {
+        if self.0.keys.is_empty() {
+            return None;
+        }
+        let key = req
+            .headers()
+            .get(&self.0.header)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string)
+            .or_else(|| {
+                Query::<UrlQuery>::from_query(req.query_string())
+                    .ok()
+                    .and_then(|mut q| q.remove(API_KEY_QUERY_PARAM))
+            })?;
+        self.0.keys.contains_key(&key).then_some(key)
+    }
+
+    /// Find the known API key of a gRPC call, sent as the metadata named like the API key header
+    #[cfg(feature = "grpc")]
+    pub(crate) fn grpc_api_key(&self, metadata: &tonic::metadata::MetadataMap) -> Option<String> {
+        let key = metadata.get(self.0.header.as_str())?.to_str().ok()?;
+        self.0.keys.contains_key(key).then(|| key.to_string())
+    }
+
+    fn quota(&self, key: Option<&String>) -> &QuotaConfig {
+        key.and_then(|key| self.0.keys.get(key))
+            .unwrap_or(&self.0.anonymous)
+    }
+
+    /// Count a request of any kind, or fail if the daily quota has been used up
+    pub(crate) fn try_use_request(&self, key: Option<String>) -> Result<(), QuotaExceeded> {
+        let limit = self.quota(key.as_ref()).daily_requests;
+        let mut usage = self.0.usage.lock().unwrap();
+        usage.roll_over(days_now());
+        let used = usage.requests.entry(key).or_default();
+        if limit.map_or(false, |limit| *used >= limit) {
+            return Err(QuotaExceeded::DailyRequests);
+        }
+        *used += 1;
+        self.0.changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Count the tiles of a request, or fail if the tiles are above the maximum zoom level,
+    /// or if the monthly quota does not have enough tiles left
+    pub(crate) fn try_use_tiles(
+        &self,
+        key: Option<String>,
+        z: u8,
+        count: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let quota = self.quota(key.as_ref());
+        if let Some(max_zoom) = quota.max_zoom {
+            if z > max_zoom {
+                return Err(QuotaExceeded::MaxZoom(max_zoom));
+            }
+        }
+        let limit = quota.monthly_tiles;
+        let mut usage = self.0.usage.lock().unwrap();
+        usage.roll_over(days_now());
+        let used = usage.tiles.entry(key).or_default();
+        if limit.map_or(false, |limit| *used + count > limit) {
+            return Err(QuotaExceeded::MonthlyTiles);
+        }
+        *used += count;
+        self.0.changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn bandwidth_wait_time(&self) -> Option<Duration> {
+        let bucket = self.0.bandwidth.as_ref()?;
+        bucket.lock().unwrap().wait_time(Instant::now())
+    }
+
+    pub(crate) fn consume_bandwidth(&self, bytes: u64) {
+        if let Some(bucket) = &self.0.bandwidth {
+            bucket.lock().unwrap().consume(Instant::now(), bytes);
+        }
+    }
+
+    /// Get the current quota usage of all API keys
+    #[must_use]
+    pub fn report(&self) -> ThrottleReport {
+        let mut usage = self.0.usage.lock().unwrap();
+        usage.roll_over(days_now());
+        let get = |values: &BTreeMap<Option<String>, u64>, key: Option<&String>| {
+            values.get(&key.cloned()).copied().unwrap_or(0)
+        };
+        let report = |key: Option<&String>, quota: &QuotaConfig| {
+            QuotaUsage::new(quota, get(&usage.tiles, key), get(&usage.requests, key))
+        };
+        let (year, month, day) = civil_from_days(usage.day);
+        ThrottleReport {
+            month: format!("{year}-{month:02}"),
+            date: format!("{year}-{month:02}-{day:02}"),
+            max_bytes_per_sec: self.0.config.max_bytes_per_sec,
+            anonymous: report(None, &self.0.anonymous),
+            api_keys: self
+                .0
+                .keys
+                .iter()
+                .map(|(key, quota)| (key.clone(), report(Some(key), quota)))
+                .collect(),
+        }
+    }
+}
+
+fn load_usage(path: &Path) -> Option<SavedUsage> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(
+                "Unable to read the quota usage from {}: {e}",
+                path.display()
+            );
+            return None;
+        }
+    };
+    serde_json::from_slice(&data)
+        .map_err(|e| {
+            warn!(
+                "Ignoring the invalid quota usage in {}: {e}",
+                path.display()
+            );
+        })
+        .ok()
+}
+
+/// Write the usage to a temporary file first, so that a crash never leaves a partial file behind
+async fn save_usage(path: &Path, usage: &SavedUsage) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(usage)?).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Tile quota of the API key of a request, stored in the request extensions by the [`Throttle`] middleware.
+/// The tiles are counted by the handlers, once the zoom levels of the requested tiles are known.
+#[derive(Debug, Clone)]
+pub(crate) struct TileQuota {
+    throttle: Throttle,
+    api_key: Option<String>,
+}
+
+impl TileQuota {
+    /// Get the tile quota of a request, or `None` if the request is not throttled
+    pub(crate) fn of(req: &HttpRequest) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+
+    pub(crate) fn try_use(&self, z: u8, count: u64) -> Result<(), QuotaExceeded> {
+        self.throttle.try_use_tiles(self.api_key.clone(), z, count)
+    }
+}
+
+/// Count a single tile at the zoom level `z`, if the request is throttled
+pub(crate) fn use_tile(req: &HttpRequest, z: u8) -> Result<(), QuotaExceeded> {
+    TileQuota::of(req).map_or(Ok(()), |quota| quota.try_use(z, 1))
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Throttle
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ThrottleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ThrottleMiddleware {
+            service,
+            throttle: self.clone(),
+        }))
+    }
+}
+
+pub struct ThrottleMiddleware<S> {
+    service: S,
+    throttle: Throttle,
+}
+
+impl<S, B> Service<ServiceRequest> for ThrottleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let throttle = &self.throttle;
+        if let Some(wait) = throttle.bandwidth_wait_time() {
+            debug!(
+                "Rejected request for {} over the bandwidth limit",
+                req.path()
+            );
+            let resp = HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, wait.as_secs() + 1))
+                .finish()
+                .map_into_right_body();
+            return Box::pin(ready(Ok(req.into_response(resp))));
+        }
+        let api_key = throttle.api_key(req.request());
+        if let Err(e) = throttle.try_use_request(api_key.clone()) {
+            debug!("Rejected request for {}: {e}", req.path());
+            let resp = e.error_response().map_into_right_body();
+            return Box::pin(ready(Ok(req.into_response(resp))));
+        }
+        req.extensions_mut().insert(TileQuota {
+            throttle: throttle.clone(),
+            api_key,
+        });
+
+        let fut = self.service.call(req);
+        let throttle = throttle.clone();
+        Box::pin(async move {
+            let res = fut.await?;
+            if let BodySize::Sized(size) = res.response().body().size() {
+                throttle.consume_bandwidth(size);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn months() {
+        assert_eq!(
+            Month::from_days(0),
+            Month {
+                year: 1970,
+                month: 1
+            }
+        );
+        // 2024-02-29
+        assert_eq!(
+            Month::from_days(19_782),
+            Month {
+                year: 2024,
+                month: 2
+            }
+        );
+        // 2024-03-01
+        assert_eq!(
+            Month::from_days(19_783),
+            Month {
+                year: 2024,
+                month: 3
+            }
+        );
+        // 2023-12-31
+        assert_eq!(
+            Month::from_days(19_722),
+            Month {
+                year: 2023,
+                month: 12
+            }
+        );
+    }
+
+    #[test]
+    fn bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.wait_time(start), None);
+        bucket.consume(start, 3000);
+        let wait = bucket.wait_time(start).unwrap();
+        assert_eq!(wait, Duration::from_secs(2));
+        assert_eq!(bucket.wait_time(start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+
+    #[actix_rt::test]
+    async fn quotas() {
+        let cfg: ThrottleConfig = serde_yaml::from_str(indoc! {"
+            api_keys:
+              key1: 2
+            anonymous_monthly_tiles: 1
+        "})
+        .unwrap();
+        let throttle = Throttle::new(cfg);
+        let get_tile = |req: HttpRequest, path: web::Path<(String, u8, u32, u32)>| async move {
+            use_tile(&req, path.1).map(|()| HttpResponse::Ok().finish())
+        };
+        let app = init_service(
+            App::new()
+                .wrap(throttle.clone())
+                .route("/{source_ids}/{z}/{x}/{y}", web::get().to(get_tile))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |uri: &str, key: Option<&str>| {
+            let mut req = TestRequest::get().uri(uri);
+            if let Some(key) = key {
+                req = req.insert_header(("X-Api-Key", key));
+            }
+            let app = &app;
+            async move { call_service(app, req.to_request()).await.status() }
+        };
+
+        assert_eq!(status("/src/0/0/0", None).await, StatusCode::OK);
+        assert_eq!(
+            status("/src/0/0/0", None).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status("/src/0/0/0", Some("unknown")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status("/src", None).await, StatusCode::OK);
+        assert_eq!(status("/src/0/0/0", Some("key1")).await, StatusCode::OK);
+        assert_eq!(
+            status("/src/0/0/0?api_key=key1", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("/src/0/0/0", Some("key1")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let report = throttle.report();
+        assert_eq!(
+            report.anonymous,
+            QuotaUsage {
+                used: 1,
+                limit: Some(1),
+                requests_today: 4,
+                daily_requests: None,
+                max_zoom: None,
+            }
+        );
+        assert_eq!(
+            report.api_keys["key1"],
+            QuotaUsage {
+                used: 2,
+                limit: Some(2),
+                requests_today: 3,
+                daily_requests: None,
+                max_zoom: None,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn key_quotas() {
+        let cfg: ThrottleConfig = serde_yaml::from_str(indoc! {"
+            api_keys:
+              key1:
+                daily_requests: 3
+                max_zoom: 10
+            anonymous_max_zoom: 5
+        "})
+        .unwrap();
+        let throttle = Throttle::new(cfg);
+        let key1 = || Some("key1".to_string());
+        assert_eq!(throttle.try_use_tiles(None, 5, 1), Ok(()));
+        assert_eq!(
+            throttle.try_use_tiles(None, 6, 1),
+            Err(QuotaExceeded::MaxZoom(5))
+        );
+        assert_eq!(throttle.try_use_tiles(key1(), 10, 1), Ok(()));
+        assert_eq!(
+            throttle.try_use_tiles(key1(), 11, 1),
+            Err(QuotaExceeded::MaxZoom(10))
+        );
+        for _ in 0..3 {
+            assert_eq!(throttle.try_use_request(key1()), Ok(()));
+        }
+        assert_eq!(
+            throttle.try_use_request(key1()),
+            Err(QuotaExceeded::DailyRequests)
+        );
+        assert_eq!(throttle.try_use_request(None), Ok(()));
+    }
+
+    #[actix_rt::test]
+    async fn saved_usage() {
+        let path =
+            std::env::temp_dir().join(format!("martin-throttle-usage-{}.json", std::process::id()));
+        let cfg = ThrottleConfig {
+            api_keys: Some(BTreeMap::from([(
+                "key1".to_string(),
+                ApiKeyConfig::MonthlyTiles(2),
+            )])),
+            usage_file: Some(path.clone()),
+            ..ThrottleConfig::default()
+        };
+        let throttle = Throttle::new(cfg.clone());
+        throttle
+            .try_use_tiles(Some("key1".to_string()), 0, 1)
+            .unwrap();
+        throttle.try_use_tiles(None, 0, 1).unwrap();
+        let saved = SavedUsage::from(&*throttle.0.usage.lock().unwrap());
+        save_usage(&path, &saved).await.unwrap();
+
+        let restarted = Throttle::new(cfg);
+        assert_eq!(restarted.report(), throttle.report());
+        restarted
+            .try_use_tiles(Some("key1".to_string()), 0, 1)
+            .unwrap();
+        assert_eq!(
+            restarted.try_use_tiles(Some("key1".to_string()), 0, 1),
+            Err(QuotaExceeded::MonthlyTiles)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn many_tiles() {
+        let throttle = Throttle::new(ThrottleConfig {
+            anonymous_monthly_tiles: Some(10),
+            ..ThrottleConfig::default()
+        });
+        assert_eq!(
+            throttle.try_use_tiles(None, 0, 11),
+            Err(QuotaExceeded::MonthlyTiles)
+        );
+        assert_eq!(throttle.try_use_tiles(None, 0, 8), Ok(()));
+        assert_eq!(
+            throttle.try_use_tiles(None, 0, 3),
+            Err(QuotaExceeded::MonthlyTiles)
+        );
+        assert_eq!(throttle.try_use_tiles(None, 0, 2), Ok(()));
+        assert_eq!(throttle.report().anonymous.used, 10);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::Result as ActixResult;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::saturation::record_oversized_tile;
+use crate::TileCoord;
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedTilePolicy {
+    /// Log a warning, and serve the tile
+    #[default]
+    Warn,
+    /// Log a warning, and respond with `204 No Content`
+    NoContent,
+    /// Log a warning, and respond with `500 Internal Server Error`
+    Error,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TileSizeLimitConfig {
+    /// Maximum size in kilobytes of the tiles as sent to the clients, i.e. after compression
+    pub max_kb: Option<u64>,
+    /// Response to the larger tiles [default: `warn`]
+    pub policy: Option<OversizedTilePolicy>,
+    /// Limits of individual sources, keyed by source ID
+    pub sources: Option<BTreeMap<String, SourceTileSizeLimitConfig>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SourceTileSizeLimitConfig {
+    /// Maximum size in kilobytes of the tiles of the source [default: `max_kb` of all sources]
+    pub max_kb: Option<u64>,
+    /// Response to the larger tiles of the source [default: `policy` of all sources]
+    pub policy: Option<OversizedTilePolicy>,
+}
+
+/// Maximum size of the tiles of a source, and the response to the larger tiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileSizeLimit {
+    pub max_bytes: usize,
+    pub policy: OversizedTilePolicy,
+}
+
+impl TileSizeLimit {
+    fn new(max_kb: Option<u64>, policy: Option<OversizedTilePolicy>) -> Option<Self> {
+        Some(Self {
+            max_bytes: usize::try_from(max_kb?.saturating_mul(1024)).unwrap_or(usize::MAX),
+            policy: policy.unwrap_or_default(),
+        })
+    }
+}
+
+/// Size limits of the tiles of all sources, and of the configured sources
+#[derive(Clone, Debug, Default)]
+pub struct TileSizeLimits(Arc<Limits>);
+
+#[derive(Debug, Default)]
+struct Limits {
+    default: Option<TileSizeLimit>,
+    sources: BTreeMap<String, Option<TileSizeLimit>>,
+}
+
+impl TileSizeLimits {
+    #[must_use]
+    pub fn new(config: Option<&TileSizeLimitConfig>) -> Self {
+        let Some(config) = config else {
+            return Self::default();
+        };
+        let default = TileSizeLimit::new(config.max_kb, config.policy);
+        let sources = config
+            .sources
+            .iter()
+            .flatten()
+            .map(|(id, cfg)| {
+                let limit =
+                    TileSizeLimit::new(cfg.max_kb.or(config.max_kb), cfg.policy.or(config.policy));
+                (id.clone(), limit)
+            })
+            .collect();
+        Self(Arc::new(Limits { default, sources }))
+    }
+
+    /// Get the size limit of the comma-separated sources. The sources of a composite request
+    /// must all be configured the same way, otherwise the limit of all sources is used.
+    #[must_use]
+    pub fn get(&self, source_ids: &str) -> Option<TileSizeLimit> {
+        let mut limits = source_ids.split(',').map(|id| self.0.sources.get(id));
+        match limits.next().flatten() {
+            Some(first) if limits.all(|v| v == Some(first)) => *first,
+            _ => self.0.default,
+        }
+    }
+
+    /// Check the size of a tile, counting and logging the oversized ones.
+    /// Returns true if the tile must be replaced by `204 No Content`.
+    pub fn check(&self, source_ids: &str, xyz: TileCoord, size: usize) -> ActixResult<bool> {
+        let Some(limit) = self.get(source_ids).filter(|v| size > v.max_bytes) else {
+            return Ok(false);
+        };
+        record_oversized_tile(source_ids);
+        let msg = format!(
+            "Tile {xyz} of {source_ids} is {size} bytes, more than the limit of {} bytes",
+            limit.max_bytes
+        );
+        warn!("{msg}");
+        match limit.policy {
+            OversizedTilePolicy::Warn => Ok(false),
+            OversizedTilePolicy::NoContent => Ok(true),
+            OversizedTilePolicy::Error => Err(ErrorInternalServerError(msg)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn size_limits() {
+        let config: TileSizeLimitConfig = serde_yaml::from_str(indoc! {"
+            max_kb: 500
+            sources:
+              roads:
+                policy: no_content
+              water:
+                policy: no_content
+              satellite:
+                max_kb: 2000
+                policy: error
+              terrain: {}
+        "})
+        .unwrap();
+        let limits = TileSizeLimits::new(Some(&config));
+        let limit = |max_kb: usize, policy| {
+            Some(TileSizeLimit {
+                max_bytes: max_kb * 1024,
+                policy,
+            })
+        };
+        assert_eq!(limits.get("other"), limit(500, OversizedTilePolicy::Warn));
+        assert_eq!(limits.get("terrain"), limit(500, OversizedTilePolicy::Warn));
+        assert_eq!(
+            limits.get("roads,water"),
+            limit(500, OversizedTilePolicy::NoContent)
+        );
+        assert_eq!(
+            limits.get("roads,satellite"),
+            limit(500, OversizedTilePolicy::Warn)
+        );
+        assert_eq!(
+            limits.get("satellite"),
+            limit(2000, OversizedTilePolicy::Error)
+        );
+
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        assert!(!limits.check("roads", xyz, 500 * 1024).unwrap());
+        assert!(limits.check("roads", xyz, 500 * 1024 + 1).unwrap());
+        assert!(!limits.check("other", xyz, 1024 * 1024).unwrap());
+        assert!(limits.check("satellite", xyz, 3000 * 1024).is_err());
+
+        let limits = TileSizeLimits::default();
+        assert_eq!(limits.get("roads"), None);
+        assert!(!limits.check("roads", xyz, usize::MAX).unwrap());
+    }
+}
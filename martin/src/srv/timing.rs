@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// Timings of the tile request handled by the current task, only set if `server_timing` is enabled
+    static TIMING: RefCell<ServerTiming>;
+}
+
+/// Where the time to serve a tile was spent, sent in the `Server-Timing` response header
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ServerTiming {
+    /// Time spent finding the requested sources
+    lookup: Option<Duration>,
+    /// Time each source took to generate its tile, in the order they finished.
+    /// The sources of a composite run in parallel.
+    sources: Vec<(String, Duration)>,
+    /// Time spent decompressing the tile, e.g. to compress it with another encoding
+    decode: Option<Duration>,
+    /// Time spent compressing the tile for the client
+    encode: Option<Duration>,
+    cache_hits: usize,
+    cache_misses: usize,
+}
+
+impl ServerTiming {
+    /// Run the future, collecting the timings of the tiles it gets
+    pub async fn collect<F: Future>(future: F) -> (F::Output, Self) {
+        TIMING
+            .scope(RefCell::new(Self::default()), async {
+                let output = future.await;
+                (output, TIMING.with(RefCell::take))
+            })
+            .await
+    }
+
+    /// Record how long it took to find the requested sources
+    pub fn set_lookup(&mut self, elapsed: Duration) {
+        self.lookup = Some(elapsed);
+    }
+
+    /// Run the future of a source generating a tile, recording how long it took
+    pub async fn time_source<F: Future>(source_id: &str, future: F) -> F::Output {
+        let start = Instant::now();
+        let output = future.await;
+        let elapsed = start.elapsed();
+        Self::update(|t| t.sources.push((source_id.to_string(), elapsed)));
+        output
+    }
+
+    /// Run the tile decompression, recording how long it took
+    pub fn time_decode<T>(decode: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let output = decode();
+        let elapsed = start.elapsed();
+        Self::update(|t| t.decode = Some(t.decode.unwrap_or_default() + elapsed));
+        output
+    }
+
+    /// Run the tile compression, recording how long it took
+    pub fn time_encode<T>(encode: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let output = encode();
+        let elapsed = start.elapsed();
+        Self::update(|t| t.encode = Some(t.encode.unwrap_or_default() + elapsed));
+        output
+    }
+
+    pub fn record_cache(hit: bool) {
+        Self::update(|t| {
+            if hit {
+                t.cache_hits += 1;
+            } else {
+                t.cache_misses += 1;
+            }
+        });
+    }
+
+    /// Does nothing unless called within [`ServerTiming::collect`]
+    fn update(f: impl FnOnce(&mut Self)) {
+        let _ = TIMING.try_with(|t| f(&mut t.borrow_mut()));
+    }
+
+    /// Value of the `Server-Timing` header, e.g.
+    /// `lookup;dur=0.01, source;desc="roads";dur=12.5, db;dur=12.5, encode;dur=0.8, cache;desc=miss, total;dur=13.6`
+    #[must_use]
+    pub fn header_value(&self, total: Duration) -> String {
+        let mut value = String::new();
+        if let Some(v) = self.lookup {
+            let _ = write!(value, "lookup;dur={}, ", to_millis(v));
+        }
+        for (id, v) in &self.sources {
+            let id = id.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(value, "source;desc=\"{id}\";dur={}, ", to_millis(*v));
+        }
+        if let Some(v) = self.sources.iter().map(|(_, v)| *v).max() {
+            let _ = write!(value, "db;dur={}, ", to_millis(v));
+        }
+        if let Some(v) = self.decode {
+            let _ = write!(value, "decode;dur={}, ", to_millis(v));
+        }
+        if let Some(v) = self.encode {
+            let _ = write!(value, "encode;dur={}, ", to_millis(v));
+        }
+        if self.cache_hits + self.cache_misses > 0 {
+            let desc = match (self.cache_hits, self.cache_misses) {
+                (_, 0) => "hit",
+                (0, _) => "miss",
+                _ => "partial",
+            };
+            let _ = write!(value, "cache;desc={desc}, ");
+        }
+        let _ = write!(value, "total;dur={}", to_millis(total));
+        value
+    }
+}
+
+/// Milliseconds with a microsecond precision
+fn to_millis(duration: Duration) -> f64 {
+    f64::from(u32::try_from(duration.as_micros()).unwrap_or(u32::MAX)) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn collect_timings() {
+        let (output, timing) = ServerTiming::collect(async {
+            ServerTiming::record_cache(false);
+            ServerTiming::time_source("roads", async { 1 }).await
+                + ServerTiming::time_decode(|| 1)
+                + ServerTiming::time_encode(|| {
+                    std::thread::sleep(Duration::from_millis(2));
+                    1
+                })
+        })
+        .await;
+        assert_eq!(output, 3);
+        assert_eq!(timing.sources.len(), 1);
+        assert_eq!(timing.sources[0].0, "roads");
+        assert!(timing.decode.is_some());
+        assert!(timing.encode.unwrap() >= Duration::from_millis(2));
+        assert_eq!(timing.cache_misses, 1);
+
+        // outside of collect() nothing is recorded, and nothing fails
+        ServerTiming::record_cache(true);
+
+        let mut timing = ServerTiming {
+            sources: vec![
+                ("water".to_string(), Duration::from_micros(2_500)),
+                ("roads".to_string(), Duration::from_micros(12_345)),
+            ],
+            cache_hits: 1,
+            cache_misses: 1,
+            ..ServerTiming::default()
+        };
+        timing.set_lookup(Duration::from_micros(10));
+        assert_eq!(
+            timing.header_value(Duration::from_millis(15)),
+            r#"lookup;dur=0.01, source;desc="water";dur=2.5, source;desc="roads";dur=12.345, db;dur=12.345, cache;desc=partial, total;dur=15"#
+        );
+        assert_eq!(
+            ServerTiming::default().header_value(Duration::from_micros(500)),
+            "total;dur=0.5"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::{Data, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+
+use crate::srv::admin::authorize;
+use crate::srv::config::AdminConfig;
+use crate::srv::server::map_internal_error;
+use crate::srv::{get_public_url, TileOptions, API_KEY_QUERY_PARAM};
+
+pub const TRAFFIC_MAX_PATHS_DEFAULT: usize = 10_000;
+pub const TRAFFIC_EXPORT_SIZE_DEFAULT: usize = 10_000;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrafficProfileConfig {
+    /// Maximum number of distinct request paths to count. Requests for other paths are
+    /// only counted once this limit is reached. [default: 10000]
+    pub max_paths: Option<usize>,
+}
+
+/// Counts the tile requests per path, so that load tests can replay the same traffic shape
+#[derive(Clone, Debug)]
+pub struct TrafficRecorder {
+    max_paths: usize,
+    paths: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl TrafficRecorder {
+    #[must_use]
+    pub fn new(config: &TrafficProfileConfig) -> Self {
+        Self {
+            max_paths: config.max_paths.unwrap_or(TRAFFIC_MAX_PATHS_DEFAULT),
+            paths: Arc::default(),
+        }
+    }
+
+    /// Count a request, leaving out the API key so that it is never exported
+    pub fn record(&self, path: &str, query: &str) {
+        let query = query
+            .split('&')
+            .filter(|v| !v.is_empty() && v.split('=').next() != Some(API_KEY_QUERY_PARAM))
+            .collect::<Vec<_>>()
+            .join("&");
+        let key = if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{path}?{query}")
+        };
+        let mut paths = self.paths.lock().unwrap();
+        let len = paths.len();
+        if let Some(count) = paths.get_mut(&key) {
+            *count += 1;
+        } else if len < self.max_paths {
+            paths.insert(key, 1);
+        }
+    }
+
+    /// Request paths in the order they should be sent, with at most `size` requests.
+    /// Each path is repeated in proportion to its count, and the paths are interleaved,
+    /// so that any part of the list has roughly the same mix of requests.
+    #[must_use]
+    pub fn profile(&self, size: usize) -> Vec<String> {
+        let mut paths: Vec<_> = self
+            .paths
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), u128::from(*v)))
+            .collect();
+        // the most requested paths first, and the same order for the same counts
+        paths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let total: u128 = paths.iter().map(|(_, count)| count).sum();
+        let limit = size as u128;
+        if total > limit {
+            // rounded, but every path is requested at least once until the size is reached
+            for (_, count) in &mut paths {
+                *count = ((*count * limit + total / 2) / total).max(1);
+            }
+        }
+
+        let rounds = paths.first().map_or(0, |(_, count)| *count);
+        let mut result = Vec::new();
+        for round in 0..rounds {
+            for (path, _) in paths.iter().take_while(|(_, count)| round < *count) {
+                if result.len() >= size {
+                    return result;
+                }
+                result.push(path.clone());
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ProfileFormat {
+    /// `GET <url>` lines for `vegeta attack -targets`
+    #[default]
+    Vegeta,
+    /// JSON array of URLs, e.g. for a k6 `SharedArray`
+    K6,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileRequest {
+    #[serde(default)]
+    format: ProfileFormat,
+    /// Server to send the load test to, e.g. a staging instance. Defaults to this server.
+    base_url: Option<String>,
+    /// Maximum number of requests in the profile
+    size: Option<usize>,
+}
+
+/// Export the recorded tile requests as a load test profile
+#[route("/_/traffic", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_traffic_profile(
+    req: HttpRequest,
+    admin: Option<Data<AdminConfig>>,
+    options: Data<TileOptions>,
+    params: Query<ProfileRequest>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let Some(traffic) = &options.traffic else {
+        return Err(ErrorNotFound(
+            "Traffic recording is disabled, set traffic_profile in the config",
+        ));
+    };
+    let base_url = match &params.base_url {
+        Some(v) if v.starts_with("http://") || v.starts_with("https://") => {
+            v.trim_end_matches('/').to_string()
+        }
+        Some(v) => return Err(ErrorBadRequest(format!("Invalid base URL {v}"))),
+        None => get_public_url(&req).base_url(),
+    };
+    let urls = traffic
+        .profile(params.size.unwrap_or(TRAFFIC_EXPORT_SIZE_DEFAULT))
+        .into_iter()
+        .map(|path| format!("{base_url}{path}"));
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((CACHE_CONTROL, "no-cache"));
+    Ok(match params.format {
+        ProfileFormat::Vegeta => {
+            response
+                .content_type("text/plain; charset=utf-8")
+                .body(urls.fold(String::new(), |mut body, url| {
+                    let _ = writeln!(body, "GET {url}");
+                    body
+                }))
+        }
+        ProfileFormat::K6 => response
+            .json(serde_json::to_value(urls.collect::<Vec<_>>()).map_err(map_internal_error)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_profile() {
+        let traffic = TrafficRecorder::new(&TrafficProfileConfig { max_paths: Some(3) });
+        for _ in 0..4 {
+            traffic.record("/roads/0/0/0", "");
+        }
+        traffic.record("/roads/1/0/0", "api_key=secret&year=2023");
+        traffic.record("/roads/1/0/0", "year=2023&api_key=other");
+        traffic.record("/water/1/1/1", "");
+        // over the limit of distinct paths
+        traffic.record("/water/2/2/2", "");
+
+        assert_eq!(
+            traffic.profile(100),
+            vec![
+                "/roads/0/0/0",
+                "/roads/1/0/0?year=2023",
+                "/water/1/1/1",
+                "/roads/0/0/0",
+                "/roads/1/0/0?year=2023",
+                "/roads/0/0/0",
+                "/roads/0/0/0",
+            ]
+        );
+        // scaled down, but every path is requested at least once
+        assert_eq!(
+            traffic.profile(4),
+            vec![
+                "/roads/0/0/0",
+                "/roads/1/0/0?year=2023",
+                "/water/1/1/1",
+                "/roads/0/0/0",
+            ]
+        );
+        assert!(traffic.profile(0).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use actix_web::body::MessageBody as _;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{Accept, HeaderValue, Quality, CONTENT_TYPE, ETAG, VARY};
+use actix_web::{HttpResponse, Result as ActixResult};
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, ImageResult};
+use martin_tile_utils::Format;
+use serde::{Deserialize, Serialize};
+
+pub const TRANSCODE_QUALITY_DEFAULT: u8 = 80;
+
+/// Formats the raster tiles can be transcoded to, in the order of the server preference.
+/// The image encoders of this build only support PNG and JPEG, so the other formats are never produced.
+const TARGET_FORMATS: [Format; 2] = [Format::Jpeg, Format::Png];
+
+/// Transcode the stored PNG and JPEG tiles to the format preferred by the `Accept` header of the client
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TranscodeConfig {
+    /// IDs of the sources whose tiles may be transcoded [default: all PNG and JPEG sources]
+    pub sources: Option<Vec<String>>,
+    /// Quality of the JPEG tiles from 1 to 100 [default: 80]
+    pub quality: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RasterTranscoder {
+    sources: Option<Arc<BTreeSet<String>>>,
+    quality: u8,
+}
+
+impl RasterTranscoder {
+    #[must_use]
+    pub fn new(config: &TranscodeConfig) -> Self {
+        Self {
+            sources: config
+                .sources
+                .as_ref()
+                .map(|v| Arc::new(v.iter().cloned().collect())),
+            quality: config
+                .quality
+                .unwrap_or(TRANSCODE_QUALITY_DEFAULT)
+                .clamp(1, 100),
+        }
+    }
+
+    /// Check if the tiles of all requested sources may be transcoded
+    fn is_enabled(&self, source_ids: &str) -> bool {
+        self.sources
+            .as_ref()
+            .map_or(true, |v| source_ids.split(',').all(|id| v.contains(id)))
+    }
+
+    /// Get the format the client prefers over the stored format of a tile, if it can be produced
+    #[must_use]
+    pub fn target(&self, format: Format, accept: &Accept) -> Option<Format> {
+        if !matches!(format, Format::Png | Format::Jpeg) {
+            return None;
+        }
+        let current = accept_quality(accept, format);
+        TARGET_FORMATS
+            .into_iter()
+            .filter(|v| *v != format)
+            .map(|v| (accept_quality(accept, v), v))
+            .filter(|(quality, _)| *quality > current)
+            .min_by_key(|(quality, _)| Reverse(*quality))
+            .map(|(_, v)| v)
+    }
+
+    /// Transcode the tile, or return `None` if it has transparent pixels and the target format has no alpha channel
+    pub fn transcode(&self, data: &[u8], from: Format, to: Format) -> ImageResult<Option<Vec<u8>>> {
+        let input = if from == Format::Jpeg {
+            ImageFormat::Jpeg
+        } else {
+            ImageFormat::Png
+        };
+        let mut image = image::load_from_memory_with_format(data, input)?;
+        let output = if to == Format::Jpeg {
+            if image.color().has_alpha() && image.to_rgba8().pixels().any(|v| v[3] < 255) {
+                return Ok(None);
+            }
+            image = DynamicImage::ImageRgb8(image.to_rgb8());
+            ImageOutputFormat::Jpeg(self.quality)
+        } else {
+            ImageOutputFormat::Png
+        };
+        let mut result = Cursor::new(Vec::new());
+        image.write_to(&mut result, output)?;
+        Ok(Some(result.into_inner()))
+    }
+
+    /// Replace the PNG or JPEG tile of the response with the format preferred by the client
+    pub fn transcode_response(
+        &self,
+        mut response: HttpResponse,
+        source_ids: &str,
+        accept: Option<&Accept>,
+    ) -> ActixResult<HttpResponse> {
+        let format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| TARGET_FORMATS.into_iter().find(|f| f.content_type() == v));
+        let Some(format) = format.filter(|_| self.is_enabled(source_ids)) else {
+            return Ok(response);
+        };
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept"));
+        let target = accept.and_then(|v| self.target(format, v));
+        let Some(target) = target.filter(|_| response.status().is_success()) else {
+            return Ok(response);
+        };
+
+        let (mut response, body) = response.into_parts();
+        let data = match body.try_into_bytes() {
+            Ok(data) => data,
+            Err(body) => return Ok(response.set_body(body)),
+        };
+        let transcoded = self
+            .transcode(&data, format, target)
+            .map_err(|e| ErrorInternalServerError(format!("Unable to transcode tile: {e}")))?;
+        let Some(transcoded) = transcoded else {
+            return Ok(response.set_body(data).map_into_boxed_body());
+        };
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(target.content_type()) {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        // the tag of the stored tile does not identify the transcoded one
+        headers.remove(ETAG);
+        Ok(response.set_body(transcoded).map_into_boxed_body())
+    }
+}
+
+/// Quality of the most specific media range of the `Accept` header matching the format
+fn accept_quality(accept: &Accept, format: Format) -> Quality {
+    let mime = format.content_type();
+    let kind = mime.split('/').next().unwrap_or_default();
+    let mut best = None;
+    for range in accept.iter() {
+        let specificity = if range.item.essence_str() == mime {
+            2
+        } else if range.item.type_() == kind && range.item.subtype() == "*" {
+            1
+        } else if range.item.essence_str() == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.map_or(true, |(v, _)| specificity > v) {
+            best = Some((specificity, range.quality));
+        }
+    }
+    best.map_or(Quality::ZERO, |(_, quality)| quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn accept(value: &str) -> Accept {
+        Accept(
+            value
+                .split(',')
+                .map(|v| v.trim().parse().unwrap())
+                .collect(),
+        )
+    }
+
+    fn png(alpha: u8) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([10, 120, 200, alpha]));
+        let mut result = Cursor::new(Vec::new());
+        image.write_to(&mut result, ImageOutputFormat::Png).unwrap();
+        result.into_inner()
+    }
+
+    #[test]
+    fn transcode_target() {
+        let transcoder = RasterTranscoder::new(&TranscodeConfig::default());
+        let target = |format, value| transcoder.target(format, &accept(value));
+        assert_eq!(
+            target(Format::Png, "image/jpeg, image/png;q=0.5"),
+            Some(Format::Jpeg)
+        );
+        assert_eq!(
+            target(Format::Png, "image/jpeg, image/*;q=0.8"),
+            Some(Format::Jpeg)
+        );
+        assert_eq!(
+            target(Format::Jpeg, "image/png, image/*;q=0.1"),
+            Some(Format::Png)
+        );
+        // the stored format is served if the client accepts it as much as the others
+        assert_eq!(target(Format::Png, "image/avif,image/webp,*/*"), None);
+        assert_eq!(target(Format::Png, "image/*"), None);
+        assert_eq!(target(Format::Webp, "image/jpeg, image/webp;q=0.5"), None);
+    }
+
+    #[actix_rt::test]
+    async fn transcode_tiles() {
+        let transcoder = RasterTranscoder::new(&TranscodeConfig {
+            sources: Some(vec!["ortho".to_string()]),
+            quality: Some(50),
+        });
+        let prefer_jpeg = accept("image/jpeg, image/png;q=0.5");
+        let response = |data| {
+            HttpResponse::Ok()
+                .content_type("image/png")
+                .insert_header((ETAG, "\"abc\""))
+                .body(data)
+        };
+
+        let resp = transcoder
+            .transcode_response(response(png(255)), "ortho", Some(&prefer_jpeg))
+            .unwrap();
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept");
+        assert!(resp.headers().get(ETAG).is_none());
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(image::guess_format(&body).unwrap(), ImageFormat::Jpeg);
+
+        // transparent tiles cannot be stored as JPEG
+        let resp = transcoder
+            .transcode_response(response(png(100)), "ortho", Some(&prefer_jpeg))
+            .unwrap();
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        assert!(resp.headers().get(ETAG).is_some());
+
+        let resp = transcoder
+            .transcode_response(response(png(255)), "roads", Some(&prefer_jpeg))
+            .unwrap();
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        assert!(resp.headers().get(VARY).is_none());
+    }
+}
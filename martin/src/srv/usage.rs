@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::{Data, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::{Deserialize, Serialize};
+
+use crate::srv::admin::authorize;
+use crate::srv::config::AdminConfig;
+use crate::srv::TileOptions;
+
+pub const USAGE_INTERVAL_SECS_DEFAULT: u64 = 60;
+pub const USAGE_RETENTION_DEFAULT: usize = 1440;
+
+/// Upper bounds of the latency histogram buckets in milliseconds. Slower requests are counted in an extra bucket.
+const LATENCY_BOUNDS_MS: [u64; 14] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000,
+];
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsageStatsConfig {
+    /// Length of each time period in seconds [default: 60]
+    pub interval_secs: Option<u64>,
+    /// Number of periods kept for each source, older ones are dropped [default: 1440]
+    pub retention: Option<usize>,
+}
+
+/// Requests, bytes, and latencies of one source during one time period
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct UsagePeriod {
+    /// Start of the period in seconds since the Unix epoch
+    start: u64,
+    requests: u64,
+    bytes: u64,
+    latencies: [u64; LATENCY_BOUNDS_MS.len() + 1],
+}
+
+impl UsagePeriod {
+    fn add(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+        for (a, b) in self.latencies.iter_mut().zip(other.latencies) {
+            *a += b;
+        }
+    }
+
+    /// Latency in milliseconds below which the given share of the requests were served,
+    /// rounded up to the histogram bucket. `None` if it is slower than the largest bucket.
+    fn percentile(&self, share: f64) -> Option<u64> {
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let rank = ((self.requests as f64) * share).ceil().max(1.0) as u64;
+        let mut count = 0;
+        for (idx, value) in self.latencies.iter().enumerate() {
+            count += value;
+            if count >= rank {
+                return LATENCY_BOUNDS_MS.get(idx).copied();
+            }
+        }
+        None
+    }
+
+    fn report(&self) -> UsageReport {
+        UsageReport {
+            start: None,
+            requests: self.requests,
+            bytes: self.bytes,
+            latency_ms: (self.requests > 0).then(|| LatencyPercentiles {
+                p50: self.percentile(0.5),
+                p90: self.percentile(0.9),
+                p99: self.percentile(0.99),
+            }),
+            history: None,
+        }
+    }
+}
+
+/// Counts the requests, the bytes served, and the latencies of each source over time,
+/// in a ring buffer of fixed-length periods for each source
+#[derive(Clone, Debug)]
+pub struct UsageStats {
+    interval: u64,
+    retention: usize,
+    sources: Arc<Mutex<HashMap<String, VecDeque<UsagePeriod>>>>,
+}
+
+impl UsageStats {
+    #[must_use]
+    pub fn new(config: &UsageStatsConfig) -> Self {
+        Self {
+            interval: config
+                .interval_secs
+                .unwrap_or(USAGE_INTERVAL_SECS_DEFAULT)
+                .max(1),
+            retention: config.retention.unwrap_or(USAGE_RETENTION_DEFAULT).max(1),
+            sources: Arc::default(),
+        }
+    }
+
+    /// Count a successful request of the comma-separated sources. Each source of a composite request
+    /// is counted with the size and the latency of the whole response.
+    pub fn record(&self, source_ids: &str, bytes: usize, latency: Duration) {
+        self.record_at(now_secs(), source_ids, bytes, latency);
+    }
+
+    fn record_at(&self, now: u64, source_ids: &str, bytes: usize, latency: Duration) {
+        let start = now - now % self.interval;
+        let latency_ms = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BOUNDS_MS.partition_point(|bound| *bound < latency_ms);
+        let mut sources = self.sources.lock().unwrap();
+        for id in source_ids.split(',') {
+            let periods = sources.entry(id.to_string()).or_default();
+            if periods.back().map_or(true, |v| v.start != start) {
+                periods.push_back(UsagePeriod {
+                    start,
+                    ..UsagePeriod::default()
+                });
+                if periods.len() > self.retention {
+                    periods.pop_front();
+                }
+            }
+            if let Some(period) = periods.back_mut() {
+                period.requests += 1;
+                period.bytes += bytes as u64;
+                period.latencies[bucket] += 1;
+            }
+        }
+    }
+
+    /// Totals of each source over the retention time, optionally with the counts of each period
+    #[must_use]
+    pub fn report(&self, source_ids: Option<&str>, history: bool) -> UsageStatsReport {
+        self.report_at(now_secs(), source_ids, history)
+    }
+
+    fn report_at(&self, now: u64, source_ids: Option<&str>, history: bool) -> UsageStatsReport {
+        let current = now - now % self.interval;
+        let oldest = current.saturating_sub(self.interval * (self.retention as u64 - 1));
+        let filter: Option<Vec<&str>> = source_ids.map(|v| v.split(',').collect());
+        let sources = self
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| filter.as_ref().map_or(true, |v| v.contains(&id.as_str())))
+            .filter_map(|(id, periods)| {
+                let periods: Vec<_> = periods.iter().filter(|v| v.start >= oldest).collect();
+                if periods.is_empty() {
+                    return None;
+                }
+                let mut total = UsagePeriod::default();
+                for period in &periods {
+                    total.add(period);
+                }
+                let mut report = total.report();
+                if history {
+                    report.history = Some(
+                        periods
+                            .iter()
+                            .map(|v| UsageReport {
+                                start: Some(v.start),
+                                ..v.report()
+                            })
+                            .collect(),
+                    );
+                }
+                Some((id.clone(), report))
+            })
+            .collect();
+        UsageStatsReport {
+            interval_secs: self.interval,
+            sources,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageStatsReport {
+    pub interval_secs: u64,
+    pub sources: BTreeMap<String, UsageReport>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsageReport {
+    /// Start of the period in seconds since the Unix epoch, only set for the history
+    pub start: Option<u64>,
+    pub requests: u64,
+    pub bytes: u64,
+    pub latency_ms: Option<LatencyPercentiles>,
+    pub history: Option<Vec<UsageReport>>,
+}
+
+/// Latencies in milliseconds, rounded up to the histogram buckets. `null` if slower than 30 seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: Option<u64>,
+    pub p90: Option<u64>,
+    pub p99: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsRequest {
+    /// Comma-separated source IDs to report, all sources by default
+    source: Option<String>,
+    /// Include the counts of each period
+    #[serde(default)]
+    history: bool,
+}
+
+/// Report the usage of each source, e.g. to find the layers that are no longer used
+#[route("/_/stats", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_usage_stats(
+    req: HttpRequest,
+    admin: Option<Data<AdminConfig>>,
+    options: Data<TileOptions>,
+    params: Query<StatsRequest>,
+) -> ActixResult<HttpResponse> {
+    authorize(&req, admin.as_ref().map(Data::get_ref))?;
+    let Some(usage) = &options.usage else {
+        return Err(ErrorNotFound(
+            "Usage statistics are disabled, set usage_stats in the config",
+        ));
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(usage.report(params.source.as_deref(), params.history)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_stats() {
+        let stats = UsageStats::new(&UsageStatsConfig {
+            interval_secs: Some(60),
+            retention: Some(2),
+        });
+        let ms = Duration::from_millis;
+        for latency in [1, 3, 3, 40] {
+            stats.record_at(1000, "roads", 100, ms(latency));
+        }
+        stats.record_at(1010, "roads,water", 50, ms(250));
+        stats.record_at(1070, "roads", 10, ms(60_000));
+
+        let report = stats.report_at(1070, None, false);
+        assert_eq!(report.interval_secs, 60);
+        let roads = &report.sources["roads"];
+        assert_eq!((roads.requests, roads.bytes), (6, 460));
+        assert_eq!(
+            roads.latency_ms,
+            Some(LatencyPercentiles {
+                p50: Some(5),
+                p90: None,
+                p99: None,
+            })
+        );
+        assert!(roads.history.is_none());
+        assert_eq!(report.sources["water"].requests, 1);
+
+        let report = stats.report_at(1070, Some("roads"), true);
+        assert_eq!(report.sources.keys().collect::<Vec<_>>(), vec!["roads"]);
+        let history = report.sources["roads"].history.as_ref().unwrap();
+        assert_eq!(
+            history.iter().map(|v| v.start).collect::<Vec<_>>(),
+            vec![Some(960), Some(1020)]
+        );
+        assert_eq!(history[0].requests, 5);
+
+        // only the periods within the retention time are reported
+        stats.record_at(1150, "roads", 10, ms(1));
+        let report = stats.report_at(1150, None, true);
+        assert_eq!(report.sources["roads"].requests, 1);
+        assert!(!report.sources.contains_key("water"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use actix_web::web::{Data, Path};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use arc_swap::ArcSwap;
+use prost::Message as _;
+use serde_json::{json, Map, Value};
+
+use crate::source::TileSources;
+use crate::srv::geojson::{get_converted_tile, TileConversion};
+use crate::srv::server::redirect_sources;
+use crate::srv::{SourceRedirects, TileOptions, TileRequest};
+use crate::utils::mvt::{decode_geometry, GeomType, Tile, TileGeometry, DEFAULT_MVT_EXTENT};
+
+/// Number of grid cells along each side of a tile, i.e. one cell per 4 pixels of a 256 pixel tile
+const GRID_SIZE: usize = 64;
+/// Radius of the points in grid cells, so that they can be hovered like a small marker
+const POINT_RADIUS: f64 = 2.0;
+/// Half of the width of the lines in grid cells
+const LINE_HALF_WIDTH: f64 = 0.75;
+
+/// Serve a [UTFGrid](https://github.com/mapbox/utfgrid-spec) of a vector tile, for the legacy clients
+/// that use the grids for hover interactivity. Only the sources with `UTFGrid` enabled are supported.
+/// The grids are stored in the tile cache, and purged together with their sources.
+#[route(
+    "/{source_ids}/{z}/{x}/{y}.grid.json",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+async fn get_utfgrid_tile(
+    req: HttpRequest,
+    path: Path<TileRequest>,
+    sources: Data<ArcSwap<TileSources>>,
+    options: Data<TileOptions>,
+    redirects: Option<Data<SourceRedirects>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.load_full();
+    if let Some(resp) = redirect_sources(&req, &path.source_ids, &sources, redirects, 3) {
+        return Ok(resp);
+    }
+    get_converted_tile(&req, &path, &sources, &options, TileConversion::UtfGrid).await
+}
+
+/// Convert an uncompressed vector tile into a `UTFGrid`. Each grid cell references the feature drawn
+/// on top of it, i.e. the last one in the tile. The key of a feature is its ID, or its layer name and
+/// its index in the layer if it has no ID.
+pub fn tile_to_utfgrid(data: &[u8]) -> Result<Value, prost::DecodeError> {
+    let tile = Tile::decode(data)?;
+    // 0 is the empty key, and the features are numbered from 1 in the order they are drawn
+    let mut grid = vec![0_usize; GRID_SIZE * GRID_SIZE];
+    let mut features = Vec::new();
+    for layer in &tile.layers {
+        // the size of a grid cell in the layer coordinates
+        #[allow(clippy::cast_precision_loss)]
+        let cell = f64::from(layer.extent.unwrap_or(DEFAULT_MVT_EXTENT).max(1)) / GRID_SIZE as f64;
+        for (idx, feature) in layer.features.iter().enumerate() {
+            let Some(geom) = feature
+                .r#type
+                .and_then(|v| GeomType::try_from(v).ok())
+                .and_then(|t| decode_geometry(t, &feature.geometry))
+            else {
+                continue;
+            };
+            features.push((layer, idx, feature));
+            rasterize(&mut grid, &geom, cell, features.len());
+        }
+    }
+
+    // only the features that are visible in the grid are listed, in the order they are drawn
+    let mut visible = vec![false; features.len() + 1];
+    for value in &grid {
+        visible[*value] = true;
+    }
+    let mut keys = vec![String::new()];
+    let mut key_index = vec![0_usize; features.len() + 1];
+    let mut positions = HashMap::new();
+    let mut data = Map::new();
+    for (value, (layer, idx, feature)) in features.into_iter().enumerate() {
+        if !visible[value + 1] {
+            continue;
+        }
+        let key = match feature.id {
+            Some(id) => id.to_string(),
+            None => format!("{}:{idx}", layer.name),
+        };
+        // features with the same ID, e.g. the label anchors of a polygon, share their key
+        key_index[value + 1] = *positions.entry(key.clone()).or_insert_with(|| {
+            let properties = feature
+                .properties(layer)
+                .map(|(k, v)| (k.to_string(), v.to_json().unwrap_or_default()))
+                .collect();
+            data.insert(key.clone(), Value::Object(properties));
+            keys.push(key);
+            keys.len() - 1
+        });
+    }
+
+    let rows: Vec<String> = grid
+        .chunks_exact(GRID_SIZE)
+        .map(|row| row.iter().map(|v| encode_id(key_index[*v])).collect())
+        .collect();
+    Ok(json!({"grid": rows, "keys": keys, "data": data}))
+}
+
+/// Encode a key index as a grid character, skipping `"` and `\` as required by the `UTFGrid` spec
+fn encode_id(id: usize) -> char {
+    let mut code = u32::try_from(id).unwrap_or(u32::MAX).saturating_add(32);
+    if code >= 34 {
+        code += 1;
+    }
+    if code >= 92 {
+        code += 1;
+    }
+    char::from_u32(code).unwrap_or(' ')
+}
+
+/// Set all grid cells covered by the geometry to the given value
+fn rasterize(grid: &mut [usize], geom: &TileGeometry, cell: f64, value: usize) {
+    let to_grid = |p: &[i32; 2]| [f64::from(p[0]) / cell, f64::from(p[1]) / cell];
+    match geom {
+        TileGeometry::Points(points) => {
+            for point in points {
+                let center = to_grid(point);
+                fill_cells(grid, [center, center], POINT_RADIUS, value, |p| {
+                    distance(p, center) <= POINT_RADIUS
+                });
+            }
+        }
+        TileGeometry::Lines(lines) => {
+            for line in lines {
+                let line: Vec<_> = line.iter().map(to_grid).collect();
+                fill_cells(grid, bbox(&line), LINE_HALF_WIDTH, value, |p| {
+                    line.windows(2)
+                        .any(|s| segment_distance(p, s[0], s[1]) <= LINE_HALF_WIDTH)
+                });
+            }
+        }
+        TileGeometry::Polygons(polygons) => {
+            for polygon in polygons {
+                let rings: Vec<Vec<_>> = polygon
+                    .iter()
+                    .map(|ring| ring.iter().map(to_grid).collect())
+                    .collect();
+                let Some(exterior) = rings.first() else {
+                    continue;
+                };
+                fill_cells(grid, bbox(exterior), 0.0, value, |p| {
+                    // the holes are excluded by the even-odd rule
+                    rings.iter().filter(|ring| contains(ring, p)).count() % 2 == 1
+                });
+            }
+        }
+    }
+}
+
+/// Set the cells within the bounding box and the margin whose center matches the predicate
+fn fill_cells(
+    grid: &mut [usize],
+    [min, max]: [[f64; 2]; 2],
+    margin: f64,
+    value: usize,
+    matches: impl Fn([f64; 2]) -> bool,
+) {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let range = |min: f64, max: f64| {
+        let first = (min - margin - 0.5).ceil().max(0.0) as usize;
+        let last = ((max + margin - 0.5).floor().max(-1.0) + 1.0) as usize;
+        first..last.min(GRID_SIZE)
+    };
+    for row in range(min[1], max[1]) {
+        for col in range(min[0], max[0]) {
+            #[allow(clippy::cast_precision_loss)]
+            let center = [col as f64 + 0.5, row as f64 + 0.5];
+            if matches(center) {
+                grid[row * GRID_SIZE + col] = value;
+            }
+        }
+    }
+}
+
+fn bbox(points: &[[f64; 2]]) -> [[f64; 2]; 2] {
+    points.iter().fold(
+        [[f64::INFINITY; 2], [f64::NEG_INFINITY; 2]],
+        |[min, max], p| {
+            [
+                [min[0].min(p[0]), min[1].min(p[1])],
+                [max[0].max(p[0]), max[1].max(p[1])],
+            ]
+        },
+    )
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    (a[0] - b[0]).hypot(a[1] - b[1])
+}
+
+fn segment_distance(p: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let len2 = dx * dx + dy * dy;
+    if len2 == 0.0 {
+        return distance(p, a);
+    }
+    let t = (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / len2).clamp(0.0, 1.0);
+    distance(p, [a[0] + t * dx, a[1] + t * dy])
+}
+
+/// Check if a point is inside a ring, using ray casting
+fn contains(ring: &[[f64; 2]], p: [f64; 2]) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a[1] > p[1]) != (b[1] > p[1])
+            && p[0] < (b[0] - a[0]) * (p[1] - a[1]) / (b[1] - a[1]) + a[0]
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::mvt::{encode_tile, LayerBuilder, PropValue};
+
+    #[test]
+    fn convert_tile() {
+        let mut layer = LayerBuilder::new("parcels", 4096);
+        let props = |name: &str| [("name".to_string(), PropValue::String(name.to_string()))];
+        // the left half of the tile, with a hole in its top left quarter
+        let half = vec![[0, 0], [2048, 0], [2048, 4096], [0, 4096], [0, 0]];
+        let hole = vec![[0, 0], [0, 1024], [1024, 1024], [1024, 0], [0, 0]];
+        let polygon = TileGeometry::Polygons(vec![vec![half, hole]]);
+        layer.add_feature(Some(7), &polygon, &props("a"));
+        // a point drawn on top of the polygon, and a feature without an ID
+        layer.add_feature(
+            Some(8),
+            &TileGeometry::Points(vec![[1536, 3072]]),
+            &props("b"),
+        );
+        let line = TileGeometry::Lines(vec![vec![[3072, 0], [3072, 4096]]]);
+        layer.add_feature(None, &line, &[]);
+        let data = encode_tile(vec![layer.build()]);
+
+        let grid = tile_to_utfgrid(&data).unwrap();
+        assert_eq!(grid["keys"], json!(["", "7", "8", "parcels:2"]));
+        assert_eq!(
+            grid["data"],
+            json!({"7": {"name": "a"}, "8": {"name": "b"}, "parcels:2": {}})
+        );
+        let rows: Vec<Vec<char>> = grid["grid"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap().chars().collect())
+            .collect();
+        assert_eq!(rows.len(), GRID_SIZE);
+        assert!(rows.iter().all(|v| v.len() == GRID_SIZE));
+        assert_eq!(rows[8][8], ' ', "the hole is empty");
+        assert_eq!(rows[20][20], '!');
+        assert_eq!(rows[40][10], '!', "inside the polygon");
+        assert_eq!(rows[48][24], '#', "the point is drawn on top");
+        assert_eq!(rows[10][48], '$', "on the line");
+        assert_eq!(rows[10][40], ' ');
+
+        let empty = tile_to_utfgrid(&[]).unwrap();
+        assert_eq!(empty["keys"], json!([""]));
+        assert!(empty["grid"][0].as_str().unwrap().chars().all(|v| v == ' '));
+    }
+
+    #[test]
+    fn grid_characters() {
+        assert_eq!(encode_id(0), ' ');
+        assert_eq!(encode_id(1), '!');
+        assert_eq!(encode_id(2), '#');
+        assert_eq!(encode_id(58), '[');
+        assert_eq!(encode_id(59), ']');
+    }
+}
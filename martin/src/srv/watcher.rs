@@ -0,0 +1,182 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::timeout;
+
+use crate::srv::Scheduler;
+
+/// Time without any file changes to wait before discovering the sources again.
+/// A file is usually written in many chunks, and each of them causes a change event.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Extensions of all files that can be published as file sources
+const SOURCE_EXTENSIONS: &[&str] = &[
+    "pmtiles", "mbtiles", "tif", "gpkg", "parquet", "pbf", "shp", "sqlite",
+];
+
+/// Extensions of the font files
+const FONT_EXTENSIONS: &[&str] = &["otf", "ttf", "ttc"];
+
+/// Watch the directories of the file sources, and discover the sources again whenever
+/// a source file is added, removed, or modified. The tiles of the modified files are purged from the cache.
+/// The sprite directories are watched too, and the sprites are reloaded whenever an SVG file changes.
+/// The same goes for the font directories and their font files.
+/// Does nothing if there are no directories to watch, or if the watcher cannot be created.
+pub fn start_watcher(scheduler: Scheduler) {
+    let source_dirs = scheduler.discovery.get_file_directories();
+    let sprite_dirs = scheduler.sprites.get_directories();
+    let font_dirs = scheduler.fonts.load().get_directories();
+    if source_dirs.is_empty() && sprite_dirs.is_empty() && font_dirs.is_empty() {
+        warn!(
+            "File watching is enabled, but no source, sprite, or font directories are configured"
+        );
+        return;
+    }
+
+    let (tx, rx) = unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            let _ = tx.send(event);
+        }
+        Err(e) => warn!("File watcher error: {e}"),
+    });
+    let mut watcher = match watcher {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Unable to watch the source directories: {e}");
+            return;
+        }
+    };
+    let source_dirs = source_dirs
+        .into_iter()
+        .map(|v| (v, RecursiveMode::NonRecursive, "source file"));
+    // Sprite images are also loaded from the subdirectories
+    let sprite_dirs = sprite_dirs
+        .into_iter()
+        .map(|v| (v, RecursiveMode::Recursive, "sprite"));
+    let font_dirs = font_dirs
+        .into_iter()
+        .map(|v| (v, RecursiveMode::Recursive, "font"));
+    for (dir, mode, kind) in source_dirs.chain(sprite_dirs).chain(font_dirs) {
+        // Event paths are compared with the canonical paths of the sources
+        let dir = dir.canonicalize().unwrap_or(dir);
+        match watcher.watch(&dir, mode) {
+            Ok(()) => info!("Watching {} for {kind} changes", dir.display()),
+            Err(e) => warn!("Unable to watch {}: {e}", dir.display()),
+        }
+    }
+
+    actix_rt::spawn(run(scheduler, watcher, rx));
+}
+
+/// Files changed since the last reload
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    source_files: BTreeSet<PathBuf>,
+    sprites: bool,
+    fonts: bool,
+}
+
+impl Changes {
+    fn add(&mut self, event: Event) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            return;
+        }
+        for path in event.paths {
+            if is_source_file(&path) {
+                self.source_files.insert(path);
+            } else if has_extension(&path, &["svg"]) {
+                self.sprites = true;
+            } else if has_extension(&path, FONT_EXTENSIONS) {
+                self.fonts = true;
+            }
+        }
+    }
+}
+
+/// Keeps the watcher alive while handling its events
+async fn run(scheduler: Scheduler, _watcher: RecommendedWatcher, mut rx: UnboundedReceiver<Event>) {
+    while let Some(event) = rx.recv().await {
+        let mut changes = Changes::default();
+        changes.add(event);
+        loop {
+            match timeout(SETTLE_TIME, rx.recv()).await {
+                Ok(Some(event)) => changes.add(event),
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        if !changes.source_files.is_empty() {
+            let files: Vec<_> = changes.source_files.into_iter().collect();
+            debug!("Source files changed: {files:?}");
+            if let Err(e) = scheduler.rediscover_files(&files).await {
+                warn!("Source rediscovery failed, keeping the current sources: {e}");
+            }
+        }
+        if changes.sprites {
+            if let Err(e) = scheduler.reload_sprites() {
+                warn!("Unable to reload the sprites: {e}");
+            }
+        }
+        if changes.fonts {
+            if let Err(e) = scheduler.reload_fonts() {
+                warn!("Unable to reload the fonts, keeping the current fonts: {e}");
+            }
+        }
+    }
+}
+
+fn is_source_file(path: &Path) -> bool {
+    has_extension(path, SOURCE_EXTENSIONS)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|v| v.to_str())
+        .map_or(false, |ext| extensions.contains(&ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{AccessKind, CreateKind, RemoveKind};
+
+    use super::*;
+
+    #[test]
+    fn changed_files() {
+        let mut changes = Changes::default();
+        let event = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/dir/a.pmtiles"))
+            .add_path(PathBuf::from("/dir/a.mbtiles-journal"))
+            .add_path(PathBuf::from("/dir/a.pmtiles.tmp"));
+        changes.add(event);
+        let event = Event::new(EventKind::Access(AccessKind::Any))
+            .add_path(PathBuf::from("/dir/b.mbtiles"))
+            .add_path(PathBuf::from("/icons/b.svg"));
+        changes.add(event);
+        assert_eq!(
+            changes.source_files,
+            BTreeSet::from([PathBuf::from("/dir/a.pmtiles")])
+        );
+        assert!(!changes.sprites);
+        assert!(!changes.fonts);
+
+        let event = Event::new(EventKind::Remove(RemoveKind::File))
+            .add_path(PathBuf::from("/icons/sub/c.svg"));
+        changes.add(event);
+        assert!(changes.sprites);
+        assert!(!changes.fonts);
+
+        let event = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/fonts/noto/NotoSans-Bold.ttf"));
+        changes.add(event);
+        assert!(changes.fonts);
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::srv::Catalog;
+use crate::MartinError::InvalidWebhookConfig;
+use crate::MartinResult;
+
+pub const WEBHOOK_TIMEOUT_SECS_DEFAULT: u64 = 10;
+/// Number of times each event is sent to a webhook before it is dropped
+const WEBHOOK_ATTEMPTS: u32 = 3;
+/// Delay before the second attempt, doubled for every further attempt
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// URLs the catalog change events are posted to
+    pub urls: Vec<String>,
+    /// Headers added to every request, e.g. `Authorization`
+    pub headers: Option<BTreeMap<String, String>>,
+    /// Maximum time in seconds to wait for each webhook to respond [default: 10]
+    pub timeout_secs: Option<u64>,
+}
+
+impl WebhookConfig {
+    fn client(&self) -> MartinResult<Client> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter().flatten() {
+            let name = HeaderName::try_from(name)
+                .map_err(|e| InvalidWebhookConfig(format!("header {name}: {e}")))?;
+            let value = HeaderValue::try_from(value)
+                .map_err(|e| InvalidWebhookConfig(format!("value of header {name}: {e}")))?;
+            headers.insert(name, value);
+        }
+        for url in &self.urls {
+            reqwest::Url::parse(url).map_err(|e| InvalidWebhookConfig(format!("{url}: {e}")))?;
+        }
+        Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(
+                self.timeout_secs.unwrap_or(WEBHOOK_TIMEOUT_SECS_DEFAULT),
+            ))
+            .build()
+            .map_err(|e| InvalidWebhookConfig(e.to_string()))
+    }
+
+    /// Make sure the URLs and the headers are valid
+    pub fn finalize(&self) -> MartinResult<()> {
+        self.client().map(|_| ())
+    }
+}
+
+/// Body of the requests sent to the webhooks, listing the sources of one catalog change
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CatalogEvent {
+    /// Version of the catalog after the change, as used by `/_/catalog/changes`
+    pub version: u64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Sources whose data has changed, so that their cached tiles are outdated
+    pub refreshed: Vec<String>,
+}
+
+impl CatalogEvent {
+    /// Create an event without any sources for the current version of the catalog
+    #[must_use]
+    pub fn new(catalog: &Catalog) -> Self {
+        Self {
+            version: catalog.changes.version(),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.refreshed.is_empty()
+    }
+}
+
+/// Posts the catalog change events to the configured webhooks in the background,
+/// one event at a time so that they arrive in order. Failed requests are retried a few times,
+/// after which the event is dropped for that webhook.
+#[derive(Clone, Debug, Default)]
+pub struct Webhooks(Option<UnboundedSender<CatalogEvent>>);
+
+impl Webhooks {
+    /// Start sending events if any webhook is configured. Must be called from within an actix runtime.
+    pub fn new(config: Option<&WebhookConfig>) -> MartinResult<Self> {
+        let Some(config) = config.filter(|v| !v.urls.is_empty()) else {
+            return Ok(Self::default());
+        };
+        let client = config.client()?;
+        let (sender, receiver) = unbounded_channel();
+        actix_rt::spawn(deliver(client, config.urls.clone(), receiver));
+        Ok(Self(Some(sender)))
+    }
+
+    /// Queue an event for all webhooks, unless it has no changes
+    pub fn notify(&self, event: CatalogEvent) {
+        if let Some(sender) = &self.0 {
+            if !event.is_empty() {
+                let _ = sender.send(event);
+            }
+        }
+    }
+}
+
+async fn deliver(client: Client, urls: Vec<String>, mut events: UnboundedReceiver<CatalogEvent>) {
+    while let Some(event) = events.recv().await {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Unable to serialize catalog version {}: {e}", event.version);
+                continue;
+            }
+        };
+        for url in &urls {
+            let mut delay = WEBHOOK_RETRY_DELAY;
+            for attempt in 1..=WEBHOOK_ATTEMPTS {
+                let result = client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+                match result {
+                    Ok(_) => {
+                        debug!("Sent catalog version {} to {url}", event.version);
+                        break;
+                    }
+                    Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                        debug!("Webhook {url} failed, retrying in {delay:?}: {e}");
+                        actix_rt::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                    Err(e) => warn!(
+                        "Dropping catalog version {} for webhook {url} after {WEBHOOK_ATTEMPTS} attempts: {e}",
+                        event.version
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_config() {
+        let cfg = |url: &str, header: &str| WebhookConfig {
+            urls: vec![url.to_string()],
+            headers: Some(BTreeMap::from([(header.to_string(), "v".to_string())])),
+            timeout_secs: None,
+        };
+        assert!(cfg("https://example.com/hook", "Authorization")
+            .finalize()
+            .is_ok());
+        assert!(cfg("example.com/hook", "Authorization").finalize().is_err());
+        assert!(cfg("https://example.com/hook", "bad header")
+            .finalize()
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn delivery() {
+        use std::io::{Read as _, Write as _};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with(b"}") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let webhooks = Webhooks::new(Some(&WebhookConfig {
+            urls: vec![url],
+            headers: Some(BTreeMap::from([(
+                "Authorization".to_string(),
+                "Bearer secret".to_string(),
+            )])),
+            timeout_secs: None,
+        }))
+        .unwrap();
+        webhooks.notify(CatalogEvent::default());
+        webhooks.notify(CatalogEvent {
+            version: 2,
+            added: vec!["new".to_string()],
+            ..CatalogEvent::default()
+        });
+
+        let request = actix_rt::task::spawn_blocking(move || server.join().unwrap())
+            .await
+            .unwrap();
+        assert!(request.starts_with("POST /hook "));
+        assert!(request.contains("authorization: Bearer secret"));
+        assert!(request.ends_with(r#"{"version":2,"added":["new"],"removed":[],"refreshed":[]}"#));
+    }
+
+    #[actix_rt::test]
+    async fn disabled() {
+        let webhooks = Webhooks::new(Some(&WebhookConfig::default())).unwrap();
+        assert!(webhooks.0.is_none());
+        webhooks.notify(CatalogEvent {
+            added: vec!["src".to_string()],
+            ..CatalogEvent::default()
+        });
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::file_config::{FileConfigEnum, FileError, FileResult};
+
+#[derive(thiserror::Error, Debug)]
+pub enum StyleError {
+    #[error("Style {0} not found")]
+    StyleNotFound(String),
+
+    #[error("IO error {0}: {}", .1.display())]
+    IoError(std::io::Error, PathBuf),
+
+    #[error("Style {} is not a valid JSON object: {0}", .1.display())]
+    InvalidStyle(String, PathBuf),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CatalogStyleEntry {
+    pub path: PathBuf,
+}
+
+pub type StyleCatalog = BTreeMap<String, CatalogStyleEntry>;
+
+/// `MapLibre` style files, keyed by style ID
+#[derive(Debug, Clone, Default)]
+pub struct StyleSources(HashMap<String, PathBuf>);
+
+impl StyleSources {
+    pub fn resolve(config: &mut FileConfigEnum) -> FileResult<Self> {
+        let Some(cfg) = config.extract_file_config() else {
+            return Ok(Self::default());
+        };
+
+        let mut results = Self::default();
+        let mut paths = Vec::new();
+        let mut configs = BTreeMap::new();
+
+        if let Some(sources) = cfg.sources {
+            for (id, source) in sources {
+                configs.insert(id.clone(), source.clone());
+                results.add_source(id, source.abs_path()?);
+            }
+        }
+
+        for path in cfg.paths {
+            paths.push(path.clone());
+            if path.is_dir() {
+                let entries = path
+                    .read_dir()
+                    .map_err(|e| FileError::IoError(e, path.clone()))?;
+                let mut files: Vec<_> = entries
+                    .flatten()
+                    .map(|v| v.path())
+                    .filter(|v| v.is_file() && v.extension() == Some(OsStr::new("json")))
+                    .collect();
+                files.sort();
+                for file in files {
+                    results.add_file(file);
+                }
+            } else if path.is_file() {
+                results.add_file(path);
+            } else {
+                return Err(FileError::InvalidFilePath(path));
+            }
+        }
+
+        *config = FileConfigEnum::new_extended(paths, configs, cfg.unrecognized);
+
+        Ok(results)
+    }
+
+    #[must_use]
+    pub fn get_catalog(&self) -> StyleCatalog {
+        self.0
+            .iter()
+            .map(|(id, path)| (id.clone(), CatalogStyleEntry { path: path.clone() }))
+            .collect()
+    }
+
+    /// Read the style from its file, and point its relative source, sprite, and glyph URLs
+    /// to the given base URL of this server. The file is read on every call, so that changes are visible right away.
+    pub async fn get_style(&self, id: &str, base_url: &str) -> Result<Value, StyleError> {
+        let path = self
+            .0
+            .get(id)
+            .ok_or_else(|| StyleError::StyleNotFound(id.to_string()))?;
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| StyleError::IoError(e, path.clone()))?;
+        let mut style: Value = serde_json::from_slice(&data)
+            .map_err(|e| StyleError::InvalidStyle(e.to_string(), path.clone()))?;
+        if !style.is_object() {
+            let msg = "expected a JSON object".to_string();
+            return Err(StyleError::InvalidStyle(msg, path.clone()));
+        }
+        rewrite_urls(&mut style, base_url);
+        Ok(style)
+    }
+
+    /// Use the file name without the extension as the style ID
+    fn add_file(&mut self, path: PathBuf) {
+        let Some(id) = path.file_stem() else {
+            warn!("Ignoring style with no name from {}", path.display());
+            return;
+        };
+        self.add_source(id.to_string_lossy().to_string(), path);
+    }
+
+    fn add_source(&mut self, id: String, path: PathBuf) {
+        let disp_path = path.display();
+        if path.is_dir() {
+            warn!("Ignoring style {id} from {disp_path} because it is a directory");
+            return;
+        }
+        match self.0.entry(id) {
+            Entry::Occupied(v) => {
+                warn!("Ignoring duplicate style {} from {disp_path} because it was already configured for {}",
+                    v.key(), v.get().display());
+            }
+            Entry::Vacant(v) => {
+                info!("Configured style {} from {disp_path}", v.key());
+                v.insert(path);
+            }
+        }
+    }
+}
+
+/// Point the relative URLs of the sources, the sprites, and the glyphs to the base URL.
+/// Absolute URLs, e.g. the ones of other tile servers, are kept.
+fn rewrite_urls(style: &mut Value, base_url: &str) {
+    let Some(style) = style.as_object_mut() else {
+        return;
+    };
+    let mut urls: Vec<&mut Value> = Vec::new();
+    for (key, value) in style.iter_mut() {
+        match (key.as_str(), value) {
+            ("sources", Value::Object(sources)) => {
+                for source in sources.values_mut().filter_map(Value::as_object_mut) {
+                    for (key, value) in source.iter_mut() {
+                        match (key.as_str(), value) {
+                            ("tiles", Value::Array(tiles)) => urls.extend(tiles.iter_mut()),
+                            ("url" | "data", value) => urls.push(value),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            // `sprite` is either a single URL, or a list of objects with an `id` and a `url`
+            ("sprite", Value::Array(items)) => {
+                urls.extend(items.iter_mut().filter_map(|v| v.get_mut("url")));
+            }
+            ("sprite" | "glyphs", value) => urls.push(value),
+            _ => {}
+        }
+    }
+    for url in urls {
+        if let Value::String(url) = url {
+            if !url.contains("://") {
+                *url = format!("{base_url}/{}", url.trim_start_matches('/'));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn rewrite_style_urls() {
+        let mut style = json!({
+            "version": 8,
+            "sources": {
+                "cities": {"type": "vector", "url": "/world_cities"},
+                "roads": {"type": "vector", "tiles": ["roads/{z}/{x}/{y}", "https://example.org/{z}/{x}/{y}"]},
+                "points": {"type": "geojson", "data": {"type": "FeatureCollection", "features": []}},
+                "osm": {"type": "raster", "url": "https://example.org/osm.json"}
+            },
+            "sprite": [{"id": "default", "url": "/sprite/src1"}],
+            "glyphs": "/font/{fontstack}/{range}",
+            "layers": []
+        });
+        rewrite_urls(&mut style, "https://example.com/tiles");
+        assert_eq!(
+            style["sources"]["cities"]["url"],
+            "https://example.com/tiles/world_cities"
+        );
+        assert_eq!(
+            style["sources"]["roads"]["tiles"],
+            json!([
+                "https://example.com/tiles/roads/{z}/{x}/{y}",
+                "https://example.org/{z}/{x}/{y}"
+            ])
+        );
+        assert!(style["sources"]["points"]["data"].is_object());
+        assert!(style["sources"]["points"].get("url").is_none());
+        assert_eq!(
+            style["sources"]["osm"]["url"],
+            "https://example.org/osm.json"
+        );
+        assert_eq!(
+            style["sprite"][0]["url"],
+            "https://example.com/tiles/sprite/src1"
+        );
+        assert_eq!(
+            style["glyphs"],
+            "https://example.com/tiles/font/{fontstack}/{range}"
+        );
+
+        let mut style = json!({"version": 8, "sprite": "/sprite/src1"});
+        rewrite_urls(&mut style, "http://localhost:3000");
+        assert_eq!(style["sprite"], "http://localhost:3000/sprite/src1");
+        assert!(style.get("glyphs").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn style_files() {
+        let mut cfg = FileConfigEnum::new(vec![PathBuf::from("../tests/fixtures/styles")]);
+        let styles = StyleSources::resolve(&mut cfg).unwrap();
+        assert_eq!(
+            styles.get_catalog().keys().collect::<Vec<_>>(),
+            vec!["maplibre_demo"]
+        );
+        let style = styles
+            .get_style("maplibre_demo", "http://localhost:3000")
+            .await
+            .unwrap();
+        assert_eq!(style["sprite"], "http://localhost:3000/sprite/src1");
+        assert!(matches!(
+            styles.get_style("missing", "").await,
+            Err(StyleError::StyleNotFound(_))
+        ));
+    }
+}
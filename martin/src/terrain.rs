@@ -0,0 +1,582 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use async_trait::async_trait;
+use image::{ImageFormat, ImageOutputFormat, Rgb, Rgba};
+use log::info;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::colortype::Gray32Float;
+use tiff::encoder::compression::Deflate;
+use tiff::encoder::TiffEncoder;
+use tiff::tags::Tag;
+use tilejson::TileJSON;
+
+use crate::source::{PoolStatus, Source, TileData, TileInfoSources, UrlQuery};
+use crate::utils::mvt::tile_bbox;
+use crate::{MartinResult, TileCoord};
+
+/// Query parameter selecting the terrain encoding of the returned tiles, e.g. `?terrain=mapbox`
+pub const TERRAIN_QUERY_PARAM: &str = "terrain";
+
+/// `GeoTIFF` keys of a projected raster in Web Mercator (EPSG:3857), with the pixels as areas
+const GEO_KEYS: [u16; 16] = [
+    1, 1, 0, 3, // version 1.1.0 with 3 keys
+    1024, 0, 1, 1, // GTModelTypeGeoKey: projected
+    1025, 0, 1, 1, // GTRasterTypeGeoKey: pixel is area
+    3072, 0, 1, 3857, // ProjectedCSTypeGeoKey: EPSG:3857
+];
+
+pub type TerrainResult<T> = Result<T, TerrainError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TerrainError {
+    #[error("Terrain encoding is configured for source {0}, but there is no such source")]
+    UnknownSource(String),
+
+    #[error("Terrain encodings are only supported for PNG tiles, but source {1} has {0} tiles")]
+    UnsupportedFormat(TileInfo, String),
+
+    #[error("Terrain tiles of source {0} cannot be stored as GeoTIFF, use terrarium or mapbox")]
+    UnsupportedSourceEncoding(String),
+
+    #[error("Terrain encoding {0} cannot be requested from source {1}, which serves {2} tiles")]
+    InvalidEncoding(String, String, TerrainEncoding),
+
+    #[error("Unable to convert terrain tile {1} of source {2}: {0}")]
+    ImageError(image::ImageError, TileCoord, String),
+
+    #[error("Unable to write terrain tile {1} of source {2} as GeoTIFF: {0}")]
+    TiffError(tiff::TiffError, TileCoord, String),
+
+    #[error("Terrain tile {0} of source {1} is not a single band 32-bit float GeoTIFF")]
+    InvalidTiff(TileCoord, String),
+}
+
+/// How the heights are stored in the tiles of a raster DEM source
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TerrainEncoding {
+    /// RGB PNG with `height = R * 256 + G + B / 256 - 32768`
+    Terrarium,
+    /// RGB PNG with `height = -10000 + (R * 65536 + G * 256 + B) * 0.1`, also known as Terrain-RGB
+    Mapbox,
+    /// Single band 32-bit float `GeoTIFF` in Web Mercator, with the heights in meters
+    Geotiff,
+}
+
+impl TerrainEncoding {
+    #[must_use]
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value.to_ascii_lowercase().as_str() {
+            "terrarium" => Self::Terrarium,
+            "mapbox" | "terrain-rgb" => Self::Mapbox,
+            "geotiff" | "tiff" => Self::Geotiff,
+            _ => None?,
+        })
+    }
+
+    /// Height in meters of an encoded RGB pixel
+    fn decode(self, pixel: Rgb<u8>) -> f64 {
+        let [r, g, b] = pixel.0.map(f64::from);
+        // GeoTIFF tiles are never stored, so they are never decoded from RGB
+        match self {
+            Self::Mapbox => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+            Self::Terrarium | Self::Geotiff => r * 256.0 + g + b / 256.0 - 32768.0,
+        }
+    }
+
+    /// Encode the height in meters as an RGB pixel, clamped to the range of the encoding
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn encode(self, height: f64) -> Rgb<u8> {
+        // GeoTIFF tiles are written by `write_geotiff` instead
+        match self {
+            Self::Mapbox => {
+                let value = ((height + 10000.0) * 10.0).round().clamp(0.0, 16_777_215.0) as u32;
+                Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+            }
+            Self::Terrarium | Self::Geotiff => {
+                let value = ((height + 32768.0) * 256.0)
+                    .round()
+                    .clamp(0.0, 16_777_215.0) as u32;
+                Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+            }
+        }
+    }
+}
+
+impl Display for TerrainEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Terrarium => write!(f, "terrarium"),
+            Self::Mapbox => write!(f, "mapbox"),
+            Self::Geotiff => write!(f, "geotiff"),
+        }
+    }
+}
+
+/// Get the terrain encoding of the tiles served by a source, i.e. the `encoding` of the `TileJSON`
+/// of a PNG source. Only the terrain sources with a `geotiff` output serve `GeoTIFF` tiles.
+#[must_use]
+pub fn get_served_encoding(src: &dyn Source) -> Option<TerrainEncoding> {
+    match src.get_tile_info().format {
+        Format::Tiff => Some(TerrainEncoding::Geotiff),
+        Format::Png => src
+            .get_tilejson()
+            .other
+            .get("encoding")?
+            .as_str()
+            .and_then(TerrainEncoding::parse)
+            .filter(|v| *v != TerrainEncoding::Geotiff),
+        _ => None,
+    }
+}
+
+/// Heights in meters of the pixels of a terrain tile, row by row. The transparent pixels have no height.
+#[derive(Debug)]
+pub struct TerrainHeights {
+    width: u32,
+    height: u32,
+    heights: Vec<f64>,
+}
+
+impl TerrainHeights {
+    pub fn decode(
+        data: &[u8],
+        encoding: TerrainEncoding,
+        xyz: TileCoord,
+        id: &str,
+    ) -> TerrainResult<Self> {
+        if encoding == TerrainEncoding::Geotiff {
+            let err = || TerrainError::InvalidTiff(xyz, id.to_string());
+            let mut decoder = Decoder::new(Cursor::new(data)).map_err(|_| err())?;
+            let (width, height) = decoder.dimensions().map_err(|_| err())?;
+            let Ok(DecodingResult::F32(heights)) = decoder.read_image() else {
+                return Err(err());
+            };
+            if heights.len() != width as usize * height as usize {
+                return Err(err());
+            }
+            let heights = heights.into_iter().map(f64::from).collect();
+            return Ok(Self {
+                width,
+                height,
+                heights,
+            });
+        }
+        let tile = image::load_from_memory_with_format(data, ImageFormat::Png)
+            .map_err(|e| TerrainError::ImageError(e, xyz, id.to_string()))?
+            .to_rgba8();
+        let heights = tile
+            .pixels()
+            .map(|&Rgba([r, g, b, a])| {
+                if a == 0 {
+                    f64::NAN
+                } else {
+                    encoding.decode(Rgb([r, g, b]))
+                }
+            })
+            .collect();
+        Ok(Self {
+            width: tile.width(),
+            height: tile.height(),
+            heights,
+        })
+    }
+
+    /// Height of the pixel at a position within the tile, from `0.0` at its top left corner
+    /// to `1.0` at its bottom right corner
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn get(&self, x: f64, y: f64) -> Option<f64> {
+        let col = ((x * f64::from(self.width)) as u32).min(self.width.checked_sub(1)?);
+        let row = ((y * f64::from(self.height)) as u32).min(self.height.checked_sub(1)?);
+        let idx = row as usize * self.width as usize + col as usize;
+        self.heights.get(idx).copied().filter(|v| !v.is_nan())
+    }
+}
+
+/// Terrain encoding of a raster DEM source, and the encoding of its served tiles
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerrainConfig {
+    /// Encoding of the stored tiles, either `terrarium` or `mapbox`
+    pub encoding: TerrainEncoding,
+    /// Encoding of the served tiles, unless another one is requested with `?terrain=` [default: `encoding`]
+    pub output: Option<TerrainEncoding>,
+}
+
+pub type TerrainConfigs = BTreeMap<String, TerrainConfig>;
+
+/// Wrap the raster DEM sources, so that their tiles can be served in another terrain encoding
+pub fn apply_terrain(
+    sources: Vec<TileInfoSources>,
+    configs: &TerrainConfigs,
+) -> MartinResult<Vec<TileInfoSources>> {
+    if let Some(id) = configs.keys().find(|id| {
+        !sources
+            .iter()
+            .flatten()
+            .any(|src| src.get_id() == id.as_str())
+    }) {
+        return Err(TerrainError::UnknownSource(id.clone()).into());
+    }
+
+    let mut result = Vec::with_capacity(sources.len());
+    for group in sources {
+        let mut wrapped = TileInfoSources::default();
+        for src in group {
+            match configs.get(src.get_id()) {
+                Some(cfg) => wrapped.push(Box::new(TerrainSource::new(src, cfg)?)),
+                None => wrapped.push(src),
+            }
+        }
+        result.push(wrapped);
+    }
+    Ok(result)
+}
+
+/// A raster DEM source converting its tiles to the configured or requested terrain encoding.
+/// The tiles of each encoding are cached separately, because the requested encoding is part of the query.
+#[derive(Debug)]
+pub struct TerrainSource {
+    source: Box<dyn Source>,
+    tilejson: TileJSON,
+    encoding: TerrainEncoding,
+    output: TerrainEncoding,
+}
+
+impl Clone for TerrainSource {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_source(),
+            tilejson: self.tilejson.clone(),
+            encoding: self.encoding,
+            output: self.output,
+        }
+    }
+}
+
+impl TerrainSource {
+    pub fn new(source: Box<dyn Source>, cfg: &TerrainConfig) -> TerrainResult<Self> {
+        let id = source.get_id().to_string();
+        let info = source.get_tile_info();
+        if info.format != Format::Png || info.encoding.is_encoded() {
+            return Err(TerrainError::UnsupportedFormat(info, id));
+        }
+        if cfg.encoding == TerrainEncoding::Geotiff {
+            return Err(TerrainError::UnsupportedSourceEncoding(id));
+        }
+        let output = cfg.output.unwrap_or(cfg.encoding);
+        if output != cfg.encoding {
+            info!(
+                "Converting {} terrain tiles of source {id} to {output}",
+                cfg.encoding
+            );
+        }
+        let mut tilejson = source.get_tilejson().clone();
+        if output != TerrainEncoding::Geotiff {
+            tilejson
+                .other
+                .insert("encoding".to_string(), output.to_string().into());
+        }
+        Ok(Self {
+            source,
+            tilejson,
+            encoding: cfg.encoding,
+            output,
+        })
+    }
+
+    /// Get the requested output encoding, and the query to pass to the wrapped source.
+    /// Only the encodings with the same content type as the configured output can be requested.
+    fn split_query(
+        &self,
+        url_query: Option<&UrlQuery>,
+    ) -> TerrainResult<(TerrainEncoding, Option<UrlQuery>)> {
+        let mut query = url_query.cloned();
+        let requested = query.as_mut().and_then(|q| q.remove(TERRAIN_QUERY_PARAM));
+        let is_tiff = |v: TerrainEncoding| v == TerrainEncoding::Geotiff;
+        let output = match requested {
+            None => self.output,
+            Some(value) => match TerrainEncoding::parse(&value) {
+                Some(v) if is_tiff(v) == is_tiff(self.output) => v,
+                _ => {
+                    let id = self.get_id().to_string();
+                    return Err(TerrainError::InvalidEncoding(value, id, self.output));
+                }
+            },
+        };
+        let query = query.filter(|q| !q.is_empty() && self.source.support_url_query());
+        Ok((output, query))
+    }
+
+    /// Decode the heights of the tile, and encode them again with the output encoding
+    fn convert(
+        &self,
+        data: &[u8],
+        xyz: &TileCoord,
+        output: TerrainEncoding,
+    ) -> TerrainResult<TileData> {
+        let id = || self.get_id().to_string();
+        let mut tile = image::load_from_memory_with_format(data, ImageFormat::Png)
+            .map_err(|e| TerrainError::ImageError(e, *xyz, id()))?
+            .to_rgb8();
+        if output == TerrainEncoding::Geotiff {
+            #[allow(clippy::cast_possible_truncation)]
+            let heights: Vec<f32> = tile
+                .pixels()
+                .map(|v| self.encoding.decode(*v) as f32)
+                .collect();
+            return write_geotiff(&heights, tile.width(), tile.height(), *xyz)
+                .map_err(|e| TerrainError::TiffError(e, *xyz, id()));
+        }
+        for pixel in tile.pixels_mut() {
+            *pixel = output.encode(self.encoding.decode(*pixel));
+        }
+        let mut result = Cursor::new(Vec::new());
+        tile.write_to(&mut result, ImageOutputFormat::Png)
+            .map_err(|e| TerrainError::ImageError(e, *xyz, id()))?;
+        Ok(result.into_inner())
+    }
+}
+
+/// Write the heights as a `GeoTIFF` image georeferenced to the bounds of the tile
+fn write_geotiff(
+    heights: &[f32],
+    width: u32,
+    height: u32,
+    xyz: TileCoord,
+) -> tiff::TiffResult<Vec<u8>> {
+    let [min_x, min_y, max_x, max_y] = tile_bbox(xyz);
+    let scale_x = (max_x - min_x) / f64::from(width);
+    let scale_y = (max_y - min_y) / f64::from(height);
+    let mut result = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut result)?;
+    let mut image =
+        encoder.new_image_with_compression::<Gray32Float, _>(width, height, Deflate::default())?;
+    let dir = image.encoder();
+    dir.write_tag(Tag::ModelPixelScaleTag, &[scale_x, scale_y, 0.0][..])?;
+    dir.write_tag(
+        Tag::ModelTiepointTag,
+        &[0.0, 0.0, 0.0, min_x, max_y, 0.0][..],
+    )?;
+    dir.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEYS[..])?;
+    image.write_data(heights)?;
+    Ok(result.into_inner())
+}
+
+#[async_trait]
+impl Source for TerrainSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        if self.output == TerrainEncoding::Geotiff {
+            TileInfo::new(Format::Tiff, Encoding::Internal)
+        } else {
+            self.source.get_tile_info()
+        }
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    /// The query is needed to select the terrain encoding of each request
+    fn support_url_query(&self) -> bool {
+        true
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.source.is_cacheable()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let (output, query) = self.split_query(url_query.as_ref())?;
+        let data = self.source.get_tile(xyz, &query).await?;
+        if data.is_empty() || output == self.encoding {
+            return Ok(data);
+        }
+        Ok(self.convert(&data, xyz, output)?)
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        self.source.get_pool_status()
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{RgbImage, RgbaImage};
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[test]
+    fn terrain_encodings() {
+        use TerrainEncoding::{Mapbox, Terrarium};
+        for height in [-420.5, 0.0, 8848.9] {
+            let decoded = Terrarium.decode(Terrarium.encode(height));
+            assert!((decoded - height).abs() < 0.01, "{height} != {decoded}");
+            let decoded = Mapbox.decode(Mapbox.encode(height));
+            assert!((decoded - height).abs() < 0.06, "{height} != {decoded}");
+        }
+        assert_eq!(Terrarium.encode(0.0), Rgb([128, 0, 0]));
+        assert_eq!(Mapbox.encode(0.0), Rgb([1, 134, 160]));
+        assert_eq!(TerrainEncoding::parse("Terrain-RGB"), Some(Mapbox));
+        assert_eq!(TerrainEncoding::parse("png"), None);
+    }
+
+    #[derive(Debug, Clone)]
+    struct DemSource {
+        tj: TileJSON,
+    }
+
+    #[async_trait]
+    impl Source for DemSource {
+        fn get_id(&self) -> &str {
+            "dem"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            Format::Png.into()
+        }
+
+        fn get_source_type(&self) -> &'static str {
+            "test"
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: &TileCoord,
+            url_query: &Option<UrlQuery>,
+        ) -> MartinResult<TileData> {
+            assert!(url_query.is_none());
+            let image = RgbImage::from_pixel(4, 4, TerrainEncoding::Terrarium.encode(1000.0));
+            let mut result = Cursor::new(Vec::new());
+            image.write_to(&mut result, ImageOutputFormat::Png).unwrap();
+            Ok(result.into_inner())
+        }
+    }
+
+    fn dem(output: Option<TerrainEncoding>) -> TerrainSource {
+        let cfg = TerrainConfig {
+            encoding: TerrainEncoding::Terrarium,
+            output,
+        };
+        let src = DemSource {
+            tj: tilejson! { tiles: vec![] },
+        };
+        TerrainSource::new(Box::new(src), &cfg).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn convert_tiles() {
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        let query = |v: &str| {
+            Some(UrlQuery::from([(
+                TERRAIN_QUERY_PARAM.to_string(),
+                v.into(),
+            )]))
+        };
+
+        let src = dem(Some(TerrainEncoding::Mapbox));
+        assert_eq!(src.get_tilejson().other["encoding"], "mapbox");
+        let tile = src.get_tile(&xyz, &None).await.unwrap();
+        let image = image::load_from_memory(&tile).unwrap().to_rgb8();
+        assert_eq!(
+            image.get_pixel(0, 0),
+            &TerrainEncoding::Mapbox.encode(1000.0)
+        );
+        // the stored encoding is returned as is
+        let tile = src.get_tile(&xyz, &query("terrarium")).await.unwrap();
+        let image = image::load_from_memory(&tile).unwrap().to_rgb8();
+        assert_eq!(image.get_pixel(0, 0), &Rgb([131, 232, 0]));
+        assert!(src.get_tile(&xyz, &query("geotiff")).await.is_err());
+        assert!(src.get_tile(&xyz, &query("webp")).await.is_err());
+
+        let src = dem(Some(TerrainEncoding::Geotiff));
+        assert_eq!(
+            src.get_tile_info(),
+            TileInfo::new(Format::Tiff, Encoding::Internal)
+        );
+        let tile = src.get_tile(&xyz, &None).await.unwrap();
+        assert_eq!(TileInfo::detect(&tile), Some(src.get_tile_info()));
+        let mut decoder = Decoder::new(Cursor::new(tile)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (4, 4));
+        let DecodingResult::F32(heights) = decoder.read_image().unwrap() else {
+            panic!("unexpected TIFF data type");
+        };
+        assert_eq!(heights, vec![1000.0; 16]);
+        assert!(src.get_tile(&xyz, &query("mapbox")).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn decode_heights() {
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        for output in [
+            None,
+            Some(TerrainEncoding::Mapbox),
+            Some(TerrainEncoding::Geotiff),
+        ] {
+            let src = dem(output);
+            let encoding = get_served_encoding(&src).unwrap();
+            assert_eq!(encoding, output.unwrap_or(TerrainEncoding::Terrarium));
+            let tile = src.get_tile(&xyz, &None).await.unwrap();
+            let heights = TerrainHeights::decode(&tile, encoding, xyz, "dem").unwrap();
+            assert_eq!(heights.get(0.0, 0.0), Some(1000.0));
+            assert_eq!(heights.get(1.0, 1.0), Some(1000.0));
+        }
+        let src = DemSource {
+            tj: tilejson! { tiles: vec![] },
+        };
+        assert_eq!(get_served_encoding(&src), None);
+
+        let image =
+            RgbaImage::from_fn(2, 1, |x, _| Rgba([128, 0, 0, if x == 0 { 255 } else { 0 }]));
+        let mut tile = Cursor::new(Vec::new());
+        image.write_to(&mut tile, ImageOutputFormat::Png).unwrap();
+        let tile = tile.into_inner();
+        let heights =
+            TerrainHeights::decode(&tile, TerrainEncoding::Terrarium, xyz, "dem").unwrap();
+        assert_eq!(heights.get(0.2, 0.5), Some(0.0));
+        assert_eq!(heights.get(0.7, 0.5), None);
+        assert!(TerrainHeights::decode(&tile, TerrainEncoding::Geotiff, xyz, "dem").is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Handling of the WGS84 geometries that cross the antimeridian, or reach beyond the latitudes
+//! that can be shown in Web Mercator. Without it, such features are drawn across the whole world.
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::mvt::MAX_LATITUDE;
+use crate::utils::wkb::{Coord, Geometry};
+
+/// How to handle the geometries that cross the antimeridian or the Web Mercator latitude limits.
+/// All modes also clip the geometries to the latitudes that can be shown in Web Mercator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Antimeridian {
+    /// Remove the parts beyond the -180..180 longitude range
+    Clip,
+    /// Move the parts beyond the -180..180 longitude range to the other side of the world,
+    /// e.g. for data stored in the 0..360 longitude range
+    Wrap,
+    /// Split the features that jump across the antimeridian, e.g. a line from 170 to -170,
+    /// instead of drawing them around the world
+    Split,
+}
+
+/// Bounds of the world as `[min_x, min_y, max_x, max_y]` that can be shown in Web Mercator
+const WORLD: [f64; 4] = [-180.0, -MAX_LATITUDE, 180.0, MAX_LATITUDE];
+
+/// Handle the antimeridian crossing of a geometry in WGS84 coordinates.
+/// Returns `None` if nothing is left of the geometry.
+#[must_use]
+pub fn fix_antimeridian(geom: &Geometry, mode: Antimeridian) -> Option<Geometry> {
+    let result = match mode {
+        Antimeridian::Clip => clip(geom, WORLD),
+        Antimeridian::Wrap => wrap(geom),
+        Antimeridian::Split => wrap(&unwrap(geom)),
+    };
+    (!is_empty(&result)).then_some(result)
+}
+
+/// Clip the geometry to the world, and move the parts beyond it by a whole world width
+fn wrap(geom: &Geometry) -> Geometry {
+    let [_, min_y, _, max_y] = WORLD;
+    let east = clip(geom, [180.0, min_y, 540.0, max_y]);
+    let west = clip(geom, [-540.0, min_y, -180.0, max_y]);
+    merge(vec![
+        clip(geom, WORLD),
+        shift(&east, -360.0),
+        shift(&west, 360.0),
+    ])
+}
+
+/// Make the longitudes of each line and ring continuous, so that a jump of more than
+/// half of the world between two points is treated as crossing the antimeridian
+fn unwrap(geom: &Geometry) -> Geometry {
+    match geom {
+        Geometry::Points(v) => Geometry::Points(v.clone()),
+        Geometry::Lines(v) => Geometry::Lines(v.iter().map(|l| unwrap_path(l)).collect()),
+        Geometry::Polygons(v) => Geometry::Polygons(
+            v.iter()
+                .map(|rings| {
+                    let rings: Vec<_> = rings.iter().map(|r| unwrap_path(r)).collect();
+                    // keep the holes on the same side of the antimeridian as the exterior ring
+                    let Some(start) = rings.first().and_then(|r| r.first()).map(|c| c[0]) else {
+                        return rings;
+                    };
+                    rings
+                        .into_iter()
+                        .map(|r| {
+                            let offset = r.first().map_or(0.0, |c| wrap_offset(c[0] - start));
+                            r.into_iter().map(|[x, y]| [x + offset, y]).collect()
+                        })
+                        .collect()
+                })
+                .collect(),
+        ),
+        Geometry::Collection(v) => Geometry::Collection(v.iter().map(unwrap).collect()),
+    }
+}
+
+fn unwrap_path(path: &[Coord]) -> Vec<Coord> {
+    let mut offset = 0.0;
+    let mut prev: Option<f64> = None;
+    path.iter()
+        .map(|&[x, y]| {
+            if let Some(prev) = prev {
+                offset += wrap_offset(x - prev);
+            }
+            prev = Some(x);
+            [x + offset, y]
+        })
+        .collect()
+}
+
+/// The multiple of 360 to add to a longitude difference to bring it within -180..180
+fn wrap_offset(delta: f64) -> f64 {
+    -360.0 * (delta / 360.0).round()
+}
+
+fn shift(geom: &Geometry, dx: f64) -> Geometry {
+    let path = |v: &Vec<Coord>| v.iter().map(|[x, y]| [x + dx, *y]).collect::<Vec<_>>();
+    match geom {
+        Geometry::Points(v) => Geometry::Points(path(v)),
+        Geometry::Lines(v) => Geometry::Lines(v.iter().map(path).collect()),
+        Geometry::Polygons(v) => {
+            Geometry::Polygons(v.iter().map(|p| p.iter().map(path).collect()).collect())
+        }
+        Geometry::Collection(v) => Geometry::Collection(v.iter().map(|g| shift(g, dx)).collect()),
+    }
+}
+
+/// Combine the parts of the same geometry type into one geometry
+fn merge(parts: Vec<Geometry>) -> Geometry {
+    let mut iter = parts.into_iter();
+    let Some(mut result) = iter.next() else {
+        return Geometry::Collection(Vec::new());
+    };
+    for part in iter {
+        result = match (result, part) {
+            (Geometry::Points(mut a), Geometry::Points(b)) => {
+                a.extend(b);
+                Geometry::Points(a)
+            }
+            (Geometry::Lines(mut a), Geometry::Lines(b)) => {
+                a.extend(b);
+                Geometry::Lines(a)
+            }
+            (Geometry::Polygons(mut a), Geometry::Polygons(b)) => {
+                a.extend(b);
+                Geometry::Polygons(a)
+            }
+            (Geometry::Collection(a), Geometry::Collection(b)) => Geometry::Collection(
+                a.into_iter()
+                    .zip(b)
+                    .map(|(a, b)| merge(vec![a, b]))
+                    .collect(),
+            ),
+            (a, _) => a,
+        };
+    }
+    result
+}
+
+fn is_empty(geom: &Geometry) -> bool {
+    match geom {
+        Geometry::Points(v) => v.is_empty(),
+        Geometry::Lines(v) => v.is_empty(),
+        Geometry::Polygons(v) => v.is_empty(),
+        Geometry::Collection(v) => v.iter().all(is_empty),
+    }
+}
+
+/// Clip the geometry to a bounding box given as `[min_x, min_y, max_x, max_y]`
+fn clip(geom: &Geometry, bbox: [f64; 4]) -> Geometry {
+    let edges = [
+        (0, bbox[0], true),
+        (1, bbox[1], true),
+        (0, bbox[2], false),
+        (1, bbox[3], false),
+    ];
+    let inside =
+        |c: &Coord| c[0] >= bbox[0] && c[0] <= bbox[2] && c[1] >= bbox[1] && c[1] <= bbox[3];
+    match geom {
+        Geometry::Points(v) => Geometry::Points(v.iter().copied().filter(inside).collect()),
+        Geometry::Lines(v) => Geometry::Lines(
+            v.iter()
+                .flat_map(|line| {
+                    edges
+                        .iter()
+                        .fold(vec![line.clone()], |lines, &(axis, value, above)| {
+                            lines
+                                .iter()
+                                .flat_map(|l| clip_line(l, axis, value, above))
+                                .collect()
+                        })
+                })
+                .collect(),
+        ),
+        Geometry::Polygons(v) => Geometry::Polygons(
+            v.iter()
+                .filter_map(|rings| {
+                    let rings: Vec<_> = rings
+                        .iter()
+                        .map(|ring| {
+                            edges.iter().fold(ring.clone(), |r, &(axis, value, above)| {
+                                clip_ring(&r, axis, value, above)
+                            })
+                        })
+                        .collect();
+                    // without the exterior ring, the holes are meaningless
+                    if rings.first()?.len() < 4 {
+                        return None;
+                    }
+                    Some(rings.into_iter().filter(|r| r.len() >= 4).collect())
+                })
+                .collect(),
+        ),
+        Geometry::Collection(v) => Geometry::Collection(v.iter().map(|g| clip(g, bbox)).collect()),
+    }
+}
+
+fn is_inside(c: Coord, axis: usize, value: f64, above: bool) -> bool {
+    if above {
+        c[axis] >= value
+    } else {
+        c[axis] <= value
+    }
+}
+
+/// The point where the segment from `a` to `b` crosses the `value` of the given axis
+fn intersection(a: Coord, b: Coord, axis: usize, value: f64) -> Coord {
+    let t = (value - a[axis]) / (b[axis] - a[axis]);
+    let mut result = [a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])];
+    result[axis] = value;
+    result
+}
+
+/// Clip a line to one side of an axis-aligned boundary, possibly splitting it into several lines
+fn clip_line(line: &[Coord], axis: usize, value: f64, above: bool) -> Vec<Vec<Coord>> {
+    let mut result = Vec::new();
+    let mut current = Vec::new();
+    for (idx, &point) in line.iter().enumerate() {
+        let inside = is_inside(point, axis, value, above);
+        if let Some(&prev) = idx.checked_sub(1).and_then(|i| line.get(i)) {
+            if inside != is_inside(prev, axis, value, above) {
+                current.push(intersection(prev, point, axis, value));
+                if !inside {
+                    result.push(std::mem::take(&mut current));
+                }
+            }
+        }
+        if inside {
+            current.push(point);
+        }
+    }
+    result.push(current);
+    result.retain(|l| l.len() >= 2);
+    result
+}
+
+/// Clip a closed ring to one side of an axis-aligned boundary (Sutherland–Hodgman)
+fn clip_ring(ring: &[Coord], axis: usize, value: f64, above: bool) -> Vec<Coord> {
+    let mut result = Vec::with_capacity(ring.len());
+    for pair in ring.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let (a_in, b_in) = (
+            is_inside(a, axis, value, above),
+            is_inside(b, axis, value, above),
+        );
+        if a_in {
+            result.push(a);
+        }
+        if a_in != b_in {
+            result.push(intersection(a, b, axis, value));
+        }
+    }
+    if let Some(&first) = result.first() {
+        result.push(first);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn antimeridian_modes() {
+        let line = Geometry::Lines(vec![vec![[170.0, 0.0], [-170.0, 10.0]]]);
+        // without splitting, the line goes around the world
+        assert_eq!(
+            fix_antimeridian(&line, Antimeridian::Clip),
+            Some(line.clone())
+        );
+        assert_eq!(
+            fix_antimeridian(&line, Antimeridian::Split),
+            Some(Geometry::Lines(vec![
+                vec![[170.0, 0.0], [180.0, 5.0]],
+                vec![[-180.0, 5.0], [-170.0, 10.0]],
+            ]))
+        );
+
+        let polygon = Geometry::Polygons(vec![vec![vec![
+            [170.0, 0.0],
+            [190.0, 0.0],
+            [190.0, 10.0],
+            [170.0, 10.0],
+            [170.0, 0.0],
+        ]]]);
+        let Some(Geometry::Polygons(clipped)) = fix_antimeridian(&polygon, Antimeridian::Clip)
+        else {
+            panic!()
+        };
+        assert_eq!(clipped.len(), 1);
+        assert!(clipped[0][0].iter().all(|c| c[0] <= 180.0));
+        let Some(Geometry::Polygons(wrapped)) = fix_antimeridian(&polygon, Antimeridian::Wrap)
+        else {
+            panic!()
+        };
+        assert_eq!(wrapped.len(), 2);
+        assert!(wrapped[1][0]
+            .iter()
+            .all(|c| c[0] >= -180.0 && c[0] <= -170.0));
+
+        let polar = Geometry::Points(vec![[0.0, 89.0], [10.0, 10.0]]);
+        assert_eq!(
+            fix_antimeridian(&polar, Antimeridian::Clip),
+            Some(Geometry::Points(vec![[10.0, 10.0]]))
+        );
+        let outside = Geometry::Points(vec![[200.0, 0.0]]);
+        assert_eq!(fix_antimeridian(&outside, Antimeridian::Clip), None);
+        assert_eq!(
+            fix_antimeridian(&outside, Antimeridian::Wrap),
+            Some(Geometry::Points(vec![[-160.0, 0.0]]))
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use itertools::Itertools as _;
+use martin_tile_utils::Encoding;
+use moka::future::Cache;
+use moka::Expiry;
+
+use crate::source::{TileData, UrlQuery};
+use crate::TileCoord;
+
+pub type MainCache = Cache<CacheKey, CacheValue>;
+pub type OptMainCache = Option<MainCache>;
+pub const NO_MAIN_CACHE: OptMainCache = None;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub enum CacheKey {
+    /// Tile of a single source, with the URL query (if used by the source) in a normalized form
+    Tile(String, TileCoord, Option<String>),
+    /// Tile of one or more comma-separated sources converted to `GeoJSON`, with the URL query if used
+    GeoJson(String, TileCoord, Option<String>),
+    /// `UTFGrid` of one or more comma-separated sources, with the URL query if used
+    UtfGrid(String, TileCoord, Option<String>),
+    /// Tile of one or more comma-separated sources compressed for the clients, with the URL query if used
+    Encoded(String, TileCoord, Option<String>, Encoding),
+}
+
+impl CacheKey {
+    #[must_use]
+    pub fn tile(source_id: &str, xyz: TileCoord, query: Option<&UrlQuery>) -> Self {
+        Self::Tile(source_id.to_string(), xyz, normalize_query(query))
+    }
+
+    #[must_use]
+    pub fn encoded(
+        source_ids: &str,
+        xyz: TileCoord,
+        query: Option<&UrlQuery>,
+        encoding: Encoding,
+    ) -> Self {
+        Self::Encoded(
+            source_ids.to_string(),
+            xyz,
+            normalize_query(query),
+            encoding,
+        )
+    }
+
+    /// Check if the cached value was generated from the given source
+    #[must_use]
+    pub fn uses_source(&self, source_id: &str) -> bool {
+        match self {
+            Self::Tile(id, _, _) => id == source_id,
+            Self::GeoJson(ids, _, _) | Self::UtfGrid(ids, _, _) | Self::Encoded(ids, _, _, _) => {
+                ids.split(',').any(|id| id == source_id)
+            }
+        }
+    }
+
+    /// Check if the cached value was generated from the tile of a `Tile` key, e.g. compressed or converted,
+    /// whatever its URL query
+    #[must_use]
+    pub fn is_derived_from(&self, tile: &CacheKey) -> bool {
+        let Self::Tile(source_id, tile_xyz, _) = tile else {
+            return false;
+        };
+        match self {
+            Self::Tile(..) => false,
+            Self::GeoJson(_, xyz, _) | Self::UtfGrid(_, xyz, _) | Self::Encoded(_, xyz, _, _) => {
+                xyz == tile_xyz && self.uses_source(source_id)
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn zoom(&self) -> Option<u8> {
+        match self {
+            Self::Tile(_, xyz, _)
+            | Self::GeoJson(_, xyz, _)
+            | Self::UtfGrid(_, xyz, _)
+            | Self::Encoded(_, xyz, _, _) => Some(xyz.z),
+        }
+    }
+}
+
+/// The URL query in a normalized form, so that the order of the parameters does not matter
+fn normalize_query(query: Option<&UrlQuery>) -> Option<String> {
+    query.map(|q| q.iter().sorted().map(|(k, v)| format!("{k}={v}")).join("&"))
+}
+
+#[derive(Debug, Clone)]
+pub enum CacheValue {
+    /// Tile data, its `GeoJSON` or `UTFGrid` representation for the `GeoJson` and `UtfGrid` keys,
+    /// or its compressed variant for the `Encoded` keys
+    Tile(TileData),
+    /// Tile data of a source with a stale window, and the time it becomes stale. A stale tile
+    /// is still served while it is fetched again in the background, until its stale window has passed.
+    Expiring(TileData, Instant),
+}
+
+impl CacheValue {
+    #[must_use]
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Tile(data) | Self::Expiring(data, _) => data.len(),
+        }
+    }
+
+    /// The tile data, and whether it is stale and should be fetched again
+    #[must_use]
+    pub fn into_tile(self) -> (TileData, bool) {
+        match self {
+            Self::Tile(data) => (data, false),
+            Self::Expiring(data, stale_at) => (data, Instant::now() >= stale_at),
+        }
+    }
+}
+
+/// Tile `max-age` per zoom range. Each entry applies to its zoom level and all higher zooms,
+/// up to the next configured zoom level.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TileExpiration {
+    max_age: Arc<BTreeMap<u8, Duration>>,
+    /// Time the expired tiles of each source are still served while they are fetched again, keyed by source ID
+    stale: Arc<BTreeMap<String, Duration>>,
+    /// `max-age` of the empty tiles, replacing the `max-age` of their zoom
+    empty_max_age: Option<Duration>,
+}
+
+impl TileExpiration {
+    #[must_use]
+    pub fn new(max_age: Option<&BTreeMap<u8, u64>>) -> Self {
+        Self {
+            max_age: Arc::new(
+                max_age
+                    .into_iter()
+                    .flatten()
+                    .map(|(zoom, secs)| (*zoom, Duration::from_secs(*secs)))
+                    .collect(),
+            ),
+            stale: Arc::default(),
+            empty_max_age: None,
+        }
+    }
+
+    /// Keep serving the expired tiles of the given sources for their stale window in seconds,
+    /// while they are fetched again in the background
+    #[must_use]
+    pub fn with_stale_windows(mut self, stale: Option<&BTreeMap<String, u64>>) -> Self {
+        self.stale = Arc::new(
+            stale
+                .into_iter()
+                .flatten()
+                .map(|(id, secs)| (id.clone(), Duration::from_secs(*secs)))
+                .collect(),
+        );
+        self
+    }
+
+    /// Keep the empty tiles for the given number of seconds, usually much shorter than the other tiles,
+    /// so that the empty areas like oceans do not query the sources again on every request
+    #[must_use]
+    pub fn with_empty_max_age(mut self, secs: Option<u64>) -> Self {
+        self.empty_max_age = secs.map(Duration::from_secs);
+        self
+    }
+
+    /// Get the configured `max-age` of the empty tiles, if any
+    #[must_use]
+    pub fn empty_max_age(&self) -> Option<Duration> {
+        self.empty_max_age
+    }
+
+    /// Time to keep a tile in the caches: the `max-age` of the empty tiles if the tile is empty
+    /// and it is configured, or the `max-age` of the tile zoom
+    #[must_use]
+    pub fn cache_ttl(&self, key: &CacheKey, data: &[u8]) -> Option<Duration> {
+        self.empty_max_age
+            .filter(|_| data.is_empty())
+            .or_else(|| key.zoom().and_then(|z| self.max_age(z)))
+    }
+
+    /// Get the configured `max-age` for the given zoom level, if any
+    #[must_use]
+    pub fn max_age(&self, zoom: u8) -> Option<Duration> {
+        self.max_age.range(..=zoom).next_back().map(|(_, v)| *v)
+    }
+
+    /// Get the stale window of the given source, if any
+    #[must_use]
+    pub fn stale_window(&self, source_id: &str) -> Option<Duration> {
+        self.stale.get(source_id).copied()
+    }
+
+    /// The value to store in the in-memory cache. The tiles of a single source with a stale window
+    /// and a `max-age` remember when they become stale, so that they can be fetched again.
+    #[must_use]
+    pub fn new_value(&self, key: &CacheKey, data: TileData) -> CacheValue {
+        let empty = data.is_empty() && self.empty_max_age.is_some();
+        match key {
+            CacheKey::Tile(id, xyz, _) if self.stale.contains_key(id) && !empty => {
+                match self.max_age(xyz.z) {
+                    Some(max_age) => CacheValue::Expiring(data, Instant::now() + max_age),
+                    None => CacheValue::Tile(data),
+                }
+            }
+            _ => CacheValue::Tile(data),
+        }
+    }
+}
+
+impl Expiry<CacheKey, CacheValue> for TileExpiration {
+    fn expire_after_create(
+        &self,
+        key: &CacheKey,
+        value: &CacheValue,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        if let Some(max_age) = self.empty_max_age.filter(|_| value.size() == 0) {
+            return Some(max_age);
+        }
+        let max_age = key.zoom().and_then(|z| self.max_age(z))?;
+        match (key, value) {
+            (CacheKey::Tile(id, _, _), CacheValue::Expiring(..)) => {
+                Some(max_age + self.stale_window(id).unwrap_or_default())
+            }
+            _ => Some(max_age),
+        }
+    }
+}
+
+/// Create a new cache limited to `size_mb` megabytes, or `None` if the size is zero.
+#[must_use]
+pub fn new_main_cache(size_mb: u64, expiration: TileExpiration) -> OptMainCache {
+    (size_mb > 0).then(|| {
+        Cache::builder()
+            .weigher(|_key, value: &CacheValue| -> u32 {
+                value.size().try_into().unwrap_or(u32::MAX)
+            })
+            .max_capacity(size_mb * 1024 * 1024)
+            .expire_after(expiration)
+            .support_invalidation_closures()
+            .build()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_age_per_zoom() {
+        let exp = TileExpiration::new(Some(&BTreeMap::from([(3, 100), (11, 10), (16, 1)])));
+        assert_eq!(exp.max_age(0), None);
+        assert_eq!(exp.max_age(3), Some(Duration::from_secs(100)));
+        assert_eq!(exp.max_age(10), Some(Duration::from_secs(100)));
+        assert_eq!(exp.max_age(11), Some(Duration::from_secs(10)));
+        assert_eq!(exp.max_age(22), Some(Duration::from_secs(1)));
+        assert_eq!(TileExpiration::default().max_age(5), None);
+    }
+
+    #[test]
+    fn stale_window() {
+        let exp = TileExpiration::new(Some(&BTreeMap::from([(0, 60)])))
+            .with_stale_windows(Some(&BTreeMap::from([("func".to_string(), 600)])));
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        let now = Instant::now();
+
+        let key = CacheKey::tile("func", xyz, None);
+        let value = exp.new_value(&key, vec![1]);
+        assert!(matches!(value, CacheValue::Expiring(..)));
+        assert_eq!(
+            exp.expire_after_create(&key, &value, now),
+            Some(Duration::from_secs(660))
+        );
+        assert_eq!(value.into_tile(), (vec![1], false));
+        let stale = CacheValue::Expiring(vec![2], now);
+        assert_eq!(stale.into_tile(), (vec![2], true));
+
+        let key = CacheKey::tile("other", xyz, None);
+        let value = exp.new_value(&key, vec![1]);
+        assert!(matches!(value, CacheValue::Tile(_)));
+        assert_eq!(
+            exp.expire_after_create(&key, &value, now),
+            Some(Duration::from_secs(60))
+        );
+
+        // without a max-age, the tiles never become stale
+        let exp = TileExpiration::default()
+            .with_stale_windows(Some(&BTreeMap::from([("func".to_string(), 600)])));
+        let key = CacheKey::tile("func", xyz, None);
+        assert!(matches!(exp.new_value(&key, vec![1]), CacheValue::Tile(_)));
+    }
+
+    #[test]
+    fn empty_tiles() {
+        let exp = TileExpiration::new(Some(&BTreeMap::from([(0, 3600)])))
+            .with_stale_windows(Some(&BTreeMap::from([("func".to_string(), 600)])))
+            .with_empty_max_age(Some(30));
+        let key = CacheKey::tile("func", TileCoord { z: 1, x: 0, y: 0 }, None);
+        let now = Instant::now();
+
+        let value = exp.new_value(&key, vec![]);
+        assert!(matches!(value, CacheValue::Tile(_)));
+        assert_eq!(
+            exp.expire_after_create(&key, &value, now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(exp.cache_ttl(&key, &[]), Some(Duration::from_secs(30)));
+
+        let value = exp.new_value(&key, vec![1]);
+        assert!(matches!(value, CacheValue::Expiring(..)));
+        assert_eq!(
+            exp.expire_after_create(&key, &value, now),
+            Some(Duration::from_secs(4200))
+        );
+        assert_eq!(exp.cache_ttl(&key, &[1]), Some(Duration::from_secs(3600)));
+
+        // without tile_max_age, only the empty tiles expire
+        let exp = TileExpiration::default().with_empty_max_age(Some(30));
+        let value = exp.new_value(&key, vec![1]);
+        assert_eq!(exp.expire_after_create(&key, &value, now), None);
+        assert_eq!(exp.cache_ttl(&key, &[]), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn normalized_query_key() {
+        let xyz = TileCoord { z: 1, x: 2, y: 3 };
+        let q1 = UrlQuery::from([("a".into(), "1".into()), ("b".into(), "2".into())]);
+        let q2 = UrlQuery::from([("b".into(), "2".into()), ("a".into(), "1".into())]);
+        assert_eq!(
+            CacheKey::tile("src", xyz, Some(&q1)),
+            CacheKey::tile("src", xyz, Some(&q2))
+        );
+        assert_ne!(
+            CacheKey::tile("src", xyz, Some(&q1)),
+            CacheKey::tile("src", xyz, None)
+        );
+    }
+
+    #[test]
+    fn derived_keys() {
+        let xyz = TileCoord { z: 1, x: 2, y: 3 };
+        let other = TileCoord { z: 1, x: 2, y: 4 };
+        let tile = CacheKey::tile("a", xyz, None);
+        let gzip = Encoding::Gzip;
+        assert!(CacheKey::encoded("a", xyz, None, gzip).is_derived_from(&tile));
+        assert!(CacheKey::encoded("b,a", xyz, None, gzip).is_derived_from(&tile));
+        assert!(
+            CacheKey::GeoJson("a".to_string(), xyz, Some("q=1".to_string())).is_derived_from(&tile)
+        );
+        assert!(!CacheKey::encoded("b", xyz, None, gzip).is_derived_from(&tile));
+        assert!(!CacheKey::encoded("a", other, None, gzip).is_derived_from(&tile));
+        assert!(!CacheKey::tile("a", xyz, None).is_derived_from(&tile));
+    }
+}
@@ -2,13 +2,22 @@ use std::error::Error;
 use std::fmt::Write;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use mbtiles::MbtError;
 
+use crate::aliases::AliasError;
 use crate::file_config::FileError;
 use crate::fonts::FontError;
+use crate::overrides::OverrideError;
+use crate::overzoom::OverzoomError;
 use crate::pg::PgError;
+use crate::proxy::ProxyError;
 use crate::sprites::SpriteError;
+use crate::srv::H2_WINDOW_KB_MAX;
+use crate::terrain::TerrainError;
+use crate::watermark::WatermarkError;
+use crate::TileCoord;
 
 /// A convenience [`Result`] for Martin crate.
 pub type MartinResult<T> = Result<T, MartinError>;
@@ -47,12 +56,101 @@ pub enum MartinError {
     #[error("Unable to parse config file {}: {0}", .1.display())]
     ConfigParseError(subst::yaml::Error, PathBuf),
 
+    #[error("Unable to parse `{0}` in config file {}: {1}", .2.display())]
+    ConfigValueError(String, serde_yaml::Error, PathBuf),
+
+    #[error("Unable to substitute the environment variables of `{0}` in config file {}: {1}", .2.display())]
+    ConfigSubstError(String, subst::Error, PathBuf),
+
+    #[error("Config file {} includes itself", .0.display())]
+    ConfigIncludeCycle(PathBuf),
+
+    #[error("Unable to start the OpenTelemetry trace exporter: {0}")]
+    TelemetryError(opentelemetry::trace::TraceError),
+
     #[error("Unable to write config file {}: {0}", .1.display())]
     ConfigWriteError(io::Error, PathBuf),
 
+    #[error("Unable to read the answer: {0}")]
+    PromptError(io::Error),
+
+    #[error("Unable to read the recorded requests {}: {0}", .1.display())]
+    BenchReadError(io::Error, PathBuf),
+
+    #[error("Unable to run the benchmark: {0}")]
+    InvalidBench(String),
+
+    #[error("Configuration check failed, found {0} problem(s)")]
+    CheckFailed(usize),
+
+    #[error("Unable to write demo data to {}: {0}", .1.display())]
+    DemoWriteError(io::Error, PathBuf),
+
     #[error("No tile sources found. Set sources by giving a database connection string on command line, env variable, or a config file.")]
     NoSources,
 
+    #[error("Configured sources are not available or were renamed: {}", elide_vec(.0, 10, 50))]
+    MissingSources(Vec<String>),
+
+    #[error("Unable to read the empty tile fallback {}: {0}", .1.display())]
+    EmptyTileReadError(io::Error, PathBuf),
+
+    #[error("Redirect for source {1} uses status {0}, but only 301 and 308 are supported")]
+    InvalidRedirectStatus(u16, String),
+
+    #[error(
+        "Encryption key of source {0} must be 32 or 64 hex characters long, for AES-128 or AES-256"
+    )]
+    InvalidEncryptionKey(String),
+
+    #[error("Allowed origin {0} of source {1} must be a host name with an optional scheme and port, e.g. https://*.example.com")]
+    InvalidRefererPattern(String, String),
+
+    #[error("JWT configuration is invalid: {0}")]
+    InvalidJwtConfig(String),
+
+    #[error("Composite source {0} is invalid: {1}")]
+    InvalidComposite(String, String),
+
+    #[error("Source with variants {0} is invalid: {1}")]
+    InvalidVariant(String, String),
+
+    #[error("Public URL {0} is invalid, use an absolute http or https URL without a query, e.g. https://example.com/tiles")]
+    InvalidPublicUrl(String),
+
+    #[error("Tenant {0} is invalid: {1}")]
+    InvalidTenant(String, String),
+
+    #[error("Source {0} cannot be added: {1}")]
+    InvalidSource(String, String),
+
+    #[error("Config section {0} of a custom source is invalid: {1}")]
+    InvalidSourceConfig(String, serde_yaml::Error),
+
+    #[error("Scheduled task is invalid: {0}")]
+    InvalidScheduledTask(String),
+
+    #[error("Webhook configuration is invalid: {0}")]
+    InvalidWebhookConfig(String),
+
+    #[error("CDN configuration is invalid: {0}")]
+    InvalidCdnConfig(String),
+
+    #[error("Concurrency limit of {0} must allow at least one tile at a time")]
+    InvalidConcurrencyLimit(String),
+
+    #[error("Static files at {0} are invalid: {1}")]
+    InvalidStaticFiles(String, String),
+
+    #[error("gRPC configuration is invalid: {0}")]
+    InvalidGrpcConfig(String),
+
+    #[error("HTTP/2 window {0} must be between 1 and {H2_WINDOW_KB_MAX} kilobytes, not {1}")]
+    InvalidH2Window(&'static str, u32),
+
+    #[error("The gRPC API is configured, but Martin was built without the grpc feature")]
+    GrpcNotCompiled,
+
     #[error("Unrecognizable connection strings: {0:?}")]
     UnrecognizableConnections(Vec<String>),
 
@@ -65,15 +163,48 @@ pub enum MartinError {
     #[error(transparent)]
     FileError(#[from] FileError),
 
+    #[error(transparent)]
+    ProxyError(#[from] ProxyError),
+
     #[error(transparent)]
     SpriteError(#[from] SpriteError),
 
     #[error(transparent)]
     FontError(#[from] FontError),
 
+    #[error(transparent)]
+    OverzoomError(#[from] OverzoomError),
+
+    #[error(transparent)]
+    AliasError(#[from] AliasError),
+
+    #[error(transparent)]
+    OverrideError(#[from] OverrideError),
+
+    #[error(transparent)]
+    WatermarkError(#[from] WatermarkError),
+
+    #[error(transparent)]
+    TerrainError(#[from] TerrainError),
+
     #[error(transparent)]
     WebError(#[from] actix_web::Error),
 
+    #[error("Source {0} did not return tile {1} within {2:?}")]
+    SourceTimeout(String, TileCoord, Duration),
+
+    #[error("Source {0} is overloaded, too many tile requests are waiting for it")]
+    SourceOverloaded(String),
+
+    #[error("Unable to use the host tile cache {}: {0}", .1.display())]
+    HostCacheError(io::Error, PathBuf),
+
+    #[error("Unable to publish cache purge to peers: {0}")]
+    CacheSyncError(#[from] redis::RedisError),
+
+    #[error("Unable to purge the CDN cache: {0}")]
+    CdnPurgeError(String),
+
     #[error("Internal error: {0}")]
     InternalError(Box<dyn Error>),
 }
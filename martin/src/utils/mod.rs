@@ -1,3 +1,10 @@
+pub mod antimeridian;
+
+mod cache;
+pub use cache::{
+    new_main_cache, CacheKey, CacheValue, MainCache, OptMainCache, TileExpiration, NO_MAIN_CACHE,
+};
+
 mod cfg_containers;
 pub use cfg_containers::{OptBoolObj, OptOneMany};
 
@@ -7,11 +14,18 @@ pub use error::*;
 mod id_resolver;
 pub use id_resolver::IdResolver;
 
-mod rectangle;
-pub use rectangle::{append_rect, TileRect};
+pub mod mvt;
+
+pub mod remote;
+
+pub mod s3;
+
+pub mod saturation;
 
 mod utilities;
 pub use utilities::*;
 
+pub mod wkb;
+
 mod xyz;
-pub use xyz::TileCoord;
+pub use xyz::{TileCoord, TileMatrixSet, MAX_ZOOM};
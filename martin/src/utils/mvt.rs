@@ -0,0 +1,1109 @@
+//! Minimal [Mapbox Vector Tile](https://github.com/mapbox/vector-tile-spec) encoding
+//! for the sources that generate vector tiles themselves instead of getting them from a database,
+//! and decoding for the endpoints that convert vector tiles to other formats.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use prost::Message;
+use tilejson::Bounds;
+
+use crate::TileCoord;
+
+/// Half of the Web Mercator world width in meters
+pub const MERCATOR_MAX: f64 = 20_037_508.342_789_244;
+/// Maximum latitude that can be shown in Web Mercator
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+pub const DEFAULT_MVT_EXTENT: u32 = 4096;
+/// Buffer around each generated vector tile, in tile units
+pub const MVT_BUFFER: f64 = 64.0;
+
+/// Tile bounds in Web Mercator meters as `[min_x, min_y, max_x, max_y]`
+#[must_use]
+pub fn tile_bbox(xyz: TileCoord) -> [f64; 4] {
+    let size = 2.0 * MERCATOR_MAX / f64::from(1_u32 << xyz.z.min(31));
+    let min_x = -MERCATOR_MAX + f64::from(xyz.x) * size;
+    let max_y = MERCATOR_MAX - f64::from(xyz.y) * size;
+    [min_x, max_y - size, min_x + size, max_y]
+}
+
+#[must_use]
+pub fn wgs84_to_mercator(lon: f64, lat: f64) -> [f64; 2] {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+    let x = lon * MERCATOR_MAX / 180.0;
+    let y = ((90.0 + lat) * PI / 360.0).tan().ln() / PI * MERCATOR_MAX;
+    [x, y]
+}
+
+#[must_use]
+pub fn mercator_to_wgs84(x: f64, y: f64) -> [f64; 2] {
+    let lon = x / MERCATOR_MAX * 180.0;
+    let lat = (2.0 * (y / MERCATOR_MAX * PI).exp().atan() - PI / 2.0).to_degrees();
+    [lon, lat]
+}
+
+/// Spatial reference systems of the feature data that can be converted to vector tiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Srs {
+    Wgs84,
+    WebMercator,
+}
+
+impl Srs {
+    #[must_use]
+    pub fn from_epsg(organization: Option<&str>, code: Option<i64>) -> Option<Self> {
+        if !organization?.eq_ignore_ascii_case("epsg") {
+            return None;
+        }
+        match code? {
+            4326 => Some(Self::Wgs84),
+            3857 | 3785 | 900_913 => Some(Self::WebMercator),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn to_mercator(self, coord: [f64; 2]) -> [f64; 2] {
+        match self {
+            Self::Wgs84 => wgs84_to_mercator(coord[0], coord[1]),
+            Self::WebMercator => coord,
+        }
+    }
+
+    #[must_use]
+    pub fn mercator_to_srs(self, coord: [f64; 2]) -> [f64; 2] {
+        match self {
+            Self::Wgs84 => mercator_to_wgs84(coord[0], coord[1]),
+            Self::WebMercator => coord,
+        }
+    }
+
+    #[must_use]
+    pub fn to_bounds(self, bbox: [f64; 4]) -> Bounds {
+        let [left, bottom] = self.to_wgs84([bbox[0], bbox[1]]);
+        let [right, top] = self.to_wgs84([bbox[2], bbox[3]]);
+        Bounds::new(left, bottom, right, top)
+    }
+
+    #[must_use]
+    pub fn to_wgs84(self, coord: [f64; 2]) -> [f64; 2] {
+        match self {
+            Self::Wgs84 => coord,
+            Self::WebMercator => mercator_to_wgs84(coord[0], coord[1]),
+        }
+    }
+}
+
+/// Converts the coordinates of the feature data to the integer coordinates of a tile
+#[derive(Clone, Copy, Debug)]
+pub struct TileTransform {
+    srs: Srs,
+    bbox: [f64; 4],
+    size: f64,
+}
+
+impl TileTransform {
+    #[must_use]
+    pub fn new(xyz: TileCoord, srs: Srs) -> Self {
+        let bbox = tile_bbox(xyz);
+        Self {
+            srs,
+            bbox,
+            size: bbox[2] - bbox[0],
+        }
+    }
+
+    /// Bounds of the tile including the buffer as `[min_x, min_y, max_x, max_y]` in the data SRS
+    #[must_use]
+    pub fn search_bbox(&self) -> [f64; 4] {
+        let buffer = self.size * MVT_BUFFER / f64::from(DEFAULT_MVT_EXTENT);
+        let [min_x, min_y] = self
+            .srs
+            .mercator_to_srs([self.bbox[0] - buffer, self.bbox[1] - buffer]);
+        let [max_x, max_y] = self
+            .srs
+            .mercator_to_srs([self.bbox[2] + buffer, self.bbox[3] + buffer]);
+        [min_x, min_y, max_x, max_y]
+    }
+
+    #[must_use]
+    pub fn apply(&self, coord: [f64; 2]) -> [i32; 2] {
+        let extent = f64::from(DEFAULT_MVT_EXTENT);
+        // Keep far away coordinates within a range that cannot overflow
+        let limit = extent * 16.0;
+        let [x, y] = self.srs.to_mercator(coord);
+        let px = ((x - self.bbox[0]) / self.size * extent).clamp(-limit, limit);
+        let py = ((self.bbox[3] - y) / self.size * extent).clamp(-limit, limit);
+        // Values are clamped to a small range above
+        #[allow(clippy::cast_possible_truncation)]
+        let point = [px.round() as i32, py.round() as i32];
+        point
+    }
+}
+
+/// Check if two bounding boxes given as `[min_x, min_y, max_x, max_y]` intersect
+#[must_use]
+pub fn intersects(bbox: Option<[f64; 4]>, other: [f64; 4]) -> bool {
+    bbox.map_or(false, |b| {
+        b[0] <= other[2] && b[2] >= other[0] && b[1] <= other[3] && b[3] >= other[1]
+    })
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Tile {
+    #[prost(message, repeated, tag = "3")]
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Layer {
+    #[prost(uint32, required, tag = "15", default = "1")]
+    pub version: u32,
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub features: Vec<Feature>,
+    #[prost(string, repeated, tag = "3")]
+    pub keys: Vec<String>,
+    #[prost(message, repeated, tag = "4")]
+    pub values: Vec<Value>,
+    #[prost(uint32, optional, tag = "5", default = "4096")]
+    pub extent: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Feature {
+    #[prost(uint64, optional, tag = "1")]
+    pub id: Option<u64>,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    pub tags: Vec<u32>,
+    #[prost(enumeration = "GeomType", optional, tag = "3")]
+    pub r#type: Option<i32>,
+    #[prost(uint32, repeated, packed = "true", tag = "4")]
+    pub geometry: Vec<u32>,
+}
+
+// field names follow the vector tile specification
+#[allow(clippy::struct_field_names)]
+#[derive(Clone, PartialEq, Message)]
+pub struct Value {
+    #[prost(string, optional, tag = "1")]
+    pub string_value: Option<String>,
+    #[prost(float, optional, tag = "2")]
+    pub float_value: Option<f32>,
+    #[prost(double, optional, tag = "3")]
+    pub double_value: Option<f64>,
+    #[prost(int64, optional, tag = "4")]
+    pub int_value: Option<i64>,
+    #[prost(uint64, optional, tag = "5")]
+    pub uint_value: Option<u64>,
+    #[prost(sint64, optional, tag = "6")]
+    pub sint_value: Option<i64>,
+    #[prost(bool, optional, tag = "7")]
+    pub bool_value: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum GeomType {
+    Unknown = 0,
+    Point = 1,
+    LineString = 2,
+    Polygon = 3,
+}
+
+/// Property value of a feature
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl PropValue {
+    fn to_value(&self) -> Value {
+        match self {
+            Self::String(v) => Value {
+                string_value: Some(v.clone()),
+                ..Value::default()
+            },
+            Self::Int(v) => Value {
+                sint_value: Some(*v),
+                ..Value::default()
+            },
+            Self::Double(v) => Value {
+                double_value: Some(*v),
+                ..Value::default()
+            },
+            Self::Bool(v) => Value {
+                bool_value: Some(*v),
+                ..Value::default()
+            },
+        }
+    }
+
+    /// Hashable representation, used to store each distinct value only once
+    fn key(&self) -> (u8, String) {
+        match self {
+            Self::String(v) => (0, v.clone()),
+            Self::Int(v) => (1, v.to_string()),
+            Self::Double(v) => (2, v.to_bits().to_string()),
+            Self::Bool(v) => (3, v.to_string()),
+        }
+    }
+}
+
+/// Geometry in tile coordinates, with Y going down
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileGeometry {
+    Points(Vec<[i32; 2]>),
+    Lines(Vec<Vec<[i32; 2]>>),
+    /// Each polygon is a list of rings, starting with the exterior ring
+    Polygons(Vec<Vec<Vec<[i32; 2]>>>),
+}
+
+/// Builds a layer, storing each property key and value only once
+#[derive(Debug)]
+pub struct LayerBuilder {
+    layer: Layer,
+    keys: HashMap<String, u32>,
+    values: HashMap<(u8, String), u32>,
+}
+
+impl LayerBuilder {
+    #[must_use]
+    pub fn new(name: &str, extent: u32) -> Self {
+        Self {
+            layer: Layer {
+                version: 2,
+                name: name.to_string(),
+                extent: Some(extent),
+                ..Layer::default()
+            },
+            keys: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// Add a feature, unless its geometry is empty after encoding
+    pub fn add_feature(
+        &mut self,
+        id: Option<u64>,
+        geom: &TileGeometry,
+        props: &[(String, PropValue)],
+    ) {
+        let Some((geom_type, geometry)) = encode_geometry(geom) else {
+            return;
+        };
+        let mut tags = Vec::with_capacity(props.len() * 2);
+        for (key, value) in props {
+            let next = self.layer.keys.len();
+            let key_idx = *self.keys.entry(key.clone()).or_insert_with(|| {
+                self.layer.keys.push(key.clone());
+                index(next)
+            });
+            let next = self.layer.values.len();
+            let value_idx = *self.values.entry(value.key()).or_insert_with(|| {
+                self.layer.values.push(value.to_value());
+                index(next)
+            });
+            tags.push(key_idx);
+            tags.push(value_idx);
+        }
+        self.layer.features.push(Feature {
+            id,
+            tags,
+            r#type: Some(geom_type as i32),
+            geometry,
+        });
+    }
+
+    #[must_use]
+    pub fn build(self) -> Layer {
+        self.layer
+    }
+}
+
+/// Encode all non-empty layers into a tile
+#[must_use]
+pub fn encode_tile(layers: Vec<Layer>) -> Vec<u8> {
+    let layers = layers
+        .into_iter()
+        .filter(|l| !l.features.is_empty())
+        .collect();
+    Tile { layers }.encode_to_vec()
+}
+
+impl Value {
+    /// Get the value as JSON, or `None` if it is not set or is not a finite number
+    #[must_use]
+    pub fn to_json(&self) -> Option<serde_json::Value> {
+        let number = |v: f64| serde_json::Number::from_f64(v).map(serde_json::Value::Number);
+        if let Some(v) = &self.string_value {
+            Some(v.clone().into())
+        } else if let Some(v) = self.float_value {
+            number(f64::from(v))
+        } else if let Some(v) = self.double_value {
+            number(v)
+        } else if let Some(v) = self.int_value.or(self.sint_value) {
+            Some(v.into())
+        } else if let Some(v) = self.uint_value {
+            Some(v.into())
+        } else {
+            self.bool_value.map(Into::into)
+        }
+    }
+}
+
+impl Feature {
+    /// Get the property names and values, skipping the invalid tags
+    pub fn properties<'a>(
+        &'a self,
+        layer: &'a Layer,
+    ) -> impl Iterator<Item = (&'a str, &'a Value)> + 'a {
+        self.tags.chunks_exact(2).filter_map(|tag| {
+            let key = layer.keys.get(usize::try_from(tag[0]).ok()?)?;
+            let value = layer.values.get(usize::try_from(tag[1]).ok()?)?;
+            Some((key.as_str(), value))
+        })
+    }
+}
+
+fn index(value: usize) -> u32 {
+    u32::try_from(value).expect("too many keys or values in a layer")
+}
+
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+fn command(id: u32, count: usize) -> u32 {
+    (id & 0x7) | (index(count) << 3)
+}
+
+fn zigzag(value: i32) -> u32 {
+    // the standard protobuf zigzag encoding, bits are reinterpreted on purpose
+    #[allow(clippy::cast_sign_loss)]
+    let value = ((value << 1) ^ (value >> 31)) as u32;
+    value
+}
+
+/// Geometry command stream writer that keeps track of the cursor position
+#[derive(Default)]
+struct GeomWriter {
+    data: Vec<u32>,
+    cursor: [i32; 2],
+}
+
+impl GeomWriter {
+    fn push_point(&mut self, point: [i32; 2]) {
+        self.data.push(zigzag(point[0] - self.cursor[0]));
+        self.data.push(zigzag(point[1] - self.cursor[1]));
+        self.cursor = point;
+    }
+
+    /// Write a line, or a ring if `close` is set. The repeated closing point of a ring is skipped.
+    fn push_path(&mut self, points: &[[i32; 2]], close: bool) {
+        let points = if close {
+            &points[..points.len() - 1]
+        } else {
+            points
+        };
+        self.data.push(command(MOVE_TO, 1));
+        self.push_point(points[0]);
+        self.data.push(command(LINE_TO, points.len() - 1));
+        for point in &points[1..] {
+            self.push_point(*point);
+        }
+        if close {
+            self.data.push(command(CLOSE_PATH, 1));
+        }
+    }
+}
+
+/// Remove consecutive duplicate points that appear after rounding to tile coordinates
+fn dedup(points: &[[i32; 2]]) -> Vec<[i32; 2]> {
+    let mut result = points.to_vec();
+    result.dedup();
+    result
+}
+
+/// Twice the signed area of a ring, positive for the clockwise rings in tile coordinates
+fn signed_area(ring: &[[i32; 2]]) -> i64 {
+    ring.windows(2)
+        .map(|w| i64::from(w[0][0]) * i64::from(w[1][1]) - i64::from(w[1][0]) * i64::from(w[0][1]))
+        .sum()
+}
+
+/// Encode the geometry as MVT commands, fixing the ring winding order as required by the spec.
+/// Returns `None` if nothing is left after removing degenerate parts.
+#[must_use]
+pub fn encode_geometry(geom: &TileGeometry) -> Option<(GeomType, Vec<u32>)> {
+    let mut writer = GeomWriter::default();
+    let geom_type = match geom {
+        TileGeometry::Points(points) => {
+            if points.is_empty() {
+                return None;
+            }
+            writer.data.push(command(MOVE_TO, points.len()));
+            for point in points {
+                writer.push_point(*point);
+            }
+            GeomType::Point
+        }
+        TileGeometry::Lines(lines) => {
+            for line in lines {
+                let line = dedup(line);
+                if line.len() >= 2 {
+                    writer.push_path(&line, false);
+                }
+            }
+            GeomType::LineString
+        }
+        TileGeometry::Polygons(polygons) => {
+            for polygon in polygons {
+                for (idx, ring) in polygon.iter().enumerate() {
+                    let mut ring = dedup(ring);
+                    if ring.first() != ring.last() {
+                        ring.push(ring[0]);
+                    }
+                    let area = signed_area(&ring);
+                    if ring.len() < 4 || area == 0 {
+                        if idx == 0 {
+                            // without the exterior ring, the holes are meaningless
+                            break;
+                        }
+                        continue;
+                    }
+                    // exterior rings must be clockwise (positive area), and holes counterclockwise
+                    if (idx == 0) != (area > 0) {
+                        ring.reverse();
+                    }
+                    writer.push_path(&ring, true);
+                }
+            }
+            GeomType::Polygon
+        }
+    };
+    (!writer.data.is_empty()).then_some((geom_type, writer.data))
+}
+
+fn unzigzag(value: u32) -> i32 {
+    // the inverse of `zigzag`, bits are reinterpreted on purpose
+    #[allow(clippy::cast_possible_wrap)]
+    let value = ((value >> 1) as i32) ^ -((value & 1) as i32);
+    value
+}
+
+/// Decode the MVT geometry commands. Polygon rings are grouped by their winding order,
+/// with each ring wound like the first one starting a new polygon, so that the tiles with
+/// the reversed winding order are still decoded correctly. Returns `None` for invalid or unknown geometries.
+#[must_use]
+pub fn decode_geometry(geom_type: GeomType, data: &[u32]) -> Option<TileGeometry> {
+    let mut paths: Vec<Vec<[i32; 2]>> = Vec::new();
+    let mut cursor = [0_i32; 2];
+    let mut iter = data.iter();
+    while let Some(cmd) = iter.next() {
+        let (id, count) = (cmd & 0x7, cmd >> 3);
+        match id {
+            MOVE_TO | LINE_TO => {
+                for idx in 0..count {
+                    let dx = unzigzag(*iter.next()?);
+                    let dy = unzigzag(*iter.next()?);
+                    cursor = [cursor[0].wrapping_add(dx), cursor[1].wrapping_add(dy)];
+                    // each point of a multipoint is stored as a separate MoveTo
+                    if id == MOVE_TO && (idx == 0 || geom_type == GeomType::Point) {
+                        paths.push(Vec::new());
+                    }
+                    paths.last_mut()?.push(cursor);
+                }
+            }
+            CLOSE_PATH => {
+                let path = paths.last_mut()?;
+                path.push(*path.first()?);
+            }
+            _ => None?,
+        }
+    }
+    Some(match geom_type {
+        GeomType::Point => TileGeometry::Points(paths.into_iter().flatten().collect()),
+        GeomType::LineString => TileGeometry::Lines(paths),
+        GeomType::Polygon => {
+            let mut polygons: Vec<Vec<Vec<[i32; 2]>>> = Vec::new();
+            let clockwise = paths.first().map(|ring| signed_area(ring) > 0);
+            for ring in paths {
+                if polygons.is_empty() || Some(signed_area(&ring) > 0) == clockwise {
+                    polygons.push(vec![ring]);
+                } else {
+                    polygons.last_mut()?.push(ring);
+                }
+            }
+            TileGeometry::Polygons(polygons)
+        }
+        GeomType::Unknown => None?,
+    })
+}
+
+/// Repair the geometries of an uncompressed vector tile: fix the ring winding order, remove the
+/// repeated points and the degenerate lines and rings, and drop the features that have no valid geometry left.
+/// Returns the repaired tile and the number of repaired features, or `None` if all features were valid.
+pub fn make_valid(data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, prost::DecodeError> {
+    let mut tile = Tile::decode(data)?;
+    let mut repaired = 0;
+    for layer in &mut tile.layers {
+        layer.features.retain_mut(|feature| {
+            let Some(geom_type) = feature.r#type.and_then(|v| GeomType::try_from(v).ok()) else {
+                repaired += 1;
+                return false;
+            };
+            let geom = decode_geometry(geom_type, &feature.geometry);
+            let Some((_, encoded)) = geom.as_ref().and_then(encode_geometry) else {
+                repaired += 1;
+                return false;
+            };
+            // the same geometry may be encoded with different commands, so compare the decoded values
+            if decode_geometry(geom_type, &encoded) != geom {
+                repaired += 1;
+                feature.geometry = encoded;
+            }
+            true
+        });
+    }
+    Ok((repaired > 0).then(|| (encode_tile(tile.layers), repaired)))
+}
+
+/// Tile with the layers kept as encoded, so that they can be filtered without decoding the features
+#[derive(Clone, PartialEq, Message)]
+struct RawTile {
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    layers: Vec<Vec<u8>>,
+}
+
+/// Only the name of a layer, skipping everything else while decoding
+#[derive(Clone, PartialEq, Message)]
+struct LayerName {
+    #[prost(string, required, tag = "1")]
+    name: String,
+}
+
+/// Keep only the named layers of an uncompressed vector tile. The kept layers are copied as is.
+/// Returns `None` if all layers are kept.
+pub fn filter_layers(data: &[u8], names: &[&str]) -> Result<Option<Vec<u8>>, prost::DecodeError> {
+    let mut tile = RawTile::decode(data)?;
+    let count = tile.layers.len();
+    let mut result = Ok(());
+    tile.layers
+        .retain(|layer| match LayerName::decode(layer.as_slice()) {
+            Ok(layer) => names.contains(&layer.name.as_str()),
+            Err(e) => {
+                result = Err(e);
+                false
+            }
+        });
+    result?;
+    Ok((tile.layers.len() < count).then(|| tile.encode_to_vec()))
+}
+
+/// Keep only the feature properties whose key passes the `keep` check in all layers of an uncompressed
+/// vector tile, dropping the keys and the values that are no longer used. Feature IDs are always kept.
+/// Returns `None` if all properties are kept.
+pub fn filter_properties(
+    data: &[u8],
+    keep: impl Fn(&str) -> bool,
+) -> Result<Option<Vec<u8>>, prost::DecodeError> {
+    let mut tile = Tile::decode(data)?;
+    let mut changed = false;
+    for layer in &mut tile.layers {
+        let kept_keys: Vec<bool> = layer.keys.iter().map(|k| keep(k)).collect();
+        if kept_keys.iter().all(|v| *v) {
+            continue;
+        }
+        changed = true;
+        let mut key_index = HashMap::new();
+        let mut value_index = HashMap::new();
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for feature in &mut layer.features {
+            let mut tags = Vec::with_capacity(feature.tags.len());
+            for pair in feature.tags.chunks_exact(2) {
+                let (key, value) = (pair[0] as usize, pair[1] as usize);
+                if !kept_keys.get(key).copied().unwrap_or(false) || value >= layer.values.len() {
+                    continue;
+                }
+                let key = *key_index.entry(key).or_insert_with(|| {
+                    keys.push(layer.keys[key].clone());
+                    index(keys.len() - 1)
+                });
+                let value = *value_index.entry(value).or_insert_with(|| {
+                    values.push(layer.values[value].clone());
+                    index(values.len() - 1)
+                });
+                tags.extend([key, value]);
+            }
+            feature.tags = tags;
+        }
+        layer.keys = keys;
+        layer.values = values;
+    }
+    Ok(changed.then(|| tile.encode_to_vec()))
+}
+
+/// Generate a tile from one of its ancestors at a lower zoom, by scaling the geometries of the parent tile
+/// and clipping them to the area of the tile with a buffer. Feature IDs and properties are kept as is.
+pub fn overzoom_tile(
+    data: &[u8],
+    parent: TileCoord,
+    xyz: TileCoord,
+) -> Result<Vec<u8>, prost::DecodeError> {
+    let mut tile = Tile::decode(data)?;
+    let dz = xyz.z - parent.z;
+    let scale = f64::from(1_u32 << dz);
+    for layer in &mut tile.layers {
+        let extent = f64::from(layer.extent.unwrap_or(DEFAULT_MVT_EXTENT));
+        let offset = [
+            f64::from(xyz.x - (parent.x << dz)) * extent,
+            f64::from(xyz.y - (parent.y << dz)) * extent,
+        ];
+        let buffer = extent * MVT_BUFFER / f64::from(DEFAULT_MVT_EXTENT);
+        let clip = [-buffer, -buffer, extent + buffer, extent + buffer];
+        let transform = |p: &[i32; 2]| {
+            [
+                f64::from(p[0]) * scale - offset[0],
+                f64::from(p[1]) * scale - offset[1],
+            ]
+        };
+        layer.features.retain_mut(|feature| {
+            let Some(geom_type) = feature.r#type.and_then(|v| GeomType::try_from(v).ok()) else {
+                return false;
+            };
+            let Some(geom) = decode_geometry(geom_type, &feature.geometry) else {
+                return false;
+            };
+            let geom = match geom {
+                TileGeometry::Points(points) => TileGeometry::Points(
+                    points
+                        .iter()
+                        .map(transform)
+                        .filter(|p| inside(*p, clip))
+                        .map(round_point)
+                        .collect(),
+                ),
+                TileGeometry::Lines(lines) => TileGeometry::Lines(
+                    lines
+                        .iter()
+                        .flat_map(|line| {
+                            let line: Vec<_> = line.iter().map(transform).collect();
+                            clip_line(&line, clip)
+                        })
+                        .map(|line| line.into_iter().map(round_point).collect())
+                        .collect(),
+                ),
+                TileGeometry::Polygons(polygons) => TileGeometry::Polygons(
+                    polygons
+                        .iter()
+                        .map(|polygon| {
+                            polygon
+                                .iter()
+                                .map(|ring| {
+                                    let ring: Vec<_> = ring.iter().map(transform).collect();
+                                    clip_ring(&ring, clip)
+                                        .into_iter()
+                                        .map(round_point)
+                                        .collect()
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                ),
+            };
+            match encode_geometry(&geom) {
+                Some((_, geometry)) => {
+                    feature.geometry = geometry;
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+    Ok(encode_tile(tile.layers))
+}
+
+fn inside(point: [f64; 2], bbox: [f64; 4]) -> bool {
+    point[0] >= bbox[0] && point[0] <= bbox[2] && point[1] >= bbox[1] && point[1] <= bbox[3]
+}
+
+fn round_point(point: [f64; 2]) -> [i32; 2] {
+    // the points are clipped to the tile buffer, so they always fit
+    #[allow(clippy::cast_possible_truncation)]
+    let point = [point[0].round() as i32, point[1].round() as i32];
+    point
+}
+
+/// Clip a line to the bounding box with the Liang-Barsky algorithm, splitting it where it leaves the box
+fn clip_line(line: &[[f64; 2]], bbox: [f64; 4]) -> Vec<Vec<[f64; 2]>> {
+    let mut result: Vec<Vec<[f64; 2]>> = Vec::new();
+    let mut current: Vec<[f64; 2]> = Vec::new();
+    for seg in line.windows(2) {
+        let ([x0, y0], [x1, y1]) = (seg[0], seg[1]);
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+        let edges = [
+            (-dx, x0 - bbox[0]),
+            (dx, bbox[2] - x0),
+            (-dy, y0 - bbox[1]),
+            (dy, bbox[3] - y0),
+        ];
+        let visible = edges.iter().all(|&(p, q)| {
+            if p == 0.0 {
+                return q >= 0.0;
+            }
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+            t0 <= t1
+        });
+        if !visible {
+            if !current.is_empty() {
+                result.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        let start = [x0 + t0 * dx, y0 + t0 * dy];
+        if current.is_empty() {
+            current.push(start);
+        }
+        current.push([x0 + t1 * dx, y0 + t1 * dy]);
+        // the line leaves the box within this segment
+        if t1 < 1.0 {
+            result.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+/// Clip a closed ring to the bounding box with the Sutherland-Hodgman algorithm
+fn clip_ring(ring: &[[f64; 2]], bbox: [f64; 4]) -> Vec<[f64; 2]> {
+    // the edges of the box as (axis, limit, keep the values below the limit)
+    let edges = [
+        (0, bbox[0], false),
+        (0, bbox[2], true),
+        (1, bbox[1], false),
+        (1, bbox[3], true),
+    ];
+    let mut points: Vec<[f64; 2]> = ring.to_vec();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    for (axis, limit, below) in edges {
+        let is_in = |p: &[f64; 2]| {
+            if below {
+                p[axis] <= limit
+            } else {
+                p[axis] >= limit
+            }
+        };
+        let mut clipped = Vec::with_capacity(points.len());
+        for (idx, cur) in points.iter().enumerate() {
+            let prev = &points[(idx + points.len() - 1) % points.len()];
+            if is_in(cur) != is_in(prev) {
+                let t = (limit - prev[axis]) / (cur[axis] - prev[axis]);
+                clipped.push([
+                    prev[0] + t * (cur[0] - prev[0]),
+                    prev[1] + t * (cur[1] - prev[1]),
+                ]);
+            }
+            if is_in(cur) {
+                clipped.push(*cur);
+            }
+        }
+        points = clipped;
+        if points.is_empty() {
+            return points;
+        }
+    }
+    points.push(points[0]);
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mercator() {
+        let [x, y] = wgs84_to_mercator(180.0, 0.0);
+        assert!((x - MERCATOR_MAX).abs() < 1e-6);
+        assert!(y.abs() < 1e-6);
+        let [lon, lat] = mercator_to_wgs84(1_000_000.0, 2_000_000.0);
+        let [x, y] = wgs84_to_mercator(lon, lat);
+        assert!((x - 1_000_000.0).abs() < 1e-6);
+        assert!((y - 2_000_000.0).abs() < 1e-6);
+        let bbox = tile_bbox(TileCoord { z: 1, x: 1, y: 0 });
+        let expected = [0.0, 0.0, MERCATOR_MAX, MERCATOR_MAX];
+        assert!(bbox.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn geometry_encoding() {
+        // examples from the vector tile specification
+        let point = TileGeometry::Points(vec![[25, 17]]);
+        assert_eq!(
+            encode_geometry(&point),
+            Some((GeomType::Point, vec![9, 50, 34]))
+        );
+        let line = TileGeometry::Lines(vec![vec![[2, 2], [2, 10], [10, 10]]]);
+        assert_eq!(
+            encode_geometry(&line),
+            Some((GeomType::LineString, vec![9, 4, 4, 18, 0, 16, 16, 0]))
+        );
+        // counterclockwise ring is reversed to become clockwise
+        let polygon = TileGeometry::Polygons(vec![vec![vec![[3, 6], [8, 12], [20, 34], [3, 6]]]]);
+        let reversed = TileGeometry::Polygons(vec![vec![vec![[3, 6], [20, 34], [8, 12], [3, 6]]]]);
+        assert_eq!(
+            encode_geometry(&polygon).unwrap().1,
+            encode_geometry(&reversed).unwrap().1
+        );
+        assert_eq!(
+            encode_geometry(&TileGeometry::Lines(vec![vec![[1, 1], [1, 1]]])),
+            None
+        );
+    }
+
+    #[test]
+    fn geometry_decoding() {
+        let geoms = [
+            TileGeometry::Points(vec![[25, 17]]),
+            TileGeometry::Points(vec![[5, 7], [3, 2]]),
+            TileGeometry::Lines(vec![vec![[2, 2], [2, 10], [10, 10]], vec![[1, 1], [3, 5]]]),
+            TileGeometry::Polygons(vec![
+                vec![
+                    vec![[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+                    vec![[11, 11], [12, 11], [12, 12], [11, 12], [11, 11]]
+                        .into_iter()
+                        .rev()
+                        .collect(),
+                ],
+                vec![vec![[20, 20], [30, 20], [30, 30], [20, 20]]],
+            ]),
+        ];
+        for geom in geoms {
+            let (geom_type, data) = encode_geometry(&geom).unwrap();
+            assert_eq!(decode_geometry(geom_type, &data), Some(geom));
+        }
+        assert_eq!(decode_geometry(GeomType::Point, &[9, 50]), None);
+        assert_eq!(unzigzag(zigzag(-5)), -5);
+        assert_eq!(unzigzag(zigzag(i32::MAX)), i32::MAX);
+    }
+
+    #[test]
+    fn repair_tile() {
+        let mut layer = LayerBuilder::new("test", DEFAULT_MVT_EXTENT);
+        let square = vec![[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]];
+        let polygon = TileGeometry::Polygons(vec![vec![square.clone()]]);
+        layer.add_feature(Some(1), &polygon, &[]);
+        layer.add_feature(Some(2), &TileGeometry::Points(vec![[1, 1]]), &[]);
+        let mut layer = layer.build();
+        let valid = encode_tile(vec![layer.clone()]);
+        assert_eq!(make_valid(&valid).unwrap(), None);
+
+        // counterclockwise exterior ring, a degenerate line, and a feature without a type
+        let mut writer = GeomWriter::default();
+        writer.push_path(&square.iter().rev().copied().collect::<Vec<_>>(), true);
+        layer.features[0].geometry = writer.data;
+        layer.features.push(Feature {
+            id: Some(3),
+            r#type: Some(GeomType::LineString as i32),
+            geometry: vec![9, 2, 2, 10, 0, 0],
+            ..Feature::default()
+        });
+        layer.features.push(Feature {
+            id: Some(4),
+            ..layer.features[1].clone()
+        });
+        layer.features[3].r#type = None;
+
+        let (data, repaired) = make_valid(&encode_tile(vec![layer])).unwrap().unwrap();
+        assert_eq!(repaired, 3);
+        assert_eq!(data, valid);
+    }
+
+    #[test]
+    fn layer_filter() {
+        let layer = |name| {
+            let mut layer = LayerBuilder::new(name, DEFAULT_MVT_EXTENT);
+            layer.add_feature(Some(1), &TileGeometry::Points(vec![[1, 1]]), &[]);
+            layer.build()
+        };
+        // composite tiles are concatenated, so the same layer may appear more than once
+        let data = [
+            encode_tile(vec![layer("water"), layer("roads")]),
+            encode_tile(vec![layer("water"), layer("pois")]),
+        ]
+        .concat();
+
+        let filtered = filter_layers(&data, &["water", "buildings"])
+            .unwrap()
+            .unwrap();
+        let tile = Tile::decode(filtered.as_slice()).unwrap();
+        assert_eq!(tile.layers, vec![layer("water"), layer("water")]);
+        assert_eq!(
+            filter_layers(&data, &["pois", "roads", "water"]).unwrap(),
+            None
+        );
+        assert_eq!(
+            filter_layers(&data, &["buildings"]).unwrap(),
+            Some(Vec::new())
+        );
+        assert!(filter_layers(&[0x1a, 0x02, 0x08], &["water"]).is_err());
+    }
+
+    #[test]
+    fn overzoom() {
+        let mut layer = LayerBuilder::new("test", DEFAULT_MVT_EXTENT);
+        let square = vec![
+            [1000, 1000],
+            [3000, 1000],
+            [3000, 3000],
+            [1000, 3000],
+            [1000, 1000],
+        ];
+        let points = vec![[100, 100], [1500, 3000]];
+        layer.add_feature(Some(1), &TileGeometry::Polygons(vec![vec![square]]), &[]);
+        layer.add_feature(Some(2), &TileGeometry::Points(points), &[]);
+        let lines = vec![vec![[0, 2500], [4000, 2500]], vec![[0, 1000], [4000, 1000]]];
+        layer.add_feature(Some(3), &TileGeometry::Lines(lines), &[]);
+        layer.add_feature(Some(4), &TileGeometry::Points(vec![[3900, 3900]]), &[]);
+        let data = encode_tile(vec![layer.build()]);
+
+        // the lower left quarter of the parent tile is scaled twice and clipped to the buffer
+        let parent = TileCoord { z: 5, x: 10, y: 20 };
+        let xyz = TileCoord { z: 6, x: 20, y: 41 };
+        let tile = Tile::decode(overzoom_tile(&data, parent, xyz).unwrap().as_slice()).unwrap();
+        let features = &tile.layers[0].features;
+        assert_eq!(
+            features.iter().map(|f| f.id).collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(3)]
+        );
+        let decode = |f: &Feature| {
+            let geom_type = GeomType::try_from(f.r#type.unwrap()).unwrap();
+            decode_geometry(geom_type, &f.geometry).unwrap()
+        };
+        let TileGeometry::Polygons(polygons) = decode(&features[0]) else {
+            panic!("not a polygon");
+        };
+        let ring = &polygons[0][0];
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring.iter().map(|p| p[0]).min(), Some(2000));
+        assert_eq!(ring.iter().map(|p| p[0]).max(), Some(4160));
+        assert_eq!(ring.iter().map(|p| p[1]).min(), Some(-64));
+        assert_eq!(ring.iter().map(|p| p[1]).max(), Some(1904));
+        assert_eq!(
+            decode(&features[1]),
+            TileGeometry::Points(vec![[3000, 1904]])
+        );
+        assert_eq!(
+            decode(&features[2]),
+            TileGeometry::Lines(vec![vec![[0, 904], [4160, 904]]])
+        );
+    }
+
+    #[test]
+    fn clip_lines() {
+        let bbox = [0.0, 0.0, 10.0, 10.0];
+        let line = [
+            [-5.0, 5.0],
+            [5.0, 5.0],
+            [5.0, 15.0],
+            [8.0, 15.0],
+            [8.0, 5.0],
+        ];
+        assert_eq!(
+            clip_line(&line, bbox),
+            vec![
+                vec![[0.0, 5.0], [5.0, 5.0], [5.0, 10.0]],
+                vec![[8.0, 10.0], [8.0, 5.0]]
+            ]
+        );
+        assert!(clip_line(&[[11.0, 0.0], [20.0, 5.0]], bbox).is_empty());
+        let ring = [
+            [-5.0, -5.0],
+            [5.0, -5.0],
+            [5.0, 5.0],
+            [-5.0, 5.0],
+            [-5.0, -5.0],
+        ];
+        assert_eq!(
+            clip_ring(&ring, bbox),
+            vec![[0.0, 0.0], [5.0, 0.0], [5.0, 5.0], [0.0, 5.0], [0.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn property_filter() {
+        let mut layer = LayerBuilder::new("test", DEFAULT_MVT_EXTENT);
+        let point = TileGeometry::Points(vec![[1, 1]]);
+        let props = vec![
+            ("name".to_string(), PropValue::String("a".to_string())),
+            ("import_id".to_string(), PropValue::Int(7)),
+            ("class".to_string(), PropValue::String("b".to_string())),
+        ];
+        layer.add_feature(Some(1), &point, &props);
+        layer.add_feature(Some(2), &point, &props[1..2]);
+        let data = encode_tile(vec![layer.build()]);
+
+        assert_eq!(filter_properties(&data, |_| true).unwrap(), None);
+        let filtered = filter_properties(&data, |k| k != "import_id")
+            .unwrap()
+            .unwrap();
+        let tile = Tile::decode(filtered.as_slice()).unwrap();
+        let layer = &tile.layers[0];
+        assert_eq!(layer.keys, vec!["name", "class"]);
+        assert_eq!(layer.values.len(), 2);
+        assert_eq!(layer.features[0].id, Some(1));
+        let props: Vec<_> = layer.features[0]
+            .properties(layer)
+            .map(|(k, v)| (k, v.to_json()))
+            .collect();
+        assert_eq!(
+            props,
+            vec![("name", Some("a".into())), ("class", Some("b".into()))]
+        );
+        assert!(layer.features[1].tags.is_empty());
+    }
+
+    #[test]
+    fn layer_values() {
+        let mut layer = LayerBuilder::new("test", DEFAULT_MVT_EXTENT);
+        let props = vec![
+            ("name".to_string(), PropValue::String("a".to_string())),
+            ("count".to_string(), PropValue::Int(1)),
+        ];
+        let point = TileGeometry::Points(vec![[1, 1]]);
+        layer.add_feature(Some(1), &point, &props);
+        layer.add_feature(Some(2), &point, &props[..1]);
+        let layer = layer.build();
+        assert_eq!(layer.keys, vec!["name", "count"]);
+        assert_eq!(layer.values.len(), 2);
+        assert_eq!(layer.features[1].tags, vec![0, 0]);
+
+        let props: Vec<_> = layer.features[0]
+            .properties(&layer)
+            .map(|(k, v)| (k, v.to_json()))
+            .collect();
+        assert_eq!(
+            props,
+            vec![("name", Some("a".into())), ("count", Some(1.into()))]
+        );
+
+        let tile = Tile::decode(encode_tile(vec![layer.clone()]).as_slice()).unwrap();
+        assert_eq!(tile.layers, vec![layer]);
+    }
+}
@@ -0,0 +1,399 @@
+use std::io::{Cursor, Read as _};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use itertools::Itertools as _;
+use log::{debug, info};
+use regex::Regex;
+use reqwest::Url;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RemoteError {
+    #[error("Unable to download {0}: {1}")]
+    DownloadError(String, String),
+
+    #[error("IO error {0}: {}", .1.display())]
+    IoError(std::io::Error, PathBuf),
+}
+
+use RemoteError::DownloadError;
+
+/// How the files of a remote source, e.g. the SVG files of a sprite source, are downloaded, detected from its URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RemoteKind {
+    /// A repository cloned with `git`, given with a `git+` prefix, e.g. `git+https://host/icons.git`
+    Git,
+    /// A `.zip` archive
+    Zip,
+    /// A `.tar` archive
+    Tar,
+    /// A `.tar.gz` or `.tgz` archive
+    TarGz,
+    /// A single file with one of the expected extensions
+    File,
+    /// An HTML directory listing, all linked files with the expected extensions are downloaded
+    Listing,
+}
+
+/// Remote sources are given as `http://`, `https://`, or `git+...` URLs instead of local paths
+#[must_use]
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str().map_or(false, |p| {
+        p.starts_with("http://") || p.starts_with("https://") || p.starts_with("git+")
+    })
+}
+
+/// Split the URL into the location to download, and the optional subdirectory
+/// with the files given as the URL fragment, e.g. `https://host/icons.zip#icons/svg`
+fn split_url(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('#') {
+        Some((url, subdir)) if !subdir.is_empty() => (url, Some(subdir)),
+        Some((url, _)) => (url, None),
+        None => (url, None),
+    }
+}
+
+#[allow(clippy::case_sensitive_file_extension_comparisons)] // the path is lowercased
+fn remote_kind(url: &str, extensions: &[&str]) -> RemoteKind {
+    let path = url.split('?').next().unwrap_or(url).to_ascii_lowercase();
+    if url.starts_with("git+") {
+        RemoteKind::Git
+    } else if path.ends_with(".zip") {
+        RemoteKind::Zip
+    } else if path.ends_with(".tar") {
+        RemoteKind::Tar
+    } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        RemoteKind::TarGz
+    } else if has_extension(&path, extensions) {
+        RemoteKind::File
+    } else {
+        RemoteKind::Listing
+    }
+}
+
+/// ID of a remote source given without an ID, i.e. the name of the downloaded
+/// file or directory without its extension
+#[must_use]
+pub fn remote_id(url: &str) -> Option<String> {
+    let (url, _) = split_url(url);
+    let path = url.split('?').next().unwrap_or(url).trim_end_matches('/');
+    let name = path.rsplit('/').next()?;
+    let name = [".git", ".zip", ".tar.gz", ".tgz", ".tar"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name);
+    (!name.is_empty() && !name.contains(':')).then(|| name.to_string())
+}
+
+/// Directory the remote source of the given kind, e.g. `sprites`, and with the given ID is downloaded to
+#[must_use]
+pub fn download_dir(kind: &str, id: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("martin-{kind}-{}", std::process::id()))
+        .join(id)
+}
+
+/// Download the files with the given extensions of a remote source into `dir`, replacing its previous
+/// content, and return the directory with the files. Git repositories are cloned as they are.
+pub async fn download(url: &str, dir: &Path, extensions: &[&str]) -> Result<PathBuf, RemoteError> {
+    let (location, subdir) = split_url(url);
+    let on_err = |e: String| DownloadError(url.to_string(), e);
+    let tmp = dir.with_extension("download");
+    if tmp.exists() {
+        tokio::fs::remove_dir_all(&tmp)
+            .await
+            .map_err(|e| RemoteError::IoError(e, tmp.clone()))?;
+    }
+    tokio::fs::create_dir_all(&tmp)
+        .await
+        .map_err(|e| RemoteError::IoError(e, tmp.clone()))?;
+
+    let kind = remote_kind(location, extensions);
+    debug!("Downloading {url} as {kind:?} into {}", dir.display());
+    match kind {
+        RemoteKind::Git => clone_repository(&location["git+".len()..], &tmp).await,
+        RemoteKind::Zip => extract_zip(&fetch(location).await?, &tmp, extensions),
+        RemoteKind::Tar => extract_tar(&fetch(location).await?, &tmp, extensions),
+        RemoteKind::TarGz => {
+            let mut data = Vec::new();
+            GzDecoder::new(fetch(location).await?.as_slice())
+                .read_to_end(&mut data)
+                .map_err(|e| e.to_string())
+                .and_then(|_| extract_tar(&data, &tmp, extensions))
+        }
+        RemoteKind::File => {
+            let path = location.split('?').next().unwrap_or(location);
+            let name = path.rsplit('/').next().unwrap_or_default();
+            write_file(&tmp, name, &fetch(location).await?, extensions)
+        }
+        RemoteKind::Listing => download_listing(location, &tmp, extensions).await,
+    }
+    .map_err(on_err)?;
+
+    if dir.exists() {
+        tokio::fs::remove_dir_all(dir)
+            .await
+            .map_err(|e| RemoteError::IoError(e, dir.to_path_buf()))?;
+    }
+    tokio::fs::rename(&tmp, dir)
+        .await
+        .map_err(|e| RemoteError::IoError(e, dir.to_path_buf()))?;
+
+    let path = match subdir {
+        Some(subdir) => dir.join(
+            safe_path(subdir)
+                .ok_or_else(|| on_err(format!("{subdir} is not a valid subdirectory")))?,
+        ),
+        None => dir.to_path_buf(),
+    };
+    if path.is_dir() {
+        info!("Downloaded {url} into {}", path.display());
+        Ok(path)
+    } else {
+        Err(on_err(format!("{} is not a directory", path.display())))
+    }
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>, RemoteError> {
+    let on_err = |e: reqwest::Error| DownloadError(url.to_string(), e.to_string());
+    let response = reqwest::get(url)
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(on_err)?;
+    Ok(response.bytes().await.map_err(on_err)?.to_vec())
+}
+
+/// Clone the repository with the `git` command, as only its latest commit is needed
+async fn clone_repository(url: &str, dir: &Path) -> Result<(), String> {
+    let mut command = std::process::Command::new("git");
+    command
+        .args(["clone", "--depth", "1", "--quiet", url])
+        .arg(dir);
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("unable to run git: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Download all files with the given extensions linked from an HTML directory listing
+async fn download_listing(url: &str, dir: &Path, extensions: &[&str]) -> Result<(), String> {
+    // links are relative to the directory, not to its parent
+    let base = if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{url}/")
+    };
+    let base = Url::parse(&base).map_err(|e| e.to_string())?;
+    let listing = fetch(base.as_str()).await.map_err(|e| e.to_string())?;
+    let files = find_links(&String::from_utf8_lossy(&listing), extensions);
+    if files.is_empty() {
+        return Err(format!(
+            "the listing has no links to {} files",
+            extensions.join(", ")
+        ));
+    }
+    for href in &files {
+        let file_url = base.join(href).map_err(|e| e.to_string())?;
+        let Some(name) = file_url.path_segments().and_then(Iterator::last) else {
+            continue;
+        };
+        let data = fetch(file_url.as_str()).await.map_err(|e| e.to_string())?;
+        write_file(dir, name, &data, extensions)?;
+    }
+    Ok(())
+}
+
+/// Links to the files with the given extensions of an HTML page
+fn find_links(html: &str, extensions: &[&str]) -> Vec<String> {
+    let extensions = extensions.iter().map(|v| regex::escape(v)).join("|");
+    let re = Regex::new(&format!(
+        r#"(?i)href\s*=\s*["']([^"'?#]+\.(?:{extensions}))["']"#
+    ))
+    .unwrap();
+    let mut links: Vec<_> = re.captures_iter(html).map(|c| c[1].to_string()).collect();
+    links.sort();
+    links.dedup();
+    links
+}
+
+/// A relative path inside of the download directory, or `None` if it could point outside of it
+fn safe_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(v) => path.push(v),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|v| v.to_str())
+        .map_or(false, |e| {
+            extensions.iter().any(|v| e.eq_ignore_ascii_case(v))
+        })
+}
+
+/// Write the file if it has one of the extensions and a safe relative path, otherwise skip it
+fn write_file(dir: &Path, name: &str, data: &[u8], extensions: &[&str]) -> Result<(), String> {
+    let Some(path) = safe_path(name).filter(|_| has_extension(name, extensions)) else {
+        return Ok(());
+    };
+    let path = dir.join(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, data).map_err(|e| e.to_string())
+}
+
+/// Extract the files with the given extensions of a zip archive
+fn extract_zip(data: &[u8], dir: &Path, extensions: &[&str]) -> Result<(), String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    for idx in 0..archive.len() {
+        let mut file = archive.by_index(idx).map_err(|e| e.to_string())?;
+        if !file.is_file() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        write_file(dir, &name, &content, extensions)?;
+    }
+    Ok(())
+}
+
+/// Extract the files with the given extensions of a ustar archive. The GNU long names
+/// and the other extensions of the format are not supported, their entries are skipped.
+fn extract_tar(data: &[u8], dir: &Path, extensions: &[&str]) -> Result<(), String> {
+    let text = |v: &[u8]| {
+        let end = v.iter().position(|b| *b == 0).unwrap_or(v.len());
+        String::from_utf8_lossy(&v[..end]).trim().to_string()
+    };
+    let mut pos = 0;
+    while pos + 512 <= data.len() {
+        let header = &data[pos..pos + 512];
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = usize::from_str_radix(&text(&header[124..136]), 8)
+            .map_err(|_| format!("invalid entry size at offset {pos}"))?;
+        let mut name = text(&header[0..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = text(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{prefix}/{name}");
+            }
+        }
+        pos += 512;
+        let content = data
+            .get(pos..pos + size)
+            .ok_or_else(|| format!("{name} is truncated"))?;
+        // regular files only
+        if matches!(header[156], b'0' | 0) {
+            write_file(dir, &name, content, extensions)?;
+        }
+        pos += (size + 511) / 512 * 512;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    #[test]
+    fn remote_urls() {
+        assert!(is_remote(Path::new("https://example.com/icons/")));
+        assert!(is_remote(Path::new("git+https://example.com/icons.git")));
+        assert!(!is_remote(Path::new("/srv/icons")));
+
+        let kind = |url| remote_kind(url, &["svg"]);
+        assert_eq!(kind("git+https://host/icons.git"), RemoteKind::Git);
+        assert_eq!(kind("https://host/icons.ZIP?v=1"), RemoteKind::Zip);
+        assert_eq!(kind("https://host/icons.tgz"), RemoteKind::TarGz);
+        assert_eq!(kind("https://host/icons/bus.svg"), RemoteKind::File);
+        assert_eq!(kind("https://host/icons/"), RemoteKind::Listing);
+
+        assert_eq!(
+            split_url("https://host/a.zip#icons/svg"),
+            ("https://host/a.zip", Some("icons/svg"))
+        );
+        assert_eq!(remote_id("https://host/maki.tar.gz#svg").unwrap(), "maki");
+        assert_eq!(
+            remote_id("git+https://host/design/icons.git").unwrap(),
+            "icons"
+        );
+        assert_eq!(
+            remote_id("https://host/sprites/"),
+            Some("sprites".to_string())
+        );
+        assert_eq!(remote_id("https://"), None);
+    }
+
+    #[test]
+    fn listing_links() {
+        let html = r#"<a href="../">..</a> <a href="bus.svg">bus.svg</a>
+            <A HREF='sub/train.SVG'>train</A> <a href="bus.svg">bus</a> <a href="readme.md">x</a>"#;
+        assert_eq!(find_links(html, &["svg"]), ["bus.svg", "sub/train.SVG"]);
+        assert_eq!(find_links(html, &["md", "txt"]), ["readme.md"]);
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        assert_eq!(safe_path("./a/b.svg"), Some(PathBuf::from("a/b.svg")));
+        assert_eq!(safe_path("../b.svg"), None);
+        assert_eq!(safe_path("/etc/b.svg"), None);
+        assert_eq!(safe_path(""), None);
+    }
+
+    fn tar_entry(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut header = [0_u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+        header[156] = b'0';
+        header[257..262].copy_from_slice(b"ustar");
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(content);
+        entry.resize(512 + (content.len() + 511) / 512 * 512, 0);
+        entry
+    }
+
+    #[test]
+    fn extract_archives() {
+        let dir = std::env::temp_dir().join(format!("martin-remote-{}", std::process::id()));
+        let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+
+        let mut tar = tar_entry("icons/bus.svg", svg);
+        tar.extend(tar_entry("icons/readme.txt", b"skip"));
+        tar.extend(tar_entry("../evil.svg", svg));
+        tar.extend([0; 1024]);
+        extract_tar(&tar, &dir.join("tar"), &["svg"]).unwrap();
+        assert_eq!(std::fs::read(dir.join("tar/icons/bus.svg")).unwrap(), svg);
+        assert!(!dir.join("tar/icons/readme.txt").exists());
+        assert!(!dir.join("evil.svg").exists());
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("train.svg", options).unwrap();
+        zip.write_all(svg).unwrap();
+        zip.start_file("notes.md", options).unwrap();
+        zip.write_all(b"skip").unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        extract_zip(&zip, &dir.join("zip"), &["svg"]).unwrap();
+        assert!(dir.join("zip/train.svg").exists());
+        assert!(!dir.join("zip/notes.md").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
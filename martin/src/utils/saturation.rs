@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use martin_tile_utils::Encoding;
+use tokio::task::JoinError;
+
+/// Blocking tasks that were submitted to the Tokio blocking pool, but have not started yet
+pub static BLOCKING_QUEUE: AtomicU64 = AtomicU64::new(0);
+
+/// Tiles that are being compressed for the clients right now
+pub static COMPRESSION_QUEUE: AtomicU64 = AtomicU64::new(0);
+
+/// Tile requests served from the in-memory or the shared cache
+pub static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Tile requests that were not in any of the configured caches
+pub static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Requests for the compressed variants of the tiles served from the in-memory cache, per encoding
+pub static VARIANT_CACHE_HITS: EncodingCounters = EncodingCounters::new();
+
+/// Tiles that were compressed for the clients and stored in the in-memory cache, per encoding
+pub static VARIANT_CACHE_MISSES: EncodingCounters = EncodingCounters::new();
+
+/// Tile requests that shared the result of the same tile's concurrent fetch, instead of fetching it again
+pub static COALESCED_TILES: AtomicU64 = AtomicU64::new(0);
+
+/// Tile requests served from the in-memory cache with a stale tile, which was then fetched again
+pub static STALE_TILES_SERVED: AtomicU64 = AtomicU64::new(0);
+
+/// Stale tiles that were fetched again in the background and replaced in the caches
+pub static STALE_TILES_REFRESHED: AtomicU64 = AtomicU64::new(0);
+
+/// Glyph range requests served from the glyph cache
+pub static GLYPH_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Glyph range requests that had to be rendered
+pub static GLYPH_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the encodings the tiles are compressed into for the clients
+#[derive(Debug)]
+pub struct EncodingCounters {
+    brotli: AtomicU64,
+    gzip: AtomicU64,
+    zstd: AtomicU64,
+}
+
+impl EncodingCounters {
+    const fn new() -> Self {
+        Self {
+            brotli: AtomicU64::new(0),
+            gzip: AtomicU64::new(0),
+            zstd: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self, encoding: Encoding) {
+        let counter = match encoding {
+            Encoding::Brotli => &self.brotli,
+            Encoding::Gzip => &self.gzip,
+            Encoding::Zstd => &self.zstd,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Values of the counters, keyed by the `Content-Encoding` name
+    #[must_use]
+    pub fn values(&self) -> [(&'static str, u64); 3] {
+        [
+            ("br", self.brotli.load(Ordering::Relaxed)),
+            ("gzip", self.gzip.load(Ordering::Relaxed)),
+            ("zstd", self.zstd.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Time spent waiting for connections of a database pool
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolWait {
+    /// Number of connections taken from the pool
+    pub count: u64,
+    /// Total time spent waiting for them
+    pub total: Duration,
+}
+
+/// Tile requests waiting for each source, created on the first use
+static PENDING_TILES: Mutex<Option<BTreeMap<String, u64>>> = Mutex::new(None);
+
+/// Tiles larger than the size limit of their source, created on the first use
+static OVERSIZED_TILES: Mutex<Option<BTreeMap<String, u64>>> = Mutex::new(None);
+
+/// Tile requests rejected because too many requests were waiting for their source, created on the first use
+static REJECTED_TILES: Mutex<Option<BTreeMap<String, u64>>> = Mutex::new(None);
+
+/// Connection wait times of each database pool, created on the first use
+static POOL_WAITS: Mutex<Option<BTreeMap<String, PoolWait>>> = Mutex::new(None);
+
+/// Counts one unit of work in a gauge for as long as it is alive
+#[derive(Debug)]
+pub struct GaugeGuard(&'static AtomicU64);
+
+impl GaugeGuard {
+    #[must_use]
+    pub fn new(gauge: &'static AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a tile request waiting for its source to generate the tile, for as long as it is alive
+#[derive(Debug)]
+pub struct PendingTile(String);
+
+impl PendingTile {
+    #[must_use]
+    pub fn new(source_id: &str) -> Self {
+        let mut pending = PENDING_TILES.lock().unwrap();
+        let pending = pending.get_or_insert_with(BTreeMap::new);
+        *pending.entry(source_id.to_string()).or_default() += 1;
+        Self(source_id.to_string())
+    }
+}
+
+impl Drop for PendingTile {
+    fn drop(&mut self) {
+        let mut pending = PENDING_TILES.lock().unwrap();
+        if let Some(count) = pending.as_mut().and_then(|v| v.get_mut(&self.0)) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Number of tile requests waiting for each source that was used since the start
+#[must_use]
+pub fn get_pending_tiles() -> BTreeMap<String, u64> {
+    PENDING_TILES.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Count a tile of the given comma-separated sources that was larger than their size limit
+pub fn record_oversized_tile(source_ids: &str) {
+    let mut oversized = OVERSIZED_TILES.lock().unwrap();
+    *oversized
+        .get_or_insert_with(BTreeMap::new)
+        .entry(source_ids.to_string())
+        .or_default() += 1;
+}
+
+/// Number of oversized tiles of each source since the start
+#[must_use]
+pub fn get_oversized_tiles() -> BTreeMap<String, u64> {
+    OVERSIZED_TILES.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Count a tile request rejected because the queue of its source was full
+pub fn record_rejected_tile(source_id: &str) {
+    let mut rejected = REJECTED_TILES.lock().unwrap();
+    *rejected
+        .get_or_insert_with(BTreeMap::new)
+        .entry(source_id.to_string())
+        .or_default() += 1;
+}
+
+/// Number of rejected tile requests of each source since the start
+#[must_use]
+pub fn get_rejected_tiles() -> BTreeMap<String, u64> {
+    REJECTED_TILES.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Record the time it took to get a connection from the given pool
+pub fn record_pool_wait(pool_id: &str, wait: Duration) {
+    let mut waits = POOL_WAITS.lock().unwrap();
+    let entry = waits
+        .get_or_insert_with(BTreeMap::new)
+        .entry(pool_id.to_string())
+        .or_default();
+    entry.count += 1;
+    entry.total += wait;
+}
+
+#[must_use]
+pub fn get_pool_waits() -> BTreeMap<String, PoolWait> {
+    POOL_WAITS.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Run a closure on the Tokio blocking pool, counting it in [`BLOCKING_QUEUE`] until it starts
+pub async fn spawn_blocking<F, R>(f: F) -> Result<R, JoinError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let queued = GaugeGuard::new(&BLOCKING_QUEUE);
+    tokio::task::spawn_blocking(move || {
+        drop(queued);
+        f()
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn saturation_gauges() {
+        let pending = PendingTile::new("saturation_test");
+        let second = PendingTile::new("saturation_test");
+        assert_eq!(get_pending_tiles()["saturation_test"], 2);
+        drop(pending);
+        drop(second);
+        assert_eq!(get_pending_tiles()["saturation_test"], 0);
+
+        assert_eq!(spawn_blocking(|| 42).await.unwrap(), 42);
+
+        record_oversized_tile("saturation_test");
+        assert_eq!(get_oversized_tiles()["saturation_test"], 1);
+
+        record_pool_wait("saturation_test", Duration::from_millis(30));
+        record_pool_wait("saturation_test", Duration::from_millis(20));
+        assert_eq!(
+            get_pool_waits()["saturation_test"],
+            PoolWait {
+                count: 2,
+                total: Duration::from_millis(50),
+            }
+        );
+    }
+}
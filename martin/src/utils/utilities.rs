@@ -32,7 +32,12 @@ pub fn decode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
 }
 
 pub fn encode_gzip(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encode_gzip_with_level(data, flate2::Compression::default().level())
+}
+
+/// Compress with the given level from 0 (fastest) to 9 (smallest)
+pub fn encode_gzip_with_level(data: &[u8], level: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
     encoder.write_all(data)?;
     encoder.finish()
 }
@@ -45,11 +50,24 @@ pub fn decode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
 }
 
 pub fn encode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+    encode_brotli_with_quality(data, 11)
+}
+
+/// Compress with the given quality from 0 (fastest) to 11 (smallest)
+pub fn encode_brotli_with_quality(data: &[u8], quality: u32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, quality, 22);
     encoder.write_all(data)?;
     Ok(encoder.into_inner())
 }
 
+pub fn decode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::stream::decode_all(data)
+}
+
+pub fn encode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::stream::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
 pub async fn on_slow<T, S: FnOnce()>(
     future: impl Future<Output = T>,
     duration: Duration,
@@ -0,0 +1,213 @@
+//! Parsing of the [Well-known binary](https://libgeos.org/specifications/wkb/) geometry format,
+//! including the ISO and the extended (EWKB) variants. Z and M values are ignored.
+
+use crate::utils::mvt::TileGeometry;
+
+pub type Coord = [f64; 2];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Geometry {
+    Points(Vec<Coord>),
+    Lines(Vec<Vec<Coord>>),
+    /// Each polygon is a list of rings, starting with the exterior ring
+    Polygons(Vec<Vec<Vec<Coord>>>),
+    Collection(Vec<Geometry>),
+}
+
+impl Geometry {
+    /// Convert the geometry to tile coordinates, splitting collections into one geometry per type
+    #[must_use]
+    pub fn to_tile(&self, transform: &impl Fn(Coord) -> [i32; 2]) -> Vec<TileGeometry> {
+        let ring = |ring: &Vec<Coord>| ring.iter().map(|c| transform(*c)).collect::<Vec<_>>();
+        match self {
+            Self::Points(v) => vec![TileGeometry::Points(
+                v.iter().map(|c| transform(*c)).collect(),
+            )],
+            Self::Lines(v) => vec![TileGeometry::Lines(v.iter().map(ring).collect())],
+            Self::Polygons(v) => vec![TileGeometry::Polygons(
+                v.iter().map(|p| p.iter().map(ring).collect()).collect(),
+            )],
+            Self::Collection(v) => v.iter().flat_map(|g| g.to_tile(transform)).collect(),
+        }
+    }
+
+    /// Get the bounding box as `[min_x, min_y, max_x, max_y]`, or `None` if the geometry is empty
+    #[must_use]
+    pub fn bbox(&self) -> Option<[f64; 4]> {
+        let mut result: Option<[f64; 4]> = None;
+        self.for_each_coord(&mut |[x, y]| {
+            let b = result.get_or_insert([x, y, x, y]);
+            *b = [b[0].min(x), b[1].min(y), b[2].max(x), b[3].max(y)];
+        });
+        result
+    }
+
+    fn for_each_coord(&self, f: &mut impl FnMut(Coord)) {
+        match self {
+            Self::Points(v) => v.iter().for_each(|c| f(*c)),
+            Self::Lines(v) => v.iter().flatten().for_each(|c| f(*c)),
+            Self::Polygons(v) => v.iter().flatten().flatten().for_each(|c| f(*c)),
+            Self::Collection(v) => v.iter().for_each(|g| g.for_each_coord(f)),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let value = self.data.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(value)
+    }
+
+    fn u32(&mut self, little_endian: bool) -> Option<u32> {
+        let v = self.bytes::<4>()?;
+        Some(if little_endian {
+            u32::from_le_bytes(v)
+        } else {
+            u32::from_be_bytes(v)
+        })
+    }
+
+    fn f64(&mut self, little_endian: bool) -> Option<f64> {
+        let v = self.bytes::<8>()?;
+        Some(if little_endian {
+            f64::from_le_bytes(v)
+        } else {
+            f64::from_be_bytes(v)
+        })
+    }
+
+    /// Read a count, making sure it is not larger than the remaining data could possibly contain
+    fn count(&mut self, little_endian: bool) -> Option<usize> {
+        let count = usize::try_from(self.u32(little_endian)?).ok()?;
+        (count <= self.data.len() - self.pos).then_some(count)
+    }
+}
+
+const EWKB_Z: u32 = 0x8000_0000;
+const EWKB_M: u32 = 0x4000_0000;
+const EWKB_SRID: u32 = 0x2000_0000;
+
+/// Parse a WKB or EWKB geometry
+#[must_use]
+pub fn parse_wkb(data: &[u8]) -> Option<Geometry> {
+    parse_geometry(&mut Reader { data, pos: 0 })
+}
+
+fn parse_geometry(r: &mut Reader) -> Option<Geometry> {
+    let little_endian = r.bytes::<1>()?[0] == 1;
+    let code = r.u32(little_endian)?;
+    let mut dims = 2;
+    if code & EWKB_Z != 0 {
+        dims += 1;
+    }
+    if code & EWKB_M != 0 {
+        dims += 1;
+    }
+    if code & EWKB_SRID != 0 {
+        r.u32(little_endian)?;
+    }
+    let code = code & 0x0FFF_FFFF;
+    // ISO WKB uses 1000 for Z, 2000 for M, and 3000 for ZM
+    dims += match code / 1000 {
+        0 => 0,
+        1 | 2 => 1,
+        3 => 2,
+        _ => None?,
+    };
+    let le = little_endian;
+    let coord = |r: &mut Reader| -> Option<Coord> {
+        let x = r.f64(le)?;
+        let y = r.f64(le)?;
+        for _ in 2..dims {
+            r.f64(le)?;
+        }
+        Some([x, y])
+    };
+    let coords = |r: &mut Reader| -> Option<Vec<Coord>> {
+        let count = r.count(le)?;
+        (0..count).map(|_| coord(r)).collect()
+    };
+    let rings = |r: &mut Reader| -> Option<Vec<Vec<Coord>>> {
+        let count = r.count(le)?;
+        (0..count).map(|_| coords(r)).collect()
+    };
+    Some(match code % 1000 {
+        1 => {
+            let point = coord(r)?;
+            // an empty point is stored with NaN coordinates
+            if point[0].is_nan() {
+                Geometry::Points(vec![])
+            } else {
+                Geometry::Points(vec![point])
+            }
+        }
+        2 => Geometry::Lines(vec![coords(r)?]),
+        3 => Geometry::Polygons(vec![rings(r)?]),
+        4..=7 => {
+            let count = r.count(le)?;
+            let parts = (0..count)
+                .map(|_| parse_geometry(r))
+                .collect::<Option<Vec<_>>>()?;
+            merge_parts(code % 1000, parts)?
+        }
+        _ => None?,
+    })
+}
+
+/// Combine the parts of a multi-geometry, which are stored as individual geometries
+fn merge_parts(code: u32, parts: Vec<Geometry>) -> Option<Geometry> {
+    if code == 7 {
+        return Some(Geometry::Collection(parts));
+    }
+    let mut points = Vec::new();
+    let mut lines = Vec::new();
+    let mut polygons = Vec::new();
+    for part in parts {
+        match part {
+            Geometry::Points(v) => points.extend(v),
+            Geometry::Lines(v) => lines.extend(v),
+            Geometry::Polygons(v) => polygons.extend(v),
+            Geometry::Collection(_) => None?,
+        }
+    }
+    Some(match code {
+        4 => Geometry::Points(points),
+        5 => Geometry::Lines(lines),
+        _ => Geometry::Polygons(polygons),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        // POINT(1 2)
+        let wkb = hex("0101000000000000000000F03F0000000000000040");
+        assert_eq!(parse_wkb(&wkb), Some(Geometry::Points(vec![[1.0, 2.0]])));
+        // POINT Z (1 2 3) in big endian ISO WKB
+        let wkb = hex("00000003E93FF000000000000040000000000000004008000000000000");
+        assert_eq!(parse_wkb(&wkb), Some(Geometry::Points(vec![[1.0, 2.0]])));
+        // MULTILINESTRING((0 0, 1 1))
+        let wkb = hex("01050000000100000001020000000200000000000000000000000000000000000000000000000000F03F000000000000F03F");
+        let geom = parse_wkb(&wkb).unwrap();
+        assert_eq!(geom, Geometry::Lines(vec![vec![[0.0, 0.0], [1.0, 1.0]]]));
+        assert_eq!(geom.bbox(), Some([0.0, 0.0, 1.0, 1.0]));
+        // truncated data
+        assert_eq!(parse_wkb(&wkb[..wkb.len() - 1]), None);
+    }
+}
@@ -1,12 +1,120 @@
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug, Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+use crate::utils::mvt::{mercator_to_wgs84, tile_bbox, MERCATOR_MAX};
+
+/// Highest zoom level that tiles can be requested for
+pub const MAX_ZOOM: u8 = 30;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TileCoord {
     pub z: u8,
     pub x: u32,
     pub y: u32,
 }
 
+impl TileCoord {
+    /// Check that the zoom is at most [`MAX_ZOOM`], and that the tile is within the grid of its zoom
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.z <= MAX_ZOOM && self.x < (1 << self.z) && self.y < (1 << self.z)
+    }
+
+    /// Convert a [Bing Maps quadkey](https://learn.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system),
+    /// one digit from `0` to `3` per zoom level, to the coordinates of its tile
+    #[must_use]
+    pub fn from_quadkey(quadkey: &str) -> Option<Self> {
+        let z = u8::try_from(quadkey.len())
+            .ok()
+            .filter(|z| *z <= MAX_ZOOM)?;
+        let (mut x, mut y) = (0_u32, 0_u32);
+        for digit in quadkey.bytes() {
+            let digit = match digit {
+                b'0'..=b'3' => u32::from(digit - b'0'),
+                _ => return None,
+            };
+            x = (x << 1) | (digit & 1);
+            y = (y << 1) | (digit >> 1);
+        }
+        Some(Self { z, x, y })
+    }
+}
+
+/// [OGC tile matrix set](https://docs.ogc.org/is/17-083r4/17-083r4.html) of the tiles of a source
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TileMatrixSet {
+    /// Web Mercator (EPSG:3857) grid with a single tile at zoom 0
+    #[default]
+    WebMercatorQuad,
+    /// Geographic (EPSG:4326) grid with two tiles side by side at zoom 0, each 180 degrees wide
+    #[serde(rename = "WorldCRS84Quad")]
+    WorldCrs84Quad,
+}
+
+impl TileMatrixSet {
+    /// SRID of the coordinates of the tile grid
+    #[must_use]
+    pub fn srid(self) -> i32 {
+        match self {
+            Self::WebMercatorQuad => 3857,
+            Self::WorldCrs84Quad => 4326,
+        }
+    }
+
+    /// Width and height of the zoom 0 tiles in the coordinates of the grid
+    #[must_use]
+    pub fn zoom0_tile_size(self) -> f64 {
+        match self {
+            Self::WebMercatorQuad => 2.0 * MERCATOR_MAX,
+            Self::WorldCrs84Quad => 180.0,
+        }
+    }
+
+    /// Check that the zoom is at most [`MAX_ZOOM`], and that the tile is within the grid of its zoom
+    #[must_use]
+    pub fn is_valid(self, xyz: TileCoord) -> bool {
+        let columns = match self {
+            Self::WebMercatorQuad => 1_u64 << xyz.z.min(MAX_ZOOM),
+            Self::WorldCrs84Quad => 2_u64 << xyz.z.min(MAX_ZOOM),
+        };
+        xyz.z <= MAX_ZOOM && u64::from(xyz.x) < columns && xyz.y < (1 << xyz.z)
+    }
+
+    /// Tile bounds in the coordinates of the grid as `[min_x, min_y, max_x, max_y]`,
+    /// i.e. Web Mercator meters or degrees
+    #[must_use]
+    pub fn tile_bbox(self, xyz: TileCoord) -> [f64; 4] {
+        match self {
+            Self::WebMercatorQuad => tile_bbox(xyz),
+            Self::WorldCrs84Quad => {
+                let size = self.zoom0_tile_size() / f64::from(1_u32 << xyz.z.min(31));
+                let min_x = -180.0 + f64::from(xyz.x) * size;
+                let max_y = 90.0 - f64::from(xyz.y) * size;
+                [min_x, max_y - size, min_x + size, max_y]
+            }
+        }
+    }
+
+    /// Convert the coordinates of the grid to WGS84 longitude and latitude
+    #[must_use]
+    pub fn to_wgs84(self, [x, y]: [f64; 2]) -> [f64; 2] {
+        match self {
+            Self::WebMercatorQuad => mercator_to_wgs84(x, y),
+            Self::WorldCrs84Quad => [x, y],
+        }
+    }
+}
+
+impl Display for TileMatrixSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WebMercatorQuad => write!(f, "WebMercatorQuad"),
+            Self::WorldCrs84Quad => write!(f, "WorldCRS84Quad"),
+        }
+    }
+}
+
 impl Display for TileCoord {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if f.alternate() {
@@ -16,3 +124,53 @@ impl Display for TileCoord {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_coords() {
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        assert!(xyz(0, 0, 0).is_valid());
+        assert!(!xyz(0, 1, 0).is_valid());
+        assert!(!xyz(0, 0, 1).is_valid());
+        assert!(xyz(3, 7, 7).is_valid());
+        assert!(!xyz(3, 8, 0).is_valid());
+        assert!(xyz(MAX_ZOOM, (1 << MAX_ZOOM) - 1, 0).is_valid());
+        assert!(!xyz(MAX_ZOOM + 1, 0, 0).is_valid());
+        assert!(!xyz(u8::MAX, 0, 0).is_valid());
+    }
+
+    #[test]
+    fn tile_matrix_sets() {
+        let xyz = |z, x, y| TileCoord { z, x, y };
+        let grid = TileMatrixSet::WorldCrs84Quad;
+        assert!(grid.is_valid(xyz(0, 1, 0)));
+        assert!(!grid.is_valid(xyz(0, 2, 0)));
+        assert!(!grid.is_valid(xyz(0, 0, 1)));
+        assert!(grid.is_valid(xyz(MAX_ZOOM, (2 << MAX_ZOOM) - 1, 0)));
+        assert!(!TileMatrixSet::WebMercatorQuad.is_valid(xyz(0, 1, 0)));
+        assert_eq!(grid.tile_bbox(xyz(0, 1, 0)), [0.0, -90.0, 180.0, 90.0]);
+        assert_eq!(grid.tile_bbox(xyz(2, 1, 2)), [-135.0, -45.0, -90.0, 0.0]);
+        assert_eq!(grid.to_wgs84([10.0, 20.0]), [10.0, 20.0]);
+        let name = |v| serde_json::to_string(&v).unwrap();
+        assert_eq!(name(grid), r#""WorldCRS84Quad""#);
+        assert_eq!(name(TileMatrixSet::default()), r#""WebMercatorQuad""#);
+    }
+
+    #[test]
+    fn quadkeys() {
+        let xyz = |z, x, y| Some(TileCoord { z, x, y });
+        assert_eq!(TileCoord::from_quadkey(""), xyz(0, 0, 0));
+        assert_eq!(TileCoord::from_quadkey("3"), xyz(1, 1, 1));
+        assert_eq!(TileCoord::from_quadkey("213"), xyz(3, 3, 5));
+        assert_eq!(TileCoord::from_quadkey("0231"), xyz(4, 3, 6));
+        let max = "3".repeat(usize::from(MAX_ZOOM));
+        let last = (1 << MAX_ZOOM) - 1;
+        assert_eq!(TileCoord::from_quadkey(&max), xyz(MAX_ZOOM, last, last));
+        assert_eq!(TileCoord::from_quadkey(&format!("{max}0")), None);
+        assert_eq!(TileCoord::from_quadkey("014"), None);
+        assert_eq!(TileCoord::from_quadkey("01a"), None);
+    }
+}
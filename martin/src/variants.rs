@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Query;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::source::UrlQuery;
+
+pub const VARIANT_QUERY_PARAM_DEFAULT: &str = "variant";
+
+pub type VariantConfigs = BTreeMap<String, VariantConfig>;
+
+/// A source ID that is served by one of several sources, chosen for each request,
+/// e.g. label layers in different languages, or imagery of different years
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VariantConfig {
+    /// URL query parameter selecting the variant by name [default: `variant`]
+    pub query: Option<String>,
+    /// Request header selecting the variant, e.g. `Accept-Language`. The query parameter takes
+    /// precedence. Comma-separated values with `;q=` weights are tried in the order of preference,
+    /// and `de-CH` also matches a variant named `de`.
+    pub header: Option<String>,
+    /// Variant used if the request does not select a known one [default: the first variant]
+    pub default: Option<String>,
+    /// Variant names mapped to source IDs
+    pub sources: BTreeMap<String, String>,
+}
+
+impl VariantConfig {
+    #[must_use]
+    pub fn query_param(&self) -> &str {
+        self.query.as_deref().unwrap_or(VARIANT_QUERY_PARAM_DEFAULT)
+    }
+
+    /// Name of the variant used if the request does not select one
+    #[must_use]
+    pub fn default_variant(&self) -> Option<&str> {
+        self.default
+            .as_deref()
+            .or_else(|| self.sources.keys().next().map(String::as_str))
+    }
+
+    /// Get the source ID of the variant selected by the query parameter or the request header
+    #[must_use]
+    pub fn select(&self, query: &str, headers: &HeaderMap) -> Option<&str> {
+        let param = self.query_param();
+        let from_query = Query::<UrlQuery>::from_query(query)
+            .ok()
+            .and_then(|q| self.sources.get(q.get(param)?));
+        let from_header = || {
+            let value = headers.get(self.header.as_deref()?)?.to_str().ok()?;
+            weighted_values(value).into_iter().find_map(|v| {
+                self.sources.get(v).or_else(|| {
+                    let (primary, _) = v.split_once('-')?;
+                    self.sources.get(primary)
+                })
+            })
+        };
+        from_query
+            .or_else(from_header)
+            .or_else(|| self.sources.get(self.default_variant()?))
+            .map(String::as_str)
+    }
+
+    /// Description of the variants, added to the `TileJSON` of the source
+    #[must_use]
+    pub fn to_tilejson_value(&self) -> Value {
+        let mut value = json!({
+            "query": self.query_param(),
+            "default": self.default_variant(),
+            "values": self.sources.keys().collect::<Vec<_>>(),
+        });
+        if let Some(header) = &self.header {
+            value["header"] = json!(header);
+        }
+        value
+    }
+}
+
+/// Split a header value like `de-CH, de;q=0.9, en;q=0.5` into values ordered by their weight
+fn weighted_values(value: &str) -> Vec<&str> {
+    let mut values: Vec<(&str, f32)> = value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let value = parts.next().filter(|v| !v.is_empty())?;
+            let weight = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((value, weight))
+        })
+        .collect();
+    // stable sort keeps the original order of the values with the same weight
+    values.sort_by(|a, b| b.1.total_cmp(&a.1));
+    values.into_iter().map(|(v, _)| v).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn labels() -> VariantConfig {
+        VariantConfig {
+            query: Some("lang".to_string()),
+            header: Some("Accept-Language".to_string()),
+            default: Some("en".to_string()),
+            sources: BTreeMap::from([
+                ("de".to_string(), "labels_de".to_string()),
+                ("en".to_string(), "labels_en".to_string()),
+                ("fr".to_string(), "labels_fr".to_string()),
+            ]),
+        }
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("accept-language"),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn select_variant() {
+        let cfg = labels();
+        let none = HeaderMap::new();
+        assert_eq!(cfg.select("", &none), Some("labels_en"));
+        assert_eq!(cfg.select("lang=fr", &none), Some("labels_fr"));
+        assert_eq!(cfg.select("a=1&lang=de", &none), Some("labels_de"));
+        assert_eq!(cfg.select("lang=xx", &none), Some("labels_en"));
+        assert_eq!(cfg.select("", &headers("de-CH")), Some("labels_de"));
+        assert_eq!(
+            cfg.select("", &headers("it, fr;q=0.5, de;q=0.8")),
+            Some("labels_de")
+        );
+        assert_eq!(cfg.select("lang=fr", &headers("de")), Some("labels_fr"));
+        assert_eq!(cfg.select("", &headers("it")), Some("labels_en"));
+
+        let cfg = VariantConfig {
+            sources: BTreeMap::from([("2020".to_string(), "ortho_2020".to_string())]),
+            ..VariantConfig::default()
+        };
+        assert_eq!(cfg.select("", &headers("de")), Some("ortho_2020"));
+        assert_eq!(cfg.select("variant=2020", &none), Some("ortho_2020"));
+    }
+
+    #[test]
+    fn header_weights() {
+        assert_eq!(
+            weighted_values("fr;q=0.5, de , en;q=0.9,,it;q=x"),
+            vec!["de", "en", "fr"]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use image::{imageops, ImageFormat, ImageOutputFormat, Rgba, RgbaImage};
+use log::info;
+use martin_tile_utils::{Format, TileInfo};
+use pbf_font_tools::freetype::face::LoadFlag;
+use pbf_font_tools::freetype::Library;
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{PoolStatus, Source, TileData, TileInfoSources, UrlQuery};
+use crate::{MartinResult, TileCoord, TileMatrixSet};
+
+pub type WatermarkResult<T> = Result<T, WatermarkError>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum WatermarkError {
+    #[error("Watermark is configured for source {0}, but there is no such source")]
+    UnknownSource(String),
+
+    #[error("Watermark of source {0} must have either a text or an image")]
+    NoContent(String),
+
+    #[error("Watermark of source {0} has a text, but no font file to render it with")]
+    NoFont(String),
+
+    #[error("Unable to render the watermark text of source {1} with font {}: {0}", .2.display())]
+    FontError(pbf_font_tools::freetype::Error, String, PathBuf),
+
+    #[error("Unable to load the watermark image {} of source {1}: {0}", .2.display())]
+    ImageError(image::ImageError, String, PathBuf),
+
+    #[error("Invalid watermark color {0} of source {1}, use the #rrggbb or #rrggbbaa format")]
+    InvalidColor(String, String),
+
+    #[error("Watermarks are only supported for uncompressed PNG and JPEG tiles, but source {1} has {0} tiles")]
+    UnsupportedFormat(TileInfo, String),
+
+    #[error("Unable to stamp tile {1} of source {2}: {0}")]
+    StampError(image::ImageError, TileCoord, String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// Text or image stamped onto every tile of a raster source
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WatermarkConfig {
+    /// Text of the watermark, e.g. a copyright notice. Requires `font`.
+    pub text: Option<String>,
+    /// TrueType or OpenType font file to render the text with
+    pub font: Option<PathBuf>,
+    /// Font size in pixels [default: 12]
+    pub font_size: Option<u32>,
+    /// Text color as `#rrggbb` or `#rrggbbaa` [default: `#ffffff`]
+    pub color: Option<String>,
+    /// PNG or JPEG image to use instead of a text, e.g. a logo
+    pub image: Option<PathBuf>,
+    /// Where to place the watermark in each tile
+    pub position: Option<WatermarkPosition>,
+    /// Distance of the watermark from the tile edges in pixels [default: 4]
+    pub margin: Option<u32>,
+    /// Opacity of the watermark from 0 to 1 [default: 0.5]
+    pub opacity: Option<f32>,
+}
+
+pub type WatermarkConfigs = BTreeMap<String, WatermarkConfig>;
+
+const FONT_SIZE_DEFAULT: u32 = 12;
+const MARGIN_DEFAULT: u32 = 4;
+const OPACITY_DEFAULT: f32 = 0.5;
+
+/// Wrap the sources that have a watermark configured, keeping all other sources as they are
+pub fn apply_watermarks(
+    sources: Vec<TileInfoSources>,
+    configs: &WatermarkConfigs,
+) -> MartinResult<Vec<TileInfoSources>> {
+    if let Some(id) = configs.keys().find(|id| {
+        !sources
+            .iter()
+            .flatten()
+            .any(|src| src.get_id() == id.as_str())
+    }) {
+        return Err(WatermarkError::UnknownSource(id.clone()).into());
+    }
+
+    let mut result = Vec::with_capacity(sources.len());
+    for group in sources {
+        let mut wrapped = TileInfoSources::default();
+        for src in group {
+            match configs.get(src.get_id()) {
+                Some(cfg) => {
+                    info!("Stamping tiles of source {} with a watermark", src.get_id());
+                    wrapped.push(Box::new(WatermarkSource::new(src, cfg)?));
+                }
+                None => wrapped.push(src),
+            }
+        }
+        result.push(wrapped);
+    }
+    Ok(result)
+}
+
+/// A raster source whose tiles are stamped with a watermark.
+/// The stamped tiles are what gets stored in the tile cache.
+pub struct WatermarkSource {
+    source: Box<dyn Source>,
+    stamp: Arc<RgbaImage>,
+    position: WatermarkPosition,
+    margin: u32,
+}
+
+impl Clone for WatermarkSource {
+    fn clone(&self) -> Self {
+        Self {
+            source: self.source.clone_source(),
+            stamp: self.stamp.clone(),
+            position: self.position,
+            margin: self.margin,
+        }
+    }
+}
+
+impl Debug for WatermarkSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WatermarkSource {{ source: {:?} }}", self.source)
+    }
+}
+
+impl WatermarkSource {
+    pub fn new(source: Box<dyn Source>, cfg: &WatermarkConfig) -> WatermarkResult<Self> {
+        let id = source.get_id().to_string();
+        let info = source.get_tile_info();
+        if !matches!(info.format, Format::Png | Format::Jpeg) || info.encoding.is_encoded() {
+            return Err(WatermarkError::UnsupportedFormat(info, id));
+        }
+        let mut stamp = match (&cfg.text, &cfg.image) {
+            (Some(text), None) => {
+                let font = cfg
+                    .font
+                    .as_ref()
+                    .ok_or(WatermarkError::NoFont(id.clone()))?;
+                let color =
+                    parse_color(cfg.color.as_deref().unwrap_or("#ffffff")).ok_or_else(|| {
+                        WatermarkError::InvalidColor(cfg.color.clone().unwrap(), id.clone())
+                    })?;
+                let size = cfg.font_size.unwrap_or(FONT_SIZE_DEFAULT);
+                render_text(text, font, size, color)
+                    .map_err(|e| WatermarkError::FontError(e, id.clone(), font.clone()))?
+            }
+            (None, Some(path)) => image::open(path)
+                .map_err(|e| WatermarkError::ImageError(e, id.clone(), path.clone()))?
+                .to_rgba8(),
+            _ => return Err(WatermarkError::NoContent(id)),
+        };
+        let opacity = cfg.opacity.unwrap_or(OPACITY_DEFAULT).clamp(0.0, 1.0);
+        for pixel in stamp.pixels_mut() {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let alpha = (f32::from(pixel[3]) * opacity).round() as u8;
+            pixel[3] = alpha;
+        }
+        Ok(Self {
+            source,
+            stamp: Arc::new(stamp),
+            position: cfg.position.unwrap_or_default(),
+            margin: cfg.margin.unwrap_or(MARGIN_DEFAULT),
+        })
+    }
+
+    /// Decode the tile, draw the watermark over it, and encode it again in the same format
+    fn stamp_tile(&self, data: &[u8], xyz: &TileCoord) -> WatermarkResult<TileData> {
+        let err = |e| WatermarkError::StampError(e, *xyz, self.source.get_id().to_string());
+        let (format, output) = match self.source.get_tile_info().format {
+            Format::Jpeg => (ImageFormat::Jpeg, ImageOutputFormat::Jpeg(90)),
+            _ => (ImageFormat::Png, ImageOutputFormat::Png),
+        };
+        let mut tile = image::load_from_memory_with_format(data, format)
+            .map_err(err)?
+            .to_rgba8();
+        let (x, y) = self.offset(tile.width(), tile.height());
+        imageops::overlay(&mut tile, self.stamp.as_ref(), x, y);
+
+        let mut result = Cursor::new(Vec::new());
+        if format == ImageFormat::Jpeg {
+            // JPEG has no alpha channel
+            image::DynamicImage::ImageRgba8(tile)
+                .to_rgb8()
+                .write_to(&mut result, output)
+        } else {
+            tile.write_to(&mut result, output)
+        }
+        .map_err(err)?;
+        Ok(result.into_inner())
+    }
+
+    /// Top left corner of the watermark in a tile of the given size
+    fn offset(&self, width: u32, height: u32) -> (i64, i64) {
+        let margin = i64::from(self.margin);
+        let (w, h) = (i64::from(width), i64::from(height));
+        let (sw, sh) = (
+            i64::from(self.stamp.width()),
+            i64::from(self.stamp.height()),
+        );
+        match self.position {
+            WatermarkPosition::TopLeft => (margin, margin),
+            WatermarkPosition::TopRight => (w - sw - margin, margin),
+            WatermarkPosition::BottomLeft => (margin, h - sh - margin),
+            WatermarkPosition::BottomRight => (w - sw - margin, h - sh - margin),
+            WatermarkPosition::Center => ((w - sw) / 2, (h - sh) / 2),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for WatermarkSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.source.is_cacheable()
+    }
+
+    fn get_tile_matrix_set(&self) -> TileMatrixSet {
+        self.source.get_tile_matrix_set()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: &TileCoord,
+        url_query: &Option<UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let data = self.source.get_tile(xyz, url_query).await?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        Ok(self.stamp_tile(&data, xyz)?)
+    }
+
+    fn get_pool_status(&self) -> Option<(String, PoolStatus)> {
+        self.source.get_pool_status()
+    }
+
+    async fn check_health(&self) -> MartinResult<()> {
+        self.source.check_health().await
+    }
+
+    fn support_refresh(&self) -> bool {
+        self.source.support_refresh()
+    }
+
+    async fn refresh_data(&self) -> MartinResult<()> {
+        self.source.refresh_data().await
+    }
+}
+
+/// Parse a `#rrggbb` or `#rrggbbaa` color
+fn parse_color(value: &str) -> Option<Rgba<u8>> {
+    let hex = value.strip_prefix('#')?;
+    if !matches!(hex.len(), 6 | 8) {
+        return None;
+    }
+    let mut color = [255_u8; 4];
+    for (idx, v) in hex::decode(hex).ok()?.into_iter().enumerate() {
+        color[idx] = v;
+    }
+    Some(Rgba(color))
+}
+
+/// Render a single line of text into an image just large enough to hold it
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn render_text(
+    text: &str,
+    font: &PathBuf,
+    size: u32,
+    color: Rgba<u8>,
+) -> Result<RgbaImage, pbf_font_tools::freetype::Error> {
+    let lib = Library::init()?;
+    let face = lib.new_face(font, 0)?;
+    face.set_pixel_sizes(0, size)?;
+    let (ascender, descender) = face.size_metrics().map_or((size as i32, 0), |m| {
+        ((m.ascender >> 6) as i32, (m.descender >> 6) as i32)
+    });
+
+    // The glyph bitmaps with their positions relative to the start of the baseline
+    let mut glyphs = Vec::new();
+    let mut pen = 0_i32;
+    for ch in text.chars() {
+        face.load_char(ch as usize, LoadFlag::RENDER)?;
+        let glyph = face.glyph();
+        let bitmap = glyph.bitmap();
+        let (width, rows, pitch) = (bitmap.width(), bitmap.rows(), bitmap.pitch());
+        let mut coverage = Vec::with_capacity((width * rows).max(0) as usize);
+        for row in 0..rows {
+            let start = (row * pitch) as usize;
+            coverage.extend_from_slice(&bitmap.buffer()[start..start + width as usize]);
+        }
+        glyphs.push((
+            pen + glyph.bitmap_left(),
+            -glyph.bitmap_top(),
+            width,
+            coverage,
+        ));
+        pen += (glyph.advance().x >> 6) as i32;
+    }
+
+    let width = u32::try_from(pen.max(1)).unwrap_or(1);
+    let height = u32::try_from((ascender - descender).max(1)).unwrap_or(1);
+    let mut image = RgbaImage::new(width, height);
+    for (left, top, glyph_width, coverage) in glyphs {
+        for (idx, alpha) in coverage.into_iter().enumerate() {
+            let idx = idx as i32;
+            let x = left + idx % glyph_width;
+            let y = ascender + top + idx / glyph_width;
+            let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) else {
+                continue;
+            };
+            if x < width && y < height && alpha > 0 {
+                let alpha = u16::from(alpha) * u16::from(color[3]) / 255;
+                let pixel = image.get_pixel_mut(x, y);
+                if alpha as u8 > pixel[3] {
+                    *pixel = Rgba([color[0], color[1], color[2], alpha as u8]);
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watermark_colors() {
+        assert_eq!(parse_color("#ff8000"), Some(Rgba([255, 128, 0, 255])));
+        assert_eq!(parse_color("#ff800080"), Some(Rgba([255, 128, 0, 128])));
+        assert_eq!(parse_color("ff8000"), None);
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("#gg8000"), None);
+    }
+}
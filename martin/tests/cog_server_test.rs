@@ -0,0 +1,107 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use ctor::ctor;
+use indoc::indoc;
+use insta::assert_yaml_snapshot;
+use tilejson::TileJSON;
+
+pub mod utils;
+pub use utils::*;
+
+#[ctor]
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+macro_rules! create_app {
+    ($sources:expr) => {{
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
+        ::actix_web::test::init_service(
+            ::actix_web::App::new()
+                .app_data(Data::new(ArcSwap::from_pointee(
+                    ::martin::srv::Catalog::new(&state).unwrap(),
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
+                .configure(::martin::srv::router),
+        )
+        .await
+    }};
+}
+
+fn test_get(path: &str) -> TestRequest {
+    TestRequest::get().uri(path)
+}
+
+const CONFIG: &str = indoc! {"
+        cog:
+            sources:
+                c_rgb: ../tests/fixtures/cog/rgb_z1.tif
+    "};
+
+/// Decode a PNG tile, and return its size and the color of the first pixel
+fn decode_png(data: &[u8]) -> (u32, u32, [u8; 4]) {
+    let mut reader = png::Decoder::new(data).read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+    (info.width, info.height, [buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[actix_rt::test]
+async fn cog_get_catalog() {
+    let app = create_app! { "cog: ../tests/fixtures/cog/rgb_z1.tif" };
+
+    let req = test_get("/catalog").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_yaml_snapshot!(body, @r###"
+    ---
+    fonts: {}
+    sprites: {}
+    tiles:
+      rgb_z1:
+        content_type: image/png
+    "###);
+}
+
+#[actix_rt::test]
+async fn cog_get_tilejson() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/c_rgb").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.minzoom, Some(0));
+    assert_eq!(body.maxzoom, Some(1));
+    let bounds = body.bounds.unwrap();
+    assert!((bounds.left + 180.0).abs() < 1e-6);
+    assert!((bounds.right - 180.0).abs() < 1e-6);
+}
+
+#[actix_rt::test]
+async fn cog_get_tiles() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/c_rgb/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    let body = read_body(response).await;
+    assert_eq!(decode_png(&body), (256, 256, [128, 128, 128, 255]));
+
+    let req = test_get("/c_rgb/1/1/0").to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert_eq!(decode_png(&body), (256, 256, [0, 255, 0, 255]));
+
+    let req = test_get("/c_rgb/1/0/1").to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert_eq!(decode_png(&body), (256, 256, [0, 0, 255, 255]));
+
+    // zoom is outside of the source's range
+    let req = test_get("/c_rgb/2/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,150 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use ctor::ctor;
+use indoc::indoc;
+use insta::assert_yaml_snapshot;
+use tilejson::TileJSON;
+
+pub mod utils;
+pub use utils::*;
+
+#[ctor]
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+macro_rules! create_app {
+    ($sources:expr) => {{
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
+        ::actix_web::test::init_service(
+            ::actix_web::App::new()
+                .app_data(Data::new(ArcSwap::from_pointee(
+                    ::martin::srv::Catalog::new(&state).unwrap(),
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
+                .configure(::martin::srv::router),
+        )
+        .await
+    }};
+}
+
+fn test_get(path: &str) -> TestRequest {
+    TestRequest::get().uri(path)
+}
+
+const CONFIG: &str = indoc! {"
+        gpkg:
+            sources:
+                g_points: ../tests/fixtures/gpkg/points.gpkg
+                g_tiles: ../tests/fixtures/gpkg/tiles.gpkg
+    "};
+
+/// Decode a PNG tile, and return the color of the first pixel
+fn first_pixel(data: &[u8]) -> [u8; 3] {
+    let mut reader = png::Decoder::new(data).read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+    [buf[0], buf[1], buf[2]]
+}
+
+fn contains(data: &[u8], value: &str) -> bool {
+    data.windows(value.len()).any(|w| w == value.as_bytes())
+}
+
+#[actix_rt::test]
+async fn gpkg_get_catalog() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/catalog").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_yaml_snapshot!(body, @r###"
+    ---
+    fonts: {}
+    sprites: {}
+    tiles:
+      g_points:
+        content_type: application/x-protobuf
+      g_tiles:
+        content_type: image/png
+        description: Solid color tiles
+    "###);
+}
+
+#[actix_rt::test]
+async fn gpkg_get_features_tilejson() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/g_points").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_yaml_snapshot!(body["vector_layers"], @r###"
+    ---
+    - fields:
+        capital: Boolean
+        name: String
+        population: Number
+      id: cities
+    "###);
+    let bounds: TileJSON = serde_json::from_value(body).unwrap();
+    let bounds = bounds.bounds.unwrap();
+    assert!((bounds.left + 74.0).abs() < 1e-6);
+    assert!((bounds.top - 51.5).abs() < 1e-6);
+}
+
+#[actix_rt::test]
+async fn gpkg_get_features_tiles() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/g_points/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    let body = read_body(response).await;
+    assert!(contains(&body, "cities"));
+    assert!(contains(&body, "London"));
+    assert!(contains(&body, "Tokyo"));
+
+    // only London is in the north-west quarter at zoom 2
+    let req = test_get("/g_points/2/1/1").to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert!(contains(&body, "London"));
+    assert!(!contains(&body, "Tokyo"));
+
+    // no features in the south-east quarter
+    let req = test_get("/g_points/1/1/1").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+}
+
+#[actix_rt::test]
+async fn gpkg_get_pyramid_tiles() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/g_tiles").to_request();
+    let body: TileJSON = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body.minzoom, Some(0));
+    assert_eq!(body.maxzoom, Some(1));
+
+    let req = test_get("/g_tiles/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    let body = read_body(response).await;
+    assert_eq!(first_pixel(&body), [0, 0, 0]);
+
+    let req = test_get("/g_tiles/1/1/0").to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert_eq!(first_pixel(&body), [100, 100, 0]);
+
+    let req = test_get("/g_tiles/1/0/1").to_request();
+    let body = read_body(call_service(&app, req).await).await;
+    assert_eq!(first_pixel(&body), [100, 0, 100]);
+}
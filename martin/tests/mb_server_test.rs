@@ -1,5 +1,10 @@
-use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::http::header::{
+    ACCEPT_ENCODING, AUTHORIZATION, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, LINK, LOCATION,
+    VARY,
+};
 use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
@@ -16,16 +21,39 @@ fn init() {
 
 macro_rules! create_app {
     ($sources:expr) => {{
-        let state = mock_sources(mock_cfg($sources)).await.0;
-        ::actix_web::test::init_service(
-            ::actix_web::App::new()
-                .app_data(actix_web::web::Data::new(
-                    ::martin::srv::Catalog::new(&state).unwrap(),
-                ))
-                .app_data(actix_web::web::Data::new(state.tiles))
-                .configure(::martin::srv::router),
-        )
-        .await
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
+        let catalog = ::martin::srv::Catalog::new(&state).unwrap();
+        let tile_options = ::martin::srv::TileOptions::new(&cfg.srv, &state);
+        let tiles = Data::new(ArcSwap::from_pointee(state.tiles));
+        let purger = ::martin::srv::CachePurger::new(state.cache.clone(), None)
+            .unwrap()
+            .with_sources(tiles.clone());
+        let workers = cfg.srv.worker_processes.unwrap_or(1);
+        let mut app = ::actix_web::App::new()
+            .app_data(Data::new(ArcSwap::from_pointee(catalog)))
+            .app_data(Data::new(tile_options))
+            .app_data(tiles)
+            .app_data(Data::new(ArcSwap::from_pointee(state.fonts)))
+            .app_data(Data::new(state.styles))
+            .app_data(Data::new(::martin::srv::SourceRedirects::new(
+                cfg.srv.redirects.as_ref(),
+            )))
+            .app_data(Data::new(::martin::srv::RuntimeInfo::new(workers)))
+            .app_data(Data::new(purger))
+            .app_data(Data::new(::martin::srv::Readiness::default()))
+            .app_data(Data::new(::martin::srv::Webhooks::default()));
+        if let Some(admin) = cfg.srv.admin {
+            app = app.app_data(Data::new(admin));
+        }
+        let ip_filter = ::martin::srv::IpFilter::new(
+            cfg.srv.ip_filter.unwrap_or_default(),
+            cfg.srv.trusted_proxies.as_ref(),
+        );
+        let referer_filter =
+            ::martin::srv::RefererFilter::new(&cfg.srv.referer_filter.unwrap_or_default());
+        let throttle = ::martin::srv::Throttle::new(cfg.srv.throttle.unwrap_or_default());
+        let app = app.wrap(throttle).wrap(referer_filter).wrap(ip_filter);
+        ::actix_web::test::init_service(app.configure(::martin::srv::router)).await
     }};
 }
 
@@ -73,6 +101,31 @@ async fn mbt_get_catalog() {
     "###);
 }
 
+#[actix_rt::test]
+async fn mbt_get_catalog_query() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/catalog?type=vector&q=cities&limit=1&offset=1").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get("x-total-count").unwrap(), "2");
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_yaml_snapshot!(body, @r###"
+    ---
+    fonts: {}
+    sprites: {}
+    tiles:
+      m_raw_mvt:
+        content_type: application/x-protobuf
+        description: Major cities from Natural Earth data
+        name: Major cities from Natural Earth data
+    "###);
+
+    let req = test_get("/catalog?limit=all").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 400);
+}
+
 #[actix_rt::test]
 async fn mbt_get_catalog_gzip() {
     let app = create_app! { CONFIG };
@@ -174,6 +227,101 @@ async fn mbt_get_mvt() {
     assert_eq!(body.len(), 1828);
 }
 
+#[actix_rt::test]
+async fn mbt_get_quadkey_mvt() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/2/1/1").to_request();
+    let expected = read_body(call_service(&app, req).await).await;
+    assert!(!expected.is_empty());
+
+    let req = test_get("/m_mvt/quadkey/03").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    assert_eq!(read_body(response).await, expected);
+
+    let req = test_get("/m_mvt/quadkey/214").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn mbt_get_mvt_geojson() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/0/0/0.geojson").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/geo+json"
+    );
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["type"], "FeatureCollection");
+    let features = body["features"].as_array().unwrap();
+    assert!(!features.is_empty());
+    assert!(features.iter().all(|f| f["layer"] == "cities"));
+    assert_eq!(features[0]["geometry"]["type"], "Point");
+    let lon = features[0]["geometry"]["coordinates"][0].as_f64().unwrap();
+    assert!((-180.0..=180.0).contains(&lon));
+
+    let req = test_get("/m_webp/0/0/0.geojson").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn mbt_get_preview_style() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/style.json").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["version"], 8);
+    assert_eq!(body["sources"]["m_mvt"]["type"], "vector");
+    assert_eq!(
+        body["sources"]["m_mvt"]["url"],
+        "http://localhost:8080/m_mvt"
+    );
+    let layers = body["layers"].as_array().unwrap();
+    assert_eq!(layers.len(), 3);
+    assert!(layers.iter().all(|l| l["source-layer"] == "cities"));
+    assert_eq!(layers[2]["type"], "circle");
+    assert_eq!(layers[2]["maxzoom"], 7);
+
+    let req = test_get("/m_webp/style.json").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn mbt_get_preview_page() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/preview").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains(r#""url":"http://localhost:8080/m_mvt""#));
+    assert!(body.contains(r#""source-layer":"cities""#));
+    assert!(!body.contains("{bounds}"));
+
+    let req = test_get("/m_webp/preview").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains(r#""type":"raster""#));
+
+    let req = test_get("/m_json/preview").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 400);
+}
+
 /// get an MVT tile with accepted gzip enc
 #[actix_rt::test]
 async fn mbt_get_mvt_gzip() {
@@ -212,6 +360,770 @@ async fn mbt_get_mvt_brotli() {
     assert_eq!(body.len(), 1828);
 }
 
+#[actix_rt::test]
+async fn mbt_get_deep_health() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/health?deep=true").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_yaml_snapshot!(body, @r###"
+    ---
+    status: ok
+    "###);
+}
+
+#[actix_rt::test]
+async fn mbt_get_deep_health_missing_file() {
+    let path = std::env::temp_dir().join("martin_deep_health_test.mbtiles");
+    std::fs::copy("../tests/fixtures/mbtiles/world_cities.mbtiles", &path).unwrap();
+    let cfg = format!("mbtiles:\n  sources:\n    m_gone: {}\n", path.display());
+    let app = create_app! { &cfg };
+    std::fs::remove_file(&path).unwrap();
+
+    let req = test_get("/health").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+
+    let req = test_get("/health?deep=true").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["status"], "unhealthy");
+    assert!(body["failed"]["m_gone"].is_string());
+}
+
+#[actix_rt::test]
+async fn mbt_get_live_and_ready() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/health/live").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(read_body(response).await, "OK");
+
+    let req = test_get("/health/ready").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["status"], "ready");
+}
+
+#[actix_rt::test]
+async fn mbt_redirect_renamed_source() {
+    let cfg = indoc! {"
+        redirects:
+          old_mvt: m_mvt
+          old_webp:
+            to: m_webp
+            status: 308
+          m_json: m_mvt
+        mbtiles:
+            sources:
+                m_json: ../tests/fixtures/mbtiles/json.mbtiles
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_webp: ../tests/fixtures/mbtiles/webp.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/old_mvt/0/0/0?a=b").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 301);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        "/m_mvt/0/0/0?a=b"
+    );
+
+    let req = test_get("/old_webp").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 308);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), "/m_webp");
+
+    let req = test_get("/m_mvt,old_mvt").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 301);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), "/m_mvt,m_mvt");
+
+    // existing sources are never redirected
+    let req = test_get("/m_json").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn mbt_named_composite() {
+    let cfg = indoc! {"
+        composites:
+          cities: [m_mvt, m_mvt2]
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_mvt2: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/catalog").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert_yaml_snapshot!(body["tiles"]["cities"], @r###"
+    ---
+    content_encoding: gzip
+    content_type: application/x-protobuf
+    description: "Composite of m_mvt,m_mvt2"
+    "###);
+
+    let req = test_get("/cities").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.name, Some("cities".to_string()));
+    assert_eq!(body.tiles, vec!["http://localhost:8080/cities/{z}/{x}/{y}"]);
+    // both sources have the same layer, which is listed once
+    assert_eq!(body.vector_layers.unwrap().len(), 1);
+
+    let req = test_get("/cities/0/0/0").to_request();
+    let composite = read_body(call_service(&app, req).await).await;
+    let req = test_get("/m_mvt,m_mvt2/0/0/0").to_request();
+    let merged = read_body(call_service(&app, req).await).await;
+    assert!(!composite.is_empty());
+    assert_eq!(composite, merged);
+}
+
+#[actix_rt::test]
+async fn mbt_source_variants() {
+    let cfg = indoc! {"
+        variants:
+          cities:
+            query: version
+            header: X-Cities-Version
+            default: old
+            sources:
+              old: m_mvt
+              new: m_mvt_mod
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_mvt_mod: ../tests/fixtures/mbtiles/world_cities_modified.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/catalog").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        body["tiles"]["cities"]["description"],
+        "Variants new,old selected with ?version="
+    );
+
+    let req = test_get("/cities?version=new").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(VARY).unwrap(), "X-Cities-Version");
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.name, Some("cities".to_string()));
+    assert_eq!(
+        body.tiles,
+        vec!["http://localhost:8080/cities/{z}/{x}/{y}?version=new"]
+    );
+    assert_yaml_snapshot!(body.other["variants"], @r###"
+    ---
+    default: old
+    header: X-Cities-Version
+    query: version
+    values:
+      - new
+      - old
+    "###);
+
+    let tile = |path: &'static str, header: Option<&'static str>| {
+        let mut req = test_get(path);
+        if let Some(value) = header {
+            req = req.insert_header(("X-Cities-Version", value));
+        }
+        let req = req.to_request();
+        async { read_body(call_service(&app, req).await).await }
+    };
+    let old = tile("/m_mvt/1/0/0", None).await;
+    let new = tile("/m_mvt_mod/1/0/0", None).await;
+    assert_eq!(tile("/cities/1/0/0", None).await, old);
+    assert_eq!(tile("/cities/1/0/0?version=new", None).await, new);
+    assert_eq!(tile("/cities/1/0/0", Some("new")).await, new);
+    assert_eq!(tile("/cities/1/0/0?version=old", Some("new")).await, old);
+}
+
+#[actix_rt::test]
+async fn mbt_get_status() {
+    let cfg = indoc! {"
+        worker_processes: 3
+        mbtiles:
+            sources:
+                m_json: ../tests/fixtures/mbtiles/json.mbtiles
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/status").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["workers"], 3);
+    assert_eq!(body["sources"]["mbtiles"], 2);
+    assert_eq!(body["sprites"], 0);
+    assert_eq!(body["pools"], serde_json::json!({}));
+    assert!(body["uptime_secs"].is_u64());
+}
+
+#[actix_rt::test]
+async fn mbt_get_tile_max_age() {
+    let cfg = indoc! {"
+        tile_max_age:
+          0: 86400
+          1: 60
+        mbtiles:
+            sources:
+                m_webp: ../tests/fixtures/mbtiles/webp.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/m_webp/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CACHE_CONTROL).unwrap(),
+        "public, max-age=86400"
+    );
+
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_webp/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get(CACHE_CONTROL).is_none());
+}
+
+#[actix_rt::test]
+async fn mbt_get_empty_tile_max_age() {
+    let cfg = indoc! {"
+        tile_max_age:
+          0: 86400
+        empty_tile_max_age: 30
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/m_mvt/6/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(
+        response.headers().get(CACHE_CONTROL).unwrap(),
+        "public, max-age=30"
+    );
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CACHE_CONTROL).unwrap(),
+        "public, max-age=86400"
+    );
+}
+
+#[actix_rt::test]
+async fn mbt_get_surrogate_keys() {
+    let cfg = indoc! {"
+        cdn:
+          surrogate_keys: ['src:{id}', 'z:{z}']
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_copy: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get("Surrogate-Key").unwrap(),
+        "src:m_mvt z:0"
+    );
+
+    // composite tiles are purged with each of their sources
+    let req = test_get("/m_mvt,m_copy/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get("Surrogate-Key").unwrap(),
+        "src:m_mvt src:m_copy z:0"
+    );
+
+    // empty tiles are cached by the CDN too
+    let req = test_get("/m_mvt/6/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(
+        response.headers().get("Surrogate-Key").unwrap(),
+        "src:m_mvt z:6"
+    );
+}
+
+#[actix_rt::test]
+async fn mbt_get_server_timing() {
+    let cfg = indoc! {"
+        server_timing: true
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let timing = response.headers().get("server-timing").unwrap();
+    let timing = timing.to_str().unwrap();
+    assert!(timing.starts_with("lookup;dur="), "{timing}");
+    assert!(timing.contains(r#", source;desc="m_mvt";dur="#), "{timing}");
+    assert!(timing.contains(", db;dur="), "{timing}");
+    assert!(timing.contains(", decode;dur="), "{timing}");
+    assert!(timing.contains(", cache;desc=miss, total;dur="), "{timing}");
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    let timing = response.headers().get("server-timing").unwrap();
+    let timing = timing.to_str().unwrap();
+    assert!(!timing.contains("db;"), "{timing}");
+    assert!(timing.contains("cache;desc=hit"), "{timing}");
+
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.headers().get("server-timing").is_none());
+}
+
+#[actix_rt::test]
+async fn mbt_admin_purge() {
+    let app = create_app! { CONFIG };
+    let req = TestRequest::post().uri("/_/purge/m_mvt").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 404);
+
+    let app = create_app! { &format!("admin:
+  token: secret
+{CONFIG}") };
+    let req = TestRequest::post().uri("/_/purge/m_mvt").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 401);
+    for token in ["Bearer secre", "Bearer secrets", "Bearer Secret", "secret"] {
+        let req = TestRequest::post()
+            .uri("/_/purge/m_mvt")
+            .insert_header((AUTHORIZATION, token))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), 401, "{token} must be rejected");
+    }
+
+    let req = TestRequest::post()
+        .uri("/_/purge/m_mvt,m_webp")
+        .insert_header((AUTHORIZATION, "Bearer secret"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+
+    let req = TestRequest::post()
+        .uri("/_/purge/m_mvt,unknown")
+        .insert_header((AUTHORIZATION, "Bearer secret"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 404);
+}
+
+#[actix_rt::test]
+async fn mbt_admin_purge_composite() {
+    let cfg = indoc! {"
+        admin:
+          token: secret
+        server_timing: true
+        composites:
+          cities: [m_mvt, m_mvt2]
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_mvt2: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+    let app = create_app! { cfg };
+    let is_cache_hit = |headers: &actix_web::http::header::HeaderMap| {
+        let timing = headers.get("server-timing").unwrap();
+        timing.to_str().unwrap().contains("cache;desc=hit")
+    };
+
+    let req = test_get("/m_mvt2/0/0/0").to_request();
+    assert!(!is_cache_hit(call_service(&app, req).await.headers()));
+    let req = test_get("/m_mvt2/0/0/0").to_request();
+    assert!(is_cache_hit(call_service(&app, req).await.headers()));
+
+    // the members of a composite are purged with it
+    let req = TestRequest::post()
+        .uri("/_/purge/cities")
+        .insert_header((AUTHORIZATION, "Bearer secret"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+    let req = test_get("/m_mvt2/0/0/0").to_request();
+    assert!(!is_cache_hit(call_service(&app, req).await.headers()));
+}
+
+#[actix_rt::test]
+async fn mbt_admin_add_remove_source() {
+    let app = create_app! { &format!("admin:\n  token: secret\n{CONFIG}") };
+    let auth = || (AUTHORIZATION, "Bearer secret");
+
+    let body = serde_json::json!({
+        "id": "p_png",
+        "type": "pmtiles",
+        "path": "../tests/fixtures/pmtiles/png.pmtiles",
+    });
+    let req = TestRequest::post()
+        .uri("/_/sources")
+        .set_json(&body)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 401);
+
+    let req = TestRequest::post()
+        .uri("/_/sources")
+        .insert_header(auth())
+        .set_json(&body)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 201);
+
+    let req = TestRequest::post()
+        .uri("/_/sources")
+        .insert_header(auth())
+        .set_json(&body)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 409);
+
+    // the IDs that the discovery would rename are rejected
+    for id in [
+        "catalog",
+        "health",
+        "my source",
+        "a,b",
+        "a/b",
+        "ünïcode",
+        "",
+    ] {
+        let mut body = body.clone();
+        body["id"] = id.into();
+        let req = TestRequest::post()
+            .uri("/_/sources")
+            .insert_header(auth())
+            .set_json(&body)
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), 400, "{id} must be rejected");
+    }
+
+    let req = test_get("/p_png/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let req = test_get("/catalog").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert!(body["tiles"]["p_png"].is_object());
+
+    let req = TestRequest::delete()
+        .uri("/_/sources/m_mvt")
+        .insert_header(auth())
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 404);
+    let req = test_get("/catalog").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.headers().get("x-catalog-version").unwrap(), "2");
+    let body: serde_json::Value = read_body_json(response).await;
+    assert!(body["tiles"]["m_mvt"].is_null());
+
+    let req = TestRequest::delete()
+        .uri("/_/sources/m_mvt")
+        .insert_header(auth())
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 404);
+
+    let req = test_get("/_/catalog/changes?since=0").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "version": 2,
+            "added": ["p_png"],
+            "removed": ["m_mvt"],
+            "modified": [],
+        })
+    );
+    let req = test_get("/_/catalog/changes?since=1").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body["added"], serde_json::json!([]));
+    assert_eq!(body["removed"], serde_json::json!(["m_mvt"]));
+    let req = test_get("/_/catalog/changes?since=3").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 410);
+}
+
+#[actix_rt::test]
+async fn mbt_ip_filter() {
+    let cfg = indoc! {"
+        trusted_proxies: [192.168.1.1/32]
+        ip_filter:
+            deny: [10.0.0.13/32]
+            sources:
+                m_mvt:
+                    allow: [10.0.0.0/8]
+    "};
+    let app = create_app! { &format!("{cfg}{CONFIG}") };
+    let get = |path: &str, ip: &str| {
+        test_get(path)
+            .peer_addr(format!("{ip}:1234").parse().unwrap())
+            .to_request()
+    };
+
+    let response = call_service(&app, get("/m_json/0/0/0", "8.8.8.8")).await;
+    assert!(response.status().is_success());
+    let response = call_service(&app, get("/m_mvt/0/0/0", "8.8.8.8")).await;
+    assert_eq!(response.status(), 403);
+    let response = call_service(&app, get("/m_mvt", "10.1.1.1")).await;
+    assert!(response.status().is_success());
+    let response = call_service(&app, get("/catalog", "10.0.0.13")).await;
+    assert_eq!(response.status(), 403);
+
+    let req = test_get("/m_mvt/0/0/0")
+        .peer_addr("192.168.1.1:1234".parse().unwrap())
+        .insert_header(("X-Forwarded-For", "10.1.1.1"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+/// the rules of the sources apply to every route serving them, and follow the renamed sources
+#[actix_rt::test]
+async fn mbt_ip_filter_routes() {
+    let app = create_app! { indoc! {"
+        ip_filter:
+            sources:
+                m_mvt:
+                    allow: [10.0.0.0/8]
+                maplibre_demo:
+                    allow: [10.0.0.0/8]
+                Overpass Mono Regular:
+                    allow: [10.0.0.0/8]
+        aliases:
+            m_mvt: cities
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        styles: ../tests/fixtures/styles/maplibre_demo.json
+        fonts: ../tests/fixtures/fonts/overpass-mono-regular.ttf
+    "} };
+    let get = |path: &str, ip: &str| {
+        test_get(path)
+            .peer_addr(format!("{ip}:1234").parse().unwrap())
+            .to_request()
+    };
+
+    for path in [
+        "/cities",
+        "/cities/0/0/0",
+        "/cities/quadkey/0",
+        "/cities/0/0/0.geojson",
+        "/cities/style.json",
+        "/style/maplibre_demo",
+        "/font/Overpass%20Mono%20Regular/0-255",
+    ] {
+        let response = call_service(&app, get(path, "8.8.8.8")).await;
+        assert_eq!(response.status(), 403, "{path}");
+        let response = call_service(&app, get(path, "10.1.1.1")).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+    let req = TestRequest::post()
+        .uri("/cities/tiles")
+        .peer_addr("8.8.8.8:1234".parse().unwrap())
+        .set_json(serde_json::json!({ "tiles": ["0/0/0"] }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 403);
+}
+
+#[actix_rt::test]
+async fn mbt_ip_filter_members() {
+    let app = create_app! { indoc! {"
+        ip_filter:
+            sources:
+                restricted:
+                    allow: [10.0.0.0/8]
+        composites:
+            mixed: [public, restricted]
+        variants:
+            cities:
+                sources:
+                    public: public
+                    restricted: restricted
+        mbtiles:
+            sources:
+                public: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                restricted: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "} };
+    let get = |path: &str, ip: &str| {
+        test_get(path)
+            .peer_addr(format!("{ip}:1234").parse().unwrap())
+            .to_request()
+    };
+
+    // the rules of the members of a composite, and of the selected variant
+    for path in [
+        "/mixed",
+        "/mixed/0/0/0",
+        "/mixed/0/0/0.geojson",
+        "/public,mixed/0/0/0",
+        "/cities/0/0/0?variant=restricted",
+    ] {
+        let response = call_service(&app, get(path, "8.8.8.8")).await;
+        assert_eq!(response.status(), 403, "{path}");
+        let response = call_service(&app, get(path, "10.1.1.1")).await;
+        assert!(response.status().is_success(), "{path}");
+    }
+    let response = call_service(&app, get("/cities/0/0/0", "8.8.8.8")).await;
+    assert!(response.status().is_success());
+    let req = TestRequest::post()
+        .uri("/mixed/tiles")
+        .peer_addr("8.8.8.8:1234".parse().unwrap())
+        .set_json(serde_json::json!({ "tiles": ["0/0/0"] }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 403);
+}
+
+#[actix_rt::test]
+async fn mbt_referer_filter() {
+    let cfg = indoc! {"
+        referer_filter:
+            m_mvt:
+                allow: ['https://*.example.com']
+    "};
+    let app = create_app! { &format!("{cfg}{CONFIG}") };
+
+    let response = call_service(&app, test_get("/m_json/0/0/0").to_request()).await;
+    assert!(response.status().is_success());
+    let response = call_service(&app, test_get("/m_mvt/0/0/0").to_request()).await;
+    assert_eq!(response.status(), 403);
+
+    let req = test_get("/m_mvt/0/0/0")
+        .insert_header(("Origin", "https://maps.example.com"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let req = test_get("/m_json,m_mvt/0/0/0")
+        .insert_header(("Referer", "https://evil.example.org/map.html"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 403);
+    let req = test_get("/m_mvt")
+        .insert_header(("Referer", "https://maps.example.com/map.html"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
+#[actix_rt::test]
+async fn mbt_referer_filter_members() {
+    let app = create_app! { indoc! {"
+        referer_filter:
+            licensed:
+                allow: [https://app.example.com]
+        composites:
+            mixed: [public, licensed]
+        variants:
+            cities:
+                default: public
+                sources:
+                    public: public
+                    licensed: licensed
+        mbtiles:
+            sources:
+                public: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                licensed: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "} };
+    let get = |path: &str, origin: &str| {
+        test_get(path)
+            .insert_header(("Origin", origin.to_string()))
+            .to_request()
+    };
+
+    // the web apps of the members of a composite, and of the selected variant
+    for path in [
+        "/mixed",
+        "/mixed/0/0/0",
+        "/mixed/0/0/0.geojson",
+        "/mixed/style.json",
+        "/cities/0/0/0?variant=licensed",
+    ] {
+        let response = call_service(&app, get(path, "https://evil.com")).await;
+        assert_eq!(response.status(), 403, "{path}");
+        assert_eq!(response.headers().get(VARY).unwrap(), "Origin, Referer");
+        let response = call_service(&app, get(path, "https://app.example.com")).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(response.headers().get(VARY).unwrap(), "Origin, Referer");
+    }
+    let response = call_service(&app, get("/cities/0/0/0", "https://evil.com")).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get(VARY).is_none());
+    let req = TestRequest::post()
+        .uri("/mixed/tiles")
+        .insert_header(("Origin", "https://evil.com"))
+        .set_json(serde_json::json!({ "tiles": ["0/0/0"] }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 403);
+}
+
+#[actix_rt::test]
+async fn mbt_prefetch_hints() {
+    let app = create_app! { &format!("prefetch_hints: true\n{CONFIG}") };
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get(LINK).is_none());
+
+    let req = test_get("/m_mvt/1/1/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(LINK).unwrap(),
+        "<../0/0>; rel=prefetch, <../0/1>; rel=prefetch, <../1/1>; rel=prefetch"
+    );
+}
+
+/// get an MVT tile with accepted zstd enc
+#[actix_rt::test]
+async fn mbt_get_mvt_zstd() {
+    let app = create_app! { CONFIG };
+    let accept = (ACCEPT_ENCODING, "zstd");
+    let req = test_get("/m_mvt/0/0/0").insert_header(accept).to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "zstd");
+    let body = martin::decode_zstd(&read_body(response).await).unwrap();
+    assert_eq!(body.len(), 1828);
+}
+
 /// get an uncompressed MVT tile
 #[actix_rt::test]
 async fn mbt_get_raw_mvt() {
@@ -271,6 +1183,21 @@ async fn mbt_get_raw_mvt_gzip_br() {
     assert_eq!(body.len(), 1828);
 }
 
+/// get an uncompressed MVT tile with accepted zstd
+#[actix_rt::test]
+async fn mbt_get_raw_mvt_zstd() {
+    let app = create_app! { CONFIG };
+    let accept = (ACCEPT_ENCODING, "zstd");
+    let req = test_get("/m_raw_mvt/0/0/0")
+        .insert_header(accept)
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "zstd");
+    let body = martin::decode_zstd(&read_body(response).await).unwrap();
+    assert_eq!(body.len(), 1828);
+}
+
 /// get a JSON tile
 #[actix_rt::test]
 async fn mbt_get_json() {
@@ -305,3 +1232,277 @@ async fn mbt_get_json_gzip() {
     let body = decode_gzip(&body).unwrap();
     assert_eq!(body.len(), 13);
 }
+
+/// get an encrypted tile, and decrypt it with the configured key
+#[actix_rt::test]
+async fn mbt_get_encrypted() {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, NONCE_LEN};
+
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_json: ../tests/fixtures/mbtiles/json.mbtiles
+                m_json_open: ../tests/fixtures/mbtiles/json.mbtiles
+        encryption:
+            m_json:
+                key: 000102030405060708090a0b0c0d0e0f
+                key_id: v1
+    "} };
+    let req = test_get("/m_json/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(
+        headers.get(CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    assert!(headers.get(CONTENT_ENCODING).is_none());
+    assert_eq!(headers.get("x-tile-encryption").unwrap(), "aes-gcm");
+    assert_eq!(headers.get("x-tile-key-id").unwrap(), "v1");
+    assert_eq!(
+        headers.get("x-tile-content-type").unwrap(),
+        "application/json"
+    );
+    let body = read_body(response).await;
+    let (nonce, payload) = body.split_at(NONCE_LEN);
+    let mut payload = payload.to_vec();
+    let key: Vec<u8> = (0..16).collect();
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &key).unwrap());
+    let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+    let tile = key
+        .open_in_place(nonce, Aad::from("0/0/0"), &mut payload)
+        .unwrap();
+    assert_eq!(tile.len(), 13);
+
+    // encrypted tiles cannot be combined with the unencrypted ones
+    let req = test_get("/m_json,m_json_open/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+/// get a tile stamped with a text watermark, and compare it with the original tile
+#[actix_rt::test]
+async fn mbt_get_watermarked() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_png: ../tests/fixtures/mbtiles/geography-class-png.mbtiles
+                m_png_stamped: ../tests/fixtures/mbtiles/geography-class-png.mbtiles
+        watermarks:
+            m_png_stamped:
+                text: Martin
+                font: ../tests/fixtures/fonts/overpass-mono-regular.ttf
+                font_size: 24
+                color: '#ff0000'
+                opacity: 1
+    "} };
+    let req = test_get("/m_png/0/0/0").to_request();
+    let original = read_body(call_service(&app, req).await).await;
+    let req = test_get("/m_png_stamped/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+    let stamped = read_body(response).await;
+
+    let original = image::load_from_memory(&original).unwrap().to_rgba8();
+    let stamped = image::load_from_memory(&stamped).unwrap().to_rgba8();
+    assert_eq!(original.dimensions(), stamped.dimensions());
+    // the watermark is in the bottom right corner
+    assert_eq!(original.get_pixel(10, 10), stamped.get_pixel(10, 10));
+    let (w, h) = stamped.dimensions();
+    let corner = |img: &image::RgbaImage| {
+        (w - 80..w - 4)
+            .flat_map(|x| (h - 30..h - 4).map(move |y| (x, y)))
+            .filter(|&(x, y)| img.get_pixel(x, y)[0] == 255 && img.get_pixel(x, y)[1] == 0)
+            .count()
+    };
+    assert!(corner(&stamped) > corner(&original));
+}
+
+/// get the high-resolution tiles stitched from the next zoom, or upsampled at the maximum zoom
+#[actix_rt::test]
+async fn mbt_get_retina() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_png: ../tests/fixtures/mbtiles/geography-class-png.mbtiles
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "} };
+    for (path, content_type) in [
+        ("/m_png/0/0/0@2x.png", "image/png"),
+        ("/m_png/0/0/0@2x.jpg", "image/jpeg"),
+        ("/m_png/1/1/0@2x.png", "image/png"),
+    ] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), content_type);
+        let image = image::load_from_memory(&read_body(response).await).unwrap();
+        assert_eq!((image.width(), image.height()), (512, 512));
+    }
+
+    let req = test_get("/m_png/0/0/0@2x.webp").to_request();
+    assert_eq!(call_service(&app, req).await.status().as_u16(), 404);
+    let req = test_get("/m_mvt/0/0/0@2x.png").to_request();
+    assert_eq!(call_service(&app, req).await.status().as_u16(), 400);
+}
+
+/// get many tiles in a single multipart response, with the status of each tile
+#[actix_rt::test]
+async fn mbt_post_tiles() {
+    let app = create_app! { CONFIG };
+    let req = TestRequest::post()
+        .uri("/m_mvt/tiles")
+        .insert_header((ACCEPT_ENCODING, "gzip"))
+        .set_json(serde_json::json!({ "tiles": ["0/0/0", "6/38/19", "6/0/0", "1/5/5"] }))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/mixed; boundary=")
+        .unwrap()
+        .to_string();
+    let body = read_body(response).await;
+    let body = String::from_utf8_lossy(&body);
+    let parts: Vec<_> = body.split(&format!("--{boundary}")).skip(1).collect();
+    assert_eq!(parts.len(), 5);
+    assert_eq!(parts[4], "--\r\n");
+    let statuses: Vec<_> = parts[..4]
+        .iter()
+        .map(|part| {
+            let location = part.lines().nth(1).unwrap();
+            let status = part.lines().nth(2).unwrap();
+            format!("{location} {status}")
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            "Content-Location: /m_mvt/0/0/0 X-Tile-Status: 200",
+            "Content-Location: /m_mvt/6/38/19 X-Tile-Status: 200",
+            "Content-Location: /m_mvt/6/0/0 X-Tile-Status: 204",
+            "Content-Location: /m_mvt/1/5/5 X-Tile-Status: 404",
+        ]
+    );
+    assert!(parts[1].contains("content-encoding: gzip\r\n"));
+
+    let req = TestRequest::post()
+        .uri("/m_mvt/tiles")
+        .set_json(serde_json::json!({ "tiles": ["0/0"] }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status().as_u16(), 400);
+}
+
+/// each tile of a batch counts against the tile quota and the maximum zoom level on its own
+#[actix_rt::test]
+async fn mbt_post_tiles_quotas() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        throttle:
+            anonymous_monthly_tiles: 3
+            anonymous_max_zoom: 5
+    "} };
+    let req = TestRequest::post()
+        .uri("/m_mvt/tiles")
+        .set_json(serde_json::json!({ "tiles": ["0/0/0", "6/38/19", "1/0/0", "1/1/0", "1/0/1"] }))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body = read_body(response).await;
+    let statuses: Vec<_> = String::from_utf8_lossy(&body)
+        .lines()
+        .filter_map(|line| line.strip_prefix("X-Tile-Status: "))
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(statuses, ["200", "403", "200", "200", "429"]);
+
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    assert_eq!(call_service(&app, req).await.status().as_u16(), 429);
+}
+
+#[actix_rt::test]
+async fn font_coverage() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        fonts: ../tests/fixtures/fonts/overpass-mono-regular.ttf
+    "} };
+
+    let req = test_get("/font").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    let font = &body["Overpass Mono Regular"];
+    assert_eq!(font["family"], "Overpass Mono");
+    assert_eq!(font["ranges"][0]["start"], 0);
+    assert_eq!(font["ranges"][0]["end"], 255);
+}
+
+#[actix_rt::test]
+async fn style_with_rewritten_urls() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                world_cities: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        styles: ../tests/fixtures/styles/maplibre_demo.json
+    "} };
+
+    let req = test_get("/style/maplibre_demo")
+        .insert_header(("x-rewrite-url", "/tiles/style/maplibre_demo"))
+        .insert_header(("x-forwarded-host", "maps.example.org"))
+        .insert_header(("x-forwarded-proto", "https"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(
+        body["sources"]["cities"]["url"],
+        "https://maps.example.org/tiles/world_cities"
+    );
+    assert_eq!(body["sprite"], "https://maps.example.org/tiles/sprite/src1");
+    assert_eq!(
+        body["glyphs"],
+        "https://maps.example.org/tiles/font/{fontstack}/{range}"
+    );
+
+    let response = call_service(&app, test_get("/style/missing").to_request()).await;
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[actix_rt::test]
+async fn mbt_hidden_source() {
+    let app = create_app! { indoc! {"
+        composites:
+          cities: [m_mvt, m_qa]
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                m_qa:
+                    path: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                    hidden: true
+    "} };
+
+    let req = test_get("/catalog").to_request();
+    let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+    let tiles = body["tiles"].as_object().unwrap();
+    assert!(tiles.contains_key("m_mvt"));
+    assert!(tiles.contains_key("cities"));
+    assert!(!tiles.contains_key("m_qa"));
+
+    for path in ["/m_qa", "/m_qa/0/0/0", "/cities", "/m_mvt,m_qa/0/0/0"] {
+        let response = call_service(&app, test_get(path).to_request()).await;
+        assert!(response.status().is_success(), "{path}");
+        assert_eq!(response.headers().get("x-robots-tag").unwrap(), "noindex");
+    }
+    let response = call_service(&app, test_get("/m_mvt/0/0/0").to_request()).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get("x-robots-tag").is_none());
+}
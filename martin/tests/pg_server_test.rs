@@ -1,6 +1,8 @@
 use actix_http::Request;
 use actix_web::http::StatusCode;
 use actix_web::test::{call_and_read_body_json, call_service, read_body, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
@@ -17,14 +19,14 @@ fn init() {
 
 macro_rules! create_app {
     ($sources:expr) => {{
-        let cfg = mock_cfg(indoc::indoc!($sources));
-        let state = mock_sources(cfg).await.0;
+        let (state, cfg) = mock_sources(mock_cfg(indoc::indoc!($sources))).await;
         ::actix_web::test::init_service(
             ::actix_web::App::new()
-                .app_data(actix_web::web::Data::new(
+                .app_data(Data::new(ArcSwap::from_pointee(
                     ::martin::srv::Catalog::new(&state).unwrap(),
-                ))
-                .app_data(actix_web::web::Data::new(state.tiles))
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
                 .configure(::martin::srv::router),
         )
         .await
@@ -959,6 +961,18 @@ postgres:
     assert!(response.status().is_success());
 }
 
+#[actix_rt::test]
+async fn pg_get_deep_health_returns_ok() {
+    let app = create_app! { "
+postgres:
+  connection_string: $DATABASE_URL
+"};
+
+    let req = test_get("/health?deep=true");
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+}
+
 #[actix_rt::test]
 async fn pg_tables_feature_id() {
     let cfg = mock_pgcfg(indoc! {"
@@ -1085,10 +1099,11 @@ tables:
     let state = mock_sources(cfg.clone()).await.0;
     let app = ::actix_web::test::init_service(
         ::actix_web::App::new()
-            .app_data(actix_web::web::Data::new(
+            .app_data(Data::new(ArcSwap::from_pointee(
                 ::martin::srv::Catalog::new(&state).unwrap(),
-            ))
-            .app_data(actix_web::web::Data::new(state.tiles))
+            )))
+            .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+            .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
             .configure(::martin::srv::router),
     )
     .await;
@@ -1,5 +1,12 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
 use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
 use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
@@ -16,13 +23,14 @@ fn init() {
 
 macro_rules! create_app {
     ($sources:expr) => {{
-        let state = mock_sources(mock_cfg($sources)).await.0;
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
         ::actix_web::test::init_service(
             ::actix_web::App::new()
-                .app_data(actix_web::web::Data::new(
+                .app_data(Data::new(ArcSwap::from_pointee(
                     ::martin::srv::Catalog::new(&state).unwrap(),
-                ))
-                .app_data(actix_web::web::Data::new(state.tiles))
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
                 .configure(::martin::srv::router),
         )
         .await
@@ -130,3 +138,101 @@ async fn pmt_get_raster_gzip() {
     let body = read_body(response).await;
     assert_eq!(body.len(), 18404);
 }
+
+/// Serve a file to any path using HTTP range requests, one request per connection.
+/// Returns the server address, and the lowercase headers of all received requests.
+fn serve_file_ranges(path: &str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let data = std::fs::read(path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut head).unwrap() > 2 && !head.ends_with("\r\n\r\n") {}
+            let head = head.to_lowercase();
+            let range = head
+                .lines()
+                .find_map(|l| l.strip_prefix("range: bytes="))
+                .and_then(|v| v.split_once('-'))
+                .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()));
+            let is_listing = head.lines().next().unwrap().contains("list-type=2");
+            received.lock().unwrap().push(head);
+            if is_listing {
+                let body = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                    <Contents><Key>dir/toner.pmtiles</Key></Contents>\
+                    <Contents><Key>dir/readme.txt</Key></Contents></ListBucketResult>";
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(body.as_bytes());
+                continue;
+            }
+            let Some((start, end)) = range else {
+                let _ = stream.write_all(
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+                continue;
+            };
+            let body = &data[start..=end.min(data.len() - 1)];
+            let head = format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {start}-{}/{}\r\nConnection: close\r\n\r\n",
+                body.len(),
+                start + body.len() - 1,
+                data.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        }
+    });
+    (addr, requests)
+}
+
+#[actix_rt::test]
+async fn pmt_get_remote() {
+    let (addr, requests) =
+        serve_file_ranges("../tests/fixtures/pmtiles/stamen_toner__raster_CC-BY+ODbL_z3.pmtiles");
+    // S3 settings are read when the source is created, and no other test uses them
+    std::env::set_var("AWS_ENDPOINT_URL", format!("http://{addr}"));
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test-key");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test-secret");
+    let cfg = format!(
+        "pmtiles:\n  paths: s3://bucket/dir/\n  sources:\n    p_http: http://{addr}/toner.pmtiles\n    p_s3: s3://bucket/toner.pmtiles"
+    );
+    let app = create_app! { &cfg };
+
+    for source in ["p_http", "p_s3", "toner"] {
+        let req = test_get(&format!("/{source}")).to_request();
+        let body: TileJSON = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body.maxzoom, Some(3));
+
+        let req = test_get(&format!("/{source}/0/0/0")).to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+        assert_eq!(read_body(response).await.len(), 18404);
+    }
+
+    let requests = requests.lock().unwrap();
+    let s3_requests: Vec<_> = requests
+        .iter()
+        .filter(|r| r.starts_with("get /bucket/toner.pmtiles "))
+        .collect();
+    assert!(!s3_requests.is_empty());
+    assert!(s3_requests
+        .iter()
+        .all(|r| r.contains("authorization: aws4-hmac-sha256 credential=test-key/")));
+    assert!(requests
+        .iter()
+        .any(|r| r.starts_with("get /toner.pmtiles ") && !r.contains("authorization")));
+    assert!(requests
+        .iter()
+        .any(|r| r.starts_with("get /bucket/?delimiter=%2f&list-type=2&prefix=dir%2f ")));
+    assert!(requests
+        .iter()
+        .any(|r| r.starts_with("get /bucket/dir/toner.pmtiles ")));
+}
@@ -0,0 +1,178 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, EXPIRES};
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use ctor::ctor;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tilejson::TileJSON;
+
+pub mod utils;
+pub use utils::*;
+
+#[ctor]
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+macro_rules! create_app {
+    ($sources:expr) => {{
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
+        ::actix_web::test::init_service(
+            ::actix_web::App::new()
+                .app_data(Data::new(ArcSwap::from_pointee(
+                    ::martin::srv::Catalog::new(&state).unwrap(),
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
+                .configure(::martin::srv::router),
+        )
+        .await
+    }};
+}
+
+fn test_get(path: &str) -> TestRequest {
+    TestRequest::get().uri(path)
+}
+
+/// A vector tile with a single empty layer named `up`
+const MVT: &[u8] = b"\x1a\x06\x0a\x02up\x78\x02";
+
+/// Caching headers of the upstream tiles
+const TILE_HEADERS: &str = "Cache-Control: public, max-age=60\r\nETag: \"v1\"\r\nExpires: Thu, 01 Jan 2037 00:00:00 GMT\r\n";
+
+/// Serve a `TileJSON` at `/tiles.json` and a gzip-compressed vector tile with caching headers
+/// at `/0/0/0.pbf`, answering all other paths with 404, one request per connection.
+/// Returns the server address, and the lowercase headers of all received requests.
+fn serve_tiles() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let tilejson = format!(
+        r#"{{"tilejson":"3.0.0","tiles":["http://{addr}/{{z}}/{{x}}/{{y}}.pbf"],"maxzoom":5,"attribution":"Upstream"}}"#
+    );
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(MVT).unwrap();
+    let tile = encoder.finish().unwrap();
+    let tile_headers = format!("Content-Encoding: gzip\r\n{TILE_HEADERS}");
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut head).unwrap() > 2 && !head.ends_with("\r\n\r\n") {}
+            let head = head.to_lowercase();
+            let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+            received.lock().unwrap().push(head);
+            let (status, extra, body) = match path.as_str() {
+                "/tiles.json" => ("200 OK", "", tilejson.as_bytes()),
+                "/0/0/0.pbf" => ("200 OK", tile_headers.as_str(), tile.as_slice()),
+                _ => ("404 Not Found", "", &b""[..]),
+            };
+            let head = format!(
+                "HTTP/1.1 {status}\r\n{extra}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        }
+    });
+    (addr, requests)
+}
+
+#[actix_rt::test]
+async fn proxy_get_tiles() {
+    let (addr, requests) = serve_tiles();
+    let cfg = format!(
+        "proxy:
+  up_json:
+    url: http://{addr}/tiles.json
+    headers:
+      Authorization: Bearer secret
+  up_xyz:
+    url: http://{addr}/{{z}}/{{x}}/{{y}}.pbf
+    maxzoom: 2
+    cache: false"
+    );
+    let app = create_app! { &cfg };
+
+    let req = test_get("/up_json").to_request();
+    let body: TileJSON = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body.maxzoom, Some(5));
+    assert_eq!(body.attribution, Some("Upstream".to_string()));
+    let req = test_get("/up_xyz").to_request();
+    let body: TileJSON = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body.maxzoom, Some(2));
+
+    for source in ["up_json", "up_xyz"] {
+        let req = test_get(&format!("/{source}/0/0/0")).to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        let headers = response.headers();
+        assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/x-protobuf");
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+        assert_eq!(read_body(response).await, MVT);
+
+        let req = test_get(&format!("/{source}/1/0/0")).to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    let requests = requests.lock().unwrap();
+    assert!(requests
+        .iter()
+        .filter(|r| r.starts_with("get /tiles.json "))
+        .all(|r| r.contains("authorization: bearer secret")));
+    assert!(requests
+        .iter()
+        .any(|r| r.starts_with("get /1/0/0.pbf ") && !r.contains("authorization")));
+}
+
+#[actix_rt::test]
+async fn proxy_cache_headers() {
+    let (addr, _) = serve_tiles();
+    let cfg = format!(
+        "proxy:
+  up_fwd:
+    url: http://{addr}/{{z}}/{{x}}/{{y}}.pbf
+    cache: false
+  up_override:
+    url: http://{addr}/{{z}}/{{x}}/{{y}}.pbf
+    cache: false
+    cache_headers: false
+    cache_control: no-store"
+    );
+    let app = create_app! { &cfg };
+
+    let req = test_get("/up_fwd/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "public, max-age=60");
+    // the tile is decompressed, so the upstream validator is only weak
+    assert_eq!(headers.get(ETAG).unwrap(), "W/\"v1\"");
+    assert_eq!(
+        headers.get(EXPIRES).unwrap(),
+        "Thu, 01 Jan 2037 00:00:00 GMT"
+    );
+
+    let req = test_get("/up_override/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(headers.get(CACHE_CONTROL).unwrap(), "no-store");
+    assert!(headers.get(ETAG).is_none());
+    assert!(headers.get(EXPIRES).is_none());
+
+    // the headers of one source do not apply to the merged tiles
+    let req = test_get("/up_fwd,up_override/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    assert!(response.headers().get(ETAG).is_none());
+}
@@ -0,0 +1,90 @@
+use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
+use actix_web::web::Data;
+use arc_swap::ArcSwap;
+use ctor::ctor;
+use indoc::indoc;
+use tilejson::TileJSON;
+
+pub mod utils;
+pub use utils::*;
+
+#[ctor]
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+macro_rules! create_app {
+    ($sources:expr) => {{
+        let (state, cfg) = mock_sources(mock_cfg($sources)).await;
+        ::actix_web::test::init_service(
+            ::actix_web::App::new()
+                .app_data(Data::new(ArcSwap::from_pointee(
+                    ::martin::srv::Catalog::new(&state).unwrap(),
+                )))
+                .app_data(Data::new(::martin::srv::TileOptions::new(&cfg.srv, &state)))
+                .app_data(Data::new(ArcSwap::from_pointee(state.tiles)))
+                .configure(::martin::srv::router),
+        )
+        .await
+    }};
+}
+
+fn test_get(path: &str) -> TestRequest {
+    TestRequest::get().uri(path)
+}
+
+const CONFIG: &str = indoc! {"
+        sqlite:
+            sources:
+                s_legacy:
+                    path: ../tests/fixtures/sqlite/legacy_xyz.sqlite
+                    table: map
+                    zoom_column: z
+                    x_column: x
+                    y_column: y
+                    data_column: image
+                    scheme: xyz
+                s_mbtiles: ../tests/fixtures/mbtiles/world_cities.mbtiles
+    "};
+
+#[actix_rt::test]
+async fn sqlite_get_tilejson() {
+    let app = create_app! { CONFIG };
+
+    let req = test_get("/s_legacy").to_request();
+    let response = call_service(&app, req).await;
+    assert!(response.status().is_success());
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(body.minzoom, Some(0));
+    assert_eq!(body.maxzoom, Some(2));
+    let bounds = body.bounds.unwrap();
+    assert!((bounds.left + 180.0).abs() < 1e-6);
+    assert!((bounds.right - 180.0).abs() < 1e-6);
+
+    let req = test_get("/s_mbtiles").to_request();
+    let body: TileJSON = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(body.minzoom, Some(0));
+    assert_eq!(body.maxzoom, Some(6));
+}
+
+#[actix_rt::test]
+async fn sqlite_get_tiles() {
+    let app = create_app! { CONFIG };
+
+    // the same tiles are stored with xyz rows in one file, and with tms rows in the other
+    for path in ["0/0/0", "1/1/0", "2/0/1", "2/3/2"] {
+        let req = test_get(&format!("/s_legacy/{path}")).to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        let legacy = read_body(response).await;
+
+        let req = test_get(&format!("/s_mbtiles/{path}")).to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_success());
+        assert_eq!(legacy, read_body(response).await, "tile {path}");
+    }
+
+    let req = test_get("/s_legacy/2/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), 204);
+}
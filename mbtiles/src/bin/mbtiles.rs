@@ -2,9 +2,12 @@ use std::path::{Path, PathBuf};
 
 use clap::{Parser, Subcommand};
 use log::error;
-use mbtiles::{apply_patch, AggHashType, IntegrityCheckType, MbtResult, Mbtiles, MbtilesCopier};
+use mbtiles::{
+    apply_patch, calc_agg_tiles_hash, AggHashType, IntegrityCheckType, MbtResult, Mbtiles,
+    MbtilesCopier, MbtilesMerger, AGG_TILES_HASH_BEFORE_APPLY, AGG_TILES_HASH_IN_DIFF,
+};
 
-#[derive(Parser, PartialEq, Eq, Debug)]
+#[derive(Parser, PartialEq, Debug)]
 #[command(
     version,
     name = "mbtiles",
@@ -18,10 +21,10 @@ pub struct Args {
     command: Commands,
 }
 
-#[derive(Subcommand, PartialEq, Eq, Debug)]
+#[derive(Subcommand, PartialEq, Debug)]
 enum Commands {
-    /// Show MBTiels file summary statistics
-    #[command(name = "summary", alias = "info")]
+    /// Show MBTiles file summary statistics: tile counts and sizes per zoom, coverage, and duplicates
+    #[command(name = "summary", visible_alias = "stats", alias = "info")]
     Summary { file: PathBuf },
     /// Prints all values in the metadata table in a free-style, unstable YAML format
     #[command(name = "meta-all")]
@@ -50,13 +53,29 @@ enum Commands {
     /// Copy tiles from one mbtiles file to another.
     #[command(name = "copy")]
     Copy(MbtilesCopier),
-    /// Apply diff file generated from 'copy' command
+    /// Merge several mbtiles files into one, e.g. the files generated for different regions
+    #[command(name = "merge")]
+    Merge(MbtilesMerger),
+    /// Compare two mbtiles files, and save the added, changed, and removed tiles into a diff file
+    #[command(name = "diff")]
+    Diff {
+        /// File to compare with, i.e. the file the diff will be applied to
+        file1: PathBuf,
+        /// Modified file, i.e. the result of applying the diff to the first file
+        file2: PathBuf,
+        /// Diff file to create
+        diff_file: PathBuf,
+    },
+    /// Apply diff file generated from 'diff' or 'copy' commands
     #[command(name = "apply-patch", alias = "apply-diff")]
     ApplyPatch {
         /// MBTiles file to apply diff to
         src_file: PathBuf,
         /// Diff file
         diff_file: PathBuf,
+        /// Apply the diff even if the file hashes do not match the ones recorded in the diff file
+        #[arg(long)]
+        force: bool,
     },
     /// Validate tile data if hash of tile data exists in file
     #[command(name = "validate")]
@@ -106,11 +125,22 @@ async fn main_int() -> anyhow::Result<()> {
         Commands::Copy(opts) => {
             opts.run().await?;
         }
+        Commands::Merge(opts) => {
+            opts.run().await?;
+        }
+        Commands::Diff {
+            file1,
+            file2,
+            diff_file,
+        } => {
+            create_diff(file1, file2, diff_file).await?;
+        }
         Commands::ApplyPatch {
             src_file,
             diff_file,
+            force,
         } => {
-            apply_patch(src_file, diff_file).await?;
+            apply_patch(src_file, diff_file, force).await?;
         }
         Commands::Validate {
             file,
@@ -142,6 +172,29 @@ async fn main_int() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Create a diff file, recording the aggregate tiles hashes of both files
+/// so that `apply-patch` can verify the file it is applied to and the result.
+async fn create_diff(file1: PathBuf, file2: PathBuf, diff_file: PathBuf) -> MbtResult<()> {
+    let before_hash = calc_hash(&file1).await?;
+    let after_hash = calc_hash(&file2).await?;
+
+    let mut opts = MbtilesCopier::new(file1, diff_file.clone());
+    opts.diff_with_file = Some(file2);
+    let mut conn = opts.run().await?;
+
+    let mbt = Mbtiles::new(diff_file)?;
+    mbt.set_metadata_value(&mut conn, AGG_TILES_HASH_BEFORE_APPLY, &before_hash)
+        .await?;
+    mbt.set_metadata_value(&mut conn, AGG_TILES_HASH_IN_DIFF, &after_hash)
+        .await
+}
+
+async fn calc_hash(file: &Path) -> MbtResult<String> {
+    let mbt = Mbtiles::new(file)?;
+    let mut conn = mbt.open_readonly().await?;
+    calc_agg_tiles_hash(&mut conn).await
+}
+
 async fn meta_print_all(file: &Path) -> anyhow::Result<()> {
     let mbt = Mbtiles::new(file)?;
     let mut conn = mbt.open_readonly().await?;
@@ -178,7 +231,7 @@ mod tests {
     use mbtiles::{CopyDuplicateMode, MbtilesCopier};
 
     use super::*;
-    use crate::Commands::{ApplyPatch, Copy, MetaGetValue, MetaSetValue, Validate};
+    use crate::Commands::{ApplyPatch, Copy, Diff, Merge, MetaGetValue, MetaSetValue, Validate};
     use crate::{Args, IntegrityCheckType};
 
     #[test]
@@ -441,6 +494,63 @@ mod tests {
                 command: ApplyPatch {
                     src_file: PathBuf::from("src_file"),
                     diff_file: PathBuf::from("diff_file"),
+                    force: false,
+                }
+            }
+        );
+        assert_eq!(
+            Args::parse_from(["mbtiles", "apply-patch", "src_file", "diff_file", "--force"]),
+            Args {
+                verbose: false,
+                command: ApplyPatch {
+                    src_file: PathBuf::from("src_file"),
+                    diff_file: PathBuf::from("diff_file"),
+                    force: true,
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge_arguments() {
+        let mut opt = MbtilesMerger::new(
+            PathBuf::from("dst_file"),
+            vec![PathBuf::from("src1"), PathBuf::from("src2")],
+        );
+        opt.on_duplicate = CopyDuplicateMode::Abort;
+        assert_eq!(
+            Args::parse_from([
+                "mbtiles",
+                "merge",
+                "dst_file",
+                "src1",
+                "src2",
+                "--on-duplicate",
+                "abort",
+            ]),
+            Args {
+                verbose: false,
+                command: Merge(opt)
+            }
+        );
+        assert_eq!(
+            Args::try_parse_from(["mbtiles", "merge", "dst_file"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn test_diff_with_arguments() {
+        assert_eq!(
+            Args::parse_from(["mbtiles", "diff", "file1", "file2", "diff_file"]),
+            Args {
+                verbose: false,
+                command: Diff {
+                    file1: PathBuf::from("file1"),
+                    file2: PathBuf::from("file2"),
+                    diff_file: PathBuf::from("diff_file"),
                 }
             }
         );
@@ -1,13 +1,16 @@
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr as _;
 
 #[cfg(feature = "cli")]
 use clap::{Args, ValueEnum};
 use enum_display::EnumDisplay;
 use log::{debug, info};
+use martin_tile_utils::{tile_index, TileArea, TileRect};
 use serde::{Deserialize, Serialize};
 use sqlite_hashes::rusqlite::{params_from_iter, Connection};
-use sqlx::{query, Executor as _, Row, SqliteConnection};
+use sqlx::{query, query_scalar, Connection as _, Executor as _, Row, SqliteConnection};
+use tilejson::{Bounds, Center};
 
 use crate::errors::MbtResult;
 use crate::queries::{
@@ -15,8 +18,8 @@ use crate::queries::{
 };
 use crate::MbtType::{Flat, FlatWithHash, Normalized};
 use crate::{
-    reset_db_settings, MbtError, MbtType, MbtTypeCli, Mbtiles, AGG_TILES_HASH,
-    AGG_TILES_HASH_IN_DIFF,
+    invert_y_value, reset_db_settings, MbtError, MbtType, MbtTypeCli, Mbtiles, AGG_TILES_HASH,
+    AGG_TILES_HASH_BEFORE_APPLY, AGG_TILES_HASH_IN_DIFF,
 };
 
 #[derive(PartialEq, Eq, Default, Debug, Clone, Copy, EnumDisplay, Serialize, Deserialize)]
@@ -40,7 +43,7 @@ impl CopyDuplicateMode {
     }
 }
 
-#[derive(Clone, Default, PartialEq, Eq, Debug)]
+#[derive(Clone, Default, PartialEq, Debug)]
 #[cfg_attr(feature = "cli", derive(Args))]
 pub struct MbtilesCopier {
     /// MBTiles file to read from
@@ -74,6 +77,16 @@ pub struct MbtilesCopier {
     /// List of zoom levels to copy
     #[cfg_attr(feature = "cli", arg(long, value_delimiter = ','))]
     pub zoom_levels: Vec<u8>,
+    /// Bounds to copy, as `left,bottom,right,top` longitudes and latitudes. Can be specified multiple times.
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, value_name = "BBOX", allow_hyphen_values = true)
+    )]
+    pub bbox: Vec<Bounds>,
+    /// `GeoJSON` file with the polygons to copy. Only the tiles intersecting the polygons are copied.
+    /// If used together with `--bbox`, the tiles within either of them are copied.
+    #[cfg_attr(feature = "cli", arg(long, value_name = "GEOJSON", alias = "polygon"))]
+    pub area: Option<PathBuf>,
     /// Compare source file with this file, and only copy non-identical tiles to destination.
     /// It should be later possible to run `mbtiles apply-diff SRC_FILE DST_FILE` to get the same DIFF file.
     #[cfg_attr(feature = "cli", arg(long, conflicts_with("apply_patch")))]
@@ -92,6 +105,7 @@ struct MbtileCopierInt {
     src_mbtiles: Mbtiles,
     dst_mbtiles: Mbtiles,
     options: MbtilesCopier,
+    area: Option<TileArea>,
 }
 
 impl MbtilesCopier {
@@ -106,6 +120,8 @@ impl MbtilesCopier {
             on_duplicate: CopyDuplicateMode::Override,
             min_zoom: None,
             max_zoom: None,
+            bbox: Vec::default(),
+            area: None,
             diff_with_file: None,
             apply_patch: None,
             skip_agg_tiles_hash: false,
@@ -147,10 +163,25 @@ impl MbtileCopierInt {
             }
         }
 
+        let has_area = !options.bbox.is_empty() || options.area.is_some();
+        if has_area && (options.diff_with_file.is_some() || options.apply_patch.is_some()) {
+            return Err(MbtError::UnsupportedCopyOperation {
+                reason: "bbox and area filters cannot be used when diffing or applying a patch"
+                    .to_string(),
+            });
+        }
+        for bbox in &options.bbox {
+            if bbox.left > bbox.right || bbox.bottom > bbox.top {
+                return Err(MbtError::InvalidBbox(*bbox));
+            }
+        }
+        let area = options.area.as_deref().map(read_area).transpose()?;
+
         Ok(MbtileCopierInt {
             src_mbtiles: Mbtiles::new(&options.src_file)?,
             dst_mbtiles: Mbtiles::new(&options.dst_file)?,
             options,
+            area,
         })
     }
 
@@ -210,7 +241,8 @@ impl MbtileCopierInt {
         };
 
         let (where_clause, query_args) = self.get_where_clause();
-        let select_from = format!("{select_from} {where_clause}");
+        let area_clause = self.init_area_filter(&mut conn, src_type).await?;
+        let select_from = format!("{select_from} {where_clause}{area_clause}");
         let on_dupl = self.options.on_duplicate.to_sql();
         let sql_cond = self.get_on_duplicate_sql_cond(dst_type);
 
@@ -237,10 +269,17 @@ impl MbtileCopierInt {
             self.copy_metadata(&rusqlite_conn, &dif, on_dupl)?;
         }
 
+        if dif.is_none() {
+            self.update_filtered_metadata(&mut conn).await?;
+        }
+
         if !self.options.skip_agg_tiles_hash {
             dst_mbt.update_agg_tiles_hash(&mut conn).await?;
         }
 
+        if !area_clause.is_empty() {
+            conn.execute("DROP TABLE temp.copy_area").await?;
+        }
         detach_db(&mut conn, "sourceDb").await?;
         // Ignore error because we might not have attached diffDb
         let _ = detach_db(&mut conn, "diffDb").await;
@@ -287,7 +326,7 @@ impl MbtileCopierInt {
                  ON srcMD.name = difMD.name
             WHERE difMD.name ISNULL OR difMD.value NOTNULL
         ) joinedMD
-        WHERE name != '{AGG_TILES_HASH}'"
+        WHERE name != '{AGG_TILES_HASH}' AND name != '{AGG_TILES_HASH_BEFORE_APPLY}'"
                 );
             }
             if self.options.diff_with_file.is_some() {
@@ -578,6 +617,156 @@ impl MbtileCopierInt {
         }
     }
 
+    /// Save the tile ranges covered by the bbox and area filters into a temporary table,
+    /// and return the SQL condition selecting only the tiles within them.
+    async fn init_area_filter(
+        &self,
+        conn: &mut SqliteConnection,
+        src_type: MbtType,
+    ) -> MbtResult<String> {
+        if self.options.bbox.is_empty() && self.area.is_none() {
+            return Ok(String::new());
+        }
+
+        let table = match src_type {
+            Flat => "tiles",
+            FlatWithHash => "tiles_with_hash",
+            Normalized { .. } => "map",
+        };
+        let sql = format!("SELECT min(zoom_level), max(zoom_level) FROM sourceDb.{table}");
+        let row = query(&sql).fetch_one(&mut *conn).await?;
+        let (Some(min), Some(max)) = (row.get::<Option<u8>, _>(0), row.get::<Option<u8>, _>(1))
+        else {
+            return Ok(String::new());
+        };
+        let opts = &self.options;
+        let zooms: Vec<u8> = if opts.zoom_levels.is_empty() {
+            (min.max(opts.min_zoom.unwrap_or(0))..=max.min(opts.max_zoom.unwrap_or(u8::MAX)))
+                .collect()
+        } else {
+            let zooms: HashSet<u8> = opts.zoom_levels.iter().copied().collect();
+            zooms
+                .into_iter()
+                .filter(|z| (min..=max).contains(z))
+                .collect()
+        };
+
+        let mut tx = conn.begin().await?;
+        tx.execute(
+            "CREATE TEMP TABLE copy_area (
+                 z       INTEGER NOT NULL,
+                 min_x   INTEGER NOT NULL,
+                 max_x   INTEGER NOT NULL,
+                 min_row INTEGER NOT NULL,
+                 max_row INTEGER NOT NULL)",
+        )
+        .await?;
+        for zoom in zooms {
+            for rect in self.tile_ranges(zoom) {
+                // MBTiles rows are counted from the bottom, unlike the XYZ tile indexes
+                query("INSERT INTO temp.copy_area VALUES (?, ?, ?, ?, ?)")
+                    .bind(zoom)
+                    .bind(rect.min_x)
+                    .bind(rect.max_x)
+                    .bind(invert_y_value(zoom, rect.max_y))
+                    .bind(invert_y_value(zoom, rect.min_y))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(" AND EXISTS (SELECT 1 FROM temp.copy_area
+                         WHERE z = zoom_level
+                           AND tile_column BETWEEN min_x AND max_x
+                           AND tile_row BETWEEN min_row AND max_row)"
+            .to_string())
+    }
+
+    /// Tile ranges of a zoom level covered by the bbox and area filters. The ranges may overlap.
+    fn tile_ranges(&self, zoom: u8) -> Vec<TileRect> {
+        let mut ranges = self
+            .area
+            .as_ref()
+            .map(|area| area.tile_ranges(zoom))
+            .unwrap_or_default();
+        for bbox in &self.options.bbox {
+            let (min_x, min_y) = tile_index(bbox.left, bbox.top, zoom);
+            let (max_x, max_y) = tile_index(bbox.right, bbox.bottom, zoom);
+            ranges.push(TileRect::new(zoom, min_x, min_y, max_x, max_y));
+        }
+        ranges
+    }
+
+    /// Bounds of the bbox and area filters, or `None` if the copy is not limited to an area
+    fn filter_bounds(&self) -> Option<Bounds> {
+        let area = self.area.as_ref().map(|v| Bounds::from(v.bounds()));
+        self.options
+            .bbox
+            .iter()
+            .copied()
+            .chain(area)
+            .reduce(|a, b| a + b)
+    }
+
+    /// Update the bounds, center, and zoom range in the metadata of the destination
+    /// to match the copied subset of the tiles, if the copy was filtered.
+    async fn update_filtered_metadata(&self, conn: &mut SqliteConnection) -> MbtResult<()> {
+        let mbt = &self.dst_mbtiles;
+        let opts = &self.options;
+
+        if let Some(filter) = self.filter_bounds() {
+            let sql = "SELECT value FROM sourceDb.metadata WHERE name = 'bounds'";
+            let src = query_scalar::<_, String>(sql)
+                .fetch_optional(&mut *conn)
+                .await?
+                .and_then(|v| Bounds::from_str(&v).ok())
+                .unwrap_or(Bounds::MAX);
+            let bounds = Bounds::new(
+                src.left.max(filter.left),
+                src.bottom.max(filter.bottom),
+                src.right.min(filter.right),
+                src.top.min(filter.top),
+            );
+            // the filter may not overlap the declared bounds of the source
+            let bounds = if bounds.left <= bounds.right && bounds.bottom <= bounds.top {
+                bounds
+            } else {
+                filter
+            };
+            info!("Setting bounds of {mbt} to {bounds}");
+            mbt.set_metadata_value(&mut *conn, "bounds", bounds).await?;
+
+            let center = mbt.get_metadata_value(&mut *conn, "center").await?;
+            if let Some(center) = center.and_then(|v| Center::from_str(&v).ok()) {
+                let inside = (bounds.left..=bounds.right).contains(&center.longitude)
+                    && (bounds.bottom..=bounds.top).contains(&center.latitude);
+                if !inside {
+                    let center = Center::new(
+                        (bounds.left + bounds.right) / 2.0,
+                        (bounds.bottom + bounds.top) / 2.0,
+                        center.zoom,
+                    );
+                    mbt.set_metadata_value(&mut *conn, "center", center).await?;
+                }
+            }
+        }
+
+        if opts.min_zoom.is_some() || opts.max_zoom.is_some() || !opts.zoom_levels.is_empty() {
+            let row = query("SELECT min(zoom_level), max(zoom_level) FROM tiles")
+                .fetch_one(&mut *conn)
+                .await?;
+            if let (Some(min), Some(max)) =
+                (row.get::<Option<u8>, _>(0), row.get::<Option<u8>, _>(1))
+            {
+                mbt.set_metadata_value(&mut *conn, "minzoom", min).await?;
+                mbt.set_metadata_value(&mut *conn, "maxzoom", max).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn get_where_clause(&self) -> (String, Vec<u8>) {
         let mut query_args = vec![];
 
@@ -607,6 +796,12 @@ impl MbtileCopierInt {
     }
 }
 
+fn read_area(path: &Path) -> MbtResult<TileArea> {
+    let json =
+        std::fs::read_to_string(path).map_err(|e| MbtError::AreaRead(e, path.to_path_buf()))?;
+    TileArea::from_geojson(&json).map_err(|e| MbtError::InvalidArea(e, path.to_path_buf()))
+}
+
 #[cfg(test)]
 mod tests {
     use sqlx::{Decode, Sqlite, SqliteConnection, Type};
@@ -766,6 +961,75 @@ mod tests {
         verify_copy_with_zoom_filter(opt, 2).await
     }
 
+    #[actix_rt::test]
+    async fn copy_with_bbox() -> MbtResult<()> {
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let dst = PathBuf::from("file:copy_with_bbox_mem_db?mode=memory&cache=shared");
+        let mut opt = MbtilesCopier::new(src, dst.clone());
+        opt.bbox.push(Bounds::new(-10.0, 35.0, 30.0, 60.0));
+        opt.max_zoom = Some(5);
+        let mut dst_conn = opt.run().await?;
+
+        let count = "SELECT zoom_level, COUNT(*) FROM tiles GROUP BY zoom_level ORDER BY 1";
+        let counts: Vec<(u8, u32)> = sqlx::query_as(count).fetch_all(&mut dst_conn).await?;
+        assert_eq!(
+            counts,
+            vec![(0, 1), (1, 2), (2, 2), (3, 4), (4, 7), (5, 10)]
+        );
+
+        let dst = Mbtiles::new(dst)?;
+        let mut values = Vec::new();
+        for key in ["bounds", "center", "minzoom", "maxzoom"] {
+            values.push(dst.get_metadata_value(&mut dst_conn, key).await?.unwrap());
+        }
+        // the top of the bbox is outside the bounds of the source
+        assert_eq!(values, ["-10,35,30,59.352706", "10,47.176353,6", "0", "5"]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn copy_with_area() -> MbtResult<()> {
+        let area = std::env::temp_dir().join(format!("mbtiles-{}.geojson", std::process::id()));
+        std::fs::write(
+            &area,
+            r#"{"type": "Polygon", "coordinates": [[[-4.8, 48.4], [2.5, 51.1], [8.2, 48.9], [7.5, 43.8], [3.1, 42.4], [-1.8, 43.4], [-4.8, 48.4]]]}"#,
+        )
+        .unwrap();
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let dst = PathBuf::from("file:copy_with_area_mem_db?mode=memory&cache=shared");
+        let mut opt = MbtilesCopier::new(src, dst);
+        opt.area = Some(area.clone());
+        opt.zoom_levels.extend(&[4, 6]);
+        opt.dst_type_cli = NORM_CLI;
+        let result = opt.run().await;
+        std::fs::remove_file(area).unwrap();
+        let mut dst_conn = result?;
+
+        let count = "SELECT zoom_level, COUNT(*) FROM tiles GROUP BY zoom_level ORDER BY 1";
+        let counts: Vec<(u8, u32)> = sqlx::query_as(count).fetch_all(&mut dst_conn).await?;
+        assert_eq!(counts, vec![(4, 2), (6, 4)]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn copy_with_invalid_area() {
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let dst = PathBuf::from("file:copy_with_invalid_area_mem_db?mode=memory&cache=shared");
+        let mut opt = MbtilesCopier::new(src.clone(), dst.clone());
+        opt.bbox.push(Bounds::new(10.0, 35.0, -30.0, 60.0));
+        assert!(matches!(opt.run().await, Err(MbtError::InvalidBbox(..))));
+
+        let mut opt = MbtilesCopier::new(src, dst);
+        opt.bbox.push(Bounds::MAX_TILED);
+        opt.diff_with_file = Some(PathBuf::from(
+            "../tests/fixtures/mbtiles/world_cities_modified.mbtiles",
+        ));
+        assert!(matches!(
+            opt.run().await,
+            Err(MbtError::UnsupportedCopyOperation { .. })
+        ));
+    }
+
     #[actix_rt::test]
     async fn copy_with_diff_with_file() -> MbtResult<()> {
         let src = PathBuf::from("../tests/fixtures/mbtiles/geography-class-jpg.mbtiles");
@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 use martin_tile_utils::TileInfo;
 use sqlite_hashes::rusqlite;
+use tilejson::Bounds;
 
 use crate::MbtType;
 
@@ -40,6 +41,9 @@ pub enum MbtError {
     #[error("Computed aggregate tiles hash {0} does not match tile data in metadata {1} for MBTile file {2}")]
     AggHashMismatch(String, String, String),
 
+    #[error("The patch file {0} can only be applied to a file with aggregate tiles hash {1}, but {2} has hash {3}")]
+    PatchBaseHashMismatch(String, String, String, String),
+
     #[error(
         "Metadata value `agg_tiles_hash` is not set in MBTiles file {0}\n    Use `mbtiles validate --agg-hash update {0}` to fix this."
     )]
@@ -63,9 +67,21 @@ pub enum MbtError {
     #[error("Unexpected duplicate tiles found when copying")]
     DuplicateValues,
 
+    #[error("{2} tiles of {0} are different from the same tiles in {1}")]
+    MergeConflict(String, String, i64),
+
     #[error("Applying a patch while diffing is not supported")]
     CannotApplyPatchAndDiff,
 
+    #[error("Invalid bounding box {0}, its left and bottom must not exceed its right and top")]
+    InvalidBbox(Bounds),
+
+    #[error("Unable to read area file {}: {0}", .1.display())]
+    AreaRead(std::io::Error, PathBuf),
+
+    #[error("Area file {} is not valid: {0}", .1.display())]
+    InvalidArea(String, PathBuf),
+
     #[error("The MBTiles file {0} has data of type {1}, but the desired type was set to {2}")]
     MismatchedTargetType(PathBuf, MbtType, MbtType),
 }
@@ -12,6 +12,9 @@ pub use errors::{MbtError, MbtResult};
 mod mbtiles;
 pub use mbtiles::{MbtTypeCli, Mbtiles};
 
+mod merger;
+pub use merger::{merge_metadata, MbtilesMerger};
+
 mod metadata;
 pub use metadata::Metadata;
 
@@ -29,7 +32,7 @@ mod summary;
 mod validation;
 pub use validation::{
     calc_agg_tiles_hash, AggHashType, IntegrityCheckType, MbtType, AGG_TILES_HASH,
-    AGG_TILES_HASH_IN_DIFF,
+    AGG_TILES_HASH_BEFORE_APPLY, AGG_TILES_HASH_IN_DIFF,
 };
 
 /// `MBTiles` uses a TMS (Tile Map Service) scheme for its tile coordinates (inverted along the Y axis).
@@ -0,0 +1,273 @@
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "cli")]
+use clap::Args;
+use log::{debug, info};
+use serde_json::{Map, Value};
+use sqlx::{query, Row as _, SqliteConnection};
+use tilejson::{tilejson, TileJSON, VectorLayer};
+
+use crate::queries::{detach_db, is_empty_database};
+use crate::{CopyDuplicateMode, MbtError, MbtResult, MbtTypeCli, Mbtiles, MbtilesCopier, Metadata};
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(Args))]
+pub struct MbtilesMerger {
+    /// File to write to. If it exists, its tiles are merged with the other files as if it was the first file to merge.
+    pub dst_file: PathBuf,
+    /// Files to merge, in order
+    #[cfg_attr(feature = "cli", arg(required = true))]
+    pub src_files: Vec<PathBuf>,
+    /// Output format of the destination file, ignored if the file exists. If not specified, defaults to the type of the first file
+    #[cfg_attr(
+        feature = "cli",
+        arg(long = "mbtiles-type", value_name = "SCHEMA", value_enum)
+    )]
+    pub dst_type_cli: Option<MbtTypeCli>,
+    /// What to do with a tile found in more than one file: keep the tile of the last file (override),
+    /// keep the tile of the first file (ignore), or fail if the tiles are different (abort)
+    #[cfg_attr(feature = "cli", arg(long, value_enum, default_value = "override"))]
+    pub on_duplicate: CopyDuplicateMode,
+}
+
+impl MbtilesMerger {
+    #[must_use]
+    pub fn new(dst_file: PathBuf, src_files: Vec<PathBuf>) -> Self {
+        Self {
+            dst_file,
+            src_files,
+            dst_type_cli: None,
+            on_duplicate: CopyDuplicateMode::default(),
+        }
+    }
+
+    /// Copy the tiles of all files into the destination file, and merge their metadata
+    pub async fn run(self) -> MbtResult<SqliteConnection> {
+        let dst_mbt = Mbtiles::new(&self.dst_file)?;
+        let mut metadata = Vec::with_capacity(self.src_files.len() + 1);
+        let mut conn = dst_mbt.open_or_new().await?;
+        if !is_empty_database(&mut conn).await? {
+            metadata.push(dst_mbt.get_metadata(&mut conn).await?);
+        }
+        for src_file in &self.src_files {
+            let src_mbt = Mbtiles::new(src_file)?;
+            let mut conn = src_mbt.open_readonly().await?;
+            metadata.push(src_mbt.get_metadata(&mut conn).await?);
+        }
+
+        for src_file in &self.src_files {
+            // identical tiles are not a conflict, so only the different ones make the merge fail
+            let on_duplicate = if self.on_duplicate == CopyDuplicateMode::Abort {
+                check_conflicts(&mut conn, &dst_mbt, src_file).await?;
+                CopyDuplicateMode::Ignore
+            } else {
+                self.on_duplicate
+            };
+            info!("Merging {} into {dst_mbt}", src_file.display());
+            let mut copier = MbtilesCopier::new(src_file.clone(), self.dst_file.clone());
+            copier.dst_type_cli = self.dst_type_cli;
+            copier.on_duplicate = on_duplicate;
+            copier.skip_agg_tiles_hash = true;
+            copier.run().await?;
+        }
+
+        let mut merged = merge_metadata(&metadata);
+        debug!("Merged metadata of {} files: {merged:?}", metadata.len());
+        // the other values of the json metadata are kept as copied from the files
+        let mut json = match dst_mbt.get_metadata(&mut conn).await?.json {
+            Some(Value::Object(json)) => json,
+            _ => Map::new(),
+        };
+        if let Some(layers) = merged.vector_layers.take() {
+            json.insert("vector_layers".to_string(), serde_json::to_value(layers)?);
+        }
+        dst_mbt.insert_metadata(&mut conn, &merged).await?;
+        if !json.is_empty() {
+            dst_mbt
+                .set_metadata_value(&mut conn, "json", Value::Object(json))
+                .await?;
+        }
+        dst_mbt.update_agg_tiles_hash(&mut conn).await?;
+        Ok(conn)
+    }
+}
+
+/// Fail if a tile of the source file is also in the destination file with different data
+async fn check_conflicts(
+    conn: &mut SqliteConnection,
+    dst_mbt: &Mbtiles,
+    src_file: &Path,
+) -> MbtResult<()> {
+    if is_empty_database(&mut *conn).await? {
+        return Ok(());
+    }
+    let src_mbt = Mbtiles::new(src_file)?;
+    src_mbt.attach_to(&mut *conn, "sourceDb").await?;
+    let count: i64 = query(
+        "
+    SELECT COUNT(*)
+    FROM tiles AS dst JOIN sourceDb.tiles AS src
+         ON dst.zoom_level = src.zoom_level
+         AND dst.tile_column = src.tile_column
+         AND dst.tile_row = src.tile_row
+    WHERE dst.tile_data != src.tile_data",
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .get(0);
+    detach_db(&mut *conn, "sourceDb").await?;
+    if count > 0 {
+        return Err(MbtError::MergeConflict(
+            src_mbt.to_string(),
+            dst_mbt.to_string(),
+            count,
+        ));
+    }
+    Ok(())
+}
+
+/// Combine the metadata of several files, the same way as the `TileJSON` of a composite source:
+/// the bounds, zoom levels, and vector layers cover all files, the first center is used,
+/// and the distinct names, descriptions, and attributions are joined.
+#[must_use]
+pub fn merge_metadata(metadata: &[Metadata]) -> TileJSON {
+    let mut result = tilejson! { tiles: vec![] };
+    let mut names: Vec<&str> = Vec::new();
+    let mut descriptions: Vec<&str> = Vec::new();
+    let mut attributions: Vec<&str> = Vec::new();
+    let mut layers: Vec<VectorLayer> = Vec::new();
+
+    for tj in metadata.iter().map(|m| &m.tilejson) {
+        if let Some(bounds) = tj.bounds {
+            result.bounds = Some(result.bounds.map_or(bounds, |v| v + bounds));
+        }
+        if result.center.is_none() {
+            result.center = tj.center;
+        }
+        if let Some(minzoom) = tj.minzoom {
+            result.minzoom = Some(result.minzoom.map_or(minzoom, |v| v.min(minzoom)));
+        }
+        if let Some(maxzoom) = tj.maxzoom {
+            result.maxzoom = Some(result.maxzoom.map_or(maxzoom, |v| v.max(maxzoom)));
+        }
+        for (values, value) in [
+            (&mut names, &tj.name),
+            (&mut descriptions, &tj.description),
+            (&mut attributions, &tj.attribution),
+        ] {
+            if let Some(value) = value {
+                if !values.contains(&value.as_str()) {
+                    values.push(value);
+                }
+            }
+        }
+        for layer in tj.vector_layers.iter().flatten() {
+            if let Some(target) = layers.iter_mut().find(|v| v.id == layer.id) {
+                merge_vector_layer(target, layer);
+            } else {
+                layers.push(layer.clone());
+            }
+        }
+    }
+
+    if !names.is_empty() {
+        result.name = Some(names.join(","));
+    }
+    if !descriptions.is_empty() {
+        result.description = Some(descriptions.join("\n"));
+    }
+    if !attributions.is_empty() {
+        result.attribution = Some(attributions.join("\n"));
+    }
+    if !layers.is_empty() {
+        result.vector_layers = Some(layers);
+    }
+    result
+}
+
+/// Add the fields and zoom levels of another layer with the same ID
+fn merge_vector_layer(target: &mut VectorLayer, layer: &VectorLayer) {
+    for (name, typ) in &layer.fields {
+        target
+            .fields
+            .entry(name.clone())
+            .or_insert_with(|| typ.clone());
+    }
+    target.minzoom = match (target.minzoom, layer.minzoom) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => None,
+    };
+    target.maxzoom = match (target.maxzoom, layer.maxzoom) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sqlx::Executor as _;
+    use tilejson::Bounds;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn merge_files() -> MbtResult<()> {
+        let src = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let modified = PathBuf::from("../tests/fixtures/mbtiles/world_cities_modified.mbtiles");
+        let dir = std::env::temp_dir().join(format!("mbtiles-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dst = dir.join("merged.mbtiles");
+
+        // the modified file has different tiles than the original one
+        let mut merger = MbtilesMerger::new(dst.clone(), vec![src.clone(), modified.clone()]);
+        merger.on_duplicate = CopyDuplicateMode::Abort;
+        let result = merger.run().await;
+        assert!(matches!(result, Err(MbtError::MergeConflict(..))));
+        std::fs::remove_file(&dst).unwrap();
+
+        let mut conn = MbtilesMerger::new(dst.clone(), vec![src, modified.clone()])
+            .run()
+            .await?;
+        // the tiles of the last file win
+        Mbtiles::new(&modified)?
+            .attach_to(&mut conn, "testOtherDb")
+            .await?;
+        assert!(conn
+            .fetch_optional("SELECT * FROM testOtherDb.tiles EXCEPT SELECT * FROM tiles")
+            .await?
+            .is_none());
+        drop(conn);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn merge_tilejson_metadata() {
+        let meta = |name: &str, bounds: Bounds, minzoom: u8, maxzoom: u8| Metadata {
+            id: name.to_string(),
+            tile_info: martin_tile_utils::Format::Mvt.into(),
+            layer_type: None,
+            tilejson: tilejson! {
+                tiles: vec![],
+                name: name.to_string(),
+                bounds: bounds,
+                minzoom: minzoom,
+                maxzoom: maxzoom,
+                vector_layers: vec![VectorLayer::new(name.to_string(), BTreeMap::new())],
+            },
+            json: None,
+        };
+        let tj = merge_metadata(&[
+            meta("a", Bounds::new(0.0, 0.0, 10.0, 10.0), 2, 10),
+            meta("b", Bounds::new(-5.0, 5.0, 5.0, 20.0), 0, 8),
+            meta("a", Bounds::new(0.0, 0.0, 1.0, 1.0), 4, 14),
+        ]);
+        assert_eq!(tj.name.as_deref(), Some("a,b"));
+        assert_eq!(tj.bounds, Some(Bounds::new(-5.0, 0.0, 10.0, 20.0)));
+        assert_eq!((tj.minzoom, tj.maxzoom), (Some(0), Some(14)));
+        assert_eq!(tj.vector_layers.map(|v| v.len()), Some(2));
+    }
+}
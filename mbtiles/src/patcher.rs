@@ -1,32 +1,66 @@
 use std::path::PathBuf;
 
-use log::{debug, info};
-use sqlx::query;
+use log::{debug, info, warn};
+use sqlx::{query, Connection as _};
 
 use crate::queries::detach_db;
 use crate::MbtType::{Flat, FlatWithHash, Normalized};
-use crate::{MbtResult, MbtType, Mbtiles, AGG_TILES_HASH, AGG_TILES_HASH_IN_DIFF};
+use crate::{
+    calc_agg_tiles_hash, MbtError, MbtResult, MbtType, Mbtiles, AGG_TILES_HASH,
+    AGG_TILES_HASH_BEFORE_APPLY, AGG_TILES_HASH_IN_DIFF,
+};
 
-pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()> {
+/// Apply a patch file to an `MBTiles` file in a single transaction.
+///
+/// If the patch file records the aggregate tiles hash of the file it was created from,
+/// the patch is only applied to a file with the same hash, unless `force` is set.
+/// If the patch file records the expected aggregate tiles hash after the patch is applied,
+/// the patched file is verified against it, and the changes are rolled back on mismatch.
+pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf, force: bool) -> MbtResult<()> {
     let src_mbt = Mbtiles::new(src_file)?;
     let patch_mbt = Mbtiles::new(patch_file)?;
-    let patch_type = patch_mbt.open_and_detect_type().await?;
+    let mut patch_conn = patch_mbt.open_readonly().await?;
+    let patch_type = patch_mbt.detect_type(&mut patch_conn).await?;
+    let before_hash = patch_mbt
+        .get_metadata_value(&mut patch_conn, AGG_TILES_HASH_BEFORE_APPLY)
+        .await?;
+    let after_hash = patch_mbt
+        .get_metadata_value(&mut patch_conn, AGG_TILES_HASH_IN_DIFF)
+        .await?;
+    drop(patch_conn);
 
     let mut conn = src_mbt.open().await?;
     let src_type = src_mbt.detect_type(&mut conn).await?;
     patch_mbt.attach_to(&mut conn, "patchDb").await?;
 
     info!("Applying patch file {patch_mbt} ({patch_type}) to {src_mbt} ({src_type})");
+    let mut tx = conn.begin().await?;
+
+    if let Some(expected) = before_hash {
+        let actual = calc_agg_tiles_hash(&mut *tx).await?;
+        if actual != expected {
+            if !force {
+                return Err(MbtError::PatchBaseHashMismatch(
+                    patch_mbt.to_string(),
+                    expected,
+                    src_mbt.to_string(),
+                    actual,
+                ));
+            }
+            warn!("Applying patch file {patch_mbt} created for a file with hash {expected} to {src_mbt} with hash {actual}");
+        }
+    }
+
     let select_from = get_select_from(src_type, patch_type);
     let (main_table, insert1, insert2) = get_insert_sql(src_type, select_from);
 
     query(&format!("{insert1} WHERE tile_data NOTNULL"))
-        .execute(&mut conn)
+        .execute(&mut *tx)
         .await?;
 
     if let Some(insert2) = insert2 {
         query(&format!("{insert2} WHERE tile_data NOTNULL"))
-            .execute(&mut conn)
+            .execute(&mut *tx)
             .await?;
     }
 
@@ -37,16 +71,27 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
         SELECT zoom_level, tile_column, tile_row FROM ({select_from} WHERE tile_data ISNULL)
     )"
     ))
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
     if src_type.is_normalized() {
         debug!("Removing unused tiles from the images table (normalized schema)");
         query("DELETE FROM images WHERE tile_id NOT IN (SELECT tile_id FROM map)")
-            .execute(&mut conn)
+            .execute(&mut *tx)
             .await?;
     }
 
+    if let Some(expected) = after_hash {
+        let actual = calc_agg_tiles_hash(&mut *tx).await?;
+        if actual != expected && !force {
+            return Err(MbtError::AggHashMismatch(
+                actual,
+                expected,
+                src_mbt.to_string(),
+            ));
+        }
+    }
+
     // Copy metadata from patchDb to the destination file, replacing existing values
     // Convert 'agg_tiles_hash_in_patch' into 'agg_tiles_hash'
     // Delete metadata entries if the value is NULL in patchDb
@@ -56,9 +101,9 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
     SELECT IIF(name = '{AGG_TILES_HASH_IN_DIFF}', '{AGG_TILES_HASH}', name) as name,
            value
     FROM patchDb.metadata
-    WHERE name NOTNULL AND name != '{AGG_TILES_HASH}';"
+    WHERE name NOTNULL AND name != '{AGG_TILES_HASH}' AND name != '{AGG_TILES_HASH_BEFORE_APPLY}';"
     ))
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
     query(
@@ -66,9 +111,10 @@ pub async fn apply_patch(src_file: PathBuf, patch_file: PathBuf) -> MbtResult<()
     DELETE FROM metadata
     WHERE name IN (SELECT name FROM patchDb.metadata WHERE value ISNULL);",
     )
-    .execute(&mut conn)
+    .execute(&mut *tx)
     .await?;
 
+    tx.commit().await?;
     detach_db(&mut conn, "patchDb").await
 }
 
@@ -154,7 +200,7 @@ mod tests {
 
         // Apply patch to the src data in in-memory DB
         let patch_file = PathBuf::from("../tests/fixtures/mbtiles/world_cities_diff.mbtiles");
-        apply_patch(src, patch_file).await?;
+        apply_patch(src, patch_file, false).await?;
 
         // Verify the data is the same as the file the patch was generated from
         Mbtiles::new("../tests/fixtures/mbtiles/world_cities_modified.mbtiles")?
@@ -182,7 +228,7 @@ mod tests {
         // Apply patch to the src data in in-memory DB
         let patch_file =
             PathBuf::from("../tests/fixtures/mbtiles/geography-class-jpg-diff.mbtiles");
-        apply_patch(src, patch_file).await?;
+        apply_patch(src, patch_file, false).await?;
 
         // Verify the data is the same as the file the patch was generated from
         Mbtiles::new("../tests/fixtures/mbtiles/geography-class-jpg-modified.mbtiles")?
@@ -196,4 +242,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn apply_patch_file_to_wrong_base() -> MbtResult<()> {
+        let src_file = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let src = PathBuf::from("file:apply_patch_wrong_base_mem_db?mode=memory&cache=shared");
+        let mut src_conn = MbtilesCopier::new(src_file, src.clone()).run().await?;
+        let src_hash = calc_agg_tiles_hash(&mut src_conn).await?;
+
+        // Record an unexpected base file hash in a copy of the patch file
+        let patch_file = PathBuf::from("../tests/fixtures/mbtiles/world_cities_diff.mbtiles");
+        let patch =
+            PathBuf::from("file:apply_patch_wrong_base_patch_mem_db?mode=memory&cache=shared");
+        let mut patch_conn = MbtilesCopier::new(patch_file, patch.clone()).run().await?;
+        Mbtiles::new(&patch)?
+            .set_metadata_value(&mut patch_conn, AGG_TILES_HASH_BEFORE_APPLY, "BAD")
+            .await?;
+
+        let err = apply_patch(src.clone(), patch.clone(), false).await;
+        assert!(matches!(err, Err(MbtError::PatchBaseHashMismatch(..))));
+        assert_eq!(calc_agg_tiles_hash(&mut src_conn).await?, src_hash);
+
+        apply_patch(src, patch, true).await?;
+        assert_ne!(calc_agg_tiles_hash(&mut src_conn).await?, src_hash);
+
+        Ok(())
+    }
 }
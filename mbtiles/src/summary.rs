@@ -11,7 +11,7 @@ use std::str::FromStr;
 use martin_tile_utils::{EARTH_CIRCUMFERENCE, EARTH_RADIUS};
 use serde::Serialize;
 use size_format::SizeFormatterBinary;
-use sqlx::{query, SqliteExecutor};
+use sqlx::{query, query_scalar, SqliteExecutor};
 use tilejson::Bounds;
 
 use crate::{MbtResult, MbtType, Mbtiles};
@@ -24,6 +24,8 @@ pub struct ZoomInfo {
     pub max_tile_size: u64,
     pub avg_tile_size: f64,
     pub bbox: Bounds,
+    /// Share of the tiles within the tile range of the bounding box that are present, from 0 to 1
+    pub coverage: f64,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -33,6 +35,8 @@ pub struct Summary {
     pub page_size: u64,
     pub page_count: u64,
     pub tile_count: u64,
+    /// Number of distinct tile contents, the other tiles are duplicates of these
+    pub unique_tile_count: u64,
     pub min_tile_size: Option<u64>,
     pub max_tile_size: Option<u64>,
     pub avg_tile_size: f64,
@@ -55,11 +59,20 @@ impl Display for Summary {
         let page_size = SizeFormatterBinary::new(self.page_size);
         writeln!(f, "Page size: {page_size:.2}B")?;
         writeln!(f, "Page count: {:.2}", self.page_count)?;
+        if self.tile_count > 0 {
+            let duplicates = self.tile_count - self.unique_tile_count;
+            writeln!(
+                f,
+                "Duplicate tiles: {duplicates} of {} ({:.2}%)",
+                self.tile_count,
+                100.0 * duplicates as f64 / self.tile_count as f64
+            )?;
+        }
         writeln!(f)?;
         writeln!(
             f,
-            " {:^4} | {:^9} | {:^9} | {:^9} | {:^9} | Bounding Box",
-            "Zoom", "Count", "Smallest", "Largest", "Average"
+            " {:^4} | {:^9} | {:^9} | {:^9} | {:^9} | {:^8} | Bounding Box",
+            "Zoom", "Count", "Smallest", "Largest", "Average", "Coverage"
         )?;
 
         for l in &self.zoom_info {
@@ -70,12 +83,13 @@ impl Display for Summary {
 
             writeln!(
                 f,
-                " {:>4} | {:>9} | {:>9} | {:>9} | {:>9} | {:.prec$}",
+                " {:>4} | {:>9} | {:>9} | {:>9} | {:>9} | {:>8} | {:.prec$}",
                 l.zoom,
                 l.tile_count,
                 format!("{min:.1}B"),
                 format!("{max:.1}B"),
                 format!("{avg:.1}B"),
+                format!("{:.1}%", 100.0 * l.coverage),
                 l.bbox,
             )?;
         }
@@ -93,12 +107,13 @@ impl Display for Summary {
                 let prec = get_zoom_precision(max_zoom);
                 writeln!(
                     f,
-                    " {:>4} | {:>9} | {:>9} | {:>9} | {:>9} | {bbox:.prec$}",
+                    " {:>4} | {:>9} | {:>9} | {:>9} | {:>9} | {:>8} | {bbox:.prec$}",
                     "all",
                     self.tile_count,
                     format!("{min}B"),
                     format!("{max}B"),
                     format!("{avg}B"),
+                    "",
                 )?;
             }
         }
@@ -146,24 +161,29 @@ impl Mbtiles {
             .into_iter()
             .map(|r| {
                 let zoom = u8::try_from(r.zoom.unwrap()).expect("zoom_level is not a u8");
+                let (min_x, min_y) = (r.min_tile_x.unwrap(), r.min_tile_y.unwrap());
+                let (max_x, max_y) = (r.max_tile_x.unwrap(), r.max_tile_y.unwrap());
+                let range_size = f64::from(max_x - min_x + 1) * f64::from(max_y - min_y + 1);
                 ZoomInfo {
                     zoom,
                     tile_count: r.count as u64,
                     min_tile_size: r.smallest.unwrap_or(0) as u64,
                     max_tile_size: r.largest.unwrap_or(0) as u64,
                     avg_tile_size: r.average.unwrap_or(0.0),
-                    bbox: xyz_to_bbox(
-                        zoom,
-                        r.min_tile_x.unwrap(),
-                        r.min_tile_y.unwrap(),
-                        r.max_tile_x.unwrap(),
-                        r.max_tile_y.unwrap(),
-                    ),
+                    bbox: xyz_to_bbox(zoom, min_x, min_y, max_x, max_y),
+                    coverage: f64::from(r.count) / range_size,
                 }
             })
             .collect();
 
         let tile_count = zoom_info.iter().map(|l| l.tile_count).sum();
+        // the hashes and the tile IDs identify the tile contents without comparing them
+        let sql = match mbt_type {
+            MbtType::Flat => "SELECT count(DISTINCT tile_data) FROM tiles",
+            MbtType::FlatWithHash => "SELECT count(DISTINCT tile_hash) FROM tiles_with_hash",
+            MbtType::Normalized { .. } => "SELECT count(DISTINCT tile_id) FROM map",
+        };
+        let unique_tile_count: i64 = query_scalar(sql).fetch_one(&mut *conn).await?;
         let avg_sum = zoom_info
             .iter()
             .map(|l| l.avg_tile_size * l.tile_count as f64)
@@ -175,6 +195,7 @@ impl Mbtiles {
             page_size,
             page_count,
             tile_count,
+            unique_tile_count: unique_tile_count as u64,
             min_tile_size: zoom_info.iter().map(|l| l.min_tile_size).reduce(u64::min),
             max_tile_size: zoom_info.iter().map(|l| l.max_tile_size).reduce(u64::max),
             avg_tile_size: avg_sum / tile_count as f64,
@@ -260,6 +281,7 @@ mod tests {
         page_size: 512
         page_count: 6
         tile_count: 0
+        unique_tile_count: 0
         min_tile_size: ~
         max_tile_size: ~
         avg_tile_size: NaN
@@ -272,6 +294,26 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn summary_duplicates() -> MbtResult<()> {
+        let mbt = Mbtiles::new("file:mbtiles_duplicates_summary?mode=memory&cache=shared")?;
+        let mut conn = mbt.open().await?;
+
+        init_mbtiles_schema(&mut conn, MbtType::Flat).await.unwrap();
+        sqlx::query(
+            "INSERT INTO tiles VALUES (1, 0, 0, x'01'), (1, 1, 0, x'01'), (1, 1, 1, x'0203')",
+        )
+        .execute(&mut conn)
+        .await?;
+        let res = mbt.summary(&mut conn).await?;
+        assert_eq!(res.tile_count, 3);
+        assert_eq!(res.unique_tile_count, 2);
+        assert_relative_eq!(res.zoom_info[0].coverage, 0.75);
+        assert!(res.to_string().contains("Duplicate tiles: 1 of 3 (33.33%)"));
+
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn summary() -> MbtResult<()> {
         let mbt = Mbtiles::new("../tests/fixtures/mbtiles/world_cities.mbtiles")?;
@@ -286,6 +328,7 @@ mod tests {
         page_size: 4096
         page_count: 12
         tile_count: 196
+        unique_tile_count: 196
         min_tile_size: 64
         max_tile_size: 1107
         avg_tile_size: 96.2295918367347
@@ -307,6 +350,7 @@ mod tests {
               - -85.0511287798066
               - 180
               - 85.0511287798066
+            coverage: 1
           - zoom: 1
             tile_count: 4
             min_tile_size: 160
@@ -317,6 +361,7 @@ mod tests {
               - -85.0511287798066
               - 180
               - 85.0511287798066
+            coverage: 1
           - zoom: 2
             tile_count: 7
             min_tile_size: 137
@@ -327,6 +372,7 @@ mod tests {
               - -66.51326044311186
               - 180
               - 66.51326044311186
+            coverage: 0.875
           - zoom: 3
             tile_count: 17
             min_tile_size: 67
@@ -337,6 +383,7 @@ mod tests {
               - -40.97989806962013
               - 180
               - 66.51326044311186
+            coverage: 0.8095238095238095
           - zoom: 4
             tile_count: 38
             min_tile_size: 64
@@ -347,6 +394,7 @@ mod tests {
               - -40.97989806962013
               - 180
               - 66.51326044311186
+            coverage: 0.4523809523809524
           - zoom: 5
             tile_count: 57
             min_tile_size: 64
@@ -357,6 +405,7 @@ mod tests {
               - -40.97989806962013
               - 180
               - 61.60639637138627
+            coverage: 0.1919191919191919
           - zoom: 6
             tile_count: 72
             min_tile_size: 64
@@ -367,6 +416,7 @@ mod tests {
               - -40.97989806962013
               - 180
               - 61.60639637138627
+            coverage: 0.06060606060606061
         "###);
 
         Ok(())
@@ -28,6 +28,10 @@ pub const AGG_TILES_HASH: &str = "agg_tiles_hash";
 /// describing the eventual [`AGG_TILES_HASH`] value once the diff is applied
 pub const AGG_TILES_HASH_IN_DIFF: &str = "agg_tiles_hash_after_apply";
 
+/// Metadata key for a diff file,
+/// describing the [`AGG_TILES_HASH`] value of the file the diff can be applied to
+pub const AGG_TILES_HASH_BEFORE_APPLY: &str = "agg_tiles_hash_before_apply";
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EnumDisplay, Serialize)]
 #[enum_display(case = "Kebab")]
 pub enum MbtType {
@@ -309,7 +309,7 @@ async fn diff_and_patch(
             TILES_V1,
             "{prefix}__v1"
         );
-        apply_patch(path(&tar1_mbt), path(&dif_mbt)).await?;
+        apply_patch(path(&tar1_mbt), path(&dif_mbt), false).await?;
         let hash_v1 = tar1_mbt.validate(Off, Verify).await?;
         allow_duplicates! {
             assert_display_snapshot!(hash_v1, @"FE0D3090E8B4E89F2C755C08E8D76BEA");
@@ -320,7 +320,7 @@ async fn diff_and_patch(
         info!("TEST: Applying the difference (v2-v1=diff) to v2, should not modify it");
         let (tar2_mbt, mut tar2_cn) =
             new_file! {diff_and_patch, *target_type, METADATA_V2, TILES_V2, "{prefix}__v2"};
-        apply_patch(path(&tar2_mbt), path(&dif_mbt)).await?;
+        apply_patch(path(&tar2_mbt), path(&dif_mbt), false).await?;
         let hash_v2 = tar2_mbt.validate(Off, Verify).await?;
         allow_duplicates! {
             assert_display_snapshot!(hash_v2, @"FE0D3090E8B4E89F2C755C08E8D76BEA");